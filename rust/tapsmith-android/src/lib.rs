@@ -7,7 +7,7 @@ use tapsmith_core::{
     mifare::desfire::{
         error::Error as DesfireError,
         transport::{Frame, MAX_FRAME_SIZE},
-        Desfire, Transport, WrappedFraming,
+        Desfire, OsRandom, Transport, WrappedFraming,
     },
 };
 
@@ -388,7 +388,8 @@ pub unsafe extern "system" fn Java_bios9_tapsmith_rfid_TapSmithNative_readGallag
     let mut desfire = Desfire::new(transport, WrappedFraming);
     let read = GallagherDesfireReader::read_from_desfire(
         &mut desfire,
-        GallagherDesfireKeySource::DefaultSiteKey,
+        &GallagherDesfireKeySource::DefaultSiteKey,
+        &mut OsRandom,
     );
 
     let Ok(gallagher) = read else {
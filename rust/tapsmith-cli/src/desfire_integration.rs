@@ -1,10 +1,9 @@
-use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 
 use heapless::Vec as HeaplessVec;
 use tapsmith_core::mifare::desfire::{
     AccessCondition, AccessRights, ApplicationId, ApplicationKeyType, CommunicationMode, Desfire,
-    Error, FileId, FileSettingsDetails, KeyNumber, KeySettings, RndA, RndA8, SessionKey, Transport,
+    Error, FileId, FileSettingsDetails, KeyNumber, KeySettings, OsRandom, SessionKey, Transport,
     WrappedFraming, U24,
 };
 
@@ -555,30 +554,18 @@ fn authenticate<T: Transport>(
     auth: AuthSpec,
 ) -> Result<(), String> {
     let session = match auth {
-        AuthSpec::Aes { key_number, key } => {
-            let rnd_a = random_rnd_a().map_err(|error| format!("/dev/urandom: {error}"))?;
-            desfire
-                .authenticate_aes_with_rnd_a(key_number, &key, rnd_a)
-                .map_err(desfire_error)?
-        }
-        AuthSpec::Des { key_number, key } => {
-            let rnd_a = random_rnd_a8().map_err(|error| format!("/dev/urandom: {error}"))?;
-            desfire
-                .authenticate_des_with_rnd_a(key_number, &key, rnd_a)
-                .map_err(desfire_error)?
-        }
-        AuthSpec::Tdea2 { key_number, key } => {
-            let rnd_a = random_rnd_a8().map_err(|error| format!("/dev/urandom: {error}"))?;
-            desfire
-                .authenticate_2tdea_with_rnd_a(key_number, &key, rnd_a)
-                .map_err(desfire_error)?
-        }
-        AuthSpec::Tdea3 { key_number, key } => {
-            let rnd_a = random_rnd_a().map_err(|error| format!("/dev/urandom: {error}"))?;
-            desfire
-                .authenticate_3tdea_with_rnd_a(key_number, &key, rnd_a)
-                .map_err(desfire_error)?
-        }
+        AuthSpec::Aes { key_number, key } => desfire
+            .authenticate_aes(key_number, &key, &mut OsRandom)
+            .map_err(desfire_error)?,
+        AuthSpec::Des { key_number, key } => desfire
+            .authenticate_des(key_number, &key, &mut OsRandom)
+            .map_err(desfire_error)?,
+        AuthSpec::Tdea2 { key_number, key } => desfire
+            .authenticate_2tdea(key_number, &key, &mut OsRandom)
+            .map_err(desfire_error)?,
+        AuthSpec::Tdea3 { key_number, key } => desfire
+            .authenticate_3tdea(key_number, &key, &mut OsRandom)
+            .map_err(desfire_error)?,
     };
 
     match session.session_key() {
@@ -633,18 +620,6 @@ fn key1() -> KeyNumber {
     KeyNumber::new(1).unwrap()
 }
 
-fn random_rnd_a() -> io::Result<RndA> {
-    let mut bytes = [0u8; 16];
-    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
-    Ok(RndA::new(bytes))
-}
-
-fn random_rnd_a8() -> io::Result<RndA8> {
-    let mut bytes = [0u8; 8];
-    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
-    Ok(RndA8::new(bytes))
-}
-
 fn confirm_destructive(yes: bool) -> bool {
     if yes {
         return true;
@@ -740,3 +715,22 @@ fn hex_nibble(byte: u8) -> Option<u8> {
 fn desfire_error(error: Error) -> String {
     format!("{error:?}")
 }
+
+#[cfg(test)]
+mod tests {
+    use tapsmith_core::mifare::desfire::VirtualDesfire;
+
+    use super::{run, IntegrationArgs};
+
+    #[test]
+    fn passes_against_virtual_card() {
+        let mut card = VirtualDesfire::new();
+        let args = IntegrationArgs {
+            yes: true,
+            skip_format: false,
+            picc_auth: None,
+        };
+
+        assert!(run(&mut card, args));
+    }
+}
@@ -6,7 +6,9 @@ mod desfire_integration;
 
 use heapless::Vec as HeaplessVec;
 use tapsmith_core::gallagher::credential::GallagherCredential;
-use tapsmith_core::gallagher::desfire::{GallagherDesfireKeySource, GallagherDesfireReader};
+use tapsmith_core::gallagher::desfire::{
+    GallagherDesfire, GallagherDesfireKeySource, GallagherDesfireReader,
+};
 use tapsmith_core::gallagher::mifare_classic::cad::CardApplicationDirectory;
 use tapsmith_core::gallagher::mifare_classic::{
     write_credential_to_sector, GallagherMifareClassic, CAD_AID, CREDENTIAL_AID, CREDENTIAL_KEY_A,
@@ -18,14 +20,17 @@ use tapsmith_core::mifare::application_directory::{
 use tapsmith_core::mifare::classic::{FourBlockSector, KeyProvider, KeyType, Sector, Tag};
 use tapsmith_core::mifare::desfire::crypto::aes_cbc_decrypt_in_place;
 use tapsmith_core::mifare::desfire::{
-    AccessCondition, AccessRights, ApplicationId, ApplicationKeyType, Command, CommandCode,
-    CommunicationMode, Desfire, FileId, FileSettings, FileSettingsDetails, FileType, FrameCodec,
-    KeyNumber, KeySettings, RndA, RndA8, Transport, WrappedFraming, U24,
+    verify_originality, AccessCondition, AccessRights, ApplicationId, ApplicationKeyType, Command,
+    CommandCode, CommunicationMode, CryptoProvider, Desfire, FileId, FileSettings,
+    FileSettingsDetails, FileType, FrameCodec, Key, KeyNumber, KeySettings, Originality, OsRandom,
+    SoftwareCrypto, Transport, VersionInfo, WrappedFraming, U24,
 };
+use tapsmith_core::trace::TraceRecorder;
 use tapsmith_pcsc::{
     acr122u::Acr122uReader,
     smart_card::{SmartCardContext, SmartCardReader},
 };
+use tapsmith_pkcs11::Pkcs11Token;
 
 const CAD_SECTOR: FourBlockSector = FourBlockSector::S14;
 const CREDENTIAL_SECTOR: FourBlockSector = FourBlockSector::S15;
@@ -77,7 +82,14 @@ impl KeyProvider for WriteKeyProvider {
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let (trace_path, pkcs11_args) = match take_global_options(&mut args) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{error}");
+            std::process::exit(1);
+        }
+    };
     let command = args.get(1).map_or("read", String::as_str);
 
     if matches!(command, "--help" | "-h" | "help") {
@@ -85,6 +97,14 @@ fn main() {
         return;
     }
 
+    let mut token = pkcs11_args.map(|pkcs11_args| match open_pkcs11_token(&pkcs11_args) {
+        Ok(token) => token,
+        Err(error) => {
+            eprintln!("{error}");
+            std::process::exit(1);
+        }
+    });
+
     let context = SmartCardContext::establish().unwrap();
     let readers: Vec<SmartCardReader> = context.get_readers().unwrap().collect();
 
@@ -105,6 +125,8 @@ fn main() {
         eprintln!("Warning: card reset failed: {error}");
     }
     card.set_card_detect_beep(false).unwrap();
+    let mut card = TraceRecorder::new(card);
+    let mut success = true;
 
     match command {
         "read" => {
@@ -112,11 +134,14 @@ fn main() {
                 Ok(parsed) => parsed,
                 Err(error) => {
                     eprintln!("read: {error}");
-                    eprintln!("Usage: {} read [--desfire] [--sitekey <32_hex>]", args[0]);
+                    eprintln!(
+                        "Usage: {} read [--desfire] [--sitekey <32_hex> | --sitekey-label <label>] [--picc-key <32_hex>]",
+                        args[0]
+                    );
                     return;
                 }
             };
-            read_gallagher_tag(&mut card, &read_args);
+            read_gallagher_tag(&mut card, &read_args, token.as_mut());
         }
         "write" => {
             let credential = GallagherCredential::new(
@@ -141,7 +166,7 @@ fn main() {
                     std::process::exit(1);
                 }
             };
-            read_desfire_tag(card, &desfire_args);
+            read_desfire_tag(&mut card, &desfire_args);
         }
         "desfire-integration" | "desfire-itest" => {
             let integration_args = match desfire_integration::parse_args(&args[2..]) {
@@ -155,9 +180,7 @@ fn main() {
                     std::process::exit(1);
                 }
             };
-            if !desfire_integration::run(card, integration_args) {
-                std::process::exit(1);
-            }
+            success = desfire_integration::run(&mut card, integration_args);
         }
         "desfire-format" => {
             let auth = match parse_optional_aes_auth(&args[2..]) {
@@ -171,7 +194,7 @@ fn main() {
                     std::process::exit(1);
                 }
             };
-            format_desfire(card, auth);
+            format_desfire(&mut card, auth);
         }
         "desfire-provision" => {
             let provision_args = match parse_provision_args(&args[2..]) {
@@ -185,7 +208,7 @@ fn main() {
                     std::process::exit(1);
                 }
             };
-            provision_desfire(card, &provision_args);
+            provision_desfire(&mut card, &provision_args);
         }
         "desfire-changekey" => {
             let ck_args = match parse_change_key_args(&args[2..]) {
@@ -193,14 +216,15 @@ fn main() {
                 Err(error) => {
                     eprintln!("desfire-changekey: {error}");
                     eprintln!(
-                        "Usage: {} desfire-changekey [--aid <hex>] --auth-aes <n>:<32hex> \
-                         (--picc | --newkeyno <n>) --newkey <32hex> [--newver <v>] [--oldkey <32hex>]",
+                        "Usage: {} desfire-changekey [--aid <hex>] (--auth-aes <n>:<32hex> | --auth-label <n>:<label>) \
+                         (--picc | --newkeyno <n>) (--newkey <32hex> | --newkey-label <label>) [--newver <v>] \
+                         [--oldkey <32hex> | --oldkey-label <label>]",
                         args[0]
                     );
                     std::process::exit(1);
                 }
             };
-            change_key_desfire(card, &ck_args);
+            change_key_desfire(&mut card, &ck_args, token.as_mut());
         }
         "desfire-delete" => {
             let delete_args = match parse_delete_args(&args[2..]) {
@@ -214,19 +238,79 @@ fn main() {
                     std::process::exit(1);
                 }
             };
-            delete_desfire(card, &delete_args);
+            delete_desfire(&mut card, &delete_args);
         }
         _ => {
             print_usage(&args[0]);
             std::process::exit(1);
         }
     }
+
+    if let Some(path) = trace_path {
+        let (_, trace) = card.into_parts();
+        match std::fs::write(&path, trace.to_string()) {
+            Ok(()) => println!("Trace written to {path}"),
+            Err(error) => {
+                eprintln!("Failed to write trace to {path}: {error}");
+                success = false;
+            }
+        }
+    }
+    if !success {
+        std::process::exit(1);
+    }
 }
 
 fn print_usage(binary: &str) {
     eprintln!(
-        "Usage: {binary} [read|write|desfire|desfire-integration|desfire-format|desfire-provision|desfire-delete|desfire-changekey]"
+        "Usage: {binary} [read|write|desfire|desfire-integration|desfire-format|desfire-provision|desfire-delete|desfire-changekey] [--trace <file>] [--pkcs11-module <path> --pkcs11-token <label>]"
     );
+    eprintln!("  The PKCS#11 user PIN is read from {PKCS11_PIN_VAR}.");
+}
+
+const PKCS11_PIN_VAR: &str = "TAPSMITH_PKCS11_PIN";
+
+struct Pkcs11Args {
+    module: String,
+    token: String,
+}
+
+/// Removes the options every command accepts: `--trace <file>` and the
+/// PKCS#11 token selection.
+fn take_global_options(
+    args: &mut Vec<String>,
+) -> Result<(Option<String>, Option<Pkcs11Args>), String> {
+    let trace_path = take_option(args, "--trace", "a file path")?;
+    let module = take_option(args, "--pkcs11-module", "a module path")?;
+    let token = take_option(args, "--pkcs11-token", "a token label")?;
+    let pkcs11_args = match (module, token) {
+        (Some(module), Some(token)) => Some(Pkcs11Args { module, token }),
+        (None, None) => None,
+        _ => return Err("--pkcs11-module and --pkcs11-token go together".to_string()),
+    };
+    Ok((trace_path, pkcs11_args))
+}
+
+fn take_option(args: &mut Vec<String>, name: &str, what: &str) -> Result<Option<String>, String> {
+    let Some(index) = args.iter().skip(1).position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    let index = index + 1;
+    if index + 1 >= args.len() {
+        return Err(format!("{name} requires {what}"));
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Ok(Some(value))
+}
+
+fn open_pkcs11_token(args: &Pkcs11Args) -> Result<Pkcs11Token, String> {
+    let pin = env::var(PKCS11_PIN_VAR)
+        .map_err(|_| format!("--pkcs11-token needs the user PIN in {PKCS11_PIN_VAR}"))?;
+    let token = Pkcs11Token::open(&args.module, &args.token, &pin)
+        .map_err(|error| format!("Failed to open PKCS#11 token {:?}: {error}", args.token))?;
+    println!("Using PKCS#11 token: {}", args.token);
+    Ok(token)
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -501,18 +585,6 @@ fn hex_nibble(byte: u8) -> Option<u8> {
     }
 }
 
-fn random_rnd_a() -> std::io::Result<RndA> {
-    let mut bytes = [0u8; 16];
-    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
-    Ok(RndA::new(bytes))
-}
-
-fn random_rnd_a8() -> std::io::Result<RndA8> {
-    let mut bytes = [0u8; 8];
-    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
-    Ok(RndA8::new(bytes))
-}
-
 fn random_bytes(len: usize) -> std::io::Result<Vec<u8>> {
     let mut bytes = vec![0u8; len];
     File::open("/dev/urandom")?.read_exact(&mut bytes)?;
@@ -522,11 +594,15 @@ fn random_bytes(len: usize) -> std::io::Result<Vec<u8>> {
 struct ReadArgs {
     desfire_only: bool,
     desfire_key_source: GallagherDesfireKeySource,
+    site_key_label: Option<String>,
+    picc_key: Option<[u8; 16]>,
 }
 
 fn parse_read_args(args: &[String]) -> Result<ReadArgs, String> {
     let mut desfire_only = false;
     let mut desfire_key_source = GallagherDesfireKeySource::DefaultSiteKey;
+    let mut site_key_label: Option<String> = None;
+    let mut picc_key: Option<[u8; 16]> = None;
     let mut iter = args.iter();
     while let Some(arg) = iter.next() {
         match arg.as_str() {
@@ -541,15 +617,37 @@ fn parse_read_args(args: &[String]) -> Result<ReadArgs, String> {
                         bytes.len()
                     )
                 })?;
-                desfire_key_source = GallagherDesfireKeySource::SiteKey(key);
+                desfire_key_source = GallagherDesfireKeySource::SiteKey(key.into());
+            }
+            "--sitekey-label" => {
+                let value = iter.next().ok_or("--sitekey-label requires a key label")?;
+                site_key_label = Some(value.clone());
+            }
+            "--picc-key" => {
+                let value = iter.next().ok_or("--picc-key requires 32 hex chars")?;
+                let bytes =
+                    parse_hex(value).ok_or_else(|| format!("--picc-key invalid hex: {value}"))?;
+                let key: [u8; 16] = bytes.as_slice().try_into().map_err(|_| {
+                    format!(
+                        "--picc-key must be 16 bytes (32 hex chars), got {}",
+                        bytes.len()
+                    )
+                })?;
+                picc_key = Some(key);
             }
             other => return Err(format!("unknown option: {other}")),
         }
     }
 
+    if site_key_label.is_some() && desfire_key_source != GallagherDesfireKeySource::DefaultSiteKey {
+        return Err("--sitekey and --sitekey-label are mutually exclusive".to_string());
+    }
+
     Ok(ReadArgs {
         desfire_only,
         desfire_key_source,
+        site_key_label,
+        picc_key,
     })
 }
 
@@ -570,22 +668,58 @@ fn parse_optional_aes_auth(args: &[String]) -> Result<Option<AesAuthSpec>, Strin
 
 struct ChangeKeyArgs {
     aid: ApplicationId,
-    auth: AesAuthSpec,
+    auth_key_number: KeyNumber,
+    auth_key: KeyArg,
     picc: bool,
     new_key_number: KeyNumber,
-    new_key: [u8; 16],
+    new_key: KeyArg,
     new_key_version: u8,
-    old_key: Option<[u8; 16]>,
+    old_key: Option<KeyArg>,
+}
+
+/// An AES key given in hex, or the label of a key on the PKCS#11 token.
+enum KeyArg {
+    Hex([u8; 16]),
+    Label(String),
+}
+
+impl KeyArg {
+    fn resolve(&self, token: &mut Pkcs11Token) -> Result<tapsmith_pkcs11::Pkcs11Key, String> {
+        match self {
+            // Hex keys join the token keys as session objects.
+            Self::Hex(key) => token.import_key(&Key::Aes128(*key)),
+            Self::Label(label) => token.find_key(label),
+        }
+        .map_err(|error| error.to_string())
+    }
+
+    fn software_key(&self) -> Result<Key, String> {
+        match self {
+            Self::Hex(key) => Ok(Key::Aes128(*key)),
+            Self::Label(_) => {
+                Err("key labels require --pkcs11-module and --pkcs11-token".to_string())
+            }
+        }
+    }
+}
+
+fn set_key_arg(slot: &mut Option<KeyArg>, value: KeyArg, option: &str) -> Result<(), String> {
+    if slot.replace(value).is_some() {
+        return Err(format!(
+            "{option} and its hex or label form are mutually exclusive"
+        ));
+    }
+    Ok(())
 }
 
 fn parse_change_key_args(args: &[String]) -> Result<ChangeKeyArgs, String> {
     let mut aid: Option<ApplicationId> = None;
-    let mut auth: Option<AesAuthSpec> = None;
+    let mut auth: Option<(KeyNumber, KeyArg)> = None;
     let mut picc = false;
     let mut new_key_number: Option<KeyNumber> = None;
-    let mut new_key: Option<[u8; 16]> = None;
+    let mut new_key: Option<KeyArg> = None;
     let mut new_key_version: u8 = 0;
-    let mut old_key: Option<[u8; 16]> = None;
+    let mut old_key: Option<KeyArg> = None;
     let mut iter = args.iter();
     while let Some(arg) = iter.next() {
         match arg.as_str() {
@@ -595,7 +729,30 @@ fn parse_change_key_args(args: &[String]) -> Result<ChangeKeyArgs, String> {
             }
             "--auth-aes" => {
                 let v = iter.next().ok_or("--auth-aes requires <n>:<32hex>")?;
-                auth = Some(parse_aes_auth_spec(v)?);
+                let spec = parse_aes_auth_spec(v)?;
+                if auth
+                    .replace((spec.key_number, KeyArg::Hex(spec.key)))
+                    .is_some()
+                {
+                    return Err("--auth-aes and --auth-label are mutually exclusive".to_string());
+                }
+            }
+            "--auth-label" => {
+                let v = iter.next().ok_or("--auth-label requires <n>:<label>")?;
+                let (number, label) = v
+                    .split_once(':')
+                    .ok_or("--auth-label expects <key_number>:<label>")?;
+                let raw: u8 = number
+                    .parse()
+                    .map_err(|e| format!("--auth-label key number: {e}"))?;
+                let key_number = KeyNumber::new(raw)
+                    .map_err(|e| format!("--auth-label invalid key number: {e:?}"))?;
+                if auth
+                    .replace((key_number, KeyArg::Label(label.to_string())))
+                    .is_some()
+                {
+                    return Err("--auth-aes and --auth-label are mutually exclusive".to_string());
+                }
             }
             "--picc" => {
                 picc = true;
@@ -610,12 +767,17 @@ fn parse_change_key_args(args: &[String]) -> Result<ChangeKeyArgs, String> {
             "--newkey" => {
                 let v = iter.next().ok_or("--newkey requires 32 hex chars")?;
                 let bytes = parse_hex(v).ok_or_else(|| format!("--newkey invalid hex: {v}"))?;
-                new_key = Some(bytes.as_slice().try_into().map_err(|_| {
+                let key = bytes.as_slice().try_into().map_err(|_| {
                     format!(
                         "--newkey must be 16 bytes (32 hex chars), got {}",
                         bytes.len()
                     )
-                })?);
+                })?;
+                set_key_arg(&mut new_key, KeyArg::Hex(key), "--newkey")?;
+            }
+            "--newkey-label" => {
+                let v = iter.next().ok_or("--newkey-label requires a key label")?;
+                set_key_arg(&mut new_key, KeyArg::Label(v.clone()), "--newkey")?;
             }
             "--newver" => {
                 let v = iter.next().ok_or("--newver requires a number 0-255")?;
@@ -624,12 +786,17 @@ fn parse_change_key_args(args: &[String]) -> Result<ChangeKeyArgs, String> {
             "--oldkey" => {
                 let v = iter.next().ok_or("--oldkey requires 32 hex chars")?;
                 let bytes = parse_hex(v).ok_or_else(|| format!("--oldkey invalid hex: {v}"))?;
-                old_key = Some(bytes.as_slice().try_into().map_err(|_| {
+                let key = bytes.as_slice().try_into().map_err(|_| {
                     format!(
                         "--oldkey must be 16 bytes (32 hex chars), got {}",
                         bytes.len()
                     )
-                })?);
+                })?;
+                set_key_arg(&mut old_key, KeyArg::Hex(key), "--oldkey")?;
+            }
+            "--oldkey-label" => {
+                let v = iter.next().ok_or("--oldkey-label requires a key label")?;
+                set_key_arg(&mut old_key, KeyArg::Label(v.clone()), "--oldkey")?;
             }
             other => return Err(format!("unknown option: {other}")),
         }
@@ -645,37 +812,95 @@ fn parse_change_key_args(args: &[String]) -> Result<ChangeKeyArgs, String> {
     } else {
         aid.ok_or("--aid is required for app-level key change")?
     };
+    let (auth_key_number, auth_key) = auth.ok_or("--auth-aes or --auth-label is required")?;
     Ok(ChangeKeyArgs {
         aid: resolved_aid,
-        auth: auth.ok_or("--auth-aes is required")?,
+        auth_key_number,
+        auth_key,
         picc,
         new_key_number: new_key_number.unwrap_or(KeyNumber::new(0).unwrap()),
-        new_key: new_key.ok_or("--newkey is required")?,
+        new_key: new_key.ok_or("--newkey or --newkey-label is required")?,
         new_key_version,
         old_key,
     })
 }
 
-fn change_key_desfire<T: Transport>(transport: T, args: &ChangeKeyArgs) {
+fn change_key_desfire<T: Transport>(
+    transport: T,
+    args: &ChangeKeyArgs,
+    token: Option<&mut Pkcs11Token>,
+) {
     let mut desfire = Desfire::new(transport, WrappedFraming);
     if let Err(e) = desfire.select_application(args.aid) {
         eprintln!("  SelectApplication failed: {e:?}");
         return;
     }
 
-    let rnd_a = match random_rnd_a() {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("  /dev/urandom: {e}");
-            return;
+    if let Some(token) = token {
+        let keys = args.auth_key.resolve(token).and_then(|auth_key| {
+            let new_key = args.new_key.resolve(token)?;
+            let old_key = args.old_key.as_ref().map(|key| key.resolve(token));
+            Ok((auth_key, new_key, old_key.transpose()?))
+        });
+        let (auth_key, new_key, old_key) = match keys {
+            Ok(keys) => keys,
+            Err(e) => {
+                eprintln!("  PKCS#11 key lookup failed: {e}");
+                return;
+            }
+        };
+        change_key_with_provider(
+            &mut desfire,
+            token,
+            &auth_key,
+            &new_key,
+            old_key.as_ref(),
+            args,
+        );
+        if let Some(error) = token.last_error() {
+            eprintln!("  PKCS#11: {error}");
         }
-    };
-    match desfire.authenticate_aes_with_rnd_a(args.auth.key_number, &args.auth.key, rnd_a) {
-        Ok(session) => println!(
-            "  Authenticated as key {} (session key: {:02X?}).",
-            args.auth.key_number.as_byte(),
-            session.session_key()
-        ),
+    } else {
+        let keys = args.auth_key.software_key().and_then(|auth_key| {
+            let new_key = args.new_key.software_key()?;
+            let old_key = args.old_key.as_ref().map(KeyArg::software_key);
+            Ok((auth_key, new_key, old_key.transpose()?))
+        });
+        let (auth_key, new_key, old_key) = match keys {
+            Ok(keys) => keys,
+            Err(e) => {
+                eprintln!("  {e}");
+                return;
+            }
+        };
+        change_key_with_provider(
+            &mut desfire,
+            &mut SoftwareCrypto,
+            &auth_key,
+            &new_key,
+            old_key.as_ref(),
+            args,
+        );
+    }
+}
+
+fn change_key_with_provider<T: Transport, P: CryptoProvider>(
+    desfire: &mut Desfire<T, WrappedFraming>,
+    provider: &mut P,
+    auth_key: &P::KeyHandle,
+    new_key: &P::KeyHandle,
+    old_key: Option<&P::KeyHandle>,
+    args: &ChangeKeyArgs,
+) {
+    match desfire.authenticate_with_provider(
+        args.auth_key_number,
+        provider,
+        auth_key,
+        &mut OsRandom,
+    ) {
+        // No session key in the output: it would undo keeping the auth key
+        // in a token.
+        Ok(_) => println!("  Authenticated as key {}.", args.auth_key_number.as_byte()),
         Err(e) => {
             eprintln!("  Auth failed: {e:?}");
             return;
@@ -687,12 +912,12 @@ fn change_key_desfire<T: Transport>(transport: T, args: &ChangeKeyArgs) {
             "  Changing PICC master key (version 0x{:02X}) [session will be cleared]...",
             args.new_key_version
         );
-        desfire.change_picc_key_aes(args.new_key, args.new_key_version)
+        desfire.change_picc_key_with_provider(provider, new_key, args.new_key_version)
     } else {
-        let same_key = args.new_key_number == args.auth.key_number;
-        if !same_key && args.old_key.is_none() {
+        let same_key = args.new_key_number == args.auth_key_number;
+        if !same_key && old_key.is_none() {
             eprintln!(
-                "  Changing key {} requires --oldkey (current value of that slot).",
+                "  Changing key {} requires --oldkey or --oldkey-label (current value of that slot).",
                 args.new_key_number.as_byte()
             );
             return;
@@ -707,11 +932,12 @@ fn change_key_desfire<T: Transport>(transport: T, args: &ChangeKeyArgs) {
                 ""
             },
         );
-        desfire.change_key_aes(
+        desfire.change_key_with_provider(
             args.new_key_number,
-            args.new_key,
+            provider,
+            new_key,
             args.new_key_version,
-            args.old_key,
+            old_key,
         )
     };
 
@@ -726,14 +952,7 @@ fn format_desfire<T: Transport>(transport: T, auth: Option<AesAuthSpec>) {
     let _ = desfire.select_application(ApplicationId::PICC);
 
     if let Some(auth) = auth {
-        let rnd_a = match random_rnd_a() {
-            Ok(v) => v,
-            Err(e) => {
-                eprintln!("  /dev/urandom: {e}");
-                return;
-            }
-        };
-        match desfire.authenticate_aes_with_rnd_a(auth.key_number, &auth.key, rnd_a) {
+        match desfire.authenticate_aes(auth.key_number, &auth.key, &mut OsRandom) {
             Ok(_) => println!("  PICC authenticated."),
             Err(e) => {
                 eprintln!("  PICC auth failed: {e:?}");
@@ -753,14 +972,7 @@ fn provision_desfire<T: Transport>(transport: T, args: &ProvisionArgs) {
     let _ = desfire.select_application(ApplicationId::PICC);
 
     if let Some(auth) = args.picc_auth {
-        let rnd_a = match random_rnd_a() {
-            Ok(v) => v,
-            Err(e) => {
-                eprintln!("  /dev/urandom: {e}");
-                return;
-            }
-        };
-        match desfire.authenticate_aes_with_rnd_a(auth.key_number, &auth.key, rnd_a) {
+        match desfire.authenticate_aes(auth.key_number, &auth.key, &mut OsRandom) {
             Ok(_) => println!("  PICC authenticated."),
             Err(e) => {
                 eprintln!("  PICC auth failed: {e:?}");
@@ -782,14 +994,7 @@ fn provision_desfire<T: Transport>(transport: T, args: &ProvisionArgs) {
         eprintln!("  SelectApplication failed: {e:?}");
         return;
     }
-    let rnd_a = match random_rnd_a() {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("  /dev/urandom: {e}");
-            return;
-        }
-    };
-    match desfire.authenticate_aes_with_rnd_a(KeyNumber::new(0).unwrap(), &[0u8; 16], rnd_a) {
+    match desfire.authenticate_aes(KeyNumber::new(0).unwrap(), &[0u8; 16], &mut OsRandom) {
         Ok(_) => println!("  App authenticated (default key)."),
         Err(e) => {
             eprintln!("  App auth failed: {e:?}");
@@ -873,14 +1078,7 @@ fn delete_desfire<T: Transport>(transport: T, args: &DeleteArgs) {
     if args.files.is_empty() {
         // No files specified: delete the whole application (PICC-level auth).
         if let Some(auth) = args.auth {
-            let rnd_a = match random_rnd_a() {
-                Ok(v) => v,
-                Err(e) => {
-                    eprintln!("  /dev/urandom: {e}");
-                    return;
-                }
-            };
-            match desfire.authenticate_aes_with_rnd_a(auth.key_number, &auth.key, rnd_a) {
+            match desfire.authenticate_aes(auth.key_number, &auth.key, &mut OsRandom) {
                 Ok(_) => println!("  PICC authenticated."),
                 Err(e) => {
                     eprintln!("  PICC auth failed: {e:?}");
@@ -899,14 +1097,7 @@ fn delete_desfire<T: Transport>(transport: T, args: &DeleteArgs) {
             return;
         }
         if let Some(auth) = args.auth {
-            let rnd_a = match random_rnd_a() {
-                Ok(v) => v,
-                Err(e) => {
-                    eprintln!("  /dev/urandom: {e}");
-                    return;
-                }
-            };
-            match desfire.authenticate_aes_with_rnd_a(auth.key_number, &auth.key, rnd_a) {
+            match desfire.authenticate_aes(auth.key_number, &auth.key, &mut OsRandom) {
                 Ok(_) => println!("  App authenticated."),
                 Err(e) => {
                     eprintln!("  App auth failed: {e:?}");
@@ -923,12 +1114,16 @@ fn delete_desfire<T: Transport>(transport: T, args: &DeleteArgs) {
     }
 }
 
-fn read_gallagher_tag<T: Tag + Transport>(tag: &mut T, args: &ReadArgs) {
+fn read_gallagher_tag<T: Tag + Transport>(
+    tag: &mut T,
+    args: &ReadArgs,
+    token: Option<&mut Pkcs11Token>,
+) {
     if !args.desfire_only {
         read_gallagher_classic_tag(tag);
     }
 
-    read_gallagher_desfire_tag(tag, args);
+    read_gallagher_desfire_tag(tag, args, token);
 }
 
 fn read_gallagher_classic_tag<T: Tag>(tag: &mut T) {
@@ -1030,22 +1225,54 @@ fn print_classic_cad(cad: &CardApplicationDirectory) {
     }
 }
 
-fn read_gallagher_desfire_tag<T: Transport>(transport: &mut T, args: &ReadArgs) {
+fn read_gallagher_desfire_tag<T: Transport>(
+    transport: &mut T,
+    args: &ReadArgs,
+    token: Option<&mut Pkcs11Token>,
+) {
     println!("\n=== DESFire Credentials ===");
-    let rnd_a = match random_rnd_a() {
-        Ok(value) => value,
-        Err(error) => {
-            eprintln!("  /dev/urandom: {error}");
+
+    let mut desfire = Desfire::new(transport, WrappedFraming);
+    // Random-UID cards only reveal the real UID after authentication.
+    let uid = match args.picc_key {
+        Some(picc_key) => {
+            match GallagherDesfireReader::read_card_uid(&mut desfire, &picc_key, &mut OsRandom) {
+                Ok(uid) => {
+                    println!("  Card UID: {uid:02X?}");
+                    Some(uid)
+                }
+                Err(error) => {
+                    eprintln!("  GetCardUID failed: {error:?}");
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+    let read = match (&args.site_key_label, token) {
+        (Some(label), Some(token)) => {
+            read_gallagher_desfire_with_token(&mut desfire, token, label, uid)
+        }
+        (Some(_), None) => {
+            eprintln!("  --sitekey-label requires --pkcs11-module and --pkcs11-token");
             return;
         }
+        (None, _) => match uid {
+            Some(uid) => GallagherDesfireReader::read_from_desfire_with_uid(
+                &mut desfire,
+                &args.desfire_key_source,
+                uid,
+                &mut OsRandom,
+            ),
+            None => GallagherDesfireReader::read_from_desfire(
+                &mut desfire,
+                &args.desfire_key_source,
+                &mut OsRandom,
+            ),
+        }
+        .map_err(|error| format!("{error:?}")),
     };
-
-    let mut desfire = Desfire::new(transport, WrappedFraming);
-    match GallagherDesfireReader::read_from_desfire_with_rnd_a(
-        &mut desfire,
-        args.desfire_key_source,
-        rnd_a,
-    ) {
+    match read {
         Ok(result) => {
             for credential in &result.credentials {
                 let aid = credential.application_id.as_bytes();
@@ -1063,10 +1290,38 @@ fn read_gallagher_desfire_tag<T: Transport>(transport: &mut T, args: &ReadArgs)
                 );
             }
         }
-        Err(error) => eprintln!("  DESFire credential read failed: {error:?}"),
+        Err(error) => eprintln!("  DESFire credential read failed: {error}"),
     }
 }
 
+fn read_gallagher_desfire_with_token<T: Transport>(
+    desfire: &mut Desfire<T, WrappedFraming>,
+    token: &mut Pkcs11Token,
+    label: &str,
+    uid: Option<[u8; 7]>,
+) -> Result<GallagherDesfire, String> {
+    let site_key = token.find_key(label).map_err(|error| error.to_string())?;
+    let uid = match uid {
+        Some(uid) => uid,
+        None => desfire
+            .select_application(ApplicationId::PICC)
+            .and_then(|()| desfire.get_version())
+            .map_err(|error| format!("{error:?}"))?
+            .uid(),
+    };
+    GallagherDesfireReader::read_from_desfire_with_provider(
+        desfire,
+        token,
+        &site_key,
+        uid,
+        &mut OsRandom,
+    )
+    .map_err(|error| match token.last_error() {
+        Some(token_error) => format!("{error:?} ({token_error})"),
+        None => format!("{error:?}"),
+    })
+}
+
 fn write_gallagher_tag<T: Tag>(tag: &mut T, credential: GallagherCredential) {
     let cad_non_mad = NonMadSector::try_from(Sector::from(CAD_SECTOR)).unwrap();
     let cred_non_mad = NonMadSector::try_from(Sector::from(CREDENTIAL_SECTOR)).unwrap();
@@ -1107,6 +1362,24 @@ fn write_gallagher_tag<T: Tag>(tag: &mut T, credential: GallagherCredential) {
     mad.write_to_tag(tag, &WriteKeyProvider).unwrap();
 }
 
+fn print_originality<T: Transport>(desfire: &mut Desfire<T, WrappedFraming>, version: VersionInfo) {
+    match desfire.read_sig() {
+        Ok(signature) => match verify_originality(version, &signature) {
+            Originality::Genuine(family) => {
+                println!("  Originality:      genuine {}", family.name());
+            }
+            Originality::Invalid(family) => {
+                println!(
+                    "  Originality:      INVALID signature for {}",
+                    family.name()
+                );
+            }
+            Originality::UnknownFamily => println!("  Originality:      unknown product family"),
+        },
+        Err(error) => eprintln!("  Read_Sig failed: {error:?}"),
+    }
+}
+
 fn read_desfire_tag<T: Transport>(transport: T, args: &DesfireArgs) {
     let mut desfire = Desfire::new(transport, WrappedFraming);
 
@@ -1144,6 +1417,7 @@ fn read_desfire_tag<T: Transport>(transport: T, args: &DesfireArgs) {
                 version.production_week_decimal(),
                 version.production_year_decimal()
             );
+            print_originality(&mut desfire, version);
         }
         Err(error) => {
             eprintln!("  GetVersion failed: {error:?}");
@@ -1270,44 +1544,16 @@ fn run_desfire_auth_pass<T: Transport, C: FrameCodec>(
 
     let auth_result = match auth_spec {
         DesfireAuthSpec::Aes(spec) => {
-            let rnd_a = match random_rnd_a() {
-                Ok(value) => value,
-                Err(error) => {
-                    eprintln!("    /dev/urandom failed: {error}");
-                    return;
-                }
-            };
-            desfire.authenticate_aes_with_rnd_a(spec.key_number, &spec.key, rnd_a)
+            desfire.authenticate_aes(spec.key_number, &spec.key, &mut OsRandom)
         }
         DesfireAuthSpec::Des(spec) => {
-            let rnd_a = match random_rnd_a8() {
-                Ok(value) => value,
-                Err(error) => {
-                    eprintln!("    /dev/urandom failed: {error}");
-                    return;
-                }
-            };
-            desfire.authenticate_des_with_rnd_a(spec.key_number, &spec.key, rnd_a)
+            desfire.authenticate_des(spec.key_number, &spec.key, &mut OsRandom)
         }
         DesfireAuthSpec::Tdea2(spec) => {
-            let rnd_a = match random_rnd_a8() {
-                Ok(value) => value,
-                Err(error) => {
-                    eprintln!("    /dev/urandom failed: {error}");
-                    return;
-                }
-            };
-            desfire.authenticate_2tdea_with_rnd_a(spec.key_number, &spec.key, rnd_a)
+            desfire.authenticate_2tdea(spec.key_number, &spec.key, &mut OsRandom)
         }
         DesfireAuthSpec::Tdea3(spec) => {
-            let rnd_a = match random_rnd_a() {
-                Ok(value) => value,
-                Err(error) => {
-                    eprintln!("    /dev/urandom failed: {error}");
-                    return;
-                }
-            };
-            desfire.authenticate_3tdea_with_rnd_a(spec.key_number, &spec.key, rnd_a)
+            desfire.authenticate_3tdea(spec.key_number, &spec.key, &mut OsRandom)
         }
     };
 
@@ -1316,6 +1562,10 @@ fn run_desfire_auth_pass<T: Transport, C: FrameCodec>(
         return;
     }
     println!("    Authenticated.");
+    match desfire.get_card_uid() {
+        Ok(uid) => println!("    Card UID: {uid:02X?}"),
+        Err(error) => eprintln!("    GetCardUID failed: {error:?}"),
+    }
 
     for (file_id, settings) in file_settings {
         let Some(settings) = settings else { continue };
@@ -1459,7 +1709,7 @@ fn debug_encrypted_read<T: Transport, C: FrameCodec>(
         length.as_u32()
     );
 
-    let Some(mut session) = desfire.authenticated_session() else {
+    let Some(mut session) = desfire.authenticated_session().cloned() else {
         eprintln!("      not authenticated");
         return;
     };
@@ -1525,7 +1775,7 @@ fn debug_encrypted_read<T: Transport, C: FrameCodec>(
         eprintln!("      response too large for debug buffer");
         return;
     }
-    aes_cbc_decrypt_in_place(&session_key.as_bytes(), &post_iv, decrypted.as_mut_slice());
+    aes_cbc_decrypt_in_place(session_key.as_bytes(), &post_iv, decrypted.as_mut_slice());
     println!(
         "      decrypted ({} bytes): {:02X?}",
         decrypted.len(),
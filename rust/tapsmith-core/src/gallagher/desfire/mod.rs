@@ -12,11 +12,12 @@ use crate::{
     gallagher::credential::{CredentialError, GallagherCredential},
     mifare::desfire::{
         application::ApplicationId,
-        crypto::RndA,
         error::Error as DesfireError,
         file::{CommunicationMode, FileId, FileSettingsDetails},
         framing::FrameCodec,
-        key::KeyNumber,
+        key::{Key, KeyNumber},
+        provider::{CryptoProvider, SoftwareCrypto},
+        rng::RandomSource,
         transport::Transport,
         types::U24,
         Desfire,
@@ -81,34 +82,81 @@ impl From<CredentialError> for Error {
 pub struct GallagherDesfireReader;
 
 impl GallagherDesfireReader {
-    /// Reads Gallagher `DESFire` credentials using a fixed reader challenge.
+    /// Reads Gallagher `DESFire` credentials, drawing a fresh AES reader challenge per application.
     ///
-    /// Prefer [`Self::read_from_desfire_with_rnd_a`] when the caller can provide
-    /// fresh random bytes for AES authentication.
-    pub fn read_from_desfire<T, C>(
+    /// The diversification UID comes from `GetVersion`. On cards with random UID
+    /// enabled that value is not the real UID; use
+    /// [`Self::read_from_desfire_with_uid`] with the UID from
+    /// [`Self::read_card_uid`] or [`Desfire::get_card_uid`] instead.
+    pub fn read_from_desfire<T, C, R>(
         desfire: &mut Desfire<T, C>,
-        key_source: GallagherDesfireKeySource,
+        key_source: &GallagherDesfireKeySource,
+        random: &mut R,
     ) -> Result<GallagherDesfire, Error>
     where
         T: Transport,
         C: FrameCodec,
+        R: RandomSource + ?Sized,
     {
-        Self::read_from_desfire_with_rnd_a(desfire, key_source, RndA::new([0u8; 16]))
+        desfire.select_application(ApplicationId::PICC)?;
+        let uid = desfire.get_version()?.uid();
+
+        Self::read_from_desfire_with_uid(desfire, key_source, uid, random)
     }
 
-    /// Reads Gallagher `DESFire` credentials using caller-provided AES reader randomness.
-    pub fn read_from_desfire_with_rnd_a<T, C>(
+    /// Reads the real card UID by authenticating to the PICC with an AES master key.
+    ///
+    /// Needed before reading cards with random UID enabled, where key
+    /// diversification must use the UID returned by `GetCardUID`.
+    pub fn read_card_uid<T, C, R>(
         desfire: &mut Desfire<T, C>,
-        key_source: GallagherDesfireKeySource,
-        rnd_a: RndA,
-    ) -> Result<GallagherDesfire, Error>
+        picc_key: &[u8; 16],
+        random: &mut R,
+    ) -> Result<[u8; 7], Error>
     where
         T: Transport,
         C: FrameCodec,
+        R: RandomSource + ?Sized,
     {
         desfire.select_application(ApplicationId::PICC)?;
-        let uid = desfire.get_version()?.uid();
+        desfire.authenticate_aes(KeyNumber::new(0).expect("key 0 is valid"), picc_key, random)?;
+        Ok(desfire.get_card_uid()?)
+    }
+
+    /// Reads Gallagher `DESFire` credentials, diversifying keys with a caller-supplied UID.
+    pub fn read_from_desfire_with_uid<T, C, R>(
+        desfire: &mut Desfire<T, C>,
+        key_source: &GallagherDesfireKeySource,
+        uid: [u8; 7],
+        random: &mut R,
+    ) -> Result<GallagherDesfire, Error>
+    where
+        T: Transport,
+        C: FrameCodec,
+        R: RandomSource + ?Sized,
+    {
+        let site_key = Key::Aes128(*key_source.site_key());
+        Self::read_from_desfire_with_provider(desfire, &mut SoftwareCrypto, &site_key, uid, random)
+    }
 
+    /// Reads Gallagher `DESFire` credentials with a site key held by a crypto provider.
+    ///
+    /// Application keys are diversified by `provider`, used for one
+    /// authentication and then released. `uid` is the diversification UID, as
+    /// for [`Self::read_from_desfire_with_uid`].
+    pub fn read_from_desfire_with_provider<T, C, P, R>(
+        desfire: &mut Desfire<T, C>,
+        provider: &mut P,
+        site_key: &P::KeyHandle,
+        uid: [u8; 7],
+        random: &mut R,
+    ) -> Result<GallagherDesfire, Error>
+    where
+        T: Transport,
+        C: FrameCodec,
+        P: CryptoProvider + ?Sized,
+        R: RandomSource + ?Sized,
+    {
         let mut candidates: Vec<CandidateApplication, MAX_GALLAGHER_DESFIRE_CREDENTIALS> =
             Vec::new();
         if cad::read_candidates(desfire, &mut candidates).is_err() {
@@ -121,7 +169,7 @@ impl GallagherDesfireReader {
         let mut credentials = Vec::new();
         for candidate in candidates {
             if let Ok(credential) =
-                read_credential_application(desfire, key_source, uid, rnd_a, candidate)
+                read_credential_application(desfire, provider, site_key, uid, random, candidate)
             {
                 let _ = credentials.push(credential);
             }
@@ -154,27 +202,28 @@ fn push_card_data_range(
     }
 }
 
-fn read_credential_application<T, C>(
+fn read_credential_application<T, C, P, R>(
     desfire: &mut Desfire<T, C>,
-    key_source: GallagherDesfireKeySource,
+    provider: &mut P,
+    site_key: &P::KeyHandle,
     uid: [u8; 7],
-    rnd_a: RndA,
+    random: &mut R,
     candidate: CandidateApplication,
 ) -> Result<GallagherDesfireCredential, Error>
 where
     T: Transport,
     C: FrameCodec,
+    P: CryptoProvider + ?Sized,
+    R: RandomSource + ?Sized,
 {
     desfire.select_application(candidate.application_id)?;
 
     let key_number = KeyNumber::new(0).expect("key 0 is valid");
-    let key = key::diversify_aes_key(
-        key_source.site_key(),
-        &uid,
-        key_number.as_byte(),
-        candidate.application_id,
-    )?;
-    desfire.authenticate_aes_with_rnd_a(key_number, &key, rnd_a)?;
+    let input = key::kdf_input(&uid, key_number.as_byte(), candidate.application_id)?;
+    let key = provider.diversify(site_key, &input)?;
+    let authenticated = desfire.authenticate_with_provider(key_number, provider, &key, random);
+    provider.release(key)?;
+    authenticated?;
 
     let file_id = FileId::new(CARD_DATA_FILE_ID).expect("file 0 is valid");
     let settings = desfire.get_file_settings(file_id)?;
@@ -290,6 +339,80 @@ mod tests {
         assert_eq!(raw, encoded);
     }
 
+    #[cfg(feature = "std")]
+    #[test]
+    fn reads_credential_from_virtual_card() {
+        use crate::mifare::desfire::{
+            kdf::diversify_aes128_key, AccessCondition, AccessRights, ApplicationKeyType,
+            FixedRandom, Key, KeySettings, NativeFraming, VirtualApplication, VirtualDesfire,
+            VirtualFile,
+        };
+
+        let uid = [0x04, 0xA1, 0xB2, 0xC3, 0xD4, 0xE5, 0x80];
+        let credential_aid = ApplicationId::from_bytes([0xF4, 0x81, 0x20]);
+        let key_0 = KeyNumber::new(0).unwrap();
+        let input = key::kdf_input(&uid, 0, credential_aid).unwrap();
+        let app_key = diversify_aes128_key(&GALLAGHER_DEFAULT_SITE_KEY, &input).unwrap();
+        let free = AccessCondition::Free;
+        let cad_entry = [0x0C, 0x13, 0x37, 0x20, 0x81, 0xF4];
+
+        let mut card = VirtualDesfire::new()
+            .with_uid(uid)
+            .with_application(
+                VirtualApplication::new(
+                    ApplicationId::from_bytes(cad::GALLAGHER_DESFIRE_CAD_AID_BYTES),
+                    KeySettings::new(0x0B, ApplicationKeyType::Aes, 1),
+                )
+                .unwrap()
+                .with_file(
+                    FileId::new(0).unwrap(),
+                    VirtualFile::std_data(
+                        CommunicationMode::Plain,
+                        AccessRights::new(free, free, free, free),
+                        &[&cad_entry[..], &[0; 30]].concat(),
+                    ),
+                ),
+            )
+            .with_application(
+                VirtualApplication::new(
+                    credential_aid,
+                    KeySettings::new(0x0B, ApplicationKeyType::Aes, 3),
+                )
+                .unwrap()
+                .with_key(key_0, Key::Aes128(app_key), 0)
+                .unwrap()
+                .with_file(
+                    FileId::new(CARD_DATA_FILE_ID).unwrap(),
+                    VirtualFile::std_data(
+                        CommunicationMode::Enciphered,
+                        AccessRights::from_bytes([0x00, 0x00]),
+                        &EXAMPLE_CREDENTIAL_FILE,
+                    ),
+                ),
+            );
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+
+        let result = GallagherDesfireReader::read_from_desfire(
+            &mut desfire,
+            &GallagherDesfireKeySource::DefaultSiteKey,
+            &mut FixedRandom::new(&[0xA5]),
+        )
+        .unwrap();
+
+        let [credential] = result.credentials.as_slice() else {
+            panic!("expected one credential");
+        };
+        assert_eq!(credential.application_id, credential_aid);
+        assert_eq!(
+            credential.credential,
+            GallagherCredential::new(12, 0x1337, 0xF00D, 3).unwrap()
+        );
+        assert_eq!(
+            credential.cad_entry.map(|entry| entry.facility_code),
+            Some(0x1337)
+        );
+    }
+
     #[test]
     fn card_data_file_is_standard_data_when_settings_are_used() {
         let settings = crate::mifare::desfire::FileSettings::new(
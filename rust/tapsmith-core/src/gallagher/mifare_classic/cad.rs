@@ -3,7 +3,10 @@
 /// Based on: <https://github.com/megabug/gallagher-research/blob/master/formats/cad.md>
 use heapless::LinearMap;
 
-use crate::mifare::classic::{FourBlockOffset, FourBlockSector, KeyProvider, Tag};
+use crate::mifare::{
+    classic::{FourBlockOffset, FourBlockSector, KeyProvider, Tag},
+    desfire::secret::constant_time_eq,
+};
 
 use super::Error;
 
@@ -32,8 +35,7 @@ impl CardApplicationDirectory {
     }
 
     pub(crate) fn from_bytes(data: &[u8; 48], sector_hint: u8) -> Result<Self, Error> {
-        let expected_crc = (u16::from(data[0]) << 8) | u16::from(data[1]);
-        if crc16(&data[2..]) != expected_crc {
+        if !constant_time_eq(&crc16(&data[2..]).to_be_bytes(), &data[..2]) {
             return Err(Error::InvalidCadCrc(sector_hint));
         }
         // Bytes 2-3: unknown header (skip).
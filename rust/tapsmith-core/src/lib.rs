@@ -5,3 +5,6 @@ extern crate std;
 
 pub mod gallagher;
 pub mod mifare;
+pub mod ntag424;
+#[cfg(feature = "std")]
+pub mod trace;
@@ -9,6 +9,7 @@ pub use sector::FourBlockSector;
 pub use sector::Sector;
 pub use sector::Sector::*;
 pub use sector::SixteenBlockSector;
+pub use tag::AsyncTag;
 pub use tag::Error;
 pub use tag::KeyProvider;
 pub use tag::KeyType;
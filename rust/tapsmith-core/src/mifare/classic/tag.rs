@@ -27,6 +27,27 @@ pub trait Tag {
     fn write_block(&mut self, block: Block, data: [u8; 16]) -> Result<(), Error>;
 }
 
+/// Non-blocking counterpart of [`Tag`] for readers with asynchronous I/O.
+///
+/// Futures carry no `Send` bound so the trait works with single-threaded
+/// `no_std` executors.
+#[allow(async_fn_in_trait)]
+pub trait AsyncTag {
+    /// Authenticate to a specific sector on the tag using Key A or B.
+    async fn authenticate(
+        &mut self,
+        sector: Sector,
+        key: &[u8; 6],
+        key_type: KeyType,
+    ) -> Result<(), Error>;
+
+    /// Reads a 16-byte data block from the tag.
+    async fn read_block(&mut self, block: Block) -> Result<[u8; 16], Error>;
+
+    /// Writes a 16-byte data block to the tag.
+    async fn write_block(&mut self, block: Block, data: [u8; 16]) -> Result<(), Error>;
+}
+
 /// Represents errors that can occur during MIFARE Classic operations.
 #[derive(Debug)]
 pub enum Error {
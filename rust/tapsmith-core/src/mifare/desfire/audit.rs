@@ -0,0 +1,632 @@
+//! Default-key and weak-configuration audit of a `DESFire` card.
+//!
+//! [`audit`] tries every dictionary key against the PICC master key and
+//! every application key, then checks key settings and file access rights.
+//! The result is a flat list of [`Finding`]s; its `Display` form is one
+//! finding per line for scripts and fleet reports.
+
+use core::fmt;
+use std::vec::Vec;
+
+use heapless::Vec as HeaplessVec;
+
+use crate::mifare::desfire::{
+    application::ApplicationId,
+    client::Desfire,
+    error::Error,
+    file::{AccessCondition, CommunicationMode, FileId, FileSettings},
+    framing::FrameCodec,
+    inventory::{authenticate_key, MAX_INVENTORY_APPLICATIONS, MAX_INVENTORY_FILES},
+    key::{ApplicationKeyType, Key, KeyNumber, KeySettings},
+    rng::RandomSource,
+    transport::Transport,
+};
+
+/// Version written in the header of the report's text form.
+pub const AUDIT_FORMAT_VERSION: u32 = 1;
+
+const HEADER: &str = "tapsmith-audit";
+
+/// Keys tried against every key slot on the card.
+///
+/// A key is only tried against slots of a matching [`ApplicationKeyType`];
+/// DES keys count as 2TDEA. Slots whose type cannot be read get every key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyDictionary {
+    keys: Vec<Key>,
+}
+
+impl KeyDictionary {
+    /// Creates an empty dictionary.
+    pub const fn new() -> Self {
+        Self { keys: Vec::new() }
+    }
+
+    /// All-zero factory keys for 2TDEA, 3TDEA and AES slots.
+    pub fn factory() -> Self {
+        Self::new()
+            .with_key(Key::TwoKey3Des([0; 16]))
+            .with_key(Key::ThreeKey3Des([0; 24]))
+            .with_key(Key::Aes128([0; 16]))
+    }
+
+    /// Adds one key, ignoring duplicates.
+    #[must_use]
+    pub fn with_key(mut self, key: Key) -> Self {
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+        self
+    }
+
+    /// Keys in the order they are tried.
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    fn candidates(&self, key_type: Option<ApplicationKeyType>) -> impl Iterator<Item = &Key> + '_ {
+        self.keys.iter().filter(move |key| {
+            let Some(key_type) = key_type else {
+                return true;
+            };
+            matches!(
+                (key, key_type),
+                (
+                    Key::Des(_) | Key::TwoKey3Des(_),
+                    ApplicationKeyType::TwoKey3Des
+                ) | (Key::ThreeKey3Des(_), ApplicationKeyType::ThreeKey3Des)
+                    | (Key::Aes128(_), ApplicationKeyType::Aes)
+            )
+        })
+    }
+}
+
+/// How serious a finding is, lowest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Info,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl Severity {
+    const fn name(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// Where on the card a finding applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Picc,
+    Application(ApplicationId),
+    File(ApplicationId, FileId),
+}
+
+/// What an audit found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FindingKind {
+    /// A key slot opens with a dictionary key.
+    DictionaryKey { key_number: KeyNumber, key: Key },
+    /// The PICC master key is known, so `FormatPICC` can wipe the card.
+    ///
+    /// The format-disabled flag cannot be read back, so this is reported
+    /// whenever a dictionary key opens the PICC master key.
+    FormatPiccReachable,
+    /// The master key settings can still be changed.
+    SettingsChangeable,
+    /// Applications or files can be created and deleted without the master key.
+    FreeCreateDelete,
+    /// Applications, files and key settings can be listed without the master key.
+    FreeListing,
+    /// Anyone can read the file.
+    FreeRead,
+    /// Anyone can write the file.
+    FreeWrite,
+    /// Anyone can change the file's access rights.
+    FreeChangeAccess,
+    /// The file needs a key but travels in plain.
+    PlainCommunication,
+    /// This part of the card could not be inspected.
+    Unaudited(Error),
+}
+
+impl FindingKind {
+    /// Severity the audit assigns to this kind of finding.
+    pub fn severity(&self) -> Severity {
+        match self {
+            FindingKind::DictionaryKey { key_number, .. } if key_number.as_byte() == 0 => {
+                Severity::Critical
+            }
+            FindingKind::FormatPiccReachable => Severity::Critical,
+            FindingKind::DictionaryKey { .. }
+            | FindingKind::FreeWrite
+            | FindingKind::FreeChangeAccess => Severity::High,
+            FindingKind::FreeCreateDelete
+            | FindingKind::FreeRead
+            | FindingKind::PlainCommunication => Severity::Medium,
+            FindingKind::SettingsChangeable => Severity::Low,
+            FindingKind::FreeListing | FindingKind::Unaudited(_) => Severity::Info,
+        }
+    }
+}
+
+/// One audit result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub location: Location,
+    pub kind: FindingKind,
+}
+
+impl Finding {
+    fn new(location: Location, kind: FindingKind) -> Self {
+        Self {
+            severity: kind.severity(),
+            location,
+            kind,
+        }
+    }
+}
+
+/// Every finding of one audit, in the order the card was walked.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    pub findings: Vec<Finding>,
+}
+
+impl AuditReport {
+    /// Highest severity found, or `None` for a clean card.
+    pub fn highest_severity(&self) -> Option<Severity> {
+        self.findings.iter().map(|finding| finding.severity).max()
+    }
+
+    /// Findings at or above `severity`.
+    pub fn at_least(&self, severity: Severity) -> impl Iterator<Item = &Finding> {
+        self.findings
+            .iter()
+            .filter(move |finding| finding.severity >= severity)
+    }
+
+    fn push(&mut self, location: Location, kind: FindingKind) {
+        self.findings.push(Finding::new(location, kind));
+    }
+}
+
+/// Audits the whole card with `dictionary`.
+///
+/// Each authentication attempt draws a fresh reader challenge from `random`.
+/// Only failing to select the PICC aborts the audit; anything else that cannot be
+/// inspected is reported as [`FindingKind::Unaudited`].
+pub fn audit<T, C, R>(
+    desfire: &mut Desfire<T, C>,
+    dictionary: &KeyDictionary,
+    random: &mut R,
+) -> Result<AuditReport, Error>
+where
+    T: Transport,
+    C: FrameCodec,
+    R: RandomSource + ?Sized,
+{
+    let mut report = AuditReport::default();
+    desfire.select_application(ApplicationId::PICC)?;
+    let (key_settings, master) = audit_keys(
+        desfire,
+        dictionary,
+        ApplicationId::PICC,
+        random,
+        &mut report,
+    )?;
+    if master.is_some() {
+        report.push(Location::Picc, FindingKind::FormatPiccReachable);
+    }
+    if let Some(key_settings) = key_settings {
+        audit_key_settings(Location::Picc, key_settings, &mut report);
+    }
+
+    let mut application_ids: HeaplessVec<ApplicationId, MAX_INVENTORY_APPLICATIONS> =
+        HeaplessVec::new();
+    if let Err(error) = desfire.get_application_ids(&mut application_ids) {
+        report.push(Location::Picc, FindingKind::Unaudited(error));
+        return Ok(report);
+    }
+    for application_id in application_ids {
+        audit_application(desfire, dictionary, application_id, random, &mut report);
+    }
+    Ok(report)
+}
+
+fn audit_application<T, C, R>(
+    desfire: &mut Desfire<T, C>,
+    dictionary: &KeyDictionary,
+    application_id: ApplicationId,
+    random: &mut R,
+    report: &mut AuditReport,
+) where
+    T: Transport,
+    C: FrameCodec,
+    R: RandomSource + ?Sized,
+{
+    let location = Location::Application(application_id);
+    let key_settings = match desfire.select_application(application_id) {
+        Ok(()) => audit_keys(desfire, dictionary, application_id, random, report),
+        Err(error) => Err(error),
+    };
+    let key_settings = match key_settings {
+        Ok((key_settings, _)) => key_settings,
+        Err(error) => {
+            report.push(location, FindingKind::Unaudited(error));
+            return;
+        }
+    };
+    if let Some(key_settings) = key_settings {
+        audit_key_settings(location, key_settings, report);
+    }
+
+    let mut file_ids: HeaplessVec<FileId, MAX_INVENTORY_FILES> = HeaplessVec::new();
+    if let Err(error) = desfire.get_file_ids(&mut file_ids) {
+        report.push(location, FindingKind::Unaudited(error));
+        return;
+    }
+    for file_id in file_ids {
+        let location = Location::File(application_id, file_id);
+        match desfire.get_file_settings(file_id) {
+            Ok(settings) => audit_file(location, settings, report),
+            Err(error) => report.push(location, FindingKind::Unaudited(error)),
+        }
+    }
+}
+
+/// Tries the dictionary on every key slot of the selected application.
+///
+/// Returns the key settings, when readable, and the master key, when found.
+/// Other slots are tried last and the master key re-authenticated after them,
+/// so the caller can keep listing with whatever access the master key grants.
+fn audit_keys<T, C, R>(
+    desfire: &mut Desfire<T, C>,
+    dictionary: &KeyDictionary,
+    application_id: ApplicationId,
+    random: &mut R,
+    report: &mut AuditReport,
+) -> Result<(Option<KeySettings>, Option<Key>), Error>
+where
+    T: Transport,
+    C: FrameCodec,
+    R: RandomSource + ?Sized,
+{
+    let location = if application_id == ApplicationId::PICC {
+        Location::Picc
+    } else {
+        Location::Application(application_id)
+    };
+    let master_key_number = KeyNumber::new(0).expect("key 0 is valid");
+
+    let mut key_settings = desfire.get_key_settings();
+    let key_type = key_settings.ok().map(KeySettings::key_type);
+    let master = find_key(desfire, dictionary, master_key_number, key_type, random);
+    if let Some(key) = &master {
+        report.push(
+            location,
+            FindingKind::DictionaryKey {
+                key_number: master_key_number,
+                key: key.clone(),
+            },
+        );
+        if key_settings.is_err() {
+            key_settings = desfire.get_key_settings();
+        }
+    }
+    let settings = match key_settings {
+        Ok(settings) => settings,
+        Err(error) => {
+            report.push(location, FindingKind::Unaudited(error));
+            return Ok((None, master));
+        }
+    };
+
+    // The PICC has only its master key.
+    if application_id != ApplicationId::PICC {
+        for key_number in (1..settings.key_count()).filter_map(|n| KeyNumber::new(n).ok()) {
+            if let Some(key) = find_key(
+                desfire,
+                dictionary,
+                key_number,
+                Some(settings.key_type()),
+                random,
+            ) {
+                report.push(location, FindingKind::DictionaryKey { key_number, key });
+            }
+        }
+        desfire.select_application(application_id)?;
+        if let Some(key) = &master {
+            authenticate_key(desfire, master_key_number, key, random)?;
+        }
+    }
+    Ok((Some(settings), master))
+}
+
+/// Returns the first dictionary key that authenticates `key_number`.
+///
+/// A failed attempt drops the card's session, so the client's is cleared too.
+fn find_key<T, C, R>(
+    desfire: &mut Desfire<T, C>,
+    dictionary: &KeyDictionary,
+    key_number: KeyNumber,
+    key_type: Option<ApplicationKeyType>,
+    random: &mut R,
+) -> Option<Key>
+where
+    T: Transport,
+    C: FrameCodec,
+    R: RandomSource + ?Sized,
+{
+    dictionary
+        .candidates(key_type)
+        .find(|key| {
+            let found = authenticate_key(desfire, key_number, key, random).is_ok();
+            if !found {
+                desfire.clear_session();
+            }
+            found
+        })
+        .cloned()
+}
+
+fn audit_key_settings(location: Location, key_settings: KeySettings, report: &mut AuditReport) {
+    if key_settings.configuration_changeable() {
+        report.push(location, FindingKind::SettingsChangeable);
+    }
+    if key_settings.free_create_delete() {
+        report.push(location, FindingKind::FreeCreateDelete);
+    }
+    if key_settings.free_list() {
+        report.push(location, FindingKind::FreeListing);
+    }
+}
+
+fn audit_file(location: Location, settings: FileSettings, report: &mut AuditReport) {
+    let rights = settings.access_rights();
+    let free = AccessCondition::Free;
+    let free_read = rights.read() == free || rights.read_write() == free;
+    let free_write = rights.write() == free || rights.read_write() == free;
+    if free_read {
+        report.push(location, FindingKind::FreeRead);
+    }
+    if free_write {
+        report.push(location, FindingKind::FreeWrite);
+    }
+    if rights.change() == free {
+        report.push(location, FindingKind::FreeChangeAccess);
+    }
+
+    // Free access is always plain, so only keyed files count as sensitive.
+    let keyed = [rights.read(), rights.write(), rights.read_write()]
+        .iter()
+        .any(|condition| matches!(condition, AccessCondition::Key(_)));
+    if keyed
+        && !free_read
+        && !free_write
+        && settings.communication_mode() == CommunicationMode::Plain
+    {
+        report.push(location, FindingKind::PlainCommunication);
+    }
+}
+
+/// One finding per line after a `tapsmith-audit` header:
+/// `<severity> <location> <kind> [arguments]`.
+impl fmt::Display for AuditReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{HEADER} {AUDIT_FORMAT_VERSION}")?;
+        for finding in &self.findings {
+            write!(f, "{} ", finding.severity.name())?;
+            match finding.location {
+                Location::Picc => write!(f, "picc")?,
+                Location::Application(aid) => write!(f, "application {}", Hex(&aid.as_bytes()))?,
+                Location::File(aid, file_id) => {
+                    write!(f, "file {} {}", Hex(&aid.as_bytes()), file_id.as_byte())?;
+                }
+            }
+            match &finding.kind {
+                FindingKind::DictionaryKey { key_number, key } => {
+                    let (name, bytes): (&str, &[u8]) = match key {
+                        Key::Des(bytes) => ("des", bytes),
+                        Key::TwoKey3Des(bytes) => ("2tdea", bytes),
+                        Key::ThreeKey3Des(bytes) => ("3tdea", bytes),
+                        Key::Aes128(bytes) => ("aes", bytes),
+                    };
+                    write!(
+                        f,
+                        " dictionary-key {} {name} {}",
+                        key_number.as_byte(),
+                        Hex(bytes)
+                    )?;
+                }
+                FindingKind::FormatPiccReachable => write!(f, " format-picc-reachable")?,
+                FindingKind::SettingsChangeable => write!(f, " settings-changeable")?,
+                FindingKind::FreeCreateDelete => write!(f, " free-create-delete")?,
+                FindingKind::FreeListing => write!(f, " free-listing")?,
+                FindingKind::FreeRead => write!(f, " free-read")?,
+                FindingKind::FreeWrite => write!(f, " free-write")?,
+                FindingKind::FreeChangeAccess => write!(f, " free-change-access")?,
+                FindingKind::PlainCommunication => write!(f, " plain-communication")?,
+                FindingKind::Unaudited(error) => write!(f, " unaudited {error:?}")?,
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Uppercase hex without separators.
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02X}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::ToString;
+
+    use crate::mifare::desfire::{
+        application::ApplicationId,
+        audit::{audit, FindingKind, KeyDictionary, Location, Severity},
+        client::Desfire,
+        file::{AccessCondition, AccessRights, CommunicationMode, FileId},
+        framing::NativeFraming,
+        key::{ApplicationKeyType, Key, KeyNumber, KeySettings},
+        rng::FixedRandom,
+        sim::{VirtualApplication, VirtualDesfire, VirtualFile},
+    };
+
+    const AID: ApplicationId = ApplicationId::from_bytes([0x01, 0x02, 0x03]);
+    const PICC_KEY: [u8; 16] = [0x20; 16];
+    const MASTER_KEY: [u8; 16] = [0x10; 16];
+
+    fn key(number: u8) -> KeyNumber {
+        KeyNumber::new(number).unwrap()
+    }
+
+    fn file(number: u8) -> FileId {
+        FileId::new(number).unwrap()
+    }
+
+    fn card(picc_key: Key, picc_key_settings: u8, key_settings: u8) -> VirtualDesfire {
+        let master = AccessCondition::Key(key(0));
+        let application = VirtualApplication::new(
+            AID,
+            KeySettings::new(key_settings, ApplicationKeyType::Aes, 2),
+        )
+        .unwrap()
+        .with_key(key(0), Key::Aes128(MASTER_KEY), 1)
+        .unwrap()
+        .with_file(
+            file(0),
+            VirtualFile::std_data(
+                CommunicationMode::Plain,
+                AccessRights::new(AccessCondition::Free, master, master, master),
+                b"public",
+            ),
+        )
+        .with_file(
+            file(1),
+            VirtualFile::std_data(
+                CommunicationMode::Plain,
+                AccessRights::new(AccessCondition::Key(key(1)), master, master, master),
+                b"secret",
+            ),
+        )
+        .with_file(
+            file(2),
+            VirtualFile::std_data(
+                CommunicationMode::Enciphered,
+                AccessRights::new(AccessCondition::Key(key(1)), master, master, master),
+                b"sealed",
+            ),
+        );
+        VirtualDesfire::new()
+            .with_picc_key(picc_key, 0)
+            .with_picc_key_settings(picc_key_settings)
+            .with_application(application)
+    }
+
+    fn random() -> FixedRandom {
+        FixedRandom::new(&[0xA5])
+    }
+
+    #[test]
+    fn flags_factory_keys_and_loose_settings() {
+        let mut card = card(Key::Des([0; 8]), 0x0F, 0x0F);
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+
+        let report = audit(&mut desfire, &KeyDictionary::factory(), &mut random()).unwrap();
+
+        let kinds = |location| {
+            report
+                .findings
+                .iter()
+                .filter(move |finding| finding.location == location)
+                .map(|finding| finding.kind.clone())
+                .collect::<std::vec::Vec<_>>()
+        };
+        assert_eq!(
+            kinds(Location::Picc),
+            [
+                FindingKind::DictionaryKey {
+                    key_number: key(0),
+                    key: Key::TwoKey3Des([0; 16]),
+                },
+                FindingKind::FormatPiccReachable,
+                FindingKind::SettingsChangeable,
+                FindingKind::FreeCreateDelete,
+                FindingKind::FreeListing,
+            ]
+        );
+        // Key 1 was never changed from the factory AES key.
+        assert_eq!(
+            kinds(Location::Application(AID)),
+            [
+                FindingKind::DictionaryKey {
+                    key_number: key(1),
+                    key: Key::Aes128([0; 16]),
+                },
+                FindingKind::SettingsChangeable,
+                FindingKind::FreeCreateDelete,
+                FindingKind::FreeListing,
+            ]
+        );
+        assert_eq!(kinds(Location::File(AID, file(0))), [FindingKind::FreeRead]);
+        assert_eq!(
+            kinds(Location::File(AID, file(1))),
+            [FindingKind::PlainCommunication]
+        );
+        assert!(kinds(Location::File(AID, file(2))).is_empty());
+        assert_eq!(report.highest_severity(), Some(Severity::Critical));
+        assert_eq!(report.at_least(Severity::Critical).count(), 2);
+    }
+
+    #[test]
+    fn hardened_card_has_no_key_or_settings_findings() {
+        let mut card = card(Key::Aes128(PICC_KEY), 0x00, 0x00);
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        let dictionary = KeyDictionary::factory().with_key(Key::Aes128([0xFF; 16]));
+
+        let report = audit(&mut desfire, &dictionary, &mut random()).unwrap();
+
+        // Without the PICC master key neither settings nor applications can be listed.
+        assert!(report
+            .findings
+            .iter()
+            .all(|finding| matches!(finding.kind, FindingKind::Unaudited(_))));
+        assert_eq!(report.highest_severity(), Some(Severity::Info));
+    }
+
+    #[test]
+    fn writes_one_line_per_finding() {
+        let mut card = card(Key::Aes128(PICC_KEY), 0x02, 0x02);
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        let dictionary = KeyDictionary::new().with_key(Key::Aes128(MASTER_KEY));
+
+        let report = audit(&mut desfire, &dictionary, &mut random()).unwrap();
+
+        assert_eq!(
+            report.to_string(),
+            "tapsmith-audit 1\n\
+             info picc free-listing\n\
+             critical application 010203 dictionary-key 0 aes 10101010101010101010101010101010\n\
+             info application 010203 free-listing\n\
+             medium file 010203 0 free-read\n\
+             medium file 010203 1 plain-communication\n"
+        );
+    }
+}
@@ -3,1321 +3,2721 @@ use heapless::Vec;
 use crate::mifare::desfire::{
     application::ApplicationId,
     command::{Command, CommandCode},
+    configuration::{Ats, DefaultKey, PiccConfiguration},
     crypto::{
-        aes_cbc_decrypt_in_place, aes_cbc_encrypt_in_place, des_cbc_decrypt_in_place,
-        des_cbc_encrypt_in_place, desfire_crc16, desfire_crc32, tdes2_cbc_decrypt_in_place,
-        tdes2_cbc_encrypt_in_place, tdes3_cbc_decrypt_in_place, tdes3_cbc_encrypt_in_place,
-        AesSessionKey, DesSessionKey, RndA, RndA8, RndB, RndB8, ThreeKey3DesSessionKey,
-        TwoKey3DesSessionKey,
+        desfire_crc16, desfire_crc32, AesSessionKey, DesSessionKey, RndA, RndA8, RndB, RndB8,
+        ThreeKey3DesSessionKey, TwoKey3DesSessionKey,
     },
     error::Error,
-    executor::Executor,
-    file::{AccessRights, CommunicationMode, FileId, FileSettings},
+    executor::{AsyncExecutor, Executor, MAX_ADDITIONAL_FRAMES},
+    file::{AccessRights, CommunicationMode, DataSink, FileId, FileSettings, Records},
     framing::FrameCodec,
-    key::{KeyNumber, KeySettings},
-    session::{AuthenticatedSession, Session},
+    handle::FileHandle,
+    iso::{DfName, DfNameEntry, IsoCommand, IsoFileId, IsoSelect},
+    key::{
+        ApplicationKeyType, Key, KeyAlgorithm, KeyNumber, KeySetNumber, KeySetOptions, KeySettings,
+    },
+    originality::SIGNATURE_LEN,
+    provider::{
+        ChangeKeyCryptogram, ChangeKeyFormat, ChangeKeyRequest, CryptoProvider, SoftwareCrypto,
+    },
+    rng::RandomSource,
+    secret::constant_time_eq,
+    session::{AuthenticatedSession, Ev2Session, Session, SessionKey},
     status::Status,
-    transport::{Transport, MAX_FRAME_SIZE},
+    transaction::Transaction,
+    transport::{AsyncTransport, Transport, MAX_FRAME_SIZE},
     types::U24,
     version::VersionInfo,
 };
 
+const SET_CONFIGURATION_PICC: u8 = 0x00;
+const SET_CONFIGURATION_DEFAULT_KEY: u8 = 0x01;
+const SET_CONFIGURATION_ATS: u8 = 0x02;
+
+/// `Read_Sig` address of the originality signature.
+const READ_SIG_ADDRESS: u8 = 0x00;
+
+/// Plaintext bytes moved by each `ReadData`/`WriteData` of a streamed transfer.
+///
+/// Small enough that a chunk plus CRC, padding and MAC fits one command or
+/// response buffer in every communication mode.
+pub(crate) const STREAM_CHUNK_SIZE: u32 = 192;
+
 /// High-level `DESFire` command client.
 pub struct Desfire<T, C> {
     executor: Executor<T, C>,
     session: Session,
 }
 
-impl<T, C> Desfire<T, C>
-where
-    T: Transport,
-    C: FrameCodec,
-{
-    /// Creates a client from a byte transport and frame codec.
-    pub const fn new(transport: T, codec: C) -> Self {
-        Self {
-            executor: Executor::new(transport, codec),
-            session: Session::Unauthenticated,
-        }
-    }
+/// [`Desfire`] over an [`AsyncTransport`].
+///
+/// Exposes the same commands as async methods. Command building, secure
+/// messaging and session handling are shared with the blocking client.
+pub struct AsyncDesfire<T, C> {
+    executor: AsyncExecutor<T, C>,
+    session: Session,
+}
 
-    /// Creates a client from an existing executor.
-    pub const fn from_executor(executor: Executor<T, C>) -> Self {
-        Self {
-            executor,
-            session: Session::Unauthenticated,
+/// Expands the commands shared by [`Desfire`] and [`AsyncDesfire`].
+///
+/// `$async` is either empty or `async`, and `$await` either empty or `.await`;
+/// every call that reaches the transport is followed by `$($await)*`.
+macro_rules! client_methods {
+    ([$($async:tt)?], [$($await:tt)*]) => {
+        /// Current authentication/session state.
+        pub const fn session(&self) -> &Session {
+            &self.session
         }
-    }
 
-    /// Returns a shared reference to the command executor.
-    pub const fn executor(&self) -> &Executor<T, C> {
-        &self.executor
-    }
+        /// Authenticated session state, when authentication has succeeded.
+        pub const fn authenticated_session(&self) -> Option<&AuthenticatedSession> {
+            match &self.session {
+                Session::Unauthenticated | Session::AuthenticatedEv2(_) => None,
+                Session::Authenticated(session) => Some(session),
+            }
+        }
 
-    /// Returns a mutable reference to the command executor.
-    pub fn executor_mut(&mut self) -> &mut Executor<T, C> {
-        &mut self.executor
-    }
+        /// EV2 secure-messaging state, when `AuthenticateEV2First` has succeeded.
+        pub const fn ev2_session(&self) -> Option<&Ev2Session> {
+            match &self.session {
+                Session::Unauthenticated | Session::Authenticated(_) => None,
+                Session::AuthenticatedEv2(session) => Some(session),
+            }
+        }
 
-    /// Consumes the client and returns the command executor.
-    pub fn into_executor(self) -> Executor<T, C> {
-        self.executor
-    }
+        /// Clears any authenticated session state held by this client.
+        pub fn clear_session(&mut self) {
+            self.session = Session::Unauthenticated;
+        }
 
-    /// Current authentication/session state.
-    pub const fn session(&self) -> Session {
-        self.session
-    }
+        /// Reads and parses the card version information.
+        pub $($async)? fn get_version(&mut self) -> Result<VersionInfo, Error> {
+            let command = Command::new(CommandCode::GET_VERSION, &[])?;
+            let mut data: Vec<u8, 28> = Vec::new();
 
-    /// Authenticated session state, when authentication has succeeded.
-    pub const fn authenticated_session(&self) -> Option<AuthenticatedSession> {
-        match self.session {
-            Session::Unauthenticated => None,
-            Session::Authenticated(session) => Some(session),
+            self.execute_query(&command, &mut data)$($await)*?;
+            VersionInfo::parse(data.as_slice())
         }
-    }
-
-    /// Clears any authenticated session state held by this client.
-    pub const fn clear_session(&mut self) {
-        self.session = Session::Unauthenticated;
-    }
 
-    /// Reads and parses the card version information.
-    pub fn get_version(&mut self) -> Result<VersionInfo, Error> {
-        let command = Command::new(CommandCode::GET_VERSION, &[])?;
-        let mut data: Vec<u8, 28> = Vec::new();
+        /// Reads the real card UID with `GetCardUID`.
+        ///
+        /// Requires authentication with any PICC or application key. Unlike
+        /// [`Self::get_version`], this returns the hardware UID when random UID is
+        /// enabled, since the card sends it enciphered under the session key.
+        pub $($async)? fn get_card_uid(&mut self) -> Result<[u8; 7], Error> {
+            if matches!(self.session, Session::Unauthenticated) {
+                return Err(Error::MissingAuthentication);
+            }
 
-        self.executor.execute(&command, &mut data)?;
-        VersionInfo::parse(data.as_slice())
-    }
+            let command = Command::new(CommandCode::GET_CARD_UID, &[])?;
+            let mut data: Vec<u8, 7> = Vec::new();
+            self.execute_enciphered_read(&command, data.capacity(), &mut data)$($await)*?;
 
-    /// Performs legacy AES authentication with caller-provided reader randomness.
-    ///
-    /// This stores the session key and initializes secure-messaging state.
-    pub fn authenticate_aes_with_rnd_a(
-        &mut self,
-        key_number: KeyNumber,
-        key: &[u8; 16],
-        rnd_a: RndA,
-    ) -> Result<AuthenticatedSession, Error> {
-        let command = Command::new(CommandCode::AUTHENTICATE_AES, &[key_number.as_byte()])?;
-        let response = self.executor.exchange_one(&command)?;
-        if response.status() != Status::AdditionalFrame {
-            return Err(Error::Status(response.status()));
+            data.as_slice()
+                .try_into()
+                .map_err(|_| Error::InvalidResponseLength)
         }
-        if response.data().len() != 16 {
-            return Err(Error::InvalidResponseLength);
+
+        /// Reads the NXP originality signature over the UID with `Read_Sig`.
+        ///
+        /// Sent plain without a session and enciphered under one. Check the
+        /// result with [`verify_originality`](crate::mifare::desfire::originality::verify_originality).
+        pub $($async)? fn read_sig(&mut self) -> Result<[u8; SIGNATURE_LEN], Error> {
+            let command = Command::new(CommandCode::READ_SIG, &[READ_SIG_ADDRESS])?;
+            let communication_mode = match self.session.clone() {
+                Session::Unauthenticated => CommunicationMode::Plain,
+                Session::Authenticated(_) | Session::AuthenticatedEv2(_) => {
+                    CommunicationMode::Enciphered
+                }
+            };
+            let mut data: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            self.execute_read_command(
+                &command,
+                communication_mode,
+                SIGNATURE_LEN,
+                &mut data,
+            )$($await)*?;
+
+            data.as_slice()
+                .try_into()
+                .map_err(|_| Error::InvalidResponseLength)
         }
 
-        let encrypted_rnd_b: [u8; 16] = response.data().try_into().expect("length is checked");
-        let mut rnd_b_bytes = encrypted_rnd_b;
-        aes_cbc_decrypt_in_place(key, &[0u8; 16], &mut rnd_b_bytes);
-        let rnd_b = RndB::new(rnd_b_bytes);
+        /// Performs legacy AES authentication with a fresh reader challenge.
+        ///
+        /// This stores the session key and initializes secure-messaging state.
+        pub $($async)? fn authenticate_aes<R: RandomSource + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            key: &[u8; 16],
+            random: &mut R,
+        ) -> Result<AuthenticatedSession, Error> {
+            let rnd_a = RndA::random(random)?;
+            let key = Key::Aes128(*key);
+            self.authenticate_aes_with_rnd_a(key_number, &mut SoftwareCrypto, &key, rnd_a)$($await)*
+        }
 
-        let mut challenge_response = [0u8; 32];
-        challenge_response[..16].copy_from_slice(&rnd_a.as_bytes());
-        challenge_response[16..].copy_from_slice(&rnd_b.rotate_left());
-        aes_cbc_encrypt_in_place(key, &encrypted_rnd_b, &mut challenge_response);
+        /// Performs `AuthenticateEV2First` with a fresh reader challenge.
+        ///
+        /// Starts a new EV2 secure-messaging session: the card assigns a fresh
+        /// transaction identifier and the command counter restarts at zero.
+        pub $($async)? fn authenticate_ev2_first<R: RandomSource + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            key: &[u8; 16],
+            random: &mut R,
+        ) -> Result<Ev2Session, Error> {
+            let rnd_a = RndA::random(random)?;
+            let key = Key::Aes128(*key);
+            self.authenticate_ev2_first_with_rnd_a(key_number, &mut SoftwareCrypto, &key, rnd_a)$($await)*
+        }
 
-        let response = self.executor.exchange_one(&Command::new(
-            CommandCode::ADDITIONAL_FRAME,
-            &challenge_response,
-        )?)?;
-        if response.status() != Status::OperationOk {
-            return Err(Error::Status(response.status()));
+        /// Performs `AuthenticateEV2NonFirst` with a fresh reader challenge.
+        ///
+        /// Requires an active EV2 session. New session keys are derived while the
+        /// transaction identifier and command counter carry over.
+        pub $($async)? fn authenticate_ev2_non_first<R: RandomSource + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            key: &[u8; 16],
+            random: &mut R,
+        ) -> Result<Ev2Session, Error> {
+            let rnd_a = RndA::random(random)?;
+            let key = Key::Aes128(*key);
+            self.authenticate_ev2_non_first_with_rnd_a(key_number, &mut SoftwareCrypto, &key, rnd_a)$($await)*
         }
-        if response.data().len() != 16 {
-            return Err(Error::InvalidResponseLength);
+
+        /// Performs 2TDEA (`AUTHENTICATE_ISO`) authentication with a fresh reader challenge.
+        pub $($async)? fn authenticate_2tdea<R: RandomSource + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            key: &[u8; 16],
+            random: &mut R,
+        ) -> Result<AuthenticatedSession, Error> {
+            let rnd_a = RndA8::random(random)?;
+            let key = Key::TwoKey3Des(*key);
+            self.authenticate_2tdea_with_rnd_a(key_number, &mut SoftwareCrypto, &key, rnd_a)$($await)*
         }
 
-        let mut returned_rnd_a: [u8; 16] = response.data().try_into().expect("length is checked");
-        let response_iv: [u8; 16] = challenge_response[16..32].try_into().expect("valid slice");
-        aes_cbc_decrypt_in_place(key, &response_iv, &mut returned_rnd_a);
-        if returned_rnd_a != rnd_a.rotate_left() {
-            return Err(Error::AuthenticationFailed);
+        /// Performs 3TDEA (`AUTHENTICATE_ISO`) authentication with a fresh reader challenge.
+        pub $($async)? fn authenticate_3tdea<R: RandomSource + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            key: &[u8; 24],
+            random: &mut R,
+        ) -> Result<AuthenticatedSession, Error> {
+            let rnd_a = RndA::random(random)?;
+            let key = Key::ThreeKey3Des(*key);
+            self.authenticate_3tdea_with_rnd_a(key_number, &mut SoftwareCrypto, &key, rnd_a)$($await)*
         }
 
-        let session_key = AesSessionKey::derive(rnd_a, rnd_b);
-        let session = AuthenticatedSession::new_aes(key_number, session_key);
-        self.session = Session::Authenticated(session);
-        Ok(session)
-    }
+        /// Performs legacy DES (`AUTHENTICATE_LEGACY`) authentication with a fresh reader
+        /// challenge.
+        pub $($async)? fn authenticate_des<R: RandomSource + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            key: &[u8; 8],
+            random: &mut R,
+        ) -> Result<AuthenticatedSession, Error> {
+            let rnd_a = RndA8::random(random)?;
+            let key = Key::Des(*key);
+            self.authenticate_des_with_rnd_a(key_number, &mut SoftwareCrypto, &key, rnd_a)$($await)*
+        }
 
-    /// Performs 2TDEA (`AUTHENTICATE_ISO`) authentication with caller-provided reader randomness.
-    pub fn authenticate_2tdea_with_rnd_a(
-        &mut self,
-        key_number: KeyNumber,
-        key: &[u8; 16],
-        rnd_a: RndA8,
-    ) -> Result<AuthenticatedSession, Error> {
-        let session = self.authenticate_des_family_with_rnd_a(
-            CommandCode::AUTHENTICATE_ISO,
-            key_number,
-            |encrypted_rnd_b, out_rnd_b| {
-                tdes2_cbc_decrypt_in_place(key, &[0u8; 8], encrypted_rnd_b);
-                out_rnd_b.copy_from_slice(encrypted_rnd_b);
-            },
-            |rnd_a_bytes, rnd_b_rotated, iv, out| {
-                out[..8].copy_from_slice(rnd_a_bytes);
-                out[8..16].copy_from_slice(rnd_b_rotated);
-                tdes2_cbc_encrypt_in_place(key, iv, out);
-            },
-            |ciphertext, iv, out| {
-                out.copy_from_slice(ciphertext);
-                tdes2_cbc_decrypt_in_place(key, iv, out);
-            },
-            rnd_a,
-        )?;
-        let session_key = TwoKey3DesSessionKey::derive(session.0, session.1);
-        let auth_session = if key[..8] == key[8..] {
-            AuthenticatedSession::new_2tdea_with_secure_messaging_key(
-                key_number,
-                session_key,
-                session_key.ev1_des_working_key(),
-            )
-        } else {
-            AuthenticatedSession::new_2tdea(key_number, session_key)
-        };
-        self.session = Session::Authenticated(auth_session);
-        Ok(auth_session)
-    }
+        /// Authenticates with a key held by `provider`, using the handshake for its algorithm.
+        ///
+        /// DES keys use `AUTHENTICATE_LEGACY`, 2TDEA and 3TDEA keys `AUTHENTICATE_ISO` and
+        /// AES keys `AUTHENTICATE_AES`. The key is only used through `provider`.
+        pub $($async)? fn authenticate_with_provider<P, R>(
+            &mut self,
+            key_number: KeyNumber,
+            provider: &mut P,
+            key: &P::KeyHandle,
+            random: &mut R,
+        ) -> Result<AuthenticatedSession, Error>
+        where
+            P: CryptoProvider + ?Sized,
+            R: RandomSource + ?Sized,
+        {
+            match provider.algorithm(key)? {
+                KeyAlgorithm::Des => {
+                    let rnd_a = RndA8::random(random)?;
+                    self.authenticate_des_with_rnd_a(key_number, provider, key, rnd_a)$($await)*
+                }
+                KeyAlgorithm::TwoKey3Des => {
+                    let rnd_a = RndA8::random(random)?;
+                    self.authenticate_2tdea_with_rnd_a(key_number, provider, key, rnd_a)$($await)*
+                }
+                KeyAlgorithm::ThreeKey3Des => {
+                    let rnd_a = RndA::random(random)?;
+                    self.authenticate_3tdea_with_rnd_a(key_number, provider, key, rnd_a)$($await)*
+                }
+                KeyAlgorithm::Aes128 => {
+                    let rnd_a = RndA::random(random)?;
+                    self.authenticate_aes_with_rnd_a(key_number, provider, key, rnd_a)$($await)*
+                }
+            }
+        }
 
-    /// Performs 3TDEA (`AUTHENTICATE_ISO`) authentication with caller-provided reader randomness.
-    pub fn authenticate_3tdea_with_rnd_a(
-        &mut self,
-        key_number: KeyNumber,
-        key: &[u8; 24],
-        rnd_a: RndA,
-    ) -> Result<AuthenticatedSession, Error> {
-        let command = Command::new(CommandCode::AUTHENTICATE_ISO, &[key_number.as_byte()])?;
-        let response = self.executor.exchange_one(&command)?;
-        if response.status() != Status::AdditionalFrame {
-            return Err(Error::Status(response.status()));
+        /// [`Self::authenticate_ev2_first`] with an AES key held by `provider`.
+        pub $($async)? fn authenticate_ev2_first_with_provider<P, R>(
+            &mut self,
+            key_number: KeyNumber,
+            provider: &mut P,
+            key: &P::KeyHandle,
+            random: &mut R,
+        ) -> Result<Ev2Session, Error>
+        where
+            P: CryptoProvider + ?Sized,
+            R: RandomSource + ?Sized,
+        {
+            let rnd_a = RndA::random(random)?;
+            self.authenticate_ev2_first_with_rnd_a(key_number, provider, key, rnd_a)$($await)*
         }
-        if response.data().len() != 16 {
-            return Err(Error::InvalidResponseLength);
+
+        /// [`Self::authenticate_ev2_non_first`] with an AES key held by `provider`.
+        pub $($async)? fn authenticate_ev2_non_first_with_provider<P, R>(
+            &mut self,
+            key_number: KeyNumber,
+            provider: &mut P,
+            key: &P::KeyHandle,
+            random: &mut R,
+        ) -> Result<Ev2Session, Error>
+        where
+            P: CryptoProvider + ?Sized,
+            R: RandomSource + ?Sized,
+        {
+            let rnd_a = RndA::random(random)?;
+            self.authenticate_ev2_non_first_with_rnd_a(key_number, provider, key, rnd_a)$($await)*
         }
 
-        let encrypted_rnd_b: [u8; 16] = response.data().try_into().expect("length is checked");
-        let mut rnd_b_bytes = encrypted_rnd_b;
-        tdes3_cbc_decrypt_in_place(key, &[0u8; 8], &mut rnd_b_bytes);
-        let rnd_b = RndB::new(rnd_b_bytes);
+        /// [`Self::authenticate_aes`] with a caller-chosen challenge, for replaying traces.
+        $($async)? fn authenticate_aes_with_rnd_a<P: CryptoProvider + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            provider: &mut P,
+            key: &P::KeyHandle,
+            rnd_a: RndA,
+        ) -> Result<AuthenticatedSession, Error> {
+            let command = Command::new(CommandCode::AUTHENTICATE_AES, &[key_number.as_byte()])?;
+            let response = self.executor.exchange_one(&command)$($await)*?;
+            if response.status() != Status::AdditionalFrame {
+                return Err(Error::Status(response.status()));
+            }
+            if response.data().len() != 16 {
+                return Err(Error::InvalidResponseLength);
+            }
+
+            let encrypted_rnd_b: [u8; 16] = response.data().try_into().expect("length is checked");
+            let mut rnd_b_bytes = encrypted_rnd_b;
+            provider.cbc_decrypt(key, &[0u8; 16], &mut rnd_b_bytes)?;
+            let rnd_b = RndB::new(rnd_b_bytes);
+
+            let mut challenge_response = [0u8; 32];
+            challenge_response[..16].copy_from_slice(&rnd_a.as_bytes());
+            challenge_response[16..].copy_from_slice(&rnd_b.rotate_left());
+            provider.cbc_encrypt(key, &encrypted_rnd_b, &mut challenge_response)?;
+
+            let response = self.executor.exchange_one(&Command::new(
+                CommandCode::ADDITIONAL_FRAME,
+                &challenge_response,
+            )?)$($await)*?;
+            if response.status() != Status::OperationOk {
+                return Err(Error::Status(response.status()));
+            }
+            if response.data().len() != 16 {
+                return Err(Error::InvalidResponseLength);
+            }
 
-        let mut challenge_response = [0u8; 32];
-        challenge_response[..16].copy_from_slice(&rnd_a.as_bytes());
-        challenge_response[16..].copy_from_slice(&rnd_b.rotate_left());
-        let challenge_iv: [u8; 8] = encrypted_rnd_b[8..16].try_into().expect("valid slice");
-        tdes3_cbc_encrypt_in_place(key, &challenge_iv, &mut challenge_response);
+            let mut returned_rnd_a: [u8; 16] =
+                response.data().try_into().expect("length is checked");
+            provider.cbc_decrypt(key, &challenge_response[16..32], &mut returned_rnd_a)?;
+            if !constant_time_eq(&returned_rnd_a, &rnd_a.rotate_left()) {
+                return Err(Error::AuthenticationFailed);
+            }
 
-        let response = self.executor.exchange_one(&Command::new(
-            CommandCode::ADDITIONAL_FRAME,
-            &challenge_response,
-        )?)?;
-        if response.status() != Status::OperationOk {
-            return Err(Error::Status(response.status()));
+            let session_key = AesSessionKey::derive(rnd_a, rnd_b);
+            let session = AuthenticatedSession::new_aes(key_number, session_key);
+            self.session = Session::Authenticated(session.clone());
+            Ok(session)
         }
-        if response.data().len() != 16 {
-            return Err(Error::InvalidResponseLength);
+
+        /// [`Self::authenticate_ev2_first`] with a caller-chosen challenge.
+        $($async)? fn authenticate_ev2_first_with_rnd_a<P: CryptoProvider + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            provider: &mut P,
+            key: &P::KeyHandle,
+            rnd_a: RndA,
+        ) -> Result<Ev2Session, Error> {
+            // LenCap = 0: no PCD capabilities are sent.
+            let (rnd_b, response) = self.authenticate_ev2_with_rnd_a(
+                CommandCode::AUTHENTICATE_EV2_FIRST,
+                &[key_number.as_byte(), 0x00],
+                provider,
+                key,
+                rnd_a,
+            )$($await)*?;
+
+            // Response: [TI(4) || RndA'(16) || PDcap2(6) || PCDcap2(6)].
+            if response.len() != 32 {
+                return Err(Error::InvalidResponseLength);
+            }
+            if !constant_time_eq(&response[4..20], &rnd_a.rotate_left()) {
+                return Err(Error::AuthenticationFailed);
+            }
+
+            let transaction_identifier = response[..4].try_into().expect("length is checked");
+            let session = Ev2Session::new(
+                key_number,
+                transaction_identifier,
+                AesSessionKey::derive_ev2_encryption_with(provider, key, rnd_a, rnd_b)?,
+                AesSessionKey::derive_ev2_mac_with(provider, key, rnd_a, rnd_b)?,
+            );
+            self.session = Session::AuthenticatedEv2(session.clone());
+            Ok(session)
         }
 
-        let mut returned_rnd_a: [u8; 16] = response.data().try_into().expect("length is checked");
-        let response_iv: [u8; 8] = challenge_response[24..32].try_into().expect("valid slice");
-        tdes3_cbc_decrypt_in_place(key, &response_iv, &mut returned_rnd_a);
-        if returned_rnd_a != rnd_a.rotate_left() {
-            return Err(Error::AuthenticationFailed);
+        /// [`Self::authenticate_ev2_non_first`] with a caller-chosen challenge.
+        $($async)? fn authenticate_ev2_non_first_with_rnd_a<P: CryptoProvider + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            provider: &mut P,
+            key: &P::KeyHandle,
+            rnd_a: RndA,
+        ) -> Result<Ev2Session, Error> {
+            let Session::AuthenticatedEv2(current) = self.session.clone() else {
+                return Err(Error::MissingAuthentication);
+            };
+
+            let (rnd_b, response) = self.authenticate_ev2_with_rnd_a(
+                CommandCode::AUTHENTICATE_EV2_NON_FIRST,
+                &[key_number.as_byte()],
+                provider,
+                key,
+                rnd_a,
+            )$($await)*?;
+
+            // Response: [RndA'(16)].
+            if response.len() != 16 {
+                return Err(Error::InvalidResponseLength);
+            }
+            if !constant_time_eq(&response[..16], &rnd_a.rotate_left()) {
+                return Err(Error::AuthenticationFailed);
+            }
+
+            let session = current.reauthenticated(
+                key_number,
+                AesSessionKey::derive_ev2_encryption_with(provider, key, rnd_a, rnd_b)?,
+                AesSessionKey::derive_ev2_mac_with(provider, key, rnd_a, rnd_b)?,
+            );
+            self.session = Session::AuthenticatedEv2(session.clone());
+            Ok(session)
         }
 
-        let session_key = ThreeKey3DesSessionKey::derive(rnd_a, rnd_b);
-        let auth_session = AuthenticatedSession::new_3tdea(key_number, session_key);
-        self.session = Session::Authenticated(auth_session);
-        Ok(auth_session)
-    }
+        /// Common EV2 authentication handshake.
+        ///
+        /// All EV2 authentication cryptograms use a zero IV. Returns `RndB` and the
+        /// decrypted final card response.
+        $($async)? fn authenticate_ev2_with_rnd_a<P: CryptoProvider + ?Sized>(
+            &mut self,
+            command_code: CommandCode,
+            command_data: &[u8],
+            provider: &mut P,
+            key: &P::KeyHandle,
+            rnd_a: RndA,
+        ) -> Result<(RndB, Vec<u8, 32>), Error> {
+            if provider.algorithm(key)? != KeyAlgorithm::Aes128 {
+                return Err(Error::UnsupportedAlgorithm);
+            }
 
-    /// Performs legacy DES (`AUTHENTICATE_LEGACY`) authentication with caller-provided reader randomness.
-    pub fn authenticate_des_with_rnd_a(
-        &mut self,
-        key_number: KeyNumber,
-        key: &[u8; 8],
-        rnd_a: RndA8,
-    ) -> Result<AuthenticatedSession, Error> {
-        let session = self.authenticate_des_family_with_rnd_a(
-            CommandCode::AUTHENTICATE_LEGACY,
-            key_number,
-            |encrypted_rnd_b, out_rnd_b| {
-                des_cbc_decrypt_in_place(key, &[0u8; 8], encrypted_rnd_b);
-                out_rnd_b.copy_from_slice(encrypted_rnd_b);
-            },
-            |rnd_a_bytes, rnd_b_rotated, iv, out| {
-                out[..8].copy_from_slice(rnd_a_bytes);
-                out[8..16].copy_from_slice(rnd_b_rotated);
-                des_cbc_encrypt_in_place(key, iv, out);
-            },
-            |ciphertext, iv, out| {
-                out.copy_from_slice(ciphertext);
-                des_cbc_decrypt_in_place(key, iv, out);
-            },
-            rnd_a,
-        )?;
-        let session_key = DesSessionKey::derive(session.0, session.1);
-        let auth_session = AuthenticatedSession::new_des(key_number, session_key);
-        self.session = Session::Authenticated(auth_session);
-        Ok(auth_session)
-    }
-
-    /// Common DES-family authentication handshake.
-    ///
-    /// Returns `(RndA, RndB)` for session key derivation.
-    fn authenticate_des_family_with_rnd_a(
-        &mut self,
-        command_code: CommandCode,
-        key_number: KeyNumber,
-        decrypt_rnd_b: impl Fn(&mut [u8; 8], &mut [u8; 8]),
-        encrypt_challenge: impl Fn(&[u8; 8], &[u8; 8], &[u8; 8], &mut [u8; 16]),
-        decrypt_rnd_a_prime: impl Fn(&[u8; 8], &[u8; 8], &mut [u8; 8]),
-        rnd_a: RndA8,
-    ) -> Result<(RndA8, RndB8), Error> {
-        let command = Command::new(command_code, &[key_number.as_byte()])?;
-        let response = self.executor.exchange_one(&command)?;
-        if response.status() != Status::AdditionalFrame {
-            return Err(Error::Status(response.status()));
-        }
-        if response.data().len() != 8 {
-            return Err(Error::InvalidResponseLength);
-        }
-
-        let mut enc_rnd_b: [u8; 8] = response.data().try_into().expect("length is checked");
-        let original_enc_rnd_b = enc_rnd_b;
-        let mut rnd_b_bytes = [0u8; 8];
-        decrypt_rnd_b(&mut enc_rnd_b, &mut rnd_b_bytes);
-        let rnd_b = RndB8::new(rnd_b_bytes);
-
-        let mut challenge_response = [0u8; 16];
-        encrypt_challenge(
-            &rnd_a.as_bytes(),
-            &rnd_b.rotate_left(),
-            &original_enc_rnd_b,
-            &mut challenge_response,
-        );
+            let command = Command::new(command_code, command_data)?;
+            let response = self.executor.exchange_one(&command)$($await)*?;
+            if response.status() != Status::AdditionalFrame {
+                return Err(Error::Status(response.status()));
+            }
+            if response.data().len() != 16 {
+                return Err(Error::InvalidResponseLength);
+            }
 
-        let response = self.executor.exchange_one(&Command::new(
-            CommandCode::ADDITIONAL_FRAME,
-            &challenge_response,
-        )?)?;
-        if response.status() != Status::OperationOk {
-            return Err(Error::Status(response.status()));
-        }
-        if response.data().len() != 8 {
-            return Err(Error::InvalidResponseLength);
+            let mut rnd_b_bytes: [u8; 16] = response.data().try_into().expect("length is checked");
+            provider.cbc_decrypt(key, &[0u8; 16], &mut rnd_b_bytes)?;
+            let rnd_b = RndB::new(rnd_b_bytes);
+
+            let mut challenge_response = [0u8; 32];
+            challenge_response[..16].copy_from_slice(&rnd_a.as_bytes());
+            challenge_response[16..].copy_from_slice(&rnd_b.rotate_left());
+            provider.cbc_encrypt(key, &[0u8; 16], &mut challenge_response)?;
+
+            let response = self.executor.exchange_one(&Command::new(
+                CommandCode::ADDITIONAL_FRAME,
+                &challenge_response,
+            )?)$($await)*?;
+            if response.status() != Status::OperationOk {
+                return Err(Error::Status(response.status()));
+            }
+            if response.data().is_empty() || !response.data().len().is_multiple_of(16) {
+                return Err(Error::InvalidResponseLength);
+            }
+
+            let mut decrypted: Vec<u8, 32> = Vec::new();
+            decrypted
+                .extend_from_slice(response.data())
+                .map_err(|_| Error::InvalidResponseLength)?;
+            provider.cbc_decrypt(key, &[0u8; 16], decrypted.as_mut_slice())?;
+            Ok((rnd_b, decrypted))
         }
 
-        // IV for final decryption = last 8 bytes of challenge_response.
-        let response_iv: [u8; 8] = challenge_response[8..16].try_into().expect("valid slice");
-        let mut returned_rnd_a = [0u8; 8];
-        let mut enc_rnd_a: [u8; 8] = response.data().try_into().expect("length is checked");
-        decrypt_rnd_a_prime(&mut enc_rnd_a, &response_iv, &mut returned_rnd_a);
-        if returned_rnd_a != rnd_a.rotate_left() {
-            return Err(Error::AuthenticationFailed);
+        /// [`Self::authenticate_2tdea`] with a caller-chosen challenge.
+        $($async)? fn authenticate_2tdea_with_rnd_a<P: CryptoProvider + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            provider: &mut P,
+            key: &P::KeyHandle,
+            rnd_a: RndA8,
+        ) -> Result<AuthenticatedSession, Error> {
+            let session = self.authenticate_des_family_with_rnd_a(
+                CommandCode::AUTHENTICATE_ISO,
+                key_number,
+                provider,
+                key,
+                rnd_a,
+            )$($await)*?;
+            let session_key = TwoKey3DesSessionKey::derive(session.0, session.1);
+            let auth_session = if provider.has_identical_halves(key)? {
+                let secure_messaging_key = session_key.ev1_des_working_key();
+                AuthenticatedSession::new_2tdea_with_secure_messaging_key(
+                    key_number,
+                    session_key,
+                    secure_messaging_key,
+                )
+            } else {
+                AuthenticatedSession::new_2tdea(key_number, session_key)
+            };
+            self.session = Session::Authenticated(auth_session.clone());
+            Ok(auth_session)
         }
 
-        Ok((rnd_a, rnd_b))
-    }
+        /// [`Self::authenticate_3tdea`] with a caller-chosen challenge.
+        $($async)? fn authenticate_3tdea_with_rnd_a<P: CryptoProvider + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            provider: &mut P,
+            key: &P::KeyHandle,
+            rnd_a: RndA,
+        ) -> Result<AuthenticatedSession, Error> {
+            let command = Command::new(CommandCode::AUTHENTICATE_ISO, &[key_number.as_byte()])?;
+            let response = self.executor.exchange_one(&command)$($await)*?;
+            if response.status() != Status::AdditionalFrame {
+                return Err(Error::Status(response.status()));
+            }
+            if response.data().len() != 16 {
+                return Err(Error::InvalidResponseLength);
+            }
 
-    /// Reads key settings for the currently selected application.
-    pub fn get_key_settings(&mut self) -> Result<KeySettings, Error> {
-        let command = Command::new(CommandCode::GET_KEY_SETTINGS, &[])?;
-        let mut data: Vec<u8, 2> = Vec::new();
+            let encrypted_rnd_b: [u8; 16] = response.data().try_into().expect("length is checked");
+            let mut rnd_b_bytes = encrypted_rnd_b;
+            provider.cbc_decrypt(key, &[0u8; 8], &mut rnd_b_bytes)?;
+            let rnd_b = RndB::new(rnd_b_bytes);
+
+            let mut challenge_response = [0u8; 32];
+            challenge_response[..16].copy_from_slice(&rnd_a.as_bytes());
+            challenge_response[16..].copy_from_slice(&rnd_b.rotate_left());
+            provider.cbc_encrypt(key, &encrypted_rnd_b[8..16], &mut challenge_response)?;
+
+            let response = self.executor.exchange_one(&Command::new(
+                CommandCode::ADDITIONAL_FRAME,
+                &challenge_response,
+            )?)$($await)*?;
+            if response.status() != Status::OperationOk {
+                return Err(Error::Status(response.status()));
+            }
+            if response.data().len() != 16 {
+                return Err(Error::InvalidResponseLength);
+            }
+
+            let mut returned_rnd_a: [u8; 16] =
+                response.data().try_into().expect("length is checked");
+            provider.cbc_decrypt(key, &challenge_response[24..32], &mut returned_rnd_a)?;
+            if !constant_time_eq(&returned_rnd_a, &rnd_a.rotate_left()) {
+                return Err(Error::AuthenticationFailed);
+            }
 
-        if matches!(self.session, Session::Authenticated(_)) {
-            self.execute_single_maced(&command, &mut data)?;
-        } else {
-            self.executor.execute(&command, &mut data)?;
+            let session_key = ThreeKey3DesSessionKey::derive(rnd_a, rnd_b);
+            let auth_session = AuthenticatedSession::new_3tdea(key_number, session_key);
+            self.session = Session::Authenticated(auth_session.clone());
+            Ok(auth_session)
         }
 
-        KeySettings::parse(data.as_slice())
-    }
+        /// [`Self::authenticate_des`] with a caller-chosen challenge.
+        $($async)? fn authenticate_des_with_rnd_a<P: CryptoProvider + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            provider: &mut P,
+            key: &P::KeyHandle,
+            rnd_a: RndA8,
+        ) -> Result<AuthenticatedSession, Error> {
+            let session = self.authenticate_des_family_with_rnd_a(
+                CommandCode::AUTHENTICATE_LEGACY,
+                key_number,
+                provider,
+                key,
+                rnd_a,
+            )$($await)*?;
+            let session_key = DesSessionKey::derive(session.0, session.1);
+            let auth_session = AuthenticatedSession::new_des(key_number, session_key);
+            self.session = Session::Authenticated(auth_session.clone());
+            Ok(auth_session)
+        }
 
-    /// Reads one key version from the currently selected application.
-    pub fn get_key_version(&mut self, key_number: KeyNumber) -> Result<u8, Error> {
-        let command = Command::new(CommandCode::GET_KEY_VERSION, &[key_number.as_byte()])?;
-        let mut data: Vec<u8, 1> = Vec::new();
+        /// Common DES-family authentication handshake with 8-byte challenges.
+        ///
+        /// Returns `(RndA, RndB)` for session key derivation.
+        $($async)? fn authenticate_des_family_with_rnd_a<P: CryptoProvider + ?Sized>(
+            &mut self,
+            command_code: CommandCode,
+            key_number: KeyNumber,
+            provider: &mut P,
+            key: &P::KeyHandle,
+            rnd_a: RndA8,
+        ) -> Result<(RndA8, RndB8), Error> {
+            let command = Command::new(command_code, &[key_number.as_byte()])?;
+            let response = self.executor.exchange_one(&command)$($await)*?;
+            if response.status() != Status::AdditionalFrame {
+                return Err(Error::Status(response.status()));
+            }
+            if response.data().len() != 8 {
+                return Err(Error::InvalidResponseLength);
+            }
 
-        self.executor.execute(&command, &mut data)?;
-        data.first().copied().ok_or(Error::InvalidResponseLength)
-    }
+            let encrypted_rnd_b: [u8; 8] = response.data().try_into().expect("length is checked");
+            let mut rnd_b_bytes = encrypted_rnd_b;
+            provider.cbc_decrypt(key, &[0u8; 8], &mut rnd_b_bytes)?;
+            let rnd_b = RndB8::new(rnd_b_bytes);
+
+            let mut challenge_response = [0u8; 16];
+            challenge_response[..8].copy_from_slice(&rnd_a.as_bytes());
+            challenge_response[8..].copy_from_slice(&rnd_b.rotate_left());
+            provider.cbc_encrypt(key, &encrypted_rnd_b, &mut challenge_response)?;
+
+            let response = self.executor.exchange_one(&Command::new(
+                CommandCode::ADDITIONAL_FRAME,
+                &challenge_response,
+            )?)$($await)*?;
+            if response.status() != Status::OperationOk {
+                return Err(Error::Status(response.status()));
+            }
+            if response.data().len() != 8 {
+                return Err(Error::InvalidResponseLength);
+            }
 
-    /// Changes a key in the currently selected application.
-    ///
-    /// `key_version` is the AES key version byte stored alongside the key on the card.
-    ///
-    /// When changing the key that was used for authentication, the card invalidates the
-    /// session on success and the client returns to unauthenticated state.
-    ///
-    /// When changing a different key, `old_key` must be the current value of that key slot.
-    /// The card returns an 8-byte response MAC and the session remains active.
-    pub fn change_key_aes(
-        &mut self,
-        key_number: KeyNumber,
-        new_key: [u8; 16],
-        key_version: u8,
-        old_key: Option<[u8; 16]>,
-    ) -> Result<(), Error> {
-        let changing_auth_key = match self.session {
-            Session::Authenticated(s) => key_number == s.key_number(),
-            Session::Unauthenticated => return Err(Error::MissingAuthentication),
-        };
-        self.change_key_aes_impl(
-            key_number.as_byte(),
-            new_key,
-            key_version,
-            old_key,
-            changing_auth_key,
-        )
-    }
+            // IV for final decryption = last 8 bytes of challenge_response.
+            let mut returned_rnd_a: [u8; 8] =
+                response.data().try_into().expect("length is checked");
+            provider.cbc_decrypt(key, &challenge_response[8..16], &mut returned_rnd_a)?;
+            if !constant_time_eq(&returned_rnd_a, &rnd_a.rotate_left()) {
+                return Err(Error::AuthenticationFailed);
+            }
 
-    /// Changes the PICC master key (app 0x000000 must be selected).
-    ///
-    /// The `KeyNo` byte for a PICC-level AES key encodes the algorithm (`0x80`) rather than a
-    /// slot number. Callers must authenticate with the current PICC master key before calling
-    /// this; the card invalidates the session on success.
-    pub fn change_picc_key_aes(&mut self, new_key: [u8; 16], key_version: u8) -> Result<(), Error> {
-        // 0x80 = AES algorithm flag for PICC-level key change; always same-key case.
-        self.change_key_aes_impl(0x80, new_key, key_version, None, true)
-    }
+            Ok((rnd_a, rnd_b))
+        }
 
-    fn change_key_aes_impl(
-        &mut self,
-        key_no_byte: u8,
-        new_key: [u8; 16],
-        key_version: u8,
-        old_key: Option<[u8; 16]>,
-        changing_auth_key: bool,
-    ) -> Result<(), Error> {
-        let Session::Authenticated(mut session) = self.session else {
-            return Err(Error::MissingAuthentication);
-        };
+        /// Reads key settings for the currently selected application.
+        pub $($async)? fn get_key_settings(&mut self) -> Result<KeySettings, Error> {
+            let command = Command::new(CommandCode::GET_KEY_SETTINGS, &[])?;
+            let mut data: Vec<u8, 2> = Vec::new();
 
-        let mut plaintext: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            if matches!(self.session, Session::Unauthenticated) {
+                self.executor.execute(&command, &mut data)$($await)*?;
+            } else {
+                self.execute_single_maced(&command, &mut data)$($await)*?;
+            }
 
-        if changing_auth_key {
-            // CRC32 over [cmd_code || key_no || new_key || key_version].
-            let mut crc_input: Vec<u8, 19> = Vec::new();
-            crc_input
-                .push(CommandCode::CHANGE_KEY.as_byte())
-                .map_err(|_| Error::CommandTooLong)?;
-            crc_input
-                .push(key_no_byte)
-                .map_err(|_| Error::CommandTooLong)?;
-            crc_input
-                .extend_from_slice(&new_key)
-                .map_err(|_| Error::CommandTooLong)?;
-            crc_input
-                .push(key_version)
-                .map_err(|_| Error::CommandTooLong)?;
-            let crc = desfire_crc32(crc_input.as_slice());
+            KeySettings::parse(data.as_slice())
+        }
 
-            // Plaintext: [new_key || key_version || CRC32 || zero_padding].
-            plaintext
-                .extend_from_slice(&new_key)
-                .map_err(|_| Error::CommandTooLong)?;
-            plaintext
-                .push(key_version)
-                .map_err(|_| Error::CommandTooLong)?;
-            plaintext
-                .extend_from_slice(&crc)
-                .map_err(|_| Error::CommandTooLong)?;
-        } else {
-            let old = old_key.ok_or(Error::MissingOldKey)?;
-            let key_xor: [u8; 16] = core::array::from_fn(|i| new_key[i] ^ old[i]);
-
-            // CRC32_a over [cmd_code || key_no || (new_key XOR old_key) || key_version].
-            // CRC32_b over [new_key] only (no version byte).
-            let mut crc_input_a: Vec<u8, 19> = Vec::new();
-            crc_input_a
-                .push(CommandCode::CHANGE_KEY.as_byte())
-                .map_err(|_| Error::CommandTooLong)?;
-            crc_input_a
-                .push(key_no_byte)
-                .map_err(|_| Error::CommandTooLong)?;
-            crc_input_a
-                .extend_from_slice(&key_xor)
-                .map_err(|_| Error::CommandTooLong)?;
-            crc_input_a
-                .push(key_version)
-                .map_err(|_| Error::CommandTooLong)?;
-            let crc_a = desfire_crc32(crc_input_a.as_slice());
-            let crc_b = desfire_crc32(&new_key);
+        /// Reads one key version from the currently selected application.
+        pub $($async)? fn get_key_version(&mut self, key_number: KeyNumber) -> Result<u8, Error> {
+            let command = Command::new(CommandCode::GET_KEY_VERSION, &[key_number.as_byte()])?;
+            let mut data: Vec<u8, 1> = Vec::new();
 
-            // Plaintext: [(new_key XOR old_key) || key_version || CRC32_a || CRC32_b || zero_padding].
-            plaintext
-                .extend_from_slice(&key_xor)
-                .map_err(|_| Error::CommandTooLong)?;
-            plaintext
-                .push(key_version)
-                .map_err(|_| Error::CommandTooLong)?;
-            plaintext
-                .extend_from_slice(&crc_a)
-                .map_err(|_| Error::CommandTooLong)?;
-            plaintext
-                .extend_from_slice(&crc_b)
-                .map_err(|_| Error::CommandTooLong)?;
+            self.execute_query(&command, &mut data)$($await)*?;
+            data.first().copied().ok_or(Error::InvalidResponseLength)
         }
 
-        // Zero-pad to block boundary.
-        let block_size = session.block_size();
-        while !plaintext.len().is_multiple_of(block_size) {
-            plaintext.push(0x00).map_err(|_| Error::CommandTooLong)?;
+        /// Changes a key in the currently selected application.
+        ///
+        /// `key_version` is the AES key version byte stored alongside the key on the card.
+        ///
+        /// When changing the key that was used for authentication, the card invalidates the
+        /// session on success and the client returns to unauthenticated state.
+        ///
+        /// When changing a different key, `old_key` must be the current value of that key slot.
+        /// The card returns an 8-byte response MAC and the session remains active.
+        pub $($async)? fn change_key_aes(
+            &mut self,
+            key_number: KeyNumber,
+            new_key: [u8; 16],
+            key_version: u8,
+            old_key: Option<[u8; 16]>,
+        ) -> Result<(), Error> {
+            let changing_auth_key = key_number == self.authenticated_key_number()?;
+            let old_key = old_key.map(Key::Aes128);
+            let old_key = required_old_key(old_key.as_ref(), changing_auth_key)?;
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY,
+                &[key_number.as_byte()],
+                &mut SoftwareCrypto,
+                &Key::Aes128(new_key),
+                key_version,
+                old_key,
+            )$($await)*
         }
 
-        // Encrypt using current chaining IV; chaining advances to last ciphertext block.
-        session.cbc_encrypt_in_place(plaintext.as_mut_slice())?;
-
-        let mut cmd_data: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-        cmd_data
-            .push(key_no_byte)
-            .map_err(|_| Error::CommandTooLong)?;
-        cmd_data
-            .extend_from_slice(plaintext.as_slice())
-            .map_err(|_| Error::CommandTooLong)?;
-        let command = Command::new(CommandCode::CHANGE_KEY, cmd_data.as_slice())?;
+        /// Changes the PICC master key (app 0x000000 must be selected).
+        ///
+        /// The `KeyNo` byte for a PICC-level AES key encodes the algorithm (`0x80`) rather than a
+        /// slot number. Callers must authenticate with the current PICC master key before calling
+        /// this; the card invalidates the session on success.
+        pub $($async)? fn change_picc_key_aes(
+            &mut self,
+            new_key: [u8; 16],
+            key_version: u8,
+        ) -> Result<(), Error> {
+            // 0x80 = AES algorithm flag for PICC-level key change; always same-key case.
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY,
+                &[0x80],
+                &mut SoftwareCrypto,
+                &Key::Aes128(new_key),
+                key_version,
+                None,
+            )$($await)*
+        }
 
-        let response = self.executor.exchange_one(&command)?;
-        if response.status() != Status::OperationOk {
-            return Err(Error::Status(response.status()));
+        /// Changes a DES application key slot (8-byte key).
+        ///
+        /// When changing a key other than the session key, `old_key` must hold the current value of
+        /// that slot. When changing the session key itself (`old_key` is unused in that case),
+        /// the card invalidates the session on success.
+        pub $($async)? fn change_key_des(
+            &mut self,
+            key_number: KeyNumber,
+            new_key: [u8; 8],
+            old_key: Option<[u8; 8]>,
+        ) -> Result<(), Error> {
+            let changing_auth_key = key_number == self.authenticated_key_number()?;
+            let old_key = old_key.map(Key::Des);
+            let old_key = required_old_key(old_key.as_ref(), changing_auth_key)?;
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY,
+                &[key_number.as_byte()],
+                &mut SoftwareCrypto,
+                &Key::Des(new_key),
+                0,
+                old_key,
+            )$($await)*
         }
 
-        if changing_auth_key {
-            // Card invalidates the session after changing the authenticated key.
-            self.session = Session::Unauthenticated;
-        } else {
-            // Response carries 8-byte MAC over empty body.
-            verify_response_mac(&mut session, Status::OperationOk, response.data())?;
-            self.session = Session::Authenticated(session);
+        /// Changes a two-key 3DES (2TDEA) application key slot (16-byte key).
+        ///
+        /// When changing a key other than the session key, `old_key` must hold the current value of
+        /// that slot. When changing the session key itself, the card invalidates the session on
+        /// success.
+        pub $($async)? fn change_key_2tdea(
+            &mut self,
+            key_number: KeyNumber,
+            new_key: [u8; 16],
+            old_key: Option<[u8; 16]>,
+        ) -> Result<(), Error> {
+            let changing_auth_key = key_number == self.authenticated_key_number()?;
+            let old_key = old_key.map(Key::TwoKey3Des);
+            let old_key = required_old_key(old_key.as_ref(), changing_auth_key)?;
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY,
+                &[key_number.as_byte()],
+                &mut SoftwareCrypto,
+                &Key::TwoKey3Des(new_key),
+                0,
+                old_key,
+            )$($await)*
         }
 
-        Ok(())
-    }
+        /// Changes a three-key 3DES (3TDEA) application key slot (24-byte key).
+        ///
+        /// When changing a key other than the session key, `old_key` must hold the current value of
+        /// that slot. When changing the session key itself, the card invalidates the session on
+        /// success.
+        pub $($async)? fn change_key_3tdea(
+            &mut self,
+            key_number: KeyNumber,
+            new_key: [u8; 24],
+            old_key: Option<[u8; 24]>,
+        ) -> Result<(), Error> {
+            let changing_auth_key = key_number == self.authenticated_key_number()?;
+            let old_key = old_key.map(Key::ThreeKey3Des);
+            let old_key = required_old_key(old_key.as_ref(), changing_auth_key)?;
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY,
+                &[key_number.as_byte()],
+                &mut SoftwareCrypto,
+                &Key::ThreeKey3Des(new_key),
+                0,
+                old_key,
+            )$($await)*
+        }
 
-    /// Changes a DES application key slot (8-byte key).
-    ///
-    /// When changing a key other than the session key, `old_key` must hold the current value of
-    /// that slot. When changing the session key itself (`old_key` is unused in that case),
-    /// the card invalidates the session on success.
-    pub fn change_key_des(
-        &mut self,
-        key_number: KeyNumber,
-        new_key: [u8; 8],
-        old_key: Option<[u8; 8]>,
-    ) -> Result<(), Error> {
-        let changing_auth_key = match self.session {
-            Session::Authenticated(s) => key_number == s.key_number(),
-            Session::Unauthenticated => return Err(Error::MissingAuthentication),
-        };
-        self.change_key_legacy_impl(
-            key_number.as_byte(),
-            &new_key,
-            old_key.as_ref().map(<[u8; 8]>::as_slice),
-            changing_auth_key,
-        )
-    }
+        /// Changes the PICC master key to a new two-key 3DES (2TDEA) key.
+        ///
+        /// `KeyNo` byte `0x00` signals DES/2TDEA at PICC level. The card invalidates the session on
+        /// success. Caller must be authenticated with the current PICC master key.
+        pub $($async)? fn change_picc_key_2tdea(&mut self, new_key: [u8; 16]) -> Result<(), Error> {
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY,
+                &[0x00],
+                &mut SoftwareCrypto,
+                &Key::TwoKey3Des(new_key),
+                0,
+                None,
+            )$($await)*
+        }
 
-    /// Changes a two-key 3DES (2TDEA) application key slot (16-byte key).
-    ///
-    /// When changing a key other than the session key, `old_key` must hold the current value of
-    /// that slot. When changing the session key itself, the card invalidates the session on
-    /// success.
-    pub fn change_key_2tdea(
-        &mut self,
-        key_number: KeyNumber,
-        new_key: [u8; 16],
-        old_key: Option<[u8; 16]>,
-    ) -> Result<(), Error> {
-        let changing_auth_key = match self.session {
-            Session::Authenticated(s) => key_number == s.key_number(),
-            Session::Unauthenticated => return Err(Error::MissingAuthentication),
-        };
-        self.change_key_legacy_impl(
-            key_number.as_byte(),
-            &new_key,
-            old_key.as_ref().map(<[u8; 16]>::as_slice),
-            changing_auth_key,
-        )
-    }
+        /// Changes the PICC master key to a new three-key 3DES (3TDEA) key.
+        ///
+        /// `KeyNo` byte `0x40` signals 3TDEA at PICC level. The card invalidates the session on
+        /// success. Caller must be authenticated with the current PICC master key.
+        pub $($async)? fn change_picc_key_3tdea(&mut self, new_key: [u8; 24]) -> Result<(), Error> {
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY,
+                &[0x40],
+                &mut SoftwareCrypto,
+                &Key::ThreeKey3Des(new_key),
+                0,
+                None,
+            )$($await)*
+        }
 
-    /// Changes a three-key 3DES (3TDEA) application key slot (24-byte key).
-    ///
-    /// When changing a key other than the session key, `old_key` must hold the current value of
-    /// that slot. When changing the session key itself, the card invalidates the session on
-    /// success.
-    pub fn change_key_3tdea(
-        &mut self,
-        key_number: KeyNumber,
-        new_key: [u8; 24],
-        old_key: Option<[u8; 24]>,
-    ) -> Result<(), Error> {
-        let changing_auth_key = match self.session {
-            Session::Authenticated(s) => key_number == s.key_number(),
-            Session::Unauthenticated => return Err(Error::MissingAuthentication),
-        };
-        self.change_key_legacy_impl(
-            key_number.as_byte(),
-            &new_key,
-            old_key.as_ref().map(<[u8; 24]>::as_slice),
-            changing_auth_key,
-        )
-    }
+        /// Changes an AES key in any key set of the selected application with `ChangeKeyEV2`.
+        ///
+        /// Keys in a key set staged by [`Self::initialize_key_set`] start as all zeros, so
+        /// `old_key` is `Some([0; 16])` for their first change. Changing the
+        /// authenticated key of the active key set ends the session, as for
+        /// [`Self::change_key_aes`].
+        pub $($async)? fn change_key_ev2_aes(
+            &mut self,
+            key_set: KeySetNumber,
+            key_number: KeyNumber,
+            new_key: [u8; 16],
+            key_version: u8,
+            old_key: Option<[u8; 16]>,
+        ) -> Result<(), Error> {
+            let changing_auth_key = self.changes_authenticated_key(key_set, key_number)?;
+            let old_key = old_key.map(Key::Aes128);
+            let old_key = required_old_key(old_key.as_ref(), changing_auth_key)?;
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY_EV2,
+                &[key_set.as_byte(), key_number.as_byte()],
+                &mut SoftwareCrypto,
+                &Key::Aes128(new_key),
+                key_version,
+                old_key,
+            )$($await)*
+        }
 
-    /// Changes the PICC master key to a new two-key 3DES (2TDEA) key.
-    ///
-    /// `KeyNo` byte `0x00` signals DES/2TDEA at PICC level. The card invalidates the session on
-    /// success. Caller must be authenticated with the current PICC master key.
-    pub fn change_picc_key_2tdea(&mut self, new_key: [u8; 16]) -> Result<(), Error> {
-        self.change_key_legacy_impl(0x00, &new_key, None, true)
-    }
+        /// Changes a two-key 3DES (2TDEA) key in any key set with `ChangeKeyEV2`.
+        ///
+        /// See [`Self::change_key_ev2_aes`] for the `old_key` rules.
+        pub $($async)? fn change_key_ev2_2tdea(
+            &mut self,
+            key_set: KeySetNumber,
+            key_number: KeyNumber,
+            new_key: [u8; 16],
+            old_key: Option<[u8; 16]>,
+        ) -> Result<(), Error> {
+            let changing_auth_key = self.changes_authenticated_key(key_set, key_number)?;
+            let old_key = old_key.map(Key::TwoKey3Des);
+            let old_key = required_old_key(old_key.as_ref(), changing_auth_key)?;
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY_EV2,
+                &[key_set.as_byte(), key_number.as_byte()],
+                &mut SoftwareCrypto,
+                &Key::TwoKey3Des(new_key),
+                0,
+                old_key,
+            )$($await)*
+        }
 
-    /// Changes the PICC master key to a new three-key 3DES (3TDEA) key.
-    ///
-    /// `KeyNo` byte `0x40` signals 3TDEA at PICC level. The card invalidates the session on
-    /// success. Caller must be authenticated with the current PICC master key.
-    pub fn change_picc_key_3tdea(&mut self, new_key: [u8; 24]) -> Result<(), Error> {
-        self.change_key_legacy_impl(0x40, &new_key, None, true)
-    }
+        /// Changes a three-key 3DES (3TDEA) key in any key set with `ChangeKeyEV2`.
+        ///
+        /// See [`Self::change_key_ev2_aes`] for the `old_key` rules.
+        pub $($async)? fn change_key_ev2_3tdea(
+            &mut self,
+            key_set: KeySetNumber,
+            key_number: KeyNumber,
+            new_key: [u8; 24],
+            old_key: Option<[u8; 24]>,
+        ) -> Result<(), Error> {
+            let changing_auth_key = self.changes_authenticated_key(key_set, key_number)?;
+            let old_key = old_key.map(Key::ThreeKey3Des);
+            let old_key = required_old_key(old_key.as_ref(), changing_auth_key)?;
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY_EV2,
+                &[key_set.as_byte(), key_number.as_byte()],
+                &mut SoftwareCrypto,
+                &Key::ThreeKey3Des(new_key),
+                0,
+                old_key,
+            )$($await)*
+        }
 
-    fn change_key_legacy_impl(
-        &mut self,
-        key_no_byte: u8,
-        new_key: &[u8],
-        old_key: Option<&[u8]>,
-        changing_auth_key: bool,
-    ) -> Result<(), Error> {
-        let Session::Authenticated(mut session) = self.session else {
-            return Err(Error::MissingAuthentication);
-        };
+        /// Changes a key in the currently selected application to a key held by `provider`.
+        ///
+        /// The `ChangeKey` format follows the algorithm of `new_key`. `key_version` is only
+        /// sent for AES keys; DES-family versions live in the key parity bits. `old_key`
+        /// follows the rules of [`Self::change_key_aes`].
+        pub $($async)? fn change_key_with_provider<P: CryptoProvider + ?Sized>(
+            &mut self,
+            key_number: KeyNumber,
+            provider: &mut P,
+            new_key: &P::KeyHandle,
+            key_version: u8,
+            old_key: Option<&P::KeyHandle>,
+        ) -> Result<(), Error> {
+            let changing_auth_key = key_number == self.authenticated_key_number()?;
+            let old_key = required_old_key(old_key, changing_auth_key)?;
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY,
+                &[key_number.as_byte()],
+                provider,
+                new_key,
+                key_version,
+                old_key,
+            )$($await)*
+        }
 
-        let use_crc32 = session.encrypted_command_crc_size() == 4;
-        let mut plaintext: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+        /// Changes the PICC master key to a key held by `provider`.
+        ///
+        /// Accepts 2TDEA, 3TDEA and AES keys, as [`Self::change_picc_key_2tdea`],
+        /// [`Self::change_picc_key_3tdea`] and [`Self::change_picc_key_aes`] do.
+        pub $($async)? fn change_picc_key_with_provider<P: CryptoProvider + ?Sized>(
+            &mut self,
+            provider: &mut P,
+            new_key: &P::KeyHandle,
+            key_version: u8,
+        ) -> Result<(), Error> {
+            let algorithm = provider.algorithm(new_key)?;
+            let key_number = match algorithm {
+                KeyAlgorithm::TwoKey3Des => 0x00,
+                KeyAlgorithm::ThreeKey3Des => 0x40,
+                KeyAlgorithm::Aes128 => 0x80,
+                KeyAlgorithm::Des => return Err(Error::UnsupportedAlgorithm),
+            };
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY,
+                &[key_number],
+                provider,
+                new_key,
+                key_version,
+                None,
+            )$($await)*
+        }
 
-        if changing_auth_key {
-            // Same-key: [new_key || CRC(cmd || key_no || new_key)] zero-padded to block.
-            let mut crc_input: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-            crc_input
-                .push(CommandCode::CHANGE_KEY.as_byte())
-                .map_err(|_| Error::CommandTooLong)?;
-            crc_input
-                .push(key_no_byte)
-                .map_err(|_| Error::CommandTooLong)?;
-            crc_input
-                .extend_from_slice(new_key)
-                .map_err(|_| Error::CommandTooLong)?;
+        /// Changes a key in any key set to a key held by `provider` with `ChangeKeyEV2`.
+        ///
+        /// See [`Self::change_key_ev2_aes`] for the `old_key` rules.
+        pub $($async)? fn change_key_ev2_with_provider<P: CryptoProvider + ?Sized>(
+            &mut self,
+            key_set: KeySetNumber,
+            key_number: KeyNumber,
+            provider: &mut P,
+            new_key: &P::KeyHandle,
+            key_version: u8,
+            old_key: Option<&P::KeyHandle>,
+        ) -> Result<(), Error> {
+            let changing_auth_key = self.changes_authenticated_key(key_set, key_number)?;
+            let old_key = required_old_key(old_key, changing_auth_key)?;
+            self.change_key_impl(
+                CommandCode::CHANGE_KEY_EV2,
+                &[key_set.as_byte(), key_number.as_byte()],
+                provider,
+                new_key,
+                key_version,
+                old_key,
+            )$($await)*
+        }
 
-            plaintext
-                .extend_from_slice(new_key)
-                .map_err(|_| Error::CommandTooLong)?;
-            if use_crc32 {
-                plaintext
-                    .extend_from_slice(&desfire_crc32(crc_input.as_slice()))
-                    .map_err(|_| Error::CommandTooLong)?;
-            } else {
-                plaintext
-                    .extend_from_slice(&desfire_crc16(crc_input.as_slice()))
-                    .map_err(|_| Error::CommandTooLong)?;
+        /// Sends a `ChangeKey` or `ChangeKeyEV2` whose cryptogram `provider` builds.
+        ///
+        /// `old_key` is `None` exactly when the slot is the authenticated key;
+        /// `key_version` is only sent for AES keys.
+        $($async)? fn change_key_impl<P: CryptoProvider + ?Sized>(
+            &mut self,
+            code: CommandCode,
+            header: &[u8],
+            provider: &mut P,
+            new_key: &P::KeyHandle,
+            key_version: u8,
+            old_key: Option<&P::KeyHandle>,
+        ) -> Result<(), Error> {
+            let key_version =
+                (provider.algorithm(new_key)? == KeyAlgorithm::Aes128).then_some(key_version);
+            let changing_auth_key = old_key.is_none();
+
+            match self.session.clone() {
+                Session::Authenticated(mut session) => {
+                    let format = if key_version.is_some() || session.encrypted_command_crc_size() == 4
+                    {
+                        ChangeKeyFormat::Crc32
+                    } else {
+                        ChangeKeyFormat::Crc16
+                    };
+                    let request = ChangeKeyRequest::new(
+                        code,
+                        header,
+                        format,
+                        key_version,
+                        session.encryption_key(),
+                        &session.chaining_iv(),
+                    )?;
+                    let cryptogram = provider.change_key_cryptogram(&request, new_key, old_key)?;
+                    // The provider encrypted with the chaining IV, which now moves
+                    // to the last ciphertext block.
+                    session.advance_chaining(cryptogram.as_bytes())?;
+                    let cmd_data = change_key_data(header, &cryptogram, session.block_size())?;
+                    let command = Command::new(code, cmd_data.as_slice())?;
+
+                    let response = self.executor.exchange_one(&command)$($await)*?;
+                    if response.status() != Status::OperationOk {
+                        return Err(Error::Status(response.status()));
+                    }
+
+                    if changing_auth_key {
+                        // Card invalidates the session after changing the authenticated key.
+                        self.session = Session::Unauthenticated;
+                    } else {
+                        // Response carries 8-byte MAC over empty body.
+                        verify_response_mac(&mut session, Status::OperationOk, response.data())?;
+                        self.session = Session::Authenticated(session);
+                    }
+                    Ok(())
+                }
+                Session::AuthenticatedEv2(session) => {
+                    // The header goes in the clear and the key data in `CommMode.Full`.
+                    let request = ChangeKeyRequest::new(
+                        code,
+                        header,
+                        ChangeKeyFormat::Ev2,
+                        key_version,
+                        SessionKey::Aes(session.encryption_key().clone()),
+                        &session.command_iv(),
+                    )?;
+                    let cryptogram = provider.change_key_cryptogram(&request, new_key, old_key)?;
+                    let mut cmd_data = change_key_data(header, &cryptogram, 16)?;
+
+                    let mut body: Vec<u8, 0> = Vec::new();
+                    if changing_auth_key {
+                        // Card ends the session and answers without a response MAC.
+                        let mac = session.command_mac(code, cmd_data.as_slice())?;
+                        cmd_data
+                            .extend_from_slice(&mac.as_bytes())
+                            .map_err(|_| Error::CommandTooLong)?;
+                        let command = Command::new(code, cmd_data.as_slice())?;
+                        self.executor.execute(&command, &mut body)$($await)*?;
+                        self.session = Session::Unauthenticated;
+                    } else {
+                        self.exchange_ev2(session, code, cmd_data.as_slice(), &mut body)$($await)*?;
+                    }
+                    Ok(())
+                }
+                Session::Unauthenticated => Err(Error::MissingAuthentication),
             }
-        } else {
-            let old = old_key.ok_or(Error::MissingOldKey)?;
+        }
 
-            // key_xor = new_key XOR old_key.
-            let mut key_xor: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-            for (n, o) in new_key.iter().zip(old.iter()) {
-                key_xor.push(n ^ o).map_err(|_| Error::CommandTooLong)?;
-            }
+        /// Starts staging a new key set: every key in it is reset to zeros of `key_type`.
+        ///
+        /// Requires authentication with the application master key.
+        pub $($async)? fn initialize_key_set(
+            &mut self,
+            key_set: KeySetNumber,
+            key_type: ApplicationKeyType,
+        ) -> Result<(), Error> {
+            let key_set_type = match key_type {
+                ApplicationKeyType::TwoKey3Des => 0x00,
+                ApplicationKeyType::ThreeKey3Des => 0x01,
+                ApplicationKeyType::Aes => 0x02,
+                ApplicationKeyType::Rfu => return Err(Error::UnsupportedAlgorithm),
+            };
+            let command = Command::new(
+                CommandCode::INITIALIZE_KEY_SET,
+                &[key_set.as_byte(), key_set_type],
+            )?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-            // CRC_a over [cmd || key_no || key_xor]; CRC_b over [new_key].
-            let mut crc_input_a: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-            crc_input_a
-                .push(CommandCode::CHANGE_KEY.as_byte())
-                .map_err(|_| Error::CommandTooLong)?;
-            crc_input_a
-                .push(key_no_byte)
-                .map_err(|_| Error::CommandTooLong)?;
-            crc_input_a
-                .extend_from_slice(key_xor.as_slice())
-                .map_err(|_| Error::CommandTooLong)?;
+        /// Finishes staging a key set and records its version.
+        ///
+        /// The key set can then be activated with [`Self::roll_key_set`].
+        pub $($async)? fn finalize_key_set(
+            &mut self,
+            key_set: KeySetNumber,
+            key_set_version: u8,
+        ) -> Result<(), Error> {
+            let command = Command::new(
+                CommandCode::FINALIZE_KEY_SET,
+                &[key_set.as_byte(), key_set_version],
+            )?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-            // Plaintext: [key_xor || CRC_a || CRC_b] zero-padded to block.
-            plaintext
-                .extend_from_slice(key_xor.as_slice())
+        /// Atomically makes a finalized key set the active one.
+        ///
+        /// The previous active keys stop working immediately, so the card ends the
+        /// session and answers without a response MAC. Re-authenticate with a key from
+        /// the new key set afterwards.
+        pub $($async)? fn roll_key_set(&mut self, key_set: KeySetNumber) -> Result<(), Error> {
+            let data = [key_set.as_byte()];
+            let mut cmd_data: Vec<u8, 9> = Vec::new();
+            cmd_data
+                .extend_from_slice(&data)
                 .map_err(|_| Error::CommandTooLong)?;
-            if use_crc32 {
-                plaintext
-                    .extend_from_slice(&desfire_crc32(crc_input_a.as_slice()))
-                    .map_err(|_| Error::CommandTooLong)?;
-                plaintext
-                    .extend_from_slice(&desfire_crc32(new_key))
-                    .map_err(|_| Error::CommandTooLong)?;
-            } else {
-                plaintext
-                    .extend_from_slice(&desfire_crc16(crc_input_a.as_slice()))
-                    .map_err(|_| Error::CommandTooLong)?;
-                plaintext
-                    .extend_from_slice(&desfire_crc16(new_key))
-                    .map_err(|_| Error::CommandTooLong)?;
+            match &self.session {
+                Session::AuthenticatedEv2(session) => {
+                    let mac = session.command_mac(CommandCode::ROLL_KEY_SET, &data)?;
+                    cmd_data
+                        .extend_from_slice(&mac.as_bytes())
+                        .map_err(|_| Error::CommandTooLong)?;
+                }
+                Session::Authenticated(_) => {}
+                Session::Unauthenticated => return Err(Error::MissingAuthentication),
             }
-        }
 
-        // Zero-pad to block boundary.
-        let block_size = session.block_size();
-        while !plaintext.len().is_multiple_of(block_size) {
-            plaintext.push(0x00).map_err(|_| Error::CommandTooLong)?;
+            let command = Command::new(CommandCode::ROLL_KEY_SET, cmd_data.as_slice())?;
+            let mut body: Vec<u8, 8> = Vec::new();
+            self.executor.execute(&command, &mut body)$($await)*?;
+            self.session = Session::Unauthenticated;
+            Ok(())
         }
 
-        // Encrypt using current chaining IV; chaining advances to last ciphertext block.
-        session.cbc_encrypt_in_place(plaintext.as_mut_slice())?;
-
-        let mut cmd_data: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-        cmd_data
-            .push(key_no_byte)
-            .map_err(|_| Error::CommandTooLong)?;
-        cmd_data
-            .extend_from_slice(plaintext.as_slice())
-            .map_err(|_| Error::CommandTooLong)?;
-        let command = Command::new(CommandCode::CHANGE_KEY, cmd_data.as_slice())?;
-
-        let response = self.executor.exchange_one(&command)?;
-        if response.status() != Status::OperationOk {
-            return Err(Error::Status(response.status()));
+        /// Whether a `ChangeKeyEV2` target is the key the session was authenticated with.
+        fn changes_authenticated_key(
+            &self,
+            key_set: KeySetNumber,
+            key_number: KeyNumber,
+        ) -> Result<bool, Error> {
+            let authenticated = self.authenticated_key_number()?;
+            Ok(key_set == KeySetNumber::ACTIVE && key_number == authenticated)
         }
 
-        if changing_auth_key {
-            self.session = Session::Unauthenticated;
-        } else {
-            verify_response_mac(&mut session, Status::OperationOk, response.data())?;
-            self.session = Session::Authenticated(session);
+        /// Reads available free memory, when supported by the card.
+        pub $($async)? fn free_memory(&mut self) -> Result<U24, Error> {
+            let command = Command::new(CommandCode::FREE_MEM, &[])?;
+            let mut data: Vec<u8, 3> = Vec::new();
+
+            self.execute_query(&command, &mut data)$($await)*?;
+            let bytes = data
+                .as_slice()
+                .try_into()
+                .map_err(|_| Error::InvalidResponseLength)?;
+            Ok(U24::from_le_bytes(bytes))
         }
 
-        Ok(())
-    }
+        /// Selects an application by `DESFire` AID.
+        pub $($async)? fn select_application(
+            &mut self,
+            application_id: ApplicationId,
+        ) -> Result<(), Error> {
+            let command =
+                Command::new(CommandCode::SELECT_APPLICATION, &application_id.as_bytes())?;
+            let mut data: Vec<u8, 0> = Vec::new();
+
+            self.executor.execute(&command, &mut data)$($await)*?;
+            self.clear_session();
+            Ok(())
+        }
 
-    /// Reads available free memory, when supported by the card.
-    pub fn free_memory(&mut self) -> Result<U24, Error> {
-        let command = Command::new(CommandCode::FREE_MEM, &[])?;
-        let mut data: Vec<u8, 3> = Vec::new();
+        /// Reads all application identifiers returned by the card.
+        pub $($async)? fn get_application_ids<const N: usize>(
+            &mut self,
+            application_ids: &mut Vec<ApplicationId, N>,
+        ) -> Result<(), Error> {
+            let command = Command::new(CommandCode::GET_APPLICATION_IDS, &[])?;
+            let mut data: Vec<u8, 252> = Vec::new();
 
-        self.executor.execute(&command, &mut data)?;
-        let bytes = data
-            .as_slice()
-            .try_into()
-            .map_err(|_| Error::InvalidResponseLength)?;
-        Ok(U24::from_le_bytes(bytes))
-    }
+            self.execute_query(&command, &mut data)$($await)*?;
+            parse_application_ids(data.as_slice(), application_ids)
+        }
 
-    /// Selects an application by `DESFire` AID.
-    pub fn select_application(&mut self, application_id: ApplicationId) -> Result<(), Error> {
-        let command = Command::new(CommandCode::SELECT_APPLICATION, &application_id.as_bytes())?;
-        let mut data: Vec<u8, 0> = Vec::new();
+        /// Commits all pending changes in the current transaction.
+        ///
+        /// Required after writing to backup data, value or record files to make
+        /// changes permanent. Without commit the card discards the writes on
+        /// the next `SelectApplication` or power cycle.
+        pub $($async)? fn commit_transaction(&mut self) -> Result<(), Error> {
+            let command = Command::new(CommandCode::COMMIT_TRANSACTION, &[])?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-        self.executor.execute(&command, &mut data)?;
-        self.clear_session();
-        Ok(())
-    }
+        /// Aborts all pending changes in the current transaction.
+        ///
+        /// Discards any writes to backup data, value or record files since the
+        /// last commit.
+        pub $($async)? fn abort_transaction(&mut self) -> Result<(), Error> {
+            let command = Command::new(CommandCode::ABORT_TRANSACTION, &[])?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-    /// Reads all application identifiers returned by the card.
-    pub fn get_application_ids<const N: usize>(
-        &mut self,
-        application_ids: &mut Vec<ApplicationId, N>,
-    ) -> Result<(), Error> {
-        let command = Command::new(CommandCode::GET_APPLICATION_IDS, &[])?;
-        let mut data: Vec<u8, 252> = Vec::new();
+        /// Formats the PICC, wiping all applications and reclaiming all memory.
+        ///
+        /// Requires prior authentication with the PICC master key.
+        pub $($async)? fn format_picc(&mut self) -> Result<(), Error> {
+            let command = Command::new(CommandCode::FORMAT_PICC, &[])?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-        self.executor.execute(&command, &mut data)?;
-        parse_application_ids(data.as_slice(), application_ids)
-    }
+        /// Sets the PICC configuration flags with `SetConfiguration`.
+        ///
+        /// Requires PICC master key authentication. Disabling `FormatPICC` and
+        /// enabling random UID cannot be undone.
+        pub $($async)? fn set_picc_configuration(
+            &mut self,
+            configuration: PiccConfiguration,
+        ) -> Result<(), Error> {
+            self.execute_enciphered_write(
+                CommandCode::SET_CONFIGURATION,
+                &[SET_CONFIGURATION_PICC],
+                &[configuration.as_byte()],
+            )$($await)*
+        }
 
-    /// Commits all pending changes in the current transaction.
-    ///
-    /// Required after writing to backup data files or record files to make
-    /// changes permanent. Without commit the card discards the writes on
-    /// the next `SelectApplication` or power cycle.
-    pub fn commit_transaction(&mut self) -> Result<(), Error> {
-        let command = Command::new(CommandCode::COMMIT_TRANSACTION, &[])?;
-        self.execute_management_command(&command)
-    }
+        /// Sets the key and key version used for every key slot of new applications.
+        ///
+        /// Requires PICC master key authentication.
+        pub $($async)? fn set_default_key(&mut self, default_key: DefaultKey) -> Result<(), Error> {
+            self.execute_enciphered_write(
+                CommandCode::SET_CONFIGURATION,
+                &[SET_CONFIGURATION_DEFAULT_KEY],
+                &default_key.to_bytes(),
+            )$($await)*
+        }
 
-    /// Aborts all pending changes in the current transaction.
-    ///
-    /// Discards any writes to backup data files or record files since the
-    /// last commit.
-    pub fn abort_transaction(&mut self) -> Result<(), Error> {
-        let command = Command::new(CommandCode::ABORT_TRANSACTION, &[])?;
-        self.execute_management_command(&command)
-    }
+        /// Sets the ATS the card returns after `RATS`.
+        ///
+        /// Requires PICC master key authentication.
+        pub $($async)? fn set_ats(&mut self, ats: &Ats) -> Result<(), Error> {
+            self.execute_enciphered_write_with_terminator(
+                CommandCode::SET_CONFIGURATION,
+                &[SET_CONFIGURATION_ATS],
+                ats.as_bytes(),
+                true,
+            )$($await)*
+        }
 
-    /// Formats the PICC, wiping all applications and reclaiming all memory.
-    ///
-    /// Requires prior authentication with the PICC master key.
-    pub fn format_picc(&mut self) -> Result<(), Error> {
-        let command = Command::new(CommandCode::FORMAT_PICC, &[])?;
-        self.execute_management_command(&command)
-    }
+        /// Creates a new application.
+        pub $($async)? fn create_application(
+            &mut self,
+            application_id: ApplicationId,
+            key_settings: KeySettings,
+        ) -> Result<(), Error> {
+            let mut payload: Vec<u8, 5> = Vec::new();
+            payload
+                .extend_from_slice(&application_id.as_bytes())
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .push(key_settings.raw_settings())
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .push(key_settings.raw_key_count())
+                .map_err(|_| Error::CommandTooLong)?;
+            let command = Command::new(CommandCode::CREATE_APPLICATION, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-    /// Creates a new application.
-    pub fn create_application(
-        &mut self,
-        application_id: ApplicationId,
-        key_settings: KeySettings,
-    ) -> Result<(), Error> {
-        let mut payload: Vec<u8, 5> = Vec::new();
-        payload
-            .extend_from_slice(&application_id.as_bytes())
-            .map_err(|_| Error::CommandTooLong)?;
-        payload
-            .push(key_settings.raw_settings())
-            .map_err(|_| Error::CommandTooLong)?;
-        payload
-            .push(key_settings.raw_key_count())
-            .map_err(|_| Error::CommandTooLong)?;
-        let command = Command::new(CommandCode::CREATE_APPLICATION, payload.as_slice())?;
-        self.execute_management_command(&command)
-    }
+        /// Creates a new application addressable through ISO 7816-4 by file id and optional DF
+        /// name.
+        pub $($async)? fn create_iso_application(
+            &mut self,
+            application_id: ApplicationId,
+            key_settings: KeySettings,
+            iso_file_id: IsoFileId,
+            df_name: Option<&DfName>,
+        ) -> Result<(), Error> {
+            // Bit 5 of the key-count byte announces the ISO file id in the payload.
+            let mut payload: Vec<u8, 23> = Vec::new();
+            payload
+                .extend_from_slice(&application_id.as_bytes())
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .push(key_settings.raw_settings())
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .push(key_settings.raw_key_count() | 0x20)
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .extend_from_slice(&iso_file_id.to_le_bytes())
+                .map_err(|_| Error::CommandTooLong)?;
+            if let Some(name) = df_name {
+                payload
+                    .extend_from_slice(name.as_bytes())
+                    .map_err(|_| Error::CommandTooLong)?;
+            }
+            let command = Command::new(CommandCode::CREATE_APPLICATION, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-    /// Deletes an application and all its files.
-    pub fn delete_application(&mut self, application_id: ApplicationId) -> Result<(), Error> {
-        let command = Command::new(CommandCode::DELETE_APPLICATION, &application_id.as_bytes())?;
-        self.execute_management_command(&command)
-    }
+        /// Creates a new application with EV2 key sets.
+        ///
+        /// `KeySett2` bit 4 announces `KeySett3`, whose bit 0 enables the key-set
+        /// parameters that follow it.
+        pub $($async)? fn create_application_with_key_sets(
+            &mut self,
+            application_id: ApplicationId,
+            key_settings: KeySettings,
+            key_sets: KeySetOptions,
+        ) -> Result<(), Error> {
+            let mut payload: Vec<u8, 10> = Vec::new();
+            payload
+                .extend_from_slice(&application_id.as_bytes())
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .push(key_settings.raw_settings())
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .push(key_settings.raw_key_count() | 0x10)
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .extend_from_slice(&key_sets.to_bytes())
+                .map_err(|_| Error::CommandTooLong)?;
+            let command = Command::new(CommandCode::CREATE_APPLICATION, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-    /// Reads all file identifiers in the selected application.
-    pub fn get_file_ids<const N: usize>(
-        &mut self,
-        file_ids: &mut Vec<FileId, N>,
-    ) -> Result<(), Error> {
-        let command = Command::new(CommandCode::GET_FILE_IDS, &[])?;
-        let mut data: Vec<u8, 32> = Vec::new();
+        /// Reads the ISO file id and DF name of every ISO application on the card.
+        ///
+        /// Must be sent at PICC level. The card returns one application per frame;
+        /// under a session the response MAC follows the last one.
+        pub $($async)? fn get_df_names<const N: usize>(
+            &mut self,
+            names: &mut Vec<DfNameEntry, N>,
+        ) -> Result<(), Error> {
+            names.clear();
+
+            let mut session = self.session.clone();
+            let command = match &mut session {
+                Session::AuthenticatedEv2(session) => {
+                    let mac = session.command_mac(CommandCode::GET_DF_NAMES, &[])?;
+                    Command::new(CommandCode::GET_DF_NAMES, &mac.as_bytes())?
+                }
+                Session::Authenticated(session) => {
+                    session.update_command_cmac(CommandCode::GET_DF_NAMES, &[])?;
+                    Command::new(CommandCode::GET_DF_NAMES, &[])?
+                }
+                Session::Unauthenticated => Command::new(CommandCode::GET_DF_NAMES, &[])?,
+            };
+            let mac_len = match &session {
+                Session::Authenticated(session) => Some(session.mac_len()),
+                Session::AuthenticatedEv2(_) => Some(8),
+                Session::Unauthenticated => None,
+            };
+
+            // Frames are kept apart so the variable-length entries can be split;
+            // under a session the response MAC covers their concatenation.
+            let mut mac_input: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            let mut response = self.executor.exchange_one(&command)$($await)*?;
+            let mut additional_frames = 0;
+            loop {
+                let status = response.status();
+                if !matches!(status, Status::OperationOk | Status::AdditionalFrame) {
+                    return Err(Error::Status(status));
+                }
 
-        self.executor.execute(&command, &mut data)?;
-        parse_file_ids(data.as_slice(), file_ids)
-    }
+                let mut entry = response.data();
+                if let (Some(mac_len), Status::OperationOk) = (mac_len, status) {
+                    let mac_start = entry
+                        .len()
+                        .checked_sub(mac_len)
+                        .ok_or(Error::InvalidResponseLength)?;
+                    entry = &entry[..mac_start];
+                }
+                if !entry.is_empty() {
+                    names
+                        .push(DfNameEntry::parse(entry)?)
+                        .map_err(|_| Error::ResponseTooLong)?;
+                    if mac_len.is_some() {
+                        mac_input
+                            .extend_from_slice(entry)
+                            .map_err(|_| Error::ResponseTooLong)?;
+                    }
+                }
 
-    /// Reads and parses settings for one file in the selected application.
-    pub fn get_file_settings(&mut self, file_id: FileId) -> Result<FileSettings, Error> {
-        let command = Command::new(CommandCode::GET_FILE_SETTINGS, &[file_id.as_byte()])?;
-        let mut data: Vec<u8, 32> = Vec::new();
+                if status == Status::OperationOk {
+                    break;
+                }
+                additional_frames += 1;
+                if additional_frames > MAX_ADDITIONAL_FRAMES {
+                    return Err(Error::TooManyAdditionalFrames);
+                }
+                response = self.executor.exchange_one(&Command::additional_frame())$($await)*?;
+            }
 
-        if matches!(self.session, Session::Authenticated(_)) {
-            self.execute_single_maced(&command, &mut data)?;
-        } else {
-            self.executor.execute(&command, &mut data)?;
+            let Some(mac_len) = mac_len else {
+                return Ok(());
+            };
+            let data = response.data();
+            let received = &data[data.len() - mac_len..];
+            let expected = match &mut session {
+                Session::Authenticated(session) => {
+                    session.update_response_cmac(Status::OperationOk, mac_input.as_slice())?
+                }
+                Session::AuthenticatedEv2(session) => {
+                    session.increment_command_counter()?;
+                    session.response_mac(Status::OperationOk, mac_input.as_slice())?
+                }
+                Session::Unauthenticated => return Ok(()),
+            };
+            if !constant_time_eq(&expected.as_bytes()[..mac_len], received) {
+                return Err(Error::InvalidMac);
+            }
+            self.session = session;
+            Ok(())
         }
 
-        FileSettings::parse(data.as_slice())
-    }
+        /// Deletes an application and all its files.
+        pub $($async)? fn delete_application(
+            &mut self,
+            application_id: ApplicationId,
+        ) -> Result<(), Error> {
+            let command =
+                Command::new(CommandCode::DELETE_APPLICATION, &application_id.as_bytes())?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-    /// Changes the communication mode and access rights of a file.
-    ///
-    /// Requires authentication. New settings are sent encrypted with CRC32 using the current
-    /// session chaining IV (same pattern as enciphered writes — no command CMAC update).
-    pub fn change_file_settings(
-        &mut self,
-        file_id: FileId,
-        communication_mode: CommunicationMode,
-        access_rights: AccessRights,
-    ) -> Result<(), Error> {
-        let Session::Authenticated(mut session) = self.session else {
-            return Err(Error::MissingAuthentication);
-        };
+        /// Reads all file identifiers in the selected application.
+        pub $($async)? fn get_file_ids<const N: usize>(
+            &mut self,
+            file_ids: &mut Vec<FileId, N>,
+        ) -> Result<(), Error> {
+            let command = Command::new(CommandCode::GET_FILE_IDS, &[])?;
+            let mut data: Vec<u8, 32> = Vec::new();
 
-        let ar = access_rights.to_bytes();
-        let new_settings = [u8::from(communication_mode), ar[0], ar[1]];
+            self.execute_query(&command, &mut data)$($await)*?;
+            parse_file_ids(data.as_slice(), file_ids)
+        }
 
-        // CRC covers [cmd_code || fid || new_settings].
-        let mut crc_input: Vec<u8, 5> = Vec::new();
-        crc_input
-            .push(CommandCode::CHANGE_FILE_SETTINGS.as_byte())
-            .map_err(|_| Error::CommandTooLong)?;
-        crc_input
-            .push(file_id.as_byte())
-            .map_err(|_| Error::CommandTooLong)?;
-        crc_input
-            .extend_from_slice(&new_settings)
-            .map_err(|_| Error::CommandTooLong)?;
+        /// Reads the ISO file ids of all files in the selected application that have one.
+        pub $($async)? fn get_iso_file_ids<const N: usize>(
+            &mut self,
+            iso_file_ids: &mut Vec<IsoFileId, N>,
+        ) -> Result<(), Error> {
+            let command = Command::new(CommandCode::GET_ISO_FILE_IDS, &[])?;
+            let mut data: Vec<u8, 64> = Vec::new();
 
-        let block_size = session.block_size();
-        let mut plaintext: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-        plaintext
-            .extend_from_slice(&new_settings)
-            .map_err(|_| Error::CommandTooLong)?;
-        extend_desfire_crc(
-            &mut plaintext,
-            crc_input.as_slice(),
-            session.encrypted_command_crc_size(),
-        )?;
-        while !plaintext.len().is_multiple_of(block_size) {
-            plaintext.push(0x00).map_err(|_| Error::CommandTooLong)?;
+            self.execute_query(&command, &mut data)$($await)*?;
+            parse_iso_file_ids(data.as_slice(), iso_file_ids)
         }
 
-        // Encrypts in place using current chaining IV; chaining advances to last ciphertext block.
-        session.cbc_encrypt_in_place(plaintext.as_mut_slice())?;
+        /// Selects an application by DF name, or a DF/EF by ISO file id, with `ISOSelectFile`.
+        ///
+        /// The card drops any authentication when the selection changes, so the
+        /// local session is cleared as well.
+        pub $($async)? fn iso_select_file(&mut self, target: &IsoSelect) -> Result<(), Error> {
+            let command = IsoCommand::select_file(target)?;
+            let mut data: Vec<u8, 0> = Vec::new();
+
+            self.executor.exchange_iso(&command, &mut data)$($await)*?;
+            self.clear_session();
+            Ok(())
+        }
 
-        let mut cmd_data: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-        cmd_data
-            .push(file_id.as_byte())
-            .map_err(|_| Error::CommandTooLong)?;
-        cmd_data
-            .extend_from_slice(plaintext.as_slice())
-            .map_err(|_| Error::CommandTooLong)?;
-        let command = Command::new(CommandCode::CHANGE_FILE_SETTINGS, cmd_data.as_slice())?;
+        /// Reads bytes from the selected standard or backup data file with `ISOReadBinary`.
+        ///
+        /// ISO commands are always sent in plain. A `length` of zero reads up to the
+        /// end of the file.
+        pub $($async)? fn iso_read_binary<const N: usize>(
+            &mut self,
+            offset: u16,
+            length: u8,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            let command = IsoCommand::read_binary(offset, length)?;
+            self.executor.exchange_iso(&command, data)$($await)*
+        }
 
-        let response = self.executor.exchange_one(&command)?;
-        if response.status() != Status::OperationOk {
-            return Err(Error::Status(response.status()));
+        /// Writes bytes to the selected standard or backup data file with `ISOUpdateBinary`.
+        pub $($async)? fn iso_update_binary(
+            &mut self,
+            offset: u16,
+            data: &[u8],
+        ) -> Result<(), Error> {
+            let command = IsoCommand::update_binary(offset, data)?;
+            let mut response: Vec<u8, 0> = Vec::new();
+            self.executor.exchange_iso(&command, &mut response)$($await)*
         }
 
-        verify_response_mac(&mut session, Status::OperationOk, response.data())?;
-        self.session = Session::Authenticated(session);
-        Ok(())
-    }
+        /// Reads records from the selected record file with `ISOReadRecords`.
+        ///
+        /// Record number 1 is the most recently written record. With `all_following`
+        /// set, that record and all older ones are returned.
+        pub $($async)? fn iso_read_records<const N: usize>(
+            &mut self,
+            record_number: u8,
+            all_following: bool,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            let command = IsoCommand::read_records(record_number, all_following)?;
+            self.executor.exchange_iso(&command, data)$($await)*
+        }
 
-    /// Reads and MAC-verifies bytes from a `MACed` standard or backup data file.
-    pub fn read_data_maced<const N: usize>(
-        &mut self,
-        file_id: FileId,
-        offset: U24,
-        length: U24,
-        data: &mut Vec<u8, N>,
-    ) -> Result<(), Error> {
-        let command_data = read_data_command_data(file_id, offset, length)?;
-        let command = Command::new(CommandCode::READ_DATA, command_data.as_slice())?;
-        let Session::Authenticated(mut session) = self.session else {
-            return Err(Error::MissingAuthentication);
-        };
+        /// Reads and parses settings for one file in the selected application.
+        pub $($async)? fn get_file_settings(
+            &mut self,
+            file_id: FileId,
+        ) -> Result<FileSettings, Error> {
+            let command = Command::new(CommandCode::GET_FILE_SETTINGS, &[file_id.as_byte()])?;
+            let mut data: Vec<u8, 32> = Vec::new();
 
-        session.update_command_cmac(command.code(), command.data())?;
+            if matches!(self.session, Session::Unauthenticated) {
+                self.executor.execute(&command, &mut data)$($await)*?;
+            } else {
+                self.execute_single_maced(&command, &mut data)$($await)*?;
+            }
 
-        let mut raw: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-        self.executor.execute(&command, &mut raw)?;
+            FileSettings::parse(data.as_slice())
+        }
 
-        let body = verify_response_mac(&mut session, Status::OperationOk, raw.as_slice())?;
-        data.clear();
-        data.extend_from_slice(body)
-            .map_err(|_| Error::ResponseTooLong)?;
+        /// Changes the communication mode and access rights of a file.
+        ///
+        /// Requires authentication. New settings are sent encrypted with CRC32 using the current
+        /// session chaining IV (same pattern as enciphered writes — no command CMAC update).
+        pub $($async)? fn change_file_settings(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            access_rights: AccessRights,
+        ) -> Result<(), Error> {
+            let ar = access_rights.to_bytes();
+            let new_settings = [u8::from(communication_mode), ar[0], ar[1]];
+            self.execute_enciphered_write(
+                CommandCode::CHANGE_FILE_SETTINGS,
+                &[file_id.as_byte()],
+                &new_settings,
+            )$($await)*
+        }
 
-        self.session = Session::Authenticated(session);
-        Ok(())
-    }
+        /// Reads and MAC-verifies bytes from a `MACed` standard or backup data file.
+        pub $($async)? fn read_data_maced<const N: usize>(
+            &mut self,
+            file_id: FileId,
+            offset: U24,
+            length: U24,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            let command_data = read_data_command_data(file_id, offset, length)?;
+            let command = Command::new(CommandCode::READ_DATA, command_data.as_slice())?;
+            self.execute_maced_read(&command, data)$($await)*
+        }
 
-    /// Reads bytes from a standard or backup data file.
-    pub fn read_data<const N: usize>(
-        &mut self,
-        file_id: FileId,
-        offset: U24,
-        length: U24,
-        data: &mut Vec<u8, N>,
-    ) -> Result<(), Error> {
-        let command_data = read_data_command_data(file_id, offset, length)?;
-        let command = Command::new(CommandCode::READ_DATA, command_data.as_slice())?;
-        self.executor.execute(&command, data)
-    }
+        /// Reads bytes from a standard or backup data file.
+        pub $($async)? fn read_data<const N: usize>(
+            &mut self,
+            file_id: FileId,
+            offset: U24,
+            length: U24,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            let command_data = read_data_command_data(file_id, offset, length)?;
+            let command = Command::new(CommandCode::READ_DATA, command_data.as_slice())?;
+            match self.session.clone() {
+                Session::AuthenticatedEv2(session) => {
+                    self.execute_ev2_plain(session, &command, data)$($await)*
+                }
+                Session::Unauthenticated | Session::Authenticated(_) => {
+                    self.executor.execute(&command, data)$($await)*
+                }
+            }
+        }
 
-    /// Reads and decrypts bytes from an enciphered standard or backup data file.
-    ///
-    /// A `length` of zero is passed through to the card and the plaintext length
-    /// is inferred from the encrypted response CRC and zero padding.
-    pub fn read_data_enciphered<const N: usize>(
-        &mut self,
-        file_id: FileId,
-        offset: U24,
-        length: U24,
-        data: &mut Vec<u8, N>,
-    ) -> Result<(), Error> {
-        let command_data = read_data_command_data(file_id, offset, length)?;
-        let command = Command::new(CommandCode::READ_DATA, command_data.as_slice())?;
-        let Session::Authenticated(mut session) = self.session else {
-            return Err(Error::MissingAuthentication);
-        };
+        /// Reads and decrypts bytes from an enciphered standard or backup data file.
+        ///
+        /// A `length` of zero is passed through to the card and the plaintext length
+        /// is inferred from the encrypted response CRC and zero padding.
+        pub $($async)? fn read_data_enciphered<const N: usize>(
+            &mut self,
+            file_id: FileId,
+            offset: U24,
+            length: U24,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            let command_data = read_data_command_data(file_id, offset, length)?;
+            let command = Command::new(CommandCode::READ_DATA, command_data.as_slice())?;
+            self.execute_enciphered_read(
+                &command,
+                usize::try_from(length.as_u32()).expect("U24 fits in usize"),
+                data,
+            )$($await)*
+        }
 
-        #[allow(unused_variables)]
-        // The cmac value is not used in nostd due to the logging call below
-        session.update_command_cmac(command.code(), command.data())?;
+        /// Writes bytes to a plain standard or backup data file.
+        pub $($async)? fn write_data(
+            &mut self,
+            file_id: FileId,
+            offset: U24,
+            data: &[u8],
+        ) -> Result<(), Error> {
+            let header = write_data_command_header(file_id, offset, data)?;
+            self.execute_write_command(
+                CommandCode::WRITE_DATA,
+                CommunicationMode::Plain,
+                header.as_slice(),
+                data,
+            )$($await)*
+        }
 
-        let response = self.executor.exchange_one(&command)?;
-        if response.status() != Status::OperationOk {
-            return Err(Error::Status(response.status()));
+        /// Writes and `MAC`-signs bytes to a `MACed` standard or backup data file.
+        pub $($async)? fn write_data_maced(
+            &mut self,
+            file_id: FileId,
+            offset: U24,
+            data: &[u8],
+        ) -> Result<(), Error> {
+            let header = write_data_command_header(file_id, offset, data)?;
+            self.execute_maced_write(CommandCode::WRITE_DATA, header.as_slice(), data)$($await)*
         }
-        let block_size = session.block_size();
-        if response.data().len() < block_size || response.data().len() % block_size != 0 {
-            return Err(Error::InvalidResponseLength);
+
+        /// Writes and encrypts bytes to an enciphered standard or backup data file.
+        pub $($async)? fn write_data_enciphered(
+            &mut self,
+            file_id: FileId,
+            offset: U24,
+            data: &[u8],
+        ) -> Result<(), Error> {
+            let header = write_data_command_header(file_id, offset, data)?;
+            self.execute_enciphered_write(
+                CommandCode::WRITE_DATA,
+                header.as_slice(),
+                data,
+            )$($await)*
         }
 
-        let mut decrypted: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-        decrypted
-            .extend_from_slice(response.data())
-            .map_err(|_| Error::ResponseTooLong)?;
+        /// Streams `length` bytes of a standard or backup data file into `sink`.
+        ///
+        /// The transfer is split into several `ReadData` commands, each protected
+        /// according to `communication_mode`, so it is not bounded by the frame size
+        /// or a fixed buffer capacity. Unlike [`Self::read_data`], a `length` of zero
+        /// reads nothing.
+        pub $($async)? fn read_data_streamed<S: DataSink>(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            offset: U24,
+            length: U24,
+            sink: &mut S,
+        ) -> Result<(), Error> {
+            let end = stream_end(offset, length.as_u32())?;
+            let mut position = offset.as_u32();
+            let mut chunk: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+
+            while position < end {
+                let chunk_length = (end - position).min(STREAM_CHUNK_SIZE);
+                let command_data = read_data_command_data(
+                    file_id,
+                    U24::new(position).expect("stream position checked against U24 range"),
+                    U24::new(chunk_length).expect("chunk length fits U24"),
+                )?;
+                let command = Command::new(CommandCode::READ_DATA, command_data.as_slice())?;
+                let expected = usize::try_from(chunk_length).expect("chunk length fits usize");
+                self.execute_read_command(
+                    &command,
+                    communication_mode,
+                    expected,
+                    &mut chunk,
+                )$($await)*?;
+                if chunk.len() != expected {
+                    return Err(Error::InvalidResponseLength);
+                }
 
-        // Decrypts in place using current chaining IV and updates chaining state to last ciphertext block.
-        session.cbc_decrypt_in_place(decrypted.as_mut_slice())?;
+                sink.write_chunk(chunk.as_slice())?;
+                position += chunk_length;
+            }
+            Ok(())
+        }
 
-        let crc_size = session.encrypted_read_crc_size();
-        let plaintext_len = encrypted_read_plaintext_len(
-            decrypted.as_slice(),
-            usize::try_from(length.as_u32()).expect("U24 fits in usize"),
-            response.status(),
-            crc_size,
-        )?;
+        /// Writes `data` of any length to a standard or backup data file.
+        ///
+        /// The data is sent as several `WriteData` commands, each protected according
+        /// to `communication_mode` and split into `AdditionalFrame` frames as needed.
+        /// Backup files only take the new contents after [`Self::commit_transaction`].
+        pub $($async)? fn write_data_streamed(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            offset: U24,
+            data: &[u8],
+        ) -> Result<(), Error> {
+            let length = u32::try_from(data.len()).map_err(|_| Error::CommandTooLong)?;
+            stream_end(offset, length)?;
+            let chunk_size = usize::try_from(STREAM_CHUNK_SIZE).expect("chunk size fits usize");
+            let mut position = offset.as_u32();
+
+            for chunk in data.chunks(chunk_size) {
+                let header = write_data_command_header(
+                    file_id,
+                    U24::new(position).expect("stream position checked against U24 range"),
+                    chunk,
+                )?;
+                self.execute_write_command(
+                    CommandCode::WRITE_DATA,
+                    communication_mode,
+                    header.as_slice(),
+                    chunk,
+                )$($await)*?;
+                position += u32::try_from(chunk.len()).expect("chunk length fits u32");
+            }
+            Ok(())
+        }
 
-        data.clear();
-        data.extend_from_slice(&decrypted.as_slice()[..plaintext_len])
-            .map_err(|_| Error::ResponseTooLong)?;
+        /// Reads the current value of a value file.
+        ///
+        /// Plain reads made while authenticated still verify the response MAC so the
+        /// session chaining state stays in sync with the card.
+        pub $($async)? fn get_value(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+        ) -> Result<i32, Error> {
+            let command = Command::new(CommandCode::GET_VALUE, &[file_id.as_byte()])?;
+            let mut data: Vec<u8, 4> = Vec::new();
+            self.execute_read_command(
+                &command,
+                communication_mode,
+                data.capacity(),
+                &mut data,
+            )$($await)*?;
 
-        self.session = Session::Authenticated(session);
-        Ok(())
-    }
+            let bytes = data
+                .as_slice()
+                .try_into()
+                .map_err(|_| Error::InvalidResponseLength)?;
+            Ok(i32::from_le_bytes(bytes))
+        }
 
-    /// Writes bytes to a plain standard or backup data file.
-    pub fn write_data(&mut self, file_id: FileId, offset: U24, data: &[u8]) -> Result<(), Error> {
-        let header = write_data_command_header(file_id, offset, data)?;
-        let mut cmd_data: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-        cmd_data
-            .extend_from_slice(header.as_slice())
-            .map_err(|_| Error::CommandTooLong)?;
-        cmd_data
-            .extend_from_slice(data)
-            .map_err(|_| Error::CommandTooLong)?;
-        let command = Command::new(CommandCode::WRITE_DATA, cmd_data.as_slice())?;
-        self.execute_management_command(&command)
-    }
+        /// Increases the value stored in a value file.
+        ///
+        /// `amount` must be positive. The change only becomes permanent after
+        /// [`Self::commit_transaction`].
+        pub $($async)? fn credit(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            amount: i32,
+        ) -> Result<(), Error> {
+            self.execute_write_command(
+                CommandCode::CREDIT,
+                communication_mode,
+                &[file_id.as_byte()],
+                &value_amount(amount)?,
+            )$($await)*
+        }
 
-    /// Writes and `MAC`-signs bytes to a `MACed` standard or backup data file.
-    pub fn write_data_maced(
-        &mut self,
-        file_id: FileId,
-        offset: U24,
-        data: &[u8],
-    ) -> Result<(), Error> {
-        let Session::Authenticated(mut session) = self.session else {
-            return Err(Error::MissingAuthentication);
-        };
+        /// Decreases the value stored in a value file.
+        ///
+        /// `amount` must be positive. The change only becomes permanent after
+        /// [`Self::commit_transaction`].
+        pub $($async)? fn debit(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            amount: i32,
+        ) -> Result<(), Error> {
+            self.execute_write_command(
+                CommandCode::DEBIT,
+                communication_mode,
+                &[file_id.as_byte()],
+                &value_amount(amount)?,
+            )$($await)*
+        }
 
-        let header = write_data_command_header(file_id, offset, data)?;
+        /// Increases a value file by at most the amount debited in the last committed transaction.
+        ///
+        /// Requires limited credit to be enabled on the file and a positive `amount`.
+        /// The change only becomes permanent after [`Self::commit_transaction`].
+        pub $($async)? fn limited_credit(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            amount: i32,
+        ) -> Result<(), Error> {
+            self.execute_write_command(
+                CommandCode::LIMITED_CREDIT,
+                communication_mode,
+                &[file_id.as_byte()],
+                &value_amount(amount)?,
+            )$($await)*
+        }
 
-        // CMAC covers [cmd_code || header || data].
-        let mut cmac_payload: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-        cmac_payload
-            .extend_from_slice(header.as_slice())
-            .map_err(|_| Error::CommandTooLong)?;
-        cmac_payload
-            .extend_from_slice(data)
-            .map_err(|_| Error::CommandTooLong)?;
-        let mac = session.update_command_cmac(CommandCode::WRITE_DATA, cmac_payload.as_slice())?;
+        /// Appends a record to a linear or cyclic record file.
+        ///
+        /// `offset` is the byte offset within the new record. The record only
+        /// becomes visible after [`Self::commit_transaction`].
+        pub $($async)? fn write_record(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            offset: U24,
+            data: &[u8],
+        ) -> Result<(), Error> {
+            let header = write_data_command_header(file_id, offset, data)?;
+            self.execute_write_command(
+                CommandCode::WRITE_RECORD,
+                communication_mode,
+                header.as_slice(),
+                data,
+            )$($await)*
+        }
 
-        // Build command: [header || data || MAC].
-        cmac_payload
-            .extend_from_slice(&mac.as_bytes())
-            .map_err(|_| Error::CommandTooLong)?;
-        let command = Command::new(CommandCode::WRITE_DATA, cmac_payload.as_slice())?;
-        let response = self.executor.exchange_one(&command)?;
-        if response.status() != Status::OperationOk {
-            return Err(Error::Status(response.status()));
+        /// Reads records from a linear or cyclic record file.
+        ///
+        /// `offset` counts back from the newest record and a `count` of zero reads all
+        /// records from `offset` to the oldest. `record_size` is the file's record size
+        /// from [`Self::get_file_settings`].
+        pub $($async)? fn read_records<'a, const N: usize>(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            record_size: U24,
+            offset: U24,
+            count: U24,
+            data: &'a mut Vec<u8, N>,
+        ) -> Result<Records<'a>, Error> {
+            if record_size.as_u32() == 0 {
+                return Err(Error::InvalidResponseLength);
+            }
+            let record_count = usize::try_from(count.as_u32()).expect("U24 fits in usize");
+            let length = usize::try_from(record_size.as_u32())
+                .expect("U24 fits in usize")
+                .checked_mul(record_count)
+                .ok_or(Error::ResponseTooLong)?;
+
+            let command_data = read_data_command_data(file_id, offset, count)?;
+            let command = Command::new(CommandCode::READ_RECORDS, command_data.as_slice())?;
+            self.execute_read_command(&command, communication_mode, length, data)$($await)*?;
+
+            let data: &'a [u8] = data;
+            let records = Records::new(data, record_size)?;
+            if record_count != 0 && records.len() != record_count {
+                return Err(Error::InvalidResponseLength);
+            }
+            Ok(records)
         }
 
-        verify_response_mac(&mut session, Status::OperationOk, response.data())?;
-        self.session = Session::Authenticated(session);
-        Ok(())
-    }
+        /// Clears all records from a linear or cyclic record file.
+        ///
+        /// The file is only emptied after [`Self::commit_transaction`].
+        pub $($async)? fn clear_record_file(&mut self, file_id: FileId) -> Result<(), Error> {
+            let command = Command::new(CommandCode::CLEAR_RECORD_FILE, &[file_id.as_byte()])?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-    /// Writes and encrypts bytes to an enciphered standard or backup data file.
-    pub fn write_data_enciphered(
-        &mut self,
-        file_id: FileId,
-        offset: U24,
-        data: &[u8],
-    ) -> Result<(), Error> {
-        let Session::Authenticated(mut session) = self.session else {
-            return Err(Error::MissingAuthentication);
-        };
+        /// Creates a standard data file in the selected application.
+        pub $($async)? fn create_std_data_file(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            access_rights: AccessRights,
+            size: U24,
+        ) -> Result<(), Error> {
+            let payload =
+                create_data_file_payload(file_id, None, communication_mode, access_rights, size)?;
+            let command = Command::new(CommandCode::CREATE_STD_DATA_FILE, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-        let header = write_data_command_header(file_id, offset, data)?;
+        /// Creates a standard data file with an ISO file id in the selected ISO application.
+        pub $($async)? fn create_iso_std_data_file(
+            &mut self,
+            file_id: FileId,
+            iso_file_id: IsoFileId,
+            communication_mode: CommunicationMode,
+            access_rights: AccessRights,
+            size: U24,
+        ) -> Result<(), Error> {
+            let payload = create_data_file_payload(
+                file_id,
+                Some(iso_file_id),
+                communication_mode,
+                access_rights,
+                size,
+            )?;
+            let command = Command::new(CommandCode::CREATE_STD_DATA_FILE, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-        // IV = current chaining state (no command CMAC update for enciphered writes).
-        // CRC covers the full command payload: [cmd_code || header || data].
-        // Plaintext: [data || CRC(cmd_code||header||data)] zero-padded to block boundary
-        // (ISO 9797-1 method 1 — no 0x80 terminator).
-        let mut crc_input: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-        crc_input
-            .push(CommandCode::WRITE_DATA.as_byte())
-            .map_err(|_| Error::CommandTooLong)?;
-        crc_input
-            .extend_from_slice(header.as_slice())
-            .map_err(|_| Error::CommandTooLong)?;
-        crc_input
-            .extend_from_slice(data)
-            .map_err(|_| Error::CommandTooLong)?;
-        let crc_size = session.encrypted_command_crc_size();
+        /// Creates a backup data file in the selected application.
+        pub $($async)? fn create_backup_data_file(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            access_rights: AccessRights,
+            size: U24,
+        ) -> Result<(), Error> {
+            let payload =
+                create_data_file_payload(file_id, None, communication_mode, access_rights, size)?;
+            let command = Command::new(CommandCode::CREATE_BACKUP_DATA_FILE, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-        let block_size = session.block_size();
-        let mut plaintext: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-        plaintext
-            .extend_from_slice(data)
-            .map_err(|_| Error::CommandTooLong)?;
-        extend_desfire_crc(&mut plaintext, crc_input.as_slice(), crc_size)?;
-        while !plaintext.len().is_multiple_of(block_size) {
-            plaintext.push(0x00).map_err(|_| Error::CommandTooLong)?;
+        /// Creates a backup data file with an ISO file id in the selected ISO application.
+        pub $($async)? fn create_iso_backup_data_file(
+            &mut self,
+            file_id: FileId,
+            iso_file_id: IsoFileId,
+            communication_mode: CommunicationMode,
+            access_rights: AccessRights,
+            size: U24,
+        ) -> Result<(), Error> {
+            let payload = create_data_file_payload(
+                file_id,
+                Some(iso_file_id),
+                communication_mode,
+                access_rights,
+                size,
+            )?;
+            let command = Command::new(CommandCode::CREATE_BACKUP_DATA_FILE, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
         }
 
-        // Encrypts in place using current chaining IV and updates chaining state to last ciphertext block.
-        session.cbc_encrypt_in_place(plaintext.as_mut_slice())?;
+        /// Creates a value file in the selected application.
+        #[allow(clippy::too_many_arguments)]
+        pub $($async)? fn create_value_file(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            access_rights: AccessRights,
+            lower_limit: i32,
+            upper_limit: i32,
+            initial_value: i32,
+            limited_credit_enabled: bool,
+        ) -> Result<(), Error> {
+            let ar = access_rights.to_bytes();
+            let mut payload: Vec<u8, 17> = Vec::new();
+            payload
+                .push(file_id.as_byte())
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .push(u8::from(communication_mode))
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .extend_from_slice(&ar)
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .extend_from_slice(&lower_limit.to_le_bytes())
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .extend_from_slice(&upper_limit.to_le_bytes())
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .extend_from_slice(&initial_value.to_le_bytes())
+                .map_err(|_| Error::CommandTooLong)?;
+            payload
+                .push(u8::from(limited_credit_enabled))
+                .map_err(|_| Error::CommandTooLong)?;
+            let command = Command::new(CommandCode::CREATE_VALUE_FILE, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-        // Build command: [header || ciphertext].
-        let mut cmd_data: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-        cmd_data
-            .extend_from_slice(header.as_slice())
-            .map_err(|_| Error::CommandTooLong)?;
-        cmd_data
-            .extend_from_slice(plaintext.as_slice())
-            .map_err(|_| Error::CommandTooLong)?;
-        let command = Command::new(CommandCode::WRITE_DATA, cmd_data.as_slice())?;
+        /// Creates a linear record file in the selected application.
+        pub $($async)? fn create_linear_record_file(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            access_rights: AccessRights,
+            record_size: U24,
+            max_records: U24,
+        ) -> Result<(), Error> {
+            let payload = create_record_file_payload(
+                file_id,
+                None,
+                communication_mode,
+                access_rights,
+                record_size,
+                max_records,
+            )?;
+            let command = Command::new(CommandCode::CREATE_LINEAR_RECORD_FILE, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-        let response = self.executor.exchange_one(&command)?;
-        if response.status() != Status::OperationOk {
-            return Err(Error::Status(response.status()));
+        /// Creates a linear record file with an ISO file id in the selected ISO application.
+        pub $($async)? fn create_iso_linear_record_file(
+            &mut self,
+            file_id: FileId,
+            iso_file_id: IsoFileId,
+            communication_mode: CommunicationMode,
+            access_rights: AccessRights,
+            record_size: U24,
+            max_records: U24,
+        ) -> Result<(), Error> {
+            let payload = create_record_file_payload(
+                file_id,
+                Some(iso_file_id),
+                communication_mode,
+                access_rights,
+                record_size,
+                max_records,
+            )?;
+            let command = Command::new(CommandCode::CREATE_LINEAR_RECORD_FILE, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
         }
 
-        verify_response_mac(&mut session, Status::OperationOk, response.data())?;
-        self.session = Session::Authenticated(session);
-        Ok(())
-    }
+        /// Creates a cyclic record file in the selected application.
+        pub $($async)? fn create_cyclic_record_file(
+            &mut self,
+            file_id: FileId,
+            communication_mode: CommunicationMode,
+            access_rights: AccessRights,
+            record_size: U24,
+            max_records: U24,
+        ) -> Result<(), Error> {
+            let payload = create_record_file_payload(
+                file_id,
+                None,
+                communication_mode,
+                access_rights,
+                record_size,
+                max_records,
+            )?;
+            let command = Command::new(CommandCode::CREATE_CYCLIC_RECORD_FILE, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-    /// Creates a standard data file in the selected application.
-    pub fn create_std_data_file(
-        &mut self,
-        file_id: FileId,
-        communication_mode: CommunicationMode,
-        access_rights: AccessRights,
-        size: U24,
-    ) -> Result<(), Error> {
-        let payload = create_data_file_payload(file_id, communication_mode, access_rights, size)?;
-        let command = Command::new(CommandCode::CREATE_STD_DATA_FILE, payload.as_slice())?;
-        self.execute_management_command(&command)
-    }
+        /// Creates a cyclic record file with an ISO file id in the selected ISO application.
+        pub $($async)? fn create_iso_cyclic_record_file(
+            &mut self,
+            file_id: FileId,
+            iso_file_id: IsoFileId,
+            communication_mode: CommunicationMode,
+            access_rights: AccessRights,
+            record_size: U24,
+            max_records: U24,
+        ) -> Result<(), Error> {
+            let payload = create_record_file_payload(
+                file_id,
+                Some(iso_file_id),
+                communication_mode,
+                access_rights,
+                record_size,
+                max_records,
+            )?;
+            let command = Command::new(CommandCode::CREATE_CYCLIC_RECORD_FILE, payload.as_slice())?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-    /// Creates a backup data file in the selected application.
-    pub fn create_backup_data_file(
-        &mut self,
-        file_id: FileId,
-        communication_mode: CommunicationMode,
-        access_rights: AccessRights,
-        size: U24,
-    ) -> Result<(), Error> {
-        let payload = create_data_file_payload(file_id, communication_mode, access_rights, size)?;
-        let command = Command::new(CommandCode::CREATE_BACKUP_DATA_FILE, payload.as_slice())?;
-        self.execute_management_command(&command)
-    }
-
-    /// Creates a value file in the selected application.
-    #[allow(clippy::too_many_arguments)]
-    pub fn create_value_file(
-        &mut self,
-        file_id: FileId,
-        communication_mode: CommunicationMode,
-        access_rights: AccessRights,
-        lower_limit: i32,
-        upper_limit: i32,
-        initial_value: i32,
-        limited_credit_enabled: bool,
-    ) -> Result<(), Error> {
-        let ar = access_rights.to_bytes();
-        let mut payload: Vec<u8, 17> = Vec::new();
-        payload
-            .push(file_id.as_byte())
-            .map_err(|_| Error::CommandTooLong)?;
-        payload
-            .push(u8::from(communication_mode))
-            .map_err(|_| Error::CommandTooLong)?;
-        payload
-            .extend_from_slice(&ar)
-            .map_err(|_| Error::CommandTooLong)?;
-        payload
-            .extend_from_slice(&lower_limit.to_le_bytes())
-            .map_err(|_| Error::CommandTooLong)?;
-        payload
-            .extend_from_slice(&upper_limit.to_le_bytes())
-            .map_err(|_| Error::CommandTooLong)?;
-        payload
-            .extend_from_slice(&initial_value.to_le_bytes())
-            .map_err(|_| Error::CommandTooLong)?;
-        payload
-            .push(u8::from(limited_credit_enabled))
-            .map_err(|_| Error::CommandTooLong)?;
-        let command = Command::new(CommandCode::CREATE_VALUE_FILE, payload.as_slice())?;
-        self.execute_management_command(&command)
-    }
+        /// Deletes a file from the selected application.
+        pub $($async)? fn delete_file(&mut self, file_id: FileId) -> Result<(), Error> {
+            let command = Command::new(CommandCode::DELETE_FILE, &[file_id.as_byte()])?;
+            self.execute_management_command(&command)$($await)*
+        }
 
-    /// Creates a linear record file in the selected application.
-    pub fn create_linear_record_file(
-        &mut self,
-        file_id: FileId,
-        communication_mode: CommunicationMode,
-        access_rights: AccessRights,
-        record_size: U24,
-        max_records: U24,
-    ) -> Result<(), Error> {
-        let payload = create_record_file_payload(
-            file_id,
-            communication_mode,
-            access_rights,
-            record_size,
-            max_records,
-        )?;
-        let command = Command::new(CommandCode::CREATE_LINEAR_RECORD_FILE, payload.as_slice())?;
-        self.execute_management_command(&command)
-    }
-
-    /// Creates a cyclic record file in the selected application.
-    pub fn create_cyclic_record_file(
-        &mut self,
-        file_id: FileId,
-        communication_mode: CommunicationMode,
-        access_rights: AccessRights,
-        record_size: U24,
-        max_records: U24,
-    ) -> Result<(), Error> {
-        let payload = create_record_file_payload(
-            file_id,
-            communication_mode,
-            access_rights,
-            record_size,
-            max_records,
-        )?;
-        let command = Command::new(CommandCode::CREATE_CYCLIC_RECORD_FILE, payload.as_slice())?;
-        self.execute_management_command(&command)
-    }
-
-    /// Deletes a file from the selected application.
-    pub fn delete_file(&mut self, file_id: FileId) -> Result<(), Error> {
-        let command = Command::new(CommandCode::DELETE_FILE, &[file_id.as_byte()])?;
-        self.execute_management_command(&command)
-    }
-
-    /// Sends a management command (create/delete application or file).
-    ///
-    /// When authenticated the card returns an 8-byte response MAC that must be
-    /// verified and the session CMAC state updated. When unauthenticated the
-    /// card returns only a status byte with no MAC.
-    fn execute_management_command(&mut self, command: &Command) -> Result<(), Error> {
-        match self.session {
-            Session::Authenticated(mut session) => {
-                session.update_command_cmac(command.code(), command.data())?;
-                let response = self.executor.exchange_one(command)?;
-                if response.status() != Status::OperationOk {
-                    return Err(Error::Status(response.status()));
+        /// Sends a management command (create/delete application or file).
+        ///
+        /// When authenticated the card returns an 8-byte response MAC that must be
+        /// verified and the session CMAC state updated. When unauthenticated the
+        /// card returns only a status byte with no MAC.
+        $($async)? fn execute_management_command(
+            &mut self,
+            command: &Command,
+        ) -> Result<(), Error> {
+            match self.session.clone() {
+                Session::Authenticated(mut session) => {
+                    session.update_command_cmac(command.code(), command.data())?;
+                    let response = self.executor.exchange_one(command)$($await)*?;
+                    if response.status() != Status::OperationOk {
+                        return Err(Error::Status(response.status()));
+                    }
+                    verify_response_mac(&mut session, Status::OperationOk, response.data())?;
+                    self.session = Session::Authenticated(session);
+                }
+                Session::AuthenticatedEv2(session) => {
+                    let mut body: Vec<u8, 0> = Vec::new();
+                    self.exchange_ev2(
+                        session,
+                        command.code(),
+                        command.data(),
+                        &mut body,
+                    )$($await)*?;
+                }
+                Session::Unauthenticated => {
+                    let mut data: Vec<u8, 0> = Vec::new();
+                    self.executor.execute(command, &mut data)$($await)*?;
                 }
-                verify_response_mac(&mut session, Status::OperationOk, response.data())?;
-                self.session = Session::Authenticated(session);
             }
-            Session::Unauthenticated => {
-                let mut data: Vec<u8, 0> = Vec::new();
-                self.executor.execute(command, &mut data)?;
+            Ok(())
+        }
+
+        /// Sends a query command that carries no file data.
+        ///
+        /// While authenticated the card appends a MAC to every response, so queries
+        /// take the MAC path to strip it and keep the chaining state in sync.
+        pub(crate) $($async)? fn execute_query<const N: usize>(
+            &mut self,
+            command: &Command,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            match self.session.clone() {
+                Session::Unauthenticated => self.executor.execute(command, data)$($await)*,
+                Session::Authenticated(_) | Session::AuthenticatedEv2(_) => {
+                    self.execute_maced_read(command, data)$($await)*
+                }
             }
         }
-        Ok(())
-    }
 
-    fn execute_single_maced<const N: usize>(
-        &mut self,
-        command: &Command,
-        data: &mut Vec<u8, N>,
-    ) -> Result<(), Error> {
-        let Session::Authenticated(mut session) = self.session else {
-            return Err(Error::MissingAuthentication);
-        };
+        $($async)? fn execute_single_maced<const N: usize>(
+            &mut self,
+            command: &Command,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            let mut session = match self.session.clone() {
+                Session::Authenticated(session) => session,
+                Session::AuthenticatedEv2(session) => {
+                    self.exchange_ev2(session, command.code(), command.data(), data)$($await)*?;
+                    return Ok(());
+                }
+                Session::Unauthenticated => return Err(Error::MissingAuthentication),
+            };
+
+            session.update_command_cmac(command.code(), command.data())?;
+            let response = self.executor.exchange_one(command)$($await)*?;
+            if response.status() != Status::OperationOk {
+                return Err(Error::Status(response.status()));
+            }
+
+            let body = verify_response_mac(&mut session, response.status(), response.data())?;
+            data.clear();
+            data.extend_from_slice(body)
+                .map_err(|_| Error::ResponseTooLong)?;
 
-        session.update_command_cmac(command.code(), command.data())?;
-        let response = self.executor.exchange_one(command)?;
-        if response.status() != Status::OperationOk {
-            return Err(Error::Status(response.status()));
+            self.session = Session::Authenticated(session);
+            Ok(())
         }
 
-        let body = verify_response_mac(&mut session, response.status(), response.data())?;
-        data.clear();
-        data.extend_from_slice(body)
-            .map_err(|_| Error::ResponseTooLong)?;
+        /// Sends a read command and unwraps the response according to `communication_mode`.
+        ///
+        /// Plain reads made under an EV1 session are MAC-verified, since the card still
+        /// appends a response MAC; EV2 plain responses carry no MAC. `length` is only
+        /// used for enciphered responses.
+        pub(crate) $($async)? fn execute_read_command<const N: usize>(
+            &mut self,
+            command: &Command,
+            communication_mode: CommunicationMode,
+            length: usize,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            match (communication_mode, self.session.clone()) {
+                (CommunicationMode::Plain, Session::Unauthenticated) => {
+                    self.executor.execute(command, data)$($await)*
+                }
+                (CommunicationMode::Plain, Session::AuthenticatedEv2(session)) => {
+                    self.execute_ev2_plain(session, command, data)$($await)*
+                }
+                (CommunicationMode::Plain, Session::Authenticated(_))
+                | (CommunicationMode::Maced, _) => self.execute_maced_read(command, data)$($await)*,
+                (CommunicationMode::Enciphered, _) => {
+                    self.execute_enciphered_read(command, length, data)$($await)*
+                }
+            }
+        }
 
-        self.session = Session::Authenticated(session);
-        Ok(())
-    }
-}
+        $($async)? fn execute_maced_read<const N: usize>(
+            &mut self,
+            command: &Command,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            let mut session = match self.session.clone() {
+                Session::Authenticated(session) => session,
+                Session::AuthenticatedEv2(session) => {
+                    self.exchange_ev2(session, command.code(), command.data(), data)$($await)*?;
+                    return Ok(());
+                }
+                Session::Unauthenticated => return Err(Error::MissingAuthentication),
+            };
 
-fn create_data_file_payload(
-    file_id: FileId,
-    communication_mode: CommunicationMode,
-    access_rights: AccessRights,
-    size: U24,
-) -> Result<Vec<u8, 7>, Error> {
-    let ar = access_rights.to_bytes();
-    let mut payload: Vec<u8, 7> = Vec::new();
-    payload
-        .push(file_id.as_byte())
-        .map_err(|_| Error::CommandTooLong)?;
-    payload
-        .push(u8::from(communication_mode))
-        .map_err(|_| Error::CommandTooLong)?;
-    payload
-        .extend_from_slice(&ar)
-        .map_err(|_| Error::CommandTooLong)?;
-    payload
-        .extend_from_slice(&size.to_le_bytes())
-        .map_err(|_| Error::CommandTooLong)?;
-    Ok(payload)
-}
+            session.update_command_cmac(command.code(), command.data())?;
 
-fn create_record_file_payload(
-    file_id: FileId,
-    communication_mode: CommunicationMode,
-    access_rights: AccessRights,
-    record_size: U24,
-    max_records: U24,
-) -> Result<Vec<u8, 10>, Error> {
-    let ar = access_rights.to_bytes();
-    let mut payload: Vec<u8, 10> = Vec::new();
-    payload
-        .push(file_id.as_byte())
-        .map_err(|_| Error::CommandTooLong)?;
-    payload
-        .push(u8::from(communication_mode))
-        .map_err(|_| Error::CommandTooLong)?;
-    payload
-        .extend_from_slice(&ar)
-        .map_err(|_| Error::CommandTooLong)?;
-    payload
-        .extend_from_slice(&record_size.to_le_bytes())
-        .map_err(|_| Error::CommandTooLong)?;
-    payload
-        .extend_from_slice(&max_records.to_le_bytes())
-        .map_err(|_| Error::CommandTooLong)?;
-    Ok(payload)
-}
+            let mut raw: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            self.executor.execute(command, &mut raw)$($await)*?;
 
-fn write_data_command_header(
-    file_id: FileId,
-    offset: U24,
-    data: &[u8],
-) -> Result<Vec<u8, 7>, Error> {
-    let length = u32::try_from(data.len())
-        .ok()
-        .and_then(U24::new)
-        .ok_or(Error::CommandTooLong)?;
-    read_data_command_data(file_id, offset, length)
-}
+            let body = verify_response_mac(&mut session, Status::OperationOk, raw.as_slice())?;
+            data.clear();
+            data.extend_from_slice(body)
+                .map_err(|_| Error::ResponseTooLong)?;
 
-fn read_data_command_data(file_id: FileId, offset: U24, length: U24) -> Result<Vec<u8, 7>, Error> {
-    let mut command_data: Vec<u8, 7> = Vec::new();
-    command_data
-        .push(file_id.as_byte())
-        .map_err(|_| Error::CommandTooLong)?;
-    command_data
-        .extend_from_slice(&offset.to_le_bytes())
-        .map_err(|_| Error::CommandTooLong)?;
-    command_data
-        .extend_from_slice(&length.to_le_bytes())
-        .map_err(|_| Error::CommandTooLong)?;
-    Ok(command_data)
+            self.session = Session::Authenticated(session);
+            Ok(())
+        }
+
+        /// Sends a command whose `header` travels in the clear and whose `data` is
+        /// protected according to `communication_mode`.
+        pub(crate) $($async)? fn execute_write_command(
+            &mut self,
+            code: CommandCode,
+            communication_mode: CommunicationMode,
+            header: &[u8],
+            data: &[u8],
+        ) -> Result<(), Error> {
+            match communication_mode {
+                CommunicationMode::Plain => {
+                    let mut cmd_data: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+                    cmd_data
+                        .extend_from_slice(header)
+                        .map_err(|_| Error::CommandTooLong)?;
+                    cmd_data
+                        .extend_from_slice(data)
+                        .map_err(|_| Error::CommandTooLong)?;
+                    let command = Command::new(code, cmd_data.as_slice())?;
+                    if let Session::AuthenticatedEv2(session) = self.session.clone() {
+                        let mut body: Vec<u8, 0> = Vec::new();
+                        self.execute_ev2_plain(session, &command, &mut body)$($await)*
+                    } else {
+                        self.execute_management_command(&command)$($await)*
+                    }
+                }
+                CommunicationMode::Maced => self.execute_maced_write(code, header, data)$($await)*,
+                CommunicationMode::Enciphered => {
+                    self.execute_enciphered_write(code, header, data)$($await)*
+                }
+            }
+        }
+
+        $($async)? fn execute_maced_write(
+            &mut self,
+            code: CommandCode,
+            header: &[u8],
+            data: &[u8],
+        ) -> Result<(), Error> {
+            let mut session = match self.session.clone() {
+                Session::Authenticated(session) => session,
+                Session::AuthenticatedEv2(session) => {
+                    let mut cmd_data: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+                    cmd_data
+                        .extend_from_slice(header)
+                        .map_err(|_| Error::CommandTooLong)?;
+                    cmd_data
+                        .extend_from_slice(data)
+                        .map_err(|_| Error::CommandTooLong)?;
+                    let mut body: Vec<u8, 0> = Vec::new();
+                    self.exchange_ev2(session, code, cmd_data.as_slice(), &mut body)$($await)*?;
+                    return Ok(());
+                }
+                Session::Unauthenticated => return Err(Error::MissingAuthentication),
+            };
+
+            // CMAC covers [cmd_code || header || data].
+            let mut cmac_payload: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            cmac_payload
+                .extend_from_slice(header)
+                .map_err(|_| Error::CommandTooLong)?;
+            cmac_payload
+                .extend_from_slice(data)
+                .map_err(|_| Error::CommandTooLong)?;
+            let mac = session.update_command_cmac(code, cmac_payload.as_slice())?;
+
+            // Build command: [header || data || MAC].
+            cmac_payload
+                .extend_from_slice(&mac.as_bytes())
+                .map_err(|_| Error::CommandTooLong)?;
+            let command = Command::new(code, cmac_payload.as_slice())?;
+            let response = self.executor.exchange_one(&command)$($await)*?;
+            if response.status() != Status::OperationOk {
+                return Err(Error::Status(response.status()));
+            }
+
+            verify_response_mac(&mut session, Status::OperationOk, response.data())?;
+            self.session = Session::Authenticated(session);
+            Ok(())
+        }
+
+        $($async)? fn execute_enciphered_write(
+            &mut self,
+            code: CommandCode,
+            header: &[u8],
+            data: &[u8],
+        ) -> Result<(), Error> {
+            self.execute_enciphered_write_with_terminator(code, header, data, false)$($await)*
+        }
+
+        /// Enciphered write that optionally marks the end of the CRC with `0x80` before padding.
+        ///
+        /// `SetConfiguration` with a user-defined ATS needs the terminator because the
+        /// ATS length is otherwise ambiguous. EV2 padding always starts with `0x80`.
+        $($async)? fn execute_enciphered_write_with_terminator(
+            &mut self,
+            code: CommandCode,
+            header: &[u8],
+            data: &[u8],
+            crc_terminator: bool,
+        ) -> Result<(), Error> {
+            let mut session = match self.session.clone() {
+                Session::Authenticated(session) => session,
+                Session::AuthenticatedEv2(session) => {
+                    return self.execute_ev2_enciphered_write(session, code, header, data)$($await)*;
+                }
+                Session::Unauthenticated => return Err(Error::MissingAuthentication),
+            };
+
+            // IV = current chaining state (no command CMAC update for enciphered writes).
+            // CRC covers the full command payload: [cmd_code || header || data].
+            // Plaintext: [data || CRC(cmd_code||header||data)] zero-padded to block boundary
+            // (ISO 9797-1 method 1 — no 0x80 terminator).
+            let mut crc_input: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            crc_input
+                .push(code.as_byte())
+                .map_err(|_| Error::CommandTooLong)?;
+            crc_input
+                .extend_from_slice(header)
+                .map_err(|_| Error::CommandTooLong)?;
+            crc_input
+                .extend_from_slice(data)
+                .map_err(|_| Error::CommandTooLong)?;
+            let crc_size = session.encrypted_command_crc_size();
+
+            let block_size = session.block_size();
+            let mut plaintext: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            plaintext
+                .extend_from_slice(data)
+                .map_err(|_| Error::CommandTooLong)?;
+            extend_desfire_crc(&mut plaintext, crc_input.as_slice(), crc_size)?;
+            if crc_terminator {
+                plaintext.push(0x80).map_err(|_| Error::CommandTooLong)?;
+            }
+            while !plaintext.len().is_multiple_of(block_size) {
+                plaintext.push(0x00).map_err(|_| Error::CommandTooLong)?;
+            }
+
+            // Encrypts in place using current chaining IV and updates chaining state to last
+            // ciphertext block.
+            session.cbc_encrypt_in_place(plaintext.as_mut_slice())?;
+
+            // Build command: [header || ciphertext].
+            let mut cmd_data: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            cmd_data
+                .extend_from_slice(header)
+                .map_err(|_| Error::CommandTooLong)?;
+            cmd_data
+                .extend_from_slice(plaintext.as_slice())
+                .map_err(|_| Error::CommandTooLong)?;
+            let command = Command::new(code, cmd_data.as_slice())?;
+
+            let response = self.executor.exchange_one(&command)$($await)*?;
+            if response.status() != Status::OperationOk {
+                return Err(Error::Status(response.status()));
+            }
+
+            verify_response_mac(&mut session, Status::OperationOk, response.data())?;
+            self.session = Session::Authenticated(session);
+            Ok(())
+        }
+
+        /// Sends a command whose response is encrypted with a trailing CRC.
+        ///
+        /// A `length` of zero infers the plaintext length from the CRC and padding.
+        $($async)? fn execute_enciphered_read<const N: usize>(
+            &mut self,
+            command: &Command,
+            length: usize,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            let mut session = match self.session.clone() {
+                Session::Authenticated(session) => session,
+                Session::AuthenticatedEv2(session) => {
+                    return self.execute_ev2_enciphered_read(
+                        session,
+                        command,
+                        length,
+                        data,
+                    )$($await)*;
+                }
+                Session::Unauthenticated => return Err(Error::MissingAuthentication),
+            };
+
+            #[allow(unused_variables)]
+            // The cmac value is not used in nostd due to the logging call below
+            session.update_command_cmac(command.code(), command.data())?;
+
+            // Responses longer than one frame arrive as a single ciphertext split across
+            // additional frames.
+            let mut decrypted: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            self.executor.execute(command, &mut decrypted)$($await)*?;
+            let block_size = session.block_size();
+            if decrypted.len() < block_size || !decrypted.len().is_multiple_of(block_size) {
+                return Err(Error::InvalidResponseLength);
+            }
+
+            // Decrypts in place using current chaining IV and updates chaining state to last
+            // ciphertext block.
+            session.cbc_decrypt_in_place(decrypted.as_mut_slice())?;
+
+            let crc_size = session.encrypted_read_crc_size();
+            let plaintext_len = encrypted_read_plaintext_len(
+                decrypted.as_slice(),
+                length,
+                Status::OperationOk,
+                crc_size,
+            )?;
+
+            data.clear();
+            data.extend_from_slice(&decrypted.as_slice()[..plaintext_len])
+                .map_err(|_| Error::ResponseTooLong)?;
+
+            self.session = Session::Authenticated(session);
+            Ok(())
+        }
+        /// Key number of the active authentication, for either secure-messaging flavour.
+        fn authenticated_key_number(&self) -> Result<KeyNumber, Error> {
+            self.session.key_number().ok_or(Error::MissingAuthentication)
+        }
+
+        /// Sends one EV2 `CommMode.MAC` exchange and returns the MAC-verified response body.
+        ///
+        /// `data` is the command payload after any encryption; the truncated command MAC
+        /// is appended here and the command counter advances once the card has answered.
+        $($async)? fn exchange_ev2<const N: usize>(
+            &mut self,
+            mut session: Ev2Session,
+            code: CommandCode,
+            data: &[u8],
+            body: &mut Vec<u8, N>,
+        ) -> Result<Ev2Session, Error> {
+            let mac = session.command_mac(code, data)?;
+            let mut cmd_data: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            cmd_data
+                .extend_from_slice(data)
+                .map_err(|_| Error::CommandTooLong)?;
+            cmd_data
+                .extend_from_slice(&mac.as_bytes())
+                .map_err(|_| Error::CommandTooLong)?;
+            let command = Command::new(code, cmd_data.as_slice())?;
+
+            let mut raw: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            self.executor.execute(&command, &mut raw)$($await)*?;
+            session.increment_command_counter()?;
+
+            let verified = verify_ev2_response_mac(&session, raw.as_slice())?;
+            body.clear();
+            body.extend_from_slice(verified)
+                .map_err(|_| Error::ResponseTooLong)?;
+
+            self.session = Session::AuthenticatedEv2(session.clone());
+            Ok(session)
+        }
+
+        /// Sends an EV2 `CommMode.Plain` command; only the command counter changes.
+        $($async)? fn execute_ev2_plain<const N: usize>(
+            &mut self,
+            mut session: Ev2Session,
+            command: &Command,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            self.executor.execute(command, data)$($await)*?;
+            session.increment_command_counter()?;
+            self.session = Session::AuthenticatedEv2(session);
+            Ok(())
+        }
+
+        /// EV2 `CommMode.Full` write: `[header || E(data || padding) || MACt]`.
+        $($async)? fn execute_ev2_enciphered_write(
+            &mut self,
+            session: Ev2Session,
+            code: CommandCode,
+            header: &[u8],
+            data: &[u8],
+        ) -> Result<(), Error> {
+            let mut ciphertext: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            ciphertext
+                .extend_from_slice(data)
+                .map_err(|_| Error::CommandTooLong)?;
+            session.encrypt_command_data(&mut ciphertext)?;
+
+            let mut cmd_data: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            cmd_data
+                .extend_from_slice(header)
+                .map_err(|_| Error::CommandTooLong)?;
+            cmd_data
+                .extend_from_slice(ciphertext.as_slice())
+                .map_err(|_| Error::CommandTooLong)?;
+
+            let mut body: Vec<u8, 0> = Vec::new();
+            self.exchange_ev2(session, code, cmd_data.as_slice(), &mut body)$($await)*?;
+            Ok(())
+        }
+
+        /// EV2 `CommMode.Full` read: the response is `[E(data || padding) || MACt]`.
+        ///
+        /// A `length` of zero accepts whatever plaintext length the padding marks.
+        $($async)? fn execute_ev2_enciphered_read<const N: usize>(
+            &mut self,
+            session: Ev2Session,
+            command: &Command,
+            length: usize,
+            data: &mut Vec<u8, N>,
+        ) -> Result<(), Error> {
+            let mut decrypted: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+            let session = self.exchange_ev2(
+                session,
+                command.code(),
+                command.data(),
+                &mut decrypted,
+            )$($await)*?;
+            let plaintext_len = session.decrypt_response_data(decrypted.as_mut_slice())?;
+            if length != 0 && plaintext_len != length {
+                return Err(Error::InvalidResponseLength);
+            }
+
+            data.clear();
+            data.extend_from_slice(&decrypted.as_slice()[..plaintext_len])
+                .map_err(|_| Error::ResponseTooLong)
+        }
+    };
+}
+
+impl<T, C> Desfire<T, C>
+where
+    T: Transport,
+    C: FrameCodec,
+{
+    /// Creates a client from a byte transport and frame codec.
+    pub const fn new(transport: T, codec: C) -> Self {
+        Self {
+            executor: Executor::new(transport, codec),
+            session: Session::Unauthenticated,
+        }
+    }
+
+    /// Creates a client from an existing executor.
+    pub const fn from_executor(executor: Executor<T, C>) -> Self {
+        Self {
+            executor,
+            session: Session::Unauthenticated,
+        }
+    }
+
+    /// Returns a shared reference to the command executor.
+    pub const fn executor(&self) -> &Executor<T, C> {
+        &self.executor
+    }
+
+    /// Returns a mutable reference to the command executor.
+    pub fn executor_mut(&mut self) -> &mut Executor<T, C> {
+        &mut self.executor
+    }
+
+    /// Consumes the client and returns the command executor.
+    pub fn into_executor(self) -> Executor<T, C> {
+        self.executor
+    }
+
+    /// Starts a transaction on the selected application.
+    ///
+    /// Nothing is sent to the card until the first write through the guard.
+    pub fn begin_transaction(&mut self) -> Transaction<'_, T, C> {
+        Transaction::new(self)
+    }
+
+    /// Opens a file of the selected application, fetching its settings once.
+    pub fn open_file(&mut self, file_id: FileId) -> Result<FileHandle<'_, T, C>, Error> {
+        let settings = self.get_file_settings(file_id)?;
+        Ok(FileHandle::new(self, file_id, settings))
+    }
+
+    /// Opens a file whose settings are already known, such as when
+    /// `GetFileSettings` needs a key the session does not hold.
+    pub fn open_file_with_settings(
+        &mut self,
+        file_id: FileId,
+        settings: FileSettings,
+    ) -> FileHandle<'_, T, C> {
+        FileHandle::new(self, file_id, settings)
+    }
+    client_methods!([], []);
+}
+
+impl<T, C> AsyncDesfire<T, C>
+where
+    T: AsyncTransport,
+    C: FrameCodec,
+{
+    /// Creates a client from a byte transport and frame codec.
+    pub const fn new(transport: T, codec: C) -> Self {
+        Self {
+            executor: AsyncExecutor::new(transport, codec),
+            session: Session::Unauthenticated,
+        }
+    }
+
+    /// Creates a client from an existing executor.
+    pub const fn from_executor(executor: AsyncExecutor<T, C>) -> Self {
+        Self {
+            executor,
+            session: Session::Unauthenticated,
+        }
+    }
+
+    /// Returns a shared reference to the command executor.
+    pub const fn executor(&self) -> &AsyncExecutor<T, C> {
+        &self.executor
+    }
+
+    /// Returns a mutable reference to the command executor.
+    pub fn executor_mut(&mut self) -> &mut AsyncExecutor<T, C> {
+        &mut self.executor
+    }
+
+    /// Consumes the client and returns the command executor.
+    pub fn into_executor(self) -> AsyncExecutor<T, C> {
+        self.executor
+    }
+    client_methods!([async], [.await]);
+}
+
+fn create_data_file_payload(
+    file_id: FileId,
+    iso_file_id: Option<IsoFileId>,
+    communication_mode: CommunicationMode,
+    access_rights: AccessRights,
+    size: U24,
+) -> Result<Vec<u8, 9>, Error> {
+    let ar = access_rights.to_bytes();
+    let mut payload: Vec<u8, 9> = Vec::new();
+    payload
+        .push(file_id.as_byte())
+        .map_err(|_| Error::CommandTooLong)?;
+    if let Some(iso_file_id) = iso_file_id {
+        payload
+            .extend_from_slice(&iso_file_id.to_le_bytes())
+            .map_err(|_| Error::CommandTooLong)?;
+    }
+    payload
+        .push(u8::from(communication_mode))
+        .map_err(|_| Error::CommandTooLong)?;
+    payload
+        .extend_from_slice(&ar)
+        .map_err(|_| Error::CommandTooLong)?;
+    payload
+        .extend_from_slice(&size.to_le_bytes())
+        .map_err(|_| Error::CommandTooLong)?;
+    Ok(payload)
+}
+
+fn create_record_file_payload(
+    file_id: FileId,
+    iso_file_id: Option<IsoFileId>,
+    communication_mode: CommunicationMode,
+    access_rights: AccessRights,
+    record_size: U24,
+    max_records: U24,
+) -> Result<Vec<u8, 12>, Error> {
+    let ar = access_rights.to_bytes();
+    let mut payload: Vec<u8, 12> = Vec::new();
+    payload
+        .push(file_id.as_byte())
+        .map_err(|_| Error::CommandTooLong)?;
+    if let Some(iso_file_id) = iso_file_id {
+        payload
+            .extend_from_slice(&iso_file_id.to_le_bytes())
+            .map_err(|_| Error::CommandTooLong)?;
+    }
+    payload
+        .push(u8::from(communication_mode))
+        .map_err(|_| Error::CommandTooLong)?;
+    payload
+        .extend_from_slice(&ar)
+        .map_err(|_| Error::CommandTooLong)?;
+    payload
+        .extend_from_slice(&record_size.to_le_bytes())
+        .map_err(|_| Error::CommandTooLong)?;
+    payload
+        .extend_from_slice(&max_records.to_le_bytes())
+        .map_err(|_| Error::CommandTooLong)?;
+    Ok(payload)
+}
+
+pub(crate) fn write_data_command_header(
+    file_id: FileId,
+    offset: U24,
+    data: &[u8],
+) -> Result<Vec<u8, 7>, Error> {
+    let length = u32::try_from(data.len())
+        .ok()
+        .and_then(U24::new)
+        .ok_or(Error::CommandTooLong)?;
+    read_data_command_data(file_id, offset, length)
+}
+
+/// Encodes a `Credit`, `Debit` or `LimitedCredit` amount.
+///
+/// The card takes a signed value, so a negative credit would really be a debit
+/// that some PICC versions accept; only positive amounts are sent.
+pub(crate) fn value_amount(amount: i32) -> Result<[u8; 4], Error> {
+    if amount > 0 {
+        Ok(amount.to_le_bytes())
+    } else {
+        Err(Error::InvalidValueAmount(amount))
+    }
+}
+
+/// Exclusive end offset of a streamed transfer; every chunk offset must fit in a `U24`.
+pub(crate) fn stream_end(offset: U24, length: u32) -> Result<u32, Error> {
+    offset
+        .as_u32()
+        .checked_add(length)
+        .filter(|&end| end <= U24::MAX + 1)
+        .ok_or(Error::CommandTooLong)
+}
+
+pub(crate) fn read_data_command_data(
+    file_id: FileId,
+    offset: U24,
+    length: U24,
+) -> Result<Vec<u8, 7>, Error> {
+    let mut command_data: Vec<u8, 7> = Vec::new();
+    command_data
+        .push(file_id.as_byte())
+        .map_err(|_| Error::CommandTooLong)?;
+    command_data
+        .extend_from_slice(&offset.to_le_bytes())
+        .map_err(|_| Error::CommandTooLong)?;
+    command_data
+        .extend_from_slice(&length.to_le_bytes())
+        .map_err(|_| Error::CommandTooLong)?;
+    Ok(command_data)
+}
+
+/// The current key of a `ChangeKey` slot, needed only when it is not the
+/// authenticated key.
+fn required_old_key<K>(old_key: Option<&K>, changing_auth_key: bool) -> Result<Option<&K>, Error> {
+    if changing_auth_key {
+        Ok(None)
+    } else {
+        old_key.ok_or(Error::MissingOldKey).map(Some)
+    }
+}
+
+/// `ChangeKey` command data: the clear header, then the cryptogram.
+fn change_key_data(
+    header: &[u8],
+    cryptogram: &ChangeKeyCryptogram,
+    block_size: usize,
+) -> Result<Vec<u8, MAX_FRAME_SIZE>, Error> {
+    let ciphertext = cryptogram.as_bytes();
+    if ciphertext.is_empty() || !ciphertext.len().is_multiple_of(block_size) {
+        return Err(Error::CryptoProvider);
+    }
+    let mut cmd_data = Vec::new();
+    cmd_data
+        .extend_from_slice(header)
+        .map_err(|_| Error::CommandTooLong)?;
+    cmd_data
+        .extend_from_slice(ciphertext)
+        .map_err(|_| Error::CommandTooLong)?;
+    Ok(cmd_data)
 }
 
 fn verify_response_mac<'a>(
@@ -1332,7 +2732,21 @@ fn verify_response_mac<'a>(
 
     let (body, received) = data.split_at(data.len() - mac_len);
     let expected = session.update_response_cmac(status, body)?;
-    if expected.as_bytes()[..mac_len] != *received {
+    if !constant_time_eq(&expected.as_bytes()[..mac_len], received) {
+        return Err(Error::InvalidMac);
+    }
+
+    Ok(body)
+}
+
+fn verify_ev2_response_mac<'a>(session: &Ev2Session, data: &'a [u8]) -> Result<&'a [u8], Error> {
+    if data.len() < 8 {
+        return Err(Error::InvalidResponseLength);
+    }
+
+    let (body, received) = data.split_at(data.len() - 8);
+    let expected = session.response_mac(Status::OperationOk, body)?;
+    if !constant_time_eq(&expected.as_bytes(), received) {
         return Err(Error::InvalidMac);
     }
 
@@ -1416,8 +2830,8 @@ fn extend_desfire_crc<const N: usize>(
 
 fn desfire_crc_matches(data: &[u8], expected: &[u8]) -> bool {
     match expected.len() {
-        2 => desfire_crc16(data) == expected,
-        4 => desfire_crc32(data) == expected,
+        2 => constant_time_eq(&desfire_crc16(data), expected),
+        4 => constant_time_eq(&desfire_crc32(data), expected),
         _ => false,
     }
 }
@@ -1463,13 +2877,33 @@ fn parse_file_ids<const N: usize>(data: &[u8], file_ids: &mut Vec<FileId, N>) ->
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
+fn parse_iso_file_ids<const N: usize>(
+    data: &[u8],
+    iso_file_ids: &mut Vec<IsoFileId, N>,
+) -> Result<(), Error> {
+    if !data.len().is_multiple_of(2) {
+        return Err(Error::InvalidResponseLength);
+    }
+
+    iso_file_ids.clear();
+    for chunk in data.chunks(2) {
+        iso_file_ids
+            .push(IsoFileId::new(u16::from_le_bytes([chunk[0], chunk[1]]))?)
+            .map_err(|_| Error::ResponseTooLong)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
     use heapless::Vec;
 
     use crate::mifare::desfire::{
         application::ApplicationId,
         client::Desfire,
+        command::CommandCode,
+        configuration::{Ats, DefaultKey, PiccConfiguration},
         crypto::{
             aes_cbc_encrypt_in_place, AesSessionKey, DesSessionKey, RndA, RndA8, RndB,
             ThreeKey3DesSessionKey, TwoKey3DesSessionKey,
@@ -1477,8 +2911,10 @@ mod tests {
         error::Error,
         file::{AccessCondition, AccessRights, CommunicationMode, FileId, FileSettingsDetails},
         framing::{NativeFraming, WrappedFraming},
-        key::{ApplicationKeyType, KeyNumber, KeySettings},
-        session::{AuthenticatedSession, Session, SessionKey},
+        iso::{DfName, IsoFileId, IsoSelect},
+        key::{ApplicationKeyType, Key, KeyNumber, KeySetNumber, KeySetOptions, KeySettings},
+        provider::SoftwareCrypto,
+        session::{AuthenticatedSession, Ev2Session, Session, SessionKey},
         transport::{Frame, Transport},
         types::U24,
     };
@@ -1680,7 +3116,12 @@ mod tests {
         let mut desfire = Desfire::new(transport, NativeFraming);
 
         let session = desfire
-            .authenticate_aes_with_rnd_a(KeyNumber::new(1).unwrap(), &key, rnd_a)
+            .authenticate_aes_with_rnd_a(
+                KeyNumber::new(1).unwrap(),
+                &mut SoftwareCrypto,
+                &Key::Aes128(key),
+                rnd_a,
+            )
             .unwrap();
 
         assert_eq!(session.key_number(), KeyNumber::new(1).unwrap());
@@ -1688,14 +3129,14 @@ mod tests {
             session.session_key(),
             SessionKey::Aes(AesSessionKey::derive(rnd_a, rnd_b))
         );
-        assert_eq!(desfire.session(), Session::Authenticated(session));
-        assert_eq!(desfire.authenticated_session(), Some(session));
+        assert_eq!(*desfire.session(), Session::Authenticated(session.clone()));
+        assert_eq!(desfire.authenticated_session(), Some(&session));
 
         desfire
             .select_application(crate::mifare::desfire::ApplicationId::new(0x12_34_56).unwrap())
             .unwrap();
 
-        assert_eq!(desfire.session(), Session::Unauthenticated);
+        assert_eq!(*desfire.session(), Session::Unauthenticated);
         assert_eq!(desfire.authenticated_session(), None);
     }
 
@@ -1720,7 +3161,12 @@ mod tests {
         let mut desfire = Desfire::new(transport, NativeFraming);
 
         let session = desfire
-            .authenticate_des_with_rnd_a(KeyNumber::new(0).unwrap(), &key, rnd_a)
+            .authenticate_des_with_rnd_a(
+                KeyNumber::new(0).unwrap(),
+                &mut SoftwareCrypto,
+                &Key::Des(key),
+                rnd_a,
+            )
             .unwrap();
 
         assert_eq!(
@@ -1729,7 +3175,7 @@ mod tests {
                 0xA0, 0xA1, 0xA2, 0xA3, 0x00, 0x11, 0x22, 0x33
             ]))
         );
-        assert_eq!(desfire.session(), Session::Authenticated(session));
+        assert_eq!(*desfire.session(), Session::Authenticated(session.clone()));
         assert_eq!(desfire.executor().transport().index, 2);
     }
 
@@ -1757,7 +3203,12 @@ mod tests {
         let mut desfire = Desfire::new(transport, NativeFraming);
 
         let session = desfire
-            .authenticate_2tdea_with_rnd_a(KeyNumber::new(1).unwrap(), &key, rnd_a)
+            .authenticate_2tdea_with_rnd_a(
+                KeyNumber::new(1).unwrap(),
+                &mut SoftwareCrypto,
+                &Key::TwoKey3Des(key),
+                rnd_a,
+            )
             .unwrap();
 
         assert_eq!(
@@ -1767,7 +3218,7 @@ mod tests {
                 0x66, 0x77
             ]))
         );
-        assert_eq!(desfire.session(), Session::Authenticated(session));
+        assert_eq!(*desfire.session(), Session::Authenticated(session.clone()));
         assert_eq!(desfire.executor().transport().index, 2);
     }
 
@@ -1791,7 +3242,8 @@ mod tests {
         let session = desfire
             .authenticate_2tdea_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::TwoKey3Des([0u8; 16]),
                 RndA8::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]),
             )
             .unwrap();
@@ -1804,7 +3256,7 @@ mod tests {
                 0xAC, 0xC4
             ]))
         );
-        assert_eq!(desfire.session(), Session::Authenticated(session));
+        assert_eq!(*desfire.session(), Session::Authenticated(session.clone()));
         assert_eq!(desfire.executor().transport().index, 2);
     }
 
@@ -1835,7 +3287,8 @@ mod tests {
         let session = desfire
             .authenticate_2tdea_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::TwoKey3Des([0u8; 16]),
                 RndA8::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]),
             )
             .unwrap();
@@ -1848,7 +3301,7 @@ mod tests {
                 0xAC, 0x4D
             ]))
         );
-        assert_eq!(desfire.session(), Session::Authenticated(session));
+        assert_eq!(*desfire.session(), Session::Authenticated(session.clone()));
         assert_eq!(desfire.executor().transport().index, 3);
     }
 
@@ -1887,7 +3340,8 @@ mod tests {
         let session = desfire
             .authenticate_2tdea_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::TwoKey3Des([0u8; 16]),
                 RndA8::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]),
             )
             .unwrap();
@@ -1948,7 +3402,8 @@ mod tests {
         let session = desfire
             .authenticate_3tdea_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 24],
+                &mut SoftwareCrypto,
+                &Key::ThreeKey3Des([0u8; 24]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -2019,7 +3474,8 @@ mod tests {
         let session = desfire
             .authenticate_2tdea_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::TwoKey3Des([0u8; 16]),
                 RndA8::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]),
             )
             .unwrap();
@@ -2106,7 +3562,8 @@ mod tests {
         let session = desfire
             .authenticate_3tdea_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 24],
+                &mut SoftwareCrypto,
+                &Key::ThreeKey3Des([0u8; 24]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -2174,7 +3631,8 @@ mod tests {
         let session = desfire
             .authenticate_2tdea_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::TwoKey3Des([0u8; 16]),
                 RndA8::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]),
             )
             .unwrap();
@@ -2225,7 +3683,8 @@ mod tests {
         let session = desfire
             .authenticate_2tdea_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::TwoKey3Des([0u8; 16]),
                 RndA8::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]),
             )
             .unwrap();
@@ -2280,7 +3739,8 @@ mod tests {
         let session = desfire
             .authenticate_3tdea_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 24],
+                &mut SoftwareCrypto,
+                &Key::ThreeKey3Des([0u8; 24]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -2339,7 +3799,8 @@ mod tests {
         let session = desfire
             .authenticate_3tdea_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 24],
+                &mut SoftwareCrypto,
+                &Key::ThreeKey3Des([0u8; 24]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -2355,7 +3816,7 @@ mod tests {
                 0x99, 0xFA, 0x13, 0x14, 0x15, 0x16, 0x62, 0x2F, 0x4A, 0x01
             ]))
         );
-        assert_eq!(desfire.session(), Session::Authenticated(session));
+        assert_eq!(*desfire.session(), Session::Authenticated(session.clone()));
         assert_eq!(desfire.executor().transport().index, 3);
     }
 
@@ -2440,7 +3901,8 @@ mod tests {
         let session = desfire
             .authenticate_aes_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -2453,7 +3915,7 @@ mod tests {
             session.session_key(),
             SessionKey::Aes(AesSessionKey::new(expected_session_key))
         );
-        assert_eq!(desfire.session(), Session::Authenticated(session));
+        assert_eq!(*desfire.session(), Session::Authenticated(session.clone()));
     }
 
     #[test]
@@ -2508,7 +3970,8 @@ mod tests {
         let session = desfire
             .authenticate_aes_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -2568,11 +4031,11 @@ mod tests {
 
         // Compute expected MAC: mirrors what read_data_maced will compute internally.
         let mut chaining = AesCmacChaining::new();
-        chaining.update(sk, &[0xBD, 0x01, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00]);
+        chaining.update(&sk, &[0xBD, 0x01, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00]);
         let mut resp_input = [0u8; 9];
         resp_input[..8].copy_from_slice(&body);
         // resp_input[8] = 0x00 = Status::OperationOk
-        let mac = chaining.update(sk, &resp_input).desfire_mac();
+        let mac = chaining.update(&sk, &resp_input).desfire_mac();
 
         // Build mock read response: body + MAC + 91 00 (wrapped OperationOk)
         let mut read_response = std::vec::Vec::new();
@@ -2623,7 +4086,8 @@ mod tests {
         desfire
             .authenticate_aes_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -2657,10 +4121,10 @@ mod tests {
         let body = [0x00u8, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07];
 
         let mut chaining = AesCmacChaining::new();
-        chaining.update(sk, &[0xBD, 0x01, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00]);
+        chaining.update(&sk, &[0xBD, 0x01, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00]);
         let mut resp_input = [0u8; 9];
         resp_input[..8].copy_from_slice(&body);
-        let mut mac = chaining.update(sk, &resp_input).desfire_mac().as_bytes();
+        let mut mac = chaining.update(&sk, &resp_input).desfire_mac().as_bytes();
         mac[0] ^= 0xFF; // corrupt MAC
 
         let mut read_response = std::vec::Vec::new();
@@ -2707,7 +4171,8 @@ mod tests {
         desfire
             .authenticate_aes_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -2781,7 +4246,8 @@ mod tests {
         let session = desfire
             .authenticate_aes_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -2880,7 +4346,8 @@ mod tests {
         let session = desfire
             .authenticate_aes_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -2977,7 +4444,8 @@ mod tests {
         let session = desfire
             .authenticate_aes_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -3075,7 +4543,8 @@ mod tests {
         let session = desfire
             .authenticate_aes_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -3160,7 +4629,8 @@ mod tests {
         let session = desfire
             .authenticate_aes_with_rnd_a(
                 KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
                 RndA::new([
                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
                     0x14, 0x15, 0x16,
@@ -3240,236 +4710,1756 @@ mod tests {
         let mut desfire = Desfire::new(transport, WrappedFraming);
 
         desfire
-            .select_application(crate::mifare::desfire::ApplicationId::new(0x22_33_44).unwrap())
-            .unwrap();
-        desfire
-            .authenticate_aes_with_rnd_a(
-                KeyNumber::new(0).unwrap(),
-                &[0u8; 16],
-                RndA::new([
-                    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
-                    0x14, 0x15, 0x16,
-                ]),
+            .select_application(crate::mifare::desfire::ApplicationId::new(0x22_33_44).unwrap())
+            .unwrap();
+        desfire
+            .authenticate_aes_with_rnd_a(
+                KeyNumber::new(0).unwrap(),
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
+                RndA::new([
+                    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x10, 0x11, 0x12, 0x13,
+                    0x14, 0x15, 0x16,
+                ]),
+            )
+            .unwrap();
+
+        let settings = desfire
+            .get_file_settings(FileId::new(0x03).unwrap())
+            .unwrap();
+        assert_eq!(settings.communication_mode(), CommunicationMode::Plain);
+
+        desfire
+            .write_data(
+                FileId::new(0x03).unwrap(),
+                U24::new(0).unwrap(),
+                &[
+                    0xF0, 0xE0, 0xD0, 0xC0, 0xB0, 0xA0, 0x90, 0x80, 0x70, 0x60, 0x50, 0x40, 0x30,
+                    0x20, 0x10,
+                ],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn reads_free_memory() {
+        let transport = MockTransport::new([(&[0x6E][..], &[0x00, 0x20, 0x03, 0x00][..])]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        let memory = desfire.free_memory().unwrap();
+
+        assert_eq!(memory.as_u32(), 800);
+    }
+
+    #[test]
+    fn reads_file_settings() {
+        let transport = MockTransport::new([(
+            &[0xF5, 0x01][..],
+            &[0x00, 0x00, 0x00, 0xEE, 0xEE, 0x20, 0x00, 0x00][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        let settings = desfire
+            .get_file_settings(FileId::new(0x01).unwrap())
+            .unwrap();
+
+        assert_eq!(
+            settings.details(),
+            FileSettingsDetails::Data {
+                size: U24::new(32).unwrap()
+            }
+        );
+    }
+
+    #[test]
+    fn reads_plain_data() {
+        let transport = MockTransport::new([(
+            &[0xBD, 0x01, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00][..],
+            &[0x00, 0xDE, 0xAD, 0xBE, 0xEF][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        let mut data: Vec<u8, 4> = Vec::new();
+
+        desfire
+            .read_data(
+                FileId::new(0x01).unwrap(),
+                U24::new(2).unwrap(),
+                U24::new(4).unwrap(),
+                &mut data,
+            )
+            .unwrap();
+
+        assert_eq!(data.as_slice(), &[0xDE, 0xAD, 0xBE, 0xEF]);
+    }
+
+    // Proxmark trace: `hf mfdes createapp --aid 222222 --no-auth --dstalgo aes --numkeys 1`
+    // TX: 90 CA 00 00 05 22 22 22 0F 81 00  (wrapped; native: CA 22 22 22 0F 81)
+    // RX: 91 00  (wrapped; native: 00)  — no MAC when unauthenticated
+    #[test]
+    fn change_file_settings_2tdea_proxmark_trace() {
+        // Proxmark3 trace: hf mfdes chfilesettings --aid 222222 --fid 01
+        //   --rrights key1 --wrights key1 --rwrights key1 --chrights key0 -n 0 -t 2tdea
+        // Session key: 01 02 03 04 51 99 63 96 01 02 03 04 51 99 63 96
+        // GetFileSettings cmd:  90 F5 00 00 01 01 00
+        //                 rsp:  00 00 10 11 10 00 00 0C 38 70 35 84 D6 7E 10 91 00
+        // ChangeFileSettings cmd: 90 5F 00 00 09 01 6A 40 3C 01 E8 88 23 2F 00
+        //                   rsp: 10 CD 6F 8D C3 D3 42 B9 91 00
+        let session_key = TwoKey3DesSessionKey::new([
+            0x01, 0x02, 0x03, 0x04, 0x51, 0x99, 0x63, 0x96, 0x01, 0x02, 0x03, 0x04, 0x51, 0x99,
+            0x63, 0x96,
+        ]);
+        let auth_session = AuthenticatedSession::new_2tdea(KeyNumber::new(0).unwrap(), session_key);
+
+        let transport = MockTransport::new([
+            (
+                &[0x90, 0xF5, 0x00, 0x00, 0x01, 0x01, 0x00][..],
+                &[
+                    0x00, 0x00, 0x10, 0x11, 0x10, 0x00, 0x00, 0x0C, 0x38, 0x70, 0x35, 0x84, 0xD6,
+                    0x7E, 0x10, 0x91, 0x00,
+                ][..],
+            ),
+            (
+                &[
+                    0x90, 0x5F, 0x00, 0x00, 0x09, 0x01, 0x6A, 0x40, 0x3C, 0x01, 0xE8, 0x88, 0x23,
+                    0x2F, 0x00,
+                ][..],
+                &[0x10, 0xCD, 0x6F, 0x8D, 0xC3, 0xD3, 0x42, 0xB9, 0x91, 0x00][..],
+            ),
+        ]);
+
+        let mut desfire = Desfire::new(transport, WrappedFraming);
+        desfire.session = Session::Authenticated(auth_session);
+
+        let key0 = AccessCondition::Key(KeyNumber::new(0).unwrap());
+        let key1 = AccessCondition::Key(KeyNumber::new(1).unwrap());
+        let access_rights = AccessRights::new(key1, key1, key1, key0);
+
+        desfire.get_file_settings(FileId::new(1).unwrap()).unwrap();
+        desfire
+            .change_file_settings(
+                FileId::new(1).unwrap(),
+                CommunicationMode::Plain,
+                access_rights,
+            )
+            .unwrap();
+
+        assert_eq!(desfire.executor().transport().index, 2);
+    }
+
+    #[test]
+    fn read_data_maced_2tdea_zero_key1_proxmark_trace() {
+        // Proxmark3 trace: hf mfdes read --aid 222222 --fid 01 -n 1 -t 2tdea
+        // Session key: 01 02 03 04 FC 67 24 71 01 02 03 04 FC 67 24 71
+        // GetFileSettings cmd:  90 F5 00 00 01 01 00
+        //                 rsp:  00 00 10 11 10 00 00 4F 25 14 78 C4 F0 66 FC 91 00
+        // ReadData cmd:  90 BD 00 00 07 01 00 00 00 00 00 00 00 00
+        //          rsp:  00*16 AF 4E 90 AE AE 3A DC F4 91 00
+        let session_key = TwoKey3DesSessionKey::new([
+            0x01, 0x02, 0x03, 0x04, 0xFC, 0x67, 0x24, 0x71, 0x01, 0x02, 0x03, 0x04, 0xFC, 0x67,
+            0x24, 0x71,
+        ]);
+        let auth_session = AuthenticatedSession::new_2tdea(KeyNumber::new(1).unwrap(), session_key);
+
+        let transport = MockTransport::new([
+            (
+                &[0x90, 0xF5, 0x00, 0x00, 0x01, 0x01, 0x00][..],
+                &[
+                    0x00, 0x00, 0x10, 0x11, 0x10, 0x00, 0x00, 0x4F, 0x25, 0x14, 0x78, 0xC4, 0xF0,
+                    0x66, 0xFC, 0x91, 0x00,
+                ][..],
+            ),
+            (
+                &[
+                    0x90, 0xBD, 0x00, 0x00, 0x07, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                ][..],
+                &[
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00, 0xAF, 0x4E, 0x90, 0xAE, 0xAE, 0x3A, 0xDC, 0xF4, 0x91, 0x00,
+                ][..],
+            ),
+        ]);
+
+        let mut desfire = Desfire::new(transport, WrappedFraming);
+        desfire.session = Session::Authenticated(auth_session);
+
+        let mut data: Vec<u8, 32> = Vec::new();
+        desfire.get_file_settings(FileId::new(1).unwrap()).unwrap();
+        desfire
+            .read_data_maced(
+                FileId::new(1).unwrap(),
+                U24::new(0).unwrap(),
+                U24::new(0).unwrap(),
+                &mut data,
+            )
+            .unwrap();
+
+        assert_eq!(data.as_slice(), &[0u8; 16]);
+        assert_eq!(desfire.executor().transport().index, 2);
+    }
+
+    #[test]
+    fn creates_application_unauthenticated() {
+        let transport =
+            MockTransport::new([(&[0xCA, 0x22, 0x22, 0x22, 0x0F, 0x81][..], &[0x00][..])]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        let aid = crate::mifare::desfire::ApplicationId::new(0x22_22_22).unwrap();
+        let ks = crate::mifare::desfire::KeySettings::new(0x0F, ApplicationKeyType::Aes, 1);
+
+        desfire.create_application(aid, ks).unwrap();
+
+        assert_eq!(desfire.executor().transport().index, 1);
+    }
+
+    #[test]
+    fn changes_2tdea_app_key_different_key_proxmark_trace() {
+        // Proxmark3 trace: hf mfdes changekey --aid 222222 -t 2tdea -n 0 --newkeyno 1 --verbose --apdu
+        // Auth key 0: 2tdea zero key.  New/old key 1: 2tdea zero key.
+        // Session key: 01 02 03 04 82 E4 29 94 01 02 03 04 82 E4 29 94
+        // Command: 90 C4 00 00 19 01 13 85 5D BB EC 9A 5C CB BB 34 92 D8 97 98 DC 8D AE C7 52 BF ED 26 55 AF 00
+        // Response: 2D 23 DD E4 FF 07 EB 0E 91 00
+        let session_key = TwoKey3DesSessionKey::new([
+            0x01, 0x02, 0x03, 0x04, 0x82, 0xE4, 0x29, 0x94, 0x01, 0x02, 0x03, 0x04, 0x82, 0xE4,
+            0x29, 0x94,
+        ]);
+        let auth_session = AuthenticatedSession::new_2tdea(KeyNumber::new(0).unwrap(), session_key);
+
+        let transport = MockTransport::new([(
+            &[
+                0x90, 0xC4, 0x00, 0x00, 0x19, 0x01, 0x13, 0x85, 0x5D, 0xBB, 0xEC, 0x9A, 0x5C, 0xCB,
+                0xBB, 0x34, 0x92, 0xD8, 0x97, 0x98, 0xDC, 0x8D, 0xAE, 0xC7, 0x52, 0xBF, 0xED, 0x26,
+                0x55, 0xAF, 0x00,
+            ][..],
+            &[0x2D, 0x23, 0xDD, 0xE4, 0xFF, 0x07, 0xEB, 0x0E, 0x91, 0x00][..],
+        )]);
+
+        let mut desfire = Desfire::new(transport, WrappedFraming);
+        desfire.session = Session::Authenticated(auth_session);
+
+        desfire
+            .change_key_2tdea(KeyNumber::new(1).unwrap(), [0u8; 16], Some([0u8; 16]))
+            .unwrap();
+
+        assert!(matches!(desfire.session, Session::Authenticated(_)));
+        assert_eq!(desfire.executor().transport().index, 1);
+    }
+
+    #[test]
+    fn gets_plain_value_unauthenticated() {
+        let transport =
+            MockTransport::new([(&[0x6C, 0x02][..], &[0x00, 0xE8, 0x03, 0x00, 0x00][..])]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        let value = desfire
+            .get_value(FileId::new(0x02).unwrap(), CommunicationMode::Plain)
+            .unwrap();
+
+        assert_eq!(value, 1000);
+    }
+
+    #[test]
+    fn credits_plain_value_and_commits_unauthenticated() {
+        let transport = MockTransport::new([
+            (&[0x0C, 0x02, 0x64, 0x00, 0x00, 0x00][..], &[0x00][..]),
+            (&[0xC7][..], &[0x00][..]),
+        ]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        desfire
+            .credit(FileId::new(0x02).unwrap(), CommunicationMode::Plain, 100)
+            .unwrap();
+        desfire.commit_transaction().unwrap();
+
+        assert_eq!(desfire.executor().transport().index, 2);
+    }
+
+    #[test]
+    fn rejects_non_positive_value_amounts_before_sending() {
+        let transport = MockTransport::new([]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        let file_id = FileId::new(0x02).unwrap();
+
+        assert_eq!(
+            desfire.credit(file_id, CommunicationMode::Plain, -100),
+            Err(Error::InvalidValueAmount(-100))
+        );
+        assert_eq!(
+            desfire.debit(file_id, CommunicationMode::Plain, 0),
+            Err(Error::InvalidValueAmount(0))
+        );
+        assert_eq!(
+            desfire.limited_credit(file_id, CommunicationMode::Plain, i32::MIN),
+            Err(Error::InvalidValueAmount(i32::MIN))
+        );
+        assert_eq!(desfire.executor().transport().index, 0);
+    }
+
+    #[test]
+    fn rejects_maced_value_read_without_authentication() {
+        let transport = MockTransport::new([]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        let err = desfire
+            .get_value(FileId::new(0x02).unwrap(), CommunicationMode::Maced)
+            .unwrap_err();
+
+        assert_eq!(err, Error::MissingAuthentication);
+        assert_eq!(desfire.executor().transport().index, 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn debits_maced_value_with_aes_session() {
+        use crate::mifare::desfire::crypto::AesCmacChaining;
+
+        let sk = AesSessionKey::new([
+            0x01, 0x02, 0x03, 0x04, 0x7C, 0xB5, 0xEA, 0x83, 0x13, 0x14, 0x15, 0x16, 0xD8, 0x51,
+            0xEF, 0x57,
+        ]);
+        let payload = [0x02, 0x0A, 0x00, 0x00, 0x00];
+
+        // Command MAC covers [DC || fid || amount]; response MAC covers the status byte.
+        let mut chaining = AesCmacChaining::new();
+        let mut cmac_input = std::vec![0xDC];
+        cmac_input.extend_from_slice(&payload);
+        let command_mac = chaining.update(&sk, &cmac_input).desfire_mac();
+        let response_mac = chaining.update(&sk, &[0x00]).desfire_mac();
+
+        let mut tx = std::vec![0xDC];
+        tx.extend_from_slice(&payload);
+        tx.extend_from_slice(&command_mac.as_bytes());
+        let mut rx = std::vec![0x00];
+        rx.extend_from_slice(&response_mac.as_bytes());
+
+        let transport = DynMockTransport::new(std::vec![(tx, rx)]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::Authenticated(AuthenticatedSession::new_aes(
+            KeyNumber::new(0).unwrap(),
+            sk,
+        ));
+
+        desfire
+            .debit(FileId::new(0x02).unwrap(), CommunicationMode::Maced, 10)
+            .unwrap();
+
+        assert_eq!(
+            desfire
+                .authenticated_session()
+                .unwrap()
+                .aes_state()
+                .unwrap()
+                .1,
+            chaining
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn gets_enciphered_value_with_aes_session() {
+        use crate::mifare::desfire::crypto::{desfire_crc32, AesCmacChaining};
+
+        let sk = AesSessionKey::new([
+            0x01, 0x02, 0x03, 0x04, 0x7C, 0xB5, 0xEA, 0x83, 0x13, 0x14, 0x15, 0x16, 0xD8, 0x51,
+            0xEF, 0x57,
+        ]);
+        let value: i32 = -250;
+
+        // The command CMAC advances the IV; the response is value || CRC32(value || status).
+        let mut chaining = AesCmacChaining::new();
+        chaining.update(&sk, &[0x6C, 0x02]);
+        let mut crc_input = value.to_le_bytes().to_vec();
+        crc_input.push(0x00);
+        let mut ciphertext = [0u8; 16];
+        ciphertext[..4].copy_from_slice(&value.to_le_bytes());
+        ciphertext[4..8].copy_from_slice(&desfire_crc32(&crc_input));
+        aes_cbc_encrypt_in_place(sk.as_bytes(), &chaining.state(), &mut ciphertext);
+
+        let mut rx = std::vec![0x00];
+        rx.extend_from_slice(&ciphertext);
+
+        let transport = DynMockTransport::new(std::vec![(std::vec![0x6C, 0x02], rx)]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::Authenticated(AuthenticatedSession::new_aes(
+            KeyNumber::new(0).unwrap(),
+            sk,
+        ));
+
+        let read = desfire
+            .get_value(FileId::new(0x02).unwrap(), CommunicationMode::Enciphered)
+            .unwrap();
+
+        assert_eq!(read, value);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn limited_credits_enciphered_value_with_aes_session() {
+        use crate::mifare::desfire::crypto::{desfire_crc32, AesCmacChaining};
+
+        let sk = AesSessionKey::new([
+            0x01, 0x02, 0x03, 0x04, 0x7C, 0xB5, 0xEA, 0x83, 0x13, 0x14, 0x15, 0x16, 0xD8, 0x51,
+            0xEF, 0x57,
+        ]);
+
+        // Plaintext: amount || CRC32(1C || fid || amount), zero-padded, IV = zero chaining.
+        let mut ciphertext = [0u8; 16];
+        ciphertext[..4].copy_from_slice(&5i32.to_le_bytes());
+        ciphertext[4..8].copy_from_slice(&desfire_crc32(&[0x1C, 0x02, 0x05, 0x00, 0x00, 0x00]));
+        aes_cbc_encrypt_in_place(sk.as_bytes(), &[0u8; 16], &mut ciphertext);
+
+        let mut chaining = AesCmacChaining::from_state(ciphertext);
+        let response_mac = chaining.update(&sk, &[0x00]).desfire_mac();
+
+        let mut tx = std::vec![0x1C, 0x02];
+        tx.extend_from_slice(&ciphertext);
+        let mut rx = std::vec![0x00];
+        rx.extend_from_slice(&response_mac.as_bytes());
+
+        let transport = DynMockTransport::new(std::vec![(tx, rx)]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::Authenticated(AuthenticatedSession::new_aes(
+            KeyNumber::new(0).unwrap(),
+            sk,
+        ));
+
+        desfire
+            .limited_credit(FileId::new(0x02).unwrap(), CommunicationMode::Enciphered, 5)
+            .unwrap();
+
+        assert_eq!(desfire.executor().transport().index, 1);
+    }
+
+    #[test]
+    fn writes_plain_record_and_commits_unauthenticated() {
+        let transport = MockTransport::new([
+            (
+                &[
+                    0x3B, 0x04, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0xDE, 0xAD, 0xBE, 0xEF,
+                ][..],
+                &[0x00][..],
+            ),
+            (&[0xC7][..], &[0x00][..]),
+        ]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        desfire
+            .write_record(
+                FileId::new(0x04).unwrap(),
+                CommunicationMode::Plain,
+                U24::new(0).unwrap(),
+                &[0xDE, 0xAD, 0xBE, 0xEF],
+            )
+            .unwrap();
+        desfire.commit_transaction().unwrap();
+
+        assert_eq!(desfire.executor().transport().index, 2);
+    }
+
+    #[test]
+    fn reads_plain_records_across_additional_frames() {
+        let transport = MockTransport::new([
+            (
+                &[0xBB, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00][..],
+                &[0xAF, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06][..],
+            ),
+            (&[0xAF][..], &[0x00, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C][..]),
+        ]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        let mut data: Vec<u8, 32> = Vec::new();
+
+        let records = desfire
+            .read_records(
+                FileId::new(0x04).unwrap(),
+                CommunicationMode::Plain,
+                U24::new(4).unwrap(),
+                U24::new(0).unwrap(),
+                U24::new(0).unwrap(),
+                &mut data,
+            )
+            .unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records.get(0), Some(&[0x01, 0x02, 0x03, 0x04][..]));
+        assert_eq!(records.get(2), Some(&[0x09, 0x0A, 0x0B, 0x0C][..]));
+    }
+
+    #[test]
+    fn rejects_read_records_with_unexpected_record_count() {
+        let transport = MockTransport::new([(
+            &[0xBB, 0x04, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00][..],
+            &[0x00, 0x01, 0x02, 0x03, 0x04][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        let mut data: Vec<u8, 32> = Vec::new();
+
+        let err = desfire
+            .read_records(
+                FileId::new(0x04).unwrap(),
+                CommunicationMode::Plain,
+                U24::new(4).unwrap(),
+                U24::new(0).unwrap(),
+                U24::new(2).unwrap(),
+                &mut data,
+            )
+            .unwrap_err();
+
+        assert_eq!(err, Error::InvalidResponseLength);
+    }
+
+    #[test]
+    fn rejects_read_records_with_zero_record_size() {
+        let transport = MockTransport::new([]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        let mut data: Vec<u8, 32> = Vec::new();
+
+        let err = desfire
+            .read_records(
+                FileId::new(0x01).unwrap(),
+                CommunicationMode::Plain,
+                U24::new(0).unwrap(),
+                U24::new(0).unwrap(),
+                U24::new(1).unwrap(),
+                &mut data,
+            )
+            .unwrap_err();
+
+        assert_eq!(err, Error::InvalidResponseLength);
+        assert_eq!(desfire.executor().transport().index, 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn reads_enciphered_records_with_aes_session() {
+        use crate::mifare::desfire::crypto::{desfire_crc32, AesCmacChaining};
+
+        let sk = AesSessionKey::new([
+            0x01, 0x02, 0x03, 0x04, 0x7C, 0xB5, 0xEA, 0x83, 0x13, 0x14, 0x15, 0x16, 0xD8, 0x51,
+            0xEF, 0x57,
+        ]);
+        let records = [
+            0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25,
+        ];
+        let command = [0xBB, 0x04, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00];
+
+        // Plaintext: records || CRC32(records || status), zero-padded; IV = command CMAC.
+        let mut chaining = AesCmacChaining::new();
+        chaining.update(&sk, &command);
+        let mut crc_input = records.to_vec();
+        crc_input.push(0x00);
+        let mut ciphertext = [0u8; 16];
+        ciphertext[..12].copy_from_slice(&records);
+        ciphertext[12..].copy_from_slice(&desfire_crc32(&crc_input));
+        aes_cbc_encrypt_in_place(sk.as_bytes(), &chaining.state(), &mut ciphertext);
+
+        let mut rx = std::vec![0x00];
+        rx.extend_from_slice(&ciphertext);
+
+        let transport = DynMockTransport::new(std::vec![(command.to_vec(), rx)]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::Authenticated(AuthenticatedSession::new_aes(
+            KeyNumber::new(0).unwrap(),
+            sk,
+        ));
+        let mut data: Vec<u8, 32> = Vec::new();
+
+        let read = desfire
+            .read_records(
+                FileId::new(0x04).unwrap(),
+                CommunicationMode::Enciphered,
+                U24::new(6).unwrap(),
+                U24::new(0).unwrap(),
+                U24::new(2).unwrap(),
+                &mut data,
+            )
+            .unwrap();
+
+        assert_eq!(read.len(), 2);
+        assert_eq!(read.get(1), Some(&records[6..]));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn clears_record_file_with_aes_session() {
+        use crate::mifare::desfire::crypto::AesCmacChaining;
+
+        let sk = AesSessionKey::new([
+            0x01, 0x02, 0x03, 0x04, 0x7C, 0xB5, 0xEA, 0x83, 0x13, 0x14, 0x15, 0x16, 0xD8, 0x51,
+            0xEF, 0x57,
+        ]);
+
+        let mut chaining = AesCmacChaining::new();
+        chaining.update(&sk, &[0xEB, 0x04]);
+        let response_mac = chaining.update(&sk, &[0x00]).desfire_mac();
+        let mut rx = std::vec![0x00];
+        rx.extend_from_slice(&response_mac.as_bytes());
+
+        let transport = DynMockTransport::new(std::vec![(std::vec![0xEB, 0x04], rx)]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::Authenticated(AuthenticatedSession::new_aes(
+            KeyNumber::new(0).unwrap(),
+            sk,
+        ));
+
+        desfire
+            .clear_record_file(FileId::new(0x04).unwrap())
+            .unwrap();
+
+        assert_eq!(
+            desfire
+                .authenticated_session()
+                .unwrap()
+                .aes_state()
+                .unwrap()
+                .1,
+            chaining
+        );
+    }
+
+    /// EV2 session from the NXP AN12196 `AuthenticateEV2First` example (key 0, all-zero key).
+    fn nxp_ev2_session(command_counter: u16) -> Ev2Session {
+        Ev2Session::new(
+            KeyNumber::new(0).unwrap(),
+            [0x9D, 0x00, 0xC4, 0xDF],
+            AesSessionKey::new([
+                0x13, 0x09, 0xC8, 0x77, 0x50, 0x9E, 0x5A, 0x21, 0x50, 0x07, 0xFF, 0x0E, 0xD1, 0x9C,
+                0xA5, 0x64,
+            ]),
+            AesSessionKey::new([
+                0x4C, 0x66, 0x26, 0xF5, 0xE7, 0x2E, 0xA6, 0x94, 0x20, 0x21, 0x39, 0x29, 0x5C, 0x7A,
+                0x7F, 0xC7,
+            ]),
+        )
+        .with_command_counter(command_counter)
+    }
+
+    /// EV2 session of the NXP AN12196 `ChangeKey` examples.
+    fn nxp_change_key_session(command_counter: u16) -> Ev2Session {
+        Ev2Session::new(
+            KeyNumber::new(0).unwrap(),
+            [0x76, 0x14, 0x28, 0x1A],
+            AesSessionKey::new([
+                0x4C, 0xF3, 0xCB, 0x41, 0xA2, 0x25, 0x83, 0xA6, 0x1E, 0x89, 0xB1, 0x58, 0xD2, 0x52,
+                0xFC, 0x53,
+            ]),
+            AesSessionKey::new([
+                0x55, 0x29, 0x86, 0x0B, 0x2F, 0xC5, 0xFB, 0x61, 0x54, 0xB7, 0xF2, 0x83, 0x61, 0xD3,
+                0x0B, 0xF9,
+            ]),
+        )
+        .with_command_counter(command_counter)
+    }
+
+    #[test]
+    fn authenticates_ev2_first_with_nxp_example() {
+        let rnd_a = RndA::new([
+            0x13, 0xC5, 0xDB, 0x8A, 0x59, 0x30, 0x43, 0x9F, 0xC3, 0xDE, 0xF9, 0xA4, 0xC6, 0x75,
+            0x36, 0x0F,
+        ]);
+        let transport = MockTransport::new([
+            (
+                &[0x90, 0x71, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00][..],
+                &[
+                    0xA0, 0x4C, 0x12, 0x42, 0x13, 0xC1, 0x86, 0xF2, 0x23, 0x99, 0xD3, 0x3A, 0xC2,
+                    0xA3, 0x02, 0x15, 0x91, 0xAF,
+                ][..],
+            ),
+            (
+                &[
+                    0x90, 0xAF, 0x00, 0x00, 0x20, 0x35, 0xC3, 0xE0, 0x5A, 0x75, 0x2E, 0x01, 0x44,
+                    0xBA, 0xC0, 0xDE, 0x51, 0xC1, 0xF2, 0x2C, 0x56, 0xB3, 0x44, 0x08, 0xA2, 0x3D,
+                    0x8A, 0xEA, 0x26, 0x6C, 0xAB, 0x94, 0x7E, 0xA8, 0xE0, 0x11, 0x8D, 0x00,
+                ][..],
+                &[
+                    0x3F, 0xA6, 0x4D, 0xB5, 0x44, 0x6D, 0x1F, 0x34, 0xCD, 0x6E, 0xA3, 0x11, 0x16,
+                    0x7F, 0x5E, 0x49, 0x85, 0xB8, 0x96, 0x90, 0xC0, 0x4A, 0x05, 0xF1, 0x7F, 0xA7,
+                    0xAB, 0x2F, 0x08, 0x12, 0x06, 0x63, 0x91, 0x00,
+                ][..],
+            ),
+        ]);
+        let mut desfire = Desfire::new(transport, WrappedFraming);
+
+        let session = desfire
+            .authenticate_ev2_first_with_rnd_a(
+                KeyNumber::new(0).unwrap(),
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
+                rnd_a,
+            )
+            .unwrap();
+
+        assert_eq!(session, nxp_ev2_session(0));
+        assert_eq!(
+            *desfire.session(),
+            Session::AuthenticatedEv2(session.clone())
+        );
+        assert_eq!(desfire.ev2_session(), Some(&session));
+        assert_eq!(desfire.authenticated_session(), None);
+    }
+
+    /// `RndA` of the NXP AN12343 `AuthenticateEV2First` example (key 0, all-zero key).
+    fn an12343_rnd_a() -> RndA {
+        RndA::new([
+            0xB0, 0x4D, 0x07, 0x87, 0xC9, 0x3E, 0xE0, 0xCC, 0x8C, 0xAC, 0xC8, 0xE8, 0x6F, 0x16,
+            0xC6, 0xFE,
+        ])
+    }
+
+    /// Session keys AN12343 derives from its `RndA` and `RndB`.
+    fn assert_an12343_session_keys(session: &Ev2Session) {
+        assert_eq!(
+            *session.encryption_key(),
+            AesSessionKey::new([
+                0x63, 0xDC, 0x07, 0x28, 0x62, 0x89, 0xA7, 0xA6, 0xC0, 0x33, 0x4C, 0xA3, 0x1C, 0x31,
+                0x4A, 0x04
+            ])
+        );
+        assert_eq!(
+            *session.mac_key(),
+            AesSessionKey::new([
+                0x77, 0x4F, 0x26, 0x74, 0x3E, 0xCE, 0x6A, 0xF5, 0x03, 0x3B, 0x6A, 0xE8, 0x52, 0x29,
+                0x46, 0xF6
+            ])
+        );
+    }
+
+    #[test]
+    fn authenticates_ev2_first_with_an12343_example() {
+        // The card cryptograms encrypt the example's RndB and RndA' under the
+        // all-zero key; TI and capabilities are not part of the example.
+        let transport = MockTransport::new([
+            (
+                &[0x71, 0x00, 0x00][..],
+                &[
+                    0xAF, 0x24, 0x67, 0x7D, 0xDB, 0xD4, 0x63, 0x49, 0xE6, 0x23, 0x79, 0x8F, 0xD7,
+                    0x29, 0x00, 0x6E, 0x79,
+                ][..],
+            ),
+            (
+                &[
+                    0xAF, 0x3B, 0x50, 0x44, 0x5F, 0x21, 0xD2, 0x1D, 0x77, 0xD5, 0x00, 0x79, 0x4D,
+                    0xEB, 0x24, 0x5E, 0x5A, 0x75, 0x4F, 0x5F, 0x90, 0x18, 0x44, 0x25, 0x9F, 0x4C,
+                    0x9B, 0x31, 0xA5, 0xC7, 0x33, 0x5A, 0xCD,
+                ][..],
+                &[
+                    0x00, 0x3F, 0x44, 0x52, 0x52, 0xB6, 0x78, 0xAA, 0xC1, 0x14, 0xE6, 0xA0, 0xA1,
+                    0x2F, 0x4C, 0x23, 0x36, 0xCD, 0xFF, 0x5D, 0x39, 0x76, 0x77, 0xCC, 0xF1, 0xD4,
+                    0x5C, 0x91, 0x78, 0xC0, 0x67, 0x44, 0xDE,
+                ][..],
+            ),
+        ]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        let session = desfire
+            .authenticate_ev2_first_with_rnd_a(
+                KeyNumber::new(0).unwrap(),
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
+                an12343_rnd_a(),
+            )
+            .unwrap();
+
+        assert_an12343_session_keys(&session);
+        assert_eq!(session.transaction_identifier(), [0xE2, 0xD3, 0xAF, 0x69]);
+        assert_eq!(session.command_counter(), 0);
+    }
+
+    #[test]
+    fn authenticates_ev2_non_first_keeping_transaction_state() {
+        // AN12343 random numbers; `NonFirst` derives the same session keys but
+        // keeps the transaction identifier and command counter.
+        let transport = MockTransport::new([
+            (
+                &[0x77, 0x00][..],
+                &[
+                    0xAF, 0x24, 0x67, 0x7D, 0xDB, 0xD4, 0x63, 0x49, 0xE6, 0x23, 0x79, 0x8F, 0xD7,
+                    0x29, 0x00, 0x6E, 0x79,
+                ][..],
+            ),
+            (
+                &[
+                    0xAF, 0x3B, 0x50, 0x44, 0x5F, 0x21, 0xD2, 0x1D, 0x77, 0xD5, 0x00, 0x79, 0x4D,
+                    0xEB, 0x24, 0x5E, 0x5A, 0x75, 0x4F, 0x5F, 0x90, 0x18, 0x44, 0x25, 0x9F, 0x4C,
+                    0x9B, 0x31, 0xA5, 0xC7, 0x33, 0x5A, 0xCD,
+                ][..],
+                &[
+                    0x00, 0x42, 0x55, 0x7C, 0xAC, 0xA7, 0x5F, 0xB4, 0x89, 0xEB, 0x68, 0x2F, 0x71,
+                    0x6D, 0x91, 0x23, 0xD7,
+                ][..],
+            ),
+        ]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_ev2_session(5));
+
+        let session = desfire
+            .authenticate_ev2_non_first_with_rnd_a(
+                KeyNumber::new(0).unwrap(),
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
+                an12343_rnd_a(),
+            )
+            .unwrap();
+
+        assert_an12343_session_keys(&session);
+        assert_eq!(session.transaction_identifier(), [0x9D, 0x00, 0xC4, 0xDF]);
+        assert_eq!(session.command_counter(), 5);
+        assert_eq!(desfire.ev2_session(), Some(&session));
+    }
+
+    fn an12343_write_data() -> [u8; 32] {
+        let mut data = [0x22; 32];
+        data[..7].copy_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00]);
+        data
+    }
+
+    #[test]
+    fn exchanges_maced_commands_with_an12343_example() {
+        // AN12343 `CommMode.MAC` example: WriteData of 25 bytes, then ReadData of
+        // the 48-byte file. Only the MAC key is used in this mode.
+        let transport = MockTransport::new([
+            (
+                &[
+                    0x8D, 0x00, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x22, 0x22, 0x22, 0x22, 0x22,
+                    0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+                    0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x68, 0xF2, 0xC2, 0x8C, 0x57, 0x5A,
+                    0x16, 0x28,
+                ][..],
+                &[0x00, 0x08, 0x20, 0xF6, 0x88, 0x98, 0xC2, 0xA7, 0xF1][..],
+            ),
+            (
+                &[
+                    0xAD, 0x00, 0x00, 0x00, 0x00, 0x30, 0x00, 0x00, 0x0D, 0x9B, 0xE1, 0x91, 0xD5,
+                    0x96, 0x08, 0x34,
+                ][..],
+                &[
+                    0x00, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+                    0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x9A, 0x44,
+                    0x22, 0x2D, 0x92, 0x66, 0x66,
+                ][..],
+            ),
+        ]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        let session = Ev2Session::new(
+            KeyNumber::new(0).unwrap(),
+            [0xE2, 0xD3, 0xAF, 0x69],
+            AesSessionKey::new([0; 16]),
+            AesSessionKey::new([
+                0x93, 0x66, 0xFA, 0x19, 0x5E, 0xB5, 0x66, 0xF5, 0xBD, 0x2B, 0xAD, 0x40, 0x20, 0xB8,
+                0x30, 0x02,
+            ]),
+        );
+        let mut body: Vec<u8, 48> = Vec::new();
+
+        let session = desfire
+            .exchange_ev2(
+                session,
+                CommandCode::WRITE_DATA_ISO,
+                &an12343_write_data(),
+                &mut body,
+            )
+            .unwrap();
+        assert!(body.is_empty());
+        desfire
+            .exchange_ev2(
+                session,
+                CommandCode::READ_DATA_ISO,
+                &[0x00, 0x00, 0x00, 0x00, 0x30, 0x00, 0x00],
+                &mut body,
+            )
+            .unwrap();
+
+        assert_eq!(&body[..25], &[0x22; 25]);
+        assert_eq!(&body[25..], &[0; 23]);
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 2);
+    }
+
+    #[test]
+    fn rejects_ev2_non_first_without_ev2_session() {
+        let transport = MockTransport::new([]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        assert_eq!(
+            desfire.authenticate_ev2_non_first_with_rnd_a(
+                KeyNumber::new(0).unwrap(),
+                &mut SoftwareCrypto,
+                &Key::Aes128([0u8; 16]),
+                RndA::new([0u8; 16])
+            ),
+            Err(Error::MissingAuthentication)
+        );
+        assert_eq!(desfire.executor().transport().index, 0);
+    }
+
+    #[test]
+    fn gets_file_settings_with_ev2_mac() {
+        let transport = MockTransport::new([(
+            &[0xF5, 0x02, 0x04, 0x6F, 0xD9, 0xC8, 0x0D, 0x11, 0xD1, 0x75][..],
+            &[
+                0x00, 0x00, 0x00, 0xE0, 0xEE, 0x20, 0x00, 0x00, 0xA0, 0x10, 0xCE, 0xEB, 0x6A, 0xDB,
+                0x0F, 0x2D,
+            ][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_ev2_session(0));
+
+        let settings = desfire
+            .get_file_settings(FileId::new(0x02).unwrap())
+            .unwrap();
+
+        assert_eq!(settings.communication_mode(), CommunicationMode::Plain);
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 1);
+    }
+
+    #[test]
+    fn rejects_ev2_response_with_wrong_mac() {
+        let transport = MockTransport::new([(
+            &[0xF5, 0x02, 0x04, 0x6F, 0xD9, 0xC8, 0x0D, 0x11, 0xD1, 0x75][..],
+            &[
+                0x00, 0x00, 0x00, 0xE0, 0xEE, 0x20, 0x00, 0x00, 0xA0, 0x10, 0xCE, 0xEB, 0x6A, 0xDB,
+                0x0F, 0x2E,
+            ][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_ev2_session(0));
+
+        assert_eq!(
+            desfire.get_file_settings(FileId::new(0x02).unwrap()),
+            Err(Error::InvalidMac)
+        );
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 0);
+    }
+
+    #[test]
+    fn writes_enciphered_data_with_ev2_session() {
+        let transport = MockTransport::new([(
+            &[
+                0x3D, 0x01, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0xD1, 0xAC, 0x3B, 0xC9, 0x18, 0x59,
+                0xCE, 0xCA, 0x1A, 0x7A, 0x72, 0x81, 0x81, 0x29, 0xBE, 0x3B, 0xBB, 0x79, 0x84, 0xBE,
+                0x3C, 0xB7, 0x2C, 0x6E,
+            ][..],
+            &[0x00, 0x57, 0xBF, 0xF8, 0x7B, 0x12, 0x41, 0xE9, 0x3D][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_ev2_session(1));
+
+        desfire
+            .write_data_enciphered(
+                FileId::new(0x01).unwrap(),
+                U24::new(0).unwrap(),
+                &[0x01, 0x02, 0x03, 0x04],
+            )
+            .unwrap();
+
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 2);
+    }
+
+    #[test]
+    fn reads_enciphered_data_with_ev2_session() {
+        let transport = MockTransport::new([(
+            &[
+                0xBD, 0x01, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x65, 0x63, 0x4D, 0x28, 0x22, 0xF2,
+                0xDC, 0xE9,
+            ][..],
+            &[
+                0x00, 0xA1, 0xB5, 0x6A, 0xC1, 0xA9, 0x61, 0x4C, 0xFE, 0xBB, 0x46, 0x71, 0x84, 0x36,
+                0xAB, 0x12, 0xD6, 0xF9, 0x90, 0x96, 0xD9, 0x02, 0x48, 0xB4, 0x0E,
+            ][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_ev2_session(2));
+        let mut data: Vec<u8, 4> = Vec::new();
+
+        desfire
+            .read_data_enciphered(
+                FileId::new(0x01).unwrap(),
+                U24::new(0).unwrap(),
+                U24::new(4).unwrap(),
+                &mut data,
+            )
+            .unwrap();
+
+        assert_eq!(data.as_slice(), &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 3);
+    }
+
+    #[test]
+    fn reads_plain_data_with_ev2_session_without_mac() {
+        let transport = MockTransport::new([(
+            &[0xBD, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00][..],
+            &[0x00, 0xAA, 0xBB][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_ev2_session(7));
+        let mut data: Vec<u8, 2> = Vec::new();
+
+        desfire
+            .read_data(
+                FileId::new(0x01).unwrap(),
+                U24::new(0).unwrap(),
+                U24::new(2).unwrap(),
+                &mut data,
+            )
+            .unwrap();
+
+        assert_eq!(data.as_slice(), &[0xAA, 0xBB]);
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 8);
+    }
+
+    #[test]
+    fn writes_full_mode_data_with_nxp_change_file_settings_example() {
+        // AN12196 `ChangeFileSettings`: file 02 gets SDM settings in `CommMode.Full`.
+        let transport = MockTransport::new([(
+            &[
+                0x5F, 0x02, 0x61, 0xB6, 0xD9, 0x79, 0x03, 0x56, 0x6E, 0x84, 0xC3, 0xAE, 0x52, 0x74,
+                0x46, 0x7E, 0x89, 0xEA, 0xD7, 0x99, 0xB7, 0xC1, 0xA0, 0xEF, 0x7A, 0x04,
+            ][..],
+            &[0x00, 0x57, 0xBF, 0xF8, 0x7B, 0x12, 0x41, 0xE9, 0x3D][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        desfire
+            .execute_ev2_enciphered_write(
+                nxp_ev2_session(1),
+                CommandCode::CHANGE_FILE_SETTINGS,
+                &[0x02],
+                &[
+                    0x40, 0x00, 0xE0, 0xC1, 0xF1, 0x21, 0x20, 0x00, 0x00, 0x43, 0x00, 0x00, 0x43,
+                    0x00, 0x00,
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 2);
+    }
+
+    #[test]
+    fn changes_other_aes_key_with_nxp_ev2_example() {
+        // New key of the AN12196 `ChangeKey` case 1, written over an all-zero key 2 with
+        // the CRC32 of the new key.
+        let transport = MockTransport::new([(
+            &[
+                0xC4, 0x02, 0x8E, 0xC7, 0xA1, 0xDC, 0x69, 0x08, 0xD0, 0x85, 0x24, 0x83, 0x7F, 0x7B,
+                0xA3, 0xA2, 0xB8, 0x65, 0x22, 0xB2, 0x12, 0x75, 0xA7, 0x8C, 0x8C, 0xB2, 0xD4, 0x05,
+                0x02, 0xEE, 0x90, 0xC5, 0xC5, 0x26, 0xF8, 0x91, 0xF2, 0xFB, 0x6C, 0x8D, 0x67, 0x81,
+            ][..],
+            &[0x00, 0x5D, 0x7C, 0xFF, 0xA9, 0x17, 0x0B, 0x7C, 0x62][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_change_key_session(3));
+
+        desfire
+            .change_key_aes(
+                KeyNumber::new(2).unwrap(),
+                [
+                    0xF3, 0x84, 0x7D, 0x62, 0x77, 0x27, 0xED, 0x3B, 0xC9, 0xC4, 0xCC, 0x05, 0x04,
+                    0x89, 0xB9, 0x66,
+                ],
+                0x01,
+                Some([0u8; 16]),
+            )
+            .unwrap();
+
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 4);
+    }
+
+    #[test]
+    fn changing_ev2_authenticated_key_matches_nxp_example() {
+        // AN12196 `ChangeKey` case 2: the authenticated key 0 is replaced, so the
+        // cryptogram has no CRC and the card answers without a MAC.
+        let transport = MockTransport::new([(
+            &[
+                0xC4, 0x00, 0xC0, 0xEB, 0x4D, 0xEE, 0xFE, 0xDD, 0xF0, 0xB5, 0x13, 0xA0, 0x3A, 0x95,
+                0xA7, 0x54, 0x91, 0x81, 0x85, 0x80, 0x50, 0x31, 0x90, 0xD4, 0xD0, 0x50, 0x53, 0xFF,
+                0x75, 0x66, 0x8A, 0x01, 0xD6, 0xFD, 0xA6, 0x61, 0x02, 0x34, 0xBD, 0xED, 0x64, 0x32,
+            ][..],
+            &[0x00][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_change_key_session(3));
+
+        desfire
+            .change_key_aes(
+                KeyNumber::new(0).unwrap(),
+                [
+                    0x50, 0x04, 0xBF, 0x99, 0x1F, 0x40, 0x86, 0x72, 0xB1, 0xEF, 0x00, 0xF0, 0x8F,
+                    0x9E, 0x86, 0x47,
+                ],
+                0x01,
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(*desfire.session(), Session::Unauthenticated);
+    }
+
+    #[test]
+    fn changes_2tdea_key_set_key_with_ev2_session() {
+        // Plaintext: new key XOR old key || CRC32(new key) || 80 00..; no KeyVer
+        // byte, since DES-family versions live in the parity bits.
+        let transport = MockTransport::new([(
+            &[
+                0xC6, 0x01, 0x00, 0xBC, 0x97, 0x4F, 0x23, 0xBE, 0x6F, 0xD5, 0x0E, 0x82, 0x58, 0xCC,
+                0x55, 0xD1, 0x7A, 0xF0, 0xAF, 0x18, 0xD1, 0x65, 0x72, 0x5E, 0x15, 0x18, 0x80, 0xF4,
+                0x34, 0x69, 0x01, 0xB4, 0xE3, 0xFD, 0x9D, 0xBD, 0x19, 0xF0, 0x78, 0xD9, 0x86, 0x6E,
+                0x40,
+            ][..],
+            &[0x00, 0x18, 0x14, 0x12, 0x00, 0xE0, 0x42, 0x0D, 0x1C][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_ev2_session(3));
+
+        desfire
+            .change_key_ev2_2tdea(
+                KeySetNumber::new(1).unwrap(),
+                KeyNumber::new(0).unwrap(),
+                [
+                    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC,
+                    0xDD, 0xEE, 0xFF,
+                ],
+                Some([0u8; 16]),
+            )
+            .unwrap();
+
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 4);
+    }
+
+    #[test]
+    fn changes_3tdea_key_set_key_with_ev2_session() {
+        // 24 key bytes and the CRC32 fill the first 28 bytes; 80 00.. pads to 32.
+        let transport = MockTransport::new([(
+            &[
+                0xC6, 0x01, 0x00, 0x56, 0xC6, 0xCF, 0xC7, 0x4D, 0x67, 0x82, 0xE9, 0x60, 0xD8, 0xB4,
+                0x57, 0x5C, 0x84, 0x3F, 0x53, 0xDA, 0xA3, 0x38, 0x1F, 0x73, 0xC7, 0xA4, 0xBB, 0x99,
+                0x28, 0xFC, 0x84, 0x77, 0xAE, 0x91, 0xDA, 0x79, 0x44, 0xFC, 0xD7, 0x06, 0x54, 0x17,
+                0x2F,
+            ][..],
+            &[0x00, 0x6C, 0xA0, 0x00, 0xD0, 0x31, 0x73, 0x85, 0x01][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_ev2_session(4));
+
+        desfire
+            .change_key_ev2_3tdea(
+                KeySetNumber::new(1).unwrap(),
+                KeyNumber::new(0).unwrap(),
+                [
+                    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC,
+                    0xDD, 0xEE, 0xFF, 0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF,
+                ],
+                Some([0u8; 24]),
+            )
+            .unwrap();
+
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 5);
+    }
+
+    #[test]
+    fn creates_iso_application_with_file_id_and_df_name() {
+        let transport = MockTransport::new([(
+            &[
+                0xCA, 0x01, 0x00, 0xF0, 0x0F, 0xA3, 0x05, 0xE1, 0xD2, 0x76, 0x00, 0x00, 0x85, 0x01,
+                0x01,
+            ][..],
+            &[0x00][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        let df_name = DfName::new(&[0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01]).unwrap();
+
+        desfire
+            .create_iso_application(
+                ApplicationId::new(0xF0_00_01).unwrap(),
+                KeySettings::new(0x0F, ApplicationKeyType::Aes, 3),
+                IsoFileId::new(0xE105).unwrap(),
+                Some(&df_name),
+            )
+            .unwrap();
+
+        assert_eq!(desfire.executor().transport().index, 1);
+    }
+
+    #[test]
+    fn creates_iso_std_data_file() {
+        let transport = MockTransport::new([(
+            &[0xCD, 0x02, 0x04, 0xE1, 0x00, 0xE0, 0xEE, 0x80, 0x00, 0x00][..],
+            &[0x00][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        desfire
+            .create_iso_std_data_file(
+                FileId::new(0x02).unwrap(),
+                IsoFileId::new(0xE104).unwrap(),
+                CommunicationMode::Plain,
+                AccessRights::from_bytes([0xE0, 0xEE]),
+                U24::new(0x80).unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(desfire.executor().transport().index, 1);
+    }
+
+    #[test]
+    fn reads_df_names_one_frame_per_application() {
+        let transport = MockTransport::new([
+            (
+                &[0x6D][..],
+                &[
+                    0xAF, 0x01, 0x00, 0xF0, 0x05, 0xE1, 0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01,
+                ][..],
+            ),
+            (
+                &[0xAF][..],
+                &[
+                    0x00, 0x02, 0x00, 0xF0, 0x10, 0xE1, 0xA0, 0x00, 0x00, 0x00, 0x01,
+                ][..],
+            ),
+        ]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        let mut names: Vec<crate::mifare::desfire::iso::DfNameEntry, 4> = Vec::new();
+
+        desfire.get_df_names(&mut names).unwrap();
+
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[0].application_id().as_u32(), 0xF0_00_01);
+        assert_eq!(names[0].iso_file_id().as_u16(), 0xE105);
+        assert_eq!(names[1].application_id().as_u32(), 0xF0_00_02);
+        assert_eq!(
+            names[1].df_name().as_bytes(),
+            &[0xA0, 0x00, 0x00, 0x00, 0x01]
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn checks_df_names_mac_under_aes_session() {
+        use crate::mifare::desfire::crypto::AesCmacChaining;
+
+        let sk = AesSessionKey::new([0x5C; 16]);
+        let first = [
+            0x01, 0x00, 0xF0, 0x05, 0xE1, 0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01,
+        ];
+        let last = [0x02, 0x00, 0xF0, 0x10, 0xE1, 0xA0, 0x00, 0x00, 0x00, 0x01];
+        // CMAC over [6D] then over both entries and the status, with the chained IV.
+        let mac = [0xB1, 0xF8, 0xBE, 0x70, 0x19, 0xE0, 0x81, 0x02];
+
+        let mut chaining = AesCmacChaining::new();
+        chaining.update(&sk, &[0x6D]);
+        let mut response_input = std::vec::Vec::new();
+        response_input.extend_from_slice(&first);
+        response_input.extend_from_slice(&last);
+        response_input.push(0x00);
+        chaining.update(&sk, &response_input);
+
+        let exchanges = |mac: &[u8]| {
+            let mut rx_first = std::vec![0xAF];
+            rx_first.extend_from_slice(&first);
+            let mut rx_last = std::vec![0x00];
+            rx_last.extend_from_slice(&last);
+            rx_last.extend_from_slice(mac);
+            std::vec![(std::vec![0x6D], rx_first), (std::vec![0xAF], rx_last)]
+        };
+        let session = || {
+            Session::Authenticated(AuthenticatedSession::new_aes(
+                KeyNumber::new(0).unwrap(),
+                sk.clone(),
+            ))
+        };
+
+        let mut desfire = Desfire::new(DynMockTransport::new(exchanges(&mac)), NativeFraming);
+        desfire.session = session();
+        let mut names: Vec<crate::mifare::desfire::iso::DfNameEntry, 4> = Vec::new();
+
+        desfire.get_df_names(&mut names).unwrap();
+
+        assert_eq!(names.len(), 2);
+        assert_eq!(
+            names[1].df_name().as_bytes(),
+            &[0xA0, 0x00, 0x00, 0x00, 0x01]
+        );
+        assert_eq!(
+            desfire
+                .authenticated_session()
+                .unwrap()
+                .aes_state()
+                .unwrap()
+                .1,
+            chaining
+        );
+
+        let mut tampered = mac;
+        tampered[7] ^= 0x01;
+        let mut desfire = Desfire::new(DynMockTransport::new(exchanges(&tampered)), NativeFraming);
+        desfire.session = session();
+        names.clear();
+
+        assert_eq!(desfire.get_df_names(&mut names), Err(Error::InvalidMac));
+    }
+
+    #[test]
+    fn reads_iso_file_ids() {
+        let transport = MockTransport::new([(&[0x61][..], &[0x00, 0x03, 0xE1, 0x04, 0xE1][..])]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        let mut ids: Vec<IsoFileId, 4> = Vec::new();
+
+        desfire.get_iso_file_ids(&mut ids).unwrap();
+
+        assert_eq!(
+            ids.as_slice(),
+            &[
+                IsoFileId::new(0xE103).unwrap(),
+                IsoFileId::new(0xE104).unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn iso_select_clears_session_and_reads_binary() {
+        let transport = MockTransport::new([
+            (
+                &[
+                    0x00, 0xA4, 0x04, 0x0C, 0x07, 0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01,
+                ][..],
+                &[0x90, 0x00][..],
+            ),
+            (
+                &[0x00, 0xA4, 0x00, 0x0C, 0x02, 0xE1, 0x04][..],
+                &[0x90, 0x00][..],
+            ),
+            (
+                &[0x00, 0xB0, 0x00, 0x02, 0x03][..],
+                &[0xD1, 0x01, 0x0C, 0x90, 0x00][..],
+            ),
+        ]);
+        let mut desfire = Desfire::new(transport, WrappedFraming);
+        desfire.session = Session::Authenticated(AuthenticatedSession::new_aes(
+            KeyNumber::new(0).unwrap(),
+            AesSessionKey::new([0u8; 16]),
+        ));
+        let mut data: Vec<u8, 8> = Vec::new();
+
+        desfire
+            .iso_select_file(&IsoSelect::DfName(
+                DfName::new(&[0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01]).unwrap(),
+            ))
+            .unwrap();
+        assert_eq!(*desfire.session(), Session::Unauthenticated);
+        desfire
+            .iso_select_file(&IsoSelect::FileId(IsoFileId::new(0xE104).unwrap()))
+            .unwrap();
+        desfire.iso_read_binary(2, 3, &mut data).unwrap();
+
+        assert_eq!(data.as_slice(), &[0xD1, 0x01, 0x0C]);
+    }
+
+    #[test]
+    fn iso_updates_binary_and_reads_records() {
+        let transport = MockTransport::new([
+            (
+                &[0x00, 0xD6, 0x00, 0x00, 0x02, 0x00, 0x0C][..],
+                &[0x90, 0x00][..],
+            ),
+            (
+                &[0x00, 0xB2, 0x01, 0x05, 0x00][..],
+                &[0x01, 0x02, 0x03, 0x04, 0x90, 0x00][..],
+            ),
+            (&[0x00, 0xB2, 0x03, 0x04, 0x00][..], &[0x6A, 0x83][..]),
+        ]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        let mut data: Vec<u8, 8> = Vec::new();
+
+        desfire.iso_update_binary(0, &[0x00, 0x0C]).unwrap();
+        desfire.iso_read_records(1, true, &mut data).unwrap();
+        assert_eq!(data.as_slice(), &[0x01, 0x02, 0x03, 0x04]);
+
+        assert_eq!(
+            desfire.iso_read_records(3, false, &mut data),
+            Err(Error::IsoStatus(0x6A83))
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn gets_card_uid_with_aes_session() {
+        use crate::mifare::desfire::crypto::{desfire_crc32, AesCmacChaining};
+
+        let sk = AesSessionKey::new([
+            0x01, 0x02, 0x03, 0x04, 0x7C, 0xB5, 0xEA, 0x83, 0x13, 0x14, 0x15, 0x16, 0xD8, 0x51,
+            0xEF, 0x57,
+        ]);
+        let uid = [0x04, 0x51, 0x2A, 0x9A, 0x6B, 0x3C, 0x80];
+
+        // Response is E(UID || CRC32(UID || status) || padding) under the chained IV.
+        let mut chaining = AesCmacChaining::new();
+        chaining.update(&sk, &[0x51]);
+        let mut crc_input = uid.to_vec();
+        crc_input.push(0x00);
+        let mut ciphertext = [0u8; 16];
+        ciphertext[..7].copy_from_slice(&uid);
+        ciphertext[7..11].copy_from_slice(&desfire_crc32(&crc_input));
+        aes_cbc_encrypt_in_place(sk.as_bytes(), &chaining.state(), &mut ciphertext);
+
+        let mut rx = std::vec![0x00];
+        rx.extend_from_slice(&ciphertext);
+
+        let transport = DynMockTransport::new(std::vec![(std::vec![0x51], rx)]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::Authenticated(AuthenticatedSession::new_aes(
+            KeyNumber::new(0).unwrap(),
+            sk,
+        ));
+
+        assert_eq!(desfire.get_card_uid().unwrap(), uid);
+    }
+
+    #[test]
+    fn rejects_card_uid_without_authentication() {
+        let transport = MockTransport::new([]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        assert_eq!(desfire.get_card_uid(), Err(Error::MissingAuthentication));
+        assert_eq!(desfire.executor().transport().index, 0);
+    }
+
+    fn set_configuration_aes_session() -> Session {
+        Session::Authenticated(AuthenticatedSession::new_aes(
+            KeyNumber::new(0).unwrap(),
+            AesSessionKey::new([
+                0x01, 0x02, 0x03, 0x04, 0x7C, 0xB5, 0xEA, 0x83, 0x13, 0x14, 0x15, 0x16, 0xD8, 0x51,
+                0xEF, 0x57,
+            ]),
+        ))
+    }
+
+    #[test]
+    fn sets_picc_configuration_with_aes_session() {
+        let transport = MockTransport::new([(
+            &[
+                0x5C, 0x00, 0x28, 0xFF, 0x5A, 0xE2, 0x8F, 0x2C, 0x2A, 0x4C, 0x58, 0xD0, 0xBE, 0x03,
+                0x09, 0x23, 0xFF, 0xDB,
+            ][..],
+            &[0x00, 0x8F, 0xDF, 0x2A, 0x04, 0x43, 0x4C, 0xF3, 0xEF][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = set_configuration_aes_session();
+
+        desfire
+            .set_picc_configuration(
+                PiccConfiguration::new()
+                    .with_format_disabled(true)
+                    .with_random_uid(true),
             )
             .unwrap();
 
-        let settings = desfire
-            .get_file_settings(FileId::new(0x03).unwrap())
-            .unwrap();
-        assert_eq!(settings.communication_mode(), CommunicationMode::Plain);
+        assert_eq!(desfire.executor().transport().index, 1);
+    }
+
+    #[test]
+    fn sets_default_key_with_aes_session() {
+        let transport = MockTransport::new([(
+            &[
+                0x5C, 0x01, 0xFF, 0xC4, 0xE9, 0xE1, 0x1C, 0x67, 0x29, 0x07, 0xED, 0xF5, 0x26, 0xAF,
+                0x83, 0x05, 0x70, 0xCA, 0x5A, 0x8C, 0x9F, 0x5A, 0xBF, 0xE2, 0x14, 0x3C, 0x07, 0xD2,
+                0x5A, 0x67, 0x72, 0xDA, 0x44, 0x07,
+            ][..],
+            &[0x00, 0x5B, 0x2B, 0xC8, 0xD7, 0xBC, 0xBA, 0x84, 0x92][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = set_configuration_aes_session();
 
         desfire
-            .write_data(
-                FileId::new(0x03).unwrap(),
-                U24::new(0).unwrap(),
-                &[
-                    0xF0, 0xE0, 0xD0, 0xC0, 0xB0, 0xA0, 0x90, 0x80, 0x70, 0x60, 0x50, 0x40, 0x30,
-                    0x20, 0x10,
-                ],
-            )
+            .set_default_key(DefaultKey::new(
+                Key::Aes128([
+                    0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x1C,
+                    0x1D, 0x1E, 0x1F,
+                ]),
+                0x01,
+            ))
             .unwrap();
+
+        assert_eq!(desfire.executor().transport().index, 1);
     }
 
     #[test]
-    fn reads_free_memory() {
-        let transport = MockTransport::new([(&[0x6E][..], &[0x00, 0x20, 0x03, 0x00][..])]);
+    fn sets_ats_with_crc_terminator_under_aes_session() {
+        let transport = MockTransport::new([(
+            &[
+                0x5C, 0x02, 0x1B, 0xB9, 0xF5, 0x58, 0xB0, 0x35, 0xF7, 0xBB, 0x0D, 0xF0, 0x9D, 0x36,
+                0x6C, 0x7B, 0x41, 0x58,
+            ][..],
+            &[0x00, 0x90, 0x78, 0x0C, 0xE9, 0x14, 0xAE, 0xB9, 0xD0][..],
+        )]);
         let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = set_configuration_aes_session();
 
-        let memory = desfire.free_memory().unwrap();
+        desfire
+            .set_ats(&Ats::new(&[0x06, 0x75, 0x77, 0x81, 0x02, 0x80]).unwrap())
+            .unwrap();
 
-        assert_eq!(memory.as_u32(), 800);
+        assert_eq!(desfire.executor().transport().index, 1);
     }
 
     #[test]
-    fn reads_file_settings() {
+    fn sets_picc_configuration_with_ev2_session() {
         let transport = MockTransport::new([(
-            &[0xF5, 0x01][..],
-            &[0x00, 0x00, 0x00, 0xEE, 0xEE, 0x20, 0x00, 0x00][..],
+            &[
+                0x5C, 0x00, 0xD2, 0x68, 0x34, 0x11, 0xBC, 0x63, 0x70, 0xEB, 0xDA, 0x2F, 0xD1, 0x3B,
+                0x63, 0x71, 0xDD, 0x89, 0xBE, 0x52, 0x69, 0x31, 0x75, 0x61, 0x7C, 0xE0,
+            ][..],
+            &[0x00, 0xFC, 0x22, 0x2E, 0x5F, 0x7A, 0x54, 0x24, 0x52][..],
         )]);
         let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_ev2_session(0));
 
-        let settings = desfire
-            .get_file_settings(FileId::new(0x01).unwrap())
+        desfire
+            .set_picc_configuration(PiccConfiguration::new().with_random_uid(true))
             .unwrap();
 
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 1);
+    }
+
+    #[test]
+    fn rejects_set_configuration_without_authentication() {
+        let transport = MockTransport::new([]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
         assert_eq!(
-            settings.details(),
-            FileSettingsDetails::Data {
-                size: U24::new(32).unwrap()
+            desfire.set_picc_configuration(PiccConfiguration::new().with_format_disabled(true)),
+            Err(Error::MissingAuthentication)
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn streams_plain_write_across_chunks_and_frames() {
+        let data: std::vec::Vec<u8> = (0..250u8).collect();
+
+        // 192-byte WriteData chunks, each split into 54-byte frames.
+        let mut exchanges = std::vec::Vec::new();
+        for (offset, chunk) in [(0x00u8, &data[..192]), (0xC0, &data[192..])] {
+            let length = u8::try_from(chunk.len()).unwrap();
+            let mut payload = std::vec![0x05, offset, 0x00, 0x00, length, 0x00, 0x00];
+            payload.extend_from_slice(chunk);
+            let frames: std::vec::Vec<&[u8]> = payload.chunks(54).collect();
+            for (index, frame) in frames.iter().enumerate() {
+                let code = if index == 0 { 0x3D } else { 0xAF };
+                let status = if index + 1 == frames.len() {
+                    0x00
+                } else {
+                    0xAF
+                };
+                let mut tx = std::vec![code];
+                tx.extend_from_slice(frame);
+                exchanges.push((tx, std::vec![status]));
+            }
+        }
+        assert_eq!(exchanges.len(), 6);
+
+        let transport = DynMockTransport::new(exchanges);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        desfire
+            .write_data_streamed(
+                FileId::new(0x05).unwrap(),
+                CommunicationMode::Plain,
+                U24::new(0).unwrap(),
+                &data,
+            )
+            .unwrap();
+
+        assert_eq!(desfire.executor().transport().index, 6);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn streams_maced_read_across_chunks_with_aes_session() {
+        use crate::mifare::desfire::crypto::AesCmacChaining;
+
+        let sk = AesSessionKey::new([
+            0x01, 0x02, 0x03, 0x04, 0x7C, 0xB5, 0xEA, 0x83, 0x13, 0x14, 0x15, 0x16, 0xD8, 0x51,
+            0xEF, 0x57,
+        ]);
+        let data: std::vec::Vec<u8> = (0..200u8).map(|byte| byte ^ 0x5A).collect();
+
+        // Each chunk is its own ReadData; the card answers in 59-byte frames and
+        // the response MAC covers the whole chunk.
+        let mut chaining = AesCmacChaining::new();
+        let mut exchanges = std::vec::Vec::new();
+        for (offset, chunk) in [(0x00u8, &data[..192]), (0xC0, &data[192..])] {
+            let length = u8::try_from(chunk.len()).unwrap();
+            let mut command = std::vec![0xBD, 0x01, offset, 0x00, 0x00, length, 0x00, 0x00];
+            chaining.update(&sk, &command);
+
+            let mut mac_input = chunk.to_vec();
+            mac_input.push(0x00);
+            let mut response = chunk.to_vec();
+            response.extend_from_slice(&chaining.update(&sk, &mac_input).desfire_mac().as_bytes());
+
+            let frames: std::vec::Vec<&[u8]> = response.chunks(59).collect();
+            for (index, frame) in frames.iter().enumerate() {
+                let status = if index + 1 == frames.len() {
+                    0x00
+                } else {
+                    0xAF
+                };
+                let mut rx = std::vec![status];
+                rx.extend_from_slice(frame);
+                exchanges.push((command, rx));
+                command = std::vec![0xAF];
             }
+        }
+
+        let transport = DynMockTransport::new(exchanges);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::Authenticated(AuthenticatedSession::new_aes(
+            KeyNumber::new(0).unwrap(),
+            sk,
+        ));
+
+        let mut buffer = [0u8; 200];
+        desfire
+            .read_data_streamed(
+                FileId::new(0x01).unwrap(),
+                CommunicationMode::Maced,
+                U24::new(0).unwrap(),
+                U24::new(200).unwrap(),
+                &mut &mut buffer[..],
+            )
+            .unwrap();
+
+        assert_eq!(buffer.as_slice(), data.as_slice());
+        assert_eq!(desfire.executor().transport().index, 5);
+        assert_eq!(
+            desfire
+                .authenticated_session()
+                .unwrap()
+                .aes_state()
+                .unwrap()
+                .1,
+            chaining
         );
     }
 
     #[test]
-    fn reads_plain_data() {
-        let transport = MockTransport::new([(
-            &[0xBD, 0x01, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00][..],
-            &[0x00, 0xDE, 0xAD, 0xBE, 0xEF][..],
-        )]);
+    fn rejects_streamed_read_beyond_u24_range() {
+        let transport = MockTransport::new([]);
         let mut desfire = Desfire::new(transport, NativeFraming);
         let mut data: Vec<u8, 4> = Vec::new();
 
-        desfire
-            .read_data(
+        let err = desfire
+            .read_data_streamed(
                 FileId::new(0x01).unwrap(),
+                CommunicationMode::Plain,
+                U24::new(U24::MAX).unwrap(),
                 U24::new(2).unwrap(),
-                U24::new(4).unwrap(),
                 &mut data,
             )
-            .unwrap();
+            .unwrap_err();
 
-        assert_eq!(data.as_slice(), &[0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(err, Error::CommandTooLong);
+        assert_eq!(desfire.executor().transport().index, 0);
     }
 
-    // Proxmark trace: `hf mfdes createapp --aid 222222 --no-auth --dstalgo aes --numkeys 1`
-    // TX: 90 CA 00 00 05 22 22 22 0F 81 00  (wrapped; native: CA 22 22 22 0F 81)
-    // RX: 91 00  (wrapped; native: 00)  — no MAC when unauthenticated
     #[test]
-    fn change_file_settings_2tdea_proxmark_trace() {
-        // Proxmark3 trace: hf mfdes chfilesettings --aid 222222 --fid 01
-        //   --rrights key1 --wrights key1 --rwrights key1 --chrights key0 -n 0 -t 2tdea
-        // Session key: 01 02 03 04 51 99 63 96 01 02 03 04 51 99 63 96
-        // GetFileSettings cmd:  90 F5 00 00 01 01 00
-        //                 rsp:  00 00 10 11 10 00 00 0C 38 70 35 84 D6 7E 10 91 00
-        // ChangeFileSettings cmd: 90 5F 00 00 09 01 6A 40 3C 01 E8 88 23 2F 00
-        //                   rsp: 10 CD 6F 8D C3 D3 42 B9 91 00
-        let session_key = TwoKey3DesSessionKey::new([
-            0x01, 0x02, 0x03, 0x04, 0x51, 0x99, 0x63, 0x96, 0x01, 0x02, 0x03, 0x04, 0x51, 0x99,
-            0x63, 0x96,
-        ]);
-        let auth_session = AuthenticatedSession::new_2tdea(KeyNumber::new(0).unwrap(), session_key);
+    fn creates_application_with_key_sets() {
+        let transport = MockTransport::new([(
+            &[
+                0xCA, 0x01, 0x00, 0x00, 0x0F, 0x92, 0x01, 0x00, 0x03, 0x10, 0x00,
+            ][..],
+            &[0x00][..],
+        )]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+
+        desfire
+            .create_application_with_key_sets(
+                ApplicationId::new(0x01).unwrap(),
+                KeySettings::new(0x0F, ApplicationKeyType::Aes, 2),
+                KeySetOptions::new(3, ApplicationKeyType::Aes).unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(desfire.executor().transport().index, 1);
+    }
 
+    #[test]
+    fn stages_key_set_with_ev2_session() {
         let transport = MockTransport::new([
             (
-                &[0x90, 0xF5, 0x00, 0x00, 0x01, 0x01, 0x00][..],
                 &[
-                    0x00, 0x00, 0x10, 0x11, 0x10, 0x00, 0x00, 0x0C, 0x38, 0x70, 0x35, 0x84, 0xD6,
-                    0x7E, 0x10, 0x91, 0x00,
+                    0x56, 0x01, 0x02, 0x46, 0xB8, 0x5D, 0x80, 0x6F, 0x82, 0x8E, 0xA7,
                 ][..],
+                &[0x00, 0x18, 0x14, 0x12, 0x00, 0xE0, 0x42, 0x0D, 0x1C][..],
             ),
             (
                 &[
-                    0x90, 0x5F, 0x00, 0x00, 0x09, 0x01, 0x6A, 0x40, 0x3C, 0x01, 0xE8, 0x88, 0x23,
-                    0x2F, 0x00,
+                    0x57, 0x01, 0x01, 0x49, 0x7B, 0xBB, 0x49, 0xD3, 0x81, 0x3A, 0x3C,
                 ][..],
-                &[0x10, 0xCD, 0x6F, 0x8D, 0xC3, 0xD3, 0x42, 0xB9, 0x91, 0x00][..],
+                &[0x00, 0x6C, 0xA0, 0x00, 0xD0, 0x31, 0x73, 0x85, 0x01][..],
             ),
         ]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_ev2_session(3));
+        let key_set = KeySetNumber::new(1).unwrap();
 
-        let mut desfire = Desfire::new(transport, WrappedFraming);
-        desfire.session = Session::Authenticated(auth_session);
-
-        let key0 = AccessCondition::Key(KeyNumber::new(0).unwrap());
-        let key1 = AccessCondition::Key(KeyNumber::new(1).unwrap());
-        let access_rights = AccessRights::new(key1, key1, key1, key0);
-
-        desfire.get_file_settings(FileId::new(1).unwrap()).unwrap();
         desfire
-            .change_file_settings(
-                FileId::new(1).unwrap(),
-                CommunicationMode::Plain,
-                access_rights,
-            )
+            .initialize_key_set(key_set, ApplicationKeyType::Aes)
             .unwrap();
+        desfire.finalize_key_set(key_set, 0x01).unwrap();
 
-        assert_eq!(desfire.executor().transport().index, 2);
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 5);
     }
 
     #[test]
-    fn read_data_maced_2tdea_zero_key1_proxmark_trace() {
-        // Proxmark3 trace: hf mfdes read --aid 222222 --fid 01 -n 1 -t 2tdea
-        // Session key: 01 02 03 04 FC 67 24 71 01 02 03 04 FC 67 24 71
-        // GetFileSettings cmd:  90 F5 00 00 01 01 00
-        //                 rsp:  00 00 10 11 10 00 00 4F 25 14 78 C4 F0 66 FC 91 00
-        // ReadData cmd:  90 BD 00 00 07 01 00 00 00 00 00 00 00 00
-        //          rsp:  00*16 AF 4E 90 AE AE 3A DC F4 91 00
-        let session_key = TwoKey3DesSessionKey::new([
-            0x01, 0x02, 0x03, 0x04, 0xFC, 0x67, 0x24, 0x71, 0x01, 0x02, 0x03, 0x04, 0xFC, 0x67,
-            0x24, 0x71,
-        ]);
-        let auth_session = AuthenticatedSession::new_2tdea(KeyNumber::new(1).unwrap(), session_key);
-
+    fn changes_staged_key_set_key_and_rolls_with_ev2_session() {
         let transport = MockTransport::new([
             (
-                &[0x90, 0xF5, 0x00, 0x00, 0x01, 0x01, 0x00][..],
                 &[
-                    0x00, 0x00, 0x10, 0x11, 0x10, 0x00, 0x00, 0x4F, 0x25, 0x14, 0x78, 0xC4, 0xF0,
-                    0x66, 0xFC, 0x91, 0x00,
+                    0xC6, 0x01, 0x00, 0x17, 0x5B, 0xC6, 0x02, 0x58, 0x91, 0x55, 0x76, 0x32, 0x7E,
+                    0x1C, 0x0F, 0x8F, 0x48, 0x37, 0x48, 0x15, 0xFA, 0x3D, 0x92, 0x3D, 0x45, 0x97,
+                    0xC8, 0xAC, 0x85, 0x67, 0x62, 0x24, 0xB3, 0x94, 0xEA, 0x48, 0x3C, 0xBD, 0x81,
+                    0x9F, 0x87, 0xF7, 0x3F,
                 ][..],
+                &[0x00, 0x95, 0x4E, 0xB5, 0x02, 0xC5, 0xC0, 0x7B, 0x7B][..],
             ),
             (
-                &[
-                    0x90, 0xBD, 0x00, 0x00, 0x07, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                ][..],
-                &[
-                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                    0x00, 0x00, 0x00, 0xAF, 0x4E, 0x90, 0xAE, 0xAE, 0x3A, 0xDC, 0xF4, 0x91, 0x00,
-                ][..],
+                &[0x55, 0x01, 0xE1, 0xC6, 0x89, 0x07, 0x00, 0xF8, 0x57, 0x30][..],
+                &[0x00][..],
             ),
         ]);
+        let mut desfire = Desfire::new(transport, NativeFraming);
+        desfire.session = Session::AuthenticatedEv2(nxp_ev2_session(5));
+        let key_set = KeySetNumber::new(1).unwrap();
 
-        let mut desfire = Desfire::new(transport, WrappedFraming);
-        desfire.session = Session::Authenticated(auth_session);
-
-        let mut data: Vec<u8, 32> = Vec::new();
-        desfire.get_file_settings(FileId::new(1).unwrap()).unwrap();
+        // Key 0 of a staged key set is not the authenticated key, so the XOR form is used.
         desfire
-            .read_data_maced(
-                FileId::new(1).unwrap(),
-                U24::new(0).unwrap(),
-                U24::new(0).unwrap(),
-                &mut data,
+            .change_key_ev2_aes(
+                key_set,
+                KeyNumber::new(0).unwrap(),
+                [
+                    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C,
+                    0x0D, 0x0E, 0x0F,
+                ],
+                0x01,
+                Some([0u8; 16]),
             )
             .unwrap();
+        assert_eq!(desfire.ev2_session().unwrap().command_counter(), 6);
 
-        assert_eq!(data.as_slice(), &[0u8; 16]);
+        desfire.roll_key_set(key_set).unwrap();
+
+        assert_eq!(*desfire.session(), Session::Unauthenticated);
         assert_eq!(desfire.executor().transport().index, 2);
     }
 
     #[test]
-    fn creates_application_unauthenticated() {
-        let transport =
-            MockTransport::new([(&[0xCA, 0x22, 0x22, 0x22, 0x0F, 0x81][..], &[0x00][..])]);
+    fn rejects_roll_key_set_without_authentication() {
+        let transport = MockTransport::new([]);
         let mut desfire = Desfire::new(transport, NativeFraming);
 
-        let aid = crate::mifare::desfire::ApplicationId::new(0x22_22_22).unwrap();
-        let ks = crate::mifare::desfire::KeySettings::new(0x0F, ApplicationKeyType::Aes, 1);
-
-        desfire.create_application(aid, ks).unwrap();
-
-        assert_eq!(desfire.executor().transport().index, 1);
-    }
-
-    #[test]
-    fn changes_2tdea_app_key_different_key_proxmark_trace() {
-        // Proxmark3 trace: hf mfdes changekey --aid 222222 -t 2tdea -n 0 --newkeyno 1 --verbose --apdu
-        // Auth key 0: 2tdea zero key.  New/old key 1: 2tdea zero key.
-        // Session key: 01 02 03 04 82 E4 29 94 01 02 03 04 82 E4 29 94
-        // Command: 90 C4 00 00 19 01 13 85 5D BB EC 9A 5C CB BB 34 92 D8 97 98 DC 8D AE C7 52 BF ED 26 55 AF 00
-        // Response: 2D 23 DD E4 FF 07 EB 0E 91 00
-        let session_key = TwoKey3DesSessionKey::new([
-            0x01, 0x02, 0x03, 0x04, 0x82, 0xE4, 0x29, 0x94, 0x01, 0x02, 0x03, 0x04, 0x82, 0xE4,
-            0x29, 0x94,
-        ]);
-        let auth_session = AuthenticatedSession::new_2tdea(KeyNumber::new(0).unwrap(), session_key);
-
-        let transport = MockTransport::new([(
-            &[
-                0x90, 0xC4, 0x00, 0x00, 0x19, 0x01, 0x13, 0x85, 0x5D, 0xBB, 0xEC, 0x9A, 0x5C, 0xCB,
-                0xBB, 0x34, 0x92, 0xD8, 0x97, 0x98, 0xDC, 0x8D, 0xAE, 0xC7, 0x52, 0xBF, 0xED, 0x26,
-                0x55, 0xAF, 0x00,
-            ][..],
-            &[0x2D, 0x23, 0xDD, 0xE4, 0xFF, 0x07, 0xEB, 0x0E, 0x91, 0x00][..],
-        )]);
-
-        let mut desfire = Desfire::new(transport, WrappedFraming);
-        desfire.session = Session::Authenticated(auth_session);
-
-        desfire
-            .change_key_2tdea(KeyNumber::new(1).unwrap(), [0u8; 16], Some([0u8; 16]))
-            .unwrap();
-
-        assert!(matches!(desfire.session, Session::Authenticated(_)));
-        assert_eq!(desfire.executor().transport().index, 1);
+        assert_eq!(
+            desfire.roll_key_set(KeySetNumber::new(1).unwrap()),
+            Err(Error::MissingAuthentication)
+        );
+        assert_eq!(desfire.executor().transport().index, 0);
     }
 }
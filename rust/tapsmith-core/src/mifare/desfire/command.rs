@@ -17,12 +17,21 @@ impl CommandCode {
     pub const AUTHENTICATE_AES: Self = Self(0xAA);
     pub const AUTHENTICATE_ISO: Self = Self(0x1A);
     pub const AUTHENTICATE_LEGACY: Self = Self(0x0A);
+    pub const AUTHENTICATE_EV2_FIRST: Self = Self(0x71);
+    pub const AUTHENTICATE_EV2_NON_FIRST: Self = Self(0x77);
     pub const GET_KEY_SETTINGS: Self = Self(0x45);
     pub const GET_KEY_VERSION: Self = Self(0x64);
     pub const GET_VERSION: Self = Self(0x60);
+    pub const GET_CARD_UID: Self = Self(0x51);
+    pub const READ_SIG: Self = Self(0x3C);
     pub const GET_APPLICATION_IDS: Self = Self(0x6A);
     pub const FORMAT_PICC: Self = Self(0xFC);
+    pub const SET_CONFIGURATION: Self = Self(0x5C);
     pub const CHANGE_KEY: Self = Self(0xC4);
+    pub const CHANGE_KEY_EV2: Self = Self(0xC6);
+    pub const INITIALIZE_KEY_SET: Self = Self(0x56);
+    pub const FINALIZE_KEY_SET: Self = Self(0x57);
+    pub const ROLL_KEY_SET: Self = Self(0x55);
     pub const COMMIT_TRANSACTION: Self = Self(0xC7);
     pub const ABORT_TRANSACTION: Self = Self(0xA7);
     pub const CREATE_APPLICATION: Self = Self(0xCA);
@@ -30,6 +39,8 @@ impl CommandCode {
     pub const SELECT_APPLICATION: Self = Self(0x5A);
     pub const FREE_MEM: Self = Self(0x6E);
     pub const GET_FILE_IDS: Self = Self(0x6F);
+    pub const GET_ISO_FILE_IDS: Self = Self(0x61);
+    pub const GET_DF_NAMES: Self = Self(0x6D);
     pub const GET_FILE_SETTINGS: Self = Self(0xF5);
     pub const CHANGE_FILE_SETTINGS: Self = Self(0x5F);
     pub const CREATE_STD_DATA_FILE: Self = Self(0xCD);
@@ -40,6 +51,15 @@ impl CommandCode {
     pub const DELETE_FILE: Self = Self(0xDF);
     pub const READ_DATA: Self = Self(0xBD);
     pub const WRITE_DATA: Self = Self(0x3D);
+    pub const READ_DATA_ISO: Self = Self(0xAD);
+    pub const WRITE_DATA_ISO: Self = Self(0x8D);
+    pub const GET_VALUE: Self = Self(0x6C);
+    pub const CREDIT: Self = Self(0x0C);
+    pub const DEBIT: Self = Self(0xDC);
+    pub const LIMITED_CREDIT: Self = Self(0x1C);
+    pub const WRITE_RECORD: Self = Self(0x3B);
+    pub const READ_RECORDS: Self = Self(0xBB);
+    pub const CLEAR_RECORD_FILE: Self = Self(0xEB);
 
     /// Creates a command code from its raw `DESFire` byte.
     pub const fn new(value: u8) -> Self {
@@ -0,0 +1,165 @@
+use heapless::Vec;
+
+use crate::mifare::desfire::{error::Error, key::Key};
+
+/// PICC configuration flags written with `SetConfiguration` option `0x00`.
+///
+/// Both flags are permanent: once set, the card cannot clear them again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PiccConfiguration {
+    format_disabled: bool,
+    random_uid: bool,
+}
+
+impl PiccConfiguration {
+    /// Configuration with both flags cleared.
+    pub const fn new() -> Self {
+        Self {
+            format_disabled: false,
+            random_uid: false,
+        }
+    }
+
+    /// Disables `FormatPICC`.
+    #[must_use]
+    pub const fn with_format_disabled(self, format_disabled: bool) -> Self {
+        Self {
+            format_disabled,
+            ..self
+        }
+    }
+
+    /// Enables random UID during anticollision.
+    #[must_use]
+    pub const fn with_random_uid(self, random_uid: bool) -> Self {
+        Self { random_uid, ..self }
+    }
+
+    /// Whether `FormatPICC` is disabled.
+    pub const fn format_disabled(self) -> bool {
+        self.format_disabled
+    }
+
+    /// Whether random UID is enabled.
+    pub const fn random_uid(self) -> bool {
+        self.random_uid
+    }
+
+    /// Raw configuration byte.
+    pub const fn as_byte(self) -> u8 {
+        (self.format_disabled as u8) | ((self.random_uid as u8) << 1)
+    }
+}
+
+/// Default key and version written with `SetConfiguration` option `0x01`.
+///
+/// New applications start with this key in every slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefaultKey {
+    key: Key,
+    version: u8,
+}
+
+impl DefaultKey {
+    /// Creates a default key with its key version.
+    pub const fn new(key: Key, version: u8) -> Self {
+        Self { key, version }
+    }
+
+    /// Key material.
+    pub const fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Key version.
+    pub const fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Encodes `[key data zero-padded to 24 bytes || key version]`.
+    ///
+    /// Single DES keys are stored as a 16-byte key with equal halves.
+    pub fn to_bytes(&self) -> [u8; 25] {
+        let mut out = [0u8; 25];
+        match &self.key {
+            Key::Des(key) => {
+                out[..8].copy_from_slice(key);
+                out[8..16].copy_from_slice(key);
+            }
+            Key::TwoKey3Des(key) | Key::Aes128(key) => out[..16].copy_from_slice(key),
+            Key::ThreeKey3Des(key) => out[..24].copy_from_slice(key),
+        }
+        out[24] = self.version;
+        out
+    }
+}
+
+/// User-defined ATS written with `SetConfiguration` option `0x02`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ats(Vec<u8, 20>);
+
+impl Ats {
+    /// Maximum ATS length accepted by `DESFire`.
+    pub const MAX_LEN: usize = 20;
+
+    /// Creates a validated ATS.
+    ///
+    /// The first byte (`TL`) must equal the full ATS length.
+    pub fn new(value: &[u8]) -> Result<Self, Error> {
+        match value.first() {
+            Some(&tl) if usize::from(tl) == value.len() => Vec::from_slice(value)
+                .map(Self)
+                .map_err(|_| Error::InvalidAts),
+            _ => Err(Error::InvalidAts),
+        }
+    }
+
+    /// Raw ATS bytes, starting with `TL`.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::{
+        configuration::{Ats, DefaultKey, PiccConfiguration},
+        error::Error,
+        key::Key,
+    };
+
+    #[test]
+    fn encodes_picc_configuration_flags() {
+        assert_eq!(PiccConfiguration::new().as_byte(), 0x00);
+        assert_eq!(
+            PiccConfiguration::new()
+                .with_format_disabled(true)
+                .as_byte(),
+            0x01
+        );
+        assert_eq!(
+            PiccConfiguration::new()
+                .with_format_disabled(true)
+                .with_random_uid(true)
+                .as_byte(),
+            0x03
+        );
+    }
+
+    #[test]
+    fn pads_default_key_data_to_24_bytes() {
+        let bytes = DefaultKey::new(Key::Des([0x11; 8]), 0x05).to_bytes();
+
+        assert_eq!(&bytes[..16], &[0x11; 16]);
+        assert_eq!(&bytes[16..24], &[0x00; 8]);
+        assert_eq!(bytes[24], 0x05);
+    }
+
+    #[test]
+    fn validates_ats_length_byte() {
+        assert!(Ats::new(&[0x06, 0x75, 0x77, 0x81, 0x02, 0x80]).is_ok());
+        assert_eq!(Ats::new(&[0x05, 0x75]), Err(Error::InvalidAts));
+        assert_eq!(Ats::new(&[]), Err(Error::InvalidAts));
+        assert_eq!(Ats::new(&[0x15; 21]), Err(Error::InvalidAts));
+    }
+}
@@ -4,6 +4,10 @@ use aes::{
 };
 use des::{Des, TdesEde2, TdesEde3};
 
+use crate::mifare::desfire::{
+    error::Error, provider::CryptoProvider, rng::RandomSource, secret::SecretKey,
+};
+
 /// Reader challenge used during AES authentication.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RndA([u8; 16]);
@@ -14,6 +18,13 @@ impl RndA {
         Self(bytes)
     }
 
+    /// Draws a fresh reader challenge.
+    pub fn random<R: RandomSource + ?Sized>(random: &mut R) -> Result<Self, Error> {
+        let mut bytes = [0u8; 16];
+        random.fill_bytes(&mut bytes)?;
+        Ok(Self(bytes))
+    }
+
     /// Raw challenge bytes.
     pub const fn as_bytes(self) -> [u8; 16] {
         self.0
@@ -47,13 +58,13 @@ impl RndB {
 }
 
 /// AES session key derived from `RndA` and `RndB`.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct AesSessionKey([u8; 16]);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AesSessionKey(SecretKey<16>);
 
 impl AesSessionKey {
     /// Creates an AES session key from raw bytes.
     pub const fn new(bytes: [u8; 16]) -> Self {
-        Self(bytes)
+        Self(SecretKey::new(bytes))
     }
 
     /// Derives the `DESFire` AES session key from the reader and card challenges.
@@ -65,12 +76,48 @@ impl AesSessionKey {
         out[4..8].copy_from_slice(&rnd_b[0..4]);
         out[8..12].copy_from_slice(&rnd_a[12..16]);
         out[12..16].copy_from_slice(&rnd_b[12..16]);
-        Self(out)
+        Self::new(out)
+    }
+
+    /// Derives the EV2 `SesAuthENCKey` from the authentication key and challenges.
+    pub fn derive_ev2_encryption(key: &[u8; 16], rnd_a: RndA, rnd_b: RndB) -> Self {
+        Self::new(
+            AesCmac::calculate(key, &ev2_session_vector([0xA5, 0x5A], rnd_a, rnd_b)).as_bytes(),
+        )
+    }
+
+    /// Derives the EV2 `SesAuthMACKey` from the authentication key and challenges.
+    pub fn derive_ev2_mac(key: &[u8; 16], rnd_a: RndA, rnd_b: RndB) -> Self {
+        Self::new(
+            AesCmac::calculate(key, &ev2_session_vector([0x5A, 0xA5], rnd_a, rnd_b)).as_bytes(),
+        )
+    }
+
+    /// [`Self::derive_ev2_encryption`] with the CMAC computed by `provider`.
+    pub fn derive_ev2_encryption_with<P: CryptoProvider + ?Sized>(
+        provider: &mut P,
+        key: &P::KeyHandle,
+        rnd_a: RndA,
+        rnd_b: RndB,
+    ) -> Result<Self, Error> {
+        let sv1 = ev2_session_vector([0xA5, 0x5A], rnd_a, rnd_b);
+        Ok(Self::new(provider.cmac(key, &sv1)?.as_bytes()))
+    }
+
+    /// [`Self::derive_ev2_mac`] with the CMAC computed by `provider`.
+    pub fn derive_ev2_mac_with<P: CryptoProvider + ?Sized>(
+        provider: &mut P,
+        key: &P::KeyHandle,
+        rnd_a: RndA,
+        rnd_b: RndB,
+    ) -> Result<Self, Error> {
+        let sv2 = ev2_session_vector([0x5A, 0xA5], rnd_a, rnd_b);
+        Ok(Self::new(provider.cmac(key, &sv2)?.as_bytes()))
     }
 
     /// Raw session-key bytes.
-    pub const fn as_bytes(self) -> [u8; 16] {
-        self.0
+    pub const fn as_bytes(&self) -> &[u8; 16] {
+        self.0.as_bytes()
     }
 }
 
@@ -79,6 +126,11 @@ impl AesSessionKey {
 pub struct AesCmac([u8; 16]);
 
 impl AesCmac {
+    /// Wraps a CMAC computed elsewhere, such as by a crypto provider.
+    pub const fn new(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
     /// Calculates an AES-CMAC using the NIST SP 800-38B construction.
     pub fn calculate(key: &[u8; 16], data: &[u8]) -> Self {
         Self::calculate_chained(key, &[0u8; 16], data)
@@ -130,6 +182,12 @@ impl AesCmac {
     pub const fn desfire_mac(self) -> DesfireMac {
         DesfireMac::from_cmac(self)
     }
+
+    /// Returns the 8-byte EV2 `MACt` form (the odd-indexed CMAC bytes).
+    pub const fn ev2_mac(self) -> DesfireMac {
+        let b = self.0;
+        DesfireMac([b[1], b[3], b[5], b[7], b[9], b[11], b[13], b[15]])
+    }
 }
 
 /// 8-byte `DESFire` MAC value derived from the full AES-CMAC.
@@ -184,8 +242,8 @@ impl AesCmacChaining {
     }
 
     /// Calculates a chained CMAC and stores the full result as the next state.
-    pub fn update(&mut self, session_key: AesSessionKey, data: &[u8]) -> AesCmac {
-        let cmac = AesCmac::calculate_chained(&session_key.as_bytes(), &self.state, data);
+    pub fn update(&mut self, session_key: &AesSessionKey, data: &[u8]) -> AesCmac {
+        let cmac = AesCmac::calculate_chained(session_key.as_bytes(), &self.state, data);
         self.state = cmac.as_bytes();
         cmac
     }
@@ -206,6 +264,13 @@ impl RndA8 {
         Self(bytes)
     }
 
+    /// Draws a fresh reader challenge.
+    pub fn random<R: RandomSource + ?Sized>(random: &mut R) -> Result<Self, Error> {
+        let mut bytes = [0u8; 8];
+        random.fill_bytes(&mut bytes)?;
+        Ok(Self(bytes))
+    }
+
     pub const fn as_bytes(self) -> [u8; 8] {
         self.0
     }
@@ -234,16 +299,16 @@ impl RndB8 {
 }
 
 /// DES session key (8 bytes).
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct DesSessionKey([u8; 8]);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesSessionKey(SecretKey<8>);
 
 impl DesSessionKey {
     pub const fn new(bytes: [u8; 8]) -> Self {
-        Self(bytes)
+        Self(SecretKey::new(bytes))
     }
 
-    pub const fn as_bytes(self) -> [u8; 8] {
-        self.0
+    pub const fn as_bytes(&self) -> &[u8; 8] {
+        self.0.as_bytes()
     }
 
     /// Derives the `DESFire` single-DES session key: `RndA[0..4] || RndB[0..4]`.
@@ -251,21 +316,21 @@ impl DesSessionKey {
         let mut out = [0u8; 8];
         out[0..4].copy_from_slice(&rnd_a.as_bytes()[0..4]);
         out[4..8].copy_from_slice(&rnd_b.as_bytes()[0..4]);
-        Self(out)
+        Self::new(out)
     }
 }
 
 /// Two-key triple-DES session key (16 bytes).
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct TwoKey3DesSessionKey([u8; 16]);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TwoKey3DesSessionKey(SecretKey<16>);
 
 impl TwoKey3DesSessionKey {
     pub const fn new(bytes: [u8; 16]) -> Self {
-        Self(bytes)
+        Self(SecretKey::new(bytes))
     }
 
-    pub const fn as_bytes(self) -> [u8; 16] {
-        self.0
+    pub const fn as_bytes(&self) -> &[u8; 16] {
+        self.0.as_bytes()
     }
 
     /// Derives the `DESFire` 2TDEA session key:
@@ -278,14 +343,14 @@ impl TwoKey3DesSessionKey {
         out[4..8].copy_from_slice(&b[0..4]);
         out[8..12].copy_from_slice(&a[4..8]);
         out[12..16].copy_from_slice(&b[4..8]);
-        Self(out)
+        Self::new(out)
     }
 
     /// `DESFire` EV1 DES-family secure messaging uses the first 8-byte session half.
     #[must_use]
-    pub const fn ev1_des_working_key(self) -> Self {
-        let b = self.0;
-        Self([
+    pub const fn ev1_des_working_key(&self) -> Self {
+        let b = self.as_bytes();
+        Self::new([
             b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[0], b[1], b[2], b[3], b[4], b[5],
             b[6], b[7],
         ])
@@ -293,16 +358,16 @@ impl TwoKey3DesSessionKey {
 }
 
 /// Three-key triple-DES session key (24 bytes).
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct ThreeKey3DesSessionKey([u8; 24]);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreeKey3DesSessionKey(SecretKey<24>);
 
 impl ThreeKey3DesSessionKey {
     pub const fn new(bytes: [u8; 24]) -> Self {
-        Self(bytes)
+        Self(SecretKey::new(bytes))
     }
 
-    pub const fn as_bytes(self) -> [u8; 24] {
-        self.0
+    pub const fn as_bytes(&self) -> &[u8; 24] {
+        self.0.as_bytes()
     }
 
     /// Derives the `DESFire` 3TDEA session key from 16-byte authentication challenges.
@@ -316,7 +381,7 @@ impl ThreeKey3DesSessionKey {
         out[12..16].copy_from_slice(&b[6..10]);
         out[16..20].copy_from_slice(&a[12..16]);
         out[20..24].copy_from_slice(&b[12..16]);
-        Self(out)
+        Self::new(out)
     }
 }
 
@@ -526,6 +591,22 @@ pub fn aes_cbc_encrypt_in_place(key: &[u8; 16], iv: &[u8; 16], data: &mut [u8])
     }
 }
 
+/// Builds the 32-byte EV2 session vector `SV1`/`SV2` for session-key derivation.
+fn ev2_session_vector(label: [u8; 2], rnd_a: RndA, rnd_b: RndB) -> [u8; 32] {
+    let a = rnd_a.as_bytes();
+    let b = rnd_b.as_bytes();
+    let mut out = [0u8; 32];
+    out[0..2].copy_from_slice(&label);
+    out[2..6].copy_from_slice(&[0x00, 0x01, 0x00, 0x80]);
+    out[6..8].copy_from_slice(&a[0..2]);
+    for i in 0..6 {
+        out[8 + i] = a[2 + i] ^ b[i];
+    }
+    out[14..24].copy_from_slice(&b[6..16]);
+    out[24..32].copy_from_slice(&a[8..16]);
+    out
+}
+
 fn aes_encrypt_block(key: &[u8; 16], block: &mut [u8; 16]) {
     let cipher = Aes128::new(key.into());
     cipher.encrypt_block(block.into());
@@ -621,6 +702,73 @@ mod tests {
         ThreeKey3DesSessionKey, TwoKey3DesSessionKey,
     };
 
+    #[test]
+    fn derives_ev2_session_keys_from_nxp_example() {
+        // NXP AN12196 AuthenticateEV2First example, key 00..00.
+        let rnd_a = RndA::new([
+            0x13, 0xC5, 0xDB, 0x8A, 0x59, 0x30, 0x43, 0x9F, 0xC3, 0xDE, 0xF9, 0xA4, 0xC6, 0x75,
+            0x36, 0x0F,
+        ]);
+        let rnd_b = RndB::new([
+            0xB9, 0xE2, 0xFC, 0x78, 0x9B, 0x64, 0xBF, 0x23, 0x7C, 0xCC, 0xAA, 0x20, 0xEC, 0x7E,
+            0x6E, 0x48,
+        ]);
+
+        assert_eq!(
+            AesSessionKey::derive_ev2_encryption(&[0u8; 16], rnd_a, rnd_b),
+            AesSessionKey::new([
+                0x13, 0x09, 0xC8, 0x77, 0x50, 0x9E, 0x5A, 0x21, 0x50, 0x07, 0xFF, 0x0E, 0xD1, 0x9C,
+                0xA5, 0x64,
+            ])
+        );
+        assert_eq!(
+            AesSessionKey::derive_ev2_mac(&[0u8; 16], rnd_a, rnd_b),
+            AesSessionKey::new([
+                0x4C, 0x66, 0x26, 0xF5, 0xE7, 0x2E, 0xA6, 0x94, 0x20, 0x21, 0x39, 0x29, 0x5C, 0x7A,
+                0x7F, 0xC7,
+            ])
+        );
+    }
+
+    #[test]
+    fn derives_ev2_session_keys_from_an12343_example() {
+        // NXP AN12343 AuthenticateEV2First example, key 00..00.
+        let rnd_a = RndA::new([
+            0xB0, 0x4D, 0x07, 0x87, 0xC9, 0x3E, 0xE0, 0xCC, 0x8C, 0xAC, 0xC8, 0xE8, 0x6F, 0x16,
+            0xC6, 0xFE,
+        ]);
+        let rnd_b = RndB::new([
+            0xFA, 0x65, 0x9A, 0xD0, 0xDC, 0xA7, 0x38, 0xDD, 0x65, 0xDC, 0x7D, 0xC3, 0x86, 0x12,
+            0xAD, 0x81,
+        ]);
+
+        assert_eq!(
+            AesSessionKey::derive_ev2_encryption(&[0u8; 16], rnd_a, rnd_b),
+            AesSessionKey::new([
+                0x63, 0xDC, 0x07, 0x28, 0x62, 0x89, 0xA7, 0xA6, 0xC0, 0x33, 0x4C, 0xA3, 0x1C, 0x31,
+                0x4A, 0x04
+            ])
+        );
+        assert_eq!(
+            AesSessionKey::derive_ev2_mac(&[0u8; 16], rnd_a, rnd_b),
+            AesSessionKey::new([
+                0x77, 0x4F, 0x26, 0x74, 0x3E, 0xCE, 0x6A, 0xF5, 0x03, 0x3B, 0x6A, 0xE8, 0x52, 0x29,
+                0x46, 0xF6
+            ])
+        );
+    }
+
+    #[test]
+    fn truncates_ev2_mac_to_odd_bytes() {
+        let cmac = AesCmac::calculate(&[0u8; 16], &[]);
+        let full = cmac.as_bytes();
+
+        assert_eq!(
+            cmac.ev2_mac().as_bytes(),
+            [full[1], full[3], full[5], full[7], full[9], full[11], full[13], full[15]]
+        );
+    }
+
     #[test]
     fn rotates_challenges_left() {
         let rnd_a = RndA::new([
@@ -651,7 +799,7 @@ mod tests {
         let session_key = AesSessionKey::derive(rnd_a, rnd_b);
 
         assert_eq!(
-            session_key.as_bytes(),
+            *session_key.as_bytes(),
             [
                 0xA0, 0xA1, 0xA2, 0xA3, 0xB0, 0xB1, 0xB2, 0xB3, 0xAC, 0xAD, 0xAE, 0xAF, 0xBC, 0xBD,
                 0xBE, 0xBF,
@@ -685,11 +833,11 @@ mod tests {
         ]);
 
         assert_eq!(
-            DesSessionKey::derive(rnd_a8, rnd_b8).as_bytes(),
+            *DesSessionKey::derive(rnd_a8, rnd_b8).as_bytes(),
             [0xA0, 0xA1, 0xA2, 0xA3, 0xB0, 0xB1, 0xB2, 0xB3]
         );
         assert_eq!(
-            TwoKey3DesSessionKey::derive(rnd_a8, rnd_b8).as_bytes(),
+            *TwoKey3DesSessionKey::derive(rnd_a8, rnd_b8).as_bytes(),
             [
                 0xA0, 0xA1, 0xA2, 0xA3, 0xB0, 0xB1, 0xB2, 0xB3, 0xA4, 0xA5, 0xA6, 0xA7, 0xB4, 0xB5,
                 0xB6, 0xB7
@@ -701,14 +849,14 @@ mod tests {
         let pm3_rnd_b = RndB8::new([0xC1, 0xBF, 0x6D, 0x84, 0xDD, 0xFD, 0xD2, 0xC7]);
         let pm3_session_key = TwoKey3DesSessionKey::derive(pm3_rnd_a, pm3_rnd_b);
         assert_eq!(
-            pm3_session_key.as_bytes(),
+            *pm3_session_key.as_bytes(),
             [
                 0x01, 0x02, 0x03, 0x04, 0xC1, 0xBF, 0x6D, 0x84, 0x05, 0x06, 0x07, 0x08, 0xDD, 0xFD,
                 0xD2, 0xC7
             ]
         );
         assert_eq!(
-            pm3_session_key.ev1_des_working_key().as_bytes(),
+            *pm3_session_key.ev1_des_working_key().as_bytes(),
             [
                 0x01, 0x02, 0x03, 0x04, 0xC1, 0xBF, 0x6D, 0x84, 0x01, 0x02, 0x03, 0x04, 0xC1, 0xBF,
                 0x6D, 0x84
@@ -716,7 +864,7 @@ mod tests {
         );
 
         assert_eq!(
-            ThreeKey3DesSessionKey::derive(rnd_a, rnd_b).as_bytes(),
+            *ThreeKey3DesSessionKey::derive(rnd_a, rnd_b).as_bytes(),
             [
                 0xA0, 0xA1, 0xA2, 0xA3, 0xB0, 0xB1, 0xB2, 0xB3, 0xA6, 0xA7, 0xA8, 0xA9, 0xB6, 0xB7,
                 0xB8, 0xB9, 0xAC, 0xAD, 0xAE, 0xAF, 0xBC, 0xBD, 0xBE, 0xBF
@@ -909,13 +1057,13 @@ mod tests {
         let session_key = AesSessionKey::derive(rnd_a, rnd_b);
         let mut chaining = AesCmacChaining::new();
 
-        let first = chaining.update(session_key, &[0xBD, 0x00, 0x00, 0x00]);
-        let second = chaining.update(session_key, &[0xAF]);
+        let first = chaining.update(&session_key, &[0xBD, 0x00, 0x00, 0x00]);
+        let second = chaining.update(&session_key, &[0xAF]);
 
         assert_eq!(chaining.state(), second.as_bytes());
         assert_eq!(
             second,
-            AesCmac::calculate_chained(&session_key.as_bytes(), &first.as_bytes(), &[0xAF])
+            AesCmac::calculate_chained(session_key.as_bytes(), &first.as_bytes(), &[0xAF])
         );
     }
 }
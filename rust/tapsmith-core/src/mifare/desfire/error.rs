@@ -1,4 +1,4 @@
-use crate::mifare::desfire::status::Status;
+use crate::mifare::desfire::{command::CommandCode, key::KeyNumber, status::Status};
 
 /// Errors raised by core `DESFire` command handling.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -33,6 +33,8 @@ pub enum Error {
     InvalidFileId(u8),
     /// A key number exceeded the `DESFire` key-number range.
     InvalidKeyNumber(u8),
+    /// A key-set number or key-set count was outside the range supported by EV2 applications.
+    InvalidKeySetNumber(u8),
     /// A file communication-mode byte was not recognized.
     InvalidCommunicationMode(u8),
     /// A file-type byte was not recognized.
@@ -43,4 +45,33 @@ pub enum Error {
     UnsupportedAlgorithm,
     /// `ChangeKey` for a key other than the session key requires the old key value.
     MissingOldKey,
+    /// An ISO file identifier was one of the values reserved by ISO 7816-4.
+    InvalidIsoFileId(u16),
+    /// A DF name was empty or longer than 16 bytes.
+    InvalidDfName(usize),
+    /// An ISO binary offset does not fit the 15-bit `P1`/`P2` encoding.
+    InvalidIsoOffset(u16),
+    /// An ISO 7816-4 command returned a status word other than `90 00`.
+    IsoStatus(u16),
+    /// A user-defined ATS was empty, longer than 20 bytes, or its `TL` byte did not match.
+    InvalidAts,
+    /// An AN10922 diversification input was empty or too long for the key type.
+    InvalidDiversificationInput(usize),
+    /// The EV2 command counter is exhausted and the session must be re-established.
+    CommandCounterOverflow,
+    /// The card does not implement this native command.
+    UnsupportedCommand(CommandCode),
+    /// NTAG 424 SDM settings lack an offset their options and access rights require.
+    InvalidSdmSettings,
+    /// The random source could not produce a reader challenge.
+    RandomSource,
+    /// A crypto provider rejected a key handle or could not perform the operation.
+    CryptoProvider,
+    /// The file's access rights never allow this operation.
+    FileAccessDenied,
+    /// The session is not authenticated with a key the file's access rights
+    /// accept; carries the first such key.
+    MissingFileKey(KeyNumber),
+    /// A `Credit`, `Debit` or `LimitedCredit` amount was zero or negative.
+    InvalidValueAmount(i32),
 }
@@ -1,91 +1,177 @@
 use heapless::Vec;
 
 use crate::mifare::desfire::{
-    command::{Command, Response},
+    command::{Command, CommandCode, Response},
     error::Error,
-    framing::FrameCodec,
+    framing::{wrapped::split_status_word, FrameCodec},
+    iso::IsoCommand,
     status::Status,
-    transport::{Frame, Transport},
+    transport::{AsyncTransport, Frame, Transport},
 };
 
 /// Upper bound for chained `DESFire` continuation frames.
 pub const MAX_ADDITIONAL_FRAMES: usize = 64;
 
+/// Largest command payload sent in one frame.
+///
+/// Longer payloads continue in `AdditionalFrame` frames, since the card cannot
+/// buffer a full-length frame in one go.
+pub const MAX_COMMAND_FRAME_DATA: usize = 54;
+
 /// Sends canonical `DESFire` commands through a transport and framing codec.
 pub struct Executor<T, C> {
     transport: T,
     codec: C,
 }
 
-impl<T, C> Executor<T, C>
-where
-    T: Transport,
-    C: FrameCodec,
-{
-    /// Creates an executor from a byte transport and frame codec.
-    pub const fn new(transport: T, codec: C) -> Self {
-        Self { transport, codec }
-    }
+/// [`Executor`] over an [`AsyncTransport`].
+///
+/// Frame chaining and codec handling are shared with the blocking executor;
+/// only the transport exchange is awaited.
+pub struct AsyncExecutor<T, C> {
+    transport: T,
+    codec: C,
+}
 
-    /// Returns a shared reference to the underlying transport.
-    pub const fn transport(&self) -> &T {
-        &self.transport
-    }
+/// Implements the executor methods once for both transport flavours.
+///
+/// `$async` is either empty or `async`, and `$await` either empty or `.await`,
+/// so every transport call site reads `call()$($await)*?`.
+macro_rules! impl_executor {
+    ($executor:ident, $transport:ident, [$($async:tt)?], [$($await:tt)*]) => {
+        impl<T, C> $executor<T, C>
+        where
+            T: $transport,
+            C: FrameCodec,
+        {
+            /// Creates an executor from a byte transport and frame codec.
+            pub const fn new(transport: T, codec: C) -> Self {
+                Self { transport, codec }
+            }
 
-    /// Returns a mutable reference to the underlying transport.
-    pub fn transport_mut(&mut self) -> &mut T {
-        &mut self.transport
-    }
+            /// Returns a shared reference to the underlying transport.
+            pub const fn transport(&self) -> &T {
+                &self.transport
+            }
 
-    /// Consumes the executor and returns its transport.
-    pub fn into_transport(self) -> T {
-        self.transport
-    }
+            /// Returns a mutable reference to the underlying transport.
+            pub fn transport_mut(&mut self) -> &mut T {
+                &mut self.transport
+            }
 
-    /// Sends one framed command and decodes one response frame.
-    pub fn exchange_one(&mut self, command: &Command) -> Result<Response, Error> {
-        let mut tx = Frame::new();
-        let mut rx = Frame::new();
-        let mut response = Response::new(Status::OperationOk);
+            /// Consumes the executor and returns its transport.
+            pub fn into_transport(self) -> T {
+                self.transport
+            }
 
-        self.codec.encode(command, &mut tx)?;
-        self.transport.transceive(tx.as_slice(), &mut rx)?;
-        self.codec.decode(rx.as_slice(), &mut response)?;
+            /// Sends one command and decodes the response to its last frame.
+            ///
+            /// Payloads longer than [`MAX_COMMAND_FRAME_DATA`] are split across
+            /// `AdditionalFrame` frames; every intermediate frame must be acknowledged
+            /// with an empty `AdditionalFrame` response. A card that rejects the chain
+            /// early has its response returned as-is.
+            pub $($async)? fn exchange_one(
+                &mut self,
+                command: &Command,
+            ) -> Result<Response, Error> {
+                let mut chunks = command.data().chunks(MAX_COMMAND_FRAME_DATA);
+                let first = chunks.next().unwrap_or_default();
+                let mut response = self.exchange_frame(command.code(), first)$($await)*?;
+
+                for chunk in chunks {
+                    match response.status() {
+                        Status::AdditionalFrame if response.data().is_empty() => {}
+                        Status::AdditionalFrame | Status::OperationOk => {
+                            return Err(Error::MalformedResponse)
+                        }
+                        _ => return Ok(response),
+                    }
+                    response = self
+                        .exchange_frame(CommandCode::ADDITIONAL_FRAME, chunk)$($await)*?;
+                }
 
-        Ok(response)
-    }
+                Ok(response)
+            }
 
-    /// Executes a command and collects all `AdditionalFrame` response data.
-    pub fn execute<const N: usize>(
-        &mut self,
-        command: &Command,
-        data: &mut Vec<u8, N>,
-    ) -> Result<(), Error> {
-        data.clear();
+            /// Executes a command and collects all `AdditionalFrame` response data.
+            pub $($async)? fn execute<const N: usize>(
+                &mut self,
+                command: &Command,
+                data: &mut Vec<u8, N>,
+            ) -> Result<(), Error> {
+                data.clear();
+
+                let mut response = self.exchange_one(command)$($await)*?;
+                let mut additional_frames = 0;
+
+                loop {
+                    match response.status() {
+                        Status::OperationOk => {
+                            append_response_data(data, response.data())?;
+                            return Ok(());
+                        }
+                        Status::AdditionalFrame => {
+                            append_response_data(data, response.data())?;
+                            additional_frames += 1;
+                            if additional_frames > MAX_ADDITIONAL_FRAMES {
+                                return Err(Error::TooManyAdditionalFrames);
+                            }
+                            response = self.exchange_one(&Command::additional_frame())$($await)*?;
+                        }
+                        status => return Err(Error::Status(status)),
+                    }
+                }
+            }
 
-        let mut response = self.exchange_one(command)?;
-        let mut additional_frames = 0;
+            $($async)? fn exchange_frame(
+                &mut self,
+                code: CommandCode,
+                data: &[u8],
+            ) -> Result<Response, Error> {
+                let mut tx = Frame::new();
+                let mut rx = Frame::new();
+                let mut response = Response::new(Status::OperationOk);
 
-        loop {
-            match response.status() {
-                Status::OperationOk => {
-                    append_response_data(data, response.data())?;
-                    return Ok(());
-                }
-                Status::AdditionalFrame => {
-                    append_response_data(data, response.data())?;
-                    additional_frames += 1;
-                    if additional_frames > MAX_ADDITIONAL_FRAMES {
-                        return Err(Error::TooManyAdditionalFrames);
-                    }
-                    response = self.exchange_one(&Command::additional_frame())?;
+                self.codec.encode(&Command::new(code, data)?, &mut tx)?;
+                self.transport.transceive(tx.as_slice(), &mut rx)$($await)*?;
+                self.codec.decode(rx.as_slice(), &mut response)?;
+
+                Ok(response)
+            }
+        }
+
+        impl<T, C> $executor<T, C>
+        where
+            T: $transport,
+        {
+            /// Sends one ISO 7816-4 APDU, bypassing the native framing codec.
+            ///
+            /// Response data is returned on `90 00`; any other status word becomes
+            /// [`Error::IsoStatus`].
+            pub $($async)? fn exchange_iso<const N: usize>(
+                &mut self,
+                command: &IsoCommand,
+                data: &mut Vec<u8, N>,
+            ) -> Result<(), Error> {
+                let mut tx = Frame::new();
+                let mut rx = Frame::new();
+                data.clear();
+
+                command.encode(&mut tx)?;
+                self.transport.transceive(tx.as_slice(), &mut rx)$($await)*?;
+                let (body, [sw1, sw2]) = split_status_word(rx.as_slice())?;
+                if [sw1, sw2] != [0x90, 0x00] {
+                    return Err(Error::IsoStatus(u16::from_be_bytes([sw1, sw2])));
                 }
-                status => return Err(Error::Status(status)),
+                append_response_data(data, body)
             }
         }
-    }
+    };
 }
 
+impl_executor!(Executor, Transport, [], []);
+impl_executor!(AsyncExecutor, AsyncTransport, [async], [.await]);
+
 fn append_response_data<const N: usize>(out: &mut Vec<u8, N>, chunk: &[u8]) -> Result<(), Error> {
     out.extend_from_slice(chunk)
         .map_err(|_| Error::ResponseTooLong)
@@ -93,15 +179,22 @@ fn append_response_data<const N: usize>(out: &mut Vec<u8, N>, chunk: &[u8]) -> R
 
 #[cfg(test)]
 mod tests {
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
     use heapless::Vec;
 
     use crate::mifare::desfire::{
         command::{Command, CommandCode},
         error::Error,
-        executor::Executor,
+        executor::{AsyncExecutor, Executor},
         framing::{NativeFraming, WrappedFraming},
+        iso::IsoCommand,
         status::Status,
-        transport::{Frame, Transport},
+        transport::{AsyncTransport, Frame, Transport},
     };
 
     struct MockTransport<const N: usize> {
@@ -129,6 +222,34 @@ mod tests {
         }
     }
 
+    /// Suspends once before every exchange, like a reader waiting on I/O.
+    struct PendingTransport<const N: usize>(MockTransport<N>);
+
+    impl<const N: usize> AsyncTransport for PendingTransport<N> {
+        async fn transceive(&mut self, tx: &[u8], rx: &mut Frame) -> Result<(), Error> {
+            let mut pending = true;
+            core::future::poll_fn(|_| {
+                if core::mem::take(&mut pending) {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            })
+            .await;
+            self.0.transceive(tx, rx)
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
     #[test]
     fn executes_single_native_frame() {
         let transport = MockTransport::new([(&[0x60][..], &[0x00, 0x04, 0x01][..])]);
@@ -164,6 +285,40 @@ mod tests {
         assert_eq!(executor.transport().index, 2);
     }
 
+    static CHAINED_FIRST_FRAME: [u8; 55] = {
+        let mut frame = [0x11; 55];
+        frame[0] = 0x3D;
+        frame
+    };
+    static CHAINED_LAST_FRAME: [u8; 7] = [0xAF, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11];
+
+    #[test]
+    fn chains_long_command_across_additional_frames() {
+        let transport = MockTransport::new([
+            (&CHAINED_FIRST_FRAME[..], &[0xAF][..]),
+            (&CHAINED_LAST_FRAME[..], &[0x00][..]),
+        ]);
+        let mut executor = Executor::new(transport, NativeFraming);
+        let command = Command::new(CommandCode::WRITE_DATA, &[0x11; 60]).unwrap();
+
+        let response = executor.exchange_one(&command).unwrap();
+
+        assert_eq!(response.status(), Status::OperationOk);
+        assert_eq!(executor.transport().index, 2);
+    }
+
+    #[test]
+    fn stops_command_chain_when_card_rejects_first_frame() {
+        let transport = MockTransport::new([(&CHAINED_FIRST_FRAME[..], &[0x9D][..])]);
+        let mut executor = Executor::new(transport, NativeFraming);
+        let command = Command::new(CommandCode::WRITE_DATA, &[0x11; 60]).unwrap();
+
+        let response = executor.exchange_one(&command).unwrap();
+
+        assert_eq!(response.status(), Status::PermissionDenied);
+        assert_eq!(executor.transport().index, 1);
+    }
+
     #[test]
     fn returns_non_success_status() {
         let transport = MockTransport::new([(&[0x6F][..], &[0x9D][..])]);
@@ -188,4 +343,49 @@ mod tests {
 
         assert_eq!(error, Error::ResponseTooLong);
     }
+
+    #[test]
+    fn exchanges_iso_apdu_independent_of_native_framing() {
+        let transport = MockTransport::new([
+            (
+                &[0x00, 0xB0, 0x00, 0x00, 0x02][..],
+                &[0x01, 0x02, 0x90, 0x00][..],
+            ),
+            (&[0x00, 0xB0, 0x00, 0x10, 0x02][..], &[0x6B, 0x00][..]),
+        ]);
+        let mut executor = Executor::new(transport, NativeFraming);
+        let mut data: Vec<u8, 8> = Vec::new();
+
+        executor
+            .exchange_iso(&IsoCommand::read_binary(0, 2).unwrap(), &mut data)
+            .unwrap();
+        assert_eq!(data.as_slice(), &[0x01, 0x02]);
+
+        let error = executor
+            .exchange_iso(&IsoCommand::read_binary(0x10, 2).unwrap(), &mut data)
+            .unwrap_err();
+        assert_eq!(error, Error::IsoStatus(0x6B00));
+    }
+
+    #[test]
+    fn async_executor_follows_additional_frames() {
+        let transport = PendingTransport(MockTransport::new([
+            (
+                &[0x90, 0x60, 0x00, 0x00, 0x00][..],
+                &[0x01, 0x02, 0x91, 0xAF][..],
+            ),
+            (
+                &[0x90, 0xAF, 0x00, 0x00, 0x00][..],
+                &[0x03, 0x04, 0x91, 0x00][..],
+            ),
+        ]));
+        let mut executor = AsyncExecutor::new(transport, WrappedFraming);
+        let command = Command::new(CommandCode::GET_VERSION, &[]).unwrap();
+        let mut data: Vec<u8, 8> = Vec::new();
+
+        block_on(executor.execute(&command, &mut data)).unwrap();
+
+        assert_eq!(data.as_slice(), &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(executor.transport().0.index, 2);
+    }
 }
@@ -1,3 +1,5 @@
+use heapless::Vec;
+
 use crate::mifare::desfire::key::KeyNumber;
 
 /// One `DESFire` file access condition.
@@ -29,6 +31,19 @@ impl AccessCondition {
     }
 }
 
+/// Which access conditions grant a file command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAccess {
+    /// `ReadData` and `ReadRecords`: read or read-write.
+    Read,
+    /// `WriteData`, `WriteRecord` and `LimitedCredit`: write or read-write.
+    Write,
+    /// `Credit` and `ClearRecordFile`: read-write only.
+    ReadWrite,
+    /// `GetValue` and `Debit`: any of read, write or read-write.
+    Any,
+}
+
 /// Access conditions for a `DESFire` file.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct AccessRights {
@@ -84,6 +99,17 @@ impl AccessRights {
         self.change
     }
 
+    /// Conditions any one of which grants `access`.
+    pub fn granting(self, access: FileAccess) -> Vec<AccessCondition, 3> {
+        let conditions: &[AccessCondition] = match access {
+            FileAccess::Read => &[self.read, self.read_write],
+            FileAccess::Write => &[self.write, self.read_write],
+            FileAccess::ReadWrite => &[self.read_write],
+            FileAccess::Any => &[self.read, self.write, self.read_write],
+        };
+        Vec::from_slice(conditions).expect("at most three conditions")
+    }
+
     /// Encodes access rights to the two-byte `DESFire` wire format.
     pub fn to_bytes(self) -> [u8; 2] {
         [
@@ -96,7 +122,7 @@ impl AccessRights {
 #[cfg(test)]
 mod tests {
     use crate::mifare::desfire::{
-        file::{AccessCondition, AccessRights},
+        file::{AccessCondition, AccessRights, FileAccess},
         key::KeyNumber,
     };
 
@@ -126,4 +152,24 @@ mod tests {
             AccessCondition::Key(KeyNumber::new(2).unwrap())
         );
     }
+
+    #[test]
+    fn granting_conditions_follow_the_command_class() {
+        let rights = AccessRights::from_bytes([0x1F, 0xE2]);
+        let key = |n| AccessCondition::Key(KeyNumber::new(n).unwrap());
+
+        assert_eq!(
+            rights.granting(FileAccess::Read).as_slice(),
+            [AccessCondition::Free, key(1)]
+        );
+        assert_eq!(
+            rights.granting(FileAccess::Write).as_slice(),
+            [key(2), key(1)]
+        );
+        assert_eq!(rights.granting(FileAccess::ReadWrite).as_slice(), [key(1)]);
+        assert_eq!(
+            rights.granting(FileAccess::Any).as_slice(),
+            [AccessCondition::Free, key(2), key(1)]
+        );
+    }
 }
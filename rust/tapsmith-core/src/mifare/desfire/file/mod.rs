@@ -1,7 +1,11 @@
 pub mod access;
 pub mod id;
+pub mod record;
 pub mod settings;
+pub mod sink;
 
-pub use access::{AccessCondition, AccessRights};
+pub use access::{AccessCondition, AccessRights, FileAccess};
 pub use id::FileId;
+pub use record::Records;
 pub use settings::{CommunicationMode, FileSettings, FileSettingsDetails, FileType};
+pub use sink::DataSink;
@@ -0,0 +1,106 @@
+use core::slice::ChunksExact;
+
+use crate::mifare::desfire::{error::Error, types::U24};
+
+/// Records returned by `ReadRecords`, split by the file's record size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Records<'a> {
+    data: &'a [u8],
+    record_size: usize,
+}
+
+impl<'a> Records<'a> {
+    /// Splits raw `ReadRecords` bytes into records of `record_size` bytes.
+    ///
+    /// Fails when the record size is zero or the data is not a whole number of records.
+    pub fn new(data: &'a [u8], record_size: U24) -> Result<Self, Error> {
+        let record_size = usize::try_from(record_size.as_u32()).expect("U24 fits in usize");
+        if record_size == 0 || !data.len().is_multiple_of(record_size) {
+            return Err(Error::InvalidResponseLength);
+        }
+
+        Ok(Self { data, record_size })
+    }
+
+    /// Number of records.
+    pub const fn len(&self) -> usize {
+        self.data.len() / self.record_size
+    }
+
+    /// Returns `true` when no records were returned.
+    pub const fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Size of each record in bytes.
+    pub const fn record_size(&self) -> usize {
+        self.record_size
+    }
+
+    /// Returns one record by position in the response.
+    pub fn get(&self, index: usize) -> Option<&'a [u8]> {
+        let start = index.checked_mul(self.record_size)?;
+        self.data.get(start..start + self.record_size)
+    }
+
+    /// Iterates over the records in the order the card returned them.
+    pub fn iter(&self) -> ChunksExact<'a, u8> {
+        self.data.chunks_exact(self.record_size)
+    }
+
+    /// All record bytes concatenated.
+    pub const fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+impl<'a> IntoIterator for Records<'a> {
+    type Item = &'a [u8];
+    type IntoIter = ChunksExact<'a, u8>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a> IntoIterator for &Records<'a> {
+    type Item = &'a [u8];
+    type IntoIter = ChunksExact<'a, u8>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::{error::Error, file::Records, types::U24};
+
+    #[test]
+    fn splits_records_by_record_size() {
+        let records = Records::new(&[1, 2, 3, 4, 5, 6], U24::new(2).unwrap()).unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records.get(1), Some(&[3, 4][..]));
+        assert_eq!(records.get(3), None);
+        assert_eq!(
+            records
+                .iter()
+                .collect::<heapless::Vec<&[u8], 3>>()
+                .as_slice(),
+            &[&[1, 2][..], &[3, 4][..], &[5, 6][..]]
+        );
+    }
+
+    #[test]
+    fn rejects_partial_records() {
+        assert_eq!(
+            Records::new(&[1, 2, 3], U24::new(2).unwrap()),
+            Err(Error::InvalidResponseLength)
+        );
+        assert_eq!(
+            Records::new(&[], U24::new(0).unwrap()),
+            Err(Error::InvalidResponseLength)
+        );
+    }
+}
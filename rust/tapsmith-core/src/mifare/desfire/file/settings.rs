@@ -1,4 +1,9 @@
-use crate::mifare::desfire::{error::Error, file::AccessRights, types::U24};
+use crate::mifare::desfire::{
+    error::Error,
+    file::{AccessCondition, AccessRights, FileAccess},
+    key::KeyNumber,
+    types::U24,
+};
 
 /// `DESFire` file communication mode.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -153,6 +158,36 @@ impl FileSettings {
     pub const fn details(self) -> FileSettingsDetails {
         self.details
     }
+
+    /// Communication mode the card uses for a command needing `access`, given
+    /// the key the session authenticated with.
+    ///
+    /// Free access is always plain; access granted by the session key uses the
+    /// file's communication mode, as on the card.
+    pub fn communication_mode_for(
+        self,
+        access: FileAccess,
+        session_key: Option<KeyNumber>,
+    ) -> Result<CommunicationMode, Error> {
+        let conditions = self.access_rights.granting(access);
+        if conditions.contains(&AccessCondition::Free) {
+            return Ok(CommunicationMode::Plain);
+        }
+        if let Some(key) = session_key {
+            if conditions.contains(&AccessCondition::Key(key)) {
+                return Ok(self.communication_mode);
+            }
+        }
+        conditions
+            .iter()
+            .find_map(|condition| match condition {
+                AccessCondition::Key(key) => Some(*key),
+                AccessCondition::Free | AccessCondition::Never => None,
+            })
+            .map_or(Err(Error::FileAccessDenied), |key| {
+                Err(Error::MissingFileKey(key))
+            })
+    }
 }
 
 /// Type-specific `DESFire` file settings.
@@ -195,7 +230,11 @@ fn parse_i32(data: &[u8], offset: usize) -> Result<i32, Error> {
 #[cfg(test)]
 mod tests {
     use crate::mifare::desfire::{
-        file::{AccessCondition, CommunicationMode, FileSettings, FileSettingsDetails, FileType},
+        error::Error,
+        file::{
+            AccessCondition, AccessRights, CommunicationMode, FileAccess, FileSettings,
+            FileSettingsDetails, FileType,
+        },
         key::KeyNumber,
         types::U24,
     };
@@ -240,4 +279,46 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn infers_communication_mode_from_access_rights_and_session_key() {
+        let key = |n| KeyNumber::new(n).unwrap();
+        // Read free, write key 2, read-write key 1, change never.
+        let settings = FileSettings::new(
+            FileType::StandardData,
+            CommunicationMode::Enciphered,
+            AccessRights::from_bytes([0x1F, 0xE2]),
+            FileSettingsDetails::Data {
+                size: U24::new(32).unwrap(),
+            },
+        );
+
+        assert_eq!(
+            settings.communication_mode_for(FileAccess::Read, Some(key(1))),
+            Ok(CommunicationMode::Plain)
+        );
+        assert_eq!(
+            settings.communication_mode_for(FileAccess::Write, Some(key(1))),
+            Ok(CommunicationMode::Enciphered)
+        );
+        assert_eq!(
+            settings.communication_mode_for(FileAccess::Write, Some(key(3))),
+            Err(Error::MissingFileKey(key(2)))
+        );
+        assert_eq!(
+            settings.communication_mode_for(FileAccess::ReadWrite, None),
+            Err(Error::MissingFileKey(key(1)))
+        );
+
+        let sealed = FileSettings::new(
+            FileType::StandardData,
+            CommunicationMode::Plain,
+            AccessRights::from_bytes([0xFF, 0xEF]),
+            settings.details(),
+        );
+        assert_eq!(
+            sealed.communication_mode_for(FileAccess::Write, Some(key(0))),
+            Err(Error::FileAccessDenied)
+        );
+    }
 }
@@ -0,0 +1,57 @@
+use heapless::Vec;
+
+use crate::mifare::desfire::error::Error;
+
+/// Destination for file data streamed off the card in chunks.
+///
+/// Chunks arrive in file order and have already been MAC-verified or decrypted.
+pub trait DataSink {
+    /// Appends one chunk of file data.
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), Error>;
+}
+
+/// Fills the slice from the front and advances it past the written bytes,
+/// like `std::io::Write` for `&mut [u8]`.
+impl DataSink for &mut [u8] {
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        if chunk.len() > self.len() {
+            return Err(Error::ResponseTooLong);
+        }
+        let (head, tail) = core::mem::take(self).split_at_mut(chunk.len());
+        head.copy_from_slice(chunk);
+        *self = tail;
+        Ok(())
+    }
+}
+
+impl<const N: usize> DataSink for Vec<u8, N> {
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.extend_from_slice(chunk)
+            .map_err(|_| Error::ResponseTooLong)
+    }
+}
+
+#[cfg(feature = "std")]
+impl DataSink for std::vec::Vec<u8> {
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.extend_from_slice(chunk);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::{error::Error, file::DataSink};
+
+    #[test]
+    fn slice_sink_advances_and_rejects_overflow() {
+        let mut buffer = [0u8; 4];
+        let mut sink = &mut buffer[..];
+
+        sink.write_chunk(&[1, 2]).unwrap();
+        sink.write_chunk(&[3]).unwrap();
+        assert_eq!(sink.len(), 1);
+        assert_eq!(sink.write_chunk(&[4, 5]), Err(Error::ResponseTooLong));
+        assert_eq!(buffer, [1, 2, 3, 0]);
+    }
+}
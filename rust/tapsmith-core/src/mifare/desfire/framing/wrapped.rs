@@ -12,31 +12,16 @@ pub struct WrappedFraming;
 
 impl FrameCodec for WrappedFraming {
     fn encode(&self, command: &Command, frame: &mut Frame) -> Result<(), Error> {
-        let payload_len = u8::try_from(command.data().len()).map_err(|_| Error::CommandTooLong)?;
-
-        frame.clear();
-        if command.data().is_empty() {
-            frame
-                .extend_from_slice(&[0x90, command.code().as_byte(), 0x00, 0x00, 0x00])
-                .map_err(|_| Error::CommandTooLong)
-        } else {
-            frame
-                .extend_from_slice(&[0x90, command.code().as_byte(), 0x00, 0x00, payload_len])
-                .map_err(|_| Error::CommandTooLong)?;
-            frame
-                .extend_from_slice(command.data())
-                .map_err(|_| Error::CommandTooLong)?;
-            frame.push(0x00).map_err(|_| Error::CommandTooLong)
-        }
+        encode_apdu(
+            [0x90, command.code().as_byte(), 0x00, 0x00],
+            command.data(),
+            Some(0x00),
+            frame,
+        )
     }
 
     fn decode(&self, frame: &[u8], response: &mut Response) -> Result<(), Error> {
-        let Some((&status, rest)) = frame.split_last() else {
-            return Err(Error::MalformedResponse);
-        };
-        let Some((&marker, data)) = rest.split_last() else {
-            return Err(Error::MalformedResponse);
-        };
+        let (data, [marker, status]) = split_status_word(frame)?;
         if marker != 0x91 {
             return Err(Error::InvalidWrappedResponse);
         }
@@ -44,6 +29,41 @@ impl FrameCodec for WrappedFraming {
     }
 }
 
+/// Encodes a short ISO 7816-4 APDU: `[CLA INS P1 P2] [Lc data] [Le]`.
+pub(crate) fn encode_apdu(
+    header: [u8; 4],
+    data: &[u8],
+    le: Option<u8>,
+    frame: &mut Frame,
+) -> Result<(), Error> {
+    frame.clear();
+    frame
+        .extend_from_slice(&header)
+        .map_err(|_| Error::CommandTooLong)?;
+    if !data.is_empty() {
+        let lc = u8::try_from(data.len()).map_err(|_| Error::CommandTooLong)?;
+        frame.push(lc).map_err(|_| Error::CommandTooLong)?;
+        frame
+            .extend_from_slice(data)
+            .map_err(|_| Error::CommandTooLong)?;
+    }
+    if let Some(le) = le {
+        frame.push(le).map_err(|_| Error::CommandTooLong)?;
+    }
+    Ok(())
+}
+
+/// Splits an ISO 7816-4 response APDU into its data and `SW1 SW2` trailer.
+pub(crate) fn split_status_word(frame: &[u8]) -> Result<(&[u8], [u8; 2]), Error> {
+    let Some((&sw2, rest)) = frame.split_last() else {
+        return Err(Error::MalformedResponse);
+    };
+    let Some((&sw1, data)) = rest.split_last() else {
+        return Err(Error::MalformedResponse);
+    };
+    Ok((data, [sw1, sw2]))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::mifare::desfire::{
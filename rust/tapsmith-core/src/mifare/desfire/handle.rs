@@ -0,0 +1,198 @@
+//! `DESFire` file handles that pick secure messaging on their own.
+//!
+//! The card protects each file command according to the file's access rights
+//! and the key the session authenticated with: free access is plain, access
+//! granted by the session key uses the file's communication mode. A
+//! [`FileHandle`] fetches the settings once and applies the same rule to every
+//! command, so callers never pass a communication mode and a session without
+//! the right key fails before anything is sent.
+
+use heapless::Vec;
+
+use crate::mifare::desfire::{
+    client::{value_amount, Desfire},
+    error::Error,
+    file::{
+        CommunicationMode, DataSink, FileAccess, FileId, FileSettings, FileSettingsDetails,
+        FileType, Records,
+    },
+    framing::FrameCodec,
+    transport::Transport,
+    types::U24,
+};
+
+/// One file of the selected application.
+///
+/// Created by [`Desfire::open_file`] or [`Desfire::open_file_with_settings`].
+/// The settings are not refreshed, so a handle should not outlive a
+/// `ChangeFileSettings` on the same file.
+pub struct FileHandle<'a, T, C>
+where
+    T: Transport,
+    C: FrameCodec,
+{
+    desfire: &'a mut Desfire<T, C>,
+    file_id: FileId,
+    settings: FileSettings,
+}
+
+impl<'a, T, C> FileHandle<'a, T, C>
+where
+    T: Transport,
+    C: FrameCodec,
+{
+    pub(crate) const fn new(
+        desfire: &'a mut Desfire<T, C>,
+        file_id: FileId,
+        settings: FileSettings,
+    ) -> Self {
+        Self {
+            desfire,
+            file_id,
+            settings,
+        }
+    }
+}
+
+impl<T, C> FileHandle<'_, T, C>
+where
+    T: Transport,
+    C: FrameCodec,
+{
+    /// File number within the selected application.
+    pub const fn file_id(&self) -> FileId {
+        self.file_id
+    }
+
+    /// Settings the handle was opened with.
+    pub const fn settings(&self) -> FileSettings {
+        self.settings
+    }
+
+    /// Communication mode of a command needing `access` under the current session.
+    pub fn communication_mode(&self, access: FileAccess) -> Result<CommunicationMode, Error> {
+        self.settings
+            .communication_mode_for(access, self.desfire.session().key_number())
+    }
+
+    /// Streams `length` bytes of a data file into `sink`, starting at `offset`.
+    pub fn read<S: DataSink>(
+        &mut self,
+        offset: U24,
+        length: U24,
+        sink: &mut S,
+    ) -> Result<(), Error> {
+        self.require_data_file()?;
+        let mode = self.communication_mode(FileAccess::Read)?;
+        self.desfire
+            .read_data_streamed(self.file_id, mode, offset, length, sink)
+    }
+
+    /// Streams a whole data file into `sink`.
+    pub fn read_all<S: DataSink>(&mut self, sink: &mut S) -> Result<(), Error> {
+        let size = self.require_data_file()?;
+        self.read(U24::new(0).expect("zero is a valid U24"), size, sink)
+    }
+
+    /// Writes `data` to a data file at `offset`.
+    ///
+    /// Backup files only take the new contents after `CommitTransaction`.
+    pub fn write(&mut self, offset: U24, data: &[u8]) -> Result<(), Error> {
+        self.require_data_file()?;
+        let mode = self.communication_mode(FileAccess::Write)?;
+        self.desfire
+            .write_data_streamed(self.file_id, mode, offset, data)
+    }
+
+    /// Reads the current balance of a value file.
+    pub fn value(&mut self) -> Result<i32, Error> {
+        self.require_file_type(&[FileType::Value])?;
+        let mode = self.communication_mode(FileAccess::Any)?;
+        self.desfire.get_value(self.file_id, mode)
+    }
+
+    /// Increases the value of a value file by a positive `amount`.
+    pub fn credit(&mut self, amount: i32) -> Result<(), Error> {
+        value_amount(amount)?;
+        self.require_file_type(&[FileType::Value])?;
+        let mode = self.communication_mode(FileAccess::ReadWrite)?;
+        self.desfire.credit(self.file_id, mode, amount)
+    }
+
+    /// Decreases the value of a value file by a positive `amount`.
+    pub fn debit(&mut self, amount: i32) -> Result<(), Error> {
+        value_amount(amount)?;
+        self.require_file_type(&[FileType::Value])?;
+        let mode = self.communication_mode(FileAccess::Any)?;
+        self.desfire.debit(self.file_id, mode, amount)
+    }
+
+    /// Increases a value file by at most the last committed debit.
+    pub fn limited_credit(&mut self, amount: i32) -> Result<(), Error> {
+        value_amount(amount)?;
+        self.require_file_type(&[FileType::Value])?;
+        let mode = self.communication_mode(FileAccess::Write)?;
+        self.desfire.limited_credit(self.file_id, mode, amount)
+    }
+
+    /// Appends a record to a linear or cyclic record file.
+    pub fn write_record(&mut self, offset: U24, data: &[u8]) -> Result<(), Error> {
+        self.require_record_file()?;
+        let mode = self.communication_mode(FileAccess::Write)?;
+        self.desfire.write_record(self.file_id, mode, offset, data)
+    }
+
+    /// Reads `count` records starting `offset` records back from the newest.
+    ///
+    /// A `count` of zero reads every record from `offset` on.
+    pub fn read_records<'b, const N: usize>(
+        &mut self,
+        offset: U24,
+        count: U24,
+        data: &'b mut Vec<u8, N>,
+    ) -> Result<Records<'b>, Error> {
+        let record_size = self.require_record_file()?;
+        let mode = self.communication_mode(FileAccess::Read)?;
+        self.desfire
+            .read_records(self.file_id, mode, record_size, offset, count, data)
+    }
+
+    /// Clears all records from a linear or cyclic record file.
+    pub fn clear_records(&mut self) -> Result<(), Error> {
+        self.require_record_file()?;
+        self.check_access(FileAccess::ReadWrite)?;
+        self.desfire.clear_record_file(self.file_id)
+    }
+
+    /// Fails unless the session may issue a command needing `access`.
+    ///
+    /// For commands without a data phase, such as `ClearRecordFile`, whose
+    /// protection does not depend on the file's communication mode.
+    fn check_access(&self, access: FileAccess) -> Result<(), Error> {
+        self.communication_mode(access).map(drop)
+    }
+
+    /// Returns the size of a standard or backup data file.
+    fn require_data_file(&self) -> Result<U24, Error> {
+        match self.settings.details() {
+            FileSettingsDetails::Data { size } => Ok(size),
+            _ => Err(Error::InvalidFileType(u8::from(self.settings.file_type()))),
+        }
+    }
+
+    /// Returns the record size of a linear or cyclic record file.
+    fn require_record_file(&self) -> Result<U24, Error> {
+        match self.settings.details() {
+            FileSettingsDetails::Record { record_size, .. } => Ok(record_size),
+            _ => Err(Error::InvalidFileType(u8::from(self.settings.file_type()))),
+        }
+    }
+
+    fn require_file_type(&self, file_types: &[FileType]) -> Result<(), Error> {
+        if file_types.contains(&self.settings.file_type()) {
+            Ok(())
+        } else {
+            Err(Error::InvalidFileType(u8::from(self.settings.file_type())))
+        }
+    }
+}
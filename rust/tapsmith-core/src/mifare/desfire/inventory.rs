@@ -0,0 +1,632 @@
+//! Structured snapshot of everything readable on a `DESFire` card.
+//!
+//! [`CardInventory::read`] walks the PICC, every application and every file
+//! with whatever keys are supplied. Parts that cannot be read are kept in the
+//! snapshot with an [`Unavailable`] reason instead of aborting the walk.
+
+use std::vec::Vec;
+
+use heapless::Vec as HeaplessVec;
+
+use crate::mifare::desfire::{
+    application::ApplicationId,
+    client::Desfire,
+    error::Error,
+    file::{AccessCondition, CommunicationMode, FileId, FileSettings, FileSettingsDetails},
+    framing::FrameCodec,
+    key::{Key, KeyNumber, KeySettings},
+    rng::RandomSource,
+    session::Session,
+    status::Status,
+    transport::{Transport, MAX_FRAME_SIZE},
+    types::U24,
+    version::VersionInfo,
+};
+
+/// Most applications a `DESFire` card can hold.
+pub const MAX_INVENTORY_APPLICATIONS: usize = 28;
+
+/// Most files a `DESFire` application can hold.
+pub const MAX_INVENTORY_FILES: usize = 32;
+
+/// Keys tried while taking an inventory.
+///
+/// Application keys are looked up by application and key number; the PICC
+/// master key is used for the card-level listing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InventoryKeys {
+    picc: Option<Key>,
+    applications: Vec<(ApplicationId, KeyNumber, Key)>,
+}
+
+impl InventoryKeys {
+    /// Creates an empty key set; only free data is read.
+    pub const fn new() -> Self {
+        Self {
+            picc: None,
+            applications: Vec::new(),
+        }
+    }
+
+    /// Adds the PICC master key.
+    #[must_use]
+    pub fn with_picc_key(mut self, key: Key) -> Self {
+        self.picc = Some(key);
+        self
+    }
+
+    /// Adds one application key, replacing any key already given for the slot.
+    #[must_use]
+    pub fn with_application_key(
+        mut self,
+        application_id: ApplicationId,
+        key_number: KeyNumber,
+        key: Key,
+    ) -> Self {
+        self.applications
+            .retain(|(aid, number, _)| (*aid, *number) != (application_id, key_number));
+        self.applications.push((application_id, key_number, key));
+        self
+    }
+
+    /// PICC master key, when supplied.
+    pub const fn picc_key(&self) -> Option<&Key> {
+        self.picc.as_ref()
+    }
+
+    /// Application key for one slot, when supplied.
+    pub fn application_key(
+        &self,
+        application_id: ApplicationId,
+        key_number: KeyNumber,
+    ) -> Option<&Key> {
+        self.applications
+            .iter()
+            .find(|(aid, number, _)| (*aid, *number) == (application_id, key_number))
+            .map(|(_, _, key)| key)
+    }
+}
+
+/// Why part of an inventory could not be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailable {
+    /// Reading requires this key, but it was not supplied.
+    MissingKey(KeyNumber),
+    /// The access rights do not allow reading with any key.
+    Denied,
+    /// The card refused the command or the exchange failed.
+    Failed(Error),
+}
+
+impl From<Error> for Unavailable {
+    fn from(error: Error) -> Self {
+        Self::Failed(error)
+    }
+}
+
+/// Snapshot of a whole card.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CardInventory {
+    pub version: Result<VersionInfo, Unavailable>,
+    pub free_memory: Result<U24, Unavailable>,
+    pub key_settings: Result<KeySettings, Unavailable>,
+    pub key_versions: Vec<(KeyNumber, Result<u8, Unavailable>)>,
+    pub applications: Result<Vec<ApplicationInventory>, Unavailable>,
+}
+
+/// Snapshot of one application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApplicationInventory {
+    pub application_id: ApplicationId,
+    pub key_settings: Result<KeySettings, Unavailable>,
+    pub key_versions: Vec<(KeyNumber, Result<u8, Unavailable>)>,
+    pub files: Result<Vec<FileInventory>, Unavailable>,
+}
+
+/// Snapshot of one file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInventory {
+    pub file_id: FileId,
+    pub settings: Result<FileSettings, Unavailable>,
+    pub contents: Result<FileContents, Unavailable>,
+}
+
+/// Contents of one file, by file type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileContents {
+    /// Bytes of a standard or backup data file.
+    Data(Vec<u8>),
+    /// Committed value of a value file.
+    Value(i32),
+    /// Records of a linear or cyclic record file, oldest first.
+    Records(Vec<Vec<u8>>),
+}
+
+impl CardInventory {
+    /// Walks the card and reads everything the supplied keys allow.
+    ///
+    /// Each authentication draws a fresh reader challenge from `random`.
+    /// Only failing to select the PICC aborts the walk, since nothing else can
+    /// be read after that.
+    pub fn read<T, C, R>(
+        desfire: &mut Desfire<T, C>,
+        keys: &InventoryKeys,
+        random: &mut R,
+    ) -> Result<Self, Error>
+    where
+        T: Transport,
+        C: FrameCodec,
+        R: RandomSource + ?Sized,
+    {
+        desfire.select_application(ApplicationId::PICC)?;
+        let version = desfire.get_version().map_err(Unavailable::from);
+        let master = match keys.picc_key() {
+            Some(key) => authenticate_key(desfire, master_key_number(), key, random)
+                .map_err(Unavailable::from),
+            None => Err(Unavailable::MissingKey(master_key_number())),
+        };
+
+        let free_memory = desfire.free_memory().map_err(Unavailable::from);
+        let key_settings = desfire
+            .get_key_settings()
+            .map_err(|error| explain(error, master));
+        let key_versions = read_key_versions(desfire, key_settings);
+
+        let mut application_ids: HeaplessVec<ApplicationId, MAX_INVENTORY_APPLICATIONS> =
+            HeaplessVec::new();
+        let applications = match desfire.get_application_ids(&mut application_ids) {
+            Ok(()) => Ok(application_ids
+                .iter()
+                .map(|application_id| read_application(desfire, keys, *application_id, random))
+                .collect()),
+            Err(error) => Err(explain(error, master)),
+        };
+
+        Ok(Self {
+            version,
+            free_memory,
+            key_settings,
+            key_versions,
+            applications,
+        })
+    }
+
+    /// Returns one application's snapshot, when the application list was read.
+    pub fn application(&self, application_id: ApplicationId) -> Option<&ApplicationInventory> {
+        self.applications
+            .as_ref()
+            .ok()?
+            .iter()
+            .find(|application| application.application_id == application_id)
+    }
+}
+
+impl ApplicationInventory {
+    /// Returns one file's snapshot, when the file list was read.
+    pub fn file(&self, file_id: FileId) -> Option<&FileInventory> {
+        self.files
+            .as_ref()
+            .ok()?
+            .iter()
+            .find(|file| file.file_id == file_id)
+    }
+}
+
+/// Authenticates with the command matching the key's cipher family.
+pub(crate) fn authenticate_key<T, C, R>(
+    desfire: &mut Desfire<T, C>,
+    key_number: KeyNumber,
+    key: &Key,
+    random: &mut R,
+) -> Result<(), Error>
+where
+    T: Transport,
+    C: FrameCodec,
+    R: RandomSource + ?Sized,
+{
+    match key {
+        Key::Des(key) => desfire.authenticate_des(key_number, key, random)?,
+        Key::TwoKey3Des(key) => desfire.authenticate_2tdea(key_number, key, random)?,
+        Key::ThreeKey3Des(key) => desfire.authenticate_3tdea(key_number, key, random)?,
+        Key::Aes128(key) => desfire.authenticate_aes(key_number, key, random)?,
+    };
+    Ok(())
+}
+
+fn read_application<T, C, R>(
+    desfire: &mut Desfire<T, C>,
+    keys: &InventoryKeys,
+    application_id: ApplicationId,
+    random: &mut R,
+) -> ApplicationInventory
+where
+    T: Transport,
+    C: FrameCodec,
+    R: RandomSource + ?Sized,
+{
+    let mut inventory = ApplicationInventory {
+        application_id,
+        key_settings: Err(Unavailable::Denied),
+        key_versions: Vec::new(),
+        files: Err(Unavailable::Denied),
+    };
+    if let Err(error) = desfire.select_application(application_id) {
+        inventory.key_settings = Err(error.into());
+        inventory.files = Err(error.into());
+        return inventory;
+    }
+
+    let master = match keys.application_key(application_id, master_key_number()) {
+        Some(key) => {
+            authenticate_key(desfire, master_key_number(), key, random).map_err(Unavailable::from)
+        }
+        None => Err(Unavailable::MissingKey(master_key_number())),
+    };
+    inventory.key_settings = desfire
+        .get_key_settings()
+        .map_err(|error| explain(error, master));
+    inventory.key_versions = read_key_versions(desfire, inventory.key_settings);
+
+    let mut file_ids: HeaplessVec<FileId, MAX_INVENTORY_FILES> = HeaplessVec::new();
+    if let Err(error) = desfire.get_file_ids(&mut file_ids) {
+        inventory.files = Err(explain(error, master));
+        return inventory;
+    }
+
+    // Settings first: reading contents switches to the file keys.
+    let settings: Vec<(FileId, Result<FileSettings, Unavailable>)> = file_ids
+        .iter()
+        .map(|file_id| {
+            let settings = desfire
+                .get_file_settings(*file_id)
+                .map_err(|error| explain(error, master));
+            (*file_id, settings)
+        })
+        .collect();
+
+    inventory.files = Ok(settings
+        .into_iter()
+        .map(|(file_id, settings)| FileInventory {
+            file_id,
+            settings,
+            contents: settings.and_then(|settings| {
+                read_contents(desfire, keys, application_id, file_id, settings, random)
+            }),
+        })
+        .collect());
+    inventory
+}
+
+fn read_key_versions<T, C>(
+    desfire: &mut Desfire<T, C>,
+    key_settings: Result<KeySettings, Unavailable>,
+) -> Vec<(KeyNumber, Result<u8, Unavailable>)>
+where
+    T: Transport,
+    C: FrameCodec,
+{
+    let Ok(key_settings) = key_settings else {
+        return Vec::new();
+    };
+    (0..key_settings.key_count())
+        .filter_map(|key_number| KeyNumber::new(key_number).ok())
+        .map(|key_number| {
+            let version = desfire
+                .get_key_version(key_number)
+                .map_err(Unavailable::from);
+            (key_number, version)
+        })
+        .collect()
+}
+
+fn read_contents<T, C, R>(
+    desfire: &mut Desfire<T, C>,
+    keys: &InventoryKeys,
+    application_id: ApplicationId,
+    file_id: FileId,
+    settings: FileSettings,
+    random: &mut R,
+) -> Result<FileContents, Unavailable>
+where
+    T: Transport,
+    C: FrameCodec,
+    R: RandomSource + ?Sized,
+{
+    let rights = settings.access_rights();
+    let conditions: &[AccessCondition] = match settings.details() {
+        // GetValue is also granted by the write key.
+        FileSettingsDetails::Value { .. } => &[rights.read(), rights.write(), rights.read_write()],
+        _ => &[rights.read(), rights.read_write()],
+    };
+
+    let communication_mode = if conditions.contains(&AccessCondition::Free) {
+        // Free access is always plain; drop any session so the card agrees.
+        if *desfire.session() != Session::Unauthenticated {
+            desfire.select_application(application_id)?;
+        }
+        CommunicationMode::Plain
+    } else {
+        let mut candidates: HeaplessVec<KeyNumber, 3> = HeaplessVec::new();
+        for condition in conditions {
+            if let AccessCondition::Key(key_number) = condition {
+                if !candidates.contains(key_number) {
+                    candidates
+                        .push(*key_number)
+                        .expect("one slot per condition");
+                }
+            }
+        }
+        let mut missing = None;
+        let mut failed = None;
+        let mut unlocked = false;
+        for key_number in candidates {
+            let Some(key) = keys.application_key(application_id, key_number) else {
+                missing.get_or_insert(key_number);
+                continue;
+            };
+            // A rejected key leaves the card unauthenticated, so the next
+            // candidate can still be tried.
+            if let Err(error) = authenticate_key(desfire, key_number, key, random) {
+                failed.get_or_insert(error);
+                continue;
+            }
+            unlocked = true;
+            break;
+        }
+        if !unlocked {
+            return Err(match (failed, missing) {
+                (Some(error), _) => Unavailable::Failed(error),
+                (None, Some(key_number)) => Unavailable::MissingKey(key_number),
+                (None, None) => Unavailable::Denied,
+            });
+        }
+        settings.communication_mode()
+    };
+
+    let zero = U24::new(0).expect("zero is a valid U24");
+    match settings.details() {
+        FileSettingsDetails::Data { size } => {
+            let mut data = Vec::new();
+            desfire.read_data_streamed(file_id, communication_mode, zero, size, &mut data)?;
+            Ok(FileContents::Data(data))
+        }
+        FileSettingsDetails::Value { .. } => Ok(FileContents::Value(
+            desfire.get_value(file_id, communication_mode)?,
+        )),
+        FileSettingsDetails::Record {
+            record_size,
+            current_records,
+            ..
+        } => {
+            if current_records.as_u32() == 0 {
+                return Ok(FileContents::Records(Vec::new()));
+            }
+            // One read from the newest record back returns them oldest first.
+            let mut data: HeaplessVec<u8, MAX_FRAME_SIZE> = HeaplessVec::new();
+            let records = desfire
+                .read_records(
+                    file_id,
+                    communication_mode,
+                    record_size,
+                    zero,
+                    current_records,
+                    &mut data,
+                )?
+                .iter()
+                .map(<[u8]>::to_vec)
+                .collect();
+            Ok(FileContents::Records(records))
+        }
+    }
+}
+
+/// Attributes an access failure to the master key that could not be used.
+fn explain(error: Error, master: Result<(), Unavailable>) -> Unavailable {
+    match (error, master) {
+        (Error::Status(Status::PermissionDenied | Status::AuthenticationError), Err(reason)) => {
+            reason
+        }
+        _ => Unavailable::Failed(error),
+    }
+}
+
+fn master_key_number() -> KeyNumber {
+    KeyNumber::new(0).expect("key 0 is valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::{
+        application::ApplicationId,
+        client::Desfire,
+        error::Error,
+        file::{AccessCondition, AccessRights, CommunicationMode, FileId},
+        framing::NativeFraming,
+        inventory::{CardInventory, FileContents, InventoryKeys, Unavailable},
+        key::{ApplicationKeyType, Key, KeyNumber, KeySettings},
+        rng::FixedRandom,
+        sim::{VirtualApplication, VirtualDesfire, VirtualFile, DEFAULT_UID},
+        status::Status,
+        types::U24,
+    };
+
+    const AID: ApplicationId = ApplicationId::from_bytes([0x01, 0x02, 0x03]);
+    const MASTER_KEY: [u8; 16] = [0x10; 16];
+    const READ_KEY: [u8; 16] = [0x11; 16];
+
+    fn key(number: u8) -> KeyNumber {
+        KeyNumber::new(number).unwrap()
+    }
+
+    fn file(number: u8) -> FileId {
+        FileId::new(number).unwrap()
+    }
+
+    fn u24(value: u32) -> U24 {
+        U24::new(value).unwrap()
+    }
+
+    fn rights(read: AccessCondition) -> AccessRights {
+        let master = AccessCondition::Key(key(0));
+        AccessRights::new(read, master, AccessCondition::Never, master)
+    }
+
+    fn card(key_settings: u8) -> VirtualDesfire {
+        let free = AccessCondition::Free;
+        let read_key = AccessCondition::Key(key(1));
+        let application = VirtualApplication::new(
+            AID,
+            KeySettings::new(key_settings, ApplicationKeyType::Aes, 3),
+        )
+        .unwrap()
+        .with_key(key(0), Key::Aes128(MASTER_KEY), 4)
+        .unwrap()
+        .with_key(key(1), Key::Aes128(READ_KEY), 7)
+        .unwrap()
+        .with_file(
+            file(0),
+            VirtualFile::std_data(CommunicationMode::Plain, rights(free), b"public"),
+        )
+        .with_file(
+            file(1),
+            VirtualFile::std_data(CommunicationMode::Enciphered, rights(read_key), b"secret"),
+        )
+        .with_file(
+            file(2),
+            VirtualFile::value(
+                CommunicationMode::Maced,
+                rights(read_key),
+                0,
+                1000,
+                250,
+                false,
+            ),
+        )
+        .with_file(
+            file(3),
+            VirtualFile::cyclic_record(CommunicationMode::Plain, rights(free), u24(4), u24(4)),
+        )
+        .with_file(
+            file(4),
+            VirtualFile::std_data(
+                CommunicationMode::Plain,
+                rights(AccessCondition::Key(key(2))),
+                b"locked",
+            ),
+        )
+        .with_file(
+            file(5),
+            VirtualFile::std_data(
+                CommunicationMode::Plain,
+                rights(AccessCondition::Never),
+                b"never",
+            ),
+        );
+        VirtualDesfire::new().with_application(application)
+    }
+
+    fn random() -> FixedRandom {
+        FixedRandom::new(&[0xA5])
+    }
+
+    #[test]
+    fn reads_everything_the_keys_allow() {
+        let mut card = card(0x0F);
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_aes(key(0), &MASTER_KEY, &mut random())
+            .unwrap();
+        for record in [b"one.", b"two."] {
+            desfire
+                .write_record(file(3), CommunicationMode::Plain, u24(0), record)
+                .unwrap();
+            desfire.commit_transaction().unwrap();
+        }
+        let keys = InventoryKeys::new()
+            .with_picc_key(Key::TwoKey3Des([0; 16]))
+            .with_application_key(AID, key(0), Key::Aes128(MASTER_KEY))
+            .with_application_key(AID, key(1), Key::Aes128(READ_KEY));
+
+        let inventory = CardInventory::read(&mut desfire, &keys, &mut random()).unwrap();
+
+        assert_eq!(inventory.version.unwrap().uid(), DEFAULT_UID);
+        assert!(inventory.free_memory.is_ok());
+        assert_eq!(inventory.key_versions, [(key(0), Ok(0))]);
+
+        let application = inventory.application(AID).unwrap();
+        assert_eq!(application.key_settings.unwrap().key_count(), 3);
+        assert_eq!(application.key_versions[1], (key(1), Ok(7)));
+        let contents = |number| application.file(file(number)).unwrap().contents.clone();
+        assert_eq!(contents(0), Ok(FileContents::Data(b"public".to_vec())));
+        assert_eq!(contents(1), Ok(FileContents::Data(b"secret".to_vec())));
+        assert_eq!(contents(2), Ok(FileContents::Value(250)));
+        assert_eq!(
+            contents(3),
+            Ok(FileContents::Records(std::vec![
+                b"one.".to_vec(),
+                b"two.".to_vec()
+            ]))
+        );
+        assert_eq!(contents(4), Err(Unavailable::MissingKey(key(2))));
+        assert_eq!(contents(5), Err(Unavailable::Denied));
+    }
+
+    #[test]
+    fn tries_every_key_that_opens_a_file() {
+        let mut card = card(0x0F);
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        let wrong = Key::Aes128([0xEE; 16]);
+        let keys = InventoryKeys::new()
+            .with_application_key(AID, key(0), Key::Aes128(MASTER_KEY))
+            .with_application_key(AID, key(1), wrong);
+
+        let inventory = CardInventory::read(&mut desfire, &keys, &mut random()).unwrap();
+
+        let application = inventory.application(AID).unwrap();
+        let contents = |number| application.file(file(number)).unwrap().contents.clone();
+        // Key 1 is rejected, but the value file's write key still reads it.
+        assert_eq!(contents(2), Ok(FileContents::Value(250)));
+        assert!(matches!(
+            contents(1),
+            Err(Unavailable::Failed(
+                Error::AuthenticationFailed | Error::Status(Status::AuthenticationError)
+            ))
+        ));
+        assert_eq!(contents(3), Ok(FileContents::Records(std::vec::Vec::new())));
+    }
+
+    #[test]
+    fn marks_listing_that_needs_a_missing_master_key() {
+        // Key settings 0x0D: listing the application requires its master key.
+        let mut card = card(0x0D);
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+
+        let inventory =
+            CardInventory::read(&mut desfire, &InventoryKeys::new(), &mut random()).unwrap();
+
+        assert!(inventory.key_settings.is_ok());
+        let application = inventory.application(AID).unwrap();
+        let missing = Unavailable::MissingKey(key(0));
+        assert_eq!(application.key_settings, Err(missing));
+        assert!(application.key_versions.is_empty());
+        assert_eq!(application.files, Err(missing));
+    }
+
+    #[test]
+    fn reports_failed_authentication() {
+        let mut card = card(0x0D);
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        let keys = InventoryKeys::new().with_application_key(AID, key(0), Key::Aes128([0xEE; 16]));
+
+        let inventory = CardInventory::read(&mut desfire, &keys, &mut random()).unwrap();
+
+        let application = inventory.application(AID).unwrap();
+        assert!(matches!(
+            application.files,
+            Err(Unavailable::Failed(
+                Error::AuthenticationFailed | Error::Status(Status::AuthenticationError)
+            ))
+        ));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::mifare::desfire::{
+    command::CommandData,
+    error::Error,
+    framing::wrapped::encode_apdu,
+    iso::{DfName, IsoFileId},
+    transport::Frame,
+};
+
+/// Target of an `ISOSelectFile` command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IsoSelect {
+    /// Select a DF or EF by ISO file identifier (`P1 = 0x00`).
+    FileId(IsoFileId),
+    /// Select an application by DF name (`P1 = 0x04`).
+    DfName(DfName),
+}
+
+/// ISO 7816-4 command APDU sent alongside native `DESFire` commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsoCommand {
+    header: [u8; 4],
+    data: CommandData,
+    le: Option<u8>,
+}
+
+impl IsoCommand {
+    const CLA: u8 = 0x00;
+    const INS_SELECT_FILE: u8 = 0xA4;
+    const INS_READ_BINARY: u8 = 0xB0;
+    const INS_UPDATE_BINARY: u8 = 0xD6;
+    const INS_READ_RECORDS: u8 = 0xB2;
+
+    /// Highest offset encodable in `P1`/`P2` when no short file identifier is used.
+    pub const MAX_BINARY_OFFSET: u16 = 0x7FFF;
+
+    /// Builds a short APDU from its header, command data and optional `Le`.
+    pub fn new(header: [u8; 4], data: &[u8], le: Option<u8>) -> Result<Self, Error> {
+        let mut out = CommandData::new();
+        out.extend_from_slice(data)
+            .map_err(|_| Error::CommandTooLong)?;
+        Ok(Self {
+            header,
+            data: out,
+            le,
+        })
+    }
+
+    /// `ISOSelectFile` without returning FCI (`P2 = 0x0C`).
+    pub fn select_file(target: &IsoSelect) -> Result<Self, Error> {
+        match target {
+            IsoSelect::FileId(file_id) => Self::new(
+                [Self::CLA, Self::INS_SELECT_FILE, 0x00, 0x0C],
+                &file_id.to_be_bytes(),
+                None,
+            ),
+            IsoSelect::DfName(name) => Self::new(
+                [Self::CLA, Self::INS_SELECT_FILE, 0x04, 0x0C],
+                name.as_bytes(),
+                None,
+            ),
+        }
+    }
+
+    /// `ISOReadBinary` from the currently selected EF.
+    ///
+    /// A `length` of zero requests the file contents up to the end of the file.
+    pub fn read_binary(offset: u16, length: u8) -> Result<Self, Error> {
+        let [p1, p2] = binary_offset(offset)?;
+        Self::new(
+            [Self::CLA, Self::INS_READ_BINARY, p1, p2],
+            &[],
+            Some(length),
+        )
+    }
+
+    /// `ISOUpdateBinary` on the currently selected EF.
+    pub fn update_binary(offset: u16, data: &[u8]) -> Result<Self, Error> {
+        let [p1, p2] = binary_offset(offset)?;
+        Self::new([Self::CLA, Self::INS_UPDATE_BINARY, p1, p2], data, None)
+    }
+
+    /// `ISOReadRecords` from the currently selected record EF.
+    ///
+    /// Reads the single record `record_number`, or that record and all older
+    /// records when `all_following` is set.
+    pub fn read_records(record_number: u8, all_following: bool) -> Result<Self, Error> {
+        let p2 = if all_following { 0x05 } else { 0x04 };
+        Self::new(
+            [Self::CLA, Self::INS_READ_RECORDS, record_number, p2],
+            &[],
+            Some(0x00),
+        )
+    }
+
+    /// APDU header bytes `[CLA, INS, P1, P2]`.
+    pub const fn header(&self) -> [u8; 4] {
+        self.header
+    }
+
+    /// Command data field.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Expected response length, when present.
+    pub const fn le(&self) -> Option<u8> {
+        self.le
+    }
+
+    /// Encodes the command as a short APDU.
+    pub fn encode(&self, frame: &mut Frame) -> Result<(), Error> {
+        encode_apdu(self.header, &self.data, self.le, frame)
+    }
+}
+
+fn binary_offset(offset: u16) -> Result<[u8; 2], Error> {
+    if offset > IsoCommand::MAX_BINARY_OFFSET {
+        return Err(Error::InvalidIsoOffset(offset));
+    }
+    Ok(offset.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::{
+        error::Error,
+        iso::{DfName, IsoCommand, IsoFileId, IsoSelect},
+        transport::Frame,
+    };
+
+    #[test]
+    fn encodes_select_by_df_name() {
+        let name = DfName::new(&[0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01]).unwrap();
+        let mut frame = Frame::new();
+
+        IsoCommand::select_file(&IsoSelect::DfName(name))
+            .unwrap()
+            .encode(&mut frame)
+            .unwrap();
+
+        assert_eq!(
+            frame.as_slice(),
+            &[0x00, 0xA4, 0x04, 0x0C, 0x07, 0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01]
+        );
+    }
+
+    #[test]
+    fn encodes_select_by_file_id_big_endian() {
+        let mut frame = Frame::new();
+
+        IsoCommand::select_file(&IsoSelect::FileId(IsoFileId::new(0xE104).unwrap()))
+            .unwrap()
+            .encode(&mut frame)
+            .unwrap();
+
+        assert_eq!(
+            frame.as_slice(),
+            &[0x00, 0xA4, 0x00, 0x0C, 0x02, 0xE1, 0x04]
+        );
+    }
+
+    #[test]
+    fn encodes_read_binary_and_rejects_large_offset() {
+        let mut frame = Frame::new();
+
+        IsoCommand::read_binary(0x0102, 0x0F)
+            .unwrap()
+            .encode(&mut frame)
+            .unwrap();
+
+        assert_eq!(frame.as_slice(), &[0x00, 0xB0, 0x01, 0x02, 0x0F]);
+        assert_eq!(
+            IsoCommand::read_binary(0x8000, 0),
+            Err(Error::InvalidIsoOffset(0x8000))
+        );
+    }
+}
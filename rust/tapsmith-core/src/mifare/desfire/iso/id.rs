@@ -0,0 +1,136 @@
+use heapless::Vec;
+
+use crate::mifare::desfire::{application::ApplicationId, error::Error};
+
+/// ISO 7816-4 file identifier for a `DESFire` application (DF) or file (EF).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IsoFileId(u16);
+
+impl IsoFileId {
+    /// Creates a validated ISO file identifier.
+    ///
+    /// `0x3F00` (MF), `0x3FFF` and `0xFFFF` are reserved by ISO 7816-4.
+    pub fn new(value: u16) -> Result<Self, Error> {
+        match value {
+            0x3F00 | 0x3FFF | 0xFFFF => Err(Error::InvalidIsoFileId(value)),
+            _ => Ok(Self(value)),
+        }
+    }
+
+    /// Integer representation of the file identifier.
+    pub const fn as_u16(self) -> u16 {
+        self.0
+    }
+
+    /// Little-endian bytes, as used in native `DESFire` command payloads.
+    pub const fn to_le_bytes(self) -> [u8; 2] {
+        self.0.to_le_bytes()
+    }
+
+    /// Big-endian bytes, as used in ISO 7816-4 APDUs.
+    pub const fn to_be_bytes(self) -> [u8; 2] {
+        self.0.to_be_bytes()
+    }
+}
+
+/// ISO 7816-4 DF name (application identifier) of up to 16 bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DfName(Vec<u8, 16>);
+
+impl DfName {
+    /// Maximum DF name length accepted by `DESFire`.
+    pub const MAX_LEN: usize = 16;
+
+    /// Creates a validated DF name.
+    pub fn new(value: &[u8]) -> Result<Self, Error> {
+        if value.is_empty() {
+            return Err(Error::InvalidDfName(0));
+        }
+
+        Vec::from_slice(value)
+            .map(Self)
+            .map_err(|_| Error::InvalidDfName(value.len()))
+    }
+
+    /// Raw DF name bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// One application entry returned by `GetDFNames`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfNameEntry {
+    application_id: ApplicationId,
+    iso_file_id: IsoFileId,
+    df_name: DfName,
+}
+
+impl DfNameEntry {
+    /// Parses one `GetDFNames` response frame: `[AID(3) || ISO FID(2, LE) || DF name]`.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < 6 {
+            return Err(Error::InvalidResponseLength);
+        }
+
+        let application_id = ApplicationId::from_bytes([data[0], data[1], data[2]]);
+        let iso_file_id = IsoFileId::new(u16::from_le_bytes([data[3], data[4]]))?;
+        let df_name = DfName::new(&data[5..]).map_err(|_| Error::InvalidResponseLength)?;
+
+        Ok(Self {
+            application_id,
+            iso_file_id,
+            df_name,
+        })
+    }
+
+    /// Native `DESFire` application identifier.
+    pub const fn application_id(&self) -> ApplicationId {
+        self.application_id
+    }
+
+    /// ISO file identifier of the application DF.
+    pub const fn iso_file_id(&self) -> IsoFileId {
+        self.iso_file_id
+    }
+
+    /// ISO DF name of the application.
+    pub const fn df_name(&self) -> &DfName {
+        &self.df_name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::{
+        error::Error,
+        iso::{DfName, DfNameEntry, IsoFileId},
+    };
+
+    #[test]
+    fn rejects_reserved_iso_file_ids() {
+        assert_eq!(IsoFileId::new(0x3F00), Err(Error::InvalidIsoFileId(0x3F00)));
+        assert_eq!(IsoFileId::new(0xE104).unwrap().to_le_bytes(), [0x04, 0xE1]);
+    }
+
+    #[test]
+    fn rejects_empty_or_oversized_df_names() {
+        assert_eq!(DfName::new(&[]), Err(Error::InvalidDfName(0)));
+        assert_eq!(DfName::new(&[0xAA; 17]), Err(Error::InvalidDfName(17)));
+    }
+
+    #[test]
+    fn parses_df_name_entry() {
+        let entry = DfNameEntry::parse(&[
+            0x01, 0x00, 0xF0, 0x05, 0xE1, 0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01,
+        ])
+        .unwrap();
+
+        assert_eq!(entry.application_id().as_u32(), 0xF0_00_01);
+        assert_eq!(entry.iso_file_id().as_u16(), 0xE105);
+        assert_eq!(
+            entry.df_name().as_bytes(),
+            &[0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01]
+        );
+    }
+}
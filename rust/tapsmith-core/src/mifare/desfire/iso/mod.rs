@@ -0,0 +1,5 @@
+pub mod apdu;
+pub mod id;
+
+pub use apdu::{IsoCommand, IsoSelect};
+pub use id::{DfName, DfNameEntry, IsoFileId};
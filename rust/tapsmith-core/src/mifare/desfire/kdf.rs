@@ -0,0 +1,483 @@
+//! NXP AN10922 key diversification for `DESFire` keys.
+//!
+//! A master key and a caller-defined diversification input `M` (usually the card
+//! UID, an application id and a system identifier) give a per-card key by
+//! CMAC-ing `M` behind a per-key-type constant.
+
+use aes::{
+    cipher::{BlockEncrypt, KeyInit},
+    Aes128,
+};
+use des::{TdesEde2, TdesEde3};
+use heapless::Vec;
+
+use crate::mifare::desfire::{
+    application::ApplicationId,
+    error::Error,
+    key::{Key, KeyAlgorithm},
+};
+
+/// Longest diversification input accepted for AES-128 keys.
+pub const MAX_AES_INPUT_SIZE: usize = 31;
+
+/// Longest diversification input accepted for 2TDEA and 3TDEA keys.
+pub const MAX_TDEA_INPUT_SIZE: usize = 15;
+
+/// Diversification input `M`, built from parts in the order they are added.
+///
+/// The length limit depends on the key type and is checked when a key is derived.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiversificationInput(Vec<u8, MAX_AES_INPUT_SIZE>);
+
+impl DiversificationInput {
+    /// Creates an empty input.
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Creates an input from raw `M` bytes.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        Self::new().with_bytes(data)
+    }
+
+    /// Appends the card UID.
+    pub fn with_uid(self, uid: &[u8]) -> Result<Self, Error> {
+        self.with_bytes(uid)
+    }
+
+    /// Appends an application id in raw `DESFire` byte order.
+    pub fn with_application_id(self, application_id: ApplicationId) -> Result<Self, Error> {
+        self.with_bytes(&application_id.as_bytes())
+    }
+
+    /// Appends a system identifier, such as an issuer name.
+    pub fn with_system_identifier(self, system_identifier: &[u8]) -> Result<Self, Error> {
+        self.with_bytes(system_identifier)
+    }
+
+    /// Appends arbitrary bytes.
+    pub fn with_bytes(mut self, data: &[u8]) -> Result<Self, Error> {
+        self.0
+            .extend_from_slice(data)
+            .map_err(|_| Error::InvalidDiversificationInput(self.0.len() + data.len()))?;
+        Ok(self)
+    }
+
+    /// Raw `M` bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Diversifies an AES-128 key: `CMAC(K, 0x01 || M)` with `M` padded to 32 bytes.
+pub fn diversify_aes128_key(
+    master_key: &[u8; 16],
+    input: &DiversificationInput,
+) -> Result<[u8; 16], Error> {
+    let cipher = Aes128::new(master_key.into());
+    aes128_key(input, |block| {
+        cipher.encrypt_block(block.into());
+        Ok(())
+    })
+}
+
+/// Diversifies a 2TDEA key from two CMACs with constants `0x21` and `0x22`.
+///
+/// The output keeps the raw CMAC bits; `DESFire` key versions live in the parity
+/// bits and are applied when the key is written to the card.
+pub fn diversify_2tdea_key(
+    master_key: &[u8; 16],
+    input: &DiversificationInput,
+) -> Result<[u8; 16], Error> {
+    let cipher = TdesEde2::new(master_key.into());
+    tdea_key(input, 0x21, |block| {
+        cipher.encrypt_block(block.into());
+        Ok(())
+    })
+}
+
+/// Diversifies a 3TDEA key from three CMACs with constants `0x31` to `0x33`.
+///
+/// As for [`diversify_2tdea_key`], the parity bits are left as calculated.
+pub fn diversify_3tdea_key(
+    master_key: &[u8; 24],
+    input: &DiversificationInput,
+) -> Result<[u8; 24], Error> {
+    let cipher = TdesEde3::new(master_key.into());
+    tdea_key(input, 0x31, |block| {
+        cipher.encrypt_block(block.into());
+        Ok(())
+    })
+}
+
+/// Diversifies a key of `algorithm` with `encrypt_block` enciphering single blocks
+/// under the master key.
+///
+/// For crypto providers that keep the master key in a token and can only be asked
+/// to encrypt with it. The derived key is returned in the clear.
+pub fn diversify_key_with(
+    algorithm: KeyAlgorithm,
+    input: &DiversificationInput,
+    mut encrypt_block: impl FnMut(&mut [u8]) -> Result<(), Error>,
+) -> Result<Key, Error> {
+    match algorithm {
+        KeyAlgorithm::Aes128 => aes128_key(input, |block| encrypt_block(block)).map(Key::Aes128),
+        KeyAlgorithm::TwoKey3Des => {
+            tdea_key(input, 0x21, |block| encrypt_block(block)).map(Key::TwoKey3Des)
+        }
+        KeyAlgorithm::ThreeKey3Des => {
+            tdea_key(input, 0x31, |block| encrypt_block(block)).map(Key::ThreeKey3Des)
+        }
+        KeyAlgorithm::Des => Err(Error::UnsupportedAlgorithm),
+    }
+}
+
+/// Last cipher call of one AN10922 CMAC.
+///
+/// The derived key part is [`Self::block`] CBC-encrypted under the master key
+/// with [`Self::chaining`] as the IV.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FinalCmacBlock {
+    chaining: Vec<u8, 16>,
+    block: Vec<u8, 16>,
+}
+
+impl FinalCmacBlock {
+    fn new<const B: usize>((chaining, block): ([u8; B], [u8; B])) -> Self {
+        Self {
+            chaining: Vec::from_slice(&chaining).expect("one block"),
+            block: Vec::from_slice(&block).expect("one block"),
+        }
+    }
+
+    /// CMAC state before the last block, used as the IV.
+    pub fn chaining(&self) -> &[u8] {
+        &self.chaining
+    }
+
+    /// Last message block, already masked with the CMAC subkey.
+    pub fn block(&self) -> &[u8] {
+        &self.block
+    }
+}
+
+/// Runs AN10922 for a key of `algorithm` up to the last cipher call of each CMAC.
+///
+/// For crypto providers that can finish a CMAC inside a token, such as with a
+/// PKCS#11 `CBC_ENCRYPT_DATA` key derivation, so the derived key never leaves
+/// it. `encrypt_block` enciphers single blocks under the master key. There is
+/// one step per key part, in order: one for AES-128, two for 2TDEA and three
+/// for 3TDEA.
+pub fn final_cmac_blocks_with(
+    algorithm: KeyAlgorithm,
+    input: &DiversificationInput,
+    mut encrypt_block: impl FnMut(&mut [u8]) -> Result<(), Error>,
+) -> Result<Vec<FinalCmacBlock, 3>, Error> {
+    let (first_constant, parts) = match algorithm {
+        KeyAlgorithm::Aes128 => {
+            let step =
+                cmac_final_block::<16>(input, 0x01, MAX_AES_INPUT_SIZE, 32, 0x87, |block| {
+                    encrypt_block(block)
+                })?;
+            return Ok(Vec::from_iter([FinalCmacBlock::new(step)]));
+        }
+        KeyAlgorithm::TwoKey3Des => (0x21, 2),
+        KeyAlgorithm::ThreeKey3Des => (0x31, 3),
+        KeyAlgorithm::Des => return Err(Error::UnsupportedAlgorithm),
+    };
+
+    let mut steps = Vec::new();
+    for constant in (first_constant..).take(parts) {
+        let step =
+            cmac_final_block::<8>(input, constant, MAX_TDEA_INPUT_SIZE, 16, 0x1B, |block| {
+                encrypt_block(block)
+            })?;
+        steps
+            .push(FinalCmacBlock::new(step))
+            .expect("at most three parts");
+    }
+    Ok(steps)
+}
+
+fn aes128_key(
+    input: &DiversificationInput,
+    encrypt: impl FnMut(&mut [u8; 16]) -> Result<(), Error>,
+) -> Result<[u8; 16], Error> {
+    cmac_part(input, 0x01, MAX_AES_INPUT_SIZE, 32, 0x87, encrypt)
+}
+
+/// One 8-byte CMAC per key part, with constants counting up from `first_constant`.
+fn tdea_key<const N: usize>(
+    input: &DiversificationInput,
+    first_constant: u8,
+    mut encrypt: impl FnMut(&mut [u8; 8]) -> Result<(), Error>,
+) -> Result<[u8; N], Error> {
+    let mut key = [0u8; N];
+    for (part, constant) in key.chunks_exact_mut(8).zip(first_constant..) {
+        part.copy_from_slice(&cmac_part(
+            input,
+            constant,
+            MAX_TDEA_INPUT_SIZE,
+            16,
+            0x1B,
+            &mut encrypt,
+        )?);
+    }
+    Ok(key)
+}
+
+/// CMAC over `constant || M`, padded with `0x80 00..` up to `padded_len` bytes.
+fn cmac_part<const B: usize>(
+    input: &DiversificationInput,
+    constant: u8,
+    max_input_len: usize,
+    padded_len: usize,
+    reduction: u8,
+    mut encrypt: impl FnMut(&mut [u8; B]) -> Result<(), Error>,
+) -> Result<[u8; B], Error> {
+    let (mut state, last_block) = cmac_final_block(
+        input,
+        constant,
+        max_input_len,
+        padded_len,
+        reduction,
+        &mut encrypt,
+    )?;
+    for (byte, data) in state.iter_mut().zip(last_block) {
+        *byte ^= data;
+    }
+    encrypt(&mut state)?;
+    Ok(state)
+}
+
+/// CMAC over `constant || M` up to its last cipher call: the chaining value
+/// and the masked last block.
+///
+/// AN10922 pads (and uses subkey `K2`) whenever the input is shorter than
+/// `padded_len`, even if it already ends on a block boundary.
+fn cmac_final_block<const B: usize>(
+    input: &DiversificationInput,
+    constant: u8,
+    max_input_len: usize,
+    padded_len: usize,
+    reduction: u8,
+    mut encrypt: impl FnMut(&mut [u8; B]) -> Result<(), Error>,
+) -> Result<([u8; B], [u8; B]), Error> {
+    let m = input.as_bytes();
+    if m.is_empty() || m.len() > max_input_len {
+        return Err(Error::InvalidDiversificationInput(m.len()));
+    }
+
+    let mut message: Vec<u8, 32> = Vec::new();
+    message.push(constant).expect("capacity is sufficient");
+    message
+        .extend_from_slice(m)
+        .expect("capacity is sufficient");
+
+    let mut subkey = [0u8; B];
+    encrypt(&mut subkey)?;
+    double_subkey(&mut subkey, reduction);
+    if message.len() < padded_len {
+        double_subkey(&mut subkey, reduction);
+        message.push(0x80).expect("capacity is sufficient");
+        message
+            .resize(padded_len, 0x00)
+            .expect("capacity is sufficient");
+    }
+
+    let (blocks, last_block) = message.split_at(message.len() - B);
+    let mut last_block: [u8; B] = last_block.try_into().expect("one block");
+    for (byte, mask) in last_block.iter_mut().zip(subkey) {
+        *byte ^= mask;
+    }
+
+    let mut state = [0u8; B];
+    for block in blocks.chunks_exact(B) {
+        for (byte, data) in state.iter_mut().zip(block) {
+            *byte ^= data;
+        }
+        encrypt(&mut state)?;
+    }
+    Ok((state, last_block))
+}
+
+fn double_subkey<const B: usize>(block: &mut [u8; B], reduction: u8) {
+    let carry = block[0] & 0x80 != 0;
+    let mut shifted_in = 0;
+    for byte in block.iter_mut().rev() {
+        let next = *byte >> 7;
+        *byte = (*byte << 1) | shifted_in;
+        shifted_in = next;
+    }
+    if carry {
+        block[B - 1] ^= reduction;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::{
+        application::ApplicationId,
+        crypto::{aes_cbc_encrypt_in_place, tdes2_cbc_encrypt_in_place},
+        error::Error,
+        kdf::{
+            diversify_2tdea_key, diversify_3tdea_key, diversify_aes128_key, diversify_key_with,
+            final_cmac_blocks_with, DiversificationInput,
+        },
+        key::{Key, KeyAlgorithm},
+    };
+
+    const MASTER_KEY: [u8; 16] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE,
+        0xFF,
+    ];
+
+    /// AN10922 example: UID `04782E21801D80`, AID `3042F5` and a system identifier.
+    fn an10922_input(system_identifier: &[u8]) -> DiversificationInput {
+        DiversificationInput::new()
+            .with_uid(&[0x04, 0x78, 0x2E, 0x21, 0x80, 0x1D, 0x80])
+            .unwrap()
+            .with_application_id(ApplicationId::from_bytes([0x30, 0x42, 0xF5]))
+            .unwrap()
+            .with_system_identifier(system_identifier)
+            .unwrap()
+    }
+
+    #[test]
+    fn diversifies_aes128_key_with_an10922_vector() {
+        let input = an10922_input(b"NXP Abu");
+
+        assert_eq!(
+            diversify_aes128_key(&MASTER_KEY, &input).unwrap(),
+            [
+                0xA8, 0xDD, 0x63, 0xA3, 0xB8, 0x9D, 0x54, 0xB3, 0x7C, 0xA8, 0x02, 0x47, 0x3F, 0xDA,
+                0x91, 0x75,
+            ]
+        );
+    }
+
+    #[test]
+    fn diversifies_2tdea_key_with_an10922_vector() {
+        let input = an10922_input(b"NXP A");
+
+        assert_eq!(
+            diversify_2tdea_key(&MASTER_KEY, &input).unwrap(),
+            [
+                0x16, 0xF8, 0x59, 0x7C, 0x9E, 0x89, 0x10, 0xC8, 0x6B, 0x96, 0x48, 0xD0, 0x06, 0x10,
+                0x7D, 0xD7,
+            ]
+        );
+    }
+
+    #[test]
+    fn diversifies_3tdea_key_with_an10922_vector() {
+        let mut master_key = [0u8; 24];
+        master_key[..16].copy_from_slice(&MASTER_KEY);
+        master_key[16..].copy_from_slice(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+        let input = an10922_input(b"NXP");
+
+        assert_eq!(
+            diversify_3tdea_key(&master_key, &input).unwrap(),
+            [
+                0x2F, 0x0D, 0xD0, 0x36, 0x75, 0xD3, 0xFB, 0x9A, 0x57, 0x05, 0xAB, 0x0B, 0xDA, 0x91,
+                0xCA, 0x0B, 0x55, 0xB8, 0xE0, 0x7F, 0xCD, 0xBF, 0x10, 0xEC,
+            ]
+        );
+    }
+
+    #[test]
+    fn diversifies_through_a_block_cipher_oracle() {
+        let aes_input = an10922_input(b"NXP Abu");
+        let tdea_input = an10922_input(b"NXP A");
+
+        let aes = diversify_key_with(KeyAlgorithm::Aes128, &aes_input, |block| {
+            aes_cbc_encrypt_in_place(&MASTER_KEY, &[0; 16], block);
+            Ok(())
+        })
+        .unwrap();
+        let tdea = diversify_key_with(KeyAlgorithm::TwoKey3Des, &tdea_input, |block| {
+            tdes2_cbc_encrypt_in_place(&MASTER_KEY, &[0; 8], block);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            aes,
+            Key::Aes128(diversify_aes128_key(&MASTER_KEY, &aes_input).unwrap())
+        );
+        assert_eq!(
+            tdea,
+            Key::TwoKey3Des(diversify_2tdea_key(&MASTER_KEY, &tdea_input).unwrap())
+        );
+        assert_eq!(
+            diversify_key_with(KeyAlgorithm::Aes128, &aes_input, |_| Err(
+                Error::CryptoProvider
+            )),
+            Err(Error::CryptoProvider)
+        );
+    }
+
+    #[test]
+    fn final_cmac_blocks_finish_into_the_derived_key() {
+        let aes_input = an10922_input(b"NXP Abu");
+        let tdea_input = an10922_input(b"NXP A");
+
+        let aes = final_cmac_blocks_with(KeyAlgorithm::Aes128, &aes_input, |block| {
+            aes_cbc_encrypt_in_place(&MASTER_KEY, &[0; 16], block);
+            Ok(())
+        })
+        .unwrap();
+        let tdea = final_cmac_blocks_with(KeyAlgorithm::TwoKey3Des, &tdea_input, |block| {
+            tdes2_cbc_encrypt_in_place(&MASTER_KEY, &[0; 8], block);
+            Ok(())
+        })
+        .unwrap();
+
+        let [aes] = aes.as_slice() else {
+            panic!("AES keys have one part");
+        };
+        let mut aes_key: [u8; 16] = aes.block().try_into().unwrap();
+        aes_cbc_encrypt_in_place(
+            &MASTER_KEY,
+            aes.chaining().try_into().unwrap(),
+            &mut aes_key,
+        );
+        assert_eq!(
+            aes_key,
+            diversify_aes128_key(&MASTER_KEY, &aes_input).unwrap()
+        );
+
+        let mut tdea_key = [0u8; 16];
+        for (part, step) in tdea_key.chunks_exact_mut(8).zip(&tdea) {
+            part.copy_from_slice(step.block());
+            tdes2_cbc_encrypt_in_place(&MASTER_KEY, step.chaining().try_into().unwrap(), part);
+        }
+        assert_eq!(tdea.len(), 2);
+        assert_eq!(
+            tdea_key,
+            diversify_2tdea_key(&MASTER_KEY, &tdea_input).unwrap()
+        );
+        assert_eq!(
+            final_cmac_blocks_with(KeyAlgorithm::Des, &tdea_input, |_| Ok(())),
+            Err(Error::UnsupportedAlgorithm)
+        );
+    }
+
+    #[test]
+    fn rejects_input_outside_key_type_limits() {
+        let input = an10922_input(b"NXP Abu");
+
+        assert_eq!(
+            diversify_2tdea_key(&MASTER_KEY, &input),
+            Err(Error::InvalidDiversificationInput(17))
+        );
+        assert_eq!(
+            diversify_aes128_key(&MASTER_KEY, &DiversificationInput::new()),
+            Err(Error::InvalidDiversificationInput(0))
+        );
+        assert_eq!(
+            DiversificationInput::from_bytes(&[0u8; 32]),
+            Err(Error::InvalidDiversificationInput(32))
+        );
+    }
+}
@@ -1,7 +1,14 @@
-use crate::mifare::desfire::error::Error;
+use core::fmt;
+
+use crate::mifare::desfire::{
+    error::Error,
+    secret::{constant_time_eq, zeroize},
+};
 
 /// `DESFire` key material with its cryptographic family encoded in the type.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// The bytes are wiped on drop and never printed.
+#[derive(Clone)]
 pub enum Key {
     Des([u8; 8]),
     TwoKey3Des([u8; 16]),
@@ -9,6 +16,79 @@ pub enum Key {
     Aes128([u8; 16]),
 }
 
+impl Key {
+    /// Raw key bytes.
+    pub const fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Des(key) => key,
+            Self::TwoKey3Des(key) | Self::Aes128(key) => key,
+            Self::ThreeKey3Des(key) => key,
+        }
+    }
+
+    /// Cryptographic family of the key.
+    pub const fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            Self::Des(_) => KeyAlgorithm::Des,
+            Self::TwoKey3Des(_) => KeyAlgorithm::TwoKey3Des,
+            Self::ThreeKey3Des(_) => KeyAlgorithm::ThreeKey3Des,
+            Self::Aes128(_) => KeyAlgorithm::Aes128,
+        }
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        match self {
+            Self::Des(key) => zeroize(key),
+            Self::TwoKey3Des(key) | Self::Aes128(key) => zeroize(key),
+            Self::ThreeKey3Des(key) => zeroize(key),
+        }
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{:?}(..)", self.algorithm())
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.algorithm() == other.algorithm() && constant_time_eq(self.as_bytes(), other.as_bytes())
+    }
+}
+
+impl Eq for Key {}
+
+/// Cipher family of a `DESFire` key, without the key material.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    Des,
+    TwoKey3Des,
+    ThreeKey3Des,
+    Aes128,
+}
+
+impl KeyAlgorithm {
+    /// Key length in bytes.
+    pub const fn key_size(self) -> usize {
+        match self {
+            Self::Des => 8,
+            Self::TwoKey3Des | Self::Aes128 => 16,
+            Self::ThreeKey3Des => 24,
+        }
+    }
+
+    /// Cipher block size in bytes.
+    pub const fn block_size(self) -> usize {
+        match self {
+            Self::Des | Self::TwoKey3Des | Self::ThreeKey3Des => 8,
+            Self::Aes128 => 16,
+        }
+    }
+}
+
 /// `DESFire` key slot number.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct KeyNumber(u8);
@@ -41,6 +121,103 @@ impl From<KeyNumber> for u8 {
     }
 }
 
+/// EV2 application key-set number.
+///
+/// Key set `0` is always the active key set; the others are staged with
+/// `InitializeKeySet` and activated by `RollKeySet`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KeySetNumber(u8);
+
+impl KeySetNumber {
+    /// The active key set.
+    pub const ACTIVE: Self = Self(0);
+
+    /// Highest key-set number; an application holds at most 16 key sets.
+    pub const MAX: u8 = 0x0F;
+
+    /// Creates a validated key-set number.
+    pub fn new(value: u8) -> Result<Self, Error> {
+        if value <= Self::MAX {
+            Ok(Self(value))
+        } else {
+            Err(Error::InvalidKeySetNumber(value))
+        }
+    }
+
+    /// Raw key-set number byte.
+    pub const fn as_byte(self) -> u8 {
+        self.0
+    }
+}
+
+/// Application key-set layout sent with `CreateApplication` on EV2 and later cards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeySetOptions {
+    key_set_count: u8,
+    max_key_size: u8,
+    active_key_set_version: u8,
+    roll_key: KeyNumber,
+}
+
+impl KeySetOptions {
+    /// Creates options for `key_set_count` key sets (2 to 16).
+    ///
+    /// `largest_key_type` sizes the key-set storage: 24 bytes for 3TDEA, otherwise 16.
+    /// The active key set starts at version `0` and key `0` may roll the key sets.
+    pub fn new(key_set_count: u8, largest_key_type: ApplicationKeyType) -> Result<Self, Error> {
+        if !(2..=16).contains(&key_set_count) {
+            return Err(Error::InvalidKeySetNumber(key_set_count));
+        }
+        let max_key_size = match largest_key_type {
+            ApplicationKeyType::TwoKey3Des | ApplicationKeyType::Aes => 0x10,
+            ApplicationKeyType::ThreeKey3Des => 0x18,
+            ApplicationKeyType::Rfu => return Err(Error::UnsupportedAlgorithm),
+        };
+
+        Ok(Self {
+            key_set_count,
+            max_key_size,
+            active_key_set_version: 0,
+            roll_key: KeyNumber(0),
+        })
+    }
+
+    /// Sets the version of the initial active key set.
+    #[must_use]
+    pub const fn with_active_key_set_version(mut self, version: u8) -> Self {
+        self.active_key_set_version = version;
+        self
+    }
+
+    /// Sets the application key that is allowed to run `RollKeySet`.
+    #[must_use]
+    pub const fn with_roll_key(mut self, roll_key: KeyNumber) -> Self {
+        self.roll_key = roll_key;
+        self
+    }
+
+    /// Number of key sets, including the active one.
+    pub const fn key_set_count(self) -> u8 {
+        self.key_set_count
+    }
+
+    /// Largest key size in bytes that the key sets can hold.
+    pub const fn max_key_size(self) -> u8 {
+        self.max_key_size
+    }
+
+    /// Encodes `KeySett3 || AKSVersion || NoKeySets || MaxKeySize || AppKeySetSett`.
+    pub const fn to_bytes(self) -> [u8; 5] {
+        [
+            0x01,
+            self.active_key_set_version,
+            self.key_set_count,
+            self.max_key_size,
+            self.roll_key.0,
+        ]
+    }
+}
+
 /// Key settings for the currently selected `DESFire` application.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct KeySettings {
@@ -154,7 +331,10 @@ pub enum ApplicationKeyType {
 
 #[cfg(test)]
 mod tests {
-    use crate::mifare::desfire::key::{ApplicationKeyType, KeySettings};
+    use crate::mifare::desfire::{
+        error::Error,
+        key::{ApplicationKeyType, KeyNumber, KeySetNumber, KeySetOptions, KeySettings},
+    };
 
     #[test]
     fn parses_picc_key_settings() {
@@ -202,4 +382,37 @@ mod tests {
         assert_eq!(settings.key_count(), 1);
         assert_eq!(settings.key_type(), ApplicationKeyType::Aes);
     }
+
+    #[test]
+    fn encodes_key_set_options() {
+        let options = KeySetOptions::new(3, ApplicationKeyType::ThreeKey3Des)
+            .unwrap()
+            .with_active_key_set_version(0x07)
+            .with_roll_key(KeyNumber::new(2).unwrap());
+
+        assert_eq!(options.to_bytes(), [0x01, 0x07, 0x03, 0x18, 0x02]);
+        assert_eq!(
+            KeySetOptions::new(3, ApplicationKeyType::Aes)
+                .unwrap()
+                .max_key_size(),
+            0x10
+        );
+    }
+
+    #[test]
+    fn rejects_key_set_numbers_and_counts_out_of_range() {
+        assert_eq!(KeySetNumber::new(0x0F).unwrap().as_byte(), 0x0F);
+        assert_eq!(
+            KeySetNumber::new(0x10),
+            Err(Error::InvalidKeySetNumber(0x10))
+        );
+        assert_eq!(
+            KeySetOptions::new(1, ApplicationKeyType::Aes),
+            Err(Error::InvalidKeySetNumber(1))
+        );
+        assert_eq!(
+            KeySetOptions::new(17, ApplicationKeyType::Aes),
+            Err(Error::InvalidKeySetNumber(17))
+        );
+    }
 }
@@ -0,0 +1,611 @@
+//! MIFARE `DESFire` Light client.
+//!
+//! The Light card has one application with a fixed set of files, accepts only
+//! ISO-wrapped commands and cannot create applications. [`DesfireLight`]
+//! drives it through the regular [`Desfire`] client over [`WrappedFraming`],
+//! so EV2 secure messaging and session handling are shared.
+
+use heapless::Vec;
+
+use crate::mifare::desfire::{
+    client::Desfire,
+    command::{Command, CommandCode},
+    error::Error,
+    executor::Executor,
+    file::{
+        AccessRights, CommunicationMode, DataSink, FileId, FileSettings, FileSettingsDetails,
+        Records,
+    },
+    framing::WrappedFraming,
+    iso::{DfName, IsoFileId, IsoSelect},
+    key::KeyNumber,
+    originality::SIGNATURE_LEN,
+    rng::RandomSource,
+    session::{Ev2Session, Session},
+    transport::Transport,
+    types::U24,
+    version::VersionInfo,
+};
+
+/// ISO DF name of the `DESFire` Light application.
+pub const LIGHT_DF_NAME: [u8; 16] = [
+    0xA0, 0x00, 0x00, 0x03, 0x96, 0x56, 0x43, 0x41, 0x03, 0xF0, 0x15, 0x40, 0x00, 0x00, 0x00, 0x0B,
+];
+
+/// `CommitTransaction` option asking for the transaction MAC in the response.
+const COMMIT_RETURN_TRANSACTION_MAC: u8 = 0x01;
+
+/// Native commands the Light card implements.
+const SUPPORTED_COMMANDS: [CommandCode; 23] = [
+    CommandCode::ADDITIONAL_FRAME,
+    CommandCode::AUTHENTICATE_EV2_FIRST,
+    CommandCode::AUTHENTICATE_EV2_NON_FIRST,
+    CommandCode::GET_VERSION,
+    CommandCode::GET_CARD_UID,
+    CommandCode::READ_SIG,
+    CommandCode::SET_CONFIGURATION,
+    CommandCode::GET_KEY_VERSION,
+    CommandCode::CHANGE_KEY,
+    CommandCode::GET_FILE_SETTINGS,
+    CommandCode::CHANGE_FILE_SETTINGS,
+    CommandCode::READ_DATA,
+    CommandCode::WRITE_DATA,
+    CommandCode::GET_VALUE,
+    CommandCode::CREDIT,
+    CommandCode::DEBIT,
+    CommandCode::LIMITED_CREDIT,
+    CommandCode::READ_RECORDS,
+    CommandCode::WRITE_RECORD,
+    CommandCode::CLEAR_RECORD_FILE,
+    CommandCode::COMMIT_TRANSACTION,
+    CommandCode::ABORT_TRANSACTION,
+    // Only for the transaction MAC file, as `DeleteTransactionMACFile`.
+    CommandCode::DELETE_FILE,
+];
+
+/// Whether the Light card implements the native command `code`.
+pub fn supports_command(code: CommandCode) -> bool {
+    SUPPORTED_COMMANDS.contains(&code)
+}
+
+/// One of the fixed files of the Light application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LightFile {
+    /// Standard data file `0x00`.
+    Data00,
+    /// Cyclic record file `0x01`.
+    CyclicRecord,
+    /// Value file `0x03`.
+    Value,
+    /// Standard data file `0x04`.
+    Data04,
+    /// Transaction MAC file `0x0F`.
+    TransactionMac,
+    /// Standard data file `0x1F`.
+    Data1F,
+}
+
+impl LightFile {
+    /// Every file, in file number order.
+    pub const ALL: [Self; 6] = [
+        Self::Data00,
+        Self::CyclicRecord,
+        Self::Value,
+        Self::Data04,
+        Self::TransactionMac,
+        Self::Data1F,
+    ];
+
+    /// Native file number.
+    pub fn file_id(self) -> FileId {
+        FileId::new(self.number()).expect("fixed file numbers are valid")
+    }
+
+    /// ISO file identifier, `0xEF00` plus the file number.
+    pub fn iso_file_id(self) -> IsoFileId {
+        IsoFileId::new(0xEF00 | u16::from(self.number())).expect("fixed ISO file ids are valid")
+    }
+
+    const fn number(self) -> u8 {
+        match self {
+            Self::Data00 => 0x00,
+            Self::CyclicRecord => 0x01,
+            Self::Value => 0x03,
+            Self::Data04 => 0x04,
+            Self::TransactionMac => 0x0F,
+            Self::Data1F => 0x1F,
+        }
+    }
+}
+
+/// Settings of the transaction MAC file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionMacSettings {
+    communication_mode: CommunicationMode,
+    access_rights: AccessRights,
+    key_option: u8,
+    key_version: u8,
+}
+
+impl TransactionMacSettings {
+    const FILE_TYPE: u8 = 0x05;
+
+    /// Parses the `GetFileSettings` response body of a transaction MAC file.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let [file_type, communication_mode, rights_0, rights_1, key_option, key_version] = *data
+        else {
+            return Err(Error::InvalidResponseLength);
+        };
+        if file_type != Self::FILE_TYPE {
+            return Err(Error::InvalidFileType(file_type));
+        }
+        Ok(Self {
+            communication_mode: CommunicationMode::try_from(communication_mode)?,
+            access_rights: AccessRights::from_bytes([rights_0, rights_1]),
+            key_option,
+            key_version,
+        })
+    }
+
+    /// Communication mode for reading the file.
+    pub const fn communication_mode(self) -> CommunicationMode {
+        self.communication_mode
+    }
+
+    /// File access rights.
+    pub const fn access_rights(self) -> AccessRights {
+        self.access_rights
+    }
+
+    /// Key type of the transaction MAC key; `0x02` is AES.
+    pub const fn key_option(self) -> u8 {
+        self.key_option
+    }
+
+    /// Version of the transaction MAC key.
+    pub const fn key_version(self) -> u8 {
+        self.key_version
+    }
+}
+
+/// Settings of one Light file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightFileSettings {
+    /// A data, value or record file.
+    File(FileSettings),
+    /// The transaction MAC file.
+    TransactionMac(TransactionMacSettings),
+}
+
+/// Transaction MAC counter and value of the last committed transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionMac {
+    counter: u32,
+    value: [u8; 8],
+}
+
+impl TransactionMac {
+    /// Parses `TMC || TMV` as stored in the transaction MAC file.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() != 12 {
+            return Err(Error::InvalidResponseLength);
+        }
+        Ok(Self {
+            counter: u32::from_le_bytes(data[..4].try_into().expect("slice length is checked")),
+            value: data[4..].try_into().expect("slice length is checked"),
+        })
+    }
+
+    /// Transaction MAC counter.
+    pub const fn counter(self) -> u32 {
+        self.counter
+    }
+
+    /// Transaction MAC value.
+    pub const fn value(self) -> [u8; 8] {
+        self.value
+    }
+}
+
+/// High-level `DESFire` Light command client.
+pub struct DesfireLight<T> {
+    desfire: Desfire<T, WrappedFraming>,
+}
+
+impl<T> DesfireLight<T>
+where
+    T: Transport,
+{
+    /// Creates a client from a byte transport.
+    pub const fn new(transport: T) -> Self {
+        Self {
+            desfire: Desfire::new(transport, WrappedFraming),
+        }
+    }
+
+    /// Consumes the client and returns the command executor.
+    pub fn into_executor(self) -> Executor<T, WrappedFraming> {
+        self.desfire.into_executor()
+    }
+
+    /// Current authentication session.
+    pub const fn session(&self) -> &Session {
+        self.desfire.session()
+    }
+
+    /// Selects the Light application with `ISOSelectFile` by DF name.
+    pub fn select_application(&mut self) -> Result<(), Error> {
+        let name = DfName::new(&LIGHT_DF_NAME)?;
+        self.desfire.iso_select_file(&IsoSelect::DfName(name))
+    }
+
+    /// Reads the chained `GetVersion` response.
+    pub fn get_version(&mut self) -> Result<VersionInfo, Error> {
+        self.desfire.get_version()
+    }
+
+    /// Reads the real UID; requires authentication.
+    pub fn get_card_uid(&mut self) -> Result<[u8; 7], Error> {
+        self.desfire.get_card_uid()
+    }
+
+    /// Reads the NXP originality signature over the UID.
+    pub fn read_sig(&mut self) -> Result<[u8; SIGNATURE_LEN], Error> {
+        self.desfire.read_sig()
+    }
+
+    /// Starts an EV2 session with an AES application key.
+    pub fn authenticate_ev2_first<R: RandomSource + ?Sized>(
+        &mut self,
+        key_number: KeyNumber,
+        key: &[u8; 16],
+        random: &mut R,
+    ) -> Result<Ev2Session, Error> {
+        self.desfire.authenticate_ev2_first(key_number, key, random)
+    }
+
+    /// Switches keys within the current EV2 session.
+    pub fn authenticate_ev2_non_first<R: RandomSource + ?Sized>(
+        &mut self,
+        key_number: KeyNumber,
+        key: &[u8; 16],
+        random: &mut R,
+    ) -> Result<Ev2Session, Error> {
+        self.desfire
+            .authenticate_ev2_non_first(key_number, key, random)
+    }
+
+    /// Reads the version of one application key.
+    pub fn get_key_version(&mut self, key_number: KeyNumber) -> Result<u8, Error> {
+        self.desfire.get_key_version(key_number)
+    }
+
+    /// Changes one AES application key.
+    ///
+    /// `old_key` is required unless `key_number` is the authenticated key.
+    pub fn change_key(
+        &mut self,
+        key_number: KeyNumber,
+        new_key: [u8; 16],
+        key_version: u8,
+        old_key: Option<[u8; 16]>,
+    ) -> Result<(), Error> {
+        self.desfire
+            .change_key_aes(key_number, new_key, key_version, old_key)
+    }
+
+    /// Reads the settings of one file.
+    pub fn get_file_settings(&mut self, file: LightFile) -> Result<LightFileSettings, Error> {
+        let command = Command::new(CommandCode::GET_FILE_SETTINGS, &[file.file_id().as_byte()])?;
+        let mut data: Vec<u8, 32> = Vec::new();
+        self.desfire.execute_query(&command, &mut data)?;
+
+        match file {
+            LightFile::TransactionMac => {
+                TransactionMacSettings::parse(&data).map(LightFileSettings::TransactionMac)
+            }
+            _ => FileSettings::parse(&data).map(LightFileSettings::File),
+        }
+    }
+
+    /// Changes the communication mode and access rights of one file.
+    pub fn change_file_settings(
+        &mut self,
+        file: LightFile,
+        communication_mode: CommunicationMode,
+        access_rights: AccessRights,
+    ) -> Result<(), Error> {
+        self.desfire
+            .change_file_settings(file.file_id(), communication_mode, access_rights)
+    }
+
+    /// Streams `length` bytes of a data file into `sink`.
+    pub fn read_data<S: DataSink>(
+        &mut self,
+        file: LightFile,
+        communication_mode: CommunicationMode,
+        offset: U24,
+        length: U24,
+        sink: &mut S,
+    ) -> Result<(), Error> {
+        self.desfire
+            .read_data_streamed(file.file_id(), communication_mode, offset, length, sink)
+    }
+
+    /// Writes `data` to a data file.
+    pub fn write_data(
+        &mut self,
+        file: LightFile,
+        communication_mode: CommunicationMode,
+        offset: U24,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.desfire
+            .write_data_streamed(file.file_id(), communication_mode, offset, data)
+    }
+
+    /// Reads the value file.
+    pub fn get_value(&mut self, communication_mode: CommunicationMode) -> Result<i32, Error> {
+        self.desfire
+            .get_value(LightFile::Value.file_id(), communication_mode)
+    }
+
+    /// Increases the value file; permanent after commit.
+    pub fn credit(
+        &mut self,
+        communication_mode: CommunicationMode,
+        amount: i32,
+    ) -> Result<(), Error> {
+        self.desfire
+            .credit(LightFile::Value.file_id(), communication_mode, amount)
+    }
+
+    /// Decreases the value file; permanent after commit.
+    pub fn debit(
+        &mut self,
+        communication_mode: CommunicationMode,
+        amount: i32,
+    ) -> Result<(), Error> {
+        self.desfire
+            .debit(LightFile::Value.file_id(), communication_mode, amount)
+    }
+
+    /// Increases the value file by at most the last committed debit.
+    pub fn limited_credit(
+        &mut self,
+        communication_mode: CommunicationMode,
+        amount: i32,
+    ) -> Result<(), Error> {
+        self.desfire
+            .limited_credit(LightFile::Value.file_id(), communication_mode, amount)
+    }
+
+    /// Reads records from the cyclic record file.
+    ///
+    /// The record size and communication mode come from `settings`, as read by
+    /// [`Self::get_file_settings`]. `offset` counts back from the newest record.
+    pub fn read_records<'a, const N: usize>(
+        &mut self,
+        settings: FileSettings,
+        offset: U24,
+        count: U24,
+        data: &'a mut Vec<u8, N>,
+    ) -> Result<Records<'a>, Error> {
+        let FileSettingsDetails::Record { record_size, .. } = settings.details() else {
+            return Err(Error::InvalidFileType(u8::from(settings.file_type())));
+        };
+        self.desfire.read_records(
+            LightFile::CyclicRecord.file_id(),
+            settings.communication_mode(),
+            record_size,
+            offset,
+            count,
+            data,
+        )
+    }
+
+    /// Appends a record to the cyclic record file; visible after commit.
+    pub fn write_record(
+        &mut self,
+        communication_mode: CommunicationMode,
+        offset: U24,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.desfire.write_record(
+            LightFile::CyclicRecord.file_id(),
+            communication_mode,
+            offset,
+            data,
+        )
+    }
+
+    /// Clears the cyclic record file; emptied after commit.
+    pub fn clear_record_file(&mut self) -> Result<(), Error> {
+        self.desfire
+            .clear_record_file(LightFile::CyclicRecord.file_id())
+    }
+
+    /// Commits all pending value and record changes.
+    pub fn commit_transaction(&mut self) -> Result<(), Error> {
+        self.desfire.commit_transaction()
+    }
+
+    /// Commits all pending changes and returns the new transaction MAC.
+    ///
+    /// Requires the transaction MAC file to be present.
+    pub fn commit_transaction_with_mac(&mut self) -> Result<TransactionMac, Error> {
+        let command = Command::new(
+            CommandCode::COMMIT_TRANSACTION,
+            &[COMMIT_RETURN_TRANSACTION_MAC],
+        )?;
+        let mut data: Vec<u8, 12> = Vec::new();
+        self.desfire.execute_query(&command, &mut data)?;
+        TransactionMac::parse(&data)
+    }
+
+    /// Discards all pending value and record changes.
+    pub fn abort_transaction(&mut self) -> Result<(), Error> {
+        self.desfire.abort_transaction()
+    }
+
+    /// Reads the transaction MAC of the last committed transaction.
+    pub fn read_transaction_mac(
+        &mut self,
+        communication_mode: CommunicationMode,
+    ) -> Result<TransactionMac, Error> {
+        let mut data: Vec<u8, 12> = Vec::new();
+        self.desfire.read_data_streamed(
+            LightFile::TransactionMac.file_id(),
+            communication_mode,
+            U24::new(0).expect("zero is a valid U24"),
+            U24::new(12).expect("twelve is a valid U24"),
+            &mut data,
+        )?;
+        TransactionMac::parse(&data)
+    }
+
+    /// Sends one native command in the current session.
+    ///
+    /// Commands the Light card does not implement are rejected with
+    /// [`Error::UnsupportedCommand`] before anything is sent.
+    pub fn execute<const N: usize>(
+        &mut self,
+        command: &Command,
+        data: &mut Vec<u8, N>,
+    ) -> Result<(), Error> {
+        if !supports_command(command.code()) {
+            return Err(Error::UnsupportedCommand(command.code()));
+        }
+        self.desfire.execute_query(command, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use crate::mifare::desfire::{
+        command::{Command, CommandCode},
+        error::Error,
+        file::{AccessCondition, CommunicationMode, FileType},
+        key::KeyNumber,
+        light::{supports_command, DesfireLight, LightFile, LightFileSettings},
+        transport::{Frame, Transport},
+    };
+
+    struct MockTransport<const N: usize> {
+        exchanges: [(&'static [u8], &'static [u8]); N],
+        index: usize,
+    }
+
+    impl<const N: usize> MockTransport<N> {
+        const fn new(exchanges: [(&'static [u8], &'static [u8]); N]) -> Self {
+            Self {
+                exchanges,
+                index: 0,
+            }
+        }
+    }
+
+    impl<const N: usize> Transport for MockTransport<N> {
+        fn transceive(&mut self, tx: &[u8], rx: &mut Frame) -> Result<(), Error> {
+            let (expected_tx, response) = self.exchanges[self.index];
+            self.index += 1;
+
+            assert_eq!(tx, expected_tx);
+            rx.clear();
+            rx.extend_from_slice(response).map_err(|_| Error::Transport)
+        }
+    }
+
+    #[test]
+    fn selects_application_by_df_name() {
+        const SELECT: [u8; 21] = [
+            0x00, 0xA4, 0x04, 0x0C, 0x10, 0xA0, 0x00, 0x00, 0x03, 0x96, 0x56, 0x43, 0x41, 0x03,
+            0xF0, 0x15, 0x40, 0x00, 0x00, 0x00, 0x0B,
+        ];
+        let mut light = DesfireLight::new(MockTransport::new([(&SELECT[..], &[0x90, 0x00][..])]));
+
+        light.select_application().unwrap();
+
+        assert_eq!(light.into_executor().transport().index, 1);
+    }
+
+    #[test]
+    fn reads_fixed_file_settings() {
+        let mut light = DesfireLight::new(MockTransport::new([
+            (
+                &[0x90, 0xF5, 0x00, 0x00, 0x01, 0x03, 0x00][..],
+                &[
+                    0x02, 0x00, 0x10, 0x32, 0x00, 0x00, 0x00, 0x00, 0xE8, 0x03, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00, 0x00, 0x91, 0x00,
+                ][..],
+            ),
+            (
+                &[0x90, 0xF5, 0x00, 0x00, 0x01, 0x0F, 0x00][..],
+                &[0x05, 0x00, 0xF0, 0x1F, 0x02, 0x01, 0x91, 0x00][..],
+            ),
+        ]));
+
+        let LightFileSettings::File(value) = light.get_file_settings(LightFile::Value).unwrap()
+        else {
+            panic!("value file has regular settings");
+        };
+        assert_eq!(value.file_type(), FileType::Value);
+
+        let LightFileSettings::TransactionMac(settings) =
+            light.get_file_settings(LightFile::TransactionMac).unwrap()
+        else {
+            panic!("transaction MAC file has its own settings");
+        };
+        assert_eq!(settings.communication_mode(), CommunicationMode::Plain);
+        assert_eq!(
+            settings.access_rights().read(),
+            AccessCondition::Key(KeyNumber::new(1).unwrap())
+        );
+        assert_eq!(
+            settings.access_rights().change(),
+            AccessCondition::Key(KeyNumber::new(0).unwrap())
+        );
+        assert_eq!(settings.key_option(), 0x02);
+        assert_eq!(settings.key_version(), 0x01);
+    }
+
+    #[test]
+    fn commits_and_returns_transaction_mac() {
+        let mut light = DesfireLight::new(MockTransport::new([(
+            &[0x90, 0xC7, 0x00, 0x00, 0x01, 0x01, 0x00][..],
+            &[
+                0x05, 0x00, 0x00, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x91, 0x00,
+            ][..],
+        )]));
+
+        let mac = light.commit_transaction_with_mac().unwrap();
+
+        assert_eq!(mac.counter(), 5);
+        assert_eq!(
+            mac.value(),
+            [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]
+        );
+    }
+
+    #[test]
+    fn rejects_native_commands_the_card_lacks() {
+        let mut light = DesfireLight::new(MockTransport::new([]));
+        let mut data: Vec<u8, 8> = Vec::new();
+
+        for code in [
+            CommandCode::CREATE_APPLICATION,
+            CommandCode::FORMAT_PICC,
+            CommandCode::AUTHENTICATE_AES,
+            CommandCode::GET_APPLICATION_IDS,
+        ] {
+            let command = Command::new(code, &[]).unwrap();
+            assert_eq!(
+                light.execute(&command, &mut data),
+                Err(Error::UnsupportedCommand(code))
+            );
+        }
+        assert!(supports_command(CommandCode::GET_FILE_SETTINGS));
+    }
+}
@@ -5,37 +5,91 @@
 //! `DESFire` commands and vendor credential formats should build on these types.
 
 pub mod application;
+#[cfg(feature = "std")]
+pub mod audit;
 pub mod client;
 pub mod command;
+pub mod configuration;
 pub mod crypto;
 pub mod error;
 pub mod executor;
 pub mod file;
 pub mod framing;
+pub mod handle;
+#[cfg(feature = "std")]
+pub mod inventory;
+pub mod iso;
+pub mod kdf;
 pub mod key;
+pub mod light;
+pub mod originality;
+pub mod provider;
+#[cfg(feature = "std")]
+pub mod restore;
+pub mod rng;
+pub mod secret;
 pub mod session;
+#[cfg(any(feature = "sim", all(test, feature = "std")))]
+pub mod sim;
 pub mod status;
+pub mod transaction;
 pub mod transport;
 pub mod types;
 pub mod version;
 
 pub use application::ApplicationId;
-pub use client::Desfire;
+#[cfg(feature = "std")]
+pub use audit::{
+    audit, AuditReport, Finding, FindingKind, KeyDictionary, Location, Severity,
+    AUDIT_FORMAT_VERSION,
+};
+pub use client::{AsyncDesfire, Desfire};
 pub use command::{Command, CommandCode, Response};
+pub use configuration::{Ats, DefaultKey, PiccConfiguration};
 pub use crypto::{
     desfire_crc16, desfire_crc32, AesCmac, AesCmacChaining, AesSessionKey, DesSessionKey,
     DesfireMac, RndA, RndA8, RndB, RndB8, ThreeKey3DesSessionKey, TwoKey3DesSessionKey,
 };
 pub use error::Error;
-pub use executor::{Executor, MAX_ADDITIONAL_FRAMES};
+pub use executor::{AsyncExecutor, Executor, MAX_ADDITIONAL_FRAMES, MAX_COMMAND_FRAME_DATA};
 pub use file::{
-    AccessCondition, AccessRights, CommunicationMode, FileId, FileSettings, FileSettingsDetails,
-    FileType,
+    AccessCondition, AccessRights, CommunicationMode, DataSink, FileAccess, FileId, FileSettings,
+    FileSettingsDetails, FileType, Records,
 };
 pub use framing::{FrameCodec, NativeFraming, WrappedFraming};
-pub use key::{ApplicationKeyType, Key, KeyNumber, KeySettings};
-pub use session::{AuthenticatedSession, Session, SessionKey};
+pub use handle::FileHandle;
+#[cfg(feature = "std")]
+pub use inventory::{
+    ApplicationInventory, CardInventory, FileContents, FileInventory, InventoryKeys, Unavailable,
+};
+pub use iso::{DfName, DfNameEntry, IsoCommand, IsoFileId, IsoSelect};
+pub use kdf::DiversificationInput;
+pub use key::{
+    ApplicationKeyType, Key, KeyAlgorithm, KeyNumber, KeySetNumber, KeySetOptions, KeySettings,
+};
+pub use light::{
+    DesfireLight, LightFile, LightFileSettings, TransactionMac, TransactionMacSettings,
+    LIGHT_DF_NAME,
+};
+pub use originality::{
+    verify_originality, verify_signature, Originality, ProductFamily, PUBLIC_KEY_LEN, SIGNATURE_LEN,
+};
+pub use provider::{
+    ChangeKeyCryptogram, ChangeKeyFormat, ChangeKeyRequest, CryptoProvider, SoftwareCrypto,
+};
+#[cfg(feature = "std")]
+pub use restore::{restore_layout, RestoreError};
+#[cfg(any(test, feature = "sim"))]
+pub use rng::FixedRandom;
+#[cfg(all(feature = "std", unix))]
+pub use rng::OsRandom;
+pub use rng::RandomSource;
+pub use secret::{constant_time_eq, SecretKey};
+pub use session::{AuthenticatedSession, Ev2Session, Session, SessionKey};
+#[cfg(any(feature = "sim", all(test, feature = "std")))]
+pub use sim::{VirtualApplication, VirtualDesfire, VirtualFile};
 pub use status::Status;
-pub use transport::{Frame, Transport, MAX_FRAME_SIZE};
+pub use transaction::{Transaction, MAX_TRANSACTION_FILES};
+pub use transport::{AsyncTransport, Frame, Transport, MAX_FRAME_SIZE};
 pub use types::U24;
 pub use version::{VersionInfo, VersionPart};
@@ -0,0 +1,244 @@
+//! NXP originality signature checks.
+//!
+//! `Read_Sig` returns an ECDSA signature over the card UID, made with an NXP
+//! key per product family. The signature is verified offline against the
+//! public keys NXP publishes, with the family picked from `GetVersion`.
+
+mod p224;
+
+use crate::mifare::desfire::version::VersionInfo;
+
+/// Length of the `r || s` signature returned by `Read_Sig`.
+pub const SIGNATURE_LEN: usize = 2 * p224::SCALAR_LEN;
+
+/// Length of an uncompressed secp224r1 public key.
+pub const PUBLIC_KEY_LEN: usize = 1 + 2 * p224::SCALAR_LEN;
+
+type PublicKey = [u8; PUBLIC_KEY_LEN];
+
+/// NXP vendor id in the `GetVersion` hardware block.
+const NXP_VENDOR_ID: u8 = 0x04;
+
+/// Published key for `DESFire` EV2.
+const DESFIRE_EV2: PublicKey = [
+    0x04, 0xB3, 0x04, 0xDC, 0x4C, 0x61, 0x5F, 0x53, 0x26, 0xFE, 0x93, 0x83, 0xDD, 0xEC, 0x9A, 0xA8,
+    0x92, 0xDF, 0x3A, 0x57, 0xFA, 0x7F, 0xFB, 0x32, 0x76, 0x19, 0x2B, 0xC0, 0xEA, 0xA2, 0x52, 0xED,
+    0x45, 0xA8, 0x65, 0xE3, 0xB0, 0x93, 0xA3, 0xD0, 0xDC, 0xE5, 0xBE, 0x29, 0xE9, 0x2F, 0x13, 0x92,
+    0xCE, 0x7D, 0xE3, 0x21, 0xE3, 0xE5, 0xC5, 0x2B, 0x3A,
+];
+const DESFIRE_EV2_XL: PublicKey = [
+    0x04, 0xCD, 0x5D, 0x45, 0xE5, 0x0B, 0x15, 0x02, 0xF0, 0xBA, 0x46, 0x56, 0xFF, 0x37, 0x66, 0x95,
+    0x97, 0xE7, 0xE1, 0x83, 0x25, 0x11, 0x50, 0xF9, 0x57, 0x4C, 0xC8, 0xDA, 0x56, 0xBF, 0x01, 0xC7,
+    0xAB, 0xE0, 0x19, 0xE2, 0x9F, 0xEA, 0x48, 0xF9, 0xCE, 0x22, 0xC3, 0xEA, 0x40, 0x29, 0xA7, 0x65,
+    0xE1, 0xBC, 0x95, 0xA8, 0x95, 0x43, 0xBA, 0xD1, 0xBC,
+];
+const DESFIRE_EV3: PublicKey = [
+    0x04, 0x1D, 0xB4, 0x6C, 0x14, 0x5D, 0x0A, 0x36, 0x53, 0x9C, 0x65, 0x44, 0xBD, 0x6D, 0x9B, 0x0A,
+    0xA6, 0x2F, 0xF9, 0x1E, 0xC4, 0x8C, 0xBC, 0x6A, 0xBA, 0xE3, 0x6E, 0x00, 0x89, 0xA4, 0x6F, 0x0D,
+    0x08, 0xC8, 0xA7, 0x15, 0xEA, 0x40, 0xA6, 0x33, 0x13, 0xB9, 0x2E, 0x90, 0xDD, 0xC1, 0x73, 0x02,
+    0x30, 0xE0, 0x45, 0x8A, 0x33, 0x27, 0x6F, 0xB7, 0x43,
+];
+const DESFIRE_LIGHT: PublicKey = [
+    0x04, 0x0E, 0x98, 0xE1, 0x17, 0xAA, 0xA3, 0x64, 0x57, 0xF4, 0x31, 0x73, 0xDC, 0x92, 0x0A, 0x87,
+    0x57, 0x26, 0x7F, 0x44, 0xCE, 0x4E, 0xC5, 0xAD, 0xD3, 0xC5, 0x40, 0x75, 0x57, 0x1A, 0xEB, 0xBF,
+    0x7B, 0x94, 0x2A, 0x97, 0x74, 0xA1, 0xD9, 0x4A, 0xD0, 0x25, 0x72, 0x42, 0x7E, 0x5A, 0xE0, 0xA2,
+    0xDD, 0x36, 0x59, 0x1B, 0x1F, 0xB3, 0x4F, 0xCF, 0x3D,
+];
+const NTAG424_DNA: PublicKey = [
+    0x04, 0x8A, 0x9B, 0x38, 0x0A, 0xF2, 0xEE, 0x1B, 0x98, 0xDC, 0x41, 0x7F, 0xEC, 0xC2, 0x63, 0xF8,
+    0x44, 0x9C, 0x76, 0x25, 0xCE, 0xCE, 0x82, 0xD9, 0xB9, 0x16, 0xC9, 0x92, 0xDA, 0x20, 0x9D, 0x68,
+    0x42, 0x2B, 0x81, 0xEC, 0x20, 0xB6, 0x5A, 0x66, 0xB5, 0x10, 0x2A, 0x61, 0x59, 0x6A, 0xF3, 0x37,
+    0x92, 0x00, 0x59, 0x93, 0x16, 0xA0, 0x0A, 0x14, 0x10,
+];
+
+/// NXP product family with its own originality key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProductFamily {
+    DesfireEv2,
+    DesfireEv2Xl,
+    DesfireEv3,
+    DesfireLight,
+    Ntag424Dna,
+}
+
+impl ProductFamily {
+    /// Picks the family from the `GetVersion` hardware block.
+    ///
+    /// Returns `None` for non-NXP hardware and for families without a
+    /// published signature key, such as `DESFire` EV1.
+    pub const fn from_version(version: VersionInfo) -> Option<Self> {
+        let hardware = version.hardware();
+        if hardware.vendor_id() != NXP_VENDOR_ID {
+            return None;
+        }
+        match (hardware.card_type(), hardware.major()) {
+            (0x01, 0x12) => Some(Self::DesfireEv2),
+            (0x01, 0x22) => Some(Self::DesfireEv2Xl),
+            (0x01, 0x33) => Some(Self::DesfireEv3),
+            (0x08, _) => Some(Self::DesfireLight),
+            (0x04, _) => Some(Self::Ntag424Dna),
+            _ => None,
+        }
+    }
+
+    /// NXP originality public key, uncompressed.
+    pub const fn public_key(self) -> &'static [u8; PUBLIC_KEY_LEN] {
+        match self {
+            Self::DesfireEv2 => &DESFIRE_EV2,
+            Self::DesfireEv2Xl => &DESFIRE_EV2_XL,
+            Self::DesfireEv3 => &DESFIRE_EV3,
+            Self::DesfireLight => &DESFIRE_LIGHT,
+            Self::Ntag424Dna => &NTAG424_DNA,
+        }
+    }
+
+    /// Short product name.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::DesfireEv2 => "DESFire EV2",
+            Self::DesfireEv2Xl => "DESFire EV2 XL",
+            Self::DesfireEv3 => "DESFire EV3",
+            Self::DesfireLight => "DESFire Light",
+            Self::Ntag424Dna => "NTAG 424 DNA",
+        }
+    }
+}
+
+/// Outcome of an originality check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Originality {
+    /// The signature matches the family key.
+    Genuine(ProductFamily),
+    /// The signature does not match; the chip is not an original NXP part.
+    Invalid(ProductFamily),
+    /// `GetVersion` names no family with a known key.
+    UnknownFamily,
+}
+
+/// Checks a `Read_Sig` signature against the key of the reported family.
+pub fn verify_originality(version: VersionInfo, signature: &[u8; SIGNATURE_LEN]) -> Originality {
+    let Some(family) = ProductFamily::from_version(version) else {
+        return Originality::UnknownFamily;
+    };
+    if verify_signature(family.public_key(), &version.uid(), signature) {
+        Originality::Genuine(family)
+    } else {
+        Originality::Invalid(family)
+    }
+}
+
+/// Verifies a secp224r1 ECDSA signature over an unhashed message.
+///
+/// NXP signs the UID directly, so `message` is the UID and at most 28 bytes.
+pub fn verify_signature(
+    public_key: &[u8; PUBLIC_KEY_LEN],
+    message: &[u8],
+    signature: &[u8; SIGNATURE_LEN],
+) -> bool {
+    p224::verify(public_key, message, signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::{
+        originality::{
+            p224, verify_originality, verify_signature, Originality, ProductFamily, SIGNATURE_LEN,
+        },
+        version::VersionInfo,
+    };
+
+    const FAMILIES: [ProductFamily; 5] = [
+        ProductFamily::DesfireEv2,
+        ProductFamily::DesfireEv2Xl,
+        ProductFamily::DesfireEv3,
+        ProductFamily::DesfireLight,
+        ProductFamily::Ntag424Dna,
+    ];
+
+    /// `Read_Sig` of an NTAG 424 DNA with UID `04518DFAA96180`, from AN12196.
+    const AN12196_SIGNATURE: [u8; SIGNATURE_LEN] = [
+        0xD1, 0x94, 0x0D, 0x17, 0xCF, 0xED, 0xA4, 0xBF, 0xF8, 0x03, 0x59, 0xAB, 0x97, 0x5F, 0x9F,
+        0x65, 0x14, 0x31, 0x3E, 0x8F, 0x90, 0xC1, 0xD3, 0xCA, 0xAF, 0x59, 0x41, 0xAD, 0x74, 0x4A,
+        0x1C, 0xDF, 0x9A, 0x83, 0xF8, 0x83, 0xCA, 0xFE, 0x0F, 0xE9, 0x5D, 0x19, 0x39, 0xB1, 0xB7,
+        0xE4, 0x71, 0x13, 0x99, 0x33, 0x24, 0x47, 0x3B, 0x78, 0x5D, 0x21,
+    ];
+
+    fn version(vendor_id: u8, card_type: u8, major: u8) -> VersionInfo {
+        let mut data = [0u8; 28];
+        data[0] = vendor_id;
+        data[1] = card_type;
+        data[3] = major;
+        data[14..21].copy_from_slice(&[0x04, 0x51, 0x8D, 0xFA, 0xA9, 0x61, 0x80]);
+        VersionInfo::parse(&data).unwrap()
+    }
+
+    #[test]
+    fn maps_hardware_version_to_family() {
+        let cases = [
+            (version(0x04, 0x01, 0x12), Some(ProductFamily::DesfireEv2)),
+            (version(0x04, 0x01, 0x22), Some(ProductFamily::DesfireEv2Xl)),
+            (version(0x04, 0x01, 0x33), Some(ProductFamily::DesfireEv3)),
+            (version(0x04, 0x08, 0x30), Some(ProductFamily::DesfireLight)),
+            (version(0x04, 0x04, 0x30), Some(ProductFamily::Ntag424Dna)),
+            (version(0x04, 0x01, 0x01), None),
+            (version(0x05, 0x01, 0x12), None),
+        ];
+
+        for (version, family) in cases {
+            assert_eq!(ProductFamily::from_version(version), family);
+        }
+    }
+
+    #[test]
+    fn published_keys_are_curve_points() {
+        for family in FAMILIES {
+            assert!(p224::is_valid_public_key(family.public_key()));
+        }
+    }
+
+    #[test]
+    fn every_family_has_its_own_key() {
+        for (index, family) in FAMILIES.iter().enumerate() {
+            for other in &FAMILIES[index + 1..] {
+                assert_ne!(
+                    family.public_key(),
+                    other.public_key(),
+                    "{family:?} / {other:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn verifies_an12196_ntag424_signature() {
+        assert_eq!(
+            verify_originality(version(0x04, 0x04, 0x30), &AN12196_SIGNATURE),
+            Originality::Genuine(ProductFamily::Ntag424Dna)
+        );
+
+        // The same signature must not pass under any other family's key.
+        for family in FAMILIES {
+            if family != ProductFamily::Ntag424Dna {
+                assert!(!verify_signature(
+                    family.public_key(),
+                    &version(0x04, 0x04, 0x30).uid(),
+                    &AN12196_SIGNATURE
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn reports_invalid_signature_and_unknown_family() {
+        let signature = [0x5A; SIGNATURE_LEN];
+
+        assert_eq!(
+            verify_originality(version(0x04, 0x01, 0x33), &signature),
+            Originality::Invalid(ProductFamily::DesfireEv3)
+        );
+        assert_eq!(
+            verify_originality(version(0x04, 0x01, 0x00), &signature),
+            Originality::UnknownFamily
+        );
+    }
+}
@@ -0,0 +1,432 @@
+//! ECDSA verification over NIST P-224 (secp224r1).
+//!
+//! Only what originality checks need: public values, no secrets, so nothing
+//! here tries to run in constant time. Numbers are four little-endian 64-bit
+//! limbs, kept in Montgomery form with `R = 2^256` for both moduli.
+
+/// Length of one encoded coordinate or scalar.
+pub const SCALAR_LEN: usize = 28;
+
+/// Field prime `p = 2^224 - 2^96 + 1`.
+const P: [u8; SCALAR_LEN] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+];
+/// Curve coefficient `b`; `a` is `-3`.
+const B: [u8; SCALAR_LEN] = [
+    0xB4, 0x05, 0x0A, 0x85, 0x0C, 0x04, 0xB3, 0xAB, 0xF5, 0x41, 0x32, 0x56, 0x50, 0x44, 0xB0, 0xB7,
+    0xD7, 0xBF, 0xD8, 0xBA, 0x27, 0x0B, 0x39, 0x43, 0x23, 0x55, 0xFF, 0xB4,
+];
+/// Group order `n`.
+const N: [u8; SCALAR_LEN] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x16, 0xA2,
+    0xE0, 0xB8, 0xF0, 0x3E, 0x13, 0xDD, 0x29, 0x45, 0x5C, 0x5C, 0x2A, 0x3D,
+];
+const GX: [u8; SCALAR_LEN] = [
+    0xB7, 0x0E, 0x0C, 0xBD, 0x6B, 0xB4, 0xBF, 0x7F, 0x32, 0x13, 0x90, 0xB9, 0x4A, 0x03, 0xC1, 0xD3,
+    0x56, 0xC2, 0x11, 0x22, 0x34, 0x32, 0x80, 0xD6, 0x11, 0x5C, 0x1D, 0x21,
+];
+const GY: [u8; SCALAR_LEN] = [
+    0xBD, 0x37, 0x63, 0x88, 0xB5, 0xF7, 0x23, 0xFB, 0x4C, 0x22, 0xDF, 0xE6, 0xCD, 0x43, 0x75, 0xA0,
+    0x5A, 0x07, 0x47, 0x64, 0x44, 0xD5, 0x81, 0x99, 0x85, 0x00, 0x7E, 0x34,
+];
+
+type Limbs = [u64; 4];
+
+const ZERO: Limbs = [0; 4];
+const ONE: Limbs = [1, 0, 0, 0];
+
+/// Verifies an ECDSA signature `r || s` over the unhashed message `e`.
+///
+/// `public_key` is an uncompressed `04 || X || Y` point. `message` is taken
+/// as a big-endian integer of at most 28 bytes, as NXP signs the raw UID.
+pub fn verify(
+    public_key: &[u8; 1 + 2 * SCALAR_LEN],
+    message: &[u8],
+    signature: &[u8; 2 * SCALAR_LEN],
+) -> bool {
+    let field = Modulus::new(from_be_bytes(&P));
+    let order = Modulus::new(from_be_bytes(&N));
+
+    let Some(public_key) = decode_point(&field, public_key) else {
+        return false;
+    };
+    if message.len() > SCALAR_LEN {
+        return false;
+    }
+    let (r, s) = signature.split_at(SCALAR_LEN);
+    let (r, s) = (from_be_bytes(r), from_be_bytes(s));
+    if r == ZERO || s == ZERO || !less_than(&r, &order.value) || !less_than(&s, &order.value) {
+        return false;
+    }
+
+    // `e < 2^224`, so one subtraction brings it below `n`.
+    let mut e = from_be_bytes(message);
+    if !less_than(&e, &order.value) {
+        e = sub(&e, &order.value).0;
+    }
+    let s_inverse = order.invert(&order.to_montgomery(&s));
+    let u1 = order.to_canonical(&order.mul(&order.to_montgomery(&e), &s_inverse));
+    let u2 = order.to_canonical(&order.mul(&order.to_montgomery(&r), &s_inverse));
+
+    let generator = Point {
+        x: field.to_montgomery(&from_be_bytes(&GX)),
+        y: field.to_montgomery(&from_be_bytes(&GY)),
+        z: field.one(),
+    };
+    let sum = field.add_points(&generator, &public_key);
+    let mut result = Point::INFINITY;
+    for bit in (0..256).rev() {
+        result = field.double(&result);
+        match (bit_at(&u1, bit), bit_at(&u2, bit)) {
+            (false, false) => {}
+            (true, false) => result = field.add_points(&result, &generator),
+            (false, true) => result = field.add_points(&result, &public_key),
+            (true, true) => result = field.add_points(&result, &sum),
+        }
+    }
+    if result.is_infinity() {
+        return false;
+    }
+
+    let z_inverse = field.invert(&result.z);
+    let mut x = field.to_canonical(&field.mul(&result.x, &field.square(&z_inverse)));
+    // `x < p < 2n`, so one subtraction reduces it modulo `n`.
+    if !less_than(&x, &order.value) {
+        x = sub(&x, &order.value).0;
+    }
+    x == r
+}
+
+/// Checks that an uncompressed public key is a valid curve point.
+#[cfg(test)]
+pub fn is_valid_public_key(public_key: &[u8; 1 + 2 * SCALAR_LEN]) -> bool {
+    decode_point(&Modulus::new(from_be_bytes(&P)), public_key).is_some()
+}
+
+/// Decodes an uncompressed point and checks that it lies on the curve.
+fn decode_point(field: &Modulus, encoded: &[u8; 1 + 2 * SCALAR_LEN]) -> Option<Point> {
+    let (&tag, coordinates) = encoded.split_first()?;
+    if tag != 0x04 {
+        return None;
+    }
+    let (x, y) = coordinates.split_at(SCALAR_LEN);
+    let (x, y) = (from_be_bytes(x), from_be_bytes(y));
+    if !less_than(&x, &field.value) || !less_than(&y, &field.value) {
+        return None;
+    }
+
+    let x = field.to_montgomery(&x);
+    let y = field.to_montgomery(&y);
+    let b = field.to_montgomery(&from_be_bytes(&B));
+    let three_x = field.add(&field.add(&x, &x), &x);
+    let right = field.add(&field.sub(&field.mul(&field.square(&x), &x), &three_x), &b);
+    (field.square(&y) == right).then_some(Point {
+        x,
+        y,
+        z: field.one(),
+    })
+}
+
+/// A point in Jacobian coordinates, `Z = 0` for the point at infinity.
+#[derive(Clone, Copy)]
+struct Point {
+    x: Limbs,
+    y: Limbs,
+    z: Limbs,
+}
+
+impl Point {
+    const INFINITY: Self = Self {
+        x: ZERO,
+        y: ZERO,
+        z: ZERO,
+    };
+
+    fn is_infinity(&self) -> bool {
+        self.z == ZERO
+    }
+}
+
+/// An odd modulus below `2^224` with its Montgomery constants.
+struct Modulus {
+    value: Limbs,
+    /// `-value^-1 mod 2^64`.
+    inverse: u64,
+    /// `R^2 mod value`.
+    r_squared: Limbs,
+}
+
+impl Modulus {
+    fn new(value: Limbs) -> Self {
+        // Newton's iteration doubles the correct low bits each step.
+        let mut inverse: u64 = 1;
+        for _ in 0..6 {
+            inverse = inverse.wrapping_mul(2u64.wrapping_sub(value[0].wrapping_mul(inverse)));
+        }
+        let mut modulus = Self {
+            value,
+            inverse: inverse.wrapping_neg(),
+            r_squared: ONE,
+        };
+        let mut r_squared = ONE;
+        for _ in 0..512 {
+            r_squared = modulus.add(&r_squared, &r_squared);
+        }
+        modulus.r_squared = r_squared;
+        modulus
+    }
+
+    fn add(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        // Both operands are below 2^224, so the sum cannot overflow.
+        let (sum, _) = add(a, b);
+        if less_than(&sum, &self.value) {
+            sum
+        } else {
+            sub(&sum, &self.value).0
+        }
+    }
+
+    fn sub(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        let (difference, borrow) = sub(a, b);
+        if borrow {
+            add(&difference, &self.value).0
+        } else {
+            difference
+        }
+    }
+
+    /// Montgomery product `a * b / R mod value`.
+    fn mul(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        let mut t = [0u64; 6];
+        for &b_limb in b {
+            let mut carry = 0;
+            for (t_limb, &a_limb) in t.iter_mut().zip(a) {
+                (*t_limb, carry) = mul_add(a_limb, b_limb, *t_limb, carry);
+            }
+            let (low, high) = split(u128::from(t[4]) + u128::from(carry));
+            t[4] = low;
+            t[5] = high;
+
+            let k = t[0].wrapping_mul(self.inverse);
+            let (_, mut carry) = mul_add(k, self.value[0], t[0], 0);
+            for index in 1..4 {
+                (t[index - 1], carry) = mul_add(k, self.value[index], t[index], carry);
+            }
+            let (low, high) = split(u128::from(t[4]) + u128::from(carry));
+            t[3] = low;
+            t[4] = t[5] + high;
+        }
+
+        let result = [t[0], t[1], t[2], t[3]];
+        if t[4] != 0 || !less_than(&result, &self.value) {
+            sub(&result, &self.value).0
+        } else {
+            result
+        }
+    }
+
+    fn square(&self, a: &Limbs) -> Limbs {
+        self.mul(a, a)
+    }
+
+    fn one(&self) -> Limbs {
+        self.to_montgomery(&ONE)
+    }
+
+    fn to_montgomery(&self, a: &Limbs) -> Limbs {
+        self.mul(a, &self.r_squared)
+    }
+
+    fn to_canonical(&self, a: &Limbs) -> Limbs {
+        self.mul(a, &ONE)
+    }
+
+    /// Inverse by Fermat's little theorem; both moduli are prime.
+    fn invert(&self, a: &Limbs) -> Limbs {
+        let exponent = sub(&self.value, &[2, 0, 0, 0]).0;
+        let mut result = self.one();
+        for bit in (0..256).rev() {
+            result = self.square(&result);
+            if bit_at(&exponent, bit) {
+                result = self.mul(&result, a);
+            }
+        }
+        result
+    }
+
+    /// Doubles a point on a curve with `a = -3` (`dbl-2001-b`).
+    fn double(&self, point: &Point) -> Point {
+        if point.is_infinity() || point.y == ZERO {
+            return Point::INFINITY;
+        }
+        let delta = self.square(&point.z);
+        let gamma = self.square(&point.y);
+        let beta = self.mul(&point.x, &gamma);
+        let product = self.mul(&self.sub(&point.x, &delta), &self.add(&point.x, &delta));
+        let alpha = self.add(&self.add(&product, &product), &product);
+        let four_beta = self.double_value(&self.double_value(&beta));
+        let x = self.sub(&self.square(&alpha), &self.double_value(&four_beta));
+        let z = self.sub(
+            &self.sub(&self.square(&self.add(&point.y, &point.z)), &gamma),
+            &delta,
+        );
+        let eight_gamma_squared =
+            self.double_value(&self.double_value(&self.double_value(&self.square(&gamma))));
+        let y = self.sub(
+            &self.mul(&alpha, &self.sub(&four_beta, &x)),
+            &eight_gamma_squared,
+        );
+        Point { x, y, z }
+    }
+
+    /// Adds two points (`add-2007-bl`).
+    // Variable names follow the published formulas.
+    #[allow(clippy::many_single_char_names)]
+    fn add_points(&self, a: &Point, b: &Point) -> Point {
+        if a.is_infinity() {
+            return *b;
+        }
+        if b.is_infinity() {
+            return *a;
+        }
+        let z1z1 = self.square(&a.z);
+        let z2z2 = self.square(&b.z);
+        let u1 = self.mul(&a.x, &z2z2);
+        let u2 = self.mul(&b.x, &z1z1);
+        let s1 = self.mul(&self.mul(&a.y, &b.z), &z2z2);
+        let s2 = self.mul(&self.mul(&b.y, &a.z), &z1z1);
+        let h = self.sub(&u2, &u1);
+        let r = self.double_value(&self.sub(&s2, &s1));
+        if h == ZERO {
+            return if r == ZERO {
+                self.double(a)
+            } else {
+                Point::INFINITY
+            };
+        }
+
+        let i = self.square(&self.double_value(&h));
+        let j = self.mul(&h, &i);
+        let v = self.mul(&u1, &i);
+        let x = self.sub(&self.sub(&self.square(&r), &j), &self.double_value(&v));
+        let y = self.sub(
+            &self.mul(&r, &self.sub(&v, &x)),
+            &self.double_value(&self.mul(&s1, &j)),
+        );
+        let z = self.mul(
+            &self.sub(&self.sub(&self.square(&self.add(&a.z, &b.z)), &z1z1), &z2z2),
+            &h,
+        );
+        Point { x, y, z }
+    }
+
+    fn double_value(&self, a: &Limbs) -> Limbs {
+        self.add(a, a)
+    }
+}
+
+/// `a * b + c + carry`, split into low and high limbs.
+fn mul_add(a: u64, b: u64, c: u64, carry: u64) -> (u64, u64) {
+    split(u128::from(a) * u128::from(b) + u128::from(c) + u128::from(carry))
+}
+
+fn split(value: u128) -> (u64, u64) {
+    // Deliberately keeps the low 64 bits; the high half is returned separately.
+    #[allow(clippy::cast_possible_truncation)]
+    (value as u64, (value >> 64) as u64)
+}
+
+fn add(a: &Limbs, b: &Limbs) -> (Limbs, bool) {
+    let mut result = ZERO;
+    let mut carry = false;
+    for index in 0..4 {
+        let (sum, overflow_1) = a[index].overflowing_add(b[index]);
+        let (sum, overflow_2) = sum.overflowing_add(u64::from(carry));
+        result[index] = sum;
+        carry = overflow_1 || overflow_2;
+    }
+    (result, carry)
+}
+
+fn sub(a: &Limbs, b: &Limbs) -> (Limbs, bool) {
+    let mut result = ZERO;
+    let mut borrow = false;
+    for index in 0..4 {
+        let (difference, underflow_1) = a[index].overflowing_sub(b[index]);
+        let (difference, underflow_2) = difference.overflowing_sub(u64::from(borrow));
+        result[index] = difference;
+        borrow = underflow_1 || underflow_2;
+    }
+    (result, borrow)
+}
+
+fn less_than(a: &Limbs, b: &Limbs) -> bool {
+    sub(a, b).1
+}
+
+fn bit_at(value: &Limbs, bit: usize) -> bool {
+    value[bit / 64] >> (bit % 64) & 1 == 1
+}
+
+/// Reads a big-endian number of at most 32 bytes.
+fn from_be_bytes(bytes: &[u8]) -> Limbs {
+    let mut limbs = ZERO;
+    for (index, &byte) in bytes.iter().rev().enumerate() {
+        limbs[index / 8] |= u64::from(byte) << (8 * (index % 8));
+    }
+    limbs
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::originality::p224::{from_be_bytes, verify, Modulus, N, P};
+
+    /// Key and signature over a UID generated with an independent P-224 implementation.
+    const PUBLIC_KEY: [u8; 57] = [
+        0x04, 0xFF, 0x74, 0x33, 0x62, 0x4A, 0xD1, 0xBB, 0x26, 0x2E, 0x14, 0x9C, 0x90, 0x08, 0x41,
+        0x44, 0xFA, 0x2F, 0xF8, 0x64, 0x1E, 0x77, 0x57, 0xB8, 0x9D, 0xB1, 0x05, 0xB0, 0x9B, 0x25,
+        0x66, 0x2E, 0xB8, 0x72, 0x58, 0xF8, 0x14, 0x8A, 0x52, 0x36, 0x4D, 0x22, 0x16, 0xBC, 0xD3,
+        0xAE, 0xCC, 0xA3, 0xF1, 0xF9, 0x59, 0x9B, 0x31, 0x7F, 0x67, 0xD0, 0xD6,
+    ];
+    const UID: [u8; 7] = [0x04, 0x51, 0x8D, 0xFA, 0xA9, 0x61, 0x80];
+    const SIGNATURE: [u8; 56] = [
+        0x36, 0x18, 0xAF, 0x9F, 0x50, 0x7C, 0xF2, 0x86, 0x5B, 0xEF, 0x27, 0xE5, 0xD9, 0x57, 0x33,
+        0xC0, 0x11, 0x37, 0x4E, 0x2A, 0xBA, 0x07, 0x46, 0x7C, 0x68, 0x6F, 0x20, 0x79, 0x98, 0x96,
+        0x78, 0xA8, 0xC5, 0x2F, 0xCE, 0xA0, 0xE9, 0xC7, 0xFD, 0x79, 0x55, 0x7A, 0x20, 0xAA, 0xD3,
+        0x41, 0xF9, 0xE7, 0x2F, 0xCE, 0x87, 0x7C, 0x03, 0xAC, 0x4F, 0xE8,
+    ];
+
+    #[test]
+    fn montgomery_inverse_round_trips() {
+        for modulus in [P, N] {
+            let modulus = Modulus::new(from_be_bytes(&modulus));
+            let value = modulus.to_montgomery(&[0x1234_5678_9ABC_DEF0, 42, 7, 0x00FF_FFFF]);
+
+            let product = modulus.mul(&value, &modulus.invert(&value));
+
+            assert_eq!(modulus.to_canonical(&product), [1, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn verifies_signature_over_uid() {
+        assert!(verify(&PUBLIC_KEY, &UID, &SIGNATURE));
+    }
+
+    #[test]
+    fn rejects_tampered_uid_signature_and_key() {
+        let mut uid = UID;
+        uid[6] ^= 0x01;
+        assert!(!verify(&PUBLIC_KEY, &uid, &SIGNATURE));
+
+        let mut signature = SIGNATURE;
+        signature[40] ^= 0x01;
+        assert!(!verify(&PUBLIC_KEY, &UID, &signature));
+
+        let mut key = PUBLIC_KEY;
+        key[56] ^= 0x01;
+        assert!(!verify(&key, &UID, &SIGNATURE));
+        assert!(!verify(&PUBLIC_KEY, &UID, &[0; 56]));
+    }
+}
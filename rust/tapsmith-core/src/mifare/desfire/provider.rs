@@ -0,0 +1,724 @@
+//! Cipher and CMAC operations against keys held by a provider.
+//!
+//! Authentication, diversification and `ChangeKey` only touch long-term keys
+//! through a [`CryptoProvider`], so the keys can stay in a SAM or HSM while
+//! the client runs the protocol. [`SoftwareCrypto`] keeps them in process
+//! memory as [`Key`] values.
+
+use heapless::Vec;
+
+use crate::mifare::desfire::{
+    command::CommandCode,
+    crypto::{
+        aes_cbc_decrypt_in_place, aes_cbc_encrypt_in_place, des_cbc_decrypt_in_place,
+        des_cbc_encrypt_in_place, desfire_crc16, desfire_crc32, tdes2_cbc_decrypt_in_place,
+        tdes2_cbc_encrypt_in_place, tdes3_cbc_decrypt_in_place, tdes3_cbc_encrypt_in_place,
+        AesCmac,
+    },
+    error::Error,
+    kdf::{diversify_2tdea_key, diversify_3tdea_key, diversify_aes128_key, DiversificationInput},
+    key::{Key, KeyAlgorithm},
+    secret::{constant_time_eq, zeroize},
+    session::SessionKey,
+};
+
+/// Longest `DESFire` key, 3TDEA.
+const MAX_KEY_SIZE: usize = 24;
+
+/// Longest `ChangeKey` header: key set and key number.
+const MAX_HEADER_SIZE: usize = 2;
+
+/// Longest `ChangeKey` cryptogram: a 3TDEA or AES key with both CRC32s, padded.
+const MAX_CRYPTOGRAM_SIZE: usize = 32;
+
+/// Performs the long-term key operations of the `DESFire` protocol.
+///
+/// Session keys are derived from the exchanged challenges and held by the
+/// client, which passes one in with each `ChangeKey` request; long-term keys
+/// are only addressed through handles and never need to leave the provider.
+pub trait CryptoProvider {
+    /// Reference to a key held by the provider.
+    type KeyHandle;
+
+    /// Cipher family of `key`.
+    fn algorithm(&self, key: &Self::KeyHandle) -> Result<KeyAlgorithm, Error>;
+
+    /// Whether a 2TDEA key has two identical halves and so behaves as single DES.
+    fn has_identical_halves(&self, key: &Self::KeyHandle) -> Result<bool, Error>;
+
+    /// Encrypts `data` in CBC mode; `iv` and `data` follow the key's block size.
+    fn cbc_encrypt(
+        &mut self,
+        key: &Self::KeyHandle,
+        iv: &[u8],
+        data: &mut [u8],
+    ) -> Result<(), Error>;
+
+    /// Decrypts `data` in CBC mode; `iv` and `data` follow the key's block size.
+    fn cbc_decrypt(
+        &mut self,
+        key: &Self::KeyHandle,
+        iv: &[u8],
+        data: &mut [u8],
+    ) -> Result<(), Error>;
+
+    /// AES-CMAC of `data` under an AES key.
+    fn cmac(&mut self, key: &Self::KeyHandle, data: &[u8]) -> Result<AesCmac, Error>;
+
+    /// Derives an AN10922 key from `master` and returns a handle to it.
+    ///
+    /// The derived key is a provider key like any other: the client only
+    /// holds the handle, so it can be computed and kept inside the provider.
+    fn diversify(
+        &mut self,
+        master: &Self::KeyHandle,
+        input: &DiversificationInput,
+    ) -> Result<Self::KeyHandle, Error>;
+
+    /// Builds the encrypted `ChangeKey` cryptogram that writes `new_key`.
+    ///
+    /// `old_key` is the current value of the slot when it is not the
+    /// authenticated key. Only the ciphertext comes back, so neither key has
+    /// to leave the provider; the layout is described on [`ChangeKeyRequest`].
+    fn change_key_cryptogram(
+        &mut self,
+        request: &ChangeKeyRequest<'_>,
+        new_key: &Self::KeyHandle,
+        old_key: Option<&Self::KeyHandle>,
+    ) -> Result<ChangeKeyCryptogram, Error>;
+
+    /// Discards a key returned by [`Self::diversify`] once it is no longer needed.
+    fn release(&mut self, key: Self::KeyHandle) -> Result<(), Error> {
+        drop(key);
+        Ok(())
+    }
+}
+
+impl<P> CryptoProvider for &mut P
+where
+    P: CryptoProvider + ?Sized,
+{
+    type KeyHandle = P::KeyHandle;
+
+    fn algorithm(&self, key: &Self::KeyHandle) -> Result<KeyAlgorithm, Error> {
+        (**self).algorithm(key)
+    }
+
+    fn has_identical_halves(&self, key: &Self::KeyHandle) -> Result<bool, Error> {
+        (**self).has_identical_halves(key)
+    }
+
+    fn cbc_encrypt(
+        &mut self,
+        key: &Self::KeyHandle,
+        iv: &[u8],
+        data: &mut [u8],
+    ) -> Result<(), Error> {
+        (**self).cbc_encrypt(key, iv, data)
+    }
+
+    fn cbc_decrypt(
+        &mut self,
+        key: &Self::KeyHandle,
+        iv: &[u8],
+        data: &mut [u8],
+    ) -> Result<(), Error> {
+        (**self).cbc_decrypt(key, iv, data)
+    }
+
+    fn cmac(&mut self, key: &Self::KeyHandle, data: &[u8]) -> Result<AesCmac, Error> {
+        (**self).cmac(key, data)
+    }
+
+    fn diversify(
+        &mut self,
+        master: &Self::KeyHandle,
+        input: &DiversificationInput,
+    ) -> Result<Self::KeyHandle, Error> {
+        (**self).diversify(master, input)
+    }
+
+    fn change_key_cryptogram(
+        &mut self,
+        request: &ChangeKeyRequest<'_>,
+        new_key: &Self::KeyHandle,
+        old_key: Option<&Self::KeyHandle>,
+    ) -> Result<ChangeKeyCryptogram, Error> {
+        (**self).change_key_cryptogram(request, new_key, old_key)
+    }
+
+    fn release(&mut self, key: Self::KeyHandle) -> Result<(), Error> {
+        (**self).release(key)
+    }
+}
+
+/// How a `ChangeKey` plaintext is checksummed and padded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKeyFormat {
+    /// EV1 session authenticated with single DES: CRC16 checksums, zero padding.
+    Crc16,
+    /// Other EV1 sessions, and any EV1 session writing an AES key: CRC32
+    /// checksums, zero padding.
+    Crc32,
+    /// EV2 secure messaging: no command checksum, ISO/IEC 9797-1 method 2 padding.
+    Ev2,
+}
+
+/// Everything besides the keys that a `ChangeKey` cryptogram depends on.
+///
+/// The plaintext is `key_field || [key_version] || [CRC(cmd || header ||
+/// key_field || [key_version])] || [CRC(new_key)]`, padded to the block size
+/// and encrypted in CBC mode under [`Self::session_key`] with [`Self::iv`].
+/// `key_field` is the new key, or `new_key XOR old_key` when the slot is not
+/// the authenticated key; only then is the second CRC present. The
+/// [`ChangeKeyFormat::Ev2`] format has no command CRC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeKeyRequest<'a> {
+    command: CommandCode,
+    header: &'a [u8],
+    format: ChangeKeyFormat,
+    key_version: Option<u8>,
+    session_key: SessionKey,
+    iv: Vec<u8, 16>,
+}
+
+impl<'a> ChangeKeyRequest<'a> {
+    /// Describes a `ChangeKey` or `ChangeKeyEV2` command.
+    ///
+    /// `iv` must match the block size of `session_key`; the EV2 format takes an
+    /// AES session key.
+    pub fn new(
+        command: CommandCode,
+        header: &'a [u8],
+        format: ChangeKeyFormat,
+        key_version: Option<u8>,
+        session_key: SessionKey,
+        iv: &[u8],
+    ) -> Result<Self, Error> {
+        let ev2_key = matches!(session_key, SessionKey::Aes(_));
+        if header.len() > MAX_HEADER_SIZE
+            || iv.len() != session_key.block_size()
+            || (format == ChangeKeyFormat::Ev2 && !ev2_key)
+        {
+            return Err(Error::CryptoProvider);
+        }
+        Ok(Self {
+            command,
+            header,
+            format,
+            key_version,
+            session_key,
+            iv: Vec::from_slice(iv).map_err(|_| Error::CryptoProvider)?,
+        })
+    }
+
+    /// `ChangeKey` or `ChangeKeyEV2`.
+    pub const fn command(&self) -> CommandCode {
+        self.command
+    }
+
+    /// Key number, preceded by the key set number for `ChangeKeyEV2`.
+    pub const fn header(&self) -> &[u8] {
+        self.header
+    }
+
+    /// Checksum and padding rules.
+    pub const fn format(&self) -> ChangeKeyFormat {
+        self.format
+    }
+
+    /// Version byte sent after an AES key; DES-family versions live in the parity bits.
+    pub const fn key_version(&self) -> Option<u8> {
+        self.key_version
+    }
+
+    /// Session key that encrypts the cryptogram.
+    pub const fn session_key(&self) -> &SessionKey {
+        &self.session_key
+    }
+
+    /// CBC initialization vector for the cryptogram.
+    pub fn iv(&self) -> &[u8] {
+        &self.iv
+    }
+
+    /// Builds and encrypts the cryptogram from raw key bytes.
+    ///
+    /// For providers that hold key values in memory; `old_key` must have the
+    /// same length as `new_key`. The plaintext is wiped before returning.
+    pub fn encipher(
+        &self,
+        new_key: &[u8],
+        old_key: Option<&[u8]>,
+    ) -> Result<ChangeKeyCryptogram, Error> {
+        self.encipher_with(new_key, old_key, |data| {
+            let iv = &self.iv;
+            match &self.session_key {
+                SessionKey::Des(key) => des_cbc_encrypt_in_place(key.as_bytes(), &block(iv)?, data),
+                SessionKey::TwoKey3Des(key) => {
+                    tdes2_cbc_encrypt_in_place(key.as_bytes(), &block(iv)?, data);
+                }
+                SessionKey::ThreeKey3Des(key) => {
+                    tdes3_cbc_encrypt_in_place(key.as_bytes(), &block(iv)?, data);
+                }
+                SessionKey::Aes(key) => aes_cbc_encrypt_in_place(key.as_bytes(), &block(iv)?, data),
+            }
+            Ok(())
+        })
+    }
+
+    /// Builds the cryptogram from raw key bytes and has `encrypt` encipher it.
+    ///
+    /// `encrypt` gets the padded plaintext and must CBC-encrypt it in place
+    /// under [`Self::session_key`] with [`Self::iv`], for providers that run
+    /// the cipher elsewhere. The plaintext is wiped before returning.
+    pub fn encipher_with(
+        &self,
+        new_key: &[u8],
+        old_key: Option<&[u8]>,
+        encrypt: impl FnOnce(&mut [u8]) -> Result<(), Error>,
+    ) -> Result<ChangeKeyCryptogram, Error> {
+        if new_key.len() > MAX_KEY_SIZE
+            || old_key.is_some_and(|old| old.len() != new_key.len())
+            || self.plaintext_len(new_key.len(), old_key.is_some()) > MAX_CRYPTOGRAM_SIZE
+        {
+            return Err(Error::CryptoProvider);
+        }
+
+        let mut plaintext = [0u8; MAX_CRYPTOGRAM_SIZE];
+        let mut checksummed = [0u8; 1 + MAX_HEADER_SIZE + MAX_KEY_SIZE + 1];
+        let len = self.build(new_key, old_key, &mut plaintext, &mut checksummed);
+        zeroize(&mut checksummed);
+        let result = encrypt(&mut plaintext[..len])
+            .and_then(|()| ChangeKeyCryptogram::new(&plaintext[..len]));
+        zeroize(&mut plaintext);
+        result
+    }
+
+    /// Padded plaintext length for a key of `key_len` bytes.
+    fn plaintext_len(&self, key_len: usize, with_old_key: bool) -> usize {
+        let (checksum, key_checksum, padding) = match self.format {
+            ChangeKeyFormat::Crc16 => (2, 2, 0),
+            ChangeKeyFormat::Crc32 => (4, 4, 0),
+            ChangeKeyFormat::Ev2 => (0, 4, 1),
+        };
+        let len = key_len
+            + usize::from(self.key_version.is_some())
+            + checksum
+            + if with_old_key { key_checksum } else { 0 }
+            + padding;
+        len.next_multiple_of(self.session_key.block_size())
+    }
+
+    /// Writes the padded plaintext and returns its length.
+    fn build(
+        &self,
+        new_key: &[u8],
+        old_key: Option<&[u8]>,
+        plaintext: &mut [u8; MAX_CRYPTOGRAM_SIZE],
+        checksummed: &mut [u8],
+    ) -> usize {
+        let mut len = new_key.len();
+        for (index, byte) in new_key.iter().enumerate() {
+            plaintext[index] = byte ^ old_key.map_or(0, |old| old[index]);
+        }
+        if let Some(version) = self.key_version {
+            plaintext[len] = version;
+            len += 1;
+        }
+
+        let checksummed_len = 1 + self.header.len() + len;
+        checksummed[0] = self.command.as_byte();
+        checksummed[1..=self.header.len()].copy_from_slice(self.header);
+        checksummed[1 + self.header.len()..checksummed_len].copy_from_slice(&plaintext[..len]);
+        let checksummed = &checksummed[..checksummed_len];
+
+        let mut append = |bytes: &[u8]| {
+            plaintext[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        };
+        match self.format {
+            ChangeKeyFormat::Crc16 => {
+                append(&desfire_crc16(checksummed));
+                if old_key.is_some() {
+                    append(&desfire_crc16(new_key));
+                }
+            }
+            ChangeKeyFormat::Crc32 => {
+                append(&desfire_crc32(checksummed));
+                if old_key.is_some() {
+                    append(&desfire_crc32(new_key));
+                }
+            }
+            ChangeKeyFormat::Ev2 => {
+                if old_key.is_some() {
+                    append(&desfire_crc32(new_key));
+                }
+                append(&[0x80]);
+            }
+        }
+        let len = len.next_multiple_of(self.session_key.block_size());
+        debug_assert_eq!(len, self.plaintext_len(new_key.len(), old_key.is_some()));
+        len
+    }
+}
+
+/// Encrypted key data of a `ChangeKey` command, as sent after the header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeKeyCryptogram {
+    bytes: Vec<u8, MAX_CRYPTOGRAM_SIZE>,
+}
+
+impl ChangeKeyCryptogram {
+    /// Wraps ciphertext produced by a provider.
+    pub fn new(ciphertext: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            bytes: Vec::from_slice(ciphertext).map_err(|_| Error::CryptoProvider)?,
+        })
+    }
+
+    /// Ciphertext bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Software provider whose key handles are the [`Key`] values themselves.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SoftwareCrypto;
+
+impl CryptoProvider for SoftwareCrypto {
+    type KeyHandle = Key;
+
+    fn algorithm(&self, key: &Key) -> Result<KeyAlgorithm, Error> {
+        Ok(key.algorithm())
+    }
+
+    fn has_identical_halves(&self, key: &Key) -> Result<bool, Error> {
+        match key {
+            Key::TwoKey3Des(key) => Ok(constant_time_eq(&key[..8], &key[8..])),
+            _ => Ok(false),
+        }
+    }
+
+    fn cbc_encrypt(&mut self, key: &Key, iv: &[u8], data: &mut [u8]) -> Result<(), Error> {
+        check_block_sizes(key.algorithm(), iv, data)?;
+        match key {
+            Key::Des(key) => des_cbc_encrypt_in_place(key, &block(iv)?, data),
+            Key::TwoKey3Des(key) => tdes2_cbc_encrypt_in_place(key, &block(iv)?, data),
+            Key::ThreeKey3Des(key) => tdes3_cbc_encrypt_in_place(key, &block(iv)?, data),
+            Key::Aes128(key) => aes_cbc_encrypt_in_place(key, &block(iv)?, data),
+        }
+        Ok(())
+    }
+
+    fn cbc_decrypt(&mut self, key: &Key, iv: &[u8], data: &mut [u8]) -> Result<(), Error> {
+        check_block_sizes(key.algorithm(), iv, data)?;
+        match key {
+            Key::Des(key) => des_cbc_decrypt_in_place(key, &block(iv)?, data),
+            Key::TwoKey3Des(key) => tdes2_cbc_decrypt_in_place(key, &block(iv)?, data),
+            Key::ThreeKey3Des(key) => tdes3_cbc_decrypt_in_place(key, &block(iv)?, data),
+            Key::Aes128(key) => aes_cbc_decrypt_in_place(key, &block(iv)?, data),
+        }
+        Ok(())
+    }
+
+    fn cmac(&mut self, key: &Key, data: &[u8]) -> Result<AesCmac, Error> {
+        match key {
+            Key::Aes128(key) => Ok(AesCmac::calculate(key, data)),
+            _ => Err(Error::UnsupportedAlgorithm),
+        }
+    }
+
+    fn diversify(&mut self, master: &Key, input: &DiversificationInput) -> Result<Key, Error> {
+        match master {
+            Key::Aes128(key) => diversify_aes128_key(key, input).map(Key::Aes128),
+            Key::TwoKey3Des(key) => diversify_2tdea_key(key, input).map(Key::TwoKey3Des),
+            Key::ThreeKey3Des(key) => diversify_3tdea_key(key, input).map(Key::ThreeKey3Des),
+            Key::Des(_) => Err(Error::UnsupportedAlgorithm),
+        }
+    }
+
+    fn change_key_cryptogram(
+        &mut self,
+        request: &ChangeKeyRequest<'_>,
+        new_key: &Key,
+        old_key: Option<&Key>,
+    ) -> Result<ChangeKeyCryptogram, Error> {
+        if old_key.is_some_and(|old| old.algorithm() != new_key.algorithm()) {
+            return Err(Error::CryptoProvider);
+        }
+        request.encipher(new_key.as_bytes(), old_key.map(Key::as_bytes))
+    }
+}
+
+fn check_block_sizes(algorithm: KeyAlgorithm, iv: &[u8], data: &[u8]) -> Result<(), Error> {
+    let block_size = algorithm.block_size();
+    if iv.len() == block_size && data.len().is_multiple_of(block_size) {
+        Ok(())
+    } else {
+        Err(Error::CryptoProvider)
+    }
+}
+
+fn block<const N: usize>(iv: &[u8]) -> Result<[u8; N], Error> {
+    iv.try_into().map_err(|_| Error::CryptoProvider)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mifare::desfire::crypto::{AesSessionKey, DesSessionKey};
+
+    const AES_KEY: [u8; 16] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE,
+        0xFF,
+    ];
+
+    #[test]
+    fn software_crypto_matches_raw_ciphers() {
+        let mut provider = SoftwareCrypto;
+        let key = Key::Aes128(AES_KEY);
+        let iv = [0x5A; 16];
+        let mut data = [0x42u8; 32];
+        let mut expected = data;
+        aes_cbc_encrypt_in_place(&AES_KEY, &iv, &mut expected);
+
+        provider.cbc_encrypt(&key, &iv, &mut data).unwrap();
+        assert_eq!(data, expected);
+        provider.cbc_decrypt(&key, &iv, &mut data).unwrap();
+        assert_eq!(data, [0x42; 32]);
+        assert_eq!(
+            provider.cmac(&key, b"tapsmith").unwrap(),
+            AesCmac::calculate(&AES_KEY, b"tapsmith")
+        );
+    }
+
+    #[test]
+    fn software_crypto_rejects_mismatched_blocks_and_keys() {
+        let mut provider = SoftwareCrypto;
+        let des = Key::Des([0x01; 8]);
+        let mut data = [0u8; 12];
+
+        assert_eq!(
+            provider.cbc_encrypt(&des, &[0u8; 8], &mut data),
+            Err(Error::CryptoProvider)
+        );
+        assert_eq!(
+            provider.cbc_decrypt(&des, &[0u8; 16], &mut data[..8]),
+            Err(Error::CryptoProvider)
+        );
+        assert_eq!(provider.cmac(&des, &[]), Err(Error::UnsupportedAlgorithm));
+        let request = ChangeKeyRequest::new(
+            CommandCode::CHANGE_KEY,
+            &[0x01],
+            ChangeKeyFormat::Crc32,
+            Some(0x10),
+            SessionKey::Aes(AesSessionKey::new(AES_KEY)),
+            &[0u8; 16],
+        )
+        .unwrap();
+        assert_eq!(
+            provider.change_key_cryptogram(
+                &request,
+                &Key::Aes128(AES_KEY),
+                Some(&Key::TwoKey3Des(AES_KEY))
+            ),
+            Err(Error::CryptoProvider)
+        );
+    }
+
+    #[test]
+    fn software_crypto_diversifies_into_a_new_handle() {
+        let mut provider = SoftwareCrypto;
+        let input = DiversificationInput::from_bytes(&[0x04, 0x78, 0x2E]).unwrap();
+
+        let derived = provider.diversify(&Key::Aes128(AES_KEY), &input).unwrap();
+
+        assert_eq!(
+            derived,
+            Key::Aes128(diversify_aes128_key(&AES_KEY, &input).unwrap())
+        );
+        assert_eq!(
+            provider.diversify(&Key::Des([0; 8]), &input),
+            Err(Error::UnsupportedAlgorithm)
+        );
+    }
+
+    #[test]
+    fn change_key_request_encrypts_the_crc32_layout() {
+        let new_key = [0xA5u8; 16];
+        let old_key = [0x0Fu8; 16];
+        let iv = [0x3Cu8; 16];
+        let request = ChangeKeyRequest::new(
+            CommandCode::CHANGE_KEY,
+            &[0x01],
+            ChangeKeyFormat::Crc32,
+            Some(0x10),
+            SessionKey::Aes(AesSessionKey::new(AES_KEY)),
+            &iv,
+        )
+        .unwrap();
+
+        let cryptogram = request.encipher(&new_key, Some(&old_key)).unwrap();
+
+        let mut plaintext = [0u8; 32];
+        plaintext.copy_from_slice(cryptogram.as_bytes());
+        aes_cbc_decrypt_in_place(&AES_KEY, &iv, &mut plaintext);
+        let mut checksummed = [0u8; 19];
+        checksummed[..2].copy_from_slice(&[0xC4, 0x01]);
+        checksummed[2..18].fill(0xAA);
+        checksummed[18] = 0x10;
+        assert_eq!(plaintext[..16], [0xAA; 16]);
+        assert_eq!(plaintext[16], 0x10);
+        assert_eq!(plaintext[17..21], desfire_crc32(&checksummed));
+        assert_eq!(plaintext[21..25], desfire_crc32(&new_key));
+        assert_eq!(plaintext[25..], [0; 7]);
+    }
+
+    #[test]
+    fn change_key_request_encrypts_the_crc16_layout() {
+        let new_key = [0x22u8; 8];
+        let request = ChangeKeyRequest::new(
+            CommandCode::CHANGE_KEY,
+            &[0x00],
+            ChangeKeyFormat::Crc16,
+            None,
+            SessionKey::Des(DesSessionKey::new([0x01; 8])),
+            &[0u8; 8],
+        )
+        .unwrap();
+
+        let cryptogram = request.encipher(&new_key, None).unwrap();
+
+        let mut plaintext = [0u8; 16];
+        plaintext.copy_from_slice(cryptogram.as_bytes());
+        des_cbc_decrypt_in_place(&[0x01; 8], &[0u8; 8], &mut plaintext);
+        let mut checksummed = [0x22u8; 10];
+        checksummed[..2].copy_from_slice(&[0xC4, 0x00]);
+        assert_eq!(plaintext[..8], new_key);
+        assert_eq!(plaintext[8..10], desfire_crc16(&checksummed));
+        assert_eq!(plaintext[10..], [0; 6]);
+    }
+
+    #[test]
+    fn change_key_request_encrypts_the_ev2_layout() {
+        let new_key = [0x5Au8; 24];
+        let old_key = [0x00u8; 24];
+        let iv = [0x77u8; 16];
+        let request = ChangeKeyRequest::new(
+            CommandCode::CHANGE_KEY_EV2,
+            &[0x01, 0x02],
+            ChangeKeyFormat::Ev2,
+            None,
+            SessionKey::Aes(AesSessionKey::new(AES_KEY)),
+            &iv,
+        )
+        .unwrap();
+
+        let cryptogram = request.encipher(&new_key, Some(&old_key)).unwrap();
+
+        let mut plaintext = [0u8; 32];
+        plaintext.copy_from_slice(cryptogram.as_bytes());
+        aes_cbc_decrypt_in_place(&AES_KEY, &iv, &mut plaintext);
+        assert_eq!(plaintext[..24], new_key);
+        assert_eq!(plaintext[24..28], desfire_crc32(&new_key));
+        assert_eq!(plaintext[28..], [0x80, 0, 0, 0]);
+    }
+
+    #[test]
+    fn change_key_request_hands_the_plaintext_to_an_outside_cipher() {
+        let request = ChangeKeyRequest::new(
+            CommandCode::CHANGE_KEY,
+            &[0x01],
+            ChangeKeyFormat::Crc32,
+            Some(0x10),
+            SessionKey::Aes(AesSessionKey::new(AES_KEY)),
+            &[0x3C; 16],
+        )
+        .unwrap();
+        let new_key = [0xA5u8; 16];
+
+        let cryptogram = request
+            .encipher_with(&new_key, None, |data| {
+                assert_eq!(data.len(), 32);
+                aes_cbc_encrypt_in_place(&AES_KEY, &[0x3C; 16], data);
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(cryptogram, request.encipher(&new_key, None).unwrap());
+        assert_eq!(
+            request.encipher_with(&new_key, None, |_| Err(Error::CryptoProvider)),
+            Err(Error::CryptoProvider)
+        );
+    }
+
+    #[test]
+    fn change_key_request_rejects_inconsistent_inputs() {
+        let aes = SessionKey::Aes(AesSessionKey::new(AES_KEY));
+        let des = SessionKey::Des(DesSessionKey::new([0x01; 8]));
+        let code = CommandCode::CHANGE_KEY;
+
+        assert_eq!(
+            ChangeKeyRequest::new(
+                code,
+                &[0x01],
+                ChangeKeyFormat::Crc32,
+                None,
+                aes.clone(),
+                &[0; 8]
+            ),
+            Err(Error::CryptoProvider)
+        );
+        assert_eq!(
+            ChangeKeyRequest::new(code, &[0x01], ChangeKeyFormat::Ev2, None, des, &[0; 8]),
+            Err(Error::CryptoProvider)
+        );
+        assert_eq!(
+            ChangeKeyRequest::new(
+                code,
+                &[0; 3],
+                ChangeKeyFormat::Crc32,
+                None,
+                aes.clone(),
+                &[0; 16]
+            ),
+            Err(Error::CryptoProvider)
+        );
+
+        let request = ChangeKeyRequest::new(
+            code,
+            &[0x01],
+            ChangeKeyFormat::Crc32,
+            Some(0),
+            aes,
+            &[0; 16],
+        )
+        .unwrap();
+        assert_eq!(
+            request.encipher(&[0u8; 16], Some(&[0u8; 8])),
+            Err(Error::CryptoProvider)
+        );
+        // A version byte after a 3TDEA key with both CRCs overflows the cryptogram.
+        assert_eq!(
+            request.encipher(&[0u8; 24], Some(&[0u8; 24])),
+            Err(Error::CryptoProvider)
+        );
+    }
+
+    #[test]
+    fn detects_2tdea_keys_with_identical_halves() {
+        let provider = SoftwareCrypto;
+
+        assert!(provider
+            .has_identical_halves(&Key::TwoKey3Des([0x11; 16]))
+            .unwrap());
+        assert!(!provider
+            .has_identical_halves(&Key::TwoKey3Des(AES_KEY))
+            .unwrap());
+        assert!(!provider
+            .has_identical_halves(&Key::Aes128([0x11; 16]))
+            .unwrap());
+    }
+}
@@ -0,0 +1,679 @@
+//! Recreates a card layout captured by [`CardInventory`] on a blank card.
+//!
+//! Applications are created with their original key settings, files are
+//! created and filled under staging access rights and then switched to their
+//! original settings, and the supplied application keys are installed last.
+//! The result is read back and compared with the snapshot.
+
+use std::vec::Vec;
+
+use heapless::Vec as HeaplessVec;
+
+use crate::mifare::desfire::{
+    application::ApplicationId,
+    client::Desfire,
+    error::Error,
+    file::{AccessCondition, AccessRights, FileId, FileSettings, FileSettingsDetails, FileType},
+    framing::FrameCodec,
+    inventory::{
+        authenticate_key, ApplicationInventory, CardInventory, FileContents, InventoryKeys,
+        Unavailable, MAX_INVENTORY_APPLICATIONS,
+    },
+    key::{ApplicationKeyType, Key, KeyNumber, KeySettings},
+    rng::RandomSource,
+    transport::Transport,
+    types::U24,
+};
+
+/// Card memory is allocated to files in blocks of this many bytes.
+const MEMORY_BLOCK_SIZE: u32 = 32;
+
+/// Errors raised while restoring a layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreError {
+    Desfire(Error),
+    /// The snapshot lacks the settings needed to recreate this application;
+    /// [`ApplicationId::PICC`] when the application list itself is missing.
+    Incomplete(ApplicationId),
+    /// The target card already holds this application.
+    ApplicationExists(ApplicationId),
+    /// The files need more memory than the card has free.
+    InsufficientMemory {
+        required: u32,
+        available: u32,
+    },
+    /// A supplied key does not match the application's key type.
+    KeyTypeMismatch(ApplicationId, KeyNumber),
+    /// The application's key settings do not allow installing this key.
+    KeyFrozen(ApplicationId, KeyNumber),
+    /// The card read back after restoring differs from the snapshot.
+    VerificationFailed(ApplicationId),
+}
+
+impl From<Error> for RestoreError {
+    fn from(error: Error) -> Self {
+        Self::Desfire(error)
+    }
+}
+
+/// Recreates every application and file of `snapshot` on the card.
+///
+/// `keys` holds the target PICC master key, used to create applications, and
+/// the application keys to install; keys left out keep their factory value.
+/// Contents the snapshot could not read leave the file empty and are skipped
+/// during verification. Limited-credit state and ISO identifiers are not
+/// restored.
+pub fn restore_layout<T, C, R>(
+    desfire: &mut Desfire<T, C>,
+    snapshot: &CardInventory,
+    keys: &InventoryKeys,
+    random: &mut R,
+) -> Result<(), RestoreError>
+where
+    T: Transport,
+    C: FrameCodec,
+    R: RandomSource + ?Sized,
+{
+    let applications = snapshot
+        .applications
+        .as_ref()
+        .map_err(|_| RestoreError::Incomplete(ApplicationId::PICC))?;
+    let mut required = 0;
+    for application in applications {
+        check_application(application, keys)?;
+        required += required_memory(application);
+    }
+
+    desfire.select_application(ApplicationId::PICC)?;
+    if let Some(key) = keys.picc_key() {
+        authenticate_key(desfire, master_key_number(), key, random)?;
+    }
+    let mut existing: HeaplessVec<ApplicationId, MAX_INVENTORY_APPLICATIONS> = HeaplessVec::new();
+    desfire.get_application_ids(&mut existing)?;
+    if let Some(application) = applications
+        .iter()
+        .find(|application| existing.contains(&application.application_id))
+    {
+        return Err(RestoreError::ApplicationExists(application.application_id));
+    }
+    let available = desfire.free_memory()?.as_u32();
+    if required > available {
+        return Err(RestoreError::InsufficientMemory {
+            required,
+            available,
+        });
+    }
+
+    for application in applications {
+        desfire.select_application(ApplicationId::PICC)?;
+        if let Some(key) = keys.picc_key() {
+            authenticate_key(desfire, master_key_number(), key, random)?;
+        }
+        restore_application(desfire, application, keys, random)?;
+    }
+
+    verify(
+        desfire,
+        snapshot,
+        &installed_keys(applications, keys),
+        random,
+    )
+}
+
+/// Rejects applications that cannot be rebuilt before the card is touched.
+fn check_application(
+    application: &ApplicationInventory,
+    keys: &InventoryKeys,
+) -> Result<(), RestoreError> {
+    let incomplete = RestoreError::Incomplete(application.application_id);
+    let key_settings = application.key_settings.map_err(|_| incomplete)?;
+    let files = application.files.as_ref().map_err(|_| incomplete)?;
+    if files.iter().any(|file| file.settings.is_err()) {
+        return Err(incomplete);
+    }
+    let default_key = default_key(key_settings.key_type()).ok_or(incomplete)?;
+
+    for key_number in 0..key_settings.key_count() {
+        let key_number = KeyNumber::new(key_number)?;
+        let Some(key) = keys.application_key(application.application_id, key_number) else {
+            continue;
+        };
+        let compatible = matches!(
+            (&default_key, key),
+            (Key::Aes128(_), Key::Aes128(_))
+                | (Key::TwoKey3Des(_), Key::Des(_) | Key::TwoKey3Des(_))
+                | (Key::ThreeKey3Des(_), Key::ThreeKey3Des(_))
+        );
+        if !compatible {
+            return Err(RestoreError::KeyTypeMismatch(
+                application.application_id,
+                key_number,
+            ));
+        }
+        if change_key_authority(key_settings, key_number).is_none() {
+            return Err(RestoreError::KeyFrozen(
+                application.application_id,
+                key_number,
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn restore_application<T, C, R>(
+    desfire: &mut Desfire<T, C>,
+    application: &ApplicationInventory,
+    keys: &InventoryKeys,
+    random: &mut R,
+) -> Result<(), RestoreError>
+where
+    T: Transport,
+    C: FrameCodec,
+    R: RandomSource + ?Sized,
+{
+    let application_id = application.application_id;
+    let incomplete = RestoreError::Incomplete(application_id);
+    let key_settings = application.key_settings.map_err(|_| incomplete)?;
+    let default_key = default_key(key_settings.key_type()).ok_or(incomplete)?;
+
+    desfire.create_application(application_id, key_settings)?;
+    desfire.select_application(application_id)?;
+    authenticate_key(desfire, master_key_number(), &default_key, random)?;
+    for file in application.files.as_ref().map_err(|_| incomplete)? {
+        let settings = file.settings.map_err(|_| incomplete)?;
+        restore_file(desfire, file.file_id, settings, &file.contents)?;
+    }
+
+    // Keys start at their factory value; track each one as it is replaced.
+    let mut current: Vec<(KeyNumber, Key)> = Vec::new();
+    let current_key = |current: &[(KeyNumber, Key)], key_number| {
+        current
+            .iter()
+            .find(|(number, _)| *number == key_number)
+            .map_or_else(|| default_key.clone(), |(_, key)| key.clone())
+    };
+    // The master key goes last so it can authorize the other changes.
+    let key_numbers = (1..key_settings.key_count()).chain(core::iter::once(0));
+    for key_number in key_numbers {
+        let key_number = KeyNumber::new(key_number)?;
+        let Some(new_key) = keys.application_key(application_id, key_number) else {
+            continue;
+        };
+        let authority = change_key_authority(key_settings, key_number)
+            .ok_or(RestoreError::KeyFrozen(application_id, key_number))?;
+        authenticate_key(
+            desfire,
+            authority,
+            &current_key(&current, authority),
+            random,
+        )?;
+        let old_key = (authority != key_number).then(|| current_key(&current, key_number));
+        let version = application
+            .key_versions
+            .iter()
+            .find(|(number, _)| *number == key_number)
+            .and_then(|(_, version)| version.ok())
+            .unwrap_or(0);
+        change_key(desfire, key_number, new_key, old_key.as_ref(), version)?;
+        current.retain(|(number, _)| *number != key_number);
+        current.push((key_number, new_key.clone()));
+    }
+    Ok(())
+}
+
+/// Creates one file under staging rights, fills it, then applies its settings.
+///
+/// Staging grants every right to the master key, so contents can be written
+/// even when the final rights forbid writing.
+fn restore_file<T, C>(
+    desfire: &mut Desfire<T, C>,
+    file_id: FileId,
+    settings: FileSettings,
+    contents: &Result<FileContents, Unavailable>,
+) -> Result<(), Error>
+where
+    T: Transport,
+    C: FrameCodec,
+{
+    let master = AccessCondition::Key(master_key_number());
+    let staging = AccessRights::new(master, master, master, master);
+    let mode = settings.communication_mode();
+    let zero = U24::new(0).expect("zero is a valid U24");
+
+    match (settings.details(), contents) {
+        (FileSettingsDetails::Data { size }, contents) => {
+            if settings.file_type() == FileType::BackupData {
+                desfire.create_backup_data_file(file_id, mode, staging, size)?;
+            } else {
+                desfire.create_std_data_file(file_id, mode, staging, size)?;
+            }
+            if let Ok(FileContents::Data(data)) = contents {
+                desfire.write_data_streamed(file_id, mode, zero, data)?;
+                if settings.file_type() == FileType::BackupData {
+                    desfire.commit_transaction()?;
+                }
+            }
+        }
+        (
+            FileSettingsDetails::Value {
+                lower_limit,
+                upper_limit,
+                limited_credit_enabled,
+                ..
+            },
+            contents,
+        ) => {
+            let value = match contents {
+                Ok(FileContents::Value(value)) => *value,
+                _ => lower_limit,
+            };
+            desfire.create_value_file(
+                file_id,
+                mode,
+                staging,
+                lower_limit,
+                upper_limit,
+                value,
+                limited_credit_enabled,
+            )?;
+        }
+        (
+            FileSettingsDetails::Record {
+                record_size,
+                max_records,
+                ..
+            },
+            contents,
+        ) => {
+            if settings.file_type() == FileType::CyclicRecord {
+                desfire.create_cyclic_record_file(
+                    file_id,
+                    mode,
+                    staging,
+                    record_size,
+                    max_records,
+                )?;
+            } else {
+                desfire.create_linear_record_file(
+                    file_id,
+                    mode,
+                    staging,
+                    record_size,
+                    max_records,
+                )?;
+            }
+            if let Ok(FileContents::Records(records)) = contents {
+                for record in records {
+                    desfire.write_record(file_id, mode, zero, record)?;
+                    desfire.commit_transaction()?;
+                }
+            }
+        }
+    }
+
+    desfire.change_file_settings(file_id, mode, settings.access_rights())
+}
+
+fn change_key<T, C>(
+    desfire: &mut Desfire<T, C>,
+    key_number: KeyNumber,
+    new_key: &Key,
+    old_key: Option<&Key>,
+    version: u8,
+) -> Result<(), Error>
+where
+    T: Transport,
+    C: FrameCodec,
+{
+    match new_key {
+        Key::Aes128(new_key) => {
+            let old_key = match old_key {
+                Some(Key::Aes128(old_key)) => Some(*old_key),
+                _ => None,
+            };
+            desfire.change_key_aes(key_number, *new_key, version, old_key)
+        }
+        Key::ThreeKey3Des(new_key) => {
+            let old_key = match old_key {
+                Some(Key::ThreeKey3Des(old_key)) => Some(*old_key),
+                _ => None,
+            };
+            desfire.change_key_3tdea(key_number, *new_key, old_key)
+        }
+        Key::Des(_) | Key::TwoKey3Des(_) => desfire.change_key_2tdea(
+            key_number,
+            double_des_bytes(new_key).expect("DES-family key"),
+            old_key.and_then(double_des_bytes),
+        ),
+    }
+}
+
+/// Reads the card back and compares it with the snapshot.
+fn verify<T, C, R>(
+    desfire: &mut Desfire<T, C>,
+    snapshot: &CardInventory,
+    keys: &InventoryKeys,
+    random: &mut R,
+) -> Result<(), RestoreError>
+where
+    T: Transport,
+    C: FrameCodec,
+    R: RandomSource + ?Sized,
+{
+    let restored = CardInventory::read(desfire, keys, random)?;
+    for expected in snapshot.applications.iter().flatten() {
+        let application_id = expected.application_id;
+        let mismatch = RestoreError::VerificationFailed(application_id);
+        let actual = restored.application(application_id).ok_or(mismatch)?;
+        if actual.key_settings != expected.key_settings {
+            return Err(mismatch);
+        }
+        for file in expected.files.iter().flatten() {
+            let restored_file = actual.file(file.file_id).ok_or(mismatch)?;
+            let same_settings = match (file.settings, restored_file.settings) {
+                (Ok(expected), Ok(actual)) => same_layout(expected, actual),
+                _ => false,
+            };
+            let same_contents = file.contents.is_err() || file.contents == restored_file.contents;
+            if !same_settings || !same_contents {
+                return Err(mismatch);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keys the card holds after restoring: the supplied ones, factory keys elsewhere.
+fn installed_keys(applications: &[ApplicationInventory], keys: &InventoryKeys) -> InventoryKeys {
+    let mut installed = keys.clone();
+    for application in applications {
+        let Ok(key_settings) = application.key_settings else {
+            continue;
+        };
+        let Some(default_key) = default_key(key_settings.key_type()) else {
+            continue;
+        };
+        for key_number in (0..key_settings.key_count()).filter_map(|n| KeyNumber::new(n).ok()) {
+            if keys
+                .application_key(application.application_id, key_number)
+                .is_none()
+            {
+                installed = installed.with_application_key(
+                    application.application_id,
+                    key_number,
+                    default_key.clone(),
+                );
+            }
+        }
+    }
+    installed
+}
+
+/// Compares file settings, ignoring the limited-credit amount left by past
+/// transactions.
+fn same_layout(expected: FileSettings, actual: FileSettings) -> bool {
+    let details = |settings: FileSettings| match settings.details() {
+        FileSettingsDetails::Value {
+            lower_limit,
+            upper_limit,
+            limited_credit_enabled,
+            ..
+        } => FileSettingsDetails::Value {
+            lower_limit,
+            upper_limit,
+            limited_credit_value: 0,
+            limited_credit_enabled,
+        },
+        details => details,
+    };
+    expected.file_type() == actual.file_type()
+        && expected.communication_mode() == actual.communication_mode()
+        && expected.access_rights() == actual.access_rights()
+        && details(expected) == details(actual)
+}
+
+/// Bytes of file memory the application needs, rounded to memory blocks.
+///
+/// Application and key storage overhead varies between card versions and is
+/// not counted.
+fn required_memory(application: &ApplicationInventory) -> u32 {
+    let files = application.files.iter().flatten();
+    files
+        .filter_map(|file| file.settings.ok())
+        .map(|settings| {
+            let bytes = match settings.details() {
+                FileSettingsDetails::Data { size }
+                    if settings.file_type() == FileType::BackupData =>
+                {
+                    size.as_u32() * 2
+                }
+                FileSettingsDetails::Data { size } => size.as_u32(),
+                FileSettingsDetails::Value { .. } => 4,
+                FileSettingsDetails::Record {
+                    record_size,
+                    max_records,
+                    ..
+                } => record_size.as_u32() * max_records.as_u32(),
+            };
+            bytes.div_ceil(MEMORY_BLOCK_SIZE) * MEMORY_BLOCK_SIZE
+        })
+        .sum()
+}
+
+/// Key that must be authenticated to change `key_number`, or `None` when the
+/// key settings freeze it.
+fn change_key_authority(key_settings: KeySettings, key_number: KeyNumber) -> Option<KeyNumber> {
+    if key_number == master_key_number() {
+        return key_settings
+            .master_key_changeable()
+            .then_some(master_key_number());
+    }
+    match key_settings.raw_settings() >> 4 {
+        0x0E => Some(key_number),
+        0x0F => None,
+        authority => KeyNumber::new(authority).ok(),
+    }
+}
+
+/// Factory key of an application created with `key_type`.
+const fn default_key(key_type: ApplicationKeyType) -> Option<Key> {
+    match key_type {
+        ApplicationKeyType::TwoKey3Des => Some(Key::TwoKey3Des([0; 16])),
+        ApplicationKeyType::ThreeKey3Des => Some(Key::ThreeKey3Des([0; 24])),
+        ApplicationKeyType::Aes => Some(Key::Aes128([0; 16])),
+        ApplicationKeyType::Rfu => None,
+    }
+}
+
+/// DES and 2TDEA keys as the 16 bytes `ChangeKey` sends for them.
+fn double_des_bytes(key: &Key) -> Option<[u8; 16]> {
+    match key {
+        Key::Des(key) => {
+            let mut double = [0; 16];
+            double[..8].copy_from_slice(key);
+            double[8..].copy_from_slice(key);
+            Some(double)
+        }
+        Key::TwoKey3Des(key) => Some(*key),
+        Key::ThreeKey3Des(_) | Key::Aes128(_) => None,
+    }
+}
+
+fn master_key_number() -> KeyNumber {
+    KeyNumber::new(0).expect("key 0 is valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::{
+        application::ApplicationId,
+        client::Desfire,
+        file::{AccessCondition, AccessRights, CommunicationMode, FileId},
+        framing::NativeFraming,
+        inventory::{CardInventory, InventoryKeys},
+        key::{ApplicationKeyType, Key, KeyNumber, KeySettings},
+        restore::{restore_layout, RestoreError},
+        rng::FixedRandom,
+        sim::{VirtualApplication, VirtualDesfire, VirtualFile, MEMORY_SIZE},
+        types::U24,
+    };
+
+    const AID: ApplicationId = ApplicationId::from_bytes([0x01, 0x02, 0x03]);
+    const MASTER_KEY: [u8; 16] = [0x10; 16];
+    const READ_KEY: [u8; 16] = [0x11; 16];
+
+    fn key(number: u8) -> KeyNumber {
+        KeyNumber::new(number).unwrap()
+    }
+
+    fn file(number: u8) -> FileId {
+        FileId::new(number).unwrap()
+    }
+
+    fn u24(value: u32) -> U24 {
+        U24::new(value).unwrap()
+    }
+
+    fn keys() -> InventoryKeys {
+        InventoryKeys::new()
+            .with_application_key(AID, key(0), Key::Aes128(MASTER_KEY))
+            .with_application_key(AID, key(1), Key::Aes128(READ_KEY))
+    }
+
+    fn source_card(data: &[u8]) -> VirtualDesfire {
+        let read_key = AccessCondition::Key(key(1));
+        let never = AccessCondition::Never;
+        let master = AccessCondition::Key(key(0));
+        let application =
+            VirtualApplication::new(AID, KeySettings::new(0x0B, ApplicationKeyType::Aes, 2))
+                .unwrap()
+                .with_key(key(0), Key::Aes128(MASTER_KEY), 3)
+                .unwrap()
+                .with_key(key(1), Key::Aes128(READ_KEY), 5)
+                .unwrap()
+                .with_file(
+                    file(0),
+                    VirtualFile::backup_data(
+                        CommunicationMode::Enciphered,
+                        AccessRights::new(read_key, never, never, never),
+                        data,
+                    ),
+                )
+                .with_file(
+                    file(1),
+                    VirtualFile::value(
+                        CommunicationMode::Maced,
+                        AccessRights::new(read_key, master, master, master),
+                        -10,
+                        500,
+                        42,
+                        true,
+                    ),
+                )
+                .with_file(
+                    file(2),
+                    VirtualFile::linear_record(
+                        CommunicationMode::Plain,
+                        AccessRights::new(AccessCondition::Free, master, never, master),
+                        u24(3),
+                        u24(4),
+                    ),
+                );
+        VirtualDesfire::new().with_application(application)
+    }
+
+    fn snapshot(card: &mut VirtualDesfire) -> CardInventory {
+        let mut desfire = Desfire::new(card, NativeFraming);
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_aes(key(0), &MASTER_KEY, &mut random())
+            .unwrap();
+        desfire
+            .write_record(file(2), CommunicationMode::Plain, u24(0), b"abc")
+            .unwrap();
+        desfire.commit_transaction().unwrap();
+        CardInventory::read(&mut desfire, &keys(), &mut random()).unwrap()
+    }
+
+    fn random() -> FixedRandom {
+        FixedRandom::new(&[0xA5])
+    }
+
+    #[test]
+    fn restores_layout_contents_and_keys_onto_blank_card() {
+        let data: std::vec::Vec<u8> = (0..=255).collect();
+        let snapshot = snapshot(&mut source_card(&data));
+        let mut target = VirtualDesfire::new();
+
+        restore_layout(
+            &mut Desfire::new(&mut target, NativeFraming),
+            &snapshot,
+            &keys(),
+            &mut random(),
+        )
+        .unwrap();
+
+        let application = target.application(AID).unwrap();
+        let expected = snapshot.application(AID).unwrap();
+        for number in 0..3 {
+            assert_eq!(
+                Ok(application.file(file(number)).unwrap().settings()),
+                expected.file(file(number)).unwrap().settings
+            );
+        }
+        assert_eq!(
+            application.file(file(0)).unwrap().data(),
+            Some(data.as_slice())
+        );
+        assert_eq!(application.file(file(1)).unwrap().current_value(), Some(42));
+        let records = application.file(file(2)).unwrap().records().unwrap();
+        assert_eq!(records, [b"abc".to_vec()]);
+
+        let mut desfire = Desfire::new(&mut target, NativeFraming);
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_aes(key(1), &READ_KEY, &mut random())
+            .unwrap();
+        assert_eq!(desfire.get_key_version(key(1)).unwrap(), 5);
+    }
+
+    #[test]
+    fn checks_free_memory_before_creating_anything() {
+        let data = std::vec![0x5A; usize::try_from(MEMORY_SIZE).unwrap()];
+        let snapshot = snapshot(&mut source_card(&data));
+        let mut target = VirtualDesfire::new();
+
+        let error = restore_layout(
+            &mut Desfire::new(&mut target, NativeFraming),
+            &snapshot,
+            &keys(),
+            &mut random(),
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            error,
+            RestoreError::InsufficientMemory {
+                available: MEMORY_SIZE,
+                ..
+            }
+        ));
+        assert!(target.application(AID).is_none());
+    }
+
+    #[test]
+    fn refuses_card_that_already_holds_the_application() {
+        let snapshot = snapshot(&mut source_card(b"data"));
+        let mut target = source_card(b"other");
+
+        let error = restore_layout(
+            &mut Desfire::new(&mut target, NativeFraming),
+            &snapshot,
+            &keys(),
+            &mut random(),
+        )
+        .unwrap_err();
+
+        assert_eq!(error, RestoreError::ApplicationExists(AID));
+    }
+}
@@ -0,0 +1,104 @@
+//! Randomness for reader challenges.
+//!
+//! Every authentication draws a fresh `RndA` from a [`RandomSource`]. Targets
+//! without `std` plug in their hardware generator; `std` builds on Unix can
+//! use `OsRandom`. Other `std` targets, such as Windows or WASM, need their own
+//! source.
+
+use crate::mifare::desfire::error::Error;
+
+/// Cryptographically secure source of random bytes.
+pub trait RandomSource {
+    /// Fills `bytes` with random data.
+    fn fill_bytes(&mut self, bytes: &mut [u8]) -> Result<(), Error>;
+}
+
+impl<R> RandomSource for &mut R
+where
+    R: RandomSource + ?Sized,
+{
+    fn fill_bytes(&mut self, bytes: &mut [u8]) -> Result<(), Error> {
+        (**self).fill_bytes(bytes)
+    }
+}
+
+/// Operating system random source, read from `/dev/urandom`.
+///
+/// Only available on Unix, where that device is the kernel CSPRNG. The device
+/// is opened on every call, so the type stays `Copy` and holds no descriptor.
+#[cfg(all(feature = "std", unix))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsRandom;
+
+#[cfg(all(feature = "std", unix))]
+impl RandomSource for OsRandom {
+    fn fill_bytes(&mut self, bytes: &mut [u8]) -> Result<(), Error> {
+        use std::io::Read;
+
+        std::fs::File::open("/dev/urandom")
+            .and_then(|mut file| file.read_exact(bytes))
+            .map_err(|_| Error::RandomSource)
+    }
+}
+
+/// Repeats a fixed byte pattern.
+///
+/// Only for replaying recorded traces and driving the simulator; never use
+/// it against a real card.
+#[cfg(any(test, feature = "sim"))]
+#[derive(Debug, Clone, Copy)]
+pub struct FixedRandom {
+    pattern: &'static [u8],
+    position: usize,
+}
+
+#[cfg(any(test, feature = "sim"))]
+impl FixedRandom {
+    /// Creates a source that cycles through `pattern`, which must not be empty.
+    pub const fn new(pattern: &'static [u8]) -> Self {
+        assert!(!pattern.is_empty(), "pattern must not be empty");
+        Self {
+            pattern,
+            position: 0,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "sim"))]
+impl RandomSource for FixedRandom {
+    fn fill_bytes(&mut self, bytes: &mut [u8]) -> Result<(), Error> {
+        for byte in bytes {
+            *byte = self.pattern[self.position];
+            self.position = (self.position + 1) % self.pattern.len();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::rng::{FixedRandom, RandomSource};
+
+    #[test]
+    fn fixed_random_cycles_through_pattern() {
+        let mut random = FixedRandom::new(&[1, 2, 3]);
+        let mut bytes = [0u8; 4];
+
+        random.fill_bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3, 1]);
+        random.fill_bytes(&mut bytes[..2]).unwrap();
+        assert_eq!(bytes[..2], [2, 3]);
+    }
+
+    #[cfg(all(feature = "std", unix))]
+    #[test]
+    fn os_random_fills_buffer() {
+        let mut first = [0u8; 16];
+        let mut second = [0u8; 16];
+
+        super::OsRandom.fill_bytes(&mut first).unwrap();
+        super::OsRandom.fill_bytes(&mut second).unwrap();
+
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,114 @@
+//! Handling for key material and authentication tags.
+//!
+//! [`SecretKey`] wipes its bytes on drop, never prints them and compares in
+//! constant time. [`constant_time_eq`] is for every MAC, CMAC and CRC check
+//! on data that came from the card.
+
+use core::{
+    fmt,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+/// Fixed-size key material that is zeroized on drop.
+///
+/// Deliberately not `Copy`: every duplicate is an explicit `clone` that is
+/// wiped in turn.
+#[derive(Clone)]
+pub struct SecretKey<const N: usize>([u8; N]);
+
+impl<const N: usize> SecretKey<N> {
+    /// Wraps raw key bytes.
+    pub const fn new(bytes: [u8; N]) -> Self {
+        Self(bytes)
+    }
+
+    /// Raw key bytes.
+    pub const fn as_bytes(&self) -> &[u8; N] {
+        &self.0
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for SecretKey<N> {
+    fn from(bytes: [u8; N]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl<const N: usize> Drop for SecretKey<N> {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+impl<const N: usize> fmt::Debug for SecretKey<N> {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "SecretKey<{N}>(..)")
+    }
+}
+
+impl<const N: usize> PartialEq for SecretKey<N> {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl<const N: usize> Eq for SecretKey<N> {}
+
+/// Compares two byte strings without branching on their contents.
+///
+/// Only the lengths leak; they are public for every MAC and CRC.
+pub fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    if left.len() != right.len() {
+        return false;
+    }
+    let difference = left
+        .iter()
+        .zip(right)
+        .fold(0u8, |difference, (left, right)| difference | (left ^ right));
+    // Keep the optimizer from turning the fold back into an early exit.
+    core::hint::black_box(difference) == 0
+}
+
+/// Overwrites `bytes` with zeros in a way the optimizer cannot elide.
+// Volatile writes are the only way to keep dead stores to dropped memory.
+#[allow(unsafe_code)]
+pub(crate) fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // SAFETY: `byte` is a valid, aligned and exclusive reference.
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::mifare::desfire::secret::{constant_time_eq, zeroize, SecretKey};
+
+    #[test]
+    fn compares_in_full() {
+        assert!(constant_time_eq(&[1, 2, 3], &[1, 2, 3]));
+        assert!(!constant_time_eq(&[1, 2, 3], &[1, 2, 4]));
+        assert!(!constant_time_eq(&[1, 2, 3], &[1, 2]));
+        assert!(constant_time_eq(&[], &[]));
+    }
+
+    #[test]
+    fn zeroizes_bytes() {
+        let mut bytes = [0xA5; 8];
+        zeroize(&mut bytes);
+        assert_eq!(bytes, [0; 8]);
+    }
+
+    #[test]
+    fn redacts_debug_output() {
+        let key = SecretKey::new([0xA5; 16]);
+        let mut debug: heapless::String<32> = heapless::String::new();
+        write!(debug, "{key:?}").unwrap();
+
+        assert_eq!(debug.as_str(), "SecretKey<16>(..)");
+        assert_eq!(key, SecretKey::new([0xA5; 16]));
+        assert_ne!(key, SecretKey::new([0x5A; 16]));
+    }
+}
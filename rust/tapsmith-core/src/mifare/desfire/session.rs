@@ -6,8 +6,8 @@ use crate::mifare::desfire::{
         aes_cbc_decrypt_in_place, aes_cbc_encrypt_in_place, des_cbc_decrypt_in_place,
         des_cbc_encrypt_in_place, des_cbc_mac, tdes2_cbc_decrypt_in_place,
         tdes2_cbc_encrypt_in_place, tdes2_cbc_mac, tdes3_cbc_decrypt_in_place,
-        tdes3_cbc_encrypt_in_place, tdes3_cbc_mac, AesCmacChaining, AesSessionKey, DesSessionKey,
-        DesfireMac, ThreeKey3DesSessionKey, TwoKey3DesSessionKey,
+        tdes3_cbc_encrypt_in_place, tdes3_cbc_mac, AesCmac, AesCmacChaining, AesSessionKey,
+        DesSessionKey, DesfireMac, ThreeKey3DesSessionKey, TwoKey3DesSessionKey,
     },
     error::Error,
     key::KeyNumber,
@@ -16,22 +16,37 @@ use crate::mifare::desfire::{
 
 const MAX_CMAC_INPUT_SIZE: usize = 256;
 
+/// EV2 MAC inputs prefix the payload with `Cmd/RC || CmdCtr || TI`.
+const EV2_MAC_HEADER_SIZE: usize = 7;
+
 /// Authentication state for a `DESFire` command stream.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Session {
     Unauthenticated,
     Authenticated(AuthenticatedSession),
+    AuthenticatedEv2(Ev2Session),
+}
+
+impl Session {
+    /// Key the session authenticated with, if any.
+    pub const fn key_number(&self) -> Option<KeyNumber> {
+        match self {
+            Self::Unauthenticated => None,
+            Self::Authenticated(session) => Some(session.key_number()),
+            Self::AuthenticatedEv2(session) => Some(session.key_number()),
+        }
+    }
 }
 
 /// Authenticated-session metadata and secure-messaging state.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AuthenticatedSession {
     key_number: KeyNumber,
     state: AlgoState,
 }
 
 /// Per-algorithm session state (key material and chaining IV, bundled together).
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum AlgoState {
     Aes(AesState),
     Des(DesState),
@@ -39,26 +54,26 @@ enum AlgoState {
     ThreeKey3Des(ThreeKey3DesState),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct AesState {
     key: AesSessionKey,
     chaining: AesCmacChaining,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct DesState {
     key: DesSessionKey,
     chaining: [u8; 8],
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct TwoKey3DesState {
     key: TwoKey3DesSessionKey,
     secure_messaging_key: TwoKey3DesSessionKey,
     chaining: [u8; 8],
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct ThreeKey3DesState {
     key: ThreeKey3DesSessionKey,
     chaining: [u8; 8],
@@ -88,8 +103,9 @@ impl AuthenticatedSession {
     }
 
     /// Creates session state for a successful two-key 3DES authentication.
-    pub const fn new_2tdea(key_number: KeyNumber, session_key: TwoKey3DesSessionKey) -> Self {
-        Self::new_2tdea_with_secure_messaging_key(key_number, session_key, session_key)
+    pub fn new_2tdea(key_number: KeyNumber, session_key: TwoKey3DesSessionKey) -> Self {
+        let secure_messaging_key = session_key.clone();
+        Self::new_2tdea_with_secure_messaging_key(key_number, session_key, secure_messaging_key)
     }
 
     /// Creates session state for a 2TDEA authentication whose secure messaging key differs.
@@ -120,18 +136,61 @@ impl AuthenticatedSession {
     }
 
     /// Key number used for the current authentication.
-    pub const fn key_number(self) -> KeyNumber {
+    pub const fn key_number(&self) -> KeyNumber {
         self.key_number
     }
 
     /// Session key negotiated by the current authentication.
-    pub fn session_key(self) -> SessionKey {
-        match self.state {
-            AlgoState::Aes(s) => SessionKey::Aes(s.key),
-            AlgoState::Des(s) => SessionKey::Des(s.key),
-            AlgoState::TwoKey3Des(s) => SessionKey::TwoKey3Des(s.key),
-            AlgoState::ThreeKey3Des(s) => SessionKey::ThreeKey3Des(s.key),
+    pub fn session_key(&self) -> SessionKey {
+        match &self.state {
+            AlgoState::Aes(s) => SessionKey::Aes(s.key.clone()),
+            AlgoState::Des(s) => SessionKey::Des(s.key.clone()),
+            AlgoState::TwoKey3Des(s) => SessionKey::TwoKey3Des(s.key.clone()),
+            AlgoState::ThreeKey3Des(s) => SessionKey::ThreeKey3Des(s.key.clone()),
+        }
+    }
+
+    /// Key that encrypts command payloads; the secure-messaging key for 2TDEA.
+    pub fn encryption_key(&self) -> SessionKey {
+        match &self.state {
+            AlgoState::TwoKey3Des(s) => SessionKey::TwoKey3Des(s.secure_messaging_key.clone()),
+            _ => self.session_key(),
+        }
+    }
+
+    /// Current chaining IV, one block long.
+    pub fn chaining_iv(&self) -> Vec<u8, 16> {
+        let iv = match &self.state {
+            AlgoState::Aes(s) => return Vec::from_array(s.chaining.state()),
+            AlgoState::Des(s) => s.chaining,
+            AlgoState::TwoKey3Des(s) => s.chaining,
+            AlgoState::ThreeKey3Des(s) => s.chaining,
+        };
+        Vec::from_slice(&iv).expect("a DES block fits")
+    }
+
+    /// Advances the chaining IV past `ciphertext` encrypted elsewhere under
+    /// [`Self::encryption_key`], as [`Self::cbc_encrypt_in_place`] would.
+    ///
+    /// `ciphertext` must be a non-empty multiple of [`Self::block_size()`] bytes.
+    pub fn advance_chaining(&mut self, ciphertext: &[u8]) -> Result<(), Error> {
+        let block_size = self.block_size();
+        if ciphertext.is_empty() || !ciphertext.len().is_multiple_of(block_size) {
+            return Err(Error::CryptoProvider);
+        }
+        let last_block = &ciphertext[ciphertext.len() - block_size..];
+        match &mut self.state {
+            AlgoState::Aes(s) => {
+                s.chaining =
+                    AesCmacChaining::from_state(last_block.try_into().expect("length checked"));
+            }
+            AlgoState::Des(s) => s.chaining = last_block.try_into().expect("length checked"),
+            AlgoState::TwoKey3Des(s) => s.chaining = last_block.try_into().expect("length checked"),
+            AlgoState::ThreeKey3Des(s) => {
+                s.chaining = last_block.try_into().expect("length checked");
+            }
         }
+        Ok(())
     }
 
     /// Cipher block size in bytes for this session's algorithm.
@@ -187,8 +246,8 @@ impl AuthenticatedSession {
     ///
     /// Returns `None` for all other algorithm families.
     pub fn aes_state(&self) -> Option<(AesSessionKey, AesCmacChaining)> {
-        match self.state {
-            AlgoState::Aes(s) => Some((s.key, s.chaining)),
+        match &self.state {
+            AlgoState::Aes(s) => Some((s.key.clone(), s.chaining)),
             _ => None,
         }
     }
@@ -208,18 +267,15 @@ impl AuthenticatedSession {
             .map_err(|_| Error::CommandTooLong)?;
 
         match &mut self.state {
-            AlgoState::Aes(s) => {
-                let key = s.key;
-                Ok(s.chaining.update(key, input.as_slice()).desfire_mac())
-            }
+            AlgoState::Aes(s) => Ok(s.chaining.update(&s.key, input.as_slice()).desfire_mac()),
             AlgoState::Des(s) => {
-                let mac = des_cbc_mac(&s.key.as_bytes(), &s.chaining, input.as_slice());
+                let mac = des_cbc_mac(s.key.as_bytes(), &s.chaining, input.as_slice());
                 s.chaining = mac;
                 Ok(des_mac_to_desfire_mac(mac))
             }
             AlgoState::TwoKey3Des(s) => {
                 let mac = tdes2_cbc_mac(
-                    &s.secure_messaging_key.as_bytes(),
+                    s.secure_messaging_key.as_bytes(),
                     &s.chaining,
                     input.as_slice(),
                 );
@@ -227,7 +283,7 @@ impl AuthenticatedSession {
                 Ok(des_mac_to_desfire_mac(mac))
             }
             AlgoState::ThreeKey3Des(s) => {
-                let mac = tdes3_cbc_mac(&s.key.as_bytes(), &s.chaining, input.as_slice());
+                let mac = tdes3_cbc_mac(s.key.as_bytes(), &s.chaining, input.as_slice());
                 s.chaining = mac;
                 Ok(des_mac_to_desfire_mac(mac))
             }
@@ -249,18 +305,15 @@ impl AuthenticatedSession {
             .map_err(|_| Error::ResponseTooLong)?;
 
         match &mut self.state {
-            AlgoState::Aes(s) => {
-                let key = s.key;
-                Ok(s.chaining.update(key, input.as_slice()).desfire_mac())
-            }
+            AlgoState::Aes(s) => Ok(s.chaining.update(&s.key, input.as_slice()).desfire_mac()),
             AlgoState::Des(s) => {
-                let mac = des_cbc_mac(&s.key.as_bytes(), &s.chaining, input.as_slice());
+                let mac = des_cbc_mac(s.key.as_bytes(), &s.chaining, input.as_slice());
                 s.chaining = mac;
                 Ok(des_mac_to_desfire_mac(mac))
             }
             AlgoState::TwoKey3Des(s) => {
                 let mac = tdes2_cbc_mac(
-                    &s.secure_messaging_key.as_bytes(),
+                    s.secure_messaging_key.as_bytes(),
                     &s.chaining,
                     input.as_slice(),
                 );
@@ -268,7 +321,7 @@ impl AuthenticatedSession {
                 Ok(des_mac_to_desfire_mac(mac))
             }
             AlgoState::ThreeKey3Des(s) => {
-                let mac = tdes3_cbc_mac(&s.key.as_bytes(), &s.chaining, input.as_slice());
+                let mac = tdes3_cbc_mac(s.key.as_bytes(), &s.chaining, input.as_slice());
                 s.chaining = mac;
                 Ok(des_mac_to_desfire_mac(mac))
             }
@@ -290,28 +343,28 @@ impl AuthenticatedSession {
                 let iv = s.chaining.state();
                 let last_block: [u8; 16] =
                     data[data.len() - 16..].try_into().expect("length checked");
-                aes_cbc_decrypt_in_place(&s.key.as_bytes(), &iv, data);
+                aes_cbc_decrypt_in_place(s.key.as_bytes(), &iv, data);
                 s.chaining = AesCmacChaining::from_state(last_block);
                 Ok(())
             }
             AlgoState::Des(s) => {
                 let last_block: [u8; 8] =
                     data[data.len() - 8..].try_into().expect("length checked");
-                des_cbc_decrypt_in_place(&s.key.as_bytes(), &s.chaining, data);
+                des_cbc_decrypt_in_place(s.key.as_bytes(), &s.chaining, data);
                 s.chaining = last_block;
                 Ok(())
             }
             AlgoState::TwoKey3Des(s) => {
                 let last_block: [u8; 8] =
                     data[data.len() - 8..].try_into().expect("length checked");
-                tdes2_cbc_decrypt_in_place(&s.secure_messaging_key.as_bytes(), &s.chaining, data);
+                tdes2_cbc_decrypt_in_place(s.secure_messaging_key.as_bytes(), &s.chaining, data);
                 s.chaining = last_block;
                 Ok(())
             }
             AlgoState::ThreeKey3Des(s) => {
                 let last_block: [u8; 8] =
                     data[data.len() - 8..].try_into().expect("length checked");
-                tdes3_cbc_decrypt_in_place(&s.key.as_bytes(), &s.chaining, data);
+                tdes3_cbc_decrypt_in_place(s.key.as_bytes(), &s.chaining, data);
                 s.chaining = last_block;
                 Ok(())
             }
@@ -330,24 +383,24 @@ impl AuthenticatedSession {
                     "AES CBC data must be a non-empty multiple of 16 bytes"
                 );
                 let iv = s.chaining.state();
-                aes_cbc_encrypt_in_place(&s.key.as_bytes(), &iv, data);
+                aes_cbc_encrypt_in_place(s.key.as_bytes(), &iv, data);
                 let last_block: [u8; 16] =
                     data[data.len() - 16..].try_into().expect("length checked");
                 s.chaining = AesCmacChaining::from_state(last_block);
                 Ok(())
             }
             AlgoState::Des(s) => {
-                des_cbc_encrypt_in_place(&s.key.as_bytes(), &s.chaining, data);
+                des_cbc_encrypt_in_place(s.key.as_bytes(), &s.chaining, data);
                 s.chaining = data[data.len() - 8..].try_into().expect("length checked");
                 Ok(())
             }
             AlgoState::TwoKey3Des(s) => {
-                tdes2_cbc_encrypt_in_place(&s.secure_messaging_key.as_bytes(), &s.chaining, data);
+                tdes2_cbc_encrypt_in_place(s.secure_messaging_key.as_bytes(), &s.chaining, data);
                 s.chaining = data[data.len() - 8..].try_into().expect("length checked");
                 Ok(())
             }
             AlgoState::ThreeKey3Des(s) => {
-                tdes3_cbc_encrypt_in_place(&s.key.as_bytes(), &s.chaining, data);
+                tdes3_cbc_encrypt_in_place(s.key.as_bytes(), &s.chaining, data);
                 s.chaining = data[data.len() - 8..].try_into().expect("length checked");
                 Ok(())
             }
@@ -355,12 +408,187 @@ impl AuthenticatedSession {
     }
 }
 
+/// EV2 secure-messaging state established by `AuthenticateEV2First`.
+///
+/// Unlike EV1 sessions there is no chaining IV: every MAC and IV is derived from
+/// the transaction identifier and the command counter, which advances once per
+/// command/response pair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ev2Session {
+    key_number: KeyNumber,
+    transaction_identifier: [u8; 4],
+    command_counter: u16,
+    encryption_key: AesSessionKey,
+    mac_key: AesSessionKey,
+}
+
+impl Ev2Session {
+    /// Creates session state for a successful `AuthenticateEV2First`.
+    ///
+    /// The command counter starts at zero.
+    pub const fn new(
+        key_number: KeyNumber,
+        transaction_identifier: [u8; 4],
+        encryption_key: AesSessionKey,
+        mac_key: AesSessionKey,
+    ) -> Self {
+        Self {
+            key_number,
+            transaction_identifier,
+            command_counter: 0,
+            encryption_key,
+            mac_key,
+        }
+    }
+
+    /// Returns this session with new keys after `AuthenticateEV2NonFirst`.
+    ///
+    /// The transaction identifier and command counter carry over unchanged.
+    #[must_use]
+    pub fn reauthenticated(
+        self,
+        key_number: KeyNumber,
+        encryption_key: AesSessionKey,
+        mac_key: AesSessionKey,
+    ) -> Self {
+        Self {
+            key_number,
+            encryption_key,
+            mac_key,
+            ..self
+        }
+    }
+
+    /// Returns this session with an explicit command counter.
+    #[must_use]
+    pub fn with_command_counter(self, command_counter: u16) -> Self {
+        Self {
+            command_counter,
+            ..self
+        }
+    }
+
+    /// Key number used for the current authentication.
+    pub const fn key_number(&self) -> KeyNumber {
+        self.key_number
+    }
+
+    /// Transaction identifier (`TI`) assigned by the card.
+    pub const fn transaction_identifier(&self) -> [u8; 4] {
+        self.transaction_identifier
+    }
+
+    /// Current command counter (`CmdCtr`).
+    pub const fn command_counter(&self) -> u16 {
+        self.command_counter
+    }
+
+    /// Session encryption key (`SesAuthENCKey`).
+    pub const fn encryption_key(&self) -> &AesSessionKey {
+        &self.encryption_key
+    }
+
+    /// Session MAC key (`SesAuthMACKey`).
+    pub const fn mac_key(&self) -> &AesSessionKey {
+        &self.mac_key
+    }
+
+    /// Advances the command counter after the card has answered a command.
+    pub fn increment_command_counter(&mut self) -> Result<(), Error> {
+        self.command_counter = self
+            .command_counter
+            .checked_add(1)
+            .ok_or(Error::CommandCounterOverflow)?;
+        Ok(())
+    }
+
+    /// Calculates the truncated command MAC over `Cmd || CmdCtr || TI || data`.
+    pub fn command_mac(
+        &self,
+        command_code: CommandCode,
+        command_data: &[u8],
+    ) -> Result<DesfireMac, Error> {
+        self.mac(command_code.as_byte(), command_data, Error::CommandTooLong)
+    }
+
+    /// Calculates the truncated response MAC over `RC || CmdCtr || TI || data`.
+    ///
+    /// Must be called after [`Self::increment_command_counter`].
+    pub fn response_mac(&self, status: Status, response_data: &[u8]) -> Result<DesfireMac, Error> {
+        self.mac(status.as_byte(), response_data, Error::ResponseTooLong)
+    }
+
+    /// IV for command data encrypted under [`Self::encryption_key`].
+    pub fn command_iv(&self) -> [u8; 16] {
+        self.iv([0xA5, 0x5A])
+    }
+
+    /// Pads `data` with ISO/IEC 9797-1 method 2 and encrypts it with the command IV.
+    pub fn encrypt_command_data<const N: usize>(&self, data: &mut Vec<u8, N>) -> Result<(), Error> {
+        data.push(0x80).map_err(|_| Error::CommandTooLong)?;
+        while !data.len().is_multiple_of(16) {
+            data.push(0x00).map_err(|_| Error::CommandTooLong)?;
+        }
+
+        let iv = self.command_iv();
+        aes_cbc_encrypt_in_place(self.encryption_key.as_bytes(), &iv, data.as_mut_slice());
+        Ok(())
+    }
+
+    /// Decrypts response data with the response IV and strips method 2 padding.
+    ///
+    /// Must be called after [`Self::increment_command_counter`]. Returns the
+    /// plaintext length.
+    pub fn decrypt_response_data(&self, data: &mut [u8]) -> Result<usize, Error> {
+        if data.is_empty() || !data.len().is_multiple_of(16) {
+            return Err(Error::InvalidResponseLength);
+        }
+
+        let iv = self.iv([0x5A, 0xA5]);
+        aes_cbc_decrypt_in_place(self.encryption_key.as_bytes(), &iv, data);
+
+        let padding_start = data
+            .iter()
+            .rposition(|&byte| byte != 0x00)
+            .ok_or(Error::InvalidPadding)?;
+        if data[padding_start] != 0x80 || data.len() - padding_start > 16 {
+            return Err(Error::InvalidPadding);
+        }
+        Ok(padding_start)
+    }
+
+    fn mac(&self, first_byte: u8, data: &[u8], too_long: Error) -> Result<DesfireMac, Error> {
+        let mut input: Vec<u8, { MAX_CMAC_INPUT_SIZE + EV2_MAC_HEADER_SIZE }> = Vec::new();
+        input.push(first_byte).map_err(|_| too_long)?;
+        input
+            .extend_from_slice(&self.command_counter.to_le_bytes())
+            .map_err(|_| too_long)?;
+        input
+            .extend_from_slice(&self.transaction_identifier)
+            .map_err(|_| too_long)?;
+        input.extend_from_slice(data).map_err(|_| too_long)?;
+
+        Ok(AesCmac::calculate(self.mac_key.as_bytes(), input.as_slice()).ev2_mac())
+    }
+
+    fn iv(&self, label: [u8; 2]) -> [u8; 16] {
+        let mut iv = [0u8; 16];
+        iv[0..2].copy_from_slice(&label);
+        iv[2..6].copy_from_slice(&self.transaction_identifier);
+        iv[6..8].copy_from_slice(&self.command_counter.to_le_bytes());
+
+        let mut out = iv;
+        aes_cbc_encrypt_in_place(self.encryption_key.as_bytes(), &[0u8; 16], &mut out);
+        out
+    }
+}
+
 fn des_mac_to_desfire_mac(mac: [u8; 8]) -> DesfireMac {
     DesfireMac::new(mac)
 }
 
 /// Authenticated secure-messaging key material.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SessionKey {
     Des(DesSessionKey),
     TwoKey3Des(TwoKey3DesSessionKey),
@@ -368,6 +596,16 @@ pub enum SessionKey {
     Aes(AesSessionKey),
 }
 
+impl SessionKey {
+    /// Cipher block size in bytes: 16 for AES, 8 for the DES family.
+    pub const fn block_size(&self) -> usize {
+        match self {
+            Self::Aes(_) => 16,
+            Self::Des(_) | Self::TwoKey3Des(_) | Self::ThreeKey3Des(_) => 8,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::mifare::desfire::{
@@ -375,8 +613,9 @@ mod tests {
         crypto::{
             desfire_crc32, AesCmac, AesSessionKey, ThreeKey3DesSessionKey, TwoKey3DesSessionKey,
         },
+        error::Error,
         key::KeyNumber,
-        session::AuthenticatedSession,
+        session::{AuthenticatedSession, Ev2Session},
         status::Status,
     };
 
@@ -386,7 +625,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0x47, 0xDB, 0x4F, 0x91, 0x13, 0x14, 0x15, 0x16, 0x6E, 0xC6,
             0x58, 0x25,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         let mac = session
             .update_command_cmac(
@@ -395,7 +635,7 @@ mod tests {
             )
             .unwrap();
         let expected_full_cmac = AesCmac::calculate(
-            &session_key.as_bytes(),
+            session_key.as_bytes(),
             &[0xBD, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00],
         );
 
@@ -410,9 +650,9 @@ mod tests {
     fn cbc_encrypt_decrypt_roundtrip_aes() {
         let session_key = AesSessionKey::new([0x11; 16]);
         let mut enc_session =
-            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
         let mut dec_session =
-            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         let original = [
             0x00u8, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D,
@@ -432,7 +672,8 @@ mod tests {
         use crate::mifare::desfire::crypto::aes_cbc_encrypt_in_place;
 
         let session_key = AesSessionKey::new([0x22; 16]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
         let mut data = [0x33u8; 32];
 
         session.cbc_encrypt_in_place(&mut data).unwrap();
@@ -444,7 +685,7 @@ mod tests {
         let mut second = [0x44u8; 16];
         let mut expected_second = [0x44u8; 16];
         aes_cbc_encrypt_in_place(
-            &session_key.as_bytes(),
+            session_key.as_bytes(),
             &expected_last_block,
             &mut expected_second,
         );
@@ -495,7 +736,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0x80, 0x08, 0xF7, 0x4F, 0x13, 0x14, 0x15, 0x16, 0x3E, 0x9A,
             0x3B, 0x1C,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         session
             .update_command_cmac(CommandCode::FORMAT_PICC, &[])
@@ -521,7 +763,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0x90, 0x1E, 0x6D, 0xBC, 0x13, 0x14, 0x15, 0x16, 0x69, 0xC2,
             0xAA, 0xFA,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         session
             .update_command_cmac(CommandCode::DELETE_FILE, &[0x01])
@@ -548,7 +791,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0xA3, 0x63, 0x2A, 0x85, 0x13, 0x14, 0x15, 0x16, 0xCA, 0xB9,
             0xDB, 0xE9,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         session
             .update_command_cmac(CommandCode::DELETE_APPLICATION, &[0x22, 0x22, 0x22])
@@ -576,7 +820,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0x24, 0x36, 0x0B, 0xAA, 0x13, 0x14, 0x15, 0x16, 0x19, 0x61,
             0xD1, 0xBC,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         session
             .update_command_cmac(
@@ -622,7 +867,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0x82, 0x33, 0x20, 0x14, 0x13, 0x14, 0x15, 0x16, 0xDA, 0x96,
             0xDD, 0x27,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         let new_key: [u8; 16] = [
             0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
@@ -681,7 +927,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0x0B, 0x5D, 0xFE, 0x1D, 0x13, 0x14, 0x15, 0x16, 0x4C, 0x6B,
             0xC1, 0xB3,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
         let new_key: [u8; 16] = [
             0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
             0x07, 0x08,
@@ -718,7 +965,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0x03, 0x95, 0x69, 0x17, 0x13, 0x14, 0x15, 0x16, 0x06, 0xEF,
             0x0E, 0x66,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         let new_key: [u8; 16] = [
             0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
@@ -769,7 +1017,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0xD4, 0xE8, 0x23, 0x47, 0x13, 0x14, 0x15, 0x16, 0xB1, 0xEF,
             0xFA, 0xFC,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         // Step 1: GetFileSettings fid=0x00
         session
@@ -831,7 +1080,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0xB9, 0xC5, 0xB2, 0x72, 0x13, 0x14, 0x15, 0x16, 0x2A, 0xF9,
             0xA9, 0x10,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         session
             .update_command_cmac(
@@ -867,7 +1117,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0x32, 0xD0, 0x98, 0x89, 0x13, 0x14, 0x15, 0x16, 0x36, 0x02,
             0x82, 0xD5,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         // Step 1: GET_FILE_SETTINGS fid=0x01
         session
@@ -926,7 +1177,8 @@ mod tests {
             0x01, 0x02, 0x03, 0x04, 0x59, 0x05, 0x6A, 0xFD, 0x13, 0x14, 0x15, 0x16, 0x0B, 0xA4,
             0xCE, 0xBF,
         ]);
-        let mut session = AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key);
+        let mut session =
+            AuthenticatedSession::new_aes(KeyNumber::new(0).unwrap(), session_key.clone());
 
         // CMAC state updated for command (MAC not appended to command per DESFire EV1 spec).
         session
@@ -1035,4 +1287,132 @@ mod tests {
             [0x75, 0xDF, 0xAA, 0xA4, 0x8E, 0xCA, 0x79, 0x2A]
         );
     }
+
+    fn ev2_session() -> Ev2Session {
+        Ev2Session::new(
+            KeyNumber::new(0).unwrap(),
+            [0x9D, 0x00, 0xC4, 0xDF],
+            AesSessionKey::new([0x11; 16]),
+            AesSessionKey::new([0x22; 16]),
+        )
+    }
+
+    #[test]
+    fn ev2_command_counter_rejects_overflow() {
+        let mut session = ev2_session().with_command_counter(u16::MAX - 1);
+
+        session.increment_command_counter().unwrap();
+        assert_eq!(session.command_counter(), u16::MAX);
+        assert_eq!(
+            session.increment_command_counter(),
+            Err(Error::CommandCounterOverflow)
+        );
+    }
+
+    #[test]
+    fn ev2_command_padding_always_adds_a_block_boundary() {
+        let session = ev2_session();
+        let mut full_block: heapless::Vec<u8, 32> = heapless::Vec::new();
+        full_block.extend_from_slice(&[0xAB; 16]).unwrap();
+
+        session.encrypt_command_data(&mut full_block).unwrap();
+
+        assert_eq!(full_block.len(), 32);
+    }
+
+    #[test]
+    fn ev2_command_iv_matches_an12343_example() {
+        let session = Ev2Session::new(
+            KeyNumber::new(0).unwrap(),
+            [0xED, 0x56, 0xF6, 0xE6],
+            AesSessionKey::new([
+                0x66, 0xA8, 0xCB, 0x93, 0x26, 0x9D, 0xC9, 0xBC, 0x28, 0x85, 0xB7, 0xA9, 0x1B, 0x9C,
+                0x69, 0x7B,
+            ]),
+            AesSessionKey::new([0; 16]),
+        );
+
+        assert_eq!(
+            session.command_iv(),
+            [
+                0xDA, 0x0F, 0x64, 0x4A, 0x49, 0x86, 0x27, 0x59, 0x57, 0xCF, 0x1E, 0xC3, 0xAF, 0x4C,
+                0xCE, 0x53
+            ]
+        );
+    }
+
+    #[test]
+    fn ev2_macs_match_an12343_example() {
+        // `CommMode.MAC` WriteData and ReadData of the ISO data commands.
+        let mut session = Ev2Session::new(
+            KeyNumber::new(0).unwrap(),
+            [0xE2, 0xD3, 0xAF, 0x69],
+            AesSessionKey::new([0; 16]),
+            AesSessionKey::new([
+                0x93, 0x66, 0xFA, 0x19, 0x5E, 0xB5, 0x66, 0xF5, 0xBD, 0x2B, 0xAD, 0x40, 0x20, 0xB8,
+                0x30, 0x02,
+            ]),
+        );
+        let mut write = [0x22; 32];
+        write[..7].copy_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00]);
+        let mut file = [0x22; 48];
+        file[25..].fill(0);
+
+        assert_eq!(
+            session
+                .command_mac(CommandCode::WRITE_DATA_ISO, &write)
+                .unwrap()
+                .as_bytes(),
+            [0x68, 0xF2, 0xC2, 0x8C, 0x57, 0x5A, 0x16, 0x28]
+        );
+        session.increment_command_counter().unwrap();
+        assert_eq!(
+            session
+                .response_mac(Status::OperationOk, &[])
+                .unwrap()
+                .as_bytes(),
+            [0x08, 0x20, 0xF6, 0x88, 0x98, 0xC2, 0xA7, 0xF1]
+        );
+        assert_eq!(
+            session
+                .command_mac(
+                    CommandCode::READ_DATA_ISO,
+                    &[0x00, 0x00, 0x00, 0x00, 0x30, 0x00, 0x00]
+                )
+                .unwrap()
+                .as_bytes(),
+            [0x0D, 0x9B, 0xE1, 0x91, 0xD5, 0x96, 0x08, 0x34]
+        );
+        session.increment_command_counter().unwrap();
+        assert_eq!(
+            session
+                .response_mac(Status::OperationOk, &file)
+                .unwrap()
+                .as_bytes(),
+            [0xA4, 0x9A, 0x44, 0x22, 0x2D, 0x92, 0x66, 0x66]
+        );
+    }
+
+    #[test]
+    fn ev2_response_decryption_strips_padding() {
+        use crate::mifare::desfire::crypto::aes_cbc_encrypt_in_place;
+
+        let session = ev2_session().with_command_counter(3);
+        let key = session.encryption_key().as_bytes();
+        let mut iv = [
+            0x5A, 0xA5, 0x9D, 0x00, 0xC4, 0xDF, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        aes_cbc_encrypt_in_place(key, &[0u8; 16], &mut iv);
+        let mut response = [0u8; 16];
+        response[..4].copy_from_slice(&[0x01, 0x02, 0x03, 0x80]);
+        aes_cbc_encrypt_in_place(key, &iv, &mut response);
+
+        assert_eq!(session.decrypt_response_data(&mut response), Ok(3));
+        assert_eq!(&response[..3], &[0x01, 0x02, 0x03]);
+        assert_eq!(
+            session.decrypt_response_data(&mut [0u8; 15]),
+            Err(Error::InvalidResponseLength)
+        );
+    }
 }
@@ -0,0 +1,204 @@
+use std::{collections::BTreeMap, vec::Vec};
+
+use crate::mifare::desfire::{
+    application::ApplicationId,
+    error::Error,
+    file::FileId,
+    key::{ApplicationKeyType, Key, KeyNumber, KeySettings},
+    sim::VirtualFile,
+};
+
+/// Most keys an EV1 application can hold.
+const MAX_KEYS: u8 = 14;
+
+/// One key slot of the PICC or an application.
+///
+/// DES keys are stored as 2TDEA keys with equal halves, which is how the card
+/// keeps them. Only AES keys carry a separate version byte; DES-family key
+/// versions live in the parity bits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct KeySlot {
+    key: Key,
+    version: u8,
+}
+
+impl KeySlot {
+    pub(super) fn new(key: Key, version: u8) -> Self {
+        let key = match key {
+            Key::Des(k) => Key::TwoKey3Des([
+                k[0], k[1], k[2], k[3], k[4], k[5], k[6], k[7], k[0], k[1], k[2], k[3], k[4], k[5],
+                k[6], k[7],
+            ]),
+            other => other,
+        };
+        Self { key, version }
+    }
+
+    /// All-zero key of the given family, as found on a new application.
+    pub(super) fn zero(key_type: ApplicationKeyType) -> Self {
+        match key_type {
+            ApplicationKeyType::ThreeKey3Des => Self::new(Key::ThreeKey3Des([0; 24]), 0),
+            ApplicationKeyType::Aes => Self::new(Key::Aes128([0; 16]), 0),
+            ApplicationKeyType::TwoKey3Des | ApplicationKeyType::Rfu => {
+                Self::new(Key::TwoKey3Des([0; 16]), 0)
+            }
+        }
+    }
+
+    /// Slot filled from the `SetConfiguration` default key bytes.
+    pub(super) fn from_default(key_type: ApplicationKeyType, default_key: &[u8; 25]) -> Self {
+        let key = match key_type {
+            ApplicationKeyType::ThreeKey3Des => {
+                Key::ThreeKey3Des(default_key[..24].try_into().expect("valid slice"))
+            }
+            ApplicationKeyType::Aes => {
+                Key::Aes128(default_key[..16].try_into().expect("valid slice"))
+            }
+            ApplicationKeyType::TwoKey3Des | ApplicationKeyType::Rfu => {
+                Key::TwoKey3Des(default_key[..16].try_into().expect("valid slice"))
+            }
+        };
+        Self::new(key, default_key[24])
+    }
+
+    pub(super) const fn key(&self) -> &Key {
+        &self.key
+    }
+
+    pub(super) const fn key_type(&self) -> ApplicationKeyType {
+        match self.key {
+            Key::Des(_) | Key::TwoKey3Des(_) => ApplicationKeyType::TwoKey3Des,
+            Key::ThreeKey3Des(_) => ApplicationKeyType::ThreeKey3Des,
+            Key::Aes128(_) => ApplicationKeyType::Aes,
+        }
+    }
+
+    /// Raw key bytes as used by `ChangeKey`.
+    pub(super) fn bytes(&self) -> &[u8] {
+        match &self.key {
+            Key::Des(k) => k,
+            Key::TwoKey3Des(k) | Key::Aes128(k) => k,
+            Key::ThreeKey3Des(k) => k,
+        }
+    }
+
+    /// Key version as returned by `GetKeyVersion`.
+    pub(super) fn version(self) -> u8 {
+        match self.key {
+            Key::Aes128(_) => self.version,
+            _ => self
+                .bytes()
+                .iter()
+                .take(8)
+                .fold(0, |version, byte| (version << 1) | (byte & 0x01)),
+        }
+    }
+}
+
+/// One application of a [`VirtualDesfire`](super::VirtualDesfire) card.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualApplication {
+    id: ApplicationId,
+    pub(super) key_settings: u8,
+    key_type: ApplicationKeyType,
+    pub(super) keys: Vec<KeySlot>,
+    pub(super) iso_file_id: Option<[u8; 2]>,
+    pub(super) df_name: Vec<u8>,
+    pub(super) files: BTreeMap<u8, VirtualFile>,
+}
+
+impl VirtualApplication {
+    /// Creates an application without files whose keys are all zero.
+    ///
+    /// Fails for the `Rfu` key type and for key counts outside `1..=14`.
+    pub fn new(id: ApplicationId, key_settings: KeySettings) -> Result<Self, Error> {
+        let key_type = key_settings.key_type();
+        if key_type == ApplicationKeyType::Rfu {
+            return Err(Error::UnsupportedAlgorithm);
+        }
+        let key_count = key_settings.key_count();
+        if !(1..=MAX_KEYS).contains(&key_count) {
+            return Err(Error::InvalidKeyNumber(key_count));
+        }
+
+        Ok(Self {
+            id,
+            key_settings: key_settings.raw_settings(),
+            key_type,
+            keys: std::vec![KeySlot::zero(key_type); usize::from(key_count)],
+            iso_file_id: None,
+            df_name: Vec::new(),
+            files: BTreeMap::new(),
+        })
+    }
+
+    /// Replaces one key.
+    ///
+    /// `version` is kept for AES keys; DES-family keys carry their version in
+    /// the parity bits. Single DES keys are accepted for 2TDEA applications.
+    pub fn with_key(mut self, key_number: KeyNumber, key: Key, version: u8) -> Result<Self, Error> {
+        let slot = KeySlot::new(key, version);
+        if slot.key_type() != self.key_type {
+            return Err(Error::UnsupportedAlgorithm);
+        }
+        *self
+            .keys
+            .get_mut(usize::from(key_number.as_byte()))
+            .ok_or(Error::InvalidKeyNumber(key_number.as_byte()))? = slot;
+        Ok(self)
+    }
+
+    /// Adds a file, replacing any file with the same id.
+    #[must_use]
+    pub fn with_file(mut self, file_id: FileId, file: VirtualFile) -> Self {
+        self.files.insert(file_id.as_byte(), file);
+        self
+    }
+
+    /// Application id.
+    pub const fn id(&self) -> ApplicationId {
+        self.id
+    }
+
+    /// Key settings as returned by `GetKeySettings`.
+    pub fn key_settings(&self) -> KeySettings {
+        KeySettings::new(
+            self.key_settings,
+            self.key_type,
+            u8::try_from(self.keys.len()).expect("at most 14 keys"),
+        )
+    }
+
+    /// Current value of one key.
+    pub fn key(&self, key_number: KeyNumber) -> Option<&Key> {
+        self.keys
+            .get(usize::from(key_number.as_byte()))
+            .map(KeySlot::key)
+    }
+
+    /// Version of one key, as returned by `GetKeyVersion`.
+    pub fn key_version(&self, key_number: KeyNumber) -> Option<u8> {
+        self.slot(key_number.as_byte()).map(KeySlot::version)
+    }
+
+    /// One file of the application.
+    pub fn file(&self, file_id: FileId) -> Option<&VirtualFile> {
+        self.files.get(&file_id.as_byte())
+    }
+
+    pub(super) const fn key_type(&self) -> ApplicationKeyType {
+        self.key_type
+    }
+
+    pub(super) fn slot(&self, key_number: u8) -> Option<KeySlot> {
+        self.keys.get(usize::from(key_number)).cloned()
+    }
+
+    pub(super) fn commit(&mut self) {
+        self.files.values_mut().for_each(VirtualFile::commit);
+    }
+
+    pub(super) fn abort(&mut self) {
+        self.files.values_mut().for_each(VirtualFile::abort);
+    }
+}
@@ -0,0 +1,435 @@
+//! Card side of EV1 authentication, secure messaging and `ChangeKey`.
+//!
+//! The card keeps its own [`AuthenticatedSession`] and makes the same CMAC and
+//! CBC calls as the client, in the same order, so both chaining states stay in
+//! step exactly as they do with a real card.
+
+use std::vec::Vec;
+
+use crate::mifare::desfire::{
+    command::CommandCode,
+    crypto::{
+        aes_cbc_decrypt_in_place, aes_cbc_encrypt_in_place, desfire_crc16, desfire_crc32,
+        tdes2_cbc_decrypt_in_place, tdes2_cbc_encrypt_in_place, tdes3_cbc_decrypt_in_place,
+        tdes3_cbc_encrypt_in_place, AesSessionKey, DesSessionKey, RndA, RndA8, RndB, RndB8,
+        ThreeKey3DesSessionKey, TwoKey3DesSessionKey,
+    },
+    key::{ApplicationKeyType, Key, KeyNumber},
+    session::AuthenticatedSession,
+    sim::{application::KeySlot, Reply, VirtualDesfire},
+    status::Status,
+};
+
+/// Authentication waiting for the PCD's challenge response.
+#[derive(Debug, Clone)]
+pub(super) struct PendingAuth {
+    code: CommandCode,
+    key_number: KeyNumber,
+    key: Key,
+    rnd_b: [u8; 16],
+    encrypted_rnd_b: [u8; 16],
+}
+
+impl VirtualDesfire {
+    /// First authentication step: checks the key and sends the enciphered `RndB`.
+    pub(super) fn start_authentication(
+        &mut self,
+        code: CommandCode,
+        data: &[u8],
+    ) -> Result<Reply, Status> {
+        self.session = None;
+        let &[key_number] = data else {
+            return Err(Status::LengthError);
+        };
+        let key = self.key_slot(key_number)?.key().clone();
+        let key_number = KeyNumber::new(key_number).map_err(|_| Status::NoSuchKey)?;
+
+        let rnd_b: [u8; 16] = self.random_bytes();
+        let mut encrypted_rnd_b = rnd_b;
+        let challenge_len = match (code, &key) {
+            (CommandCode::AUTHENTICATE_AES, Key::Aes128(k)) => {
+                aes_cbc_encrypt_in_place(k, &[0; 16], &mut encrypted_rnd_b);
+                16
+            }
+            (CommandCode::AUTHENTICATE_ISO, Key::ThreeKey3Des(k)) => {
+                tdes3_cbc_encrypt_in_place(k, &[0; 8], &mut encrypted_rnd_b);
+                16
+            }
+            (
+                CommandCode::AUTHENTICATE_ISO | CommandCode::AUTHENTICATE_LEGACY,
+                Key::TwoKey3Des(k),
+            ) => {
+                tdes2_cbc_encrypt_in_place(k, &[0; 8], &mut encrypted_rnd_b[..8]);
+                8
+            }
+            _ => return Err(Status::AuthenticationError),
+        };
+
+        self.pending_auth = Some(PendingAuth {
+            code,
+            key_number,
+            key,
+            rnd_b,
+            encrypted_rnd_b,
+        });
+        Ok(Reply::Unprotected(
+            Status::AdditionalFrame,
+            encrypted_rnd_b[..challenge_len].to_vec(),
+        ))
+    }
+
+    /// Second authentication step: checks `RndB'`, answers with `RndA'` and opens the session.
+    pub(super) fn finish_authentication(
+        &mut self,
+        pending: &PendingAuth,
+        data: &[u8],
+    ) -> Result<Reply, Status> {
+        let (session, response) = match &pending.key {
+            Key::Aes128(k) => {
+                let mut challenge: [u8; 32] = data.try_into().map_err(|_| Status::LengthError)?;
+                aes_cbc_decrypt_in_place(k, &pending.encrypted_rnd_b, &mut challenge);
+                let rnd_a = RndA::new(challenge[..16].try_into().expect("valid slice"));
+                let rnd_b = RndB::new(pending.rnd_b);
+                if challenge[16..] != rnd_b.rotate_left() {
+                    return Err(Status::AuthenticationError);
+                }
+
+                let mut response = rnd_a.rotate_left();
+                let iv: [u8; 16] = data[16..].try_into().expect("valid slice");
+                aes_cbc_encrypt_in_place(k, &iv, &mut response);
+                let session = AuthenticatedSession::new_aes(
+                    pending.key_number,
+                    AesSessionKey::derive(rnd_a, rnd_b),
+                );
+                (session, response.to_vec())
+            }
+            Key::ThreeKey3Des(k) => {
+                let mut challenge: [u8; 32] = data.try_into().map_err(|_| Status::LengthError)?;
+                let iv: [u8; 8] = pending.encrypted_rnd_b[8..]
+                    .try_into()
+                    .expect("valid slice");
+                tdes3_cbc_decrypt_in_place(k, &iv, &mut challenge);
+                let rnd_a = RndA::new(challenge[..16].try_into().expect("valid slice"));
+                let rnd_b = RndB::new(pending.rnd_b);
+                if challenge[16..] != rnd_b.rotate_left() {
+                    return Err(Status::AuthenticationError);
+                }
+
+                let mut response = rnd_a.rotate_left();
+                let iv: [u8; 8] = data[24..].try_into().expect("valid slice");
+                tdes3_cbc_encrypt_in_place(k, &iv, &mut response);
+                let session = AuthenticatedSession::new_3tdea(
+                    pending.key_number,
+                    ThreeKey3DesSessionKey::derive(rnd_a, rnd_b),
+                );
+                (session, response.to_vec())
+            }
+            Key::Des(_) | Key::TwoKey3Des(_) => {
+                let slot = KeySlot::new(pending.key.clone(), 0);
+                let Key::TwoKey3Des(k) = slot.key() else {
+                    unreachable!("DES keys are stored as 2TDEA keys");
+                };
+                let mut challenge: [u8; 16] = data.try_into().map_err(|_| Status::LengthError)?;
+                let iv: [u8; 8] = pending.encrypted_rnd_b[..8]
+                    .try_into()
+                    .expect("valid slice");
+                tdes2_cbc_decrypt_in_place(k, &iv, &mut challenge);
+                let rnd_a = RndA8::new(challenge[..8].try_into().expect("valid slice"));
+                let rnd_b = RndB8::new(pending.rnd_b[..8].try_into().expect("valid slice"));
+                if challenge[8..] != rnd_b.rotate_left() {
+                    return Err(Status::AuthenticationError);
+                }
+
+                let mut response = rnd_a.rotate_left();
+                let iv: [u8; 8] = data[8..].try_into().expect("valid slice");
+                tdes2_cbc_encrypt_in_place(k, &iv, &mut response);
+                (
+                    des_family_session(pending, k, rnd_a, rnd_b),
+                    response.to_vec(),
+                )
+            }
+        };
+
+        self.session = Some(session);
+        Ok(Reply::Unprotected(Status::OperationOk, response))
+    }
+
+    /// `ChangeKey`: deciphers the new key, checks both CRCs and the change permission.
+    pub(super) fn change_key(&mut self, data: &[u8]) -> Result<Reply, Status> {
+        let Some((&key_byte, ciphertext)) = data.split_first() else {
+            return Err(Status::LengthError);
+        };
+        let authenticated = self
+            .session
+            .as_ref()
+            .ok_or(Status::AuthenticationError)?
+            .key_number()
+            .as_byte();
+
+        let at_picc = self.selected_application().is_none();
+        let (key_number, key_type) = if at_picc {
+            let key_type = match key_byte & 0xC0 {
+                0x00 => ApplicationKeyType::TwoKey3Des,
+                0x40 => ApplicationKeyType::ThreeKey3Des,
+                0x80 => ApplicationKeyType::Aes,
+                _ => return Err(Status::ParameterError),
+            };
+            (key_byte & 0x3F, key_type)
+        } else {
+            (key_byte, self.current_application()?.key_type())
+        };
+        let old_key = self.key_slot(key_number)?;
+        self.check_change_key_permission(key_number, authenticated)?;
+
+        let key_len = match key_type {
+            ApplicationKeyType::ThreeKey3Des => 24,
+            _ => 16,
+        };
+        let aes = key_type == ApplicationKeyType::Aes;
+        let field_len = key_len + usize::from(aes);
+        let same_key = key_number == authenticated;
+        let crc_size = if aes {
+            4
+        } else {
+            self.session
+                .as_ref()
+                .expect("session checked above")
+                .encrypted_command_crc_size()
+        };
+
+        let plaintext = self.decipher(ciphertext)?;
+        let crc_end = field_len + crc_size * if same_key { 1 } else { 2 };
+        let block_size = self.session.as_ref().expect("session checked").block_size();
+        if plaintext.len() != crc_end.next_multiple_of(block_size) {
+            return Err(Status::LengthError);
+        }
+        if plaintext[crc_end..].iter().any(|&byte| byte != 0) {
+            return Err(Status::IntegrityError);
+        }
+
+        let field = &plaintext[..field_len];
+        let mut crc_input = std::vec![CommandCode::CHANGE_KEY.as_byte(), key_byte];
+        crc_input.extend_from_slice(field);
+        if crc(&crc_input, crc_size) != plaintext[field_len..field_len + crc_size] {
+            return Err(Status::IntegrityError);
+        }
+
+        let mut new_key = field[..key_len].to_vec();
+        if !same_key {
+            for (byte, old) in new_key.iter_mut().zip(old_key.bytes()) {
+                *byte ^= old;
+            }
+            if crc(&new_key, crc_size) != plaintext[field_len + crc_size..crc_end] {
+                return Err(Status::IntegrityError);
+            }
+        }
+
+        let key = match key_type {
+            ApplicationKeyType::ThreeKey3Des => {
+                Key::ThreeKey3Des(new_key.try_into().expect("key length checked"))
+            }
+            ApplicationKeyType::Aes => Key::Aes128(new_key.try_into().expect("key length checked")),
+            _ => Key::TwoKey3Des(new_key.try_into().expect("key length checked")),
+        };
+        let version = if aes { field[key_len] } else { 0 };
+        *self.key_slot_mut(key_number)? = KeySlot::new(key, version);
+
+        if same_key {
+            self.session = None;
+            Ok(Reply::Unprotected(Status::OperationOk, Vec::new()))
+        } else {
+            Ok(Reply::Data(Vec::new()))
+        }
+    }
+
+    /// Checks the `ChangeKey` access rule of the key settings.
+    fn check_change_key_permission(&self, key_number: u8, authenticated: u8) -> Result<(), Status> {
+        let settings = self.key_settings();
+        let allowed = if key_number == 0 {
+            authenticated == 0 && settings & 0x01 != 0
+        } else {
+            match settings >> 4 {
+                0x0E => authenticated == key_number,
+                0x0F => false,
+                change_key => authenticated == change_key,
+            }
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(Status::PermissionDenied)
+        }
+    }
+
+    /// Key slot at the selected level.
+    pub(super) fn key_slot(&self, key_number: u8) -> Result<KeySlot, Status> {
+        match self.selected_application() {
+            None if key_number == 0 => Ok(self.picc_key.clone()),
+            None => Err(Status::NoSuchKey),
+            Some(app) => app.slot(key_number).ok_or(Status::NoSuchKey),
+        }
+    }
+
+    fn key_slot_mut(&mut self, key_number: u8) -> Result<&mut KeySlot, Status> {
+        if self.selected_application().is_none() {
+            return Ok(&mut self.picc_key);
+        }
+        self.current_application_mut()?
+            .keys
+            .get_mut(usize::from(key_number))
+            .ok_or(Status::NoSuchKey)
+    }
+
+    /// Advances the command CMAC for a command sent without a MAC of its own.
+    pub(super) fn update_command_mac(
+        &mut self,
+        code: CommandCode,
+        data: &[u8],
+    ) -> Result<(), Status> {
+        if let Some(session) = &mut self.session {
+            session
+                .update_command_cmac(code, data)
+                .map_err(|_| Status::LengthError)?;
+        }
+        Ok(())
+    }
+
+    /// Checks and strips the trailing MAC of a `MACed` command.
+    pub(super) fn verify_command_mac<'a>(
+        &mut self,
+        code: CommandCode,
+        data: &'a [u8],
+    ) -> Result<&'a [u8], Status> {
+        let session = self.session.as_mut().ok_or(Status::AuthenticationError)?;
+        let payload_len = data
+            .len()
+            .checked_sub(session.mac_len())
+            .ok_or(Status::LengthError)?;
+        let (payload, mac) = data.split_at(payload_len);
+        let expected = session
+            .update_command_cmac(code, payload)
+            .map_err(|_| Status::LengthError)?;
+        if expected.as_bytes()[..] != *mac {
+            return Err(Status::IntegrityError);
+        }
+        Ok(payload)
+    }
+
+    /// Response MAC for a plain response, or `None` without a session.
+    pub(super) fn response_mac(&mut self, data: &[u8]) -> Result<Option<[u8; 8]>, Status> {
+        let Some(session) = &mut self.session else {
+            return Ok(None);
+        };
+        let mac = session
+            .update_response_cmac(Status::OperationOk, data)
+            .map_err(|_| Status::LengthError)?;
+        Ok(Some(mac.as_bytes()))
+    }
+
+    /// Deciphers command data with the session chaining state.
+    fn decipher(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Status> {
+        let session = self.session.as_mut().ok_or(Status::AuthenticationError)?;
+        if ciphertext.is_empty() || !ciphertext.len().is_multiple_of(session.block_size()) {
+            return Err(Status::LengthError);
+        }
+        let mut plaintext = ciphertext.to_vec();
+        session
+            .cbc_decrypt_in_place(&mut plaintext)
+            .map_err(|_| Status::IntegrityError)?;
+        Ok(plaintext)
+    }
+
+    /// Deciphers `data || CRC(code || header || data) [|| 0x80] || padding` and returns `data`.
+    ///
+    /// `data_len` reads the data length from the deciphered bytes, for commands
+    /// such as `SetConfiguration` whose length is only known after deciphering.
+    pub(super) fn decipher_command(
+        &mut self,
+        code: CommandCode,
+        header: &[u8],
+        ciphertext: &[u8],
+        terminator: bool,
+        data_len: impl FnOnce(&[u8]) -> usize,
+    ) -> Result<Vec<u8>, Status> {
+        let mut plaintext = self.decipher(ciphertext)?;
+        let session = self
+            .session
+            .as_ref()
+            .expect("deciphering requires a session");
+        let data_len = data_len(&plaintext);
+        let crc_end = data_len + session.encrypted_command_crc_size();
+        let padding_start = crc_end + usize::from(terminator);
+        if plaintext.len() != padding_start.next_multiple_of(session.block_size()) {
+            return Err(Status::LengthError);
+        }
+
+        let mut crc_input = std::vec![code.as_byte()];
+        crc_input.extend_from_slice(header);
+        crc_input.extend_from_slice(&plaintext[..data_len]);
+        if crc(&crc_input, session.encrypted_command_crc_size()) != plaintext[data_len..crc_end]
+            || (terminator && plaintext[crc_end] != 0x80)
+            || plaintext[padding_start..].iter().any(|&byte| byte != 0)
+        {
+            return Err(Status::IntegrityError);
+        }
+
+        plaintext.truncate(data_len);
+        Ok(plaintext)
+    }
+
+    /// Enciphers `data || CRC(data || status) || padding` for an enciphered response.
+    pub(super) fn encipher_response(
+        &mut self,
+        mut data: Vec<u8>,
+        implicit_length: bool,
+    ) -> Result<Vec<u8>, Status> {
+        let session = self.session.as_mut().ok_or(Status::AuthenticationError)?;
+        let mut crc_input = data.clone();
+        crc_input.push(Status::OperationOk.as_byte());
+        data.extend_from_slice(&crc(&crc_input, session.encrypted_read_crc_size()));
+        if implicit_length {
+            data.push(0x80);
+        }
+        data.resize(data.len().next_multiple_of(session.block_size()), 0x00);
+        session
+            .cbc_encrypt_in_place(&mut data)
+            .map_err(|_| Status::LengthError)?;
+        Ok(data)
+    }
+}
+
+/// Session opened by a 2TDEA key, following the client's choice of session type.
+fn des_family_session(
+    pending: &PendingAuth,
+    key: &[u8; 16],
+    rnd_a: RndA8,
+    rnd_b: RndB8,
+) -> AuthenticatedSession {
+    let single_des = key[..8] == key[8..];
+    if pending.code == CommandCode::AUTHENTICATE_LEGACY && single_des {
+        return AuthenticatedSession::new_des(
+            pending.key_number,
+            DesSessionKey::derive(rnd_a, rnd_b),
+        );
+    }
+
+    let session_key = TwoKey3DesSessionKey::derive(rnd_a, rnd_b);
+    if single_des {
+        let secure_messaging_key = session_key.ev1_des_working_key();
+        AuthenticatedSession::new_2tdea_with_secure_messaging_key(
+            pending.key_number,
+            session_key,
+            secure_messaging_key,
+        )
+    } else {
+        AuthenticatedSession::new_2tdea(pending.key_number, session_key)
+    }
+}
+
+fn crc(data: &[u8], crc_size: usize) -> Vec<u8> {
+    if crc_size == 2 {
+        desfire_crc16(data).to_vec()
+    } else {
+        desfire_crc32(data).to_vec()
+    }
+}
@@ -0,0 +1,682 @@
+//! Command handlers of the virtual card.
+
+use std::vec::Vec;
+
+use crate::mifare::desfire::{
+    application::ApplicationId,
+    command::CommandCode,
+    configuration::PiccConfiguration,
+    file::{AccessCondition, AccessRights, CommunicationMode, FileAccess},
+    key::{ApplicationKeyType, KeySettings},
+    sim::{
+        application::KeySlot, Reply, VirtualApplication, VirtualDesfire, VirtualFile, BATCH_NUMBER,
+        HARDWARE_VERSION, MEMORY_SIZE, PRODUCTION_WEEK, PRODUCTION_YEAR, SOFTWARE_VERSION,
+    },
+    status::Status,
+    types::U24,
+};
+
+/// Most applications an EV1 card holds.
+const MAX_APPLICATIONS: usize = 28;
+
+/// Highest file number of an EV1 application.
+const MAX_FILE_ID: u8 = 0x1F;
+
+/// Card memory is allocated in blocks of this many bytes.
+const MEMORY_BLOCK_SIZE: u32 = 32;
+
+/// `SetConfiguration` options.
+const CONFIGURATION_PICC: u8 = 0x00;
+const CONFIGURATION_DEFAULT_KEY: u8 = 0x01;
+const CONFIGURATION_ATS: u8 = 0x02;
+
+impl VirtualDesfire {
+    /// Runs one complete command.
+    pub(super) fn execute(&mut self, code: CommandCode, data: &[u8]) -> Result<Reply, Status> {
+        // These commands bring their own secure messaging.
+        match code {
+            CommandCode::AUTHENTICATE_AES
+            | CommandCode::AUTHENTICATE_ISO
+            | CommandCode::AUTHENTICATE_LEGACY => return self.start_authentication(code, data),
+            CommandCode::CHANGE_KEY => return self.change_key(data),
+            CommandCode::SET_CONFIGURATION => return self.set_configuration(data),
+            CommandCode::CHANGE_FILE_SETTINGS => return self.change_file_settings(data),
+            CommandCode::WRITE_DATA
+            | CommandCode::WRITE_RECORD
+            | CommandCode::CREDIT
+            | CommandCode::DEBIT
+            | CommandCode::LIMITED_CREDIT => return self.write_file(code, data),
+            _ => {}
+        }
+
+        self.update_command_mac(code, data)?;
+        match code {
+            CommandCode::SELECT_APPLICATION => self.select_application(data),
+            CommandCode::GET_VERSION => self.get_version(data),
+            CommandCode::GET_CARD_UID => self.get_card_uid(data),
+            CommandCode::GET_KEY_SETTINGS => self.get_key_settings(data),
+            CommandCode::GET_KEY_VERSION => self.get_key_version(data),
+            CommandCode::FREE_MEM => {
+                expect_len(data, 0)?;
+                Ok(Reply::Data(u24_bytes(self.free_memory).to_vec()))
+            }
+            CommandCode::FORMAT_PICC => self.format_picc(data),
+            CommandCode::GET_APPLICATION_IDS => self.get_application_ids(data),
+            CommandCode::GET_DF_NAMES => self.get_df_names(data),
+            CommandCode::CREATE_APPLICATION => self.create_application(data),
+            CommandCode::DELETE_APPLICATION => self.delete_application(data),
+            CommandCode::COMMIT_TRANSACTION | CommandCode::ABORT_TRANSACTION => {
+                expect_len(data, 0)?;
+                if let Some(app) = self.selected_application_mut() {
+                    if code == CommandCode::COMMIT_TRANSACTION {
+                        app.commit();
+                    } else {
+                        app.abort();
+                    }
+                }
+                Ok(Reply::Data(Vec::new()))
+            }
+            CommandCode::GET_FILE_IDS | CommandCode::GET_ISO_FILE_IDS => {
+                self.get_file_ids(code, data)
+            }
+            CommandCode::GET_FILE_SETTINGS => self.get_file_settings(data),
+            CommandCode::CREATE_STD_DATA_FILE
+            | CommandCode::CREATE_BACKUP_DATA_FILE
+            | CommandCode::CREATE_VALUE_FILE
+            | CommandCode::CREATE_LINEAR_RECORD_FILE
+            | CommandCode::CREATE_CYCLIC_RECORD_FILE => self.create_file(code, data),
+            CommandCode::DELETE_FILE => self.delete_file(data),
+            CommandCode::READ_DATA | CommandCode::READ_RECORDS | CommandCode::GET_VALUE => {
+                self.read_file(code, data)
+            }
+            CommandCode::CLEAR_RECORD_FILE => {
+                let &[file_id] = data else {
+                    return Err(Status::LengthError);
+                };
+                self.file_access(file_id, FileAccess::ReadWrite)?;
+                self.file_mut(file_id)?.clear_records()?;
+                Ok(Reply::Data(Vec::new()))
+            }
+            _ => Err(Status::IllegalCommandCode),
+        }
+    }
+
+    /// Length of a `WriteData` or `WriteRecord` command whose data continues in
+    /// `AdditionalFrame` frames, or zero for commands sent in one frame.
+    pub(super) fn expected_command_len(&self, code: CommandCode, data: &[u8]) -> usize {
+        if !matches!(code, CommandCode::WRITE_DATA | CommandCode::WRITE_RECORD) || data.len() < 7 {
+            return 0;
+        }
+        let length = u24_at(data, 4);
+        match self.file_access(data[0], FileAccess::Write) {
+            Ok(CommunicationMode::Plain) => 7 + length,
+            Ok(CommunicationMode::Maced) => 7 + length + 8,
+            Ok(CommunicationMode::Enciphered) => {
+                let session = self
+                    .session
+                    .as_ref()
+                    .expect("keyed access requires a session");
+                7 + (length + session.encrypted_command_crc_size())
+                    .next_multiple_of(session.block_size())
+            }
+            Err(_) => 0,
+        }
+    }
+
+    fn select_application(&mut self, data: &[u8]) -> Result<Reply, Status> {
+        let aid = ApplicationId::from_bytes(data.try_into().map_err(|_| Status::LengthError)?);
+        self.abort_transaction();
+        self.session = None;
+        if aid != ApplicationId::PICC && self.application_index(aid).is_none() {
+            self.selected = ApplicationId::PICC;
+            return Err(Status::ApplicationNotFound);
+        }
+        self.selected = aid;
+        Ok(Reply::Unprotected(Status::OperationOk, Vec::new()))
+    }
+
+    fn get_version(&self, data: &[u8]) -> Result<Reply, Status> {
+        expect_len(data, 0)?;
+        let uid = if self.configuration.random_uid() {
+            [0; 7]
+        } else {
+            self.uid
+        };
+        let mut production = uid.to_vec();
+        production.extend_from_slice(&BATCH_NUMBER);
+        production.extend_from_slice(&[PRODUCTION_WEEK, PRODUCTION_YEAR]);
+        Ok(Reply::Frames(std::vec![
+            HARDWARE_VERSION.to_vec(),
+            SOFTWARE_VERSION.to_vec(),
+            production,
+        ]))
+    }
+
+    fn get_card_uid(&self, data: &[u8]) -> Result<Reply, Status> {
+        expect_len(data, 0)?;
+        if self.session.is_none() {
+            return Err(Status::AuthenticationError);
+        }
+        Ok(Reply::Enciphered {
+            data: self.uid.to_vec(),
+            implicit_length: false,
+        })
+    }
+
+    fn get_key_settings(&self, data: &[u8]) -> Result<Reply, Status> {
+        expect_len(data, 0)?;
+        self.require_master_key(self.key_settings() & 0x02 != 0)?;
+        let key_settings = match self.selected_application() {
+            Some(app) => app.key_settings(),
+            None => KeySettings::new(self.picc_key_settings, self.picc_key.key_type(), 1),
+        };
+        Ok(Reply::Data(std::vec![
+            key_settings.raw_settings(),
+            key_settings.raw_key_count(),
+        ]))
+    }
+
+    fn get_key_version(&self, data: &[u8]) -> Result<Reply, Status> {
+        let &[key_number] = data else {
+            return Err(Status::LengthError);
+        };
+        let key_number = if self.selected_application().is_none() {
+            key_number & 0x3F
+        } else {
+            key_number
+        };
+        Ok(Reply::Data(std::vec![self.key_slot(key_number)?.version()]))
+    }
+
+    fn format_picc(&mut self, data: &[u8]) -> Result<Reply, Status> {
+        expect_len(data, 0)?;
+        self.require_picc_level()?;
+        self.require_master_key(false)?;
+        if self.configuration.format_disabled() {
+            return Err(Status::PermissionDenied);
+        }
+        self.applications.clear();
+        self.free_memory = MEMORY_SIZE;
+        Ok(Reply::Data(Vec::new()))
+    }
+
+    fn get_application_ids(&self, data: &[u8]) -> Result<Reply, Status> {
+        expect_len(data, 0)?;
+        self.require_picc_level()?;
+        self.require_master_key(self.picc_key_settings & 0x02 != 0)?;
+        Ok(Reply::Data(
+            self.applications
+                .iter()
+                .flat_map(|app| app.id().as_bytes())
+                .collect(),
+        ))
+    }
+
+    fn get_df_names(&self, data: &[u8]) -> Result<Reply, Status> {
+        expect_len(data, 0)?;
+        self.require_picc_level()?;
+        self.require_master_key(self.picc_key_settings & 0x02 != 0)?;
+        let mut entries: Vec<Vec<u8>> = self
+            .applications
+            .iter()
+            .filter_map(|app| {
+                let iso_file_id = app.iso_file_id?;
+                let mut entry = app.id().as_bytes().to_vec();
+                entry.extend_from_slice(&iso_file_id);
+                entry.extend_from_slice(&app.df_name);
+                Some(entry)
+            })
+            .collect();
+        if entries.is_empty() {
+            entries.push(Vec::new());
+        }
+        Ok(Reply::Frames(entries))
+    }
+
+    fn create_application(&mut self, data: &[u8]) -> Result<Reply, Status> {
+        self.require_picc_level()?;
+        self.require_master_key(self.picc_key_settings & 0x04 != 0)?;
+        if data.len() < 5 {
+            return Err(Status::LengthError);
+        }
+        let aid = ApplicationId::from_bytes([data[0], data[1], data[2]]);
+        let (settings, key_info) = (data[3], data[4]);
+        let (iso_file_id, df_name) = match (key_info & 0x30, &data[5..]) {
+            (0x00, []) => (None, &[][..]),
+            (0x20, [lo, hi, df_name @ ..]) if df_name.len() <= 16 => (Some([*lo, *hi]), df_name),
+            // EV2 key sets are not supported by an EV1 card.
+            (0x10 | 0x30, _) => return Err(Status::ParameterError),
+            _ => return Err(Status::LengthError),
+        };
+        let key_type = match key_info >> 6 {
+            0b00 => ApplicationKeyType::TwoKey3Des,
+            0b01 => ApplicationKeyType::ThreeKey3Des,
+            0b10 => ApplicationKeyType::Aes,
+            _ => return Err(Status::ParameterError),
+        };
+        if aid == ApplicationId::PICC {
+            return Err(Status::ParameterError);
+        }
+        let mut app = VirtualApplication::new(aid, KeySettings::new(settings, key_type, key_info))
+            .map_err(|_| Status::ParameterError)?;
+
+        let duplicate = self.applications.iter().any(|other| {
+            other.id() == aid
+                || (iso_file_id.is_some() && other.iso_file_id == iso_file_id)
+                || (!df_name.is_empty() && other.df_name == df_name)
+        });
+        if duplicate {
+            return Err(Status::DuplicateError);
+        }
+        if self.applications.len() >= MAX_APPLICATIONS {
+            return Err(Status::CountError);
+        }
+
+        let default_key = KeySlot::from_default(key_type, &self.default_key);
+        app.keys.fill(default_key);
+        app.iso_file_id = iso_file_id;
+        app.df_name = df_name.to_vec();
+        self.applications.push(app);
+        Ok(Reply::Data(Vec::new()))
+    }
+
+    fn delete_application(&mut self, data: &[u8]) -> Result<Reply, Status> {
+        let aid = ApplicationId::from_bytes(data.try_into().map_err(|_| Status::LengthError)?);
+        let index = self
+            .application_index(aid)
+            .ok_or(Status::ApplicationNotFound)?;
+        // The PICC master key or the master key of the application itself.
+        if self.selected != ApplicationId::PICC && self.selected != aid {
+            return Err(Status::PermissionDenied);
+        }
+        self.require_master_key(false)?;
+
+        self.applications.remove(index);
+        self.selected = ApplicationId::PICC;
+        Ok(Reply::Data(Vec::new()))
+    }
+
+    fn set_configuration(&mut self, data: &[u8]) -> Result<Reply, Status> {
+        self.require_picc_level()?;
+        self.require_master_key(false)?;
+        let Some((&option, ciphertext)) = data.split_first() else {
+            return Err(Status::LengthError);
+        };
+        let code = CommandCode::SET_CONFIGURATION;
+        match option {
+            CONFIGURATION_PICC => {
+                let settings = self.decipher_command(code, &[option], ciphertext, false, |_| 1)?;
+                let requested = PiccConfiguration::new()
+                    .with_format_disabled(settings[0] & 0x01 != 0)
+                    .with_random_uid(settings[0] & 0x02 != 0);
+                // Neither option can be switched off again.
+                self.configuration = PiccConfiguration::new()
+                    .with_format_disabled(
+                        self.configuration.format_disabled() || requested.format_disabled(),
+                    )
+                    .with_random_uid(self.configuration.random_uid() || requested.random_uid());
+            }
+            CONFIGURATION_DEFAULT_KEY => {
+                let key = self.decipher_command(code, &[option], ciphertext, false, |_| 25)?;
+                self.default_key = key.try_into().expect("length checked");
+            }
+            CONFIGURATION_ATS => {
+                let ats = self.decipher_command(code, &[option], ciphertext, true, |plain| {
+                    usize::from(plain[0])
+                })?;
+                if ats.is_empty() || ats.len() > 20 {
+                    return Err(Status::ParameterError);
+                }
+                self.ats = ats;
+            }
+            _ => return Err(Status::ParameterError),
+        }
+        Ok(Reply::Data(Vec::new()))
+    }
+
+    fn get_file_ids(&self, code: CommandCode, data: &[u8]) -> Result<Reply, Status> {
+        expect_len(data, 0)?;
+        let app = self.current_application()?;
+        self.require_master_key(app.key_settings & 0x02 != 0)?;
+        let ids = if code == CommandCode::GET_FILE_IDS {
+            app.files.keys().copied().collect()
+        } else {
+            app.files
+                .values()
+                .filter_map(|file| file.iso_file_id)
+                .flatten()
+                .collect()
+        };
+        Ok(Reply::Data(ids))
+    }
+
+    fn get_file_settings(&self, data: &[u8]) -> Result<Reply, Status> {
+        let &[file_id] = data else {
+            return Err(Status::LengthError);
+        };
+        self.require_master_key(self.current_application()?.key_settings & 0x02 != 0)?;
+        Ok(Reply::Data(self.file(file_id)?.settings_bytes()))
+    }
+
+    fn change_file_settings(&mut self, data: &[u8]) -> Result<Reply, Status> {
+        let Some((&file_id, settings)) = data.split_first() else {
+            return Err(Status::LengthError);
+        };
+        let code = CommandCode::CHANGE_FILE_SETTINGS;
+        let settings = match self.file(file_id)?.access_rights().change() {
+            AccessCondition::Never => return Err(Status::PermissionDenied),
+            AccessCondition::Free => {
+                self.update_command_mac(code, data)?;
+                settings.to_vec()
+            }
+            AccessCondition::Key(key_number) => {
+                match &self.session {
+                    None => return Err(Status::AuthenticationError),
+                    Some(session) if session.key_number() != key_number => {
+                        return Err(Status::PermissionDenied)
+                    }
+                    Some(_) => {}
+                }
+                self.decipher_command(code, &[file_id], settings, false, |_| 3)?
+            }
+        };
+        let &[communication_mode, rights_0, rights_1] = settings.as_slice() else {
+            return Err(Status::LengthError);
+        };
+        let communication_mode =
+            CommunicationMode::try_from(communication_mode).map_err(|_| Status::ParameterError)?;
+        self.file_mut(file_id)?.change_settings(
+            communication_mode,
+            AccessRights::from_bytes([rights_0, rights_1]),
+        );
+        Ok(Reply::Data(Vec::new()))
+    }
+
+    fn create_file(&mut self, code: CommandCode, data: &[u8]) -> Result<Reply, Status> {
+        self.require_master_key(self.current_application()?.key_settings & 0x04 != 0)?;
+        let (&file_id, rest) = data.split_first().ok_or(Status::LengthError)?;
+        if file_id > MAX_FILE_ID {
+            return Err(Status::ParameterError);
+        }
+
+        // ISO files carry a two-byte ISO file id after the file number.
+        let base_len = match code {
+            CommandCode::CREATE_VALUE_FILE => 16,
+            CommandCode::CREATE_LINEAR_RECORD_FILE | CommandCode::CREATE_CYCLIC_RECORD_FILE => 9,
+            _ => 6,
+        };
+        let (iso_file_id, rest) = match rest.len() {
+            len if len == base_len => (None, rest),
+            len if len == base_len + 2 && code != CommandCode::CREATE_VALUE_FILE => {
+                (Some([rest[0], rest[1]]), &rest[2..])
+            }
+            _ => return Err(Status::LengthError),
+        };
+        let communication_mode =
+            CommunicationMode::try_from(rest[0]).map_err(|_| Status::ParameterError)?;
+        let access_rights = AccessRights::from_bytes([rest[1], rest[2]]);
+
+        let mut file = match code {
+            CommandCode::CREATE_STD_DATA_FILE | CommandCode::CREATE_BACKUP_DATA_FILE => {
+                let data = std::vec![0u8; u24_at(rest, 3)];
+                if code == CommandCode::CREATE_STD_DATA_FILE {
+                    VirtualFile::std_data(communication_mode, access_rights, &data)
+                } else {
+                    VirtualFile::backup_data(communication_mode, access_rights, &data)
+                }
+            }
+            CommandCode::CREATE_VALUE_FILE => {
+                let lower_limit = i32_at(rest, 3);
+                let upper_limit = i32_at(rest, 7);
+                let value = i32_at(rest, 11);
+                if lower_limit > upper_limit || !(lower_limit..=upper_limit).contains(&value) {
+                    return Err(Status::BoundaryError);
+                }
+                VirtualFile::value(
+                    communication_mode,
+                    access_rights,
+                    lower_limit,
+                    upper_limit,
+                    value,
+                    rest[15] & 0x01 != 0,
+                )
+            }
+            _ => {
+                let record_size = U24::from_le_bytes([rest[3], rest[4], rest[5]]);
+                let max_records = U24::from_le_bytes([rest[6], rest[7], rest[8]]);
+                let cyclic = code == CommandCode::CREATE_CYCLIC_RECORD_FILE;
+                if record_size.as_u32() == 0 || max_records.as_u32() < 1 + u32::from(cyclic) {
+                    return Err(Status::ParameterError);
+                }
+                if cyclic {
+                    VirtualFile::cyclic_record(
+                        communication_mode,
+                        access_rights,
+                        record_size,
+                        max_records,
+                    )
+                } else {
+                    VirtualFile::linear_record(
+                        communication_mode,
+                        access_rights,
+                        record_size,
+                        max_records,
+                    )
+                }
+            }
+        };
+        file.iso_file_id = iso_file_id;
+
+        let app = self.current_application()?;
+        if app.files.contains_key(&file_id)
+            || iso_file_id.is_some_and(|id| app.files.values().any(|f| f.iso_file_id == Some(id)))
+        {
+            return Err(Status::DuplicateError);
+        }
+        let blocks = u32::try_from(file.allocation())
+            .map_err(|_| Status::OutOfMemory)?
+            .div_ceil(MEMORY_BLOCK_SIZE);
+        self.free_memory = self
+            .free_memory
+            .checked_sub(blocks * MEMORY_BLOCK_SIZE)
+            .ok_or(Status::OutOfMemory)?;
+        self.current_application_mut()?.files.insert(file_id, file);
+        Ok(Reply::Data(Vec::new()))
+    }
+
+    fn delete_file(&mut self, data: &[u8]) -> Result<Reply, Status> {
+        let &[file_id] = data else {
+            return Err(Status::LengthError);
+        };
+        self.require_master_key(self.current_application()?.key_settings & 0x04 != 0)?;
+        self.current_application_mut()?
+            .files
+            .remove(&file_id)
+            .ok_or(Status::FileNotFound)?;
+        Ok(Reply::Data(Vec::new()))
+    }
+
+    fn read_file(&self, code: CommandCode, data: &[u8]) -> Result<Reply, Status> {
+        let (access, expected_len) = match code {
+            CommandCode::GET_VALUE => (FileAccess::Any, 1),
+            _ => (FileAccess::Read, 7),
+        };
+        expect_len(data, expected_len)?;
+        let communication_mode = self.file_access(data[0], access)?;
+        let file = self.file(data[0])?;
+        let (body, implicit_length) = match code {
+            CommandCode::GET_VALUE => (file.get_value()?.to_le_bytes().to_vec(), false),
+            CommandCode::READ_DATA => (
+                file.read_data(u24_at(data, 1), u24_at(data, 4))?,
+                u24_at(data, 4) == 0,
+            ),
+            _ => (
+                file.read_records(u24_at(data, 1), u24_at(data, 4))?,
+                u24_at(data, 4) == 0,
+            ),
+        };
+
+        match communication_mode {
+            CommunicationMode::Plain | CommunicationMode::Maced => Ok(Reply::Data(body)),
+            CommunicationMode::Enciphered => Ok(Reply::Enciphered {
+                data: body,
+                implicit_length,
+            }),
+        }
+    }
+
+    /// `WriteData`, `WriteRecord`, `Credit`, `Debit` and `LimitedCredit`, whose data
+    /// is protected according to the file's communication mode.
+    fn write_file(&mut self, code: CommandCode, data: &[u8]) -> Result<Reply, Status> {
+        let (access, header_len) = match code {
+            CommandCode::WRITE_DATA | CommandCode::WRITE_RECORD => (FileAccess::Write, 7),
+            CommandCode::CREDIT => (FileAccess::ReadWrite, 1),
+            CommandCode::DEBIT => (FileAccess::Any, 1),
+            _ => (FileAccess::Write, 1),
+        };
+        if data.len() < header_len {
+            return Err(Status::LengthError);
+        }
+        let (header, body) = data.split_at(header_len);
+        let file_id = header[0];
+        let payload_len = if header_len == 7 {
+            u24_at(header, 4)
+        } else {
+            4
+        };
+
+        let payload = match self.file_access(file_id, access)? {
+            CommunicationMode::Plain => {
+                self.update_command_mac(code, data)?;
+                body.to_vec()
+            }
+            CommunicationMode::Maced => self.verify_command_mac(code, data)?[header_len..].to_vec(),
+            CommunicationMode::Enciphered => {
+                self.decipher_command(code, header, body, false, |_| payload_len)?
+            }
+        };
+        if payload.len() != payload_len {
+            return Err(Status::LengthError);
+        }
+
+        let file = self.file_mut(file_id)?;
+        match code {
+            CommandCode::WRITE_DATA => file.write_data(u24_at(header, 1), &payload)?,
+            CommandCode::WRITE_RECORD => file.write_record(u24_at(header, 1), &payload)?,
+            _ => {
+                let amount = i32_at(&payload, 0);
+                match code {
+                    CommandCode::CREDIT => file.credit(amount, false)?,
+                    CommandCode::DEBIT => file.debit(amount)?,
+                    _ => file.credit(amount, true)?,
+                }
+            }
+        }
+        Ok(Reply::Data(Vec::new()))
+    }
+
+    /// Checks a file's access rights and returns the communication mode to use.
+    ///
+    /// Free access is always plain; access granted by a key uses the file's mode.
+    fn file_access(&self, file_id: u8, access: FileAccess) -> Result<CommunicationMode, Status> {
+        let file = self.file(file_id)?;
+        let conditions = file.access_rights().granting(access);
+        if conditions.contains(&AccessCondition::Free) {
+            return Ok(CommunicationMode::Plain);
+        }
+        match &self.session {
+            Some(session) if conditions.contains(&AccessCondition::Key(session.key_number())) => {
+                Ok(file.communication_mode())
+            }
+            None if conditions.iter().any(|c| *c != AccessCondition::Never) => {
+                Err(Status::AuthenticationError)
+            }
+            _ => Err(Status::PermissionDenied),
+        }
+    }
+
+    /// Requires master-key authentication at the selected level unless `free`.
+    fn require_master_key(&self, free: bool) -> Result<(), Status> {
+        match &self.session {
+            _ if free => Ok(()),
+            Some(session) if session.key_number().as_byte() == 0 => Ok(()),
+            Some(_) => Err(Status::PermissionDenied),
+            None => Err(Status::AuthenticationError),
+        }
+    }
+
+    fn require_picc_level(&self) -> Result<(), Status> {
+        if self.selected == ApplicationId::PICC {
+            Ok(())
+        } else {
+            Err(Status::PermissionDenied)
+        }
+    }
+
+    /// Key settings byte of the selected level.
+    pub(super) fn key_settings(&self) -> u8 {
+        self.selected_application()
+            .map_or(self.picc_key_settings, |app| app.key_settings)
+    }
+
+    fn application_index(&self, aid: ApplicationId) -> Option<usize> {
+        self.applications.iter().position(|app| app.id() == aid)
+    }
+
+    pub(super) fn selected_application(&self) -> Option<&VirtualApplication> {
+        self.application_index(self.selected)
+            .map(|index| &self.applications[index])
+    }
+
+    fn selected_application_mut(&mut self) -> Option<&mut VirtualApplication> {
+        self.application_index(self.selected)
+            .map(|index| &mut self.applications[index])
+    }
+
+    /// The selected application; file commands are refused at PICC level.
+    pub(super) fn current_application(&self) -> Result<&VirtualApplication, Status> {
+        self.selected_application().ok_or(Status::PermissionDenied)
+    }
+
+    pub(super) fn current_application_mut(&mut self) -> Result<&mut VirtualApplication, Status> {
+        self.selected_application_mut()
+            .ok_or(Status::PermissionDenied)
+    }
+
+    fn file(&self, file_id: u8) -> Result<&VirtualFile, Status> {
+        self.current_application()?
+            .files
+            .get(&file_id)
+            .ok_or(Status::FileNotFound)
+    }
+
+    fn file_mut(&mut self, file_id: u8) -> Result<&mut VirtualFile, Status> {
+        self.current_application_mut()?
+            .files
+            .get_mut(&file_id)
+            .ok_or(Status::FileNotFound)
+    }
+}
+
+fn expect_len(data: &[u8], len: usize) -> Result<(), Status> {
+    if data.len() == len {
+        Ok(())
+    } else {
+        Err(Status::LengthError)
+    }
+}
+
+fn u24_at(data: &[u8], offset: usize) -> usize {
+    let value = U24::from_le_bytes([data[offset], data[offset + 1], data[offset + 2]]);
+    usize::try_from(value.as_u32()).expect("U24 fits in usize")
+}
+
+fn u24_bytes(value: u32) -> [u8; 3] {
+    U24::new(value)
+        .expect("free memory fits in U24")
+        .to_le_bytes()
+}
+
+fn i32_at(data: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes(data[offset..offset + 4].try_into().expect("valid slice"))
+}
@@ -0,0 +1,595 @@
+use std::vec::Vec;
+
+use crate::mifare::desfire::{
+    file::{AccessRights, CommunicationMode, FileSettings, FileSettingsDetails, FileType},
+    status::Status,
+    types::U24,
+};
+
+/// One file of a [`VirtualApplication`](super::VirtualApplication).
+///
+/// Backup data, value and record files keep their committed contents apart from
+/// the changes staged by the current transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualFile {
+    communication_mode: CommunicationMode,
+    access_rights: AccessRights,
+    pub(super) iso_file_id: Option<[u8; 2]>,
+    pub(super) contents: Contents,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Contents {
+    Data {
+        backup: bool,
+        data: Vec<u8>,
+        pending: Option<Vec<u8>>,
+    },
+    Value {
+        lower_limit: i32,
+        upper_limit: i32,
+        value: i32,
+        limited_credit_enabled: bool,
+        limited_credit_value: i32,
+        pending: Option<PendingValue>,
+    },
+    Record {
+        cyclic: bool,
+        record_size: usize,
+        max_records: usize,
+        records: Vec<Vec<u8>>,
+        pending: PendingRecords,
+    },
+}
+
+/// Value-file changes staged until `CommitTransaction`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct PendingValue {
+    value: i32,
+    debited: i32,
+    credited: bool,
+}
+
+/// Record-file changes staged until `CommitTransaction`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(super) struct PendingRecords {
+    record: Option<Vec<u8>>,
+    clear: bool,
+}
+
+impl VirtualFile {
+    /// Standard data file holding `data`; the file size is `data.len()`.
+    pub fn std_data(
+        communication_mode: CommunicationMode,
+        access_rights: AccessRights,
+        data: &[u8],
+    ) -> Self {
+        Self::with_contents(
+            communication_mode,
+            access_rights,
+            Contents::Data {
+                backup: false,
+                data: data.to_vec(),
+                pending: None,
+            },
+        )
+    }
+
+    /// Backup data file holding committed `data`; the file size is `data.len()`.
+    pub fn backup_data(
+        communication_mode: CommunicationMode,
+        access_rights: AccessRights,
+        data: &[u8],
+    ) -> Self {
+        Self::with_contents(
+            communication_mode,
+            access_rights,
+            Contents::Data {
+                backup: true,
+                data: data.to_vec(),
+                pending: None,
+            },
+        )
+    }
+
+    /// Value file with its limits and committed value.
+    pub fn value(
+        communication_mode: CommunicationMode,
+        access_rights: AccessRights,
+        lower_limit: i32,
+        upper_limit: i32,
+        value: i32,
+        limited_credit_enabled: bool,
+    ) -> Self {
+        Self::with_contents(
+            communication_mode,
+            access_rights,
+            Contents::Value {
+                lower_limit,
+                upper_limit,
+                value,
+                limited_credit_enabled,
+                limited_credit_value: 0,
+                pending: None,
+            },
+        )
+    }
+
+    /// Empty linear record file.
+    pub fn linear_record(
+        communication_mode: CommunicationMode,
+        access_rights: AccessRights,
+        record_size: U24,
+        max_records: U24,
+    ) -> Self {
+        Self::record(
+            communication_mode,
+            access_rights,
+            false,
+            record_size,
+            max_records,
+        )
+    }
+
+    /// Empty cyclic record file.
+    ///
+    /// As on a real card, one of the `max_records` slots is kept free for the
+    /// record being written, so at most `max_records - 1` records are readable.
+    pub fn cyclic_record(
+        communication_mode: CommunicationMode,
+        access_rights: AccessRights,
+        record_size: U24,
+        max_records: U24,
+    ) -> Self {
+        Self::record(
+            communication_mode,
+            access_rights,
+            true,
+            record_size,
+            max_records,
+        )
+    }
+
+    fn record(
+        communication_mode: CommunicationMode,
+        access_rights: AccessRights,
+        cyclic: bool,
+        record_size: U24,
+        max_records: U24,
+    ) -> Self {
+        Self::with_contents(
+            communication_mode,
+            access_rights,
+            Contents::Record {
+                cyclic,
+                record_size: usize::try_from(record_size.as_u32()).expect("U24 fits in usize"),
+                max_records: usize::try_from(max_records.as_u32()).expect("U24 fits in usize"),
+                records: Vec::new(),
+                pending: PendingRecords::default(),
+            },
+        )
+    }
+
+    const fn with_contents(
+        communication_mode: CommunicationMode,
+        access_rights: AccessRights,
+        contents: Contents,
+    ) -> Self {
+        Self {
+            communication_mode,
+            access_rights,
+            iso_file_id: None,
+            contents,
+        }
+    }
+
+    /// Settings as returned by `GetFileSettings`.
+    pub fn settings(&self) -> FileSettings {
+        let (file_type, details) = match &self.contents {
+            Contents::Data { backup, data, .. } => (
+                if *backup {
+                    FileType::BackupData
+                } else {
+                    FileType::StandardData
+                },
+                FileSettingsDetails::Data {
+                    size: u24(data.len()),
+                },
+            ),
+            Contents::Value {
+                lower_limit,
+                upper_limit,
+                limited_credit_enabled,
+                limited_credit_value,
+                ..
+            } => (
+                FileType::Value,
+                FileSettingsDetails::Value {
+                    lower_limit: *lower_limit,
+                    upper_limit: *upper_limit,
+                    limited_credit_value: *limited_credit_value,
+                    limited_credit_enabled: *limited_credit_enabled,
+                },
+            ),
+            Contents::Record {
+                cyclic,
+                record_size,
+                max_records,
+                records,
+                ..
+            } => (
+                if *cyclic {
+                    FileType::CyclicRecord
+                } else {
+                    FileType::LinearRecord
+                },
+                FileSettingsDetails::Record {
+                    record_size: u24(*record_size),
+                    max_records: u24(*max_records),
+                    current_records: u24(records.len()),
+                },
+            ),
+        };
+        FileSettings::new(
+            file_type,
+            self.communication_mode,
+            self.access_rights,
+            details,
+        )
+    }
+
+    /// Committed contents of a standard or backup data file.
+    pub fn data(&self) -> Option<&[u8]> {
+        match &self.contents {
+            Contents::Data { data, .. } => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Committed value of a value file.
+    pub const fn current_value(&self) -> Option<i32> {
+        match &self.contents {
+            Contents::Value { value, .. } => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Committed records of a record file, oldest first.
+    pub fn records(&self) -> Option<&[Vec<u8>]> {
+        match &self.contents {
+            Contents::Record { records, .. } => Some(records),
+            _ => None,
+        }
+    }
+
+    pub(super) const fn communication_mode(&self) -> CommunicationMode {
+        self.communication_mode
+    }
+
+    pub(super) const fn access_rights(&self) -> AccessRights {
+        self.access_rights
+    }
+
+    pub(super) const fn change_settings(
+        &mut self,
+        communication_mode: CommunicationMode,
+        access_rights: AccessRights,
+    ) {
+        self.communication_mode = communication_mode;
+        self.access_rights = access_rights;
+    }
+
+    /// Bytes of card memory the file occupies, before rounding to memory blocks.
+    pub(super) fn allocation(&self) -> usize {
+        match &self.contents {
+            Contents::Data { backup, data, .. } => data.len() * if *backup { 2 } else { 1 },
+            Contents::Value { .. } => 4,
+            Contents::Record {
+                record_size,
+                max_records,
+                ..
+            } => record_size * max_records,
+        }
+    }
+
+    /// `ReadData` for `length` bytes at `offset`; zero reads up to the end of the file.
+    pub(super) fn read_data(&self, offset: usize, length: usize) -> Result<Vec<u8>, Status> {
+        let Contents::Data { data, .. } = &self.contents else {
+            return Err(Status::PermissionDenied);
+        };
+        let end = if length == 0 {
+            data.len()
+        } else {
+            offset + length
+        };
+        if offset >= data.len() || end > data.len() {
+            return Err(Status::BoundaryError);
+        }
+        Ok(data[offset..end].to_vec())
+    }
+
+    /// `WriteData`; standard files change at once, backup files on commit.
+    pub(super) fn write_data(&mut self, offset: usize, bytes: &[u8]) -> Result<(), Status> {
+        let Contents::Data {
+            backup,
+            data,
+            pending,
+        } = &mut self.contents
+        else {
+            return Err(Status::PermissionDenied);
+        };
+        let end = offset + bytes.len();
+        if end > data.len() {
+            return Err(Status::BoundaryError);
+        }
+        let target = if *backup {
+            pending.get_or_insert_with(|| data.clone())
+        } else {
+            data
+        };
+        target[offset..end].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub(super) fn get_value(&self) -> Result<i32, Status> {
+        self.current_value().ok_or(Status::PermissionDenied)
+    }
+
+    pub(super) fn credit(&mut self, amount: i32, limited: bool) -> Result<(), Status> {
+        let Contents::Value {
+            upper_limit,
+            value,
+            limited_credit_enabled,
+            limited_credit_value,
+            pending,
+            ..
+        } = &mut self.contents
+        else {
+            return Err(Status::PermissionDenied);
+        };
+        if amount < 0 {
+            return Err(Status::ParameterError);
+        }
+        if limited && (!*limited_credit_enabled || amount > *limited_credit_value) {
+            return Err(Status::BoundaryError);
+        }
+        let staged = pending.get_or_insert(PendingValue {
+            value: *value,
+            debited: 0,
+            credited: false,
+        });
+        let new_value = staged
+            .value
+            .checked_add(amount)
+            .filter(|new_value| new_value <= upper_limit)
+            .ok_or(Status::BoundaryError)?;
+        staged.value = new_value;
+        staged.credited = true;
+        Ok(())
+    }
+
+    pub(super) fn debit(&mut self, amount: i32) -> Result<(), Status> {
+        let Contents::Value {
+            lower_limit,
+            value,
+            pending,
+            ..
+        } = &mut self.contents
+        else {
+            return Err(Status::PermissionDenied);
+        };
+        if amount < 0 {
+            return Err(Status::ParameterError);
+        }
+        let staged = pending.get_or_insert(PendingValue {
+            value: *value,
+            debited: 0,
+            credited: false,
+        });
+        let new_value = staged
+            .value
+            .checked_sub(amount)
+            .filter(|new_value| new_value >= lower_limit)
+            .ok_or(Status::BoundaryError)?;
+        staged.value = new_value;
+        staged.debited = staged.debited.saturating_add(amount);
+        Ok(())
+    }
+
+    /// `WriteRecord`; all writes of one transaction go to the same new record.
+    pub(super) fn write_record(&mut self, offset: usize, bytes: &[u8]) -> Result<(), Status> {
+        let Contents::Record {
+            cyclic,
+            record_size,
+            max_records,
+            records,
+            pending,
+        } = &mut self.contents
+        else {
+            return Err(Status::PermissionDenied);
+        };
+        if offset + bytes.len() > *record_size {
+            return Err(Status::BoundaryError);
+        }
+        let committed = if pending.clear { 0 } else { records.len() };
+        if pending.record.is_none() && !*cyclic && committed >= *max_records {
+            return Err(Status::BoundaryError);
+        }
+        let record = pending
+            .record
+            .get_or_insert_with(|| std::vec![0u8; *record_size]);
+        record[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// `ReadRecords`: `offset` counts back from the newest record; a `count` of zero
+    /// reads down to the oldest. Records are returned oldest first.
+    pub(super) fn read_records(&self, offset: usize, count: usize) -> Result<Vec<u8>, Status> {
+        let Contents::Record { records, .. } = &self.contents else {
+            return Err(Status::PermissionDenied);
+        };
+        if offset >= records.len() {
+            return Err(Status::BoundaryError);
+        }
+        let available = records.len() - offset;
+        let count = if count == 0 { available } else { count };
+        if count > available {
+            return Err(Status::BoundaryError);
+        }
+        let end = records.len() - offset;
+        Ok(records[end - count..end].concat())
+    }
+
+    pub(super) fn clear_records(&mut self) -> Result<(), Status> {
+        let Contents::Record { pending, .. } = &mut self.contents else {
+            return Err(Status::PermissionDenied);
+        };
+        *pending = PendingRecords {
+            record: None,
+            clear: true,
+        };
+        Ok(())
+    }
+
+    pub(super) fn commit(&mut self) {
+        match &mut self.contents {
+            Contents::Data { data, pending, .. } => {
+                if let Some(new_data) = pending.take() {
+                    *data = new_data;
+                }
+            }
+            Contents::Value {
+                value,
+                limited_credit_enabled,
+                limited_credit_value,
+                pending,
+                ..
+            } => {
+                if let Some(staged) = pending.take() {
+                    *value = staged.value;
+                    if staged.debited > 0 && *limited_credit_enabled {
+                        *limited_credit_value = staged.debited;
+                    } else if staged.credited {
+                        *limited_credit_value = 0;
+                    }
+                }
+            }
+            Contents::Record {
+                cyclic,
+                max_records,
+                records,
+                pending,
+                ..
+            } => {
+                let staged = core::mem::take(pending);
+                if staged.clear {
+                    records.clear();
+                }
+                if let Some(record) = staged.record {
+                    if *cyclic && records.len() + 1 >= *max_records {
+                        records.remove(0);
+                    }
+                    records.push(record);
+                }
+            }
+        }
+    }
+
+    pub(super) fn abort(&mut self) {
+        match &mut self.contents {
+            Contents::Data { pending, .. } => *pending = None,
+            Contents::Value { pending, .. } => *pending = None,
+            Contents::Record { pending, .. } => *pending = PendingRecords::default(),
+        }
+    }
+
+    /// Encodes the `GetFileSettings` response body.
+    pub(super) fn settings_bytes(&self) -> Vec<u8> {
+        let settings = self.settings();
+        let mut out = std::vec![
+            u8::from(settings.file_type()),
+            u8::from(settings.communication_mode()),
+        ];
+        out.extend_from_slice(&settings.access_rights().to_bytes());
+        match settings.details() {
+            FileSettingsDetails::Data { size } => out.extend_from_slice(&size.to_le_bytes()),
+            FileSettingsDetails::Value {
+                lower_limit,
+                upper_limit,
+                limited_credit_value,
+                limited_credit_enabled,
+            } => {
+                out.extend_from_slice(&lower_limit.to_le_bytes());
+                out.extend_from_slice(&upper_limit.to_le_bytes());
+                out.extend_from_slice(&limited_credit_value.to_le_bytes());
+                out.push(u8::from(limited_credit_enabled));
+            }
+            FileSettingsDetails::Record {
+                record_size,
+                max_records,
+                current_records,
+            } => {
+                out.extend_from_slice(&record_size.to_le_bytes());
+                out.extend_from_slice(&max_records.to_le_bytes());
+                out.extend_from_slice(&current_records.to_le_bytes());
+            }
+        }
+        out
+    }
+}
+
+fn u24(value: usize) -> U24 {
+    u32::try_from(value)
+        .ok()
+        .and_then(U24::new)
+        .expect("virtual file sizes fit in U24")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mifare::desfire::{
+        file::{AccessCondition, AccessRights, CommunicationMode},
+        sim::VirtualFile,
+        status::Status,
+        types::U24,
+    };
+
+    const FREE: AccessRights = AccessRights::new(
+        AccessCondition::Free,
+        AccessCondition::Free,
+        AccessCondition::Free,
+        AccessCondition::Free,
+    );
+
+    #[test]
+    fn cyclic_record_file_keeps_one_slot_free() {
+        let mut file = VirtualFile::cyclic_record(
+            CommunicationMode::Plain,
+            FREE,
+            U24::new(1).unwrap(),
+            U24::new(3).unwrap(),
+        );
+        for byte in 1..=4 {
+            file.write_record(0, &[byte]).unwrap();
+            file.commit();
+        }
+
+        assert_eq!(file.records().unwrap(), &[[3].to_vec(), [4].to_vec()]);
+        assert_eq!(file.read_records(0, 0).unwrap(), [3, 4]);
+        assert_eq!(file.read_records(1, 1).unwrap(), [3]);
+        assert_eq!(file.read_records(2, 0), Err(Status::BoundaryError));
+    }
+
+    #[test]
+    fn limited_credit_is_bounded_by_last_debit() {
+        let mut file = VirtualFile::value(CommunicationMode::Plain, FREE, 0, 100, 50, true);
+        file.debit(20).unwrap();
+        file.commit();
+
+        assert_eq!(file.credit(21, true), Err(Status::BoundaryError));
+        file.credit(20, true).unwrap();
+        file.commit();
+        assert_eq!(file.current_value(), Some(50));
+        assert_eq!(file.credit(1, true), Err(Status::BoundaryError));
+    }
+}
@@ -0,0 +1,1471 @@
+//! In-memory `DESFire` EV1 card for testing without a reader.
+//!
+//! [`VirtualDesfire`] implements [`Transport`] and answers native and wrapped
+//! frames the way an EV1 card does. It keeps the PICC and application keys,
+//! all five file types with their access rights and transactions, and runs the
+//! real authentication and secure-messaging crypto, so
+//! [`Desfire`](super::Desfire) and code built on it can be tested end to end.
+//!
+//! As on a real EV1 card, every plain response carries a MAC while a session is
+//! active, and any error ends the session. EV2 commands are rejected with
+//! `IllegalCommandCode` and ISO 7816-4 APDUs with `6D00`.
+
+mod application;
+mod auth;
+mod commands;
+mod file;
+
+use std::{collections::VecDeque, vec::Vec};
+
+use crate::mifare::desfire::{
+    application::ApplicationId,
+    command::CommandCode,
+    configuration::PiccConfiguration,
+    error::Error,
+    key::Key,
+    session::AuthenticatedSession,
+    status::Status,
+    transport::{Frame, Transport},
+};
+
+pub use application::VirtualApplication;
+pub use file::VirtualFile;
+
+use application::KeySlot;
+use auth::PendingAuth;
+
+/// UID of a card built with [`VirtualDesfire::new`].
+pub const DEFAULT_UID: [u8; 7] = [0x04, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+/// Free memory of an empty card, as reported by `FreeMem`.
+pub const MEMORY_SIZE: u32 = 4096;
+
+const HARDWARE_VERSION: [u8; 7] = [0x04, 0x01, 0x01, 0x01, 0x00, 0x18, 0x05];
+const SOFTWARE_VERSION: [u8; 7] = [0x04, 0x01, 0x01, 0x01, 0x04, 0x18, 0x05];
+const BATCH_NUMBER: [u8; 5] = [0xBA, 0x7C, 0x40, 0x00, 0x00];
+const PRODUCTION_WEEK: u8 = 0x10;
+const PRODUCTION_YEAR: u8 = 0x24;
+const DEFAULT_ATS: [u8; 6] = [0x06, 0x75, 0x77, 0x81, 0x02, 0x80];
+
+/// Longest data field of one response frame.
+const MAX_RESPONSE_FRAME_DATA: usize = 59;
+
+/// Class byte of `DESFire` commands wrapped in ISO 7816-4 APDUs.
+const WRAPPED_CLA: u8 = 0x90;
+
+/// Virtual `DESFire` EV1 card.
+///
+/// A new card is in factory state: an all-zero DES PICC master key with key
+/// settings `0x0F` and no applications. The builder methods provision a card
+/// before use, and the accessors inspect what a test wrote to it.
+#[derive(Debug, Clone)]
+pub struct VirtualDesfire {
+    uid: [u8; 7],
+    picc_key: KeySlot,
+    picc_key_settings: u8,
+    configuration: PiccConfiguration,
+    default_key: [u8; 25],
+    ats: Vec<u8>,
+    applications: Vec<VirtualApplication>,
+    free_memory: u32,
+    selected: ApplicationId,
+    session: Option<AuthenticatedSession>,
+    pending_auth: Option<PendingAuth>,
+    pending_command: Option<PendingCommand>,
+    pending_frames: VecDeque<(Status, Vec<u8>)>,
+    random_state: u64,
+}
+
+/// Command whose data is still arriving in `AdditionalFrame` frames.
+#[derive(Debug, Clone)]
+struct PendingCommand {
+    code: CommandCode,
+    data: Vec<u8>,
+    expected_len: usize,
+}
+
+/// Successful command result before response secure messaging is applied.
+enum Reply {
+    /// Response data, followed by a MAC while a session is active.
+    Data(Vec<u8>),
+    /// One frame per entry; a MAC over their concatenation ends the last one.
+    Frames(Vec<Vec<u8>>),
+    /// Response data enciphered behind a CRC; an implicit length pads with `0x80`.
+    Enciphered {
+        data: Vec<u8>,
+        implicit_length: bool,
+    },
+    /// One frame sent as-is, for authentication and commands that end the session.
+    Unprotected(Status, Vec<u8>),
+}
+
+impl VirtualDesfire {
+    /// Creates a card in factory state.
+    pub fn new() -> Self {
+        Self {
+            uid: DEFAULT_UID,
+            picc_key: KeySlot::new(Key::Des([0; 8]), 0),
+            picc_key_settings: 0x0F,
+            configuration: PiccConfiguration::new(),
+            default_key: [0; 25],
+            ats: DEFAULT_ATS.to_vec(),
+            applications: Vec::new(),
+            free_memory: MEMORY_SIZE,
+            selected: ApplicationId::PICC,
+            session: None,
+            pending_auth: None,
+            pending_command: None,
+            pending_frames: VecDeque::new(),
+            random_state: 0x853C_49E6_748F_EA9B,
+        }
+    }
+
+    /// Sets the 7-byte UID.
+    #[must_use]
+    pub const fn with_uid(mut self, uid: [u8; 7]) -> Self {
+        self.uid = uid;
+        self
+    }
+
+    /// Sets the PICC master key.
+    ///
+    /// `version` is kept for AES keys; DES-family keys carry their version in
+    /// the parity bits.
+    #[must_use]
+    pub fn with_picc_key(mut self, key: Key, version: u8) -> Self {
+        self.picc_key = KeySlot::new(key, version);
+        self
+    }
+
+    /// Sets the PICC master key settings byte.
+    #[must_use]
+    pub const fn with_picc_key_settings(mut self, key_settings: u8) -> Self {
+        self.picc_key_settings = key_settings;
+        self
+    }
+
+    /// Adds an application, replacing any application with the same id.
+    ///
+    /// Pre-provisioned applications and files do not use up free memory.
+    #[must_use]
+    pub fn with_application(mut self, application: VirtualApplication) -> Self {
+        self.applications.retain(|app| app.id() != application.id());
+        self.applications.push(application);
+        self
+    }
+
+    /// Seeds the generator used for the card's authentication challenges.
+    ///
+    /// Cards built from the same seed issue the same `RndB` sequence.
+    #[must_use]
+    pub const fn with_random_seed(mut self, seed: u64) -> Self {
+        // Xorshift has an all-zero fixed point.
+        self.random_state = if seed == 0 { 1 } else { seed };
+        self
+    }
+
+    /// Real card UID.
+    pub const fn uid(&self) -> [u8; 7] {
+        self.uid
+    }
+
+    /// Current PICC master key.
+    pub const fn picc_key(&self) -> &Key {
+        self.picc_key.key()
+    }
+
+    /// PICC configuration set with `SetConfiguration`.
+    pub const fn configuration(&self) -> PiccConfiguration {
+        self.configuration
+    }
+
+    /// ATS the card would return after `RATS`.
+    pub fn ats(&self) -> &[u8] {
+        &self.ats
+    }
+
+    /// Free memory as reported by `FreeMem`.
+    pub const fn free_memory(&self) -> u32 {
+        self.free_memory
+    }
+
+    /// Application ids in creation order.
+    pub fn application_ids(&self) -> impl Iterator<Item = ApplicationId> + '_ {
+        self.applications.iter().map(VirtualApplication::id)
+    }
+
+    /// One application of the card.
+    pub fn application(&self, application_id: ApplicationId) -> Option<&VirtualApplication> {
+        self.applications
+            .iter()
+            .find(|app| app.id() == application_id)
+    }
+
+    /// Simulates removing the card from the field.
+    ///
+    /// The PICC level is selected again, the session ends and uncommitted
+    /// changes are discarded.
+    pub fn reset(&mut self) {
+        self.abort_transaction();
+        self.selected = ApplicationId::PICC;
+        self.session = None;
+        self.pending_auth = None;
+        self.pending_command = None;
+        self.pending_frames.clear();
+    }
+
+    /// Handles one command frame and returns the status and data of the response frame.
+    fn process(&mut self, code: CommandCode, data: &[u8]) -> (Status, Vec<u8>) {
+        if code == CommandCode::ADDITIONAL_FRAME {
+            if let Some(pending) = self.pending_auth.take() {
+                let result = self.finish_authentication(&pending, data);
+                return self.respond(result);
+            }
+            if let Some(mut command) = self.pending_command.take() {
+                command.data.extend_from_slice(data);
+                return self.receive(command);
+            }
+            if let Some(frame) = self.pending_frames.pop_front() {
+                return frame;
+            }
+            return self.respond(Err(Status::IllegalCommandCode));
+        }
+
+        self.pending_auth = None;
+        self.pending_frames.clear();
+        let expected_len = self.expected_command_len(code, data);
+        self.receive(PendingCommand {
+            code,
+            data: data.to_vec(),
+            expected_len,
+        })
+    }
+
+    /// Runs a command once all of its data has arrived.
+    fn receive(&mut self, command: PendingCommand) -> (Status, Vec<u8>) {
+        if command.data.len() < command.expected_len {
+            self.pending_command = Some(command);
+            return (Status::AdditionalFrame, Vec::new());
+        }
+
+        let result = self.execute(command.code, &command.data);
+        self.respond(result)
+    }
+
+    /// Applies response secure messaging and queues any additional frames.
+    fn respond(&mut self, result: Result<Reply, Status>) -> (Status, Vec<u8>) {
+        match result.and_then(|reply| self.protect(reply)) {
+            Ok(frames) => {
+                self.pending_frames = frames.into();
+                self.pending_frames
+                    .pop_front()
+                    .expect("at least one response frame")
+            }
+            Err(status) => {
+                self.session = None;
+                (status, Vec::new())
+            }
+        }
+    }
+
+    fn protect(&mut self, reply: Reply) -> Result<Vec<(Status, Vec<u8>)>, Status> {
+        let frames = match reply {
+            Reply::Data(mut data) => {
+                if let Some(mac) = self.response_mac(&data)? {
+                    data.extend_from_slice(&mac);
+                }
+                split_frames(&data)
+            }
+            Reply::Frames(mut frames) => {
+                if let Some(mac) = self.response_mac(&frames.concat())? {
+                    frames
+                        .last_mut()
+                        .expect("at least one response frame")
+                        .extend_from_slice(&mac);
+                }
+                frames
+            }
+            Reply::Enciphered {
+                data,
+                implicit_length,
+            } => split_frames(&self.encipher_response(data, implicit_length)?),
+            Reply::Unprotected(status, data) => return Ok(std::vec![(status, data)]),
+        };
+
+        let last = frames.len() - 1;
+        Ok(frames
+            .into_iter()
+            .enumerate()
+            .map(|(index, frame)| {
+                let status = if index == last {
+                    Status::OperationOk
+                } else {
+                    Status::AdditionalFrame
+                };
+                (status, frame)
+            })
+            .collect())
+    }
+
+    /// Discards uncommitted changes in the selected application.
+    fn abort_transaction(&mut self) {
+        let selected = self.selected;
+        if let Some(app) = self
+            .applications
+            .iter_mut()
+            .find(|app| app.id() == selected)
+        {
+            app.abort();
+        }
+    }
+
+    /// Next pseudo-random challenge bytes (xorshift64*).
+    fn random_bytes<const N: usize>(&mut self) -> [u8; N] {
+        let mut out = [0u8; N];
+        for chunk in out.chunks_mut(8) {
+            self.random_state ^= self.random_state >> 12;
+            self.random_state ^= self.random_state << 25;
+            self.random_state ^= self.random_state >> 27;
+            let value = self.random_state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+            chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+        }
+        out
+    }
+}
+
+impl Default for VirtualDesfire {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for VirtualDesfire {
+    fn transceive(&mut self, tx: &[u8], rx: &mut Frame) -> Result<(), Error> {
+        rx.clear();
+        match tx {
+            [] => Err(Error::Transport),
+            [WRAPPED_CLA, ins, 0x00, 0x00, apdu_body @ ..] => {
+                let data = match apdu_body {
+                    [_le] => &[][..],
+                    [lc, data @ .., _le] if usize::from(*lc) == data.len() => data,
+                    _ => return push_frame(rx, &[0x67, 0x00]),
+                };
+                let (status, body) = self.process(CommandCode::new(*ins), data);
+                push_frame(rx, &body)?;
+                push_frame(rx, &[0x91, status.as_byte()])
+            }
+            [0x00, ..] => push_frame(rx, &[0x6D, 0x00]),
+            [code, data @ ..] => {
+                let (status, body) = self.process(CommandCode::new(*code), data);
+                push_frame(rx, &[status.as_byte()])?;
+                push_frame(rx, &body)
+            }
+        }
+    }
+}
+
+fn push_frame(rx: &mut Frame, bytes: &[u8]) -> Result<(), Error> {
+    rx.extend_from_slice(bytes)
+        .map_err(|_| Error::ResponseTooLong)
+}
+
+fn split_frames(data: &[u8]) -> Vec<Vec<u8>> {
+    if data.is_empty() {
+        return std::vec![Vec::new()];
+    }
+    data.chunks(MAX_RESPONSE_FRAME_DATA)
+        .map(<[u8]>::to_vec)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use heapless::Vec;
+
+    use crate::mifare::desfire::{
+        application::ApplicationId,
+        client::{AsyncDesfire, Desfire},
+        configuration::{Ats, DefaultKey, PiccConfiguration},
+        crypto::AesCmac,
+        error::Error,
+        file::{AccessCondition, AccessRights, CommunicationMode, FileId},
+        framing::{FrameCodec, NativeFraming, WrappedFraming},
+        kdf::{diversify_aes128_key, DiversificationInput},
+        key::{ApplicationKeyType, Key, KeyAlgorithm, KeyNumber, KeySettings},
+        provider::{ChangeKeyCryptogram, ChangeKeyRequest, CryptoProvider, SoftwareCrypto},
+        rng::FixedRandom,
+        session::Session,
+        sim::{VirtualApplication, VirtualDesfire, VirtualFile, DEFAULT_UID, MEMORY_SIZE},
+        status::Status,
+        transport::{AsyncTransport, Frame, Transport},
+        types::U24,
+    };
+
+    const AID: ApplicationId = ApplicationId::from_bytes([0x01, 0x02, 0x03]);
+    const AES_KEY: [u8; 16] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE,
+        0xFF,
+    ];
+
+    fn key(number: u8) -> KeyNumber {
+        KeyNumber::new(number).unwrap()
+    }
+
+    fn file(number: u8) -> FileId {
+        FileId::new(number).unwrap()
+    }
+
+    fn u24(value: u32) -> U24 {
+        U24::new(value).unwrap()
+    }
+
+    fn rights(read: AccessCondition, write: AccessCondition) -> AccessRights {
+        AccessRights::new(
+            read,
+            write,
+            AccessCondition::Key(key(0)),
+            AccessCondition::Key(key(0)),
+        )
+    }
+
+    fn aes_application() -> VirtualApplication {
+        VirtualApplication::new(AID, KeySettings::new(0x0F, ApplicationKeyType::Aes, 3))
+            .unwrap()
+            .with_key(key(1), Key::Aes128(AES_KEY), 1)
+            .unwrap()
+    }
+
+    fn authenticate_picc<T: Transport, C: FrameCodec>(desfire: &mut Desfire<T, C>) {
+        desfire
+            .authenticate_2tdea(key(0), &[0; 16], &mut random())
+            .unwrap();
+    }
+
+    /// Exposes the virtual card through the async transport interface.
+    struct AsyncCard<'a>(&'a mut VirtualDesfire);
+
+    impl AsyncTransport for AsyncCard<'_> {
+        async fn transceive(&mut self, tx: &[u8], rx: &mut Frame) -> Result<(), Error> {
+            self.0.transceive(tx, rx)
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    fn error_status(result: Result<(), Error>) -> Status {
+        match result {
+            Err(Error::Status(status)) => status,
+            other => panic!("expected a card status, got {other:?}"),
+        }
+    }
+
+    fn random() -> FixedRandom {
+        FixedRandom::new(&[0xA5])
+    }
+
+    /// Provider that only hands out slot indices, as a SAM or HSM would.
+    struct KeyStore(Vec<Key, 4>);
+
+    impl KeyStore {
+        fn key(&self, handle: usize) -> Result<&Key, Error> {
+            self.0.get(handle).ok_or(Error::CryptoProvider)
+        }
+    }
+
+    impl CryptoProvider for KeyStore {
+        type KeyHandle = usize;
+
+        fn algorithm(&self, key: &usize) -> Result<KeyAlgorithm, Error> {
+            SoftwareCrypto.algorithm(self.key(*key)?)
+        }
+
+        fn has_identical_halves(&self, key: &usize) -> Result<bool, Error> {
+            SoftwareCrypto.has_identical_halves(self.key(*key)?)
+        }
+
+        fn cbc_encrypt(&mut self, key: &usize, iv: &[u8], data: &mut [u8]) -> Result<(), Error> {
+            SoftwareCrypto.cbc_encrypt(self.key(*key)?, iv, data)
+        }
+
+        fn cbc_decrypt(&mut self, key: &usize, iv: &[u8], data: &mut [u8]) -> Result<(), Error> {
+            SoftwareCrypto.cbc_decrypt(self.key(*key)?, iv, data)
+        }
+
+        fn cmac(&mut self, key: &usize, data: &[u8]) -> Result<AesCmac, Error> {
+            SoftwareCrypto.cmac(self.key(*key)?, data)
+        }
+
+        fn diversify(
+            &mut self,
+            master: &usize,
+            input: &DiversificationInput,
+        ) -> Result<usize, Error> {
+            let derived = SoftwareCrypto.diversify(self.key(*master)?, input)?;
+            self.0.push(derived).map_err(|_| Error::CryptoProvider)?;
+            Ok(self.0.len() - 1)
+        }
+
+        fn change_key_cryptogram(
+            &mut self,
+            request: &ChangeKeyRequest<'_>,
+            new_key: &usize,
+            old_key: Option<&usize>,
+        ) -> Result<ChangeKeyCryptogram, Error> {
+            let old_key = old_key.map(|old| self.key(*old)).transpose()?;
+            SoftwareCrypto.change_key_cryptogram(request, self.key(*new_key)?, old_key)
+        }
+    }
+
+    #[test]
+    fn reports_version_of_factory_card() {
+        let mut card = VirtualDesfire::new();
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+
+        let version = desfire.get_version().unwrap();
+        assert_eq!(version.uid(), DEFAULT_UID);
+        assert_eq!(version.production_week(), 0x10);
+        assert_eq!(desfire.free_memory().unwrap().as_u32(), MEMORY_SIZE);
+        let mut ids: Vec<ApplicationId, 28> = Vec::new();
+        desfire.get_application_ids(&mut ids).unwrap();
+        assert!(ids.is_empty());
+    }
+
+    #[test]
+    fn creates_application_and_files_with_picc_master_key() {
+        let mut card = VirtualDesfire::new();
+        let mut desfire = Desfire::new(&mut card, WrappedFraming);
+        authenticate_picc(&mut desfire);
+        desfire
+            .create_application(AID, KeySettings::new(0x0F, ApplicationKeyType::Aes, 2))
+            .unwrap();
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_aes(key(0), &[0; 16], &mut random())
+            .unwrap();
+        desfire
+            .create_std_data_file(
+                file(1),
+                CommunicationMode::Enciphered,
+                rights(AccessCondition::Key(key(1)), AccessCondition::Key(key(0))),
+                u24(32),
+            )
+            .unwrap();
+        desfire
+            .write_data_enciphered(file(1), u24(4), b"virtual")
+            .unwrap();
+
+        let app = card.application(AID).unwrap();
+        assert_eq!(app.key_settings().key_count(), 2);
+        let data = app.file(file(1)).unwrap().data().unwrap();
+        assert_eq!(&data[4..11], b"virtual");
+        assert_eq!(card.free_memory(), MEMORY_SIZE - 32);
+    }
+
+    #[test]
+    fn reads_files_in_every_communication_mode() {
+        let free = AccessCondition::Free;
+        let key_1 = AccessCondition::Key(key(1));
+        let mut card = VirtualDesfire::new().with_application(
+            aes_application()
+                .with_file(
+                    file(0),
+                    VirtualFile::std_data(CommunicationMode::Plain, rights(free, free), b"plain"),
+                )
+                .with_file(
+                    file(1),
+                    VirtualFile::std_data(CommunicationMode::Maced, rights(key_1, key_1), b"maced"),
+                )
+                .with_file(
+                    file(2),
+                    VirtualFile::std_data(
+                        CommunicationMode::Enciphered,
+                        rights(key_1, key_1),
+                        b"enciphered",
+                    ),
+                ),
+        );
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        desfire.select_application(AID).unwrap();
+
+        let mut data: Vec<u8, 64> = Vec::new();
+        desfire
+            .read_data(file(0), u24(0), u24(0), &mut data)
+            .unwrap();
+        assert_eq!(data.as_slice(), b"plain");
+
+        desfire
+            .authenticate_aes(key(1), &AES_KEY, &mut random())
+            .unwrap();
+        data.clear();
+        desfire
+            .read_data_maced(file(1), u24(0), u24(5), &mut data)
+            .unwrap();
+        assert_eq!(data.as_slice(), b"maced");
+        data.clear();
+        desfire
+            .read_data_enciphered(file(2), u24(0), u24(0), &mut data)
+            .unwrap();
+        assert_eq!(data.as_slice(), b"enciphered");
+        data.clear();
+        desfire
+            .read_data_enciphered(file(2), u24(2), u24(4), &mut data)
+            .unwrap();
+        assert_eq!(data.as_slice(), b"ciph");
+    }
+
+    #[test]
+    fn async_client_authenticates_and_streams_enciphered_data() {
+        let key_1 = AccessCondition::Key(key(1));
+        let contents: std::vec::Vec<u8> = (0..=255).collect();
+        let mut card = VirtualDesfire::new().with_application(aes_application().with_file(
+            file(0),
+            VirtualFile::std_data(
+                CommunicationMode::Enciphered,
+                rights(key_1, key_1),
+                &[0; 256],
+            ),
+        ));
+        let mut desfire = AsyncDesfire::new(AsyncCard(&mut card), WrappedFraming);
+
+        block_on(async {
+            desfire.select_application(AID).await.unwrap();
+            desfire
+                .authenticate_aes(key(1), &AES_KEY, &mut random())
+                .await
+                .unwrap();
+            desfire
+                .write_data_streamed(file(0), CommunicationMode::Enciphered, u24(0), &contents)
+                .await
+                .unwrap();
+
+            let mut data = std::vec::Vec::new();
+            desfire
+                .read_data_streamed(
+                    file(0),
+                    CommunicationMode::Enciphered,
+                    u24(0),
+                    u24(256),
+                    &mut data,
+                )
+                .await
+                .unwrap();
+            assert_eq!(data, contents);
+        });
+        assert!(desfire.authenticated_session().is_some());
+    }
+
+    #[test]
+    fn async_client_authenticates_with_every_key_family() {
+        let des_key = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        let three_key = [0x42; 24];
+        let three_key_aid = ApplicationId::from_bytes([0x0A, 0x0B, 0x0C]);
+        let mut store = KeyStore(Vec::new());
+        store.0.push(Key::Aes128(AES_KEY)).unwrap();
+        let mut card = VirtualDesfire::new()
+            .with_picc_key(Key::Des(des_key), 0)
+            .with_application(aes_application())
+            .with_application(
+                VirtualApplication::new(
+                    three_key_aid,
+                    KeySettings::new(0x0F, ApplicationKeyType::ThreeKey3Des, 1),
+                )
+                .unwrap()
+                .with_key(key(0), Key::ThreeKey3Des(three_key), 0)
+                .unwrap(),
+            );
+        let mut desfire = AsyncDesfire::new(AsyncCard(&mut card), NativeFraming);
+
+        block_on(async {
+            desfire
+                .authenticate_des(key(0), &des_key, &mut random())
+                .await
+                .unwrap();
+            let mut double = [0; 16];
+            double[..8].copy_from_slice(&des_key);
+            double[8..].copy_from_slice(&des_key);
+            desfire
+                .authenticate_2tdea(key(0), &double, &mut random())
+                .await
+                .unwrap();
+            desfire.get_key_settings().await.unwrap();
+
+            desfire.select_application(three_key_aid).await.unwrap();
+            desfire
+                .authenticate_3tdea(key(0), &three_key, &mut random())
+                .await
+                .unwrap();
+            desfire.get_key_settings().await.unwrap();
+
+            desfire.select_application(AID).await.unwrap();
+            desfire
+                .authenticate_aes(key(0), &[0; 16], &mut random())
+                .await
+                .unwrap();
+            desfire
+                .authenticate_with_provider(key(1), &mut store, &0, &mut random())
+                .await
+                .unwrap();
+            assert_eq!(
+                desfire
+                    .authenticate_aes(key(1), &[0; 16], &mut random())
+                    .await,
+                Err(Error::Status(Status::AuthenticationError))
+            );
+        });
+    }
+
+    #[test]
+    fn async_client_runs_value_and_record_transactions() {
+        let key_0 = AccessCondition::Key(key(0));
+        let mut card = VirtualDesfire::new().with_application(
+            aes_application()
+                .with_file(
+                    file(0),
+                    VirtualFile::value(
+                        CommunicationMode::Maced,
+                        rights(key_0, key_0),
+                        0,
+                        1000,
+                        100,
+                        true,
+                    ),
+                )
+                .with_file(
+                    file(4),
+                    VirtualFile::cyclic_record(
+                        CommunicationMode::Enciphered,
+                        rights(key_0, key_0),
+                        u24(4),
+                        u24(3),
+                    ),
+                ),
+        );
+        let mut desfire = AsyncDesfire::new(AsyncCard(&mut card), WrappedFraming);
+
+        block_on(async {
+            desfire.select_application(AID).await.unwrap();
+            desfire
+                .authenticate_aes(key(0), &[0; 16], &mut random())
+                .await
+                .unwrap();
+
+            let mode = CommunicationMode::Maced;
+            desfire.debit(file(0), mode, 30).await.unwrap();
+            desfire.commit_transaction().await.unwrap();
+            desfire.limited_credit(file(0), mode, 20).await.unwrap();
+            desfire.commit_transaction().await.unwrap();
+            desfire.credit(file(0), mode, 500).await.unwrap();
+            desfire.abort_transaction().await.unwrap();
+            assert_eq!(desfire.get_value(file(0), mode).await.unwrap(), 90);
+
+            let mode = CommunicationMode::Enciphered;
+            for record in [b"aaaa", b"bbbb", b"cccc"] {
+                desfire
+                    .write_record(file(4), mode, u24(0), record)
+                    .await
+                    .unwrap();
+                desfire.commit_transaction().await.unwrap();
+            }
+            let mut data: Vec<u8, 64> = Vec::new();
+            let records = desfire
+                .read_records(file(4), mode, u24(4), u24(0), u24(0), &mut data)
+                .await
+                .unwrap();
+            assert_eq!(records.as_bytes(), b"bbbbcccc");
+            desfire.clear_record_file(file(4)).await.unwrap();
+            desfire.commit_transaction().await.unwrap();
+        });
+
+        let app = card.application(AID).unwrap();
+        assert_eq!(app.file(file(0)).unwrap().current_value(), Some(90));
+        assert_eq!(app.file(file(4)).unwrap().records(), Some(&[][..]));
+    }
+
+    #[test]
+    fn async_client_changes_keys_directly_and_through_a_provider() {
+        let new_key = [0x77; 16];
+        let master = [0x5C; 16];
+        let input = DiversificationInput::new()
+            .with_uid(&DEFAULT_UID)
+            .unwrap()
+            .with_application_id(AID)
+            .unwrap();
+        let mut store = KeyStore(Vec::new());
+        store.0.push(Key::Aes128([0; 16])).unwrap();
+        store.0.push(Key::Aes128(new_key)).unwrap();
+        store.0.push(Key::Aes128(master)).unwrap();
+        let mut card = VirtualDesfire::new().with_application(aes_application());
+        let mut desfire = AsyncDesfire::new(AsyncCard(&mut card), WrappedFraming);
+
+        block_on(async {
+            desfire.select_application(AID).await.unwrap();
+            desfire
+                .authenticate_aes(key(0), &[0; 16], &mut random())
+                .await
+                .unwrap();
+            desfire
+                .change_key_aes(key(1), new_key, 5, Some(AES_KEY))
+                .await
+                .unwrap();
+            let diversified = store.diversify(&2, &input).unwrap();
+            desfire
+                .change_key_with_provider(key(2), &mut store, &diversified, 7, Some(&0))
+                .await
+                .unwrap();
+            desfire
+                .authenticate_with_provider(key(2), &mut store, &diversified, &mut random())
+                .await
+                .unwrap();
+
+            desfire
+                .select_application(ApplicationId::PICC)
+                .await
+                .unwrap();
+            desfire
+                .authenticate_2tdea(key(0), &[0; 16], &mut random())
+                .await
+                .unwrap();
+            desfire.change_picc_key_aes(AES_KEY, 3).await.unwrap();
+            desfire
+                .authenticate_aes(key(0), &AES_KEY, &mut random())
+                .await
+                .unwrap();
+        });
+
+        assert_eq!(*card.picc_key(), Key::Aes128(AES_KEY));
+        let app = card.application(AID).unwrap();
+        assert_eq!(app.key(key(1)), Some(&Key::Aes128(new_key)));
+        assert_eq!(app.key_version(key(1)), Some(5));
+        assert_eq!(
+            app.key(key(2)),
+            Some(&Key::Aes128(diversify_aes128_key(&master, &input).unwrap()))
+        );
+        assert_eq!(app.key_version(key(2)), Some(7));
+    }
+
+    #[test]
+    fn denies_access_without_the_file_key() {
+        let key_1 = AccessCondition::Key(key(1));
+        let mut card = VirtualDesfire::new().with_application(aes_application().with_file(
+            file(0),
+            VirtualFile::std_data(
+                CommunicationMode::Plain,
+                AccessRights::new(key_1, key_1, key_1, key_1),
+                &[0; 8],
+            ),
+        ));
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        desfire.select_application(AID).unwrap();
+
+        let mut data: Vec<u8, 16> = Vec::new();
+        assert_eq!(
+            desfire.read_data(file(0), u24(0), u24(0), &mut data),
+            Err(Error::Status(Status::AuthenticationError))
+        );
+        desfire
+            .authenticate_aes(key(0), &[0; 16], &mut random())
+            .unwrap();
+        assert_eq!(
+            error_status(desfire.write_data(file(0), u24(0), &[1])),
+            Status::PermissionDenied
+        );
+        // The error ended the session on the card.
+        desfire.clear_session();
+        assert_eq!(
+            error_status(desfire.write_data(file(0), u24(0), &[1])),
+            Status::AuthenticationError
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let mut card = VirtualDesfire::new().with_application(aes_application());
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        desfire.select_application(AID).unwrap();
+
+        assert_eq!(
+            desfire.authenticate_aes(key(1), &[0; 16], &mut random()),
+            Err(Error::Status(Status::AuthenticationError))
+        );
+        assert_eq!(
+            desfire.authenticate_2tdea(key(1), &[0; 16], &mut random()),
+            Err(Error::Status(Status::AuthenticationError))
+        );
+    }
+
+    #[test]
+    fn streams_large_enciphered_writes_in_chained_frames() {
+        let key_0 = AccessCondition::Key(key(0));
+        let mut card = VirtualDesfire::new().with_application(aes_application().with_file(
+            file(0),
+            VirtualFile::std_data(
+                CommunicationMode::Enciphered,
+                rights(key_0, key_0),
+                &[0; 512],
+            ),
+        ));
+        let mut desfire = Desfire::new(&mut card, WrappedFraming);
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_aes(key(0), &[0; 16], &mut random())
+            .unwrap();
+
+        let written: std::vec::Vec<u8> = (0..=255).cycle().take(400).collect();
+        desfire
+            .write_data_streamed(file(0), CommunicationMode::Enciphered, u24(50), &written)
+            .unwrap();
+        let mut read = std::vec::Vec::new();
+        desfire
+            .read_data_streamed(
+                file(0),
+                CommunicationMode::Enciphered,
+                u24(50),
+                u24(400),
+                &mut read,
+            )
+            .unwrap();
+
+        assert_eq!(read, written);
+        let data = card.application(AID).unwrap().file(file(0)).unwrap().data();
+        assert_eq!(&data.unwrap()[50..450], written.as_slice());
+    }
+
+    #[test]
+    fn commits_and_aborts_value_and_backup_transactions() {
+        let key_0 = AccessCondition::Key(key(0));
+        let mut card = VirtualDesfire::new().with_application(
+            aes_application()
+                .with_file(
+                    file(0),
+                    VirtualFile::value(
+                        CommunicationMode::Maced,
+                        rights(key_0, key_0),
+                        0,
+                        1000,
+                        100,
+                        true,
+                    ),
+                )
+                .with_file(
+                    file(1),
+                    VirtualFile::backup_data(
+                        CommunicationMode::Enciphered,
+                        rights(key_0, key_0),
+                        &[0; 4],
+                    ),
+                ),
+        );
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_aes(key(0), &[0; 16], &mut random())
+            .unwrap();
+
+        let mode = CommunicationMode::Maced;
+        desfire.debit(file(0), mode, 30).unwrap();
+        desfire
+            .write_data_enciphered(file(1), u24(0), b"tap")
+            .unwrap();
+        assert_eq!(desfire.get_value(file(0), mode).unwrap(), 100);
+        desfire.commit_transaction().unwrap();
+        assert_eq!(desfire.get_value(file(0), mode).unwrap(), 70);
+
+        desfire.limited_credit(file(0), mode, 20).unwrap();
+        desfire.commit_transaction().unwrap();
+        assert_eq!(desfire.get_value(file(0), mode).unwrap(), 90);
+
+        desfire.credit(file(0), mode, 500).unwrap();
+        desfire.abort_transaction().unwrap();
+        assert_eq!(desfire.get_value(file(0), mode).unwrap(), 90);
+        assert_eq!(
+            error_status(desfire.debit(file(0), mode, 91)),
+            Status::BoundaryError
+        );
+
+        let app = card.application(AID).unwrap();
+        assert_eq!(app.file(file(0)).unwrap().current_value(), Some(90));
+        assert_eq!(app.file(file(1)).unwrap().data(), Some(&b"tap\0"[..]));
+    }
+
+    fn transaction_card(application: VirtualApplication) -> VirtualDesfire {
+        let key_0 = AccessCondition::Key(key(0));
+        VirtualDesfire::new().with_application(
+            application
+                .with_file(
+                    file(0),
+                    VirtualFile::value(
+                        CommunicationMode::Maced,
+                        rights(key_0, key_0),
+                        0,
+                        1000,
+                        100,
+                        false,
+                    ),
+                )
+                .with_file(
+                    file(1),
+                    VirtualFile::backup_data(
+                        CommunicationMode::Enciphered,
+                        rights(key_0, key_0),
+                        &[0; 4],
+                    ),
+                )
+                .with_file(
+                    file(2),
+                    VirtualFile::linear_record(
+                        CommunicationMode::Plain,
+                        rights(key_0, key_0),
+                        u24(4),
+                        u24(4),
+                    ),
+                ),
+        )
+    }
+
+    #[test]
+    fn transaction_guard_commits_or_aborts_under_aes_session() {
+        let mut card = transaction_card(aes_application());
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_aes(key(0), &[0; 16], &mut random())
+            .unwrap();
+
+        let mut transaction = desfire.begin_transaction();
+        transaction
+            .debit(file(0), CommunicationMode::Maced, 30)
+            .unwrap();
+        transaction
+            .write_data(file(1), CommunicationMode::Enciphered, u24(0), b"tap")
+            .unwrap();
+        transaction
+            .write_record(file(2), CommunicationMode::Plain, u24(0), b"one.")
+            .unwrap();
+        transaction
+            .debit(file(0), CommunicationMode::Maced, 10)
+            .unwrap();
+        assert_eq!(transaction.touched_files(), [file(0), file(1), file(2)]);
+        assert_eq!(
+            transaction.commit().unwrap().as_slice(),
+            [file(0), file(1), file(2)]
+        );
+
+        // An early return drops the guard, which aborts the pending credit.
+        let early_return = |desfire: &mut Desfire<_, _>| -> Result<(), Error> {
+            let mut transaction = desfire.begin_transaction();
+            transaction.credit(file(0), CommunicationMode::Maced, 500)?;
+            transaction.write_record(file(2), CommunicationMode::Plain, u24(0), b"toolong")?;
+            transaction.commit().map(drop)
+        };
+        assert!(early_return(&mut desfire).is_err());
+        assert_eq!(*desfire.session(), Session::Unauthenticated);
+        desfire
+            .authenticate_aes(key(0), &[0; 16], &mut random())
+            .unwrap();
+        assert_eq!(
+            desfire
+                .get_value(file(0), CommunicationMode::Maced)
+                .unwrap(),
+            60
+        );
+
+        let mut transaction = desfire.begin_transaction();
+        transaction
+            .credit(file(0), CommunicationMode::Maced, 5)
+            .unwrap();
+        // A refused amount never reaches the card, so the pending credit stays.
+        assert_eq!(
+            transaction.debit(file(0), CommunicationMode::Maced, -5),
+            Err(Error::InvalidValueAmount(-5))
+        );
+        // So does a write running past the end of the offset range.
+        assert_eq!(
+            transaction.write_data(
+                file(1),
+                CommunicationMode::Enciphered,
+                u24(0xFF_FFFF),
+                b"tap"
+            ),
+            Err(Error::CommandTooLong)
+        );
+        assert_eq!(transaction.touched_files(), [file(0)]);
+        drop(transaction);
+        assert!(matches!(desfire.session(), Session::Authenticated(_)));
+
+        let app = card.application(AID).unwrap();
+        assert_eq!(app.file(file(0)).unwrap().current_value(), Some(60));
+        assert_eq!(app.file(file(1)).unwrap().data(), Some(&b"tap\0"[..]));
+        assert_eq!(app.file(file(2)).unwrap().records().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn transaction_guard_aborts_failed_write_under_legacy_session() {
+        let application = VirtualApplication::new(
+            AID,
+            KeySettings::new(0x0F, ApplicationKeyType::TwoKey3Des, 1),
+        )
+        .unwrap();
+        let mut card = transaction_card(application);
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_des(key(0), &[0; 8], &mut random())
+            .unwrap();
+
+        let mut transaction = desfire.begin_transaction();
+        transaction
+            .write_data(file(1), CommunicationMode::Enciphered, u24(0), b"tap")
+            .unwrap();
+        assert_eq!(transaction.touched_files(), [file(1)]);
+        assert_eq!(
+            transaction.debit(file(0), CommunicationMode::Maced, 101),
+            Err(Error::Status(Status::BoundaryError))
+        );
+        assert!(transaction.touched_files().is_empty());
+        assert!(transaction.commit().unwrap().is_empty());
+
+        let app = card.application(AID).unwrap();
+        assert_eq!(app.file(file(0)).unwrap().current_value(), Some(100));
+        assert_eq!(app.file(file(1)).unwrap().data(), Some(&[0; 4][..]));
+    }
+
+    #[test]
+    fn file_handles_pick_communication_mode_from_access_rights() {
+        let key_0 = AccessCondition::Key(key(0));
+        let key_1 = AccessCondition::Key(key(1));
+        let mut card = VirtualDesfire::new().with_application(
+            aes_application()
+                .with_file(
+                    file(0),
+                    VirtualFile::std_data(
+                        CommunicationMode::Enciphered,
+                        rights(AccessCondition::Free, key_1),
+                        &[0; 4],
+                    ),
+                )
+                .with_file(
+                    file(1),
+                    VirtualFile::value(
+                        CommunicationMode::Maced,
+                        rights(key_1, key_1),
+                        0,
+                        1000,
+                        100,
+                        false,
+                    ),
+                )
+                .with_file(
+                    file(2),
+                    VirtualFile::linear_record(
+                        CommunicationMode::Enciphered,
+                        AccessRights::new(key_1, key_1, key_0, key_0),
+                        u24(4),
+                        u24(4),
+                    ),
+                ),
+        );
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        desfire.select_application(AID).unwrap();
+
+        // Free read access is plain even though the file is enciphered.
+        let mut handle = desfire.open_file(file(0)).unwrap();
+        let mut data: Vec<u8, 4> = Vec::new();
+        handle.read_all(&mut data).unwrap();
+        assert_eq!(data.as_slice(), [0; 4]);
+        assert_eq!(
+            handle.write(u24(0), b"tap"),
+            Err(Error::MissingFileKey(key(1)))
+        );
+        assert_eq!(handle.value(), Err(Error::InvalidFileType(0x00)));
+
+        desfire
+            .authenticate_aes(key(1), &AES_KEY, &mut random())
+            .unwrap();
+        let mut handle = desfire.open_file(file(0)).unwrap();
+        handle.write(u24(0), b"tap").unwrap();
+        let mut data: Vec<u8, 4> = Vec::new();
+        handle.read(u24(1), u24(3), &mut data).unwrap();
+        assert_eq!(data.as_slice(), b"ap\0");
+
+        let mut handle = desfire.open_file(file(1)).unwrap();
+        handle.debit(30).unwrap();
+        assert_eq!(handle.credit(5), Err(Error::MissingFileKey(key(0))));
+        assert_eq!(handle.debit(-5), Err(Error::InvalidValueAmount(-5)));
+
+        let mut handle = desfire.open_file(file(2)).unwrap();
+        handle.write_record(u24(0), b"one.").unwrap();
+        assert_eq!(handle.clear_records(), Err(Error::MissingFileKey(key(0))));
+        desfire.commit_transaction().unwrap();
+
+        // The session survives the refused commands, which never reach the card.
+        let mut handle = desfire.open_file(file(1)).unwrap();
+        assert_eq!(handle.value(), Ok(70));
+        let mut handle = desfire.open_file(file(2)).unwrap();
+        let mut data: Vec<u8, 4> = Vec::new();
+        let records = handle.read_records(u24(0), u24(0), &mut data).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records.get(0), Some(&b"one."[..]));
+    }
+
+    #[test]
+    fn file_handle_refuses_access_the_rights_never_grant() {
+        let never = AccessCondition::Never;
+        let mut card = VirtualDesfire::new().with_application(aes_application().with_file(
+            file(0),
+            VirtualFile::std_data(
+                CommunicationMode::Plain,
+                AccessRights::new(AccessCondition::Free, never, never, never),
+                &[0; 4],
+            ),
+        ));
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_aes(key(0), &[0; 16], &mut random())
+            .unwrap();
+
+        let mut handle = desfire.open_file(file(0)).unwrap();
+        assert_eq!(handle.write(u24(0), b"tap"), Err(Error::FileAccessDenied));
+        assert!(matches!(desfire.session(), Session::Authenticated(_)));
+        assert_eq!(
+            card.application(AID).unwrap().file(file(0)).unwrap().data(),
+            Some(&[0; 4][..])
+        );
+    }
+
+    #[test]
+    fn writes_and_reads_cyclic_records() {
+        let free = AccessCondition::Free;
+        let mut card = VirtualDesfire::new().with_application(aes_application().with_file(
+            file(4),
+            VirtualFile::cyclic_record(
+                CommunicationMode::Plain,
+                AccessRights::new(free, free, free, free),
+                u24(4),
+                u24(3),
+            ),
+        ));
+        let mut desfire = Desfire::new(&mut card, WrappedFraming);
+        desfire.select_application(AID).unwrap();
+
+        let mode = CommunicationMode::Plain;
+        for record in [b"aaaa", b"bbbb", b"cccc"] {
+            desfire.write_record(file(4), mode, u24(0), record).unwrap();
+            desfire.commit_transaction().unwrap();
+        }
+        let mut data: Vec<u8, 64> = Vec::new();
+        let records = desfire
+            .read_records(file(4), mode, u24(4), u24(0), u24(0), &mut data)
+            .unwrap();
+        assert_eq!(records.as_bytes(), b"bbbbcccc");
+
+        desfire.clear_record_file(file(4)).unwrap();
+        desfire.commit_transaction().unwrap();
+        assert_eq!(
+            card.application(AID)
+                .unwrap()
+                .file(file(4))
+                .unwrap()
+                .records(),
+            Some(&[][..])
+        );
+    }
+
+    #[test]
+    fn authenticates_with_des_family_keys() {
+        let des_key = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        let three_key = [0x42; 24];
+        let three_key_aid = ApplicationId::from_bytes([0x0A, 0x0B, 0x0C]);
+        let mut card = VirtualDesfire::new()
+            .with_picc_key(Key::Des(des_key), 0)
+            .with_application(
+                VirtualApplication::new(
+                    three_key_aid,
+                    KeySettings::new(0x0F, ApplicationKeyType::ThreeKey3Des, 1),
+                )
+                .unwrap()
+                .with_key(key(0), Key::ThreeKey3Des(three_key), 0)
+                .unwrap(),
+            );
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+
+        desfire
+            .authenticate_des(key(0), &des_key, &mut random())
+            .unwrap();
+        desfire.get_key_settings().unwrap();
+        let mut double = [0; 16];
+        double[..8].copy_from_slice(&des_key);
+        double[8..].copy_from_slice(&des_key);
+        desfire
+            .authenticate_2tdea(key(0), &double, &mut random())
+            .unwrap();
+        desfire.get_key_settings().unwrap();
+
+        desfire.select_application(three_key_aid).unwrap();
+        desfire
+            .authenticate_3tdea(key(0), &three_key, &mut random())
+            .unwrap();
+        let settings = desfire.get_key_settings().unwrap();
+        assert_eq!(settings.key_type(), ApplicationKeyType::ThreeKey3Des);
+    }
+
+    #[test]
+    fn changes_application_and_picc_keys() {
+        let new_key = [0x77; 16];
+        let mut card = VirtualDesfire::new().with_application(aes_application());
+        let mut desfire = Desfire::new(&mut card, WrappedFraming);
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_aes(key(0), &[0; 16], &mut random())
+            .unwrap();
+        desfire
+            .change_key_aes(key(1), new_key, 5, Some(AES_KEY))
+            .unwrap();
+        desfire
+            .authenticate_aes(key(1), &new_key, &mut random())
+            .unwrap();
+
+        desfire.select_application(ApplicationId::PICC).unwrap();
+        authenticate_picc(&mut desfire);
+        desfire.change_picc_key_aes(AES_KEY, 3).unwrap();
+        desfire
+            .authenticate_aes(key(0), &AES_KEY, &mut random())
+            .unwrap();
+
+        assert_eq!(*card.picc_key(), Key::Aes128(AES_KEY));
+        let app = card.application(AID).unwrap();
+        assert_eq!(app.key(key(1)), Some(&Key::Aes128(new_key)));
+        assert_eq!(app.key_version(key(1)), Some(5));
+    }
+
+    #[test]
+    fn changes_and_uses_keys_through_a_provider() {
+        let master = [0x5C; 16];
+        let three_key = [0x42; 24];
+        let input = DiversificationInput::new()
+            .with_uid(&DEFAULT_UID)
+            .unwrap()
+            .with_application_id(AID)
+            .unwrap();
+        let mut store = KeyStore(Vec::new());
+        store.0.push(Key::Aes128([0; 16])).unwrap();
+        store.0.push(Key::Aes128(AES_KEY)).unwrap();
+        store.0.push(Key::Aes128(master)).unwrap();
+        let mut card = VirtualDesfire::new().with_application(aes_application());
+        let mut desfire = Desfire::new(&mut card, WrappedFraming);
+
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_with_provider(key(0), &mut store, &0, &mut random())
+            .unwrap();
+        let diversified = store.diversify(&2, &input).unwrap();
+        desfire
+            .change_key_with_provider(key(1), &mut store, &diversified, 7, Some(&1))
+            .unwrap();
+        desfire
+            .authenticate_with_provider(key(1), &mut store, &diversified, &mut random())
+            .unwrap();
+        assert_eq!(
+            desfire.change_key_with_provider(key(2), &mut store, &diversified, 0, None),
+            Err(Error::MissingOldKey)
+        );
+
+        store.0[0] = Key::ThreeKey3Des(three_key);
+        desfire.select_application(ApplicationId::PICC).unwrap();
+        authenticate_picc(&mut desfire);
+        desfire
+            .change_picc_key_with_provider(&mut store, &0, 0)
+            .unwrap();
+        desfire
+            .authenticate_with_provider(key(0), &mut store, &0, &mut random())
+            .unwrap();
+
+        let app = card.application(AID).unwrap();
+        assert_eq!(
+            app.key(key(1)),
+            Some(&Key::Aes128(diversify_aes128_key(&master, &input).unwrap()))
+        );
+        assert_eq!(app.key_version(key(1)), Some(7));
+        assert_eq!(*card.picc_key(), Key::ThreeKey3Des(three_key));
+    }
+
+    #[test]
+    fn applies_picc_configuration() {
+        let default_key = [0x3C; 16];
+        let mut card = VirtualDesfire::new();
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        authenticate_picc(&mut desfire);
+        desfire
+            .set_default_key(DefaultKey::new(Key::Aes128(default_key), 2))
+            .unwrap();
+        desfire
+            .set_ats(&Ats::new(&[0x05, 0x78, 0x77, 0x71, 0x02]).unwrap())
+            .unwrap();
+        desfire
+            .set_picc_configuration(PiccConfiguration::new().with_format_disabled(true))
+            .unwrap();
+        desfire
+            .create_application(AID, KeySettings::new(0x0F, ApplicationKeyType::Aes, 1))
+            .unwrap();
+        assert_eq!(
+            error_status(desfire.format_picc()),
+            Status::PermissionDenied
+        );
+
+        assert!(card.configuration().format_disabled());
+        assert_eq!(card.ats(), [0x05, 0x78, 0x77, 0x71, 0x02]);
+        let app = card.application(AID).unwrap();
+        assert_eq!(app.key(key(0)), Some(&Key::Aes128(default_key)));
+        assert_eq!(app.key_version(key(0)), Some(2));
+    }
+
+    #[test]
+    fn formats_card_and_deletes_applications() {
+        let mut card = VirtualDesfire::new()
+            .with_application(aes_application())
+            .with_application(
+                VirtualApplication::new(
+                    ApplicationId::from_bytes([0x0A, 0x0B, 0x0C]),
+                    KeySettings::new(0x0F, ApplicationKeyType::Aes, 1),
+                )
+                .unwrap(),
+            );
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+        authenticate_picc(&mut desfire);
+        desfire.delete_application(AID).unwrap();
+        assert_eq!(
+            error_status(desfire.delete_application(AID)),
+            Status::ApplicationNotFound
+        );
+        authenticate_picc(&mut desfire);
+        desfire.format_picc().unwrap();
+
+        assert_eq!(card.application_ids().count(), 0);
+        assert_eq!(card.free_memory(), MEMORY_SIZE);
+    }
+
+    #[test]
+    fn rejects_iso_apdus_and_ev2_commands() {
+        let mut card = VirtualDesfire::new();
+        let mut rx = crate::mifare::desfire::transport::Frame::new();
+        card.transceive(&[0x00, 0xA4, 0x04, 0x00, 0x00], &mut rx)
+            .unwrap();
+        assert_eq!(rx.as_slice(), [0x6D, 0x00]);
+        card.transceive(&[0x71, 0x00, 0x00], &mut rx).unwrap();
+        assert_eq!(rx.as_slice(), [Status::IllegalCommandCode.as_byte()]);
+    }
+}
@@ -0,0 +1,209 @@
+//! Scoped `DESFire` transactions.
+//!
+//! Writes to backup data, value and record files stay pending on the card
+//! until `CommitTransaction`. A [`Transaction`] borrows the client for the
+//! length of one transaction and aborts it unless it is explicitly committed,
+//! so an early return cannot leave changes half-applied.
+
+use heapless::Vec;
+
+use crate::mifare::desfire::{
+    client::{value_amount, Desfire},
+    error::Error,
+    file::{CommunicationMode, FileId},
+    framing::FrameCodec,
+    transport::Transport,
+    types::U24,
+};
+
+/// Most distinct files one transaction can touch.
+pub const MAX_TRANSACTION_FILES: usize = FileId::MAX as usize + 1;
+
+/// A pending transaction on the selected application.
+///
+/// Created by [`Desfire::begin_transaction`]. Every write goes through the
+/// client's secure messaging, so it works under any EV1 or legacy session.
+/// A write the card fails aborts the transaction straight away and, like the
+/// card, drops the session. A write refused locally, such as a non-positive
+/// value amount or an oversized payload, is never sent and leaves the
+/// transaction as it was. Dropping the guard without
+/// [`Self::commit`] aborts whatever is still pending.
+///
+/// Only the blocking client has a guard, since `Drop` cannot wait for an
+/// async abort.
+pub struct Transaction<'a, T, C>
+where
+    T: Transport,
+    C: FrameCodec,
+{
+    desfire: &'a mut Desfire<T, C>,
+    touched: Vec<FileId, MAX_TRANSACTION_FILES>,
+}
+
+impl<'a, T, C> Transaction<'a, T, C>
+where
+    T: Transport,
+    C: FrameCodec,
+{
+    pub(crate) const fn new(desfire: &'a mut Desfire<T, C>) -> Self {
+        Self {
+            desfire,
+            touched: Vec::new(),
+        }
+    }
+}
+
+impl<T, C> Transaction<'_, T, C>
+where
+    T: Transport,
+    C: FrameCodec,
+{
+    /// Files written since the transaction began, in first-write order.
+    pub fn touched_files(&self) -> &[FileId] {
+        &self.touched
+    }
+
+    /// Writes `data` to a backup data file.
+    pub fn write_data(
+        &mut self,
+        file_id: FileId,
+        communication_mode: CommunicationMode,
+        offset: U24,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let result = self
+            .desfire
+            .write_data_streamed(file_id, communication_mode, offset, data);
+        self.record(file_id, result)
+    }
+
+    /// Increases the value of a value file.
+    pub fn credit(
+        &mut self,
+        file_id: FileId,
+        communication_mode: CommunicationMode,
+        amount: i32,
+    ) -> Result<(), Error> {
+        value_amount(amount)?;
+        let result = self.desfire.credit(file_id, communication_mode, amount);
+        self.record(file_id, result)
+    }
+
+    /// Decreases the value of a value file.
+    pub fn debit(
+        &mut self,
+        file_id: FileId,
+        communication_mode: CommunicationMode,
+        amount: i32,
+    ) -> Result<(), Error> {
+        value_amount(amount)?;
+        let result = self.desfire.debit(file_id, communication_mode, amount);
+        self.record(file_id, result)
+    }
+
+    /// Increases a value file by at most the last committed debit.
+    pub fn limited_credit(
+        &mut self,
+        file_id: FileId,
+        communication_mode: CommunicationMode,
+        amount: i32,
+    ) -> Result<(), Error> {
+        value_amount(amount)?;
+        let result = self
+            .desfire
+            .limited_credit(file_id, communication_mode, amount);
+        self.record(file_id, result)
+    }
+
+    /// Appends a record to a linear or cyclic record file.
+    pub fn write_record(
+        &mut self,
+        file_id: FileId,
+        communication_mode: CommunicationMode,
+        offset: U24,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let result = self
+            .desfire
+            .write_record(file_id, communication_mode, offset, data);
+        self.record(file_id, result)
+    }
+
+    /// Clears all records from a linear or cyclic record file.
+    pub fn clear_record_file(&mut self, file_id: FileId) -> Result<(), Error> {
+        let result = self.desfire.clear_record_file(file_id);
+        self.record(file_id, result)
+    }
+
+    /// Commits every pending write and returns the files it touched.
+    ///
+    /// If the card refuses the commit, the transaction is aborted as well.
+    pub fn commit(mut self) -> Result<Vec<FileId, MAX_TRANSACTION_FILES>, Error> {
+        let touched = core::mem::take(&mut self.touched);
+        if let Err(error) = self.desfire.commit_transaction() {
+            // The commit error is the one worth reporting.
+            let _ = self.desfire.abort_transaction();
+            return Err(error);
+        }
+        Ok(touched)
+    }
+
+    /// Discards every pending write.
+    pub fn abort(mut self) -> Result<(), Error> {
+        self.touched.clear();
+        self.desfire.abort_transaction()
+    }
+
+    /// Tracks a successful write, or aborts after a failed one.
+    fn record(&mut self, file_id: FileId, result: Result<(), Error>) -> Result<(), Error> {
+        match result {
+            Ok(()) => {
+                if !self.touched.contains(&file_id) {
+                    self.touched.push(file_id).expect("one slot per file id");
+                }
+                Ok(())
+            }
+            Err(error) if from_exchange(error) => {
+                self.touched.clear();
+                // The card drops its session on a failed command, and the
+                // write may have left earlier writes pending.
+                self.desfire.clear_session();
+                let _ = self.desfire.abort_transaction();
+                Err(error)
+            }
+            // Refused before anything reached the card.
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Whether `error` was raised by a card exchange rather than a local check.
+const fn from_exchange(error: Error) -> bool {
+    matches!(
+        error,
+        Error::Transport
+            | Error::Status(_)
+            | Error::IsoStatus(_)
+            | Error::MalformedResponse
+            | Error::InvalidWrappedResponse
+            | Error::ResponseTooLong
+            | Error::TooManyAdditionalFrames
+            | Error::InvalidResponseLength
+            | Error::AuthenticationFailed
+            | Error::InvalidMac
+            | Error::InvalidCrc
+            | Error::InvalidPadding
+    )
+}
+
+impl<T, C> Drop for Transaction<'_, T, C>
+where
+    T: Transport,
+    C: FrameCodec,
+{
+    fn drop(&mut self) {
+        if !self.touched.is_empty() {
+            let _ = self.desfire.abort_transaction();
+        }
+    }
+}
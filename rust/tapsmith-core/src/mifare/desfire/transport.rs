@@ -24,3 +24,22 @@ where
         (**self).transceive(tx, rx)
     }
 }
+
+/// Non-blocking counterpart of [`Transport`].
+///
+/// Suited to readers whose I/O completes asynchronously, such as Android NFC
+/// callbacks or network-attached readers. The trait has no `Send` bound on its
+/// futures, so it works with single-threaded `no_std` executors like Embassy.
+#[allow(async_fn_in_trait)]
+pub trait AsyncTransport {
+    async fn transceive(&mut self, tx: &[u8], rx: &mut Frame) -> Result<(), Error>;
+}
+
+impl<T> AsyncTransport for &mut T
+where
+    T: AsyncTransport + ?Sized,
+{
+    async fn transceive(&mut self, tx: &[u8], rx: &mut Frame) -> Result<(), Error> {
+        (**self).transceive(tx, rx).await
+    }
+}
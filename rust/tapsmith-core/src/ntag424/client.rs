@@ -0,0 +1,323 @@
+use heapless::Vec;
+
+use crate::{
+    mifare::desfire::{
+        client::{
+            read_data_command_data, stream_end, write_data_command_header, Desfire,
+            STREAM_CHUNK_SIZE,
+        },
+        command::{Command, CommandCode},
+        error::Error,
+        executor::Executor,
+        file::{CommunicationMode, DataSink},
+        framing::WrappedFraming,
+        iso::{DfName, IsoSelect},
+        key::KeyNumber,
+        originality::SIGNATURE_LEN,
+        rng::RandomSource,
+        session::{Ev2Session, Session},
+        transport::{Transport, MAX_FRAME_SIZE},
+        types::U24,
+        version::VersionInfo,
+    },
+    ntag424::{
+        file::{Ntag424File, Ntag424FileSettings},
+        NDEF_DF_NAME,
+    },
+};
+
+/// High-level NTAG 424 DNA command client.
+///
+/// Commands go through the regular [`Desfire`] client over
+/// [`WrappedFraming`], so EV2 secure messaging is shared with the `DESFire`
+/// and Light clients.
+pub struct Ntag424<T> {
+    desfire: Desfire<T, WrappedFraming>,
+}
+
+impl<T> Ntag424<T>
+where
+    T: Transport,
+{
+    /// Creates a client from a byte transport.
+    pub const fn new(transport: T) -> Self {
+        Self {
+            desfire: Desfire::new(transport, WrappedFraming),
+        }
+    }
+
+    /// Consumes the client and returns the command executor.
+    pub fn into_executor(self) -> Executor<T, WrappedFraming> {
+        self.desfire.into_executor()
+    }
+
+    /// Current authentication session.
+    pub const fn session(&self) -> &Session {
+        self.desfire.session()
+    }
+
+    /// Selects the NDEF application with `ISOSelectFile` by DF name.
+    pub fn select_application(&mut self) -> Result<(), Error> {
+        let name = DfName::new(&NDEF_DF_NAME)?;
+        self.desfire.iso_select_file(&IsoSelect::DfName(name))
+    }
+
+    /// Reads the chained `GetVersion` response.
+    pub fn get_version(&mut self) -> Result<VersionInfo, Error> {
+        self.desfire.get_version()
+    }
+
+    /// Reads the real UID; requires authentication.
+    pub fn get_card_uid(&mut self) -> Result<[u8; 7], Error> {
+        self.desfire.get_card_uid()
+    }
+
+    /// Starts an EV2 session with one of the five application keys.
+    pub fn authenticate_ev2_first<R: RandomSource + ?Sized>(
+        &mut self,
+        key_number: KeyNumber,
+        key: &[u8; 16],
+        random: &mut R,
+    ) -> Result<Ev2Session, Error> {
+        self.desfire.authenticate_ev2_first(key_number, key, random)
+    }
+
+    /// Reads the version of one application key.
+    pub fn get_key_version(&mut self, key_number: KeyNumber) -> Result<u8, Error> {
+        self.desfire.get_key_version(key_number)
+    }
+
+    /// Changes one application key; requires the master key session.
+    ///
+    /// `old_key` is required unless `key_number` is the authenticated key.
+    pub fn change_key(
+        &mut self,
+        key_number: KeyNumber,
+        new_key: [u8; 16],
+        key_version: u8,
+        old_key: Option<[u8; 16]>,
+    ) -> Result<(), Error> {
+        self.desfire
+            .change_key_aes(key_number, new_key, key_version, old_key)
+    }
+
+    /// Reads the settings of one file, including its SDM configuration.
+    pub fn get_file_settings(&mut self, file: Ntag424File) -> Result<Ntag424FileSettings, Error> {
+        let command = Command::new(CommandCode::GET_FILE_SETTINGS, &[file.file_id().as_byte()])?;
+        let mut data: Vec<u8, 64> = Vec::new();
+        self.desfire.execute_query(&command, &mut data)?;
+        Ntag424FileSettings::parse(&data)
+    }
+
+    /// Changes the communication mode, access rights and SDM settings of one file.
+    ///
+    /// The size in `settings` is ignored. The command is always sent
+    /// enciphered, so it needs a session with the file's change key.
+    pub fn change_file_settings(
+        &mut self,
+        file: Ntag424File,
+        settings: &Ntag424FileSettings,
+    ) -> Result<(), Error> {
+        let payload = settings.encode_change()?;
+        self.desfire.execute_write_command(
+            CommandCode::CHANGE_FILE_SETTINGS,
+            CommunicationMode::Enciphered,
+            &[file.file_id().as_byte()],
+            &payload,
+        )
+    }
+
+    /// Streams `length` bytes of one file into `sink`.
+    ///
+    /// `communication_mode` must match the file settings. Reads of a file
+    /// with SDM enabled return the static contents, not the mirrored data.
+    pub fn read_data<S: DataSink>(
+        &mut self,
+        file: Ntag424File,
+        communication_mode: CommunicationMode,
+        offset: U24,
+        length: U24,
+        sink: &mut S,
+    ) -> Result<(), Error> {
+        let end = stream_end(offset, length.as_u32())?;
+        let mut position = offset.as_u32();
+        let mut chunk: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+
+        while position < end {
+            let chunk_length = (end - position).min(STREAM_CHUNK_SIZE);
+            let command_data = read_data_command_data(
+                file.file_id(),
+                U24::new(position).expect("stream position checked against U24 range"),
+                U24::new(chunk_length).expect("chunk length fits U24"),
+            )?;
+            let command = Command::new(CommandCode::READ_DATA_ISO, command_data.as_slice())?;
+            let expected = usize::try_from(chunk_length).expect("chunk length fits usize");
+            self.desfire.execute_read_command(
+                &command,
+                communication_mode,
+                expected,
+                &mut chunk,
+            )?;
+            if chunk.len() != expected {
+                return Err(Error::InvalidResponseLength);
+            }
+
+            sink.write_chunk(chunk.as_slice())?;
+            position += chunk_length;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to one file.
+    ///
+    /// `communication_mode` must match the file settings.
+    pub fn write_data(
+        &mut self,
+        file: Ntag424File,
+        communication_mode: CommunicationMode,
+        offset: U24,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let length = u32::try_from(data.len()).map_err(|_| Error::CommandTooLong)?;
+        stream_end(offset, length)?;
+        let chunk_size = usize::try_from(STREAM_CHUNK_SIZE).expect("chunk size fits usize");
+        let mut position = offset.as_u32();
+
+        for chunk in data.chunks(chunk_size) {
+            let header = write_data_command_header(
+                file.file_id(),
+                U24::new(position).expect("stream position checked against U24 range"),
+                chunk,
+            )?;
+            self.desfire.execute_write_command(
+                CommandCode::WRITE_DATA_ISO,
+                communication_mode,
+                header.as_slice(),
+                chunk,
+            )?;
+            position += u32::try_from(chunk.len()).expect("chunk length fits u32");
+        }
+        Ok(())
+    }
+
+    /// Reads the NXP originality signature over the UID.
+    ///
+    /// Sent plain without a session and enciphered under one, as the tag
+    /// requires.
+    pub fn read_sig(&mut self) -> Result<[u8; SIGNATURE_LEN], Error> {
+        self.desfire.read_sig()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use crate::{
+        mifare::desfire::{
+            error::Error,
+            file::{AccessCondition, AccessRights, CommunicationMode},
+            key::KeyNumber,
+            transport::{Frame, Transport},
+            types::U24,
+        },
+        ntag424::{Ntag424, Ntag424File, Ntag424FileSettings, SIGNATURE_LEN},
+    };
+
+    struct MockTransport<const N: usize> {
+        exchanges: [(&'static [u8], &'static [u8]); N],
+        index: usize,
+    }
+
+    impl<const N: usize> MockTransport<N> {
+        const fn new(exchanges: [(&'static [u8], &'static [u8]); N]) -> Self {
+            Self {
+                exchanges,
+                index: 0,
+            }
+        }
+    }
+
+    impl<const N: usize> Transport for MockTransport<N> {
+        fn transceive(&mut self, tx: &[u8], rx: &mut Frame) -> Result<(), Error> {
+            let (expected_tx, response) = self.exchanges[self.index];
+            self.index += 1;
+
+            assert_eq!(tx, expected_tx);
+            rx.clear();
+            rx.extend_from_slice(response).map_err(|_| Error::Transport)
+        }
+    }
+
+    fn u24(value: u32) -> U24 {
+        U24::new(value).unwrap()
+    }
+
+    #[test]
+    fn selects_ndef_application_by_df_name() {
+        const SELECT: [u8; 12] = [
+            0x00, 0xA4, 0x04, 0x0C, 0x07, 0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01,
+        ];
+        let mut ntag = Ntag424::new(MockTransport::new([(&SELECT[..], &[0x90, 0x00][..])]));
+
+        ntag.select_application().unwrap();
+
+        assert_eq!(ntag.into_executor().transport().index, 1);
+    }
+
+    #[test]
+    fn reads_signature_without_session() {
+        const RESPONSE: [u8; SIGNATURE_LEN + 2] = {
+            let mut response = [0x5A; SIGNATURE_LEN + 2];
+            response[SIGNATURE_LEN] = 0x91;
+            response[SIGNATURE_LEN + 1] = 0x00;
+            response
+        };
+        let mut ntag = Ntag424::new(MockTransport::new([(
+            &[0x90, 0x3C, 0x00, 0x00, 0x01, 0x00, 0x00][..],
+            &RESPONSE[..],
+        )]));
+
+        assert_eq!(ntag.read_sig().unwrap(), [0x5A; SIGNATURE_LEN]);
+        assert_eq!(ntag.into_executor().transport().index, 1);
+    }
+
+    #[test]
+    fn reads_plain_file_with_iso_read_data() {
+        let mut ntag = Ntag424::new(MockTransport::new([(
+            &[
+                0x90, 0xAD, 0x00, 0x00, 0x07, 0x02, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,
+            ][..],
+            &[0x00, 0x1F, 0xD1, 0x01, 0x91, 0x00][..],
+        )]));
+        let mut data: Vec<u8, 4> = Vec::new();
+
+        ntag.read_data(
+            Ntag424File::Ndef,
+            CommunicationMode::Plain,
+            u24(0),
+            u24(4),
+            &mut data,
+        )
+        .unwrap();
+
+        assert_eq!(data.as_slice(), [0x00, 0x1F, 0xD1, 0x01]);
+    }
+
+    #[test]
+    fn change_file_settings_requires_session() {
+        let mut ntag = Ntag424::new(MockTransport::new([]));
+        let key = AccessCondition::Key(KeyNumber::new(0).unwrap());
+        let settings = Ntag424FileSettings {
+            communication_mode: CommunicationMode::Plain,
+            access_rights: AccessRights::new(AccessCondition::Free, key, key, key),
+            size: u24(256),
+            sdm: None,
+        };
+
+        assert_eq!(
+            ntag.change_file_settings(Ntag424File::Ndef, &settings),
+            Err(Error::MissingAuthentication)
+        );
+    }
+}
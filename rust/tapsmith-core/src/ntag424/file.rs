@@ -0,0 +1,407 @@
+use heapless::Vec;
+
+use crate::mifare::desfire::{
+    error::Error,
+    file::{AccessCondition, AccessRights, CommunicationMode, FileId},
+    iso::IsoFileId,
+    types::U24,
+};
+
+/// Largest `ChangeFileSettings` payload: option, rights, SDM options and rights, eight offsets.
+pub const MAX_FILE_SETTINGS_LEN: usize = 1 + 2 + 1 + 2 + 8 * 3;
+
+/// `FileOption` bit enabling Secure Dynamic Messaging.
+const SDM_ENABLED: u8 = 0x40;
+
+const SDM_UID_MIRROR: u8 = 0x80;
+const SDM_READ_COUNTER_MIRROR: u8 = 0x40;
+const SDM_READ_COUNTER_LIMIT: u8 = 0x20;
+const SDM_ENCRYPTED_FILE_DATA: u8 = 0x10;
+const SDM_ASCII_ENCODING: u8 = 0x01;
+
+/// One of the three standard data files of an NTAG 424 DNA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ntag424File {
+    /// NDEF capability container, 32 bytes.
+    CapabilityContainer,
+    /// NDEF message file, 256 bytes.
+    Ndef,
+    /// Proprietary data file, 128 bytes.
+    Proprietary,
+}
+
+impl Ntag424File {
+    /// Native file number.
+    pub fn file_id(self) -> FileId {
+        let number = match self {
+            Self::CapabilityContainer => 0x01,
+            Self::Ndef => 0x02,
+            Self::Proprietary => 0x03,
+        };
+        FileId::new(number).expect("fixed file numbers are valid")
+    }
+
+    /// ISO file identifier.
+    pub fn iso_file_id(self) -> IsoFileId {
+        let value = match self {
+            Self::CapabilityContainer => 0xE103,
+            Self::Ndef => 0xE104,
+            Self::Proprietary => 0xE105,
+        };
+        IsoFileId::new(value).expect("fixed ISO file ids are valid")
+    }
+
+    /// File size in bytes.
+    pub const fn size(self) -> u32 {
+        match self {
+            Self::CapabilityContainer => 32,
+            Self::Ndef => 256,
+            Self::Proprietary => 128,
+        }
+    }
+}
+
+/// Who may use the SDM features of a file.
+///
+/// `Free` disables encrypting or signing the mirrored data and `Never`
+/// disables the feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdmAccessRights {
+    /// Key for encrypting the PICC data, or `Free` to mirror UID and counter in plain.
+    pub meta_read: AccessCondition,
+    /// Key for the SDM MAC and encrypted file data.
+    pub file_read: AccessCondition,
+    /// Key for `GetFileCounters`.
+    pub counter_retrieval: AccessCondition,
+}
+
+impl SdmAccessRights {
+    fn from_bytes(bytes: [u8; 2]) -> Self {
+        Self {
+            meta_read: AccessCondition::from_nibble(bytes[1] >> 4),
+            file_read: AccessCondition::from_nibble(bytes[1] & 0x0F),
+            counter_retrieval: AccessCondition::from_nibble(bytes[0] & 0x0F),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 2] {
+        [
+            0xF0 | self.counter_retrieval.to_nibble(),
+            (self.meta_read.to_nibble() << 4) | self.file_read.to_nibble(),
+        ]
+    }
+}
+
+/// Secure Dynamic Messaging settings of one file.
+///
+/// Offsets point into the file contents as read over NFC. Which offsets the
+/// tag expects depends on the options and access rights; see
+/// [`Self::parse`] for the rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdmSettings {
+    /// Mirror the UID.
+    pub uid_mirror: bool,
+    /// Mirror the SDM read counter.
+    pub read_counter_mirror: bool,
+    /// Mirror data as ASCII hex; the tag requires this.
+    pub ascii_encoding: bool,
+    pub access_rights: SdmAccessRights,
+    pub uid_offset: Option<U24>,
+    pub read_counter_offset: Option<U24>,
+    pub picc_data_offset: Option<U24>,
+    pub mac_input_offset: Option<U24>,
+    /// Start and length of the encrypted file data; sets `SDMENCFileData`.
+    pub encrypted_data: Option<(U24, U24)>,
+    pub mac_offset: Option<U24>,
+    /// Reads allowed before the tag stops answering; sets `SDMReadCtrLimit`.
+    pub read_counter_limit: Option<U24>,
+}
+
+impl SdmSettings {
+    /// Parses `SDMOptions`, `SDMAccessRights` and the offsets that follow.
+    ///
+    /// Plain UID and counter offsets are present when `meta_read` is `Free`;
+    /// the PICC data offset when it is a key. MAC input and MAC offsets, and
+    /// the encrypted data range if enabled, are present unless `file_read` is
+    /// `Never`. The counter limit is present when enabled. Returns the
+    /// settings and the number of bytes consumed.
+    pub fn parse(data: &[u8]) -> Result<(Self, usize), Error> {
+        let [options, rights_0, rights_1, ..] = *data else {
+            return Err(Error::InvalidResponseLength);
+        };
+        let access_rights = SdmAccessRights::from_bytes([rights_0, rights_1]);
+        let mut reader = OffsetReader { data, position: 3 };
+
+        let uid_mirror = options & SDM_UID_MIRROR != 0;
+        let read_counter_mirror = options & SDM_READ_COUNTER_MIRROR != 0;
+        let plain_meta = access_rights.meta_read == AccessCondition::Free;
+        let keyed_meta = matches!(access_rights.meta_read, AccessCondition::Key(_));
+        let file_read = access_rights.file_read != AccessCondition::Never;
+
+        let uid_offset = reader.read_if(uid_mirror && plain_meta)?;
+        let read_counter_offset = reader.read_if(read_counter_mirror && plain_meta)?;
+        let picc_data_offset = reader.read_if(keyed_meta)?;
+        let mac_input_offset = reader.read_if(file_read)?;
+        let encrypted_data = if file_read && options & SDM_ENCRYPTED_FILE_DATA != 0 {
+            Some((reader.read()?, reader.read()?))
+        } else {
+            None
+        };
+        let mac_offset = reader.read_if(file_read)?;
+        let read_counter_limit = reader.read_if(options & SDM_READ_COUNTER_LIMIT != 0)?;
+
+        let settings = Self {
+            uid_mirror,
+            read_counter_mirror,
+            ascii_encoding: options & SDM_ASCII_ENCODING != 0,
+            access_rights,
+            uid_offset,
+            read_counter_offset,
+            picc_data_offset,
+            mac_input_offset,
+            encrypted_data,
+            mac_offset,
+            read_counter_limit,
+        };
+        Ok((settings, reader.position))
+    }
+
+    /// Appends the settings in `ChangeFileSettings` order.
+    ///
+    /// Fails with [`Error::InvalidSdmSettings`] when an offset required by
+    /// the rules in [`Self::parse`] is missing.
+    pub fn encode<const N: usize>(&self, out: &mut Vec<u8, N>) -> Result<(), Error> {
+        let mut options = 0;
+        for (flag, enabled) in [
+            (SDM_UID_MIRROR, self.uid_mirror),
+            (SDM_READ_COUNTER_MIRROR, self.read_counter_mirror),
+            (SDM_READ_COUNTER_LIMIT, self.read_counter_limit.is_some()),
+            (SDM_ENCRYPTED_FILE_DATA, self.encrypted_data.is_some()),
+            (SDM_ASCII_ENCODING, self.ascii_encoding),
+        ] {
+            if enabled {
+                options |= flag;
+            }
+        }
+        push(out, &[options])?;
+        push(out, &self.access_rights.to_bytes())?;
+
+        let plain_meta = self.access_rights.meta_read == AccessCondition::Free;
+        let keyed_meta = matches!(self.access_rights.meta_read, AccessCondition::Key(_));
+        let file_read = self.access_rights.file_read != AccessCondition::Never;
+        let (encrypted_offset, encrypted_length) = self.encrypted_data.unzip();
+        for (required, offset) in [
+            (self.uid_mirror && plain_meta, self.uid_offset),
+            (
+                self.read_counter_mirror && plain_meta,
+                self.read_counter_offset,
+            ),
+            (keyed_meta, self.picc_data_offset),
+            (file_read, self.mac_input_offset),
+            (file_read && self.encrypted_data.is_some(), encrypted_offset),
+            (file_read && self.encrypted_data.is_some(), encrypted_length),
+            (file_read, self.mac_offset),
+            (self.read_counter_limit.is_some(), self.read_counter_limit),
+        ] {
+            if required {
+                let offset = offset.ok_or(Error::InvalidSdmSettings)?;
+                push(out, &offset.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Settings of one NTAG 424 DNA file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ntag424FileSettings {
+    pub communication_mode: CommunicationMode,
+    pub access_rights: AccessRights,
+    /// File size; only reported by `GetFileSettings`.
+    pub size: U24,
+    pub sdm: Option<SdmSettings>,
+}
+
+impl Ntag424FileSettings {
+    /// Parses the `GetFileSettings` response body.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let [file_type, option, rights_0, rights_1, size_0, size_1, size_2, ref rest @ ..] = *data
+        else {
+            return Err(Error::InvalidResponseLength);
+        };
+        // Only standard data files exist on the tag.
+        if file_type != 0x00 {
+            return Err(Error::InvalidFileType(file_type));
+        }
+
+        let sdm = if option & SDM_ENABLED != 0 {
+            let (sdm, consumed) = SdmSettings::parse(rest)?;
+            if consumed != rest.len() {
+                return Err(Error::InvalidResponseLength);
+            }
+            Some(sdm)
+        } else if rest.is_empty() {
+            None
+        } else {
+            return Err(Error::InvalidResponseLength);
+        };
+
+        Ok(Self {
+            communication_mode: CommunicationMode::try_from(option & 0x03)?,
+            access_rights: AccessRights::from_bytes([rights_0, rights_1]),
+            size: U24::from_le_bytes([size_0, size_1, size_2]),
+            sdm,
+        })
+    }
+
+    /// Encodes the `ChangeFileSettings` payload after the file number.
+    pub fn encode_change(&self) -> Result<Vec<u8, MAX_FILE_SETTINGS_LEN>, Error> {
+        let mut option = u8::from(self.communication_mode);
+        if self.sdm.is_some() {
+            option |= SDM_ENABLED;
+        }
+        let mut out = Vec::new();
+        push(&mut out, &[option])?;
+        push(&mut out, &self.access_rights.to_bytes())?;
+        if let Some(sdm) = &self.sdm {
+            sdm.encode(&mut out)?;
+        }
+        Ok(out)
+    }
+}
+
+struct OffsetReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl OffsetReader<'_> {
+    fn read(&mut self) -> Result<U24, Error> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + 3)
+            .ok_or(Error::InvalidResponseLength)?;
+        self.position += 3;
+        Ok(U24::from_le_bytes(
+            bytes.try_into().expect("slice length is checked"),
+        ))
+    }
+
+    fn read_if(&mut self, present: bool) -> Result<Option<U24>, Error> {
+        present.then(|| self.read()).transpose()
+    }
+}
+
+fn push<const N: usize>(out: &mut Vec<u8, N>, bytes: &[u8]) -> Result<(), Error> {
+    out.extend_from_slice(bytes)
+        .map_err(|_| Error::CommandTooLong)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        mifare::desfire::{
+            error::Error,
+            file::{AccessCondition, AccessRights, CommunicationMode},
+            key::KeyNumber,
+            types::U24,
+        },
+        ntag424::file::{Ntag424FileSettings, SdmAccessRights, SdmSettings},
+    };
+
+    fn key(number: u8) -> AccessCondition {
+        AccessCondition::Key(KeyNumber::new(number).unwrap())
+    }
+
+    fn u24(value: u32) -> U24 {
+        U24::new(value).unwrap()
+    }
+
+    /// NDEF file settings from the AN12196 SUN example.
+    fn an12196_settings() -> Ntag424FileSettings {
+        Ntag424FileSettings {
+            communication_mode: CommunicationMode::Plain,
+            access_rights: AccessRights::new(AccessCondition::Free, key(0), key(0), key(0)),
+            size: u24(256),
+            sdm: Some(SdmSettings {
+                uid_mirror: true,
+                read_counter_mirror: true,
+                ascii_encoding: true,
+                access_rights: SdmAccessRights {
+                    meta_read: key(2),
+                    file_read: key(1),
+                    counter_retrieval: key(1),
+                },
+                uid_offset: None,
+                read_counter_offset: None,
+                picc_data_offset: Some(u24(0x20)),
+                mac_input_offset: Some(u24(0x43)),
+                encrypted_data: None,
+                mac_offset: Some(u24(0x43)),
+                read_counter_limit: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn encodes_an12196_change_file_settings() {
+        let payload = an12196_settings().encode_change().unwrap();
+
+        assert_eq!(
+            payload.as_slice(),
+            [
+                0x40, 0x00, 0xE0, 0xC1, 0xF1, 0x21, 0x20, 0x00, 0x00, 0x43, 0x00, 0x00, 0x43, 0x00,
+                0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_sdm_file_settings() {
+        let response = [
+            0x00, 0x40, 0x00, 0xE0, 0x00, 0x01, 0x00, 0xC1, 0xF1, 0x21, 0x20, 0x00, 0x00, 0x43,
+            0x00, 0x00, 0x43, 0x00, 0x00,
+        ];
+
+        assert_eq!(
+            Ntag424FileSettings::parse(&response).unwrap(),
+            an12196_settings()
+        );
+    }
+
+    #[test]
+    fn parses_plain_mirror_with_encrypted_data_and_limit() {
+        let response = [
+            0x00, 0x43, 0x00, 0xE0, 0x80, 0x00, 0x00, 0xF1, 0xF1, 0xE0, 0x10, 0x00, 0x00, 0x20,
+            0x00, 0x00, 0x30, 0x00, 0x00, 0x30, 0x00, 0x00, 0x20, 0x00, 0x00, 0x60, 0x00, 0x00,
+            0x64, 0x00, 0x00,
+        ];
+
+        let settings = Ntag424FileSettings::parse(&response).unwrap();
+        assert_eq!(settings.communication_mode, CommunicationMode::Enciphered);
+        let sdm = settings.sdm.unwrap();
+        assert_eq!(sdm.access_rights.meta_read, AccessCondition::Free);
+        assert_eq!(sdm.uid_offset, Some(u24(0x10)));
+        assert_eq!(sdm.read_counter_offset, Some(u24(0x20)));
+        assert_eq!(sdm.picc_data_offset, None);
+        assert_eq!(sdm.encrypted_data, Some((u24(0x30), u24(0x20))));
+        assert_eq!(sdm.mac_offset, Some(u24(0x60)));
+        assert_eq!(sdm.read_counter_limit, Some(u24(100)));
+        assert_eq!(
+            settings.encode_change().unwrap().as_slice(),
+            &response[1..4]
+                .iter()
+                .chain(&response[7..])
+                .copied()
+                .collect::<heapless::Vec<u8, 32>>()
+        );
+    }
+
+    #[test]
+    fn rejects_sdm_settings_missing_a_required_offset() {
+        let mut settings = an12196_settings();
+        settings.sdm.as_mut().unwrap().mac_offset = None;
+
+        assert_eq!(settings.encode_change(), Err(Error::InvalidSdmSettings));
+    }
+}
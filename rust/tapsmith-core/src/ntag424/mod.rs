@@ -0,0 +1,24 @@
+//! NXP NTAG 424 DNA support.
+//!
+//! The tag is a cut-down `DESFire` EV2 application with three fixed data
+//! files, five AES keys and Secure Dynamic Messaging (SDM), which mirrors the
+//! UID, a read counter and a CMAC into the NDEF file on every read.
+//! [`Ntag424`] drives it through the `DESFire` client, executor and crypto
+//! over ISO-wrapped framing, so any [`Transport`] that carries APDUs works.
+//! [`SunTemplate`] checks the resulting messages on a back end.
+//!
+//! [`Transport`]: crate::mifare::desfire::Transport
+
+pub mod client;
+pub mod file;
+pub mod sun;
+
+pub use crate::mifare::desfire::originality::SIGNATURE_LEN;
+pub use client::Ntag424;
+pub use file::{
+    Ntag424File, Ntag424FileSettings, SdmAccessRights, SdmSettings, MAX_FILE_SETTINGS_LEN,
+};
+pub use sun::{SunError, SunKeys, SunMessage, SunTemplate, MAX_SUN_FILE_DATA};
+
+/// ISO DF name of the NDEF application.
+pub const NDEF_DF_NAME: [u8; 7] = [0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01];
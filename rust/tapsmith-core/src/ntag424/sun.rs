@@ -0,0 +1,476 @@
+//! Offline verification of Secure Unique NFC (SUN) messages.
+//!
+//! An NTAG 424 DNA or `DESFire` EV3 with SDM enabled mirrors encrypted PICC
+//! data, a truncated CMAC and optionally encrypted file data into the NDEF
+//! message on every read. [`SunTemplate`] locates those fields in the message
+//! a back end receives and checks them with the SDM keys alone, so no tag
+//! needs to be present.
+
+use core::fmt;
+
+use heapless::Vec;
+
+use crate::mifare::desfire::{
+    crypto::{aes_cbc_decrypt_in_place, aes_cbc_encrypt_in_place, AesCmac},
+    secret::constant_time_eq,
+};
+
+/// Most encrypted file data one message can carry, in bytes.
+pub const MAX_SUN_FILE_DATA: usize = 128;
+
+/// Length of a UID mirrored by SDM.
+const UID_LEN: usize = 7;
+
+/// `PICCDataTag` bit set when the UID is mirrored.
+const PICC_DATA_UID: u8 = 0x80;
+/// `PICCDataTag` bit set when the read counter is mirrored.
+const PICC_DATA_READ_COUNTER: u8 = 0x40;
+
+/// Labels of the `SV1` and `SV2` session vectors.
+const SV_ENC_LABEL: [u8; 6] = [0xC3, 0x3C, 0x00, 0x01, 0x00, 0x80];
+const SV_MAC_LABEL: [u8; 6] = [0x3C, 0xC3, 0x00, 0x01, 0x00, 0x80];
+
+/// Errors raised while verifying a SUN message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SunError {
+    /// The template has an unknown, repeated or misplaced placeholder.
+    InvalidTemplate,
+    /// The message does not have the shape of the template.
+    TemplateMismatch,
+    /// A mirrored field is not hex.
+    InvalidHex,
+    /// The decrypted PICC data does not carry what the template needs.
+    InvalidPiccData,
+    /// The SDM MAC does not match; the message is forged or the key is wrong.
+    InvalidMac,
+}
+
+impl fmt::Display for SunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SunError::InvalidTemplate => write!(f, "Invalid SUN template"),
+            SunError::TemplateMismatch => write!(f, "Message does not match the SUN template"),
+            SunError::InvalidHex => write!(f, "Mirrored field is not hex"),
+            SunError::InvalidPiccData => write!(f, "Invalid PICC data"),
+            SunError::InvalidMac => write!(f, "SDM MAC mismatch"),
+        }
+    }
+}
+
+/// SDM keys of the file the message was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SunKeys {
+    /// `SDMMetaReadKey`; only used for encrypted PICC data.
+    pub meta_read: [u8; 16],
+    /// `SDMFileReadKey`, the base of the MAC and encryption session keys.
+    pub file_read: [u8; 16],
+}
+
+/// Data recovered from a verified SUN message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SunMessage {
+    pub uid: Option<[u8; UID_LEN]>,
+    pub read_counter: Option<u32>,
+    /// Decrypted file data; empty unless the template has `{enc:N}`.
+    pub file_data: Vec<u8, MAX_SUN_FILE_DATA>,
+}
+
+/// Layout of the mirrored fields within a message.
+///
+/// The template is the message text with each mirrored field replaced by a
+/// placeholder:
+///
+/// - `{picc}`: encrypted PICC data, 32 hex digits.
+/// - `{uid}` and `{ctr}`: UID and read counter mirrored in plain, 14 and 6 hex digits.
+/// - `{enc:N}`: encrypted file data, `N` hex digits; a multiple of 32.
+/// - `{mac}`: the SDM MAC, 16 hex digits. Required.
+/// - `{mac_input}`: zero-width marker where the MAC input starts, matching
+///   `SDMMACInputOffset`. Without it the MAC covers no data.
+///
+/// For example `https://example.com/?e={picc}&c={mac}`. Every field has a
+/// fixed length, so the template must match the whole message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SunTemplate<'a> {
+    template: &'a str,
+}
+
+impl<'a> SunTemplate<'a> {
+    /// Checks the placeholders of `template`.
+    ///
+    /// Each placeholder may appear once. `{picc}` excludes the plain `{uid}`
+    /// and `{ctr}`, `{enc:N}` needs `{picc}`, and `{mac_input}` must come
+    /// before `{mac}`.
+    pub fn new(template: &'a str) -> Result<Self, SunError> {
+        let mut seen = [false; Field::COUNT];
+        for token in Tokens::new(template) {
+            if let Token::Field(field) = token? {
+                let index = field.index();
+                if seen[index] {
+                    return Err(SunError::InvalidTemplate);
+                }
+                if field == Field::MacInput && seen[Field::Mac.index()] {
+                    return Err(SunError::InvalidTemplate);
+                }
+                seen[index] = true;
+            }
+        }
+
+        let [picc, uid, ctr, enc, mac, _] = seen;
+        if !mac || (picc && (uid || ctr)) || (enc && !picc) {
+            return Err(SunError::InvalidTemplate);
+        }
+        Ok(Self { template })
+    }
+
+    /// Verifies `message` and returns the data it carries.
+    pub fn verify(&self, message: &str, keys: &SunKeys) -> Result<SunMessage, SunError> {
+        let fields = self.locate(message)?;
+
+        let mut result = SunMessage {
+            uid: None,
+            read_counter: None,
+            file_data: Vec::new(),
+        };
+        if let Some(picc) = fields.picc {
+            let mut picc_data: [u8; 16] = decode_hex_array(picc)?;
+            aes_cbc_decrypt_in_place(&keys.meta_read, &[0; 16], &mut picc_data);
+            parse_picc_data(&picc_data, &mut result)?;
+        }
+        if let Some(uid) = fields.uid {
+            result.uid = Some(decode_hex_array(uid)?);
+        }
+        if let Some(ctr) = fields.ctr {
+            let [high, middle, low] = decode_hex_array(ctr)?;
+            result.read_counter = Some(u32::from_be_bytes([0, high, middle, low]));
+        }
+
+        let mac_key = session_key(&keys.file_read, SV_MAC_LABEL, &result);
+        let expected: [u8; 8] = decode_hex_array(fields.mac)?;
+        let mac_input_start = fields.mac_input.unwrap_or(fields.mac_start);
+        let mac_input = &message.as_bytes()[mac_input_start..fields.mac_start];
+        if !constant_time_eq(
+            &AesCmac::calculate(&mac_key, mac_input).ev2_mac().as_bytes(),
+            &expected,
+        ) {
+            return Err(SunError::InvalidMac);
+        }
+
+        if let Some(enc) = fields.enc {
+            let counter = result.read_counter.ok_or(SunError::InvalidPiccData)?;
+            if result.uid.is_none() {
+                return Err(SunError::InvalidPiccData);
+            }
+            let enc_key = session_key(&keys.file_read, SV_ENC_LABEL, &result);
+            decode_hex(enc, &mut result.file_data)?;
+            let mut iv = [0; 16];
+            iv[..3].copy_from_slice(&counter.to_le_bytes()[..3]);
+            aes_cbc_encrypt_in_place(&enc_key, &[0; 16], &mut iv);
+            aes_cbc_decrypt_in_place(&enc_key, &iv, &mut result.file_data);
+        }
+        Ok(result)
+    }
+
+    /// Matches `message` against the template and slices out each field.
+    fn locate<'m>(&self, message: &'m str) -> Result<Fields<'m>, SunError> {
+        let mut fields = Fields::default();
+        let mut position = 0;
+        for token in Tokens::new(self.template) {
+            let rest = message.get(position..).ok_or(SunError::TemplateMismatch)?;
+            let field = match token? {
+                Token::Literal(literal) => {
+                    if !rest.starts_with(literal) {
+                        return Err(SunError::TemplateMismatch);
+                    }
+                    position += literal.len();
+                    continue;
+                }
+                Token::Field(field) => field,
+            };
+            let value = rest
+                .get(..field.hex_len())
+                .ok_or(SunError::TemplateMismatch)?;
+            match field {
+                Field::PiccData => fields.picc = Some(value),
+                Field::Uid => fields.uid = Some(value),
+                Field::ReadCounter => fields.ctr = Some(value),
+                Field::EncFileData(_) => fields.enc = Some(value),
+                Field::Mac => {
+                    fields.mac = value;
+                    fields.mac_start = position;
+                }
+                Field::MacInput => fields.mac_input = Some(position),
+            }
+            position += value.len();
+        }
+
+        if position != message.len() {
+            return Err(SunError::TemplateMismatch);
+        }
+        Ok(fields)
+    }
+}
+
+/// Mirrored fields of one message, as hex text.
+#[derive(Default)]
+struct Fields<'m> {
+    picc: Option<&'m str>,
+    uid: Option<&'m str>,
+    ctr: Option<&'m str>,
+    enc: Option<&'m str>,
+    mac: &'m str,
+    mac_input: Option<usize>,
+    mac_start: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    PiccData,
+    Uid,
+    ReadCounter,
+    /// Encrypted file data of this many hex digits.
+    EncFileData(usize),
+    Mac,
+    MacInput,
+}
+
+impl Field {
+    const COUNT: usize = 6;
+
+    fn parse(name: &str) -> Result<Self, SunError> {
+        let field = match name {
+            "picc" => Self::PiccData,
+            "uid" => Self::Uid,
+            "ctr" => Self::ReadCounter,
+            "mac" => Self::Mac,
+            "mac_input" => Self::MacInput,
+            _ => {
+                let length = name
+                    .strip_prefix("enc:")
+                    .and_then(|length| length.parse::<usize>().ok())
+                    .filter(|&length| {
+                        length > 0 && length % 32 == 0 && length / 2 <= MAX_SUN_FILE_DATA
+                    })
+                    .ok_or(SunError::InvalidTemplate)?;
+                Self::EncFileData(length)
+            }
+        };
+        Ok(field)
+    }
+
+    const fn index(self) -> usize {
+        match self {
+            Self::PiccData => 0,
+            Self::Uid => 1,
+            Self::ReadCounter => 2,
+            Self::EncFileData(_) => 3,
+            Self::Mac => 4,
+            Self::MacInput => 5,
+        }
+    }
+
+    const fn hex_len(self) -> usize {
+        match self {
+            Self::PiccData => 32,
+            Self::Uid => 2 * UID_LEN,
+            Self::ReadCounter => 6,
+            Self::EncFileData(length) => length,
+            Self::Mac => 16,
+            Self::MacInput => 0,
+        }
+    }
+}
+
+enum Token<'a> {
+    Literal(&'a str),
+    Field(Field),
+}
+
+/// Splits a template into literal text and placeholders.
+struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokens<'a> {
+    const fn new(template: &'a str) -> Self {
+        Self { rest: template }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Result<Token<'a>, SunError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let Some(placeholder) = self.rest.strip_prefix('{') else {
+            let end = self.rest.find('{').unwrap_or(self.rest.len());
+            let (literal, rest) = self.rest.split_at(end);
+            self.rest = rest;
+            return Some(Ok(Token::Literal(literal)));
+        };
+        let Some((name, rest)) = placeholder.split_once('}') else {
+            self.rest = "";
+            return Some(Err(SunError::InvalidTemplate));
+        };
+        self.rest = rest;
+        Some(Field::parse(name).map(Token::Field))
+    }
+}
+
+/// Reads the decrypted `PICCDataTag`, UID and read counter.
+fn parse_picc_data(data: &[u8; 16], result: &mut SunMessage) -> Result<(), SunError> {
+    let tag = data[0];
+    let mut rest = &data[1..];
+    if tag & PICC_DATA_UID != 0 {
+        if usize::from(tag & 0x0F) != UID_LEN {
+            return Err(SunError::InvalidPiccData);
+        }
+        let (uid, tail) = rest.split_at(UID_LEN);
+        result.uid = Some(uid.try_into().expect("split at UID length"));
+        rest = tail;
+    }
+    if tag & PICC_DATA_READ_COUNTER != 0 {
+        result.read_counter = Some(u32::from_le_bytes([rest[0], rest[1], rest[2], 0]));
+    }
+    Ok(())
+}
+
+/// Derives `KSesSDMFileReadENC` or `KSesSDMFileReadMAC` from the mirrored UID and counter.
+fn session_key(file_read_key: &[u8; 16], label: [u8; 6], message: &SunMessage) -> [u8; 16] {
+    let mut vector = [0; 16];
+    vector[..6].copy_from_slice(&label);
+    let mut length = 6;
+    if let Some(uid) = message.uid {
+        vector[length..length + UID_LEN].copy_from_slice(&uid);
+        length += UID_LEN;
+    }
+    if let Some(counter) = message.read_counter {
+        vector[length..length + 3].copy_from_slice(&counter.to_le_bytes()[..3]);
+    }
+    AesCmac::calculate(file_read_key, &vector).as_bytes()
+}
+
+fn decode_hex_array<const N: usize>(hex: &str) -> Result<[u8; N], SunError> {
+    let mut bytes: Vec<u8, N> = Vec::new();
+    decode_hex(hex, &mut bytes)?;
+    bytes.into_array().map_err(|_| SunError::TemplateMismatch)
+}
+
+fn decode_hex<const N: usize>(hex: &str, out: &mut Vec<u8, N>) -> Result<(), SunError> {
+    out.clear();
+    for pair in hex.as_bytes().chunks(2) {
+        let [high, low] = *pair else {
+            return Err(SunError::InvalidHex);
+        };
+        out.push((hex_digit(high)? << 4) | hex_digit(low)?)
+            .map_err(|_| SunError::TemplateMismatch)?;
+    }
+    Ok(())
+}
+
+fn hex_digit(digit: u8) -> Result<u8, SunError> {
+    char::from(digit)
+        .to_digit(16)
+        .and_then(|value| u8::try_from(value).ok())
+        .ok_or(SunError::InvalidHex)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ntag424::sun::{SunError, SunKeys, SunTemplate};
+
+    const ZERO_KEYS: SunKeys = SunKeys {
+        meta_read: [0; 16],
+        file_read: [0; 16],
+    };
+
+    #[test]
+    fn verifies_an12196_picc_data_and_mac() {
+        let template = SunTemplate::new("https://choose.url.com/ntag424?e={picc}&c={mac}").unwrap();
+
+        let message = template
+            .verify(
+                "https://choose.url.com/ntag424?e=EF963FF7828658A599F3041510671E88&c=94EED9EE65337086",
+                &ZERO_KEYS,
+            )
+            .unwrap();
+
+        assert_eq!(
+            message.uid,
+            Some([0x04, 0xDE, 0x5F, 0x1E, 0xAC, 0xC0, 0x40])
+        );
+        assert_eq!(message.read_counter, Some(0x3D));
+        assert!(message.file_data.is_empty());
+    }
+
+    #[test]
+    fn verifies_an12196_encrypted_file_data() {
+        let template = SunTemplate::new(
+            "https://www.my424dna.com/?picc_data={picc}&enc={mac_input}{enc:32}&cmac={mac}",
+        )
+        .unwrap();
+
+        let message = template
+            .verify(
+                "https://www.my424dna.com/?picc_data=FD91EC264309878BE6345CBE53BADF40\
+                 &enc=CEE9A53E3E463EF1F459635736738962&cmac=ECC1E7F6C6C73BF6",
+                &ZERO_KEYS,
+            )
+            .unwrap();
+
+        assert_eq!(
+            message.uid,
+            Some([0x04, 0x95, 0x8C, 0xAA, 0x5C, 0x5E, 0x80])
+        );
+        assert_eq!(message.read_counter, Some(8));
+        assert_eq!(message.file_data.as_slice(), [b'x'; 16]);
+    }
+
+    #[test]
+    fn rejects_forged_mac_and_mismatched_messages() {
+        let template = SunTemplate::new("https://choose.url.com/ntag424?e={picc}&c={mac}").unwrap();
+
+        assert_eq!(
+            template.verify(
+                "https://choose.url.com/ntag424?e=EF963FF7828658A599F3041510671E88&c=94EED9EE65337087",
+                &ZERO_KEYS,
+            ),
+            Err(SunError::InvalidMac)
+        );
+        assert_eq!(
+            template.verify(
+                "https://choose.url.com/ntag424?e=EF963FF7828658A599F3041510671E88&c=94EED9EE6533708",
+                &ZERO_KEYS,
+            ),
+            Err(SunError::TemplateMismatch)
+        );
+        assert_eq!(
+            template.verify(
+                "https://choose.url.com/ntag424?e=EF963FF7828658A599F3041510671E8G&c=94EED9EE65337086",
+                &ZERO_KEYS,
+            ),
+            Err(SunError::InvalidHex)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for template in [
+            "https://example.com/?e={picc}",
+            "https://example.com/?e={picc}&c={mac}&d={mac}",
+            "https://example.com/?u={uid}&e={picc}&c={mac}",
+            "https://example.com/?enc={enc:32}&u={uid}&c={mac}",
+            "https://example.com/?e={picc}&enc={enc:20}&c={mac}",
+            "https://example.com/?c={mac}{mac_input}",
+            "https://example.com/?e={picc&c={mac}",
+            "https://example.com/?e={sig}&c={mac}",
+        ] {
+            assert_eq!(
+                SunTemplate::new(template),
+                Err(SunError::InvalidTemplate),
+                "{template}"
+            );
+        }
+    }
+}
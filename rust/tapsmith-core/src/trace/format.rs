@@ -0,0 +1,245 @@
+use core::{fmt, str::FromStr, time::Duration};
+use std::{string::String, vec::Vec};
+
+use crate::{
+    mifare::{classic::KeyType, desfire::error::Error as DesfireError},
+    trace::{Exchange, TagFailure, Trace, TraceEvent, TRACE_FORMAT_VERSION},
+};
+
+const HEADER: &str = "tapsmith-trace";
+
+/// Errors raised while parsing a trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The first record is not a `tapsmith-trace` header.
+    MissingHeader,
+    /// The trace was written in a format version this crate cannot read.
+    UnsupportedVersion(u32),
+    /// A record is malformed; holds the 1-based line number.
+    InvalidLine(usize),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingHeader => write!(f, "Missing {HEADER} header"),
+            ParseError::UnsupportedVersion(v) => write!(f, "Unsupported trace version: {v}"),
+            ParseError::InvalidLine(v) => write!(f, "Invalid trace record on line {v}"),
+        }
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{HEADER} {TRACE_FORMAT_VERSION}")?;
+        for event in &self.events {
+            write!(f, "{}", event.elapsed.as_micros())?;
+            match &event.exchange {
+                Exchange::Transceive { tx, result } => {
+                    write!(f, " transceive {}", Hex(tx))?;
+                    match result {
+                        Ok(rx) => write!(f, " {}", Hex(rx))?,
+                        Err(error) => write!(f, " - error {}", desfire_error_name(*error))?,
+                    }
+                }
+                Exchange::Authenticate {
+                    sector,
+                    key_type,
+                    key,
+                    result,
+                } => {
+                    let key_type = match key_type {
+                        KeyType::KeyA => 'A',
+                        KeyType::KeyB => 'B',
+                    };
+                    write!(f, " authenticate {sector} {key_type} {}", Hex(key))?;
+                    write_tag_result(f, result.as_ref().map(|()| None))?;
+                }
+                Exchange::ReadBlock { block, result } => {
+                    write!(f, " read {block}")?;
+                    write_tag_result(f, result.as_ref().map(Some))?;
+                }
+                Exchange::WriteBlock {
+                    block,
+                    data,
+                    result,
+                } => {
+                    write!(f, " write {block} {}", Hex(data))?;
+                    write_tag_result(f, result.as_ref().map(|()| None))?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Trace {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut records = s
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim_end_matches('\r')))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+        let (_, header) = records.next().ok_or(ParseError::MissingHeader)?;
+        let version = match header.split_once(' ') {
+            Some((HEADER, version)) => version
+                .parse::<u32>()
+                .map_err(|_| ParseError::MissingHeader)?,
+            _ => return Err(ParseError::MissingHeader),
+        };
+        if version != TRACE_FORMAT_VERSION {
+            return Err(ParseError::UnsupportedVersion(version));
+        }
+
+        let mut trace = Trace::new();
+        for (line_number, line) in records {
+            let event = parse_event(line).ok_or(ParseError::InvalidLine(line_number))?;
+            trace.push(event);
+        }
+        Ok(trace)
+    }
+}
+
+fn parse_event(line: &str) -> Option<TraceEvent> {
+    let mut fields = line.splitn(2, ' ');
+    let elapsed = Duration::from_micros(fields.next()?.parse().ok()?);
+    let rest = fields.next()?;
+    let (operation, rest) = rest.split_once(' ')?;
+
+    let exchange = match operation {
+        "transceive" => {
+            let (tx, result) = rest.split_once(' ')?;
+            let result = match result.strip_prefix("- error ") {
+                Some(name) => Err(parse_desfire_error(name)?),
+                None => Ok(parse_hex(result)?),
+            };
+            Exchange::Transceive {
+                tx: parse_hex(tx)?,
+                result,
+            }
+        }
+        "authenticate" => {
+            let mut fields = rest.splitn(4, ' ');
+            let sector = fields.next()?.parse().ok()?;
+            let key_type = match fields.next()? {
+                "A" => KeyType::KeyA,
+                "B" => KeyType::KeyB,
+                _ => return None,
+            };
+            let key = parse_hex(fields.next()?)?.try_into().ok()?;
+            Exchange::Authenticate {
+                sector,
+                key_type,
+                key,
+                result: parse_unit_result(fields.next()?)?,
+            }
+        }
+        "read" => {
+            let (block, result) = rest.split_once(' ')?;
+            let result = match result.strip_prefix("error ") {
+                Some(failure) => Err(parse_tag_failure(failure)?),
+                None => Ok(parse_hex(result)?.try_into().ok()?),
+            };
+            Exchange::ReadBlock {
+                block: block.parse().ok()?,
+                result,
+            }
+        }
+        "write" => {
+            let mut fields = rest.splitn(3, ' ');
+            let block = fields.next()?.parse().ok()?;
+            let data = parse_hex(fields.next()?)?.try_into().ok()?;
+            Exchange::WriteBlock {
+                block,
+                data,
+                result: parse_unit_result(fields.next()?)?,
+            }
+        }
+        _ => return None,
+    };
+
+    Some(TraceEvent { elapsed, exchange })
+}
+
+fn write_tag_result(
+    f: &mut fmt::Formatter<'_>,
+    result: Result<Option<&[u8; 16]>, &TagFailure>,
+) -> fmt::Result {
+    match result {
+        Ok(None) => write!(f, " ok"),
+        Ok(Some(data)) => write!(f, " {}", Hex(data)),
+        Err(TagFailure::AuthenticationFailed) => write!(f, " error authentication-failed"),
+        Err(TagFailure::InvalidSector(sector)) => write!(f, " error invalid-sector {sector}"),
+        Err(TagFailure::InvalidBlock(block)) => write!(f, " error invalid-block {block}"),
+        // Messages are single-line so the record stays on one line.
+        Err(TagFailure::Transport(message)) => {
+            write!(f, " error transport {}", message.replace(['\r', '\n'], " "))
+        }
+    }
+}
+
+fn parse_unit_result(field: &str) -> Option<Result<(), TagFailure>> {
+    match field {
+        "ok" => Some(Ok(())),
+        _ => Some(Err(parse_tag_failure(field.strip_prefix("error ")?)?)),
+    }
+}
+
+fn parse_tag_failure(field: &str) -> Option<TagFailure> {
+    let (name, argument) = field.split_once(' ').unwrap_or((field, ""));
+    match name {
+        "authentication-failed" if argument.is_empty() => Some(TagFailure::AuthenticationFailed),
+        "invalid-sector" => Some(TagFailure::InvalidSector(argument.parse().ok()?)),
+        "invalid-block" => Some(TagFailure::InvalidBlock(argument.parse().ok()?)),
+        "transport" => Some(TagFailure::Transport(String::from(argument))),
+        _ => None,
+    }
+}
+
+const fn desfire_error_name(error: DesfireError) -> &'static str {
+    match error {
+        DesfireError::CommandTooLong => "command-too-long",
+        DesfireError::ResponseTooLong => "response-too-long",
+        DesfireError::MalformedResponse => "malformed-response",
+        _ => "transport",
+    }
+}
+
+fn parse_desfire_error(name: &str) -> Option<DesfireError> {
+    match name {
+        "transport" => Some(DesfireError::Transport),
+        "command-too-long" => Some(DesfireError::CommandTooLong),
+        "response-too-long" => Some(DesfireError::ResponseTooLong),
+        "malformed-response" => Some(DesfireError::MalformedResponse),
+        _ => None,
+    }
+}
+
+/// Uppercase hex without separators, `-` when empty.
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "-");
+        }
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02X}"))
+    }
+}
+
+fn parse_hex(field: &str) -> Option<Vec<u8>> {
+    if field == "-" {
+        return Some(Vec::new());
+    }
+    if !field.len().is_multiple_of(2) || !field.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..field.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&field[index..index + 2], 16).ok())
+        .collect()
+}
@@ -0,0 +1,397 @@
+//! Recording and replay of card exchanges.
+//!
+//! [`TraceRecorder`] wraps a `DESFire`
+//! [`Transport`](crate::mifare::desfire::Transport) or a MIFARE Classic
+//! [`Tag`](crate::mifare::classic::Tag) and records every exchange with the
+//! time elapsed since recording started. [`Replay`] serves a recorded
+//! [`Trace`] back through the same traits and their async counterparts, so a
+//! session captured in the field can be reproduced in a unit test without a
+//! reader.
+//!
+//! # Trace format
+//!
+//! A trace is UTF-8 text with one record per line, written by the
+//! [`Display`](core::fmt::Display) impl of [`Trace`] and read by its
+//! [`FromStr`](core::str::FromStr) impl. Version 1 is stable: readers accept
+//! every file written by earlier releases, and changes that old readers cannot
+//! parse bump the version.
+//!
+//! The first line is the header `tapsmith-trace 1`. Empty lines and lines
+//! starting with `#` are ignored. Every other line is
+//! `<elapsed> <operation> <fields...>`, separated by single spaces:
+//!
+//! ```text
+//! tapsmith-trace 1
+//! # GetVersion over wrapped framing
+//! 1520 transceive 9060000000 0401010100180591AF
+//! 1733 transceive 90AF000000 - error transport
+//! 2048 authenticate 1 A FFFFFFFFFFFF ok
+//! 2210 read 4 000102030405060708090A0B0C0D0E0F
+//! 2397 write 5 000102030405060708090A0B0C0D0E0F error authentication-failed
+//! ```
+//!
+//! - `elapsed` is the number of microseconds since recording started.
+//! - Byte strings are uppercase hex without separators; an empty byte string
+//!   is written as `-`.
+//! - `transceive <tx> <rx>` is a `DESFire` `Transport` exchange. A failed
+//!   exchange is `transceive <tx> - error <name>`, where `name` is one of
+//!   `transport`, `command-too-long`, `response-too-long` or
+//!   `malformed-response`. Other errors are recorded as `transport`.
+//! - `authenticate <sector> <A|B> <key>`, `read <block>` and
+//!   `write <block> <data>` are MIFARE Classic `Tag` calls. They end in `ok`,
+//!   the 16 block bytes for a successful `read`, or `error <name>` with one of
+//!   `authentication-failed`, `invalid-sector <n>`, `invalid-block <n>` or
+//!   `transport <message>`, where the message runs to the end of the line.
+
+mod format;
+mod recorder;
+mod replay;
+
+use std::{string::String, time::Duration, vec::Vec};
+
+use crate::mifare::{
+    classic::{Error as ClassicError, KeyType, Sector},
+    desfire::error::Error as DesfireError,
+};
+
+pub use format::ParseError;
+pub use recorder::TraceRecorder;
+pub use replay::{Mismatch, Replay};
+
+/// Trace format version written by this crate.
+pub const TRACE_FORMAT_VERSION: u32 = 1;
+
+/// A recorded session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    events: Vec<TraceEvent>,
+}
+
+impl Trace {
+    /// Creates an empty trace.
+    pub const fn new() -> Self {
+        Self { events: Vec::new() }
+    }
+
+    /// Recorded events in order.
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.events
+    }
+
+    /// Appends one event.
+    pub fn push(&mut self, event: TraceEvent) {
+        self.events.push(event);
+    }
+}
+
+/// One recorded exchange.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// Time since recording started, with microsecond resolution.
+    pub elapsed: Duration,
+    pub exchange: Exchange,
+}
+
+/// A recorded call and its result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exchange {
+    /// `Transport::transceive`.
+    Transceive {
+        tx: Vec<u8>,
+        result: Result<Vec<u8>, DesfireError>,
+    },
+    /// `Tag::authenticate`.
+    Authenticate {
+        sector: u8,
+        key_type: KeyType,
+        key: [u8; 6],
+        result: Result<(), TagFailure>,
+    },
+    /// `Tag::read_block`.
+    ReadBlock {
+        block: u8,
+        result: Result<[u8; 16], TagFailure>,
+    },
+    /// `Tag::write_block`.
+    WriteBlock {
+        block: u8,
+        data: [u8; 16],
+        result: Result<(), TagFailure>,
+    },
+}
+
+/// Recorded form of a MIFARE Classic [`Error`](crate::mifare::classic::Error).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagFailure {
+    /// Authentication to the sector of the call failed.
+    AuthenticationFailed,
+    InvalidSector(u8),
+    InvalidBlock(u8),
+    Transport(String),
+}
+
+impl TagFailure {
+    /// Records a MIFARE Classic error.
+    pub fn from_error(error: &ClassicError) -> Self {
+        match error {
+            ClassicError::AuthenticationFailed(_) => Self::AuthenticationFailed,
+            ClassicError::InvalidSector(sector) => Self::InvalidSector(*sector),
+            ClassicError::InvalidBlock(block) => Self::InvalidBlock(*block),
+            ClassicError::TransportError(message) => Self::Transport(message.clone()),
+        }
+    }
+
+    /// Rebuilds the error of a call on `sector`.
+    pub fn to_error(&self, sector: Sector) -> ClassicError {
+        match self {
+            Self::AuthenticationFailed => ClassicError::AuthenticationFailed(sector),
+            Self::InvalidSector(sector) => ClassicError::InvalidSector(*sector),
+            Self::InvalidBlock(block) => ClassicError::InvalidBlock(*block),
+            Self::Transport(message) => ClassicError::TransportError(message.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+    use std::{string::ToString, vec::Vec};
+
+    use heapless::Vec as HeaplessVec;
+
+    use crate::{
+        mifare::{
+            classic::{Block, Error as ClassicError, KeyType, Sector, Tag},
+            desfire::{
+                AccessCondition, AccessRights, ApplicationId, ApplicationKeyType, AsyncDesfire,
+                CommunicationMode, Desfire, Error as DesfireError, FileId, FixedRandom, Frame,
+                KeyNumber, KeySettings, Transport, VirtualApplication, VirtualDesfire, VirtualFile,
+                WrappedFraming, U24,
+            },
+        },
+        trace::{Exchange, Mismatch, ParseError, Replay, TagFailure, Trace, TraceRecorder},
+    };
+
+    const EXAMPLE: &str = "tapsmith-trace 1
+# GetVersion over wrapped framing
+1520 transceive 9060000000 0401010100180591AF
+1733 transceive 90AF000000 - error transport
+2048 authenticate 1 A FFFFFFFFFFFF ok
+2210 read 4 000102030405060708090A0B0C0D0E0F
+2397 write 5 000102030405060708090A0B0C0D0E0F error authentication-failed
+";
+
+    /// Tag that fails every call outside sector 1.
+    struct SectorOneTag;
+
+    impl Tag for SectorOneTag {
+        fn authenticate(
+            &mut self,
+            sector: Sector,
+            _key: &[u8; 6],
+            _key_type: KeyType,
+        ) -> Result<(), ClassicError> {
+            if u8::from(sector) == 1 {
+                Ok(())
+            } else {
+                Err(ClassicError::AuthenticationFailed(sector))
+            }
+        }
+
+        fn read_block(&mut self, block: Block) -> Result<[u8; 16], ClassicError> {
+            Ok([u8::from(block); 16])
+        }
+
+        fn write_block(&mut self, _block: Block, _data: [u8; 16]) -> Result<(), ClassicError> {
+            Err(ClassicError::TransportError("write\nrejected".to_string()))
+        }
+    }
+
+    const AID: ApplicationId = ApplicationId::from_bytes([0x01, 0x02, 0x03]);
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    fn read_card<T: Transport>(transport: T) -> ([u8; 7], Vec<u8>) {
+        let mut desfire = Desfire::new(transport, WrappedFraming);
+        let uid = desfire.get_version().unwrap().uid();
+        desfire.select_application(AID).unwrap();
+        desfire
+            .authenticate_aes(
+                KeyNumber::new(0).unwrap(),
+                &[0; 16],
+                &mut FixedRandom::new(&[7]),
+            )
+            .unwrap();
+        let mut data: HeaplessVec<u8, 64> = HeaplessVec::new();
+        desfire
+            .read_data_enciphered(
+                FileId::new(0).unwrap(),
+                U24::new(0).unwrap(),
+                U24::new(0).unwrap(),
+                &mut data,
+            )
+            .unwrap();
+        (uid, data.to_vec())
+    }
+
+    #[test]
+    fn replays_recorded_desfire_session() {
+        let key_0 = AccessCondition::Key(KeyNumber::new(0).unwrap());
+        let card = VirtualDesfire::new().with_application(
+            VirtualApplication::new(AID, KeySettings::new(0x0F, ApplicationKeyType::Aes, 1))
+                .unwrap()
+                .with_file(
+                    FileId::new(0).unwrap(),
+                    VirtualFile::std_data(
+                        CommunicationMode::Enciphered,
+                        AccessRights::new(key_0, key_0, key_0, key_0),
+                        b"recorded",
+                    ),
+                ),
+        );
+        let mut recorder = TraceRecorder::new(card);
+        let original = read_card(&mut recorder);
+        let (_, trace) = recorder.into_parts();
+
+        let trace: Trace = trace.to_string().parse().unwrap();
+        let mut replay = Replay::new(trace);
+        assert_eq!(read_card(&mut replay), original);
+        assert_eq!(original.1, b"recorded");
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn parses_and_writes_documented_format() {
+        let trace: Trace = EXAMPLE.parse().unwrap();
+
+        assert_eq!(trace.events().len(), 5);
+        assert_eq!(
+            trace.events()[1].exchange,
+            Exchange::Transceive {
+                tx: [0x90, 0xAF, 0x00, 0x00, 0x00].to_vec(),
+                result: Err(DesfireError::Transport),
+            }
+        );
+        assert_eq!(
+            trace.events()[4].exchange,
+            Exchange::WriteBlock {
+                block: 5,
+                data: core::array::from_fn(|i| u8::try_from(i).unwrap()),
+                result: Err(TagFailure::AuthenticationFailed),
+            }
+        );
+        assert_eq!(
+            trace.to_string(),
+            EXAMPLE.replace("# GetVersion over wrapped framing\n", "")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_versions_and_records() {
+        assert_eq!(
+            "tapsmith-trace 2\n".parse::<Trace>(),
+            Err(ParseError::UnsupportedVersion(2))
+        );
+        assert_eq!("".parse::<Trace>(), Err(ParseError::MissingHeader));
+        assert_eq!(
+            "tapsmith-trace 1\n\n10 transceive 9060 zz\n".parse::<Trace>(),
+            Err(ParseError::InvalidLine(3))
+        );
+    }
+
+    #[test]
+    fn records_and_replays_classic_tag_calls() {
+        let mut recorder = TraceRecorder::new(SectorOneTag);
+        let key = [0xFF; 6];
+        let sector = Sector::try_from(1).unwrap();
+        recorder.authenticate(sector, &key, KeyType::KeyB).unwrap();
+        recorder
+            .authenticate(Sector::try_from(2).unwrap(), &key, KeyType::KeyA)
+            .unwrap_err();
+        recorder.read_block(Block::from(4)).unwrap();
+        recorder.write_block(Block::from(5), [1; 16]).unwrap_err();
+        let (_, trace) = recorder.into_parts();
+
+        let mut replay = Replay::new(trace.to_string().parse().unwrap());
+        replay.authenticate(sector, &key, KeyType::KeyB).unwrap();
+        assert!(matches!(
+            replay.authenticate(Sector::try_from(2).unwrap(), &key, KeyType::KeyA),
+            Err(ClassicError::AuthenticationFailed(_))
+        ));
+        assert_eq!(replay.read_block(Block::from(4)).unwrap(), [4; 16]);
+        assert!(matches!(
+            replay.write_block(Block::from(5), [1; 16]),
+            Err(ClassicError::TransportError(message)) if message == "write rejected"
+        ));
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn replays_through_async_traits() {
+        use crate::mifare::classic::AsyncTag;
+
+        let mut replay = Replay::new(EXAMPLE.parse().unwrap());
+
+        block_on(async {
+            let mut desfire = AsyncDesfire::new(&mut replay, WrappedFraming);
+            assert_eq!(desfire.get_version().await, Err(DesfireError::Transport));
+
+            let sector = Sector::try_from(1).unwrap();
+            AsyncTag::authenticate(&mut replay, sector, &[0xFF; 6], KeyType::KeyA)
+                .await
+                .unwrap();
+            assert_eq!(
+                AsyncTag::read_block(&mut replay, Block::from(4))
+                    .await
+                    .unwrap(),
+                core::array::from_fn(|i| u8::try_from(i).unwrap())
+            );
+            assert!(matches!(
+                AsyncTag::write_block(
+                    &mut replay,
+                    Block::from(5),
+                    core::array::from_fn(|i| u8::try_from(i).unwrap())
+                )
+                .await,
+                Err(ClassicError::AuthenticationFailed(_))
+            ));
+        });
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn stops_at_first_mismatch() {
+        let mut replay = Replay::new(EXAMPLE.parse().unwrap());
+        let mut rx = Frame::new();
+
+        assert_eq!(
+            replay.transceive(&[0x90, 0x6A, 0x00, 0x00, 0x00], &mut rx),
+            Err(DesfireError::Transport)
+        );
+        assert_eq!(
+            replay.transceive(&[0x90, 0x60, 0x00, 0x00, 0x00], &mut rx),
+            Err(DesfireError::Transport)
+        );
+        let Some(Mismatch {
+            position: 0,
+            expected: Some(Exchange::Transceive { .. }),
+            ..
+        }) = replay.mismatch()
+        else {
+            panic!("expected a mismatch at the first event");
+        };
+        assert_eq!(replay.remaining(), 5);
+    }
+}
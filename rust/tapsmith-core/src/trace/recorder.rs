@@ -0,0 +1,112 @@
+use std::time::Instant;
+
+use crate::{
+    mifare::{
+        classic::{Block, Error as ClassicError, KeyType, Sector, Tag},
+        desfire::{
+            error::Error as DesfireError,
+            transport::{Frame, Transport},
+        },
+    },
+    trace::{Exchange, TagFailure, Trace, TraceEvent},
+};
+
+/// Records every exchange of the wrapped transport or tag.
+///
+/// Calls are passed through unchanged; the results are copied into a
+/// [`Trace`]. Wrap the value as early as possible so the trace covers the whole
+/// session.
+#[derive(Debug)]
+pub struct TraceRecorder<T> {
+    inner: T,
+    started: Instant,
+    trace: Trace,
+}
+
+impl<T> TraceRecorder<T> {
+    /// Starts recording; elapsed times are measured from this call.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            started: Instant::now(),
+            trace: Trace::new(),
+        }
+    }
+
+    /// Exchanges recorded so far.
+    pub const fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    /// Returns a shared reference to the wrapped value.
+    pub const fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped value.
+    ///
+    /// Calls made directly on the wrapped value are not recorded.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Stops recording and returns the wrapped value and the trace.
+    pub fn into_parts(self) -> (T, Trace) {
+        (self.inner, self.trace)
+    }
+
+    fn record(&mut self, exchange: Exchange) {
+        self.trace.push(TraceEvent {
+            elapsed: self.started.elapsed(),
+            exchange,
+        });
+    }
+}
+
+impl<T: Transport> Transport for TraceRecorder<T> {
+    fn transceive(&mut self, tx: &[u8], rx: &mut Frame) -> Result<(), DesfireError> {
+        let result = self.inner.transceive(tx, rx);
+        self.record(Exchange::Transceive {
+            tx: tx.to_vec(),
+            result: result.map(|()| rx.to_vec()),
+        });
+        result
+    }
+}
+
+impl<T: Tag> Tag for TraceRecorder<T> {
+    fn authenticate(
+        &mut self,
+        sector: Sector,
+        key: &[u8; 6],
+        key_type: KeyType,
+    ) -> Result<(), ClassicError> {
+        let result = self.inner.authenticate(sector, key, key_type);
+        self.record(Exchange::Authenticate {
+            sector: u8::from(sector),
+            key_type,
+            key: *key,
+            result: result.as_ref().copied().map_err(TagFailure::from_error),
+        });
+        result
+    }
+
+    fn read_block(&mut self, block: Block) -> Result<[u8; 16], ClassicError> {
+        let result = self.inner.read_block(block);
+        self.record(Exchange::ReadBlock {
+            block: u8::from(block),
+            result: result.as_ref().copied().map_err(TagFailure::from_error),
+        });
+        result
+    }
+
+    fn write_block(&mut self, block: Block, data: [u8; 16]) -> Result<(), ClassicError> {
+        let result = self.inner.write_block(block, data);
+        self.record(Exchange::WriteBlock {
+            block: u8::from(block),
+            data,
+            result: result.as_ref().copied().map_err(TagFailure::from_error),
+        });
+        result
+    }
+}
@@ -0,0 +1,208 @@
+use std::string::String;
+
+use crate::{
+    mifare::{
+        classic::{AsyncTag, Block, Error as ClassicError, KeyType, Sector, Tag},
+        desfire::{
+            error::Error as DesfireError,
+            transport::{AsyncTransport, Frame, Transport},
+        },
+    },
+    trace::{Exchange, TagFailure, Trace},
+};
+
+/// Serves a recorded [`Trace`] back as a transport or tag.
+///
+/// Each call must match the next recorded event: the same transmitted bytes,
+/// or the same sector, key and block arguments. A matching call returns the
+/// recorded result. A call that does not match fails with a transport error,
+/// and so does every later call, since the session has diverged from the
+/// recording; [`Self::mismatch`] tells where. Recorded times are ignored.
+///
+/// The async traits are implemented too, so a trace can drive an
+/// [`AsyncDesfire`](crate::mifare::desfire::AsyncDesfire) or an [`AsyncTag`]
+/// user; their futures are ready on the first poll.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    trace: Trace,
+    position: usize,
+    mismatch: Option<Mismatch>,
+}
+
+/// The first call that did not match the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Index of the event the call was compared against.
+    pub position: usize,
+    /// The recorded event, or `None` when the trace was exhausted.
+    pub expected: Option<Exchange>,
+    /// The call that was made, with the error the replay returned.
+    pub actual: Exchange,
+}
+
+impl Replay {
+    /// Replays `trace` from its first event.
+    pub const fn new(trace: Trace) -> Self {
+        Self {
+            trace,
+            position: 0,
+            mismatch: None,
+        }
+    }
+
+    /// Number of recorded events not yet replayed.
+    pub fn remaining(&self) -> usize {
+        self.trace.events().len() - self.position
+    }
+
+    /// Whether every event was replayed without a mismatch.
+    pub fn is_finished(&self) -> bool {
+        self.mismatch.is_none() && self.remaining() == 0
+    }
+
+    /// The first call that did not match, if any.
+    pub const fn mismatch(&self) -> Option<&Mismatch> {
+        self.mismatch.as_ref()
+    }
+
+    /// Returns the next event when `matches` accepts it, or records a mismatch.
+    fn next(
+        &mut self,
+        matches: impl FnOnce(&Exchange) -> bool,
+        actual: Exchange,
+    ) -> Option<Exchange> {
+        if self.mismatch.is_some() {
+            return None;
+        }
+        let expected = self
+            .trace
+            .events()
+            .get(self.position)
+            .map(|event| event.exchange.clone());
+        match expected {
+            Some(exchange) if matches(&exchange) => {
+                self.position += 1;
+                Some(exchange)
+            }
+            expected => {
+                self.mismatch = Some(Mismatch {
+                    position: self.position,
+                    expected,
+                    actual,
+                });
+                None
+            }
+        }
+    }
+}
+
+impl Transport for Replay {
+    fn transceive(&mut self, tx: &[u8], rx: &mut Frame) -> Result<(), DesfireError> {
+        rx.clear();
+        let actual = Exchange::Transceive {
+            tx: tx.to_vec(),
+            result: Err(DesfireError::Transport),
+        };
+        let matches = |exchange: &Exchange| matches!(exchange, Exchange::Transceive { tx: recorded, .. } if recorded == tx);
+        let Some(Exchange::Transceive { result, .. }) = self.next(matches, actual) else {
+            return Err(DesfireError::Transport);
+        };
+        rx.extend_from_slice(&result?)
+            .map_err(|_| DesfireError::ResponseTooLong)
+    }
+}
+
+impl Tag for Replay {
+    fn authenticate(
+        &mut self,
+        sector: Sector,
+        key: &[u8; 6],
+        key_type: KeyType,
+    ) -> Result<(), ClassicError> {
+        let sector_number = u8::from(sector);
+        let actual = Exchange::Authenticate {
+            sector: sector_number,
+            key_type,
+            key: *key,
+            result: Err(mismatch_failure()),
+        };
+        let matches = |exchange: &Exchange| {
+            matches!(
+                exchange,
+                Exchange::Authenticate { sector: s, key_type: t, key: k, .. }
+                    if *s == sector_number && *t == key_type && k == key
+            )
+        };
+        match self.next(matches, actual) {
+            Some(Exchange::Authenticate { result, .. }) => {
+                result.map_err(|failure| failure.to_error(sector))
+            }
+            _ => Err(mismatch_failure().to_error(sector)),
+        }
+    }
+
+    fn read_block(&mut self, block: Block) -> Result<[u8; 16], ClassicError> {
+        let block_number = u8::from(block);
+        let actual = Exchange::ReadBlock {
+            block: block_number,
+            result: Err(mismatch_failure()),
+        };
+        let matches = |exchange: &Exchange| matches!(exchange, Exchange::ReadBlock { block: b, .. } if *b == block_number);
+        match self.next(matches, actual) {
+            Some(Exchange::ReadBlock { result, .. }) => {
+                result.map_err(|failure| failure.to_error(Sector::from(block)))
+            }
+            _ => Err(mismatch_failure().to_error(Sector::from(block))),
+        }
+    }
+
+    fn write_block(&mut self, block: Block, data: [u8; 16]) -> Result<(), ClassicError> {
+        let block_number = u8::from(block);
+        let actual = Exchange::WriteBlock {
+            block: block_number,
+            data,
+            result: Err(mismatch_failure()),
+        };
+        let matches = |exchange: &Exchange| {
+            matches!(
+                exchange,
+                Exchange::WriteBlock { block: b, data: d, .. } if *b == block_number && *d == data
+            )
+        };
+        match self.next(matches, actual) {
+            Some(Exchange::WriteBlock { result, .. }) => {
+                result.map_err(|failure| failure.to_error(Sector::from(block)))
+            }
+            _ => Err(mismatch_failure().to_error(Sector::from(block))),
+        }
+    }
+}
+
+impl AsyncTransport for Replay {
+    async fn transceive(&mut self, tx: &[u8], rx: &mut Frame) -> Result<(), DesfireError> {
+        Transport::transceive(self, tx, rx)
+    }
+}
+
+impl AsyncTag for Replay {
+    async fn authenticate(
+        &mut self,
+        sector: Sector,
+        key: &[u8; 6],
+        key_type: KeyType,
+    ) -> Result<(), ClassicError> {
+        Tag::authenticate(self, sector, key, key_type)
+    }
+
+    async fn read_block(&mut self, block: Block) -> Result<[u8; 16], ClassicError> {
+        Tag::read_block(self, block)
+    }
+
+    async fn write_block(&mut self, block: Block, data: [u8; 16]) -> Result<(), ClassicError> {
+        Tag::write_block(self, block, data)
+    }
+}
+
+fn mismatch_failure() -> TagFailure {
+    TagFailure::Transport(String::from("call does not match the recorded trace"))
+}
@@ -0,0 +1,40 @@
+use std::ffi::c_ulong;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    LoadFailed(String),
+    MissingFunction(&'static str),
+    FunctionFailed { function: &'static str, rv: c_ulong },
+    TokenNotFound(String),
+    KeyNotFound(String),
+    AmbiguousKey(String),
+    UnsupportedKeyType(String),
+    KeyMismatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::LoadFailed(msg) => write!(f, "Failed to load PKCS#11 module: {msg}"),
+            Error::MissingFunction(function) => {
+                write!(f, "PKCS#11 module does not provide {function}")
+            }
+            Error::FunctionFailed { function, rv } => {
+                write!(f, "{function} failed with CKR 0x{rv:08X}")
+            }
+            Error::TokenNotFound(label) => write!(f, "No PKCS#11 token labelled {label:?}"),
+            Error::KeyNotFound(label) => write!(f, "No secret key labelled {label:?}"),
+            Error::AmbiguousKey(label) => {
+                write!(f, "More than one secret key is labelled {label:?}")
+            }
+            Error::UnsupportedKeyType(label) => write!(
+                f,
+                "Secret key {label:?} is not a DES, 2TDEA, 3TDEA or AES-128 key"
+            ),
+            Error::KeyMismatch => write!(f, "Key or data does not fit the requested operation"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,585 @@
+//! In-process PKCS#11 module for the unit tests.
+//!
+//! One slot holds the token `tapsmith` with user PIN `1234`. Secret keys are
+//! kept per test thread and the cipher operations, including the
+//! `CBC_ENCRYPT_DATA` derivations, run through [`SoftwareCrypto`].
+
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::slice;
+
+use tapsmith_core::mifare::desfire::{CryptoProvider, Key, SoftwareCrypto};
+
+use crate::sys::{self, CK_ATTRIBUTE, CK_MECHANISM, CK_RV, CK_ULONG};
+
+pub const TOKEN_LABEL: &str = "tapsmith";
+pub const PIN: &str = "1234";
+
+const SLOT: sys::CK_SLOT_ID = 7;
+const SESSION: sys::CK_SESSION_HANDLE = 1;
+const CKR_ATTRIBUTE_SENSITIVE: CK_RV = 0x11;
+const CKR_ATTRIBUTE_TYPE_INVALID: CK_RV = 0x12;
+const CKR_GENERAL_ERROR: CK_RV = 0x05;
+const CKR_KEY_TYPE_INCONSISTENT: CK_RV = 0x63;
+const CKR_MECHANISM_INVALID: CK_RV = 0x70;
+const CKR_OBJECT_HANDLE_INVALID: CK_RV = 0x82;
+const CKR_OPERATION_NOT_INITIALIZED: CK_RV = 0x91;
+const CKR_PIN_INCORRECT: CK_RV = 0xA0;
+
+struct Object {
+    handle: sys::CK_OBJECT_HANDLE,
+    label: Vec<u8>,
+    key_type: sys::CK_KEY_TYPE,
+    value: Vec<u8>,
+    sensitive: bool,
+    extractable: bool,
+}
+
+impl Object {
+    /// The value as a cipher key; generic secrets have none.
+    fn key(&self) -> Option<Key> {
+        let value = self.value.as_slice();
+        match self.key_type {
+            sys::CKK_DES => value.try_into().ok().map(Key::Des),
+            sys::CKK_DES2 => value.try_into().ok().map(Key::TwoKey3Des),
+            sys::CKK_DES3 => value.try_into().ok().map(Key::ThreeKey3Des),
+            sys::CKK_AES => value.try_into().ok().map(Key::Aes128),
+            _ => None,
+        }
+    }
+}
+
+/// Attributes of a key to create, from a `C_CreateObject` or `C_DeriveKey` template.
+struct Template {
+    key_type: Option<sys::CK_KEY_TYPE>,
+    value: Option<Vec<u8>>,
+    value_len: Option<usize>,
+    sensitive: bool,
+    extractable: bool,
+}
+
+impl Template {
+    unsafe fn parse(template: *const CK_ATTRIBUTE, count: CK_ULONG) -> Self {
+        let mut parsed = Template {
+            key_type: None,
+            value: None,
+            value_len: None,
+            sensitive: true,
+            extractable: false,
+        };
+        for attribute in attributes(template, count) {
+            let data = bytes(attribute.pValue.cast(), attribute.ulValueLen);
+            match attribute.type_ {
+                sys::CKA_KEY_TYPE => {
+                    parsed.key_type = Some(attribute.pValue.cast::<CK_ULONG>().read());
+                }
+                sys::CKA_VALUE => parsed.value = Some(data.to_vec()),
+                sys::CKA_VALUE_LEN => {
+                    parsed.value_len = Some(len(attribute.pValue.cast::<CK_ULONG>().read()));
+                }
+                sys::CKA_SENSITIVE => parsed.sensitive = data == [sys::CK_TRUE],
+                sys::CKA_EXTRACTABLE => parsed.extractable = data == [sys::CK_TRUE],
+                _ => {}
+            }
+        }
+        parsed
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Encrypt,
+    Decrypt,
+    Sign,
+}
+
+#[derive(Default)]
+struct Token {
+    initialized: bool,
+    objects: Vec<Object>,
+    next_handle: sys::CK_OBJECT_HANDLE,
+    found: Vec<sys::CK_OBJECT_HANDLE>,
+    operation: Option<(Operation, sys::CK_OBJECT_HANDLE, Vec<u8>)>,
+}
+
+impl Token {
+    fn object(&self, handle: sys::CK_OBJECT_HANDLE) -> Option<&Object> {
+        self.objects.iter().find(|object| object.handle == handle)
+    }
+
+    fn add(&mut self, label: &[u8], key: &Key, sensitive: bool) -> sys::CK_OBJECT_HANDLE {
+        let key_type = match key {
+            Key::Des(_) => sys::CKK_DES,
+            Key::TwoKey3Des(_) => sys::CKK_DES2,
+            Key::ThreeKey3Des(_) => sys::CKK_DES3,
+            Key::Aes128(_) => sys::CKK_AES,
+        };
+        self.insert(Object {
+            handle: 0,
+            label: label.to_vec(),
+            key_type,
+            value: key.as_bytes().to_vec(),
+            sensitive,
+            extractable: !sensitive,
+        })
+    }
+
+    fn insert(&mut self, object: Object) -> sys::CK_OBJECT_HANDLE {
+        self.next_handle += 1;
+        self.objects.push(Object {
+            handle: self.next_handle,
+            ..object
+        });
+        self.next_handle
+    }
+}
+
+thread_local! {
+    static TOKEN: RefCell<Token> = RefCell::new(Token::default());
+}
+
+/// Empties the token of the current thread.
+pub fn reset() {
+    TOKEN.with_borrow_mut(|token| *token = Token::default());
+}
+
+/// Stores a secret key on the token of the current thread.
+///
+/// Sensitive keys are also non-extractable; other keys can be read back.
+pub fn add_key(label: &str, key: &Key, sensitive: bool) {
+    TOKEN.with_borrow_mut(|token| token.add(label.as_bytes(), key, sensitive));
+}
+
+/// Number of objects on the token of the current thread.
+pub fn object_count() -> usize {
+    TOKEN.with_borrow(|token| token.objects.len())
+}
+
+pub static FUNCTIONS: sys::CK_FUNCTION_LIST = sys::CK_FUNCTION_LIST {
+    version: sys::CK_VERSION {
+        major: 2,
+        minor: 40,
+    },
+    C_Initialize: Some(initialize),
+    C_Finalize: Some(finalize),
+    C_GetInfo: None,
+    C_GetFunctionList: None,
+    C_GetSlotList: Some(get_slot_list),
+    C_GetSlotInfo: None,
+    C_GetTokenInfo: Some(get_token_info),
+    C_GetMechanismList: None,
+    C_GetMechanismInfo: None,
+    C_InitToken: None,
+    C_InitPIN: None,
+    C_SetPIN: None,
+    C_OpenSession: Some(open_session),
+    C_CloseSession: Some(close_session),
+    C_CloseAllSessions: None,
+    C_GetSessionInfo: None,
+    C_GetOperationState: None,
+    C_SetOperationState: None,
+    C_Login: Some(login),
+    C_Logout: None,
+    C_CreateObject: Some(create_object),
+    C_CopyObject: None,
+    C_DestroyObject: Some(destroy_object),
+    C_GetObjectSize: None,
+    C_GetAttributeValue: Some(get_attribute_value),
+    C_SetAttributeValue: None,
+    C_FindObjectsInit: Some(find_objects_init),
+    C_FindObjects: Some(find_objects),
+    C_FindObjectsFinal: Some(find_objects_final),
+    C_EncryptInit: Some(encrypt_init),
+    C_Encrypt: Some(run_operation),
+    C_EncryptUpdate: None,
+    C_EncryptFinal: None,
+    C_DecryptInit: Some(decrypt_init),
+    C_Decrypt: Some(run_operation),
+    C_DecryptUpdate: None,
+    C_DecryptFinal: None,
+    C_DigestInit: None,
+    C_Digest: None,
+    C_DigestUpdate: None,
+    C_DigestKey: None,
+    C_DigestFinal: None,
+    C_SignInit: Some(sign_init),
+    C_Sign: Some(run_operation),
+    C_SignUpdate: None,
+    C_SignFinal: None,
+    C_SignRecoverInit: None,
+    C_SignRecover: None,
+    C_VerifyInit: None,
+    C_Verify: None,
+    C_VerifyUpdate: None,
+    C_VerifyFinal: None,
+    C_VerifyRecoverInit: None,
+    C_VerifyRecover: None,
+    C_DigestEncryptUpdate: None,
+    C_DecryptDigestUpdate: None,
+    C_SignEncryptUpdate: None,
+    C_DecryptVerifyUpdate: None,
+    C_GenerateKey: None,
+    C_GenerateKeyPair: None,
+    C_WrapKey: None,
+    C_UnwrapKey: None,
+    C_DeriveKey: Some(derive_key),
+};
+
+unsafe extern "C" fn initialize(_: *mut c_void) -> CK_RV {
+    TOKEN.with_borrow_mut(|token| {
+        if token.initialized {
+            sys::CKR_CRYPTOKI_ALREADY_INITIALIZED
+        } else {
+            token.initialized = true;
+            sys::CKR_OK
+        }
+    })
+}
+
+unsafe extern "C" fn finalize(_: *mut c_void) -> CK_RV {
+    TOKEN.with_borrow_mut(|token| token.initialized = false);
+    sys::CKR_OK
+}
+
+unsafe extern "C" fn get_slot_list(
+    _: sys::CK_BBOOL,
+    slots: *mut sys::CK_SLOT_ID,
+    count: *mut CK_ULONG,
+) -> CK_RV {
+    if !slots.is_null() {
+        *slots = SLOT;
+    }
+    *count = 1;
+    sys::CKR_OK
+}
+
+unsafe extern "C" fn get_token_info(slot: sys::CK_SLOT_ID, info: *mut sys::CK_TOKEN_INFO) -> CK_RV {
+    let mut token_info = sys::CK_TOKEN_INFO::default();
+    token_info.label[..TOKEN_LABEL.len()].copy_from_slice(TOKEN_LABEL.as_bytes());
+    *info = token_info;
+    if slot == SLOT {
+        sys::CKR_OK
+    } else {
+        CKR_GENERAL_ERROR
+    }
+}
+
+unsafe extern "C" fn open_session(
+    _: sys::CK_SLOT_ID,
+    _: sys::CK_FLAGS,
+    _: *mut c_void,
+    _: sys::CK_NOTIFY,
+    session: *mut sys::CK_SESSION_HANDLE,
+) -> CK_RV {
+    *session = SESSION;
+    sys::CKR_OK
+}
+
+unsafe extern "C" fn close_session(_: sys::CK_SESSION_HANDLE) -> CK_RV {
+    sys::CKR_OK
+}
+
+unsafe extern "C" fn login(
+    _: sys::CK_SESSION_HANDLE,
+    _: sys::CK_USER_TYPE,
+    pin: *mut sys::CK_BYTE,
+    pin_len: CK_ULONG,
+) -> CK_RV {
+    if bytes(pin, pin_len) == PIN.as_bytes() {
+        sys::CKR_OK
+    } else {
+        CKR_PIN_INCORRECT
+    }
+}
+
+unsafe extern "C" fn create_object(
+    _: sys::CK_SESSION_HANDLE,
+    template: *mut CK_ATTRIBUTE,
+    count: CK_ULONG,
+    object: *mut sys::CK_OBJECT_HANDLE,
+) -> CK_RV {
+    let template = Template::parse(template, count);
+    let (Some(key_type), Some(value)) = (template.key_type, template.value) else {
+        return CKR_GENERAL_ERROR;
+    };
+    let created = Object {
+        handle: 0,
+        label: Vec::new(),
+        key_type,
+        value,
+        sensitive: template.sensitive,
+        extractable: template.extractable,
+    };
+    if created.key().is_none() {
+        return CKR_GENERAL_ERROR;
+    }
+    *object = TOKEN.with_borrow_mut(|token| token.insert(created));
+    sys::CKR_OK
+}
+
+/// Supports the `CBC_ENCRYPT_DATA` derivations for 3DES and AES and
+/// `CKM_CONCATENATE_BASE_AND_KEY`.
+unsafe extern "C" fn derive_key(
+    _: sys::CK_SESSION_HANDLE,
+    mechanism: *mut CK_MECHANISM,
+    base: sys::CK_OBJECT_HANDLE,
+    template: *mut CK_ATTRIBUTE,
+    count: CK_ULONG,
+    object: *mut sys::CK_OBJECT_HANDLE,
+) -> CK_RV {
+    let mechanism = *mechanism;
+    let template = Template::parse(template, count);
+    let Some(key_type) = template.key_type else {
+        return CKR_GENERAL_ERROR;
+    };
+    TOKEN.with_borrow_mut(|token| {
+        let Some(base) = token.object(base) else {
+            return CKR_OBJECT_HANDLE_INVALID;
+        };
+        let mut value = match mechanism.mechanism {
+            sys::CKM_DES3_CBC_ENCRYPT_DATA | sys::CKM_AES_CBC_ENCRYPT_DATA => {
+                let (iv, data) = if mechanism.mechanism == sys::CKM_AES_CBC_ENCRYPT_DATA {
+                    let params = mechanism
+                        .pParameter
+                        .cast::<sys::CK_AES_CBC_ENCRYPT_DATA_PARAMS>()
+                        .read();
+                    (params.iv.to_vec(), bytes(params.pData, params.length))
+                } else {
+                    let params = mechanism
+                        .pParameter
+                        .cast::<sys::CK_DES_CBC_ENCRYPT_DATA_PARAMS>()
+                        .read();
+                    (params.iv.to_vec(), bytes(params.pData, params.length))
+                };
+                let Some(key) = base.key() else {
+                    return CKR_KEY_TYPE_INCONSISTENT;
+                };
+                let mut data = data.to_vec();
+                if SoftwareCrypto.cbc_encrypt(&key, &iv, &mut data).is_err() {
+                    return CKR_GENERAL_ERROR;
+                }
+                data
+            }
+            sys::CKM_CONCATENATE_BASE_AND_KEY => {
+                let other = mechanism.pParameter.cast::<sys::CK_OBJECT_HANDLE>().read();
+                let Some(other) = token.object(other) else {
+                    return CKR_OBJECT_HANDLE_INVALID;
+                };
+                [base.value.as_slice(), other.value.as_slice()].concat()
+            }
+            _ => return CKR_MECHANISM_INVALID,
+        };
+        if let Some(value_len) = template.value_len {
+            value.truncate(value_len);
+        }
+        let derived = Object {
+            handle: 0,
+            label: Vec::new(),
+            key_type,
+            value,
+            sensitive: template.sensitive,
+            extractable: template.extractable,
+        };
+        if key_type != sys::CKK_GENERIC_SECRET && derived.key().is_none() {
+            return CKR_KEY_TYPE_INCONSISTENT;
+        }
+        *object = token.insert(derived);
+        sys::CKR_OK
+    })
+}
+
+unsafe extern "C" fn destroy_object(
+    _: sys::CK_SESSION_HANDLE,
+    object: sys::CK_OBJECT_HANDLE,
+) -> CK_RV {
+    TOKEN.with_borrow_mut(|token| {
+        let before = token.objects.len();
+        token.objects.retain(|candidate| candidate.handle != object);
+        if token.objects.len() < before {
+            sys::CKR_OK
+        } else {
+            CKR_OBJECT_HANDLE_INVALID
+        }
+    })
+}
+
+unsafe extern "C" fn get_attribute_value(
+    _: sys::CK_SESSION_HANDLE,
+    object: sys::CK_OBJECT_HANDLE,
+    template: *mut CK_ATTRIBUTE,
+    count: CK_ULONG,
+) -> CK_RV {
+    TOKEN.with_borrow(|token| {
+        let Some(object) = token.object(object) else {
+            return CKR_OBJECT_HANDLE_INVALID;
+        };
+        let mut rv = sys::CKR_OK;
+        for attribute in attributes_mut(template, count) {
+            let value_len = object.value.len() as CK_ULONG;
+            let flag = |set: bool| if set { sys::CK_TRUE } else { sys::CK_FALSE };
+            match attribute.type_ {
+                sys::CKA_KEY_TYPE => attribute.pValue.cast::<CK_ULONG>().write(object.key_type),
+                sys::CKA_VALUE_LEN => attribute.pValue.cast::<CK_ULONG>().write(value_len),
+                sys::CKA_SENSITIVE => attribute.pValue.cast::<u8>().write(flag(object.sensitive)),
+                sys::CKA_EXTRACTABLE => {
+                    attribute
+                        .pValue
+                        .cast::<u8>()
+                        .write(flag(object.extractable));
+                }
+                sys::CKA_VALUE if object.sensitive || !object.extractable => {
+                    attribute.ulValueLen = CK_ULONG::MAX;
+                    rv = CKR_ATTRIBUTE_SENSITIVE;
+                }
+                sys::CKA_VALUE => {
+                    slice::from_raw_parts_mut(attribute.pValue.cast::<u8>(), object.value.len())
+                        .copy_from_slice(&object.value);
+                    attribute.ulValueLen = value_len;
+                }
+                _ => {
+                    attribute.ulValueLen = CK_ULONG::MAX;
+                    rv = CKR_ATTRIBUTE_TYPE_INVALID;
+                }
+            }
+        }
+        rv
+    })
+}
+
+unsafe extern "C" fn find_objects_init(
+    _: sys::CK_SESSION_HANDLE,
+    template: *mut CK_ATTRIBUTE,
+    count: CK_ULONG,
+) -> CK_RV {
+    let mut label = None;
+    for attribute in attributes(template, count) {
+        if attribute.type_ == sys::CKA_LABEL {
+            label = Some(bytes(attribute.pValue.cast(), attribute.ulValueLen).to_vec());
+        }
+    }
+    TOKEN.with_borrow_mut(|token| {
+        token.found = token
+            .objects
+            .iter()
+            .filter(|object| label.as_ref().is_none_or(|label| object.label == *label))
+            .map(|object| object.handle)
+            .collect();
+    });
+    sys::CKR_OK
+}
+
+unsafe extern "C" fn find_objects(
+    _: sys::CK_SESSION_HANDLE,
+    objects: *mut sys::CK_OBJECT_HANDLE,
+    max: CK_ULONG,
+    count: *mut CK_ULONG,
+) -> CK_RV {
+    TOKEN.with_borrow_mut(|token| {
+        let taken = token.found.len().min(len(max));
+        for (index, handle) in token.found.drain(..taken).enumerate() {
+            *objects.add(index) = handle;
+        }
+        *count = taken as CK_ULONG;
+    });
+    sys::CKR_OK
+}
+
+unsafe extern "C" fn find_objects_final(_: sys::CK_SESSION_HANDLE) -> CK_RV {
+    TOKEN.with_borrow_mut(|token| token.found.clear());
+    sys::CKR_OK
+}
+
+unsafe extern "C" fn encrypt_init(
+    _: sys::CK_SESSION_HANDLE,
+    mechanism: *mut CK_MECHANISM,
+    key: sys::CK_OBJECT_HANDLE,
+) -> CK_RV {
+    start(Operation::Encrypt, mechanism, key)
+}
+
+unsafe extern "C" fn decrypt_init(
+    _: sys::CK_SESSION_HANDLE,
+    mechanism: *mut CK_MECHANISM,
+    key: sys::CK_OBJECT_HANDLE,
+) -> CK_RV {
+    start(Operation::Decrypt, mechanism, key)
+}
+
+unsafe extern "C" fn sign_init(
+    _: sys::CK_SESSION_HANDLE,
+    mechanism: *mut CK_MECHANISM,
+    key: sys::CK_OBJECT_HANDLE,
+) -> CK_RV {
+    start(Operation::Sign, mechanism, key)
+}
+
+unsafe fn start(
+    operation: Operation,
+    mechanism: *mut CK_MECHANISM,
+    key: sys::CK_OBJECT_HANDLE,
+) -> CK_RV {
+    let mechanism = *mechanism;
+    let parameter = bytes(mechanism.pParameter.cast(), mechanism.ulParameterLen).to_vec();
+    TOKEN.with_borrow_mut(|token| {
+        if token.object(key).is_none() {
+            return CKR_OBJECT_HANDLE_INVALID;
+        }
+        token.operation = Some((operation, key, parameter));
+        sys::CKR_OK
+    })
+}
+
+unsafe extern "C" fn run_operation(
+    _: sys::CK_SESSION_HANDLE,
+    data: *mut sys::CK_BYTE,
+    data_len: CK_ULONG,
+    output: *mut sys::CK_BYTE,
+    output_len: *mut CK_ULONG,
+) -> CK_RV {
+    TOKEN.with_borrow_mut(|token| {
+        let Some((operation, key, iv)) = token.operation.take() else {
+            return CKR_OPERATION_NOT_INITIALIZED;
+        };
+        let Some(key) = token.object(key).expect("checked on init").key() else {
+            return CKR_KEY_TYPE_INCONSISTENT;
+        };
+        let key = &key;
+        let mut buffer = bytes(data, data_len).to_vec();
+        let result = match operation {
+            Operation::Encrypt => SoftwareCrypto.cbc_encrypt(key, &iv, &mut buffer),
+            Operation::Decrypt => SoftwareCrypto.cbc_decrypt(key, &iv, &mut buffer),
+            Operation::Sign => SoftwareCrypto
+                .cmac(key, &buffer)
+                .map(|cmac| buffer = cmac.as_bytes().to_vec()),
+        };
+        if result.is_err() {
+            return CKR_GENERAL_ERROR;
+        }
+        slice::from_raw_parts_mut(output, buffer.len()).copy_from_slice(&buffer);
+        *output_len = buffer.len() as CK_ULONG;
+        sys::CKR_OK
+    })
+}
+
+unsafe fn bytes<'a>(data: *const u8, data_len: CK_ULONG) -> &'a [u8] {
+    if data.is_null() {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len(data_len))
+    }
+}
+
+unsafe fn attributes<'a>(template: *const CK_ATTRIBUTE, count: CK_ULONG) -> &'a [CK_ATTRIBUTE] {
+    slice::from_raw_parts(template, len(count))
+}
+
+unsafe fn attributes_mut<'a>(
+    template: *mut CK_ATTRIBUTE,
+    count: CK_ULONG,
+) -> &'a mut [CK_ATTRIBUTE] {
+    slice::from_raw_parts_mut(template, len(count))
+}
+
+fn len(value: CK_ULONG) -> usize {
+    usize::try_from(value).expect("lengths fit in usize")
+}
@@ -0,0 +1,25 @@
+//! PKCS#11 key provider for `DESFire` and Gallagher keys held in an HSM.
+//!
+//! [`Pkcs11Token`] loads a PKCS#11 module, logs in to one of its tokens and
+//! implements [`tapsmith_core::mifare::desfire::CryptoProvider`] over the
+//! secret keys stored there.
+//!
+//! The ignored tests in `tests/softhsm.rs` run against a `SoftHSM` token:
+//!
+//! ```text
+//! softhsm2-util --init-token --free --label tapsmith --pin 1234 --so-pin 0000
+//! TAPSMITH_PKCS11_MODULE=/usr/lib/softhsm/libsofthsm2.so \
+//!     cargo test -p tapsmith-pkcs11 -- --ignored
+//! ```
+
+// Calls into the loaded module go through raw function pointers.
+#![allow(unsafe_code)]
+
+mod error;
+#[cfg(test)]
+mod fake;
+mod sys;
+mod token;
+
+pub use error::Error;
+pub use token::{Pkcs11Key, Pkcs11Token};
@@ -0,0 +1,1072 @@
+use std::ffi::{c_void, OsStr};
+use std::ptr;
+
+use libloading::Library;
+use tapsmith_core::mifare::desfire::{
+    self as desfire,
+    kdf::{final_cmac_blocks_with, FinalCmacBlock},
+    AesCmac, ChangeKeyCryptogram, ChangeKeyRequest, CryptoProvider, DiversificationInput, Key,
+    KeyAlgorithm, SessionKey,
+};
+
+use crate::{sys, Error};
+
+/// Calls a function from the module's function list, turning any `CKR`
+/// other than `CKR_OK` into [`Error::FunctionFailed`].
+macro_rules! call {
+    ($functions:expr, $name:ident($($arg:expr),* $(,)?)) => {
+        match $functions.$name {
+            // SAFETY: the function list comes from the module and every
+            // pointer argument refers to a live buffer of the length passed
+            // with it.
+            Some(function) => check(stringify!($name), unsafe { function($($arg),*) }),
+            None => Err(Error::MissingFunction(stringify!($name))),
+        }
+    };
+}
+
+/// A secret key object on a PKCS#11 token.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Pkcs11Key {
+    object: sys::CK_OBJECT_HANDLE,
+    algorithm: KeyAlgorithm,
+    identical_halves: bool,
+    session_object: bool,
+    /// Whether `CKA_VALUE` can be read: not sensitive and extractable.
+    readable: bool,
+}
+
+impl Pkcs11Key {
+    /// Cipher family of the key, from its `CKA_KEY_TYPE`.
+    pub const fn algorithm(&self) -> KeyAlgorithm {
+        self.algorithm
+    }
+}
+
+/// Logged-in session on a PKCS#11 token.
+///
+/// Implements [`CryptoProvider`], so `DESFire` authentication, Gallagher key
+/// diversification and `ChangeKey` encryption run against key objects that
+/// stay on the token. Authentication and master keys can be sensitive and
+/// non-extractable; they need `CKA_ENCRYPT`, `CKA_DECRYPT`, `CKA_SIGN` for
+/// AES CMACs and `CKA_DERIVE` for diversification.
+///
+/// A `ChangeKey` cryptogram carries CRCs of the new key, which no standard
+/// mechanism computes inside a token, so the keys it writes, and the old
+/// value of a slot other than the authenticated key, are read through
+/// `CKA_VALUE` and must be extractable and not sensitive. Keys that only
+/// authenticate never leave the token.
+///
+/// A failed operation surfaces as [`desfire::Error::CryptoProvider`];
+/// [`Self::last_error`] keeps the PKCS#11 error behind it.
+pub struct Pkcs11Token {
+    functions: *const sys::CK_FUNCTION_LIST,
+    session: sys::CK_SESSION_HANDLE,
+    finalize: bool,
+    last_error: Option<Error>,
+    _library: Option<Library>,
+}
+
+impl Pkcs11Token {
+    /// Loads a PKCS#11 module, opens a session on the token labelled
+    /// `token_label` and logs in as the user with `pin`.
+    pub fn open(
+        module: impl AsRef<OsStr>,
+        token_label: &str,
+        pin: &str,
+    ) -> Result<Pkcs11Token, Error> {
+        // SAFETY: loading a PKCS#11 module runs its initializers; the caller
+        // names the module to trust.
+        let library = unsafe { Library::new(module.as_ref()) }
+            .map_err(|err| Error::LoadFailed(err.to_string()))?;
+        let mut functions = ptr::null_mut();
+        {
+            // SAFETY: the signature is fixed by the PKCS#11 specification.
+            let get_function_list =
+                unsafe { library.get::<sys::CK_C_GetFunctionList>(b"C_GetFunctionList\0") }
+                    .map_err(|err| Error::LoadFailed(err.to_string()))?;
+            // SAFETY: `functions` is a valid out pointer.
+            check("C_GetFunctionList", unsafe {
+                get_function_list(&raw mut functions)
+            })?;
+        }
+        if functions.is_null() {
+            return Err(Error::LoadFailed(
+                "C_GetFunctionList returned no function list".to_owned(),
+            ));
+        }
+
+        Self::open_with(Some(library), functions, token_label, pin)
+    }
+
+    fn open_with(
+        library: Option<Library>,
+        functions: *const sys::CK_FUNCTION_LIST,
+        token_label: &str,
+        pin: &str,
+    ) -> Result<Pkcs11Token, Error> {
+        // SAFETY: callers pass a non-null function list that outlives `library`.
+        let initialize = unsafe { &*functions }
+            .C_Initialize
+            .ok_or(Error::MissingFunction("C_Initialize"))?;
+        // SAFETY: a null argument asks for the default, single-threaded setup.
+        let finalize = match unsafe { initialize(ptr::null_mut()) } {
+            sys::CKR_OK => true,
+            sys::CKR_CRYPTOKI_ALREADY_INITIALIZED => false,
+            rv => {
+                return Err(Error::FunctionFailed {
+                    function: "C_Initialize",
+                    rv,
+                })
+            }
+        };
+
+        let mut token = Pkcs11Token {
+            functions,
+            session: sys::CK_INVALID_HANDLE,
+            finalize,
+            last_error: None,
+            _library: library,
+        };
+        let slot = token.find_slot(token_label)?;
+
+        let mut session = sys::CK_INVALID_HANDLE;
+        call!(
+            token.functions(),
+            C_OpenSession(
+                slot,
+                sys::CKF_SERIAL_SESSION | sys::CKF_RW_SESSION,
+                ptr::null_mut(),
+                None,
+                &raw mut session,
+            )
+        )?;
+        token.session = session;
+
+        let mut pin = pin.as_bytes().to_vec();
+        let login = call!(
+            token.functions(),
+            C_Login(session, sys::CKU_USER, pin.as_mut_ptr(), ulong(pin.len()))
+        );
+        pin.fill(0);
+        match login {
+            Err(Error::FunctionFailed {
+                rv: sys::CKR_USER_ALREADY_LOGGED_IN,
+                ..
+            }) => {}
+            other => other?,
+        }
+
+        Ok(token)
+    }
+
+    /// Looks up the secret key labelled `label`.
+    ///
+    /// The label must name exactly one DES, 2TDEA, 3TDEA or AES-128 key.
+    pub fn find_key(&mut self, label: &str) -> Result<Pkcs11Key, Error> {
+        let mut class = sys::CKO_SECRET_KEY;
+        let mut label_bytes = label.as_bytes().to_vec();
+        let mut template = [
+            attribute(sys::CKA_CLASS, &mut class),
+            bytes_attribute(sys::CKA_LABEL, &mut label_bytes),
+        ];
+        call!(
+            self.functions(),
+            C_FindObjectsInit(self.session, template.as_mut_ptr(), ulong(template.len()))
+        )?;
+        let mut objects = [sys::CK_INVALID_HANDLE; 2];
+        let mut count: sys::CK_ULONG = 0;
+        let found = call!(
+            self.functions(),
+            C_FindObjects(
+                self.session,
+                objects.as_mut_ptr(),
+                ulong(objects.len()),
+                &raw mut count,
+            )
+        );
+        call!(self.functions(), C_FindObjectsFinal(self.session))?;
+        found?;
+
+        let object = match count {
+            0 => return Err(Error::KeyNotFound(label.to_owned())),
+            1 => objects[0],
+            _ => return Err(Error::AmbiguousKey(label.to_owned())),
+        };
+
+        let mut key_type: sys::CK_KEY_TYPE = 0;
+        let mut value_len: sys::CK_ULONG = 0;
+        let mut sensitive = sys::CK_TRUE;
+        let mut extractable = sys::CK_FALSE;
+        let mut attributes = [
+            attribute(sys::CKA_KEY_TYPE, &mut key_type),
+            attribute(sys::CKA_VALUE_LEN, &mut value_len),
+            attribute(sys::CKA_SENSITIVE, &mut sensitive),
+            attribute(sys::CKA_EXTRACTABLE, &mut extractable),
+        ];
+        // Not every module reports CKA_VALUE_LEN for DES keys.
+        let _ = call!(
+            self.functions(),
+            C_GetAttributeValue(
+                self.session,
+                object,
+                attributes.as_mut_ptr(),
+                ulong(attributes.len()),
+            )
+        );
+        if attributes[0].ulValueLen == sys::CK_ULONG::MAX {
+            return Err(Error::UnsupportedKeyType(label.to_owned()));
+        }
+        let algorithm = match key_type {
+            sys::CKK_DES => KeyAlgorithm::Des,
+            sys::CKK_DES2 => KeyAlgorithm::TwoKey3Des,
+            sys::CKK_DES3 => KeyAlgorithm::ThreeKey3Des,
+            sys::CKK_AES if value_len == 16 => KeyAlgorithm::Aes128,
+            _ => return Err(Error::UnsupportedKeyType(label.to_owned())),
+        };
+
+        let mut key = Pkcs11Key {
+            object,
+            algorithm,
+            identical_halves: false,
+            session_object: false,
+            readable: sensitive == sys::CK_FALSE && extractable == sys::CK_TRUE,
+        };
+        // A 2TDEA key with equal halves authenticates as single DES; that can
+        // only be told from the value, so sensitive keys count as 2TDEA.
+        if algorithm == KeyAlgorithm::TwoKey3Des {
+            if let Ok(Key::TwoKey3Des(value)) = self.key_value(&key) {
+                key.identical_halves = desfire::constant_time_eq(&value[..8], &value[8..]);
+            }
+        }
+        Ok(key)
+    }
+
+    /// Imports `key` as a session object, destroyed when the session closes
+    /// or the handle is passed to [`CryptoProvider::release`].
+    ///
+    /// The object is extractable, so it can also be written by `ChangeKey`.
+    pub fn import_key(&mut self, key: &Key) -> Result<Pkcs11Key, Error> {
+        self.create_key(key, false)
+    }
+
+    fn create_key(&mut self, key: &Key, sensitive: bool) -> Result<Pkcs11Key, Error> {
+        let algorithm = key.algorithm();
+        let mut class = sys::CKO_SECRET_KEY;
+        let mut key_type = key_type(algorithm);
+        let mut value = key.clone();
+        let mut yes = sys::CK_TRUE;
+        let mut no = sys::CK_FALSE;
+        let (mut sensitive_flag, mut extractable) = if sensitive {
+            (sys::CK_TRUE, sys::CK_FALSE)
+        } else {
+            (sys::CK_FALSE, sys::CK_TRUE)
+        };
+        let mut template = [
+            attribute(sys::CKA_CLASS, &mut class),
+            attribute(sys::CKA_KEY_TYPE, &mut key_type),
+            attribute(sys::CKA_TOKEN, &mut no),
+            bytes_attribute(sys::CKA_VALUE, key_bytes_mut(&mut value)),
+            attribute(sys::CKA_ENCRYPT, &mut yes),
+            attribute(sys::CKA_DECRYPT, &mut yes),
+            attribute(sys::CKA_SIGN, &mut yes),
+            attribute(sys::CKA_DERIVE, &mut yes),
+            attribute(sys::CKA_SENSITIVE, &mut sensitive_flag),
+            attribute(sys::CKA_EXTRACTABLE, &mut extractable),
+        ];
+        let mut object = sys::CK_INVALID_HANDLE;
+        call!(
+            self.functions(),
+            C_CreateObject(
+                self.session,
+                template.as_mut_ptr(),
+                ulong(template.len()),
+                &raw mut object,
+            )
+        )?;
+
+        let bytes = key.as_bytes();
+        Ok(Pkcs11Key {
+            object,
+            algorithm,
+            identical_halves: algorithm == KeyAlgorithm::TwoKey3Des
+                && desfire::constant_time_eq(&bytes[..8], &bytes[8..]),
+            session_object: true,
+            readable: !sensitive,
+        })
+    }
+
+    /// PKCS#11 error behind the last [`desfire::Error::CryptoProvider`].
+    pub fn last_error(&self) -> Option<&Error> {
+        self.last_error.as_ref()
+    }
+
+    fn functions(&self) -> &sys::CK_FUNCTION_LIST {
+        // SAFETY: the function list is checked non-null on open and stays
+        // valid while the module is loaded.
+        unsafe { &*self.functions }
+    }
+
+    fn find_slot(&self, token_label: &str) -> Result<sys::CK_SLOT_ID, Error> {
+        let mut count: sys::CK_ULONG = 0;
+        call!(
+            self.functions(),
+            C_GetSlotList(sys::CK_TRUE, ptr::null_mut(), &raw mut count)
+        )?;
+        let mut slots = vec![0; usize::try_from(count).unwrap_or(0)];
+        call!(
+            self.functions(),
+            C_GetSlotList(sys::CK_TRUE, slots.as_mut_ptr(), &raw mut count)
+        )?;
+        slots.truncate(usize::try_from(count).unwrap_or(0));
+
+        for slot in slots {
+            let mut info = sys::CK_TOKEN_INFO::default();
+            call!(self.functions(), C_GetTokenInfo(slot, &raw mut info))?;
+            let label = info.label;
+            if label.trim_ascii_end() == token_label.as_bytes() {
+                return Ok(slot);
+            }
+        }
+        Err(Error::TokenNotFound(token_label.to_owned()))
+    }
+
+    fn key_value(&self, key: &Pkcs11Key) -> Result<Key, Error> {
+        let mut value = match key.algorithm {
+            KeyAlgorithm::Des => Key::Des([0; 8]),
+            KeyAlgorithm::TwoKey3Des => Key::TwoKey3Des([0; 16]),
+            KeyAlgorithm::ThreeKey3Des => Key::ThreeKey3Des([0; 24]),
+            KeyAlgorithm::Aes128 => Key::Aes128([0; 16]),
+        };
+        let bytes = key_bytes_mut(&mut value);
+        let expected = ulong(bytes.len());
+        let mut template = [bytes_attribute(sys::CKA_VALUE, bytes)];
+        call!(
+            self.functions(),
+            C_GetAttributeValue(self.session, key.object, template.as_mut_ptr(), 1)
+        )?;
+        if template[0].ulValueLen != expected {
+            return Err(Error::KeyMismatch);
+        }
+        Ok(value)
+    }
+
+    fn cbc(
+        &mut self,
+        encrypt: bool,
+        key: &Pkcs11Key,
+        iv: &[u8],
+        data: &mut [u8],
+    ) -> Result<(), Error> {
+        let block_size = key.algorithm.block_size();
+        if iv.len() != block_size || !data.len().is_multiple_of(block_size) {
+            return Err(Error::KeyMismatch);
+        }
+        let mut iv = iv.to_vec();
+        let mut mechanism = sys::CK_MECHANISM {
+            mechanism: match key.algorithm {
+                KeyAlgorithm::Des => sys::CKM_DES_CBC,
+                KeyAlgorithm::TwoKey3Des | KeyAlgorithm::ThreeKey3Des => sys::CKM_DES3_CBC,
+                KeyAlgorithm::Aes128 => sys::CKM_AES_CBC,
+            },
+            pParameter: iv.as_mut_ptr().cast::<c_void>(),
+            ulParameterLen: ulong(iv.len()),
+        };
+        let mut output = vec![0u8; data.len()];
+        let mut output_len = ulong(output.len());
+        let result = if encrypt {
+            call!(
+                self.functions(),
+                C_EncryptInit(self.session, &raw mut mechanism, key.object)
+            )
+            .and_then(|()| {
+                call!(
+                    self.functions(),
+                    C_Encrypt(
+                        self.session,
+                        data.as_mut_ptr(),
+                        ulong(data.len()),
+                        output.as_mut_ptr(),
+                        &raw mut output_len,
+                    )
+                )
+            })
+        } else {
+            call!(
+                self.functions(),
+                C_DecryptInit(self.session, &raw mut mechanism, key.object)
+            )
+            .and_then(|()| {
+                call!(
+                    self.functions(),
+                    C_Decrypt(
+                        self.session,
+                        data.as_mut_ptr(),
+                        ulong(data.len()),
+                        output.as_mut_ptr(),
+                        &raw mut output_len,
+                    )
+                )
+            })
+        };
+        if result.is_ok() && output_len == ulong(data.len()) {
+            data.copy_from_slice(&output);
+        }
+        output.fill(0);
+        result?;
+        if output_len == ulong(data.len()) {
+            Ok(())
+        } else {
+            Err(Error::KeyMismatch)
+        }
+    }
+
+    fn sign_cmac(&mut self, key: &Pkcs11Key, data: &[u8]) -> Result<AesCmac, Error> {
+        if key.algorithm != KeyAlgorithm::Aes128 {
+            return Err(Error::KeyMismatch);
+        }
+        let mut mechanism = sys::CK_MECHANISM {
+            mechanism: sys::CKM_AES_CMAC,
+            pParameter: ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let mut data = data.to_vec();
+        let mut cmac = [0u8; 16];
+        let mut cmac_len = ulong(cmac.len());
+        call!(
+            self.functions(),
+            C_SignInit(self.session, &raw mut mechanism, key.object)
+        )?;
+        call!(
+            self.functions(),
+            C_Sign(
+                self.session,
+                data.as_mut_ptr(),
+                ulong(data.len()),
+                cmac.as_mut_ptr(),
+                &raw mut cmac_len,
+            )
+        )?;
+        if cmac_len != ulong(cmac.len()) {
+            return Err(Error::KeyMismatch);
+        }
+        Ok(AesCmac::new(cmac))
+    }
+
+    /// Finishes the AN10922 CMACs under `master` into a key on the token.
+    ///
+    /// DES-family keys are derived part by part and joined with
+    /// `CKM_CONCATENATE_BASE_AND_KEY`; the parts are destroyed afterwards.
+    fn derive(&mut self, master: &Pkcs11Key, steps: &[FinalCmacBlock]) -> Result<Pkcs11Key, Error> {
+        let object = if master.algorithm == KeyAlgorithm::Aes128 {
+            let [step] = steps else {
+                return Err(Error::KeyMismatch);
+            };
+            self.derive_object(master, step, sys::CKK_AES, Some(16))?
+        } else {
+            let mut parts = Vec::with_capacity(steps.len());
+            let result = self.derive_tdea(master, steps, &mut parts);
+            for part in parts {
+                let _ = call!(self.functions(), C_DestroyObject(self.session, part));
+            }
+            result?
+        };
+        Ok(Pkcs11Key {
+            object,
+            algorithm: master.algorithm,
+            identical_halves: false,
+            session_object: true,
+            readable: master.readable,
+        })
+    }
+
+    /// Derives the 8-byte parts of a 2TDEA or 3TDEA key and concatenates them,
+    /// leaving every intermediate object in `parts`.
+    fn derive_tdea(
+        &mut self,
+        master: &Pkcs11Key,
+        steps: &[FinalCmacBlock],
+        parts: &mut Vec<sys::CK_OBJECT_HANDLE>,
+    ) -> Result<sys::CK_OBJECT_HANDLE, Error> {
+        let Some((first, rest)) = steps.split_first() else {
+            return Err(Error::KeyMismatch);
+        };
+        let mut joined = self.derive_object(master, first, sys::CKK_GENERIC_SECRET, Some(8))?;
+        parts.push(joined);
+        for (index, step) in rest.iter().enumerate() {
+            let part = self.derive_object(master, step, sys::CKK_GENERIC_SECRET, Some(8))?;
+            parts.push(part);
+            let last = index + 1 == rest.len();
+            let (key_type, value_len) = match (last, master.algorithm) {
+                (true, algorithm) => (key_type(algorithm), None),
+                (false, _) => (sys::CKK_GENERIC_SECRET, Some(8 * (index + 2))),
+            };
+            let mut other = part;
+            let mut mechanism = sys::CK_MECHANISM {
+                mechanism: sys::CKM_CONCATENATE_BASE_AND_KEY,
+                pParameter: ptr::from_mut(&mut other).cast::<c_void>(),
+                ulParameterLen: ulong(size_of::<sys::CK_OBJECT_HANDLE>()),
+            };
+            joined = self.derive_with(&mut mechanism, joined, master, key_type, value_len)?;
+            if !last {
+                parts.push(joined);
+            }
+        }
+        Ok(joined)
+    }
+
+    /// Runs the last CBC step of one CMAC as a `CBC_ENCRYPT_DATA` derivation.
+    fn derive_object(
+        &mut self,
+        master: &Pkcs11Key,
+        step: &FinalCmacBlock,
+        key_type: sys::CK_KEY_TYPE,
+        value_len: Option<usize>,
+    ) -> Result<sys::CK_OBJECT_HANDLE, Error> {
+        let mut data = step.block().to_vec();
+        let mut aes_params;
+        let mut des_params;
+        let mut mechanism = if master.algorithm == KeyAlgorithm::Aes128 {
+            aes_params = sys::CK_AES_CBC_ENCRYPT_DATA_PARAMS {
+                iv: step.chaining().try_into().map_err(|_| Error::KeyMismatch)?,
+                pData: data.as_mut_ptr(),
+                length: ulong(data.len()),
+            };
+            sys::CK_MECHANISM {
+                mechanism: sys::CKM_AES_CBC_ENCRYPT_DATA,
+                pParameter: ptr::from_mut(&mut aes_params).cast::<c_void>(),
+                ulParameterLen: ulong(size_of::<sys::CK_AES_CBC_ENCRYPT_DATA_PARAMS>()),
+            }
+        } else {
+            des_params = sys::CK_DES_CBC_ENCRYPT_DATA_PARAMS {
+                iv: step.chaining().try_into().map_err(|_| Error::KeyMismatch)?,
+                pData: data.as_mut_ptr(),
+                length: ulong(data.len()),
+            };
+            sys::CK_MECHANISM {
+                mechanism: sys::CKM_DES3_CBC_ENCRYPT_DATA,
+                pParameter: ptr::from_mut(&mut des_params).cast::<c_void>(),
+                ulParameterLen: ulong(size_of::<sys::CK_DES_CBC_ENCRYPT_DATA_PARAMS>()),
+            }
+        };
+        self.derive_with(&mut mechanism, master.object, master, key_type, value_len)
+    }
+
+    /// `C_DeriveKey` into a session object that is readable only if `master` is.
+    fn derive_with(
+        &mut self,
+        mechanism: &mut sys::CK_MECHANISM,
+        base: sys::CK_OBJECT_HANDLE,
+        master: &Pkcs11Key,
+        key_type: sys::CK_KEY_TYPE,
+        value_len: Option<usize>,
+    ) -> Result<sys::CK_OBJECT_HANDLE, Error> {
+        let mut class = sys::CKO_SECRET_KEY;
+        let mut key_type = key_type;
+        let mut yes = sys::CK_TRUE;
+        let mut no = sys::CK_FALSE;
+        let (mut sensitive, mut extractable) = if master.readable {
+            (no, yes)
+        } else {
+            (yes, no)
+        };
+        let mut value_len = value_len.map(ulong);
+        let mut template = vec![
+            attribute(sys::CKA_CLASS, &mut class),
+            attribute(sys::CKA_KEY_TYPE, &mut key_type),
+            attribute(sys::CKA_TOKEN, &mut no),
+            attribute(sys::CKA_DERIVE, &mut yes),
+            attribute(sys::CKA_SENSITIVE, &mut sensitive),
+            attribute(sys::CKA_EXTRACTABLE, &mut extractable),
+        ];
+        if let Some(value_len) = &mut value_len {
+            template.push(attribute(sys::CKA_VALUE_LEN, value_len));
+        }
+        if key_type != sys::CKK_GENERIC_SECRET {
+            template.extend([
+                attribute(sys::CKA_ENCRYPT, &mut yes),
+                attribute(sys::CKA_DECRYPT, &mut yes),
+                attribute(sys::CKA_SIGN, &mut yes),
+            ]);
+        }
+        let mut object = sys::CK_INVALID_HANDLE;
+        call!(
+            self.functions(),
+            C_DeriveKey(
+                self.session,
+                ptr::from_mut(mechanism),
+                base,
+                template.as_mut_ptr(),
+                ulong(template.len()),
+                &raw mut object,
+            )
+        )?;
+        Ok(object)
+    }
+
+    fn record<T>(&mut self, result: Result<T, Error>) -> Result<T, desfire::Error> {
+        result.map_err(|error| {
+            self.last_error = Some(error);
+            desfire::Error::CryptoProvider
+        })
+    }
+}
+
+impl CryptoProvider for Pkcs11Token {
+    type KeyHandle = Pkcs11Key;
+
+    fn algorithm(&self, key: &Pkcs11Key) -> Result<KeyAlgorithm, desfire::Error> {
+        Ok(key.algorithm)
+    }
+
+    fn has_identical_halves(&self, key: &Pkcs11Key) -> Result<bool, desfire::Error> {
+        Ok(key.identical_halves)
+    }
+
+    fn cbc_encrypt(
+        &mut self,
+        key: &Pkcs11Key,
+        iv: &[u8],
+        data: &mut [u8],
+    ) -> Result<(), desfire::Error> {
+        let result = self.cbc(true, key, iv, data);
+        self.record(result)
+    }
+
+    fn cbc_decrypt(
+        &mut self,
+        key: &Pkcs11Key,
+        iv: &[u8],
+        data: &mut [u8],
+    ) -> Result<(), desfire::Error> {
+        let result = self.cbc(false, key, iv, data);
+        self.record(result)
+    }
+
+    fn cmac(&mut self, key: &Pkcs11Key, data: &[u8]) -> Result<AesCmac, desfire::Error> {
+        if key.algorithm != KeyAlgorithm::Aes128 {
+            return Err(desfire::Error::UnsupportedAlgorithm);
+        }
+        let result = self.sign_cmac(key, data);
+        self.record(result)
+    }
+
+    /// Derives the key on the token: the AN10922 CMAC runs through CBC
+    /// encryptions under `master` and ends in a `CKM_AES_CBC_ENCRYPT_DATA` or
+    /// `CKM_DES3_CBC_ENCRYPT_DATA` derivation, so the derived key is a session
+    /// object whose value can be read only if the value of `master` can.
+    fn diversify(
+        &mut self,
+        master: &Pkcs11Key,
+        input: &DiversificationInput,
+    ) -> Result<Pkcs11Key, desfire::Error> {
+        let zero_iv = [0u8; 16];
+        let steps = final_cmac_blocks_with(master.algorithm, input, |block| {
+            let result = self.cbc(true, master, &zero_iv[..block.len()], block);
+            self.record(result)
+        })?;
+        let result = self.derive(master, &steps);
+        self.record(result)
+    }
+
+    /// Encrypts the cryptogram on the token under a sensitive session object
+    /// holding the session key. The key values go into the plaintext, so they
+    /// are read through `CKA_VALUE`.
+    fn change_key_cryptogram(
+        &mut self,
+        request: &ChangeKeyRequest<'_>,
+        new_key: &Pkcs11Key,
+        old_key: Option<&Pkcs11Key>,
+    ) -> Result<ChangeKeyCryptogram, desfire::Error> {
+        if old_key.is_some_and(|old| old.algorithm != new_key.algorithm) {
+            return Err(desfire::Error::CryptoProvider);
+        }
+        let new_value = self.key_value(new_key);
+        let new_value = self.record(new_value)?;
+        let old_value = old_key.map(|old| self.key_value(old)).transpose();
+        let old_value = self.record(old_value)?;
+
+        let session_key = match request.session_key() {
+            SessionKey::Des(key) => Key::Des(*key.as_bytes()),
+            SessionKey::TwoKey3Des(key) => Key::TwoKey3Des(*key.as_bytes()),
+            SessionKey::ThreeKey3Des(key) => Key::ThreeKey3Des(*key.as_bytes()),
+            SessionKey::Aes(key) => Key::Aes128(*key.as_bytes()),
+        };
+        let session_key = self.create_key(&session_key, true);
+        let session_key = self.record(session_key)?;
+        let cryptogram = request.encipher_with(
+            new_value.as_bytes(),
+            old_value.as_ref().map(Key::as_bytes),
+            |data| {
+                let result = self.cbc(true, &session_key, request.iv(), data);
+                self.record(result)
+            },
+        );
+        let released = self.release(session_key);
+        let cryptogram = cryptogram?;
+        released?;
+        Ok(cryptogram)
+    }
+
+    /// Destroys keys created by [`Self::diversify`] or [`Self::import_key`];
+    /// keys found on the token are left alone.
+    fn release(&mut self, key: Pkcs11Key) -> Result<(), desfire::Error> {
+        if !key.session_object {
+            return Ok(());
+        }
+        let result = call!(self.functions(), C_DestroyObject(self.session, key.object));
+        self.record(result)
+    }
+}
+
+impl Drop for Pkcs11Token {
+    fn drop(&mut self) {
+        if self.session != sys::CK_INVALID_HANDLE {
+            let _ = call!(self.functions(), C_CloseSession(self.session));
+        }
+        if self.finalize {
+            let _ = call!(self.functions(), C_Finalize(ptr::null_mut()));
+        }
+    }
+}
+
+fn check(function: &'static str, rv: sys::CK_RV) -> Result<(), Error> {
+    if rv == sys::CKR_OK {
+        Ok(())
+    } else {
+        Err(Error::FunctionFailed { function, rv })
+    }
+}
+
+fn attribute<T>(type_: sys::CK_ATTRIBUTE_TYPE, value: &mut T) -> sys::CK_ATTRIBUTE {
+    sys::CK_ATTRIBUTE {
+        type_,
+        pValue: ptr::from_mut(value).cast::<c_void>(),
+        ulValueLen: ulong(size_of::<T>()),
+    }
+}
+
+fn bytes_attribute(type_: sys::CK_ATTRIBUTE_TYPE, value: &mut [u8]) -> sys::CK_ATTRIBUTE {
+    sys::CK_ATTRIBUTE {
+        type_,
+        pValue: value.as_mut_ptr().cast::<c_void>(),
+        ulValueLen: ulong(value.len()),
+    }
+}
+
+fn key_type(algorithm: KeyAlgorithm) -> sys::CK_KEY_TYPE {
+    match algorithm {
+        KeyAlgorithm::Des => sys::CKK_DES,
+        KeyAlgorithm::TwoKey3Des => sys::CKK_DES2,
+        KeyAlgorithm::ThreeKey3Des => sys::CKK_DES3,
+        KeyAlgorithm::Aes128 => sys::CKK_AES,
+    }
+}
+
+fn key_bytes_mut(key: &mut Key) -> &mut [u8] {
+    match key {
+        Key::Des(bytes) => bytes,
+        Key::TwoKey3Des(bytes) | Key::Aes128(bytes) => bytes,
+        Key::ThreeKey3Des(bytes) => bytes,
+    }
+}
+
+fn ulong(len: usize) -> sys::CK_ULONG {
+    sys::CK_ULONG::try_from(len).expect("buffer lengths fit in CK_ULONG")
+}
+
+#[cfg(test)]
+mod tests {
+    use tapsmith_core::gallagher::{
+        credential::GallagherCredential, desfire::GallagherDesfireReader,
+    };
+    use tapsmith_core::mifare::desfire::{
+        kdf::{diversify_2tdea_key, diversify_3tdea_key, diversify_aes128_key},
+        AccessRights, AesSessionKey, ApplicationId, ApplicationKeyType, ChangeKeyFormat,
+        CommandCode, CommunicationMode, Desfire, FileId, FixedRandom, KeyNumber, KeySettings,
+        NativeFraming, SessionKey, SoftwareCrypto, VirtualApplication, VirtualDesfire, VirtualFile,
+        WrappedFraming,
+    };
+
+    use super::*;
+    use crate::fake;
+
+    const SITE_KEY: [u8; 16] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE,
+        0xFF,
+    ];
+    const APP_KEY: [u8; 16] = [0x5C; 16];
+    const UID: [u8; 7] = [0x04, 0xA1, 0xB2, 0xC3, 0xD4, 0xE5, 0x80];
+
+    fn open() -> Pkcs11Token {
+        Pkcs11Token::open_with(
+            None,
+            &raw const fake::FUNCTIONS,
+            fake::TOKEN_LABEL,
+            fake::PIN,
+        )
+        .unwrap()
+    }
+
+    fn key(value: u8) -> KeyNumber {
+        KeyNumber::new(value).unwrap()
+    }
+
+    #[test]
+    fn finds_secret_keys_by_label() {
+        fake::reset();
+        fake::add_key("site", &Key::Aes128(SITE_KEY), true);
+        fake::add_key("legacy", &Key::TwoKey3Des([0x11; 16]), false);
+        fake::add_key("twin", &Key::Aes128(APP_KEY), true);
+        fake::add_key("twin", &Key::Aes128(APP_KEY), true);
+        let mut token = open();
+
+        let site = token.find_key("site").unwrap();
+        let legacy = token.find_key("legacy").unwrap();
+
+        assert_eq!(site.algorithm(), KeyAlgorithm::Aes128);
+        assert!(!token.has_identical_halves(&site).unwrap());
+        assert_eq!(legacy.algorithm(), KeyAlgorithm::TwoKey3Des);
+        assert!(token.has_identical_halves(&legacy).unwrap());
+        assert!(matches!(
+            token.find_key("missing"),
+            Err(Error::KeyNotFound(_))
+        ));
+        assert!(matches!(
+            token.find_key("twin"),
+            Err(Error::AmbiguousKey(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_token_and_wrong_pin() {
+        fake::reset();
+
+        assert!(matches!(
+            Pkcs11Token::open_with(None, &raw const fake::FUNCTIONS, "other", fake::PIN),
+            Err(Error::TokenNotFound(_))
+        ));
+        assert!(matches!(
+            Pkcs11Token::open_with(None, &raw const fake::FUNCTIONS, fake::TOKEN_LABEL, "0000"),
+            Err(Error::FunctionFailed {
+                function: "C_Login",
+                rv: 0xA0
+            })
+        ));
+    }
+
+    #[test]
+    fn token_operations_match_software_crypto() {
+        fake::reset();
+        fake::add_key("site", &Key::Aes128(SITE_KEY), true);
+        let mut token = open();
+        let site = token.find_key("site").unwrap();
+        let software = Key::Aes128(SITE_KEY);
+        let iv = [0xA5; 16];
+        let mut data = [0x42u8; 32];
+        let mut expected = data;
+
+        token.cbc_encrypt(&site, &iv, &mut data).unwrap();
+        SoftwareCrypto
+            .cbc_encrypt(&software, &iv, &mut expected)
+            .unwrap();
+        assert_eq!(data, expected);
+        token.cbc_decrypt(&site, &iv, &mut data).unwrap();
+        assert_eq!(data, [0x42; 32]);
+        assert_eq!(
+            token.cmac(&site, b"tapsmith").unwrap(),
+            SoftwareCrypto.cmac(&software, b"tapsmith").unwrap()
+        );
+        assert_eq!(
+            token.cbc_encrypt(&site, &iv[..8], &mut data),
+            Err(desfire::Error::CryptoProvider)
+        );
+        assert!(matches!(token.last_error(), Some(Error::KeyMismatch)));
+    }
+
+    #[test]
+    fn diversified_keys_are_session_objects() {
+        fake::reset();
+        fake::add_key("site", &Key::Aes128(SITE_KEY), true);
+        let mut token = open();
+        let site = token.find_key("site").unwrap();
+        let input = DiversificationInput::new().with_uid(&UID).unwrap();
+        let expected = Key::Aes128(diversify_aes128_key(&SITE_KEY, &input).unwrap());
+
+        let diversified = token.diversify(&site, &input).unwrap();
+
+        assert_eq!(fake::object_count(), 2);
+        assert_eq!(
+            token.cmac(&diversified, b"tapsmith").unwrap(),
+            SoftwareCrypto.cmac(&expected, b"tapsmith").unwrap()
+        );
+        assert!(token.key_value(&diversified).is_err());
+        let request = ChangeKeyRequest::new(
+            CommandCode::CHANGE_KEY,
+            &[0x01],
+            ChangeKeyFormat::Crc32,
+            Some(0),
+            SessionKey::Aes(AesSessionKey::new([0; 16])),
+            &[0; 16],
+        )
+        .unwrap();
+        assert_eq!(
+            token
+                .change_key_cryptogram(&request, &diversified, None)
+                .unwrap_err(),
+            desfire::Error::CryptoProvider
+        );
+        assert!(matches!(
+            token.last_error(),
+            Some(Error::FunctionFailed {
+                function: "C_GetAttributeValue",
+                ..
+            })
+        ));
+        token.release(diversified).unwrap();
+        token.release(site).unwrap();
+        assert_eq!(fake::object_count(), 1);
+    }
+
+    #[test]
+    fn diversifies_tdea_keys_on_the_token() {
+        const MASTER_2TDEA: [u8; 16] = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD,
+            0xEE, 0xFF,
+        ];
+        const MASTER_3TDEA: [u8; 24] = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD,
+            0xEE, 0xFF, 0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF,
+        ];
+        fake::reset();
+        fake::add_key("two", &Key::TwoKey3Des(MASTER_2TDEA), false);
+        fake::add_key("three", &Key::ThreeKey3Des(MASTER_3TDEA), true);
+        let mut token = open();
+        let two = token.find_key("two").unwrap();
+        let three = token.find_key("three").unwrap();
+        let input = DiversificationInput::new().with_uid(&UID).unwrap();
+
+        let two_derived = token.diversify(&two, &input).unwrap();
+        let three_derived = token.diversify(&three, &input).unwrap();
+
+        assert_eq!(fake::object_count(), 4);
+        assert_eq!(
+            token.key_value(&two_derived).unwrap(),
+            Key::TwoKey3Des(diversify_2tdea_key(&MASTER_2TDEA, &input).unwrap())
+        );
+        assert!(token.key_value(&three_derived).is_err());
+        let expected = Key::ThreeKey3Des(diversify_3tdea_key(&MASTER_3TDEA, &input).unwrap());
+        let mut data = [0x42u8; 16];
+        let mut expected_data = data;
+        token
+            .cbc_encrypt(&three_derived, &[0; 8], &mut data)
+            .unwrap();
+        SoftwareCrypto
+            .cbc_encrypt(&expected, &[0; 8], &mut expected_data)
+            .unwrap();
+        assert_eq!(data, expected_data);
+    }
+
+    #[test]
+    fn authenticates_and_changes_keys_on_a_virtual_card() {
+        fake::reset();
+        fake::add_key("app", &Key::Aes128(APP_KEY), true);
+        // The new key's CRC goes into the cryptogram, so its value must be readable.
+        fake::add_key("site", &Key::Aes128(SITE_KEY), false);
+        let mut token = open();
+        let app = token.find_key("app").unwrap();
+        let site = token.find_key("site").unwrap();
+        let aid = ApplicationId::from_bytes([0x12, 0x34, 0x56]);
+        let input = DiversificationInput::new()
+            .with_uid(&UID)
+            .unwrap()
+            .with_application_id(aid)
+            .unwrap();
+        let mut card = VirtualDesfire::new().with_application(
+            VirtualApplication::new(aid, KeySettings::new(0x0F, ApplicationKeyType::Aes, 2))
+                .unwrap()
+                .with_key(key(0), Key::Aes128(APP_KEY), 0)
+                .unwrap(),
+        );
+        let mut desfire = Desfire::new(&mut card, WrappedFraming);
+        let mut random = FixedRandom::new(&[0xA5]);
+
+        desfire.select_application(aid).unwrap();
+        desfire
+            .authenticate_with_provider(key(0), &mut token, &app, &mut random)
+            .unwrap();
+        let diversified = token.diversify(&site, &input).unwrap();
+        let old = token.import_key(&Key::Aes128([0; 16])).unwrap();
+        desfire
+            .change_key_with_provider(key(1), &mut token, &diversified, 1, Some(&old))
+            .unwrap();
+        assert_eq!(fake::object_count(), 4);
+        desfire
+            .authenticate_with_provider(key(1), &mut token, &diversified, &mut random)
+            .unwrap();
+
+        assert_eq!(
+            card.application(aid).unwrap().key(key(1)),
+            Some(&Key::Aes128(
+                diversify_aes128_key(&SITE_KEY, &input).unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn reads_gallagher_credential_with_site_key_on_token() {
+        fake::reset();
+        fake::add_key("site", &Key::Aes128(SITE_KEY), true);
+        let mut token = open();
+        let site = token.find_key("site").unwrap();
+        let aid = ApplicationId::from_bytes([0xF4, 0x81, 0x20]);
+        let input = DiversificationInput::new()
+            .with_uid(&UID)
+            .unwrap()
+            .with_bytes(&[0x00])
+            .unwrap()
+            .with_bytes(&aid.as_bytes())
+            .unwrap();
+        let credential = GallagherCredential::new(12, 0x1337, 0xF00D, 3).unwrap();
+        let encoded = credential.encode();
+        let file: Vec<u8> = encoded
+            .iter()
+            .copied()
+            .chain(encoded.iter().map(|byte| !byte))
+            .collect();
+        let mut card = VirtualDesfire::new().with_uid(UID).with_application(
+            VirtualApplication::new(aid, KeySettings::new(0x0B, ApplicationKeyType::Aes, 3))
+                .unwrap()
+                .with_key(
+                    key(0),
+                    Key::Aes128(diversify_aes128_key(&SITE_KEY, &input).unwrap()),
+                    0,
+                )
+                .unwrap()
+                .with_file(
+                    FileId::new(0).unwrap(),
+                    VirtualFile::std_data(
+                        CommunicationMode::Enciphered,
+                        AccessRights::from_bytes([0x00, 0x00]),
+                        &file,
+                    ),
+                ),
+        );
+        let mut desfire = Desfire::new(&mut card, NativeFraming);
+
+        let result = GallagherDesfireReader::read_from_desfire_with_provider(
+            &mut desfire,
+            &mut token,
+            &site,
+            UID,
+            &mut FixedRandom::new(&[0xA5]),
+        )
+        .unwrap();
+
+        let [read] = result.credentials.as_slice() else {
+            panic!("expected one credential");
+        };
+        assert_eq!(read.credential, credential);
+        assert_eq!(fake::object_count(), 1);
+    }
+}
@@ -0,0 +1,179 @@
+//! End-to-end checks against a real PKCS#11 module, usually `SoftHSM`.
+//!
+//! `TAPSMITH_PKCS11_MODULE` names the module; `TAPSMITH_PKCS11_TOKEN` and
+//! `TAPSMITH_PKCS11_PIN` default to `tapsmith` and `1234`. Keys are imported
+//! as session objects, so the token is left as it was.
+
+use std::env;
+
+use tapsmith_core::gallagher::{credential::GallagherCredential, desfire::GallagherDesfireReader};
+use tapsmith_core::mifare::desfire::{
+    kdf::diversify_aes128_key, AccessRights, ApplicationId, ApplicationKeyType, CommunicationMode,
+    CryptoProvider, Desfire, DiversificationInput, FileId, FixedRandom, Key, KeyNumber,
+    KeySettings, NativeFraming, SoftwareCrypto, VirtualApplication, VirtualDesfire, VirtualFile,
+    WrappedFraming,
+};
+use tapsmith_pkcs11::Pkcs11Token;
+
+const SITE_KEY: [u8; 16] = [
+    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF,
+];
+const APP_KEY: [u8; 16] = [0x5C; 16];
+const UID: [u8; 7] = [0x04, 0xA1, 0xB2, 0xC3, 0xD4, 0xE5, 0x80];
+
+fn open() -> Pkcs11Token {
+    let module = env::var("TAPSMITH_PKCS11_MODULE").expect("TAPSMITH_PKCS11_MODULE is set");
+    let token = env::var("TAPSMITH_PKCS11_TOKEN").unwrap_or_else(|_| "tapsmith".to_owned());
+    let pin = env::var("TAPSMITH_PKCS11_PIN").unwrap_or_else(|_| "1234".to_owned());
+    Pkcs11Token::open(module, &token, &pin).unwrap()
+}
+
+#[test]
+#[ignore = "needs a PKCS#11 module in TAPSMITH_PKCS11_MODULE"]
+fn token_ciphers_match_software_crypto() {
+    let mut token = open();
+    let keys = [
+        Key::TwoKey3Des([
+            0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0xFE, 0xDC, 0xBA, 0x98, 0x76, 0x54,
+            0x32, 0x10,
+        ]),
+        Key::ThreeKey3Des([0x5C; 24]),
+        Key::Aes128(SITE_KEY),
+    ];
+
+    for key in keys {
+        let handle = token.import_key(&key).unwrap();
+        let block_size = key.algorithm().block_size();
+        let iv = vec![0xA5; block_size];
+        let mut data = vec![0x42; block_size * 2];
+        let mut expected = data.clone();
+
+        token.cbc_encrypt(&handle, &iv, &mut data).unwrap();
+        SoftwareCrypto
+            .cbc_encrypt(&key, &iv, &mut expected)
+            .unwrap();
+        assert_eq!(data, expected, "{key:?}");
+        token.cbc_decrypt(&handle, &iv, &mut data).unwrap();
+        assert_eq!(data, vec![0x42; block_size * 2], "{key:?}");
+        token.release(handle).unwrap();
+    }
+
+    let site = token.import_key(&Key::Aes128(SITE_KEY)).unwrap();
+    assert_eq!(
+        token.cmac(&site, b"tapsmith").unwrap(),
+        SoftwareCrypto
+            .cmac(&Key::Aes128(SITE_KEY), b"tapsmith")
+            .unwrap()
+    );
+    token.release(site).unwrap();
+}
+
+#[test]
+#[ignore = "needs a PKCS#11 module in TAPSMITH_PKCS11_MODULE"]
+fn reads_gallagher_credential_with_site_key_on_token() {
+    let mut token = open();
+    let site = token.import_key(&Key::Aes128(SITE_KEY)).unwrap();
+    let aid = ApplicationId::from_bytes([0xF4, 0x81, 0x20]);
+    let input = DiversificationInput::new()
+        .with_uid(&UID)
+        .unwrap()
+        .with_bytes(&[0x00])
+        .unwrap()
+        .with_bytes(&aid.as_bytes())
+        .unwrap();
+    let credential = GallagherCredential::new(12, 0x1337, 0xF00D, 3).unwrap();
+    let encoded = credential.encode();
+    let file: Vec<u8> = encoded
+        .iter()
+        .copied()
+        .chain(encoded.iter().map(|byte| !byte))
+        .collect();
+    let mut card = VirtualDesfire::new().with_uid(UID).with_application(
+        VirtualApplication::new(aid, KeySettings::new(0x0B, ApplicationKeyType::Aes, 3))
+            .unwrap()
+            .with_key(
+                KeyNumber::new(0).unwrap(),
+                Key::Aes128(diversify_aes128_key(&SITE_KEY, &input).unwrap()),
+                0,
+            )
+            .unwrap()
+            .with_file(
+                FileId::new(0).unwrap(),
+                VirtualFile::std_data(
+                    CommunicationMode::Enciphered,
+                    AccessRights::from_bytes([0x00, 0x00]),
+                    &file,
+                ),
+            ),
+    );
+    let mut desfire = Desfire::new(&mut card, NativeFraming);
+
+    let result = GallagherDesfireReader::read_from_desfire_with_provider(
+        &mut desfire,
+        &mut token,
+        &site,
+        UID,
+        &mut FixedRandom::new(&[0xA5]),
+    )
+    .unwrap();
+
+    let [read] = result.credentials.as_slice() else {
+        panic!("expected one credential");
+    };
+    assert_eq!(read.credential, credential);
+}
+
+#[test]
+#[ignore = "needs a PKCS#11 module in TAPSMITH_PKCS11_MODULE"]
+fn changes_a_key_to_a_diversified_key_on_token() {
+    let mut token = open();
+    let app = token.import_key(&Key::Aes128(APP_KEY)).unwrap();
+    let site = token.import_key(&Key::Aes128(SITE_KEY)).unwrap();
+    let old = token.import_key(&Key::Aes128([0; 16])).unwrap();
+    let aid = ApplicationId::from_bytes([0x12, 0x34, 0x56]);
+    let input = DiversificationInput::new()
+        .with_uid(&UID)
+        .unwrap()
+        .with_application_id(aid)
+        .unwrap();
+    let mut card = VirtualDesfire::new().with_application(
+        VirtualApplication::new(aid, KeySettings::new(0x0F, ApplicationKeyType::Aes, 2))
+            .unwrap()
+            .with_key(KeyNumber::new(0).unwrap(), Key::Aes128(APP_KEY), 0)
+            .unwrap(),
+    );
+    let mut desfire = Desfire::new(&mut card, WrappedFraming);
+    let mut random = FixedRandom::new(&[0xA5]);
+
+    desfire.select_application(aid).unwrap();
+    desfire
+        .authenticate_with_provider(KeyNumber::new(0).unwrap(), &mut token, &app, &mut random)
+        .unwrap();
+    let diversified = token.diversify(&site, &input).unwrap();
+    desfire
+        .change_key_with_provider(
+            KeyNumber::new(1).unwrap(),
+            &mut token,
+            &diversified,
+            1,
+            Some(&old),
+        )
+        .unwrap();
+    desfire
+        .authenticate_with_provider(
+            KeyNumber::new(1).unwrap(),
+            &mut token,
+            &diversified,
+            &mut random,
+        )
+        .unwrap();
+
+    assert_eq!(
+        card.application(aid)
+            .unwrap()
+            .key(KeyNumber::new(1).unwrap()),
+        Some(&Key::Aes128(
+            diversify_aes128_key(&SITE_KEY, &input).unwrap()
+        ))
+    );
+}